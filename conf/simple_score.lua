@@ -3,7 +3,8 @@
 
 -- Calculate score for given proxy server and delay
 -- proxy: a table describes the proxy server
--- delay: time in seconds in float
+-- delay: time in seconds in float, nil if the probe failed, timed out
+--        or not
 -- Return a score in signed number or nil
 function calc_score(proxy, delay)
   -- proxy.addr, proxy.proto, proxy.tag:
@@ -16,7 +17,7 @@ function calc_score(proxy, delay)
   --   rx_bytes: download from proxy server
   -- proxy.status:
   --   delay: the delay before this update, in secs in float.
-  --          nil = initial value; -1 = timed out.
+  --          nil = initial value; -1 = timed out or failed.
   --   score: the score before this update, may be nil.
   --   conn_alive, conn_total, conn_error: connection counters
   --   close_history:
@@ -36,3 +37,21 @@ function calc_score(proxy, delay)
     return math.floor(delay * 1000 + proxy.config.score_base)
   end
 end
+
+-- Optional, reorder candidate servers for each connection
+-- req: a table describes the request
+--   port: the port number moproxy is listening on
+--   domain: destination domain name, may be nil
+--   ip: destination IP address in string, may be nil
+-- tags: tags of candidate servers, in the order they will be tried
+-- Return a tag or a list of tags to try first, or nil to keep the order.
+-- The script is aborted if it runs longer than 50ms, and skipped (with the
+-- order kept) if it's busy in calc_score() at the moment.
+--[[
+function pick_server(req, tags)
+  if req.domain ~= nil and req.domain:match("%.example%.com$") then
+    return "server-1"
+  end
+  return nil
+end
+]]
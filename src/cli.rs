@@ -4,7 +4,18 @@ use std::{
     time::Duration,
 };
 
-use clap::{arg, command, Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use moproxy::{
+    client::InboundOptions,
+    duration::parse_duration_or_in,
+    monitor::LivenessSignal,
+    policy::{
+        parser::{ip_prefix, is_cap_name},
+        prefix_contains,
+    },
+    privacy::HostnamePrivacy,
+    proxy::{BulkThreshold, ScoreLimits, TcpOptions},
+};
 use tracing::metadata::LevelFilter;
 
 #[derive(Parser, Debug)]
@@ -53,65 +64,527 @@ pub(crate) struct CliArgs {
     #[arg(long = "policy", value_name = "POLICY")]
     pub(crate) policy: Option<PathBuf>,
 
+    /// Answer plain HTTP requests of NATed clients to port 80 rejected by
+    /// the policy with this HTML page (a built-in one if not given), as
+    /// `403 Forbidden`. `{{host}}` and `{{rule}}` in it are substituted.
+    /// Reloaded along with the policy
+    #[arg(long, value_name = "HTML-FILE")]
+    pub(crate) reject_http_page: Option<Option<PathBuf>>,
+
     /// Period of time to make one probe.
     #[arg(short = 'i', long = "probe", value_name = "SECONDS")]
-    #[arg(default_value_t = 30)]
-    pub(crate) probe_secs: u64,
+    #[arg(default_value = "30", value_parser = parse_duration_in_seconds)]
+    pub(crate) probe: Duration,
+
+    /// With systemd watchdog, stop poking it once the accept loop has made
+    /// no progress for SECONDS, so that systemd restarts us.
+    #[arg(long, value_name = "SECONDS", default_value = "30", value_parser = parse_duration_in_seconds)]
+    pub(crate) watchdog_accept_stale: Duration,
+
+    /// Like --watchdog-accept-stale but for the probe loop. Default to four
+    /// times of --probe plus one minute. Zero to skip it.
+    #[arg(long, value_name = "SECONDS", value_parser = parse_duration_in_seconds)]
+    pub(crate) watchdog_probe_stale: Option<Duration>,
+
+    /// How to log probe results. `state-change` logs only when a server
+    /// goes up/down or its score changes a lot, plus servers still down
+    /// every hour.
+    #[arg(long, value_name = "MODE", default_value = "every")]
+    pub(crate) probe_log: ProbeLogMode,
+
+    /// Save a pcap file (with payload of probes, in synthesized IP/TCP
+    /// headers) and a JSON file of timings per probe, for a sampled
+    /// fraction of probe rounds, into DIR.
+    #[arg(long, value_name = "DIR")]
+    pub(crate) probe_capture: Option<PathBuf>,
+
+    /// Fraction of probe rounds to capture, see --probe-capture.
+    #[arg(long, value_name = "RATE", default_value = "0.01", value_parser = parse_ratio)]
+    pub(crate) probe_capture_rate: f64,
+
+    /// Remove the oldest captures once files in --probe-capture exceed MB.
+    #[arg(long, value_name = "MB", default_value_t = 100)]
+    pub(crate) probe_capture_max_mb: u64,
 
     /// Address of a DNS server with TCP support to do delay probing.
     #[arg(long, value_name = "IP-ADDR:PORT", default_value = "8.8.8.8:53")]
     pub(crate) test_dns: SocketAddr,
 
-    /// Where the web server that shows statistics bind.
+    /// DNS server to resolve domain names of direct connections, SOCKSv4
+    /// servers w/o remote DNS, and server addresses. Queried in order if
+    /// given multiple times. Use the system resolver if not set.
+    #[arg(long = "resolver", value_name = "IP-ADDR:PORT")]
+    pub(crate) resolvers: Vec<SocketAddr>,
+
+    /// Where the web server that shows statistics bind. Listen on all of
+    /// them if given multiple times.
     #[cfg(feature = "web_console")]
     #[arg(long = "stats-bind", value_name = "IP-ADDR:PORT")]
-    pub(crate) web_bind: Option<String>,
+    pub(crate) web_bind: Vec<String>,
+
+    /// Serve the web console to clients of proxy ports asking for this
+    /// destination (`moproxy.internal:80` if the value omitted) instead of
+    /// connecting it, unless rejected by the policy. For where no other
+    /// port can be opened.
+    #[cfg(feature = "web_console")]
+    #[arg(
+        long,
+        value_name = "HOST:PORT",
+        num_args = 0..=1,
+        default_missing_value = "moproxy.internal:80"
+    )]
+    pub(crate) stats_dest: Option<String>,
+
+    /// Where to write the plaintext status table upon each TCP connection
+    /// then close it, no HTTP involved (e.g. `nc localhost 2021`).
+    #[cfg(feature = "web_console")]
+    #[arg(long, value_name = "IP-ADDR:PORT")]
+    pub(crate) stats_plain_bind: Option<SocketAddr>,
+
+    /// Enable `/whoami?src_port=N` on the web console, telling which
+    /// upstream serves the client's connection from that source port.
+    /// Requests must carry `Authorization: Bearer TOKEN`, and come from
+    /// the same IP address as the connection.
+    #[cfg(feature = "web_console")]
+    #[arg(long, value_name = "TOKEN")]
+    pub(crate) whoami_token: Option<String>,
+
+    /// Enable `/debug/log?level=LEVEL&lines=N` on the web console, showing
+    /// recent log events kept in memory. Requests must carry
+    /// `Authorization: Bearer TOKEN`.
+    #[cfg(feature = "web_console")]
+    #[arg(long, value_name = "TOKEN")]
+    pub(crate) debug_log_token: Option<String>,
+
+    /// Number of recent log events kept for --debug-log-token.
+    #[cfg(feature = "web_console")]
+    #[arg(long, value_name = "N", default_value_t = 1000)]
+    pub(crate) debug_log_lines: usize,
+
+    /// Keep log events at this level or more severe for --debug-log-token,
+    /// regardless of --log-level.
+    #[cfg(feature = "web_console")]
+    #[arg(long, value_name = "LEVEL", default_value = "debug")]
+    pub(crate) debug_log_level: LevelFilter,
+
+    /// Enable pages of the web console changing things, i.e. `POST` and
+    /// `DELETE /maintenance` and `DELETE /top`. Requests must carry
+    /// `Authorization: Bearer TOKEN`.
+    #[cfg(feature = "web_console")]
+    #[arg(long, value_name = "TOKEN")]
+    pub(crate) admin_token: Option<String>,
+
+    /// Require `Authorization: Bearer TOKEN` for pages of the web console
+    /// showing all servers, e.g. `/status` and `/metrics`, but not
+    /// `/status?tenant=NAME` of --port-tenant.
+    #[cfg(feature = "web_console")]
+    #[arg(long, value_name = "TOKEN")]
+    pub(crate) status_token: Option<String>,
+
+    /// Respond 200 on `/healthz` of the web console even if no server is
+    /// healthy, describing the state in the body only.
+    #[cfg(feature = "web_console")]
+    #[arg(long)]
+    pub(crate) healthz_always_ok: bool,
+
+    /// Serve the web console under this path (e.g. `/moproxy`) instead of
+    /// the root, for mounting behind a reverse proxy.
+    #[cfg(feature = "web_console")]
+    #[arg(long, value_name = "PATH")]
+    pub(crate) web_path_prefix: Option<String>,
 
     /// Try to obtain domain name from TLS SNI, and sent it to remote
     /// proxy server. Only apply for port number 443.
     #[arg(long)]
     pub(crate) remote_dns: bool,
 
+    /// Resolve domain names of clients (from their requests or TLS SNI)
+    /// so that `dst ip` policy rules apply to them too. Wait for at most
+    /// MILLIS before applying the policy; names are cached for later
+    /// connections anyway.
+    #[arg(
+        long,
+        value_name = "MILLIS",
+        num_args = 0..=1,
+        default_missing_value = "30",
+        value_parser = parse_duration_in_millis
+    )]
+    pub(crate) resolve_sni_for_policy: Option<Duration>,
+
+    /// Log JA3-style fingerprints of TLS ClientHello from clients, and
+    /// count them on /metrics. Implies sniffing on port 443 as
+    /// --remote-dns does, yet without override destinations.
+    #[arg(long)]
+    pub(crate) fingerprint_tls: bool,
+
     /// Connect and send application data to N proxies in parallel, use
     /// the first proxy that return valid data. Currently only support
     /// TLS as application layer. Must turn on --remote-dns otherwise it
-    /// will be ignored.
+    /// will be ignored. With `auto` or `auto:N`, only destinations whose
+    /// best proxy failed recently are raced, to 3 or N proxies
+    #[arg(long, value_name = "N", default_value = "0", value_parser = parse_n_parallel)]
+    pub(crate) n_parallel: NParallel,
+
+    /// Cap the memory of client data buffered before connected (e.g.
+    /// sniffed TLS ClientHello) among all connections. Once exceeded, new
+    /// connections skip the sniff, as if no SNI found.
+    #[arg(long, value_name = "MB")]
+    pub(crate) max_pending_mb: Option<usize>,
+
+    /// Count traffic per listen port per (UTC) day, keep the last N days.
+    /// Shown on `/accounting` of the web console.
+    #[arg(long, value_name = "N")]
+    pub(crate) accounting_days: Option<usize>,
+
+    /// Append traffic per listen port to this CSV file once each day is
+    /// over. Implies --accounting-days 31 if not set.
+    #[arg(long, value_name = "PATH")]
+    pub(crate) accounting_file: Option<PathBuf>,
+
+    /// Count traffic and connections per destination (domain name, or /24
+    /// and /48 network of IP addresses), keep the top N of them. Shown on
+    /// `/top` of the web console.
+    #[arg(long, value_name = "N")]
+    pub(crate) track_destinations: Option<usize>,
+
+    /// How destination host names appear in logs, `/top`, and task lists
+    /// of the web console. `truncate` keeps only the registrable domain
+    /// (e.g. `example.co.uk`), `hash` replaces them with a short hash keyed
+    /// by --hostname-privacy-key. Routing is not affected.
+    #[arg(long, value_name = "MODE", default_value = "off")]
+    pub(crate) hostname_privacy: HostnamePrivacyMode,
+
+    /// Secret key of --hostname-privacy hash. Same host names get the same
+    /// hash with the same key, across restarts.
+    #[arg(long, value_name = "KEY", required_if_eq("hostname_privacy", "hash"))]
+    pub(crate) hostname_privacy_key: Option<String>,
+
+    /// Retry connecting to a proxy up to N times if it fails with a
+    /// transient error (e.g. connection refused or reset).
     #[arg(long, value_name = "N", default_value_t = 0)]
-    pub(crate) n_parallel: usize,
+    pub(crate) connect_retries: usize,
+
+    /// Keep Nagle's algorithm on (do not set TCP_NODELAY) for both client
+    /// and proxy sockets.
+    #[arg(long)]
+    pub(crate) no_nodelay: bool,
+
+    /// Set TCP_NOTSENT_LOWAT to BYTES on client and proxy sockets, which
+    /// limits unsent data buffered in kernel. Linux only.
+    #[arg(long, value_name = "BYTES")]
+    pub(crate) notsent_lowat: Option<u32>,
+
+    /// Set TCP_QUICKACK on client and proxy sockets. Linux only.
+    #[arg(long)]
+    pub(crate) quickack: bool,
+
+    /// Use Multipath TCP to connect proxies if supported by the kernel.
+    /// Linux only.
+    #[arg(long)]
+    pub(crate) upstream_mptcp: bool,
+
+    /// Use TCP Fast Open to connect proxies if supported by the kernel,
+    /// so that the handshake is sent along with SYN. Linux only.
+    #[arg(long)]
+    pub(crate) upstream_tfo: bool,
 
     /// Set TCP congestion control algorithm on local (client) side.
     #[cfg(target_os = "linux")]
     #[arg(long = "congestion-local", value_name = "ALG-NAME")]
     pub(crate) cong_local: Option<String>,
 
+    /// IP address put in SOCKSv5 replies as the bound address, instead of
+    /// the local address of the upstream-facing socket.
+    #[arg(long, value_name = "IP-ADDR")]
+    pub(crate) advertised_addr: Option<IpAddr>,
+
+    /// Accept connections redirected by TPROXY (iptables/nftables target).
+    /// IP_TRANSPARENT is set on listeners, which requires CAP_NET_ADMIN.
+    /// Destinations are taken from the local address of connections, and
+    /// SOCKSv5 is not accepted in this mode. Linux only.
+    #[cfg(target_os = "linux")]
+    #[arg(long)]
+    pub(crate) tproxy: bool,
+
     /// Fallback to direct connect (without proxy) if all proxies failed.
     #[arg(long)]
     pub(crate) allow_direct: bool,
 
+    /// Send IPv4-mapped IPv6 destinations (::ffff:a.b.c.d) to upstream
+    /// proxies as is, instead of converting them into IPv4.
+    #[arg(long)]
+    pub(crate) keep_ipv4_mapped: bool,
+
+    /// Also accept raw TLS connections (neither NATed nor SOCKSv5) on the
+    /// listen ports, as if they were destined to the SNI host on
+    /// `--raw-tls-port`.
+    #[arg(long)]
+    pub(crate) accept_raw_tls: bool,
+
+    /// Destination port of raw TLS connections.
+    #[arg(
+        long,
+        value_name = "PORT",
+        default_value_t = 443,
+        requires = "accept_raw_tls"
+    )]
+    pub(crate) raw_tls_port: u16,
+
+    /// Also accept HTTP CONNECT requests on the listen ports.
+    #[arg(long)]
+    pub(crate) accept_http_connect: bool,
+
+    /// Let HTTP CONNECT clients influence routing by headers:
+    /// `X-Moproxy-Require: CAP` adds a capability to the policy
+    /// requirement, `X-Moproxy-Server: TAG` pins the connection to that
+    /// server if it's healthy and allowed by the policy.
+    #[arg(long, requires = "accept_http_connect")]
+    pub(crate) allow_client_hints: bool,
+
+    /// Serve clients on the port for the tenant only, e.g. `8081=team-a`:
+    /// just servers with capability `team-a` are used for them, as if
+    /// `listen port 8081 require team-a` is added to the policy whatever
+    /// other rules say, including `direct` ones. The web console shows
+    /// them on `/status?tenant=team-a` with --tenant-token. Requires
+    /// --status-token to keep other pages from tenants. Can be repeated.
+    #[arg(long, value_name = "PORT=TENANT", value_parser = parse_port_tenant)]
+    #[cfg_attr(feature = "web_console", arg(requires = "status_token"))]
+    pub(crate) port_tenant: Vec<(u16, String)>,
+
+    /// Bearer token of the tenant for its `/status?tenant=NAME`, e.g.
+    /// `team-a=TOKEN`. Tenants without one have no status page. Can be
+    /// repeated.
+    #[cfg(feature = "web_console")]
+    #[arg(long, value_name = "TENANT=TOKEN", value_parser = parse_tenant_token)]
+    pub(crate) tenant_token: Vec<(String, String)>,
+
+    /// Treat connections from the network (e.g. `10.0.0.0/8`) closed
+    /// without sending anything in a moment as health checks of load
+    /// balancers: closed silently and counted as `health_checks`, instead
+    /// of errors. Data from server-first protocols of such sources is
+    /// delayed by the moment. Can be repeated.
+    #[arg(long, value_name = "CIDR", value_parser = parse_ip_prefix)]
+    pub(crate) health_check_source: Vec<(IpAddr, u8)>,
+
+    /// Accept username/password authentication from SOCKSv5 clients (any
+    /// credential passes). Username `tag:SERVER-TAG` pins the connection to
+    /// that server if it's healthy and allowed by the policy.
+    #[arg(long)]
+    pub(crate) socks_pin_by_username: bool,
+
+    /// Accept servers sharing the same tag (with a warning) instead of
+    /// refusing to load the server list.
+    #[arg(long)]
+    pub(crate) allow_duplicate_tags: bool,
+
+    /// Skip sections of the server list with errors (logged and listed
+    /// in the reload status) instead of refusing to load the whole list,
+    /// as long as any server remains or --allow-direct is set.
+    #[arg(long)]
+    pub(crate) server_list_lenient: bool,
+
     /// Send metrics to graphite (carbon) daemon in plaintext format with
     /// TCP.
     #[arg(long, value_name = "IP-ADDR:PORT")]
     pub(crate) graphite: Option<SocketAddr>,
 
+    /// Connect the graphite daemon with TLS.
+    #[cfg(feature = "graphite_tls")]
+    #[arg(long, requires = "graphite")]
+    pub(crate) graphite_tls: bool,
+
+    /// Server name to verify the graphite daemon's certificate against,
+    /// its IP address if not set.
+    #[cfg(feature = "graphite_tls")]
+    #[arg(long, value_name = "NAME", requires = "graphite_tls")]
+    pub(crate) graphite_tls_name: Option<String>,
+
+    /// Verify the graphite daemon's certificate with CAs in this PEM file,
+    /// instead of the bundled Mozilla's root CAs.
+    #[cfg(feature = "graphite_tls")]
+    #[arg(long, value_name = "CA-FILE", requires = "graphite_tls")]
+    pub(crate) graphite_tls_ca: Option<PathBuf>,
+
+    /// Do not verify the graphite daemon's certificate.
+    #[cfg(feature = "graphite_tls")]
+    #[arg(long, requires = "graphite_tls", conflicts_with = "graphite_tls_ca")]
+    pub(crate) graphite_tls_insecure: bool,
+
     /// Level of verbosity [possible values: off, error, warn, info, debug,
     /// trace]
     #[arg(long, default_value = "info")]
     pub(crate) log_level: LevelFilter,
 
+    /// Log identical errors of client connections from the same IP address
+    /// at most once per SECONDS, with the number of suppressed ones.
+    /// Zero (default) to disable.
+    #[arg(long, value_name = "SECONDS", default_value = "0", value_parser = parse_duration_in_seconds)]
+    pub(crate) log_sample_secs: Duration,
+
     /// Lua script that customize proxy score
     #[cfg(feature = "score_script")]
     #[arg(long, value_name = "LUA-SCRIPT")]
     pub(crate) score_script: Option<PathBuf>,
 
+    /// Sample traffic of each server every MILLIS for throughput shown on
+    /// stats page and /metrics.
+    #[arg(long, value_name = "MILLIS", default_value = "1000", value_parser = parse_duration_in_millis)]
+    pub(crate) throughput_interval: Duration,
+
+    /// Half-life of the smoothed throughput (`*_bps_avg` on /status, and
+    /// the ↑↓ column on the plaintext stats).
+    #[arg(long, value_name = "SECONDS", default_value = "5", value_parser = parse_duration_in_seconds)]
+    pub(crate) throughput_half_life: Duration,
+
     /// Max waiting time in seconds for connection establishment before
     /// timeout. Applied for both probe & regular proxy connections.
     #[arg(long, value_name = "SECONDS", default_value = "4", value_parser = parse_duration_in_seconds)]
     pub(crate) max_wait: Duration,
 
+    /// For servers with `max wait = auto`, wait for FACTOR times the 95th
+    /// percentile of recent probe delays, within --auto-max-wait-min and
+    /// --auto-max-wait-max.
+    #[arg(long, value_name = "FACTOR", default_value_t = 3.0)]
+    pub(crate) auto_max_wait_factor: f32,
+
+    /// Lower bound of `max wait = auto`.
+    #[arg(long, value_name = "MILLIS", default_value = "500", value_parser = parse_duration_in_millis)]
+    pub(crate) auto_max_wait_min: Duration,
+
+    /// Upper bound of `max wait = auto`, probes timed out are counted as
+    /// taking this long.
+    #[arg(long, value_name = "SECONDS", default_value = "30", value_parser = parse_duration_in_seconds)]
+    pub(crate) auto_max_wait_max: Duration,
+
+    /// Forcibly close half-closed connections (one side has shut down its
+    /// write half) if nothing sent from the other side for SECONDS.
+    #[arg(long, value_name = "SECONDS", default_value = "60", value_parser = parse_duration_in_seconds)]
+    pub(crate) half_close_timeout: Duration,
+
+    /// Count a connection as bulk once it has transferred more than BYTES
+    /// in total. Bulk connections are shown on stats, and avoided by
+    /// `prefer-non-bulk` policy rules.
+    #[arg(long, value_name = "BYTES")]
+    pub(crate) bulk_bytes: Option<usize>,
+
+    /// Count a connection as bulk once it has lasted longer than SECONDS,
+    /// checked on traffic. See also --bulk-bytes.
+    #[arg(long, value_name = "SECONDS", value_parser = parse_duration_in_seconds)]
+    pub(crate) bulk_secs: Option<Duration>,
+
+    /// Max number of concurrent handshakes to all upstream proxies. Waiting
+    /// for it counts toward `--max-wait`. See also `handshake concurrency`
+    /// in the server list for per-server limits.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub(crate) max_handshakes: Option<u32>,
+
+    /// Max number of clients being handled at the same time. New clients
+    /// beyond that are closed immediately, after a SOCKSv5 failure reply
+    /// if its greeting has already arrived.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub(crate) max_connections: Option<u32>,
+
+    /// Max number of clients still handshaking (e.g. sending its SOCKS
+    /// greeting, or waiting for upstream) at the same time, counting
+    /// toward --max-connections as well. New clients beyond that are
+    /// closed immediately.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub(crate) max_pending_handshakes: Option<u32>,
+
+    /// Max number of clients being handled at the same time from each
+    /// source IP address. Overridden by `src ip ... limit N` policy rules.
+    /// New clients beyond that are refused, SOCKSv5 ones with a
+    /// "connection not allowed by ruleset" reply.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub(crate) max_conn_per_ip: Option<u32>,
+
+    /// Delay a connection identical (same source IP, destination host and
+    /// port) to one still connecting and started within this window, until
+    /// that one connected or failed. For clients retrying aggressively.
+    #[arg(long, value_name = "MILLIS", value_parser = parse_duration_in_millis)]
+    pub(crate) dedup_window_ms: Option<Duration>,
+
+    /// Close clients that haven't finished their SOCKS/HTTP handshake
+    /// within SECONDS after connected.
+    #[arg(long, value_name = "SECONDS", default_value = "30", value_parser = parse_duration_in_seconds)]
+    pub(crate) client_handshake_timeout: Duration,
+
+    /// Log a warning every minute when client tasks are more than FACTOR
+    /// times of alive connections (and at least 64), indicating leaked
+    /// tasks. Those tasks are listed by GET /debug/tasks on the web
+    /// console with the bearer token of --status-token. 0 to disable.
+    #[arg(long, value_name = "FACTOR", default_value = "4", value_parser = parse_non_negative)]
+    pub(crate) task_leak_factor: f64,
+
+    /// Probe a server right before it's used if its last probe is older
+    /// than SECONDS, without delaying the connection. Useful with a long
+    /// --probe interval.
+    #[arg(long, value_name = "SECONDS", value_parser = parse_duration_in_seconds)]
+    pub(crate) probe_on_demand: Option<Duration>,
+
+    /// Log an error (and set systemd status) once less than N servers are
+    /// healthy after a probe round. It's cleared after 2 healthy rounds.
+    #[arg(long, value_name = "N")]
+    pub(crate) min_healthy: Option<usize>,
+
+    /// What to do with new connections if --min-healthy is not satisfied.
+    #[arg(long, value_name = "ACTION", default_value = "log")]
+    pub(crate) min_healthy_action: MinHealthyAction,
+
+    /// Score subtracted from servers for each `prefer` policy rule they
+    /// meet, when ordering candidates for a connection.
+    #[arg(long, value_name = "SCORE", default_value_t = 1000)]
+    pub(crate) prefer_bonus: i32,
+
+    /// Exclude servers with score above N from candidates, as if they are
+    /// down.
+    #[arg(long, value_name = "N", allow_hyphen_values = true)]
+    pub(crate) score_max: Option<i32>,
+
+    /// Raise scores below N to N, e.g. to bound negative `score base`.
+    #[arg(long, value_name = "N", allow_hyphen_values = true)]
+    pub(crate) score_min: Option<i32>,
+
     #[command(subcommand)]
     pub(crate) command: Option<Commands>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum MinHealthyAction {
+    /// Only log it, keep connecting as usual
+    Log,
+    /// Reject new connections that have only unhealthy servers to go
+    RejectNew,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ProbeLogMode {
+    /// Log each probe result (on debug level)
+    Every,
+    /// Log only changes of server state or score
+    StateChange,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum HostnamePrivacyMode {
+    /// Keep host names verbatim
+    Off,
+    /// Keep only the registrable domain
+    Truncate,
+    /// Replace with a keyed hash
+    Hash,
+}
+
+impl From<HostnamePrivacyMode> for HostnamePrivacy {
+    fn from(mode: HostnamePrivacyMode) -> Self {
+        match mode {
+            HostnamePrivacyMode::Off => Self::Off,
+            HostnamePrivacyMode::Truncate => Self::Truncate,
+            HostnamePrivacyMode::Hash => Self::Hash,
+        }
+    }
+}
+
 #[derive(Debug, Subcommand)]
 pub(crate) enum Commands {
     /// Load & check configure and then exit
@@ -126,6 +599,20 @@ pub(crate) enum Commands {
         #[command(subcommand)]
         command: PolicyCommands,
     },
+
+    /// Route requests from a file with the policy and servers, print
+    /// how they would be routed, then exit
+    Simulate {
+        /// Policy ruleset to simulate, the configured one if not set
+        #[arg(long, value_name = "POLICY-FILE")]
+        policy: Option<PathBuf>,
+        /// Lines of `<listen-port> <dest-host> <dest-port>`, `-` for stdin
+        #[arg(long, value_name = "FILE")]
+        input: PathBuf,
+        /// Compare with this policy ruleset, list requests routed differently
+        #[arg(long, value_name = "POLICY-FILE")]
+        diff: Option<PathBuf>,
+    },
 }
 
 #[derive(Debug, Subcommand)]
@@ -141,10 +628,162 @@ pub(crate) enum PolicyCommands {
     },
 }
 
+/// Plain number in seconds, or with units like `1m30s`.
 fn parse_duration_in_seconds(s: &str) -> Result<Duration, String> {
-    s.parse()
-        .map_err(|_| format!("`{}` isn't a number", s))
-        .map(Duration::from_secs)
+    parse_duration_or_in(s, Duration::from_secs(1)).map_err(|err| err.to_string())
+}
+
+fn parse_ratio(s: &str) -> Result<f64, String> {
+    match s.parse() {
+        Ok(n) if (0.0..=1.0).contains(&n) => Ok(n),
+        _ => Err(format!("`{}` isn't a number between 0 and 1", s)),
+    }
+}
+
+fn parse_non_negative(s: &str) -> Result<f64, String> {
+    match s.parse() {
+        Ok(n) if n >= 0.0 => Ok(n),
+        _ => Err(format!("`{}` isn't a non-negative number", s)),
+    }
+}
+
+/// Positive, plain number in milliseconds, or with units like `1s`.
+fn parse_duration_in_millis(s: &str) -> Result<Duration, String> {
+    match parse_duration_or_in(s, Duration::from_millis(1)) {
+        Ok(t) if t.is_zero() => Err(format!("`{}` isn't a positive duration", s)),
+        result => result.map_err(|err| err.to_string()),
+    }
+}
+
+fn parse_ip_prefix(s: &str) -> Result<(IpAddr, u8), String> {
+    ip_prefix(s).ok_or_else(|| format!("`{}` isn't a valid CIDR", s))
+}
+
+/// See `--n-parallel`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NParallel {
+    Fixed(usize),
+    /// Up to N by destination history, see `AdaptiveParallel`.
+    Auto(usize),
+}
+
+impl NParallel {
+    pub(crate) fn max(self) -> usize {
+        match self {
+            Self::Fixed(n) | Self::Auto(n) => n,
+        }
+    }
+}
+
+fn parse_n_parallel(s: &str) -> Result<NParallel, String> {
+    let err = || format!("`{}` isn't a number, `auto`, or `auto:N`", s);
+    match s.strip_prefix("auto") {
+        Some("") => Ok(NParallel::Auto(3)),
+        Some(n) => match n.strip_prefix(':').map(str::parse) {
+            Some(Ok(n)) if n > 1 => Ok(NParallel::Auto(n)),
+            _ => Err(err()),
+        },
+        None => s.parse().map(NParallel::Fixed).map_err(|_| err()),
+    }
+}
+
+fn parse_port_tenant(s: &str) -> Result<(u16, String), String> {
+    let (port, tenant) = s
+        .split_once('=')
+        .ok_or_else(|| format!("`{}` isn't in form of PORT=TENANT", s))?;
+    let port = port
+        .parse()
+        .map_err(|_| format!("`{}` isn't a valid port", port))?;
+    if !is_cap_name(tenant) {
+        return Err(format!("`{}` isn't a valid capability name", tenant));
+    }
+    Ok((port, tenant.into()))
+}
+
+#[cfg(feature = "web_console")]
+fn parse_tenant_token(s: &str) -> Result<(String, String), String> {
+    let (tenant, token) = s
+        .split_once('=')
+        .ok_or_else(|| format!("`{}` isn't in form of TENANT=TOKEN", s))?;
+    if !is_cap_name(tenant) {
+        return Err(format!("`{}` isn't a valid capability name", tenant));
+    }
+    if token.is_empty() {
+        return Err("empty token".into());
+    }
+    Ok((tenant.into(), token.into()))
+}
+
+impl CliArgs {
+    /// Tenant served on the listen port, see `--port-tenant`.
+    pub(crate) fn port_tenant(&self, port: u16) -> Option<&str> {
+        self.port_tenant
+            .iter()
+            .find(|(p, _)| *p == port)
+            .map(|(_, tenant)| tenant.as_str())
+    }
+
+    /// Whether connections from `ip` may be health checks, see
+    /// `--health-check-source`.
+    pub(crate) fn is_health_check_source(&self, ip: IpAddr) -> bool {
+        self.health_check_source
+            .iter()
+            .any(|prefix| prefix_contains(*prefix, ip))
+    }
+
+    pub(crate) fn inbound_options(&self) -> InboundOptions {
+        InboundOptions {
+            keep_ipv4_mapped: self.keep_ipv4_mapped,
+            raw_tls_port: self.accept_raw_tls.then_some(self.raw_tls_port),
+            http_connect: self.accept_http_connect,
+            client_hints: self.allow_client_hints,
+            socks_username: self.socks_pin_by_username,
+        }
+    }
+
+    pub(crate) fn tcp_options(&self) -> TcpOptions {
+        TcpOptions {
+            nodelay: !self.no_nodelay,
+            notsent_lowat: self.notsent_lowat,
+            quickack: self.quickack,
+            mptcp: self.upstream_mptcp,
+            fastopen: self.upstream_tfo,
+        }
+    }
+
+    pub(crate) fn score_limits(&self) -> ScoreLimits {
+        ScoreLimits {
+            min: self.score_min,
+            max: self.score_max,
+        }
+    }
+
+    pub(crate) fn bulk_threshold(&self) -> Option<BulkThreshold> {
+        if self.bulk_bytes.is_none() && self.bulk_secs.is_none() {
+            return None;
+        }
+        Some(BulkThreshold {
+            bytes: self.bulk_bytes.unwrap_or(usize::MAX),
+            duration: self.bulk_secs.unwrap_or(Duration::MAX),
+        })
+    }
+
+    /// Loops checked by the systemd watchdog, with their thresholds.
+    #[cfg_attr(not(all(feature = "systemd", target_os = "linux")), allow(dead_code))]
+    pub(crate) fn watchdog_thresholds(&self) -> Vec<(LivenessSignal, Duration)> {
+        let mut thresholds = vec![];
+        if !self.watchdog_accept_stale.is_zero() {
+            thresholds.push((LivenessSignal::Accept, self.watchdog_accept_stale));
+        }
+        let probe = self.probe * 4 + Duration::from_secs(60);
+        match self.watchdog_probe_stale {
+            _ if self.probe.is_zero() => (),
+            None => thresholds.push((LivenessSignal::Probe, probe)),
+            Some(stale) if stale.is_zero() => (),
+            Some(stale) => thresholds.push((LivenessSignal::Probe, stale)),
+        }
+        thresholds
+    }
 }
 
 fn parse_socket_addr_default_on_localhost(addr: &str) -> Result<SocketAddr, String> {
@@ -161,3 +800,89 @@ fn verify_cli() {
     use clap::CommandFactory;
     CliArgs::command().debug_assert()
 }
+
+#[test]
+fn test_parse_cli_durations() {
+    let args = CliArgs::parse_from(["moproxy", "-p0", "-i", "1m30s", "--max-wait", "500ms"]);
+    assert_eq!(Duration::from_secs(90), args.probe);
+    assert_eq!(Duration::from_millis(500), args.max_wait);
+    let args = CliArgs::parse_from(["moproxy", "-p0", "-i", "10", "--dedup-window-ms", "1s"]);
+    assert_eq!(Duration::from_secs(10), args.probe);
+    assert_eq!(Some(Duration::from_secs(1)), args.dedup_window_ms);
+
+    let err = CliArgs::try_parse_from(["moproxy", "-p0", "--max-wait", "4x"]).unwrap_err();
+    assert!(
+        err.to_string().contains("`4x` isn't a valid duration"),
+        "{}",
+        err
+    );
+    assert!(CliArgs::try_parse_from(["moproxy", "-p0", "--dedup-window-ms", "0s"]).is_err());
+}
+
+#[test]
+fn test_parse_port_tenant() {
+    assert_eq!(
+        Ok((8081, "team-a".into())),
+        parse_port_tenant("8081=team-a")
+    );
+    assert!(parse_port_tenant("8081").is_err());
+    assert!(parse_port_tenant("x=team-a").is_err());
+    assert!(parse_port_tenant("8081=team a").is_err());
+    let args = CliArgs::parse_from([
+        "moproxy",
+        "-p0",
+        "--port-tenant",
+        "8081=team-a",
+        "--status-token",
+        "secret",
+    ]);
+    assert_eq!(Some("team-a"), args.port_tenant(8081));
+    assert_eq!(None, args.port_tenant(8082));
+    #[cfg(feature = "web_console")]
+    {
+        let err = CliArgs::try_parse_from(["moproxy", "-p0", "--port-tenant", "8081=team-a"]);
+        assert!(err.unwrap_err().to_string().contains("--status-token"));
+        assert_eq!(
+            Ok(("team-a".into(), "t=k".into())),
+            parse_tenant_token("team-a=t=k")
+        );
+        assert!(parse_tenant_token("team-a=").is_err());
+        assert!(parse_tenant_token("team a=t").is_err());
+    }
+}
+
+#[test]
+fn test_health_check_source() {
+    let args = CliArgs::parse_from([
+        "moproxy",
+        "-p0",
+        "--health-check-source",
+        "10.0.0.0/8",
+        "--health-check-source",
+        "2001:db8::1",
+    ]);
+    let check = |ip: &str| args.is_health_check_source(ip.parse().unwrap());
+    assert!(check("10.1.2.3"));
+    assert!(check("::ffff:10.1.2.3"));
+    assert!(!check("11.0.0.1"));
+    assert!(check("2001:db8::1"));
+    assert!(!check("2001:db8::2"));
+    assert!(prefix_contains(
+        ([0, 0, 0, 0].into(), 0),
+        [192, 0, 2, 1].into()
+    ));
+    assert!(parse_ip_prefix("10.0.0.0/33").is_err());
+}
+
+#[test]
+fn test_parse_n_parallel() {
+    assert_eq!(Ok(NParallel::Fixed(0)), parse_n_parallel("0"));
+    assert_eq!(Ok(NParallel::Fixed(2)), parse_n_parallel("2"));
+    assert_eq!(Ok(NParallel::Auto(3)), parse_n_parallel("auto"));
+    assert_eq!(Ok(NParallel::Auto(5)), parse_n_parallel("auto:5"));
+    for s in ["auto:1", "auto:", "auto5", "-1", "many"] {
+        assert!(parse_n_parallel(s).is_err(), "{}", s);
+    }
+    let args = CliArgs::parse_from(["moproxy", "-p0"]);
+    assert_eq!(NParallel::Fixed(0), args.n_parallel);
+}
@@ -6,106 +6,272 @@ use std::{
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
+    time::Duration,
 };
-use tokio::{net::TcpStream, time::timeout};
-use tracing::{info, instrument};
+use tokio::{
+    io::AsyncWriteExt,
+    time::{sleep, timeout, Instant},
+};
+use tracing::{debug, info, instrument};
 
-use crate::proxy::{Destination, ProxyServer};
+use crate::proxy::{
+    error::HandshakeError, stream::ProxyStream, Destination, ProxyServer, UserPassAuthCredential,
+};
 
+/// Shared by all servers tried, and their retries.
 #[derive(Debug, Clone)]
 struct Request {
     dest: Destination,
     pending_data: Option<Bytes>,
     wait_response: bool,
+    /// Override `max_wait` of the server.
+    max_wait: Option<Duration>,
+    /// Override the credential of the server.
+    auth: Option<UserPassAuthCredential>,
 }
 
-#[instrument(skip_all, fields(proxy = %server.tag))]
-async fn try_connect(request: Request, server: Arc<ProxyServer>) -> io::Result<TcpStream> {
-    let max_wait = server.max_wait();
-    // waiting for proxy server connected
-    let stream = timeout(
-        max_wait,
-        server.connect(&request.dest, request.pending_data),
-    )
-    .await??;
+#[instrument(skip_all, fields(proxy = %server.tag()))]
+async fn try_connect(request: Arc<Request>, server: Arc<ProxyServer>) -> io::Result<ProxyStream> {
+    let max_wait = request.max_wait.unwrap_or_else(|| server.max_wait());
+    let (early_data, late_data) = match server.no_early_payload() {
+        false => (request.pending_data.clone(), None),
+        true => (None, request.pending_data.clone()),
+    };
+    // waiting for handshake permits then proxy server connected
+    let mut stream = timeout(max_wait, async {
+        let _permit = server.handshake_permit().await;
+        server
+            .connect_with_auth(&request.dest, early_data, request.auth.as_ref())
+            .await
+    })
+    .await?
+    .map_err(|err| {
+        if let Some(handshake_err) = HandshakeError::from_io(&err) {
+            debug!(
+                kind = handshake_err.kind(),
+                "Handshake failed: {}", handshake_err
+            );
+            server.add_handshake_error(handshake_err);
+        }
+        err
+    })?;
+    if let Some(data) = late_data {
+        timeout(max_wait, stream.write_all(&data)).await??;
+    }
 
     // waiting for response data
     if request.wait_response {
+        let handshaked_at = Instant::now();
         let mut buf = [0u8; 4];
         let len = timeout(max_wait, stream.peek(&mut buf)).await??;
         if len == 0 {
             return Err(io::Error::new(ErrorKind::UnexpectedEof, "no response data"));
         }
+        server.add_ttfb(handshaked_at.elapsed());
     }
     Ok(stream)
 }
 
-type PinnedConnectFuture = Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send>>;
+type PinnedConnectFuture = Pin<Box<dyn Future<Output = io::Result<ProxyStream>> + Send>>;
+
+/// Wait before retry connecting to the same server.
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Whether it worths to retry connect after this error.
+fn is_transient_error(err: &io::Error) -> bool {
+    if matches!(
+        err.kind(),
+        ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::Interrupted
+    ) {
+        return true;
+    }
+    // ErrorKind::{Host,Network}Unreachable are not stable until Rust 1.83
+    #[cfg(target_os = "linux")]
+    if let Some(code) = err.raw_os_error() {
+        return code == libc::EHOSTUNREACH || code == libc::ENETUNREACH;
+    }
+    false
+}
 
 /// Try to connect one of the proxy servers.
 /// Pick `parallel_n` servers from `queue` to `connecting` and wait for
 /// connect. Once any of them connected, move that to `reading` and wait
-/// for read respone. Once any of handshakings done, return it and cancel
-/// others.
+/// for read respone. Once any of handshakings done, return it and let
+/// others finish their handshakes in background then close them.
+/// Servers failed with transient errors are retried up to `retries` times.
 pub struct TryConnectAll {
-    request: Request,
+    request: Arc<Request>,
     parallel_n: usize,
+    retries: usize,
     standby: VecDeque<Arc<ProxyServer>>,
-    connects: VecDeque<(Arc<ProxyServer>, PinnedConnectFuture)>,
+    connects: VecDeque<Connecting>,
     last_error: Option<io::Error>,
 }
 
+struct Connecting {
+    server: Arc<ProxyServer>,
+    /// Number of retries done.
+    retried: usize,
+    started: Instant,
+    conn: PinnedConnectFuture,
+}
+
+impl Connecting {
+    /// Finish the handshake of a race loser, record it, then close it
+    /// gracefully instead of leaving half-open handshakes on upstream.
+    async fn finish_lost(self) {
+        match self.conn.await {
+            Ok(mut stream) => {
+                self.server.add_race_lost(Some(self.started.elapsed()));
+                if let Err(err) = stream.shutdown().await {
+                    debug!(proxy = %self.server.tag(), ?err, "Fail to close race loser");
+                }
+            }
+            Err(err) => {
+                debug!(proxy = %self.server.tag(), ?err, "Race loser failed");
+                self.server.add_race_lost(None);
+            }
+        }
+    }
+}
+
+/// Result of `TryConnectAll`.
+#[derive(Debug)]
+pub struct Connected {
+    pub server: Arc<ProxyServer>,
+    pub stream: ProxyStream,
+    /// Connected after retry.
+    pub retried: bool,
+    /// Servers still connecting when this one done, losers of the race.
+    pub losers: Vec<Arc<ProxyServer>>,
+}
+
 pub fn try_connect_all(
     dest: &Destination,
     servers: Vec<Arc<ProxyServer>>,
     parallel_n: usize,
     wait_response: bool,
     pending_data: Option<Bytes>,
+    retries: usize,
+    max_wait: Option<Duration>,
 ) -> TryConnectAll {
     let parallel_n = parallel_n.clamp(1, if wait_response { servers.len() } else { 1 });
     let servers = servers.into_iter().collect();
-    let request = Request {
+    let request = Arc::new(Request {
         dest: dest.clone(),
         pending_data,
         wait_response,
-    };
+        max_wait,
+        auth: None,
+    });
     TryConnectAll {
         request,
         parallel_n,
+        retries,
         standby: servers,
         connects: VecDeque::with_capacity(parallel_n),
         last_error: None,
     }
 }
 
+impl TryConnectAll {
+    /// Whether `server` must not connect along with others.
+    fn is_exclusive(&self, server: &ProxyServer) -> bool {
+        self.request.pending_data.is_some() && server.no_early_payload()
+    }
+
+    /// Whether to pick the next server from `standby` to connect.
+    fn should_start_next(&self) -> bool {
+        let next = match self.standby.front() {
+            Some(next) if self.connects.len() < self.parallel_n => next,
+            _ => return false,
+        };
+        self.connects.is_empty()
+            || !(self.is_exclusive(next)
+                || self.connects.iter().any(|c| self.is_exclusive(&c.server)))
+    }
+
+    /// Connect with `auth` instead of credentials of servers, if given.
+    pub fn with_auth(mut self, auth: Option<UserPassAuthCredential>) -> Self {
+        // Not cloned as it's called before polling
+        Arc::make_mut(&mut self.request).auth = auth;
+        self
+    }
+}
+
 impl Future for TryConnectAll {
-    type Output = io::Result<(Arc<ProxyServer>, TcpStream)>;
+    type Output = io::Result<Connected>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
         loop {
             // if current connections less than parallel_n,
             // pick servers from queue to connect.
-            while !self.standby.is_empty() && self.connects.len() < self.parallel_n {
+            while self.should_start_next() {
                 let server = self.standby.pop_front().unwrap();
                 let conn = try_connect(self.request.clone(), server.clone());
-                self.connects.push_back((server, Box::pin(conn)));
+                self.connects.push_back(Connecting {
+                    server,
+                    retried: 0,
+                    started: Instant::now(),
+                    conn: Box::pin(conn),
+                });
             }
 
             // poll all connects
             let mut i = 0;
             while i < self.connects.len() {
-                let (server, conn) = &mut self.connects[i];
-                match conn.as_mut().poll(cx) {
+                let retries = self.retries;
+                let request = self.request.clone();
+                let connecting = &mut self.connects[i];
+                let server = &connecting.server;
+                match connecting.conn.as_mut().poll(cx) {
+                    // transient error, retry it later.
+                    Poll::Ready(Err(err))
+                        if connecting.retried < retries && is_transient_error(&err) =>
+                    {
+                        debug!(proxy = %server.tag(), ?err, "Retry connecting upstream proxy");
+                        server.update_stats_conn_retry();
+                        let server = server.clone();
+                        connecting.retried += 1;
+                        connecting.conn = Box::pin(async move {
+                            sleep(RETRY_DELAY).await;
+                            try_connect(request, server).await
+                        });
+                        // poll it again to register the timer
+                    }
                     // error, stop trying, drop it.
                     Poll::Ready(Err(err)) => {
-                        info!(proxy = %server.tag, ?err, "Failed to connect upstream proxy");
+                        info!(proxy = %server.tag(), ?err, "Failed to connect upstream proxy");
                         self.last_error = Some(err);
                         drop(self.connects.remove(i));
                     }
                     // not ready, keep here, poll next one.
                     Poll::Pending => i += 1,
                     // ready, return it.
-                    Poll::Ready(Ok(conn)) => return Poll::Ready(Ok((server.clone(), conn))),
+                    Poll::Ready(Ok(stream)) => {
+                        let winner = self.connects.remove(i).unwrap();
+                        if !self.connects.is_empty() {
+                            winner.server.add_race_won();
+                        }
+                        let losers = self
+                            .connects
+                            .drain(..)
+                            .map(|loser| {
+                                let server = loser.server.clone();
+                                tokio::spawn(loser.finish_lost());
+                                server
+                            })
+                            .collect();
+                        return Poll::Ready(Ok(Connected {
+                            server: winner.server,
+                            stream,
+                            retried: winner.retried > 0,
+                            losers,
+                        }));
+                    }
                 }
             }
 
@@ -118,9 +284,246 @@ impl Future for TryConnectAll {
             }
 
             // if not need to connect standby server, wait for events.
-            if self.connects.len() >= self.parallel_n || self.standby.is_empty() {
+            if !self.should_start_next() {
                 return Poll::Pending;
             }
         }
     }
 }
+
+#[tokio::test]
+async fn test_try_connect_all_retry() {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    // Reserve a port, nothing listen on it for now
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let server = Arc::new(ProxyServer::test_http(addr, false, Duration::from_secs(1)));
+    let dest: Destination = ("example.com", 443).into();
+
+    // Refused, no retry
+    let result = try_connect_all(&dest, vec![server.clone()], 1, false, None, 0, None).await;
+    assert_eq!(ErrorKind::ConnectionRefused, result.unwrap_err().kind());
+    assert_eq!(0, server.status_snapshot().conn_retry);
+
+    // Refused, then succeed on retry
+    let connect = try_connect_all(&dest, vec![server.clone()], 1, false, None, 3, None);
+    let listen = async {
+        sleep(RETRY_DELAY / 2).await;
+        let listener = TcpListener::bind(addr).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 1024];
+        let _ = stream.read(&mut buf).await.unwrap();
+        stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await
+            .unwrap();
+        stream
+    };
+    let (result, _stream) = tokio::join!(connect, listen);
+    let retried = result.unwrap().retried;
+    assert!(retried);
+    assert_eq!(1, server.status_snapshot().conn_retry);
+}
+
+#[tokio::test]
+async fn test_try_connect_all_timeout_override() {
+    use tokio::{net::TcpListener, time::Instant};
+
+    // Accept connections but never response
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Arc::new(ProxyServer::test_http(addr, false, Duration::from_secs(60)));
+    let dest: Destination = ("example.com", 443).into();
+    let max_wait = Some(Duration::from_millis(100));
+    let start = Instant::now();
+    let result = try_connect_all(&dest, vec![server], 1, false, None, 0, max_wait).await;
+    assert_eq!(ErrorKind::TimedOut, result.unwrap_err().kind());
+    assert!(start.elapsed() < Duration::from_secs(10));
+    drop(listener);
+}
+
+#[tokio::test]
+async fn test_try_connect_all_handshake_limit() {
+    use crate::proxy::HandshakeLimit;
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Arc::new(ProxyServer::test_http(addr, false, Duration::from_secs(60)));
+    server.update_config(|config| config.handshake_limit = Some(HandshakeLimit::new(1)));
+    let permit = server.handshake_permit().await;
+    assert_eq!(1, server.status_snapshot().handshakes);
+
+    // The second one waits until timed out, w/o connecting
+    let dest: Destination = ("example.com", 443).into();
+    let max_wait = Some(Duration::from_millis(50));
+    let result = try_connect_all(&dest, vec![server.clone()], 1, false, None, 0, max_wait).await;
+    assert_eq!(ErrorKind::TimedOut, result.unwrap_err().kind());
+    let accept = timeout(Duration::from_millis(10), listener.accept()).await;
+    assert!(accept.is_err());
+
+    // Then go on once the first one done
+    let waiting = tokio::spawn({
+        let server = server.clone();
+        async move {
+            let _permit = server.handshake_permit().await;
+        }
+    });
+    sleep(Duration::from_millis(10)).await;
+    assert!(!waiting.is_finished());
+    drop(permit);
+    waiting.await.unwrap();
+    assert_eq!(0, server.status_snapshot().handshakes);
+}
+
+#[tokio::test]
+async fn test_try_connect_all_no_early_payload() {
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    let http_server = |addr| Arc::new(ProxyServer::test_http(addr, true, Duration::from_secs(5)));
+    // Read the CONNECT request, nothing must follow before the response
+    async fn accept_strict(listener: &TcpListener) -> tokio::net::TcpStream {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; 1024];
+        let mut len = 0;
+        while !buf[..len].ends_with(b"\r\n\r\n") {
+            len += stream.read(&mut buf[len..]).await.unwrap();
+        }
+        let early = timeout(Duration::from_millis(50), stream.read(&mut buf)).await;
+        assert!(early.is_err(), "payload sent before handshake done");
+        stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await
+            .unwrap();
+        stream
+    }
+
+    let listener_a = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listener_b = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_a = http_server(listener_a.local_addr().unwrap());
+    let server_b = http_server(listener_b.local_addr().unwrap());
+    server_a.update_config(|config| config.no_early_payload = true);
+    let dest: Destination = ("example.com", 443).into();
+    let payload = Bytes::from_static(b"hello");
+
+    let connect = try_connect_all(
+        &dest,
+        vec![server_a.clone(), server_b],
+        2,
+        true,
+        Some(payload.clone()),
+        0,
+        None,
+    );
+    let upstreams = async {
+        // `a` (then failed) is tried alone without racing with `b`
+        let (stream, _) = listener_a.accept().await.unwrap();
+        let racing = timeout(Duration::from_millis(50), listener_b.accept()).await;
+        assert!(racing.is_err());
+        drop(stream);
+
+        // `b` gets the payload along with the request
+        let (mut stream, _) = listener_b.accept().await.unwrap();
+        let mut buf = [0u8; 1024];
+        let mut len = 0;
+        while !buf[..len].ends_with(b"hello") {
+            len += stream.read(&mut buf[len..]).await.unwrap();
+        }
+        stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\nworld")
+            .await
+            .unwrap();
+        stream
+    };
+    let (result, _stream) = tokio::join!(connect, upstreams);
+    let server = result.unwrap().server;
+    assert_ne!(server_a.tag(), server.tag());
+
+    // `a` alone gets the payload after the handshake
+    let connect = try_connect_all(
+        &dest,
+        vec![server_a.clone()],
+        1,
+        true,
+        Some(payload),
+        0,
+        None,
+    );
+    let upstream = async {
+        let mut stream = accept_strict(&listener_a).await;
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"hello", &buf);
+        stream.write_all(b"world").await.unwrap();
+        stream
+    };
+    let (result, _stream) = tokio::join!(connect, upstream);
+    let server = result.unwrap().server;
+    assert_eq!(server_a.tag(), server.tag());
+}
+
+#[tokio::test]
+async fn test_try_connect_all_race() {
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    let http_server = |addr| Arc::new(ProxyServer::test_http(addr, false, Duration::from_secs(5)));
+    // Response after `delay`, then expect to be closed
+    async fn upstream(listener: TcpListener, delay: Duration) -> usize {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 1024];
+        let _ = stream.read(&mut buf).await.unwrap();
+        sleep(delay).await;
+        stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\nhi")
+            .await
+            .unwrap();
+        stream.read(&mut buf).await.unwrap()
+    }
+    let listener_fast = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listener_slow = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let fast = http_server(listener_fast.local_addr().unwrap());
+    let slow = http_server(listener_slow.local_addr().unwrap());
+    let dest: Destination = ("example.com", 443).into();
+
+    let slow_upstream = tokio::spawn(upstream(listener_slow, Duration::from_millis(200)));
+    let fast_upstream = tokio::spawn(upstream(listener_fast, Duration::ZERO));
+    let servers = vec![slow.clone(), fast.clone()];
+    let connected = try_connect_all(&dest, servers, 2, true, None, 0, None)
+        .await
+        .unwrap();
+    assert_eq!(fast.tag(), connected.server.tag());
+    assert_eq!(1, connected.losers.len());
+    assert!(Arc::ptr_eq(&slow, &connected.losers[0]));
+    assert_eq!(Some(1.0), fast.status_snapshot().race.win_ratio());
+
+    // The loser finishes its handshake, then get closed
+    let read = timeout(Duration::from_secs(2), slow_upstream).await;
+    assert_eq!(0, read.unwrap().unwrap());
+    let race = slow.status_snapshot().race;
+    assert_eq!(Some(0.0), race.win_ratio());
+    assert!(race.lost_handshake_mean().unwrap() >= Duration::from_millis(200));
+    drop(connected);
+    fast_upstream.await.unwrap();
+}
+
+#[test]
+fn test_is_transient_error() {
+    assert!(is_transient_error(&ErrorKind::ConnectionRefused.into()));
+    assert!(!is_transient_error(&ErrorKind::PermissionDenied.into()));
+    #[cfg(target_os = "linux")]
+    {
+        assert!(is_transient_error(&io::Error::from_raw_os_error(
+            libc::EHOSTUNREACH
+        )));
+        assert!(!is_transient_error(&io::Error::from_raw_os_error(
+            libc::EACCES
+        )));
+    }
+}
@@ -1,35 +1,462 @@
 mod connect;
-mod tls_parser;
+pub mod reject_page;
+pub(crate) mod tls_parser;
+pub(crate) mod x509;
 use bytes::{Bytes, BytesMut};
 use flexstr::SharedStr;
+use parking_lot::{const_mutex, Mutex};
+use serde::Serialize;
 use std::{
     borrow::Cow,
-    io,
-    net::{IpAddr, SocketAddr},
-    sync::Arc,
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
-    time::timeout,
+    time::{timeout, timeout_at, Instant},
 };
 use tracing::{debug, info, instrument, warn};
 
 #[cfg(target_os = "linux")]
 use crate::linux::tcp::TcpStreamExt;
 use crate::{
-    client::connect::try_connect_all,
-    policy::RequestFeatures,
-    proxy::{copy::pipe, Traffic},
-    proxy::{Address, Destination, ProxyServer},
+    client::{
+        connect::try_connect_all,
+        reject_page::{RejectPage, REJECT_PAGES},
+        tls_parser::TlsFingerprint,
+    },
+    monitor::{destination_key, ACCOUNTING, DESTINATIONS},
+    policy::{parser::is_cap_name, RequestFeatures},
+    privacy::HOSTNAMES,
+    proxy::{
+        buffers::{BufferLease, BUFFERS},
+        copy::pipe,
+        resolver::RESOLVER,
+        Traffic,
+    },
+    proxy::{
+        check_tag, intern::DOMAINS, stream::ProxyStream, Address, Destination, IpFamily,
+        ProxyServer, UserPassAuthCredential,
+    },
 };
 
 #[derive(Debug, Default)]
 pub struct TlsData {
-    pending_data: Option<Bytes>,
     has_full_tls_hello: bool,
+    /// Set if the data conclusively not a TLS handshake.
+    not_tls: bool,
     pub sni: Option<SharedStr>,
+    /// Set if `NewClient::fingerprint_tls` is on.
+    pub fingerprint: Option<TlsFingerprint>,
+}
+
+/// Counters of TLS ClientHello sniffing, see `TLS_SNIFF_STATS`.
+#[derive(Debug)]
+pub struct TlsSniffStats {
+    hello_with_sni: AtomicUsize,
+    hello_without_sni: AtomicUsize,
+    parse_error: AtomicUsize,
+    not_tls: AtomicUsize,
+    timed_out: AtomicUsize,
+    early_data: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct TlsSniffCounters {
+    pub hello_with_sni: usize,
+    pub hello_without_sni: usize,
+    pub parse_error: usize,
+    /// Data failed TLS record validation, the sniff returned early.
+    pub not_tls: usize,
+    pub timed_out: usize,
+    pub early_data: usize,
+}
+
+/// Statistics of all `NewClient::retrieve_dest_from_sni()` calls.
+pub static TLS_SNIFF_STATS: TlsSniffStats = TlsSniffStats::new();
+
+impl TlsSniffStats {
+    const fn new() -> Self {
+        Self {
+            hello_with_sni: AtomicUsize::new(0),
+            hello_without_sni: AtomicUsize::new(0),
+            parse_error: AtomicUsize::new(0),
+            not_tls: AtomicUsize::new(0),
+            timed_out: AtomicUsize::new(0),
+            early_data: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn snapshot(&self) -> TlsSniffCounters {
+        TlsSniffCounters {
+            hello_with_sni: self.hello_with_sni.load(Ordering::Relaxed),
+            hello_without_sni: self.hello_without_sni.load(Ordering::Relaxed),
+            parse_error: self.parse_error.load(Ordering::Relaxed),
+            not_tls: self.not_tls.load(Ordering::Relaxed),
+            timed_out: self.timed_out.load(Ordering::Relaxed),
+            early_data: self.early_data.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Max number of distinct fingerprints kept in `TLS_FINGERPRINTS`.
+const MAX_TLS_FINGERPRINTS: usize = 64;
+
+/// Number of ClientHello seen with the same fingerprint on a listen port.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TlsFingerprintCount {
+    pub listen_port: u16,
+    /// See `TlsFingerprint::hash_hex()`.
+    pub hash: String,
+    pub count: usize,
+}
+
+/// Counters of TLS fingerprints, bounded by `MAX_TLS_FINGERPRINTS`.
+#[derive(Debug)]
+pub struct TlsFingerprintStats {
+    counts: Mutex<Vec<TlsFingerprintCount>>,
+}
+
+/// Statistics of fingerprints from `NewClient::retrieve_dest_from_sni()`.
+pub static TLS_FINGERPRINTS: TlsFingerprintStats = TlsFingerprintStats::new();
+
+impl TlsFingerprintStats {
+    const fn new() -> Self {
+        Self {
+            counts: const_mutex(Vec::new()),
+        }
+    }
+
+    /// Count a fingerprint. Once full, the least seen one is replaced.
+    fn add(&self, listen_port: u16, fingerprint: &TlsFingerprint) {
+        let hash = fingerprint.hash_hex();
+        let mut counts = self.counts.lock();
+        if let Some(item) = counts
+            .iter_mut()
+            .find(|c| c.listen_port == listen_port && c.hash == hash)
+        {
+            item.count += 1;
+            return;
+        }
+        let item = TlsFingerprintCount {
+            listen_port,
+            hash,
+            count: 1,
+        };
+        if counts.len() < MAX_TLS_FINGERPRINTS {
+            counts.push(item);
+        } else if let Some(least) = counts.iter_mut().min_by_key(|c| c.count) {
+            *least = item;
+        }
+    }
+
+    /// Return counters, the most seen first.
+    pub fn snapshot(&self) -> Vec<TlsFingerprintCount> {
+        let mut counts = self.counts.lock().clone();
+        counts.sort_by_key(|c| std::cmp::Reverse(c.count));
+        counts
+    }
+}
+
+fn incr(counter: &AtomicUsize) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Protocol of a non-NATed connection, guessed from its first byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InboundProto {
+    Socks5,
+    Tls,
+    HttpConnect,
+    Http,
+    Unknown,
+}
+
+impl InboundProto {
+    fn guess(first: u8) -> Self {
+        match first {
+            0x05 => Self::Socks5,
+            0x16 => Self::Tls,
+            b'C' => Self::HttpConnect,
+            b'G' | b'P' => Self::Http,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// Counters of non-NATed connections refused by `NewClient::accept()`, by
+/// the guessed protocol. See `INBOUND_REJECTS`.
+#[derive(Debug)]
+pub struct InboundRejectStats {
+    tls: AtomicUsize,
+    http: AtomicUsize,
+    unknown: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct InboundRejectCounters {
+    pub tls: usize,
+    pub http: usize,
+    pub unknown: usize,
+}
+
+/// Statistics of all `NewClient::accept()` calls.
+pub static INBOUND_REJECTS: InboundRejectStats = InboundRejectStats::new();
+
+impl InboundRejectStats {
+    const fn new() -> Self {
+        Self {
+            tls: AtomicUsize::new(0),
+            http: AtomicUsize::new(0),
+            unknown: AtomicUsize::new(0),
+        }
+    }
+
+    fn add(&self, proto: InboundProto) {
+        match proto {
+            InboundProto::Tls => incr(&self.tls),
+            InboundProto::HttpConnect | InboundProto::Http => incr(&self.http),
+            InboundProto::Unknown | InboundProto::Socks5 => incr(&self.unknown),
+        }
+    }
+
+    pub fn snapshot(&self) -> InboundRejectCounters {
+        InboundRejectCounters {
+            tls: self.tls.load(Ordering::Relaxed),
+            http: self.http.load(Ordering::Relaxed),
+            unknown: self.unknown.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Clients shut down (or reset) before their upstream connected, see
+/// `NewClient::connect_server()`. Not counted as failures of servers.
+#[derive(Debug)]
+pub struct ClientGoneStats {
+    before_connect: AtomicUsize,
+    during_connect: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ClientGoneCounters {
+    /// No upstream connection attempted.
+    pub before_connect: usize,
+    /// Connecting upstream aborted.
+    pub during_connect: usize,
+}
+
+pub static CLIENT_GONE_EARLY: ClientGoneStats = ClientGoneStats::new();
+
+impl ClientGoneStats {
+    const fn new() -> Self {
+        Self {
+            before_connect: AtomicUsize::new(0),
+            during_connect: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn snapshot(&self) -> ClientGoneCounters {
+        ClientGoneCounters {
+            before_connect: self.before_connect.load(Ordering::Relaxed),
+            during_connect: self.during_connect.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// SOCKSv5 clients failed on negotiating the method or authenticating.
+/// Converted into `io::Error` with itself as the inner error, like
+/// `HandshakeError` of upstreams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Socks5NegotiationError {
+    /// None of the offered methods is acceptable, replied `05 FF`.
+    NoAcceptableMethod,
+    /// No method offered at all, replied `05 FF`.
+    NoMethods,
+    /// Username/password subnegotiation of an unknown version.
+    AuthVersion,
+    /// Username is not UTF-8, replied the failure status.
+    AuthRejected,
+}
+
+impl Socks5NegotiationError {
+    /// Return the `Socks5NegotiationError` inside `err`, if any.
+    pub fn from_io(err: &io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for Socks5NegotiationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            Self::NoAcceptableMethod => "no acceptable auth method offered",
+            Self::NoMethods => "empty auth method list",
+            Self::AuthVersion => "unsupported auth version",
+            Self::AuthRejected => "non-UTF-8 username",
+        };
+        write!(f, "SOCKSv5: {}", msg)
+    }
+}
+
+impl std::error::Error for Socks5NegotiationError {}
+
+impl From<Socks5NegotiationError> for io::Error {
+    fn from(err: Socks5NegotiationError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+/// Counters of `Socks5NegotiationError`, see `SOCKS5_NEGOTIATION`.
+#[derive(Debug)]
+pub struct Socks5NegotiationStats {
+    no_acceptable_method: AtomicUsize,
+    no_methods: AtomicUsize,
+    auth_version: AtomicUsize,
+    auth_rejected: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Socks5NegotiationCounters {
+    pub no_acceptable_method: usize,
+    pub no_methods: usize,
+    pub auth_version: usize,
+    pub auth_rejected: usize,
+}
+
+/// Statistics of negotiation of all SOCKSv5 clients.
+pub static SOCKS5_NEGOTIATION: Socks5NegotiationStats = Socks5NegotiationStats::new();
+
+impl Socks5NegotiationStats {
+    const fn new() -> Self {
+        Self {
+            no_acceptable_method: AtomicUsize::new(0),
+            no_methods: AtomicUsize::new(0),
+            auth_version: AtomicUsize::new(0),
+            auth_rejected: AtomicUsize::new(0),
+        }
+    }
+
+    fn add(&self, err: Socks5NegotiationError) {
+        match err {
+            Socks5NegotiationError::NoAcceptableMethod => incr(&self.no_acceptable_method),
+            Socks5NegotiationError::NoMethods => incr(&self.no_methods),
+            Socks5NegotiationError::AuthVersion => incr(&self.auth_version),
+            Socks5NegotiationError::AuthRejected => incr(&self.auth_rejected),
+        }
+    }
+
+    pub fn snapshot(&self) -> Socks5NegotiationCounters {
+        Socks5NegotiationCounters {
+            no_acceptable_method: self.no_acceptable_method.load(Ordering::Relaxed),
+            no_methods: self.no_methods.load(Ordering::Relaxed),
+            auth_version: self.auth_version.load(Ordering::Relaxed),
+            auth_rejected: self.auth_rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Routing hints of HTTP CONNECT clients, from `X-Moproxy-Require` and
+/// `X-Moproxy-Server` headers. See `InboundOptions::client_hints`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientHints {
+    /// Capability required in addition to those of the policy.
+    pub require: Option<SharedStr>,
+    /// Tag of the server to pin, like username `tag:SERVER-TAG` of SOCKSv5.
+    pub server: Option<String>,
+}
+
+/// Counters of client hints accepted or ignored, see `CLIENT_HINTS`.
+#[derive(Debug)]
+pub struct ClientHintStats {
+    require: AtomicUsize,
+    server: AtomicUsize,
+    invalid: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ClientHintCounters {
+    pub require: usize,
+    pub server: usize,
+    /// Ignored for malformed values.
+    pub invalid: usize,
+}
+
+/// Statistics of hints of all HTTP CONNECT clients.
+pub static CLIENT_HINTS: ClientHintStats = ClientHintStats::new();
+
+impl ClientHintStats {
+    const fn new() -> Self {
+        Self {
+            require: AtomicUsize::new(0),
+            server: AtomicUsize::new(0),
+            invalid: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn snapshot(&self) -> ClientHintCounters {
+        ClientHintCounters {
+            require: self.require.load(Ordering::Relaxed),
+            server: self.server.load(Ordering::Relaxed),
+            invalid: self.invalid.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl ClientHints {
+    /// Parse hints from HTTP headers, ignore (and log) invalid ones.
+    fn from_headers(headers: &[httparse::Header]) -> Self {
+        let mut hints = Self::default();
+        for header in headers {
+            let is_require = header.name.eq_ignore_ascii_case("X-Moproxy-Require");
+            if !is_require && !header.name.eq_ignore_ascii_case("X-Moproxy-Server") {
+                continue;
+            }
+            let value = std::str::from_utf8(header.value).map(str::trim);
+            match value {
+                Ok(cap) if is_require && is_cap_name(cap) => {
+                    incr(&CLIENT_HINTS.require);
+                    hints.require = Some(cap.into());
+                }
+                Ok(tag) if !is_require && check_tag(tag).is_ok() => {
+                    incr(&CLIENT_HINTS.server);
+                    hints.server = Some(tag.into());
+                }
+                _ => {
+                    incr(&CLIENT_HINTS.invalid);
+                    info!(header = header.name, value = ?value, "invalid client hint ignored");
+                }
+            }
+        }
+        hints
+    }
+}
+
+/// How to accept connections that are not NATed, see `NewClient::accept()`.
+/// SOCKSv5 is always accepted.
+#[derive(Debug, Clone, Default)]
+pub struct InboundOptions {
+    pub keep_ipv4_mapped: bool,
+    /// Accept raw TLS, destined to the SNI host on this port, if set.
+    pub raw_tls_port: Option<u16>,
+    /// Accept HTTP CONNECT requests.
+    pub http_connect: bool,
+    /// Read `ClientHints` from headers of HTTP CONNECT requests.
+    pub client_hints: bool,
+    /// Accept username/password authentication of SOCKSv5, with any
+    /// credential, for `NewClient::username`.
+    pub socks_username: bool,
+}
+
+/// Protocol of the reply the client is waiting for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PendingReply {
+    Socks5,
+    Http,
 }
 
 #[derive(Debug)]
@@ -44,16 +471,74 @@ pub struct NewClient {
     /// Server's TCP port number.
     from_port: u16,
     pub tls: Option<TlsData>,
+    /// Override `max_wait` of servers when connecting, if set.
+    pub connect_timeout: Option<Duration>,
+    /// Override credentials of servers when connecting, if set.
+    pub upstream_auth: Option<UserPassAuthCredential>,
+    /// Dial addresses in this family first on direct connections, if set.
+    pub prefer_family: Option<IpFamily>,
+    /// Username sent by the SOCKSv5 client, if any.
+    pub username: Option<String>,
+    /// Hints sent by the HTTP CONNECT client, if enabled.
+    pub hints: ClientHints,
+    /// Set until the SOCKSv5 or HTTP CONNECT request is replied, on
+    /// connected or failed. `None` for redirected clients.
+    ///
+    /// Such clients may send data before the reply, even along with the
+    /// request. Until replied, that data is either left in the socket or
+    /// read into `replay`, which is sent to upstream before anything else
+    /// read from the client, so nothing got lost or reordered.
+    pending_reply: Option<PendingReply>,
+    /// IP address in SOCKSv5 success reply instead of the local address
+    /// of the upstream-facing socket, if set.
+    pub advertised_addr: Option<IpAddr>,
+    /// Error of the last failed attempt of connecting, for the reply.
+    last_error: Option<io::ErrorKind>,
+    /// Compute fingerprint of TLS ClientHello on sniffing.
+    pub fingerprint_tls: bool,
+    /// Data read from client but not forwarded yet, e.g. on sniffing TLS.
+    /// Sent in full to whichever upstream that finally connected.
+    replay: BytesMut,
+    /// Size of `replay` accounted in `BUFFERS`.
+    replay_lease: BufferLease,
+}
+
+/// Reply field of SOCKSv5 responses (RFC 1928).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Socks5Reply {
+    Succeeded = 0,
+    GeneralFailure = 1,
+    NotAllowed = 2,
+    HostUnreachable = 4,
+    ConnectionRefused = 5,
+}
+
+impl From<io::ErrorKind> for Socks5Reply {
+    fn from(kind: io::ErrorKind) -> Self {
+        match kind {
+            io::ErrorKind::ConnectionRefused => Self::ConnectionRefused,
+            io::ErrorKind::TimedOut => Self::HostUnreachable,
+            _ => Self::GeneralFailure,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct ConnectedClient {
     orig: NewClient,
-    right: TcpStream,
+    right: ProxyStream,
     server: Arc<ProxyServer>,
+    /// Connected after retry.
+    retried: bool,
+    /// Servers lost the race to `server`.
+    losers: Vec<Arc<ProxyServer>>,
+    /// When the upstream handshake finished, unset if the response has
+    /// been waited on connecting.
+    handshaked_at: Option<Instant>,
 }
 
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum FailedClient {
     Recoverable(NewClient),
     Unrecoverable(io::Error),
@@ -65,16 +550,28 @@ impl From<io::Error> for FailedClient {
     }
 }
 
+impl Socks5Reply {
+    /// Status line of the HTTP CONNECT response equivalent to this.
+    fn http_status(self) -> &'static str {
+        match self {
+            Self::Succeeded => "200 Connection established",
+            Self::NotAllowed => "403 Forbidden",
+            Self::HostUnreachable => "504 Gateway Timeout",
+            Self::GeneralFailure | Self::ConnectionRefused => "502 Bad Gateway",
+        }
+    }
+}
+
 fn error_invalid_input<T>(msg: &'static str) -> io::Result<T> {
     Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
 }
 
 trait SocketAddrExt {
-    fn normalize(&self) -> Cow<SocketAddr>;
+    fn normalize(&self) -> Cow<'_, SocketAddr>;
 }
 
 impl SocketAddrExt for SocketAddr {
-    fn normalize(&self) -> Cow<SocketAddr> {
+    fn normalize(&self) -> Cow<'_, SocketAddr> {
         match self {
             SocketAddr::V4(sock) => {
                 let addr = sock.ip().to_ipv6_mapped();
@@ -86,25 +583,97 @@ impl SocketAddrExt for SocketAddr {
     }
 }
 
+/// Build a SOCKSv5 reply with BND.ADDR and BND.PORT set to `bound`, or
+/// zeros if it's not set.
+fn socks5_reply(reply: Socks5Reply, bound: Option<SocketAddr>) -> Vec<u8> {
+    let mut buf = vec![5, reply as u8, 0];
+    let bound = bound.unwrap_or_else(|| (Ipv4Addr::UNSPECIFIED, 0).into());
+    match bound.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(0x01);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => {
+                buf.push(0x01);
+                buf.extend_from_slice(&ip.octets());
+            }
+            None => {
+                buf.push(0x04);
+                buf.extend_from_slice(&ip.octets());
+            }
+        },
+    }
+    buf.extend_from_slice(&bound.port().to_be_bytes());
+    buf
+}
+
+/// Prefix of SOCKSv5 usernames pinning the connection to a server.
+const PIN_TAG_PREFIX: &str = "tag:";
+/// How long `NewClient::refuse_not_allowed()` waits for each message.
+const REFUSE_WAIT: Duration = Duration::from_secs(1);
+
+/// Read the username/password sub-negotiation (RFC 1929) and accept it
+/// regardless of the credential. Return the username.
+async fn accept_socks5_user_pass(client: &mut TcpStream) -> io::Result<String> {
+    if client.read_u8().await? != 0x01 {
+        // No way to tell the client, the reply has the version field too
+        return Err(Socks5NegotiationError::AuthVersion.into());
+    }
+    let len = client.read_u8().await? as usize;
+    let mut username = vec![0u8; len];
+    client.read_exact(&mut username).await?;
+    let len = client.read_u8().await? as usize;
+    let mut password = vec![0u8; len];
+    client.read_exact(&mut password).await?;
+    match String::from_utf8(username) {
+        Ok(username) => {
+            client.write_all(&[0x01, 0x00]).await?;
+            Ok(username)
+        }
+        Err(_) => {
+            // Any non-zero status is a failure, then the server must close
+            client.write_all(&[0x01, 0x01]).await?;
+            client.shutdown().await?;
+            Err(Socks5NegotiationError::AuthRejected.into())
+        }
+    }
+}
+
+/// Return the destination, and the username if `user_pass` is allowed and
+/// chosen by the client.
 #[instrument(skip_all)]
-async fn accept_socks5(client: &mut TcpStream) -> io::Result<Destination> {
-    // Not a NATed connection, treated as SOCKSv5
+async fn accept_socks5(
+    client: &mut TcpStream,
+    user_pass: bool,
+) -> io::Result<(Destination, Option<String>)> {
     // Parse version
-    // TODO: add timeout
     // TODO: use buffered reader
     let ver = client.read_u8().await?;
     if ver != 0x05 {
-        return error_invalid_input("Neither a NATed or SOCKSv5 connection");
+        return error_invalid_input("SOCKSv5: unsupported version");
     }
-    // Parse auth methods
+    // Parse auth methods, at most 255 by its one-byte length
     let n_methods = client.read_u8().await?;
     let mut buf = vec![0u8; n_methods as usize];
     client.read_exact(&mut buf).await?;
-    if !buf.iter().any(|&m| m == 0) {
-        return error_invalid_input("SOCKSv5: No auth is required");
-    }
-    // Select no auth
-    client.write_all(&[0x05, 0x00]).await?;
+    let username = if user_pass && buf.contains(&0x02) {
+        client.write_all(&[0x05, 0x02]).await?;
+        Some(accept_socks5_user_pass(client).await?)
+    } else if buf.contains(&0) {
+        // Select no auth
+        client.write_all(&[0x05, 0x00]).await?;
+        None
+    } else {
+        // Tell the client before closing, as RFC 1928 requires
+        client.write_all(&[0x05, 0xff]).await?;
+        client.shutdown().await?;
+        return Err(match n_methods {
+            0 => Socks5NegotiationError::NoMethods,
+            _ => Socks5NegotiationError::NoAcceptableMethod,
+        }
+        .into());
+    };
     // Parse request
     buf.resize(4, 0);
     client.read_exact(&mut buf).await?;
@@ -124,10 +693,13 @@ async fn accept_socks5(client: &mut TcpStream) -> io::Result<Destination> {
             buf.resize(len, 0);
             client.read_exact(&mut buf).await?;
 
-            let domain = std::str::from_utf8(&buf).map_err(|_| {
-                io::Error::new(io::ErrorKind::InvalidInput, "SOCKSv5: Invalid domain name")
-            })?;
-            Address::Domain(domain.into())
+            let domain = std::str::from_utf8(&buf)
+                .map_err(io::Error::other)
+                .and_then(|name| DOMAINS.normalize(name))
+                .map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "SOCKSv5: Invalid domain name")
+                })?;
+            Address::Domain(domain)
         }
         0x04 => {
             // IPv6
@@ -138,14 +710,127 @@ async fn accept_socks5(client: &mut TcpStream) -> io::Result<Destination> {
         _ => return error_invalid_input("SOCKSv5: unknown address type"),
     };
     let port = client.read_u16().await?;
-    // Send response
-    client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
-    Ok((addr, port).into())
+    // Response is deferred to `NewClient::reply()`
+    Ok(((addr, port).into(), username))
+}
+
+/// Max size of HTTP CONNECT request header.
+const MAX_HTTP_HEADER_LEN: usize = 8 * 1024;
+
+/// Parse HTTP CONNECT request, and its hints if `client_hints` is set.
+/// Data after the header is appended to `replay`. The header itself is
+/// never forwarded.
+#[instrument(skip_all)]
+async fn accept_http_connect(
+    client: &mut TcpStream,
+    replay: &mut BytesMut,
+    client_hints: bool,
+) -> io::Result<(Destination, ClientHints)> {
+    let mut buf = BytesMut::with_capacity(1024);
+    loop {
+        if client.read_buf(&mut buf).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut request = httparse::Request::new(&mut headers);
+        match request.parse(&buf) {
+            Ok(httparse::Status::Complete(len)) => {
+                if request.method != Some("CONNECT") {
+                    return error_invalid_input("HTTP: CONNECT is required");
+                }
+                let dest = request.path.and_then(parse_http_authority).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "HTTP: invalid CONNECT target")
+                })?;
+                let hints = if client_hints {
+                    ClientHints::from_headers(request.headers)
+                } else {
+                    Default::default()
+                };
+                replay.extend_from_slice(&buf[len..]);
+                // Response is deferred to `NewClient::reply()`
+                return Ok((dest, hints));
+            }
+            Ok(httparse::Status::Partial) if buf.len() < MAX_HTTP_HEADER_LEN => continue,
+            Ok(httparse::Status::Partial) => return error_invalid_input("HTTP: header too large"),
+            Err(_) => return error_invalid_input("HTTP: malformed request"),
+        }
+    }
+}
+
+/// Read a HTTP request head in `wait`, return the value of its `Host`
+/// header if any, or `None` if it's not a HTTP request. Read data is
+/// appended to `buf`.
+async fn sniff_http_host<R>(
+    reader: &mut R,
+    wait: Duration,
+    buf: &mut BytesMut,
+) -> io::Result<Option<Option<String>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let deadline = Instant::now() + wait;
+    loop {
+        match timeout_at(deadline, reader.read_buf(buf)).await {
+            Ok(Ok(0)) | Err(_) => return Ok(None),
+            Ok(result) => result?,
+        };
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut request = httparse::Request::new(&mut headers);
+        match request.parse(buf) {
+            Ok(httparse::Status::Complete(_)) => {
+                let host = request
+                    .headers
+                    .iter()
+                    .find(|h| h.name.eq_ignore_ascii_case("Host"))
+                    .and_then(|h| std::str::from_utf8(h.value).ok())
+                    .map(|host| host.trim().to_string());
+                return Ok(Some(host));
+            }
+            Ok(httparse::Status::Partial) if buf.len() < MAX_HTTP_HEADER_LEN => continue,
+            Ok(httparse::Status::Partial) | Err(_) => return Ok(None),
+        }
+    }
+}
+
+/// Parse `host:port` or `[ipv6]:port` of HTTP CONNECT.
+fn parse_http_authority(authority: &str) -> Option<Destination> {
+    let (host, port) = authority.rsplit_once(':')?;
+    let port = port.parse().ok()?;
+    let host = match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        Some(ip) => Address::Ip(ip.parse().ok()?),
+        None => match host.parse::<IpAddr>() {
+            Ok(ip) => Address::Ip(ip),
+            Err(_) if !host.is_empty() && !host.contains(['[', ']', ':']) => {
+                Address::Domain(DOMAINS.normalize(host).ok()?)
+            }
+            Err(_) => return None,
+        },
+    };
+    Some((host, port).into())
 }
 
 impl NewClient {
+    /// Accept a client and retrieve its destination, from either NAT info
+    /// or SOCKSv5 request. IPv4-mapped destinations are converted to IPv4
+    /// unless `keep_ipv4_mapped` is set.
+    ///
+    /// SOCKSv5 clients are not replied until connected or failed, see
+    /// `reply_rejected()` and `reply_failed()`.
+    #[instrument(name = "retrieve_dest", skip_all)]
+    pub async fn from_socket(left: TcpStream, keep_ipv4_mapped: bool) -> io::Result<Self> {
+        let options = InboundOptions {
+            keep_ipv4_mapped,
+            ..Default::default()
+        };
+        Self::accept(left, &options).await
+    }
+
+    /// Like `from_socket()`, but also accept other protocols enabled in
+    /// `options` for non-NATed connections. The protocol is detected by
+    /// peeking the first byte. Refused ones are counted in
+    /// `INBOUND_REJECTS`.
     #[instrument(name = "retrieve_dest", skip_all)]
-    pub async fn from_socket(mut left: TcpStream) -> io::Result<Self> {
+    pub async fn accept(mut left: TcpStream, options: &InboundOptions) -> io::Result<Self> {
         let from_port = left.local_addr()?.port();
 
         // Try to get original destination before NAT
@@ -160,42 +845,365 @@ impl NewClient {
         #[cfg(not(target_os = "linux"))]
         let dest: Option<SocketAddr> = None;
 
-        let dest = if let Some(dest) = dest {
+        let mut pending_reply = None;
+        let mut username = None;
+        let mut hints = ClientHints::default();
+        let mut tls = None;
+        let mut replay = BytesMut::new();
+        let mut dest = if let Some(dest) = dest {
             debug!(?dest, "Retrived destination via NAT info");
             dest.into()
         } else {
-            let dest = accept_socks5(&mut left).await?;
-            debug!(?dest, "Retrived destination via SOCKSv5");
-            dest
+            let mut first = [0u8; 1];
+            if left.peek(&mut first).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            match (InboundProto::guess(first[0]), options.raw_tls_port) {
+                (InboundProto::Socks5, _) => {
+                    let (dest, user) = accept_socks5(&mut left, options.socks_username)
+                        .await
+                        .map_err(|err| {
+                            if let Some(err) = Socks5NegotiationError::from_io(&err) {
+                                SOCKS5_NEGOTIATION.add(*err);
+                            }
+                            err
+                        })?;
+                    debug!(dest = %HOSTNAMES.dest(&dest), ?user, "Retrived destination via SOCKSv5");
+                    pending_reply = Some(PendingReply::Socks5);
+                    username = user;
+                    dest
+                }
+                (InboundProto::HttpConnect, _) if options.http_connect => {
+                    let (dest, client_hints) =
+                        accept_http_connect(&mut left, &mut replay, options.client_hints).await?;
+                    debug!(
+                        dest = %HOSTNAMES.dest(&dest),
+                        ?client_hints,
+                        "Retrived destination via HTTP CONNECT"
+                    );
+                    pending_reply = Some(PendingReply::Http);
+                    hints = client_hints;
+                    dest
+                }
+                (InboundProto::Tls, Some(port)) => {
+                    if BUFFERS.pending_exceeded() {
+                        // No way to tell the destination without buffering
+                        BUFFERS.add_sniff_skipped();
+                        return Err(io::Error::other(
+                            "pending buffers exceed the limit, raw TLS refused",
+                        ));
+                    }
+                    let wait = Duration::from_millis(500);
+                    let hello =
+                        sniff_tls_hello(&mut left, wait, &TLS_SNIFF_STATS, false, &mut replay)
+                            .await?;
+                    let sni = match &hello.sni {
+                        Some(sni) => DOMAINS.normalize(sni).unwrap_or_else(|_| sni.clone()),
+                        None => return error_invalid_input("raw TLS without SNI"),
+                    };
+                    debug!(sni = %HOSTNAMES.name(&sni), "Retrived destination via SNI");
+                    tls = Some(hello);
+                    (Address::Domain(sni), port).into()
+                }
+                (proto, _) => {
+                    INBOUND_REJECTS.add(proto);
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Neither a NATed or SOCKSv5 connection, guess {:?}", proto),
+                    ));
+                }
+            }
         };
+        if !options.keep_ipv4_mapped {
+            dest.canonicalize();
+        }
 
         let dest_ip_addr = match dest.host {
             Address::Ip(ip) => Some(ip),
             Address::Domain(_) => None,
         };
 
-        Ok(NewClient {
+        let mut client = NewClient {
             left,
             dest,
             dest_ip_addr,
             from_port,
+            tls,
+            connect_timeout: None,
+            upstream_auth: None,
+            prefer_family: None,
+            username,
+            hints,
+            pending_reply,
+            advertised_addr: None,
+            fingerprint_tls: false,
+            replay,
+            replay_lease: BUFFERS.lease_pending(),
+            last_error: None,
+        };
+        client.account_replay();
+        Ok(client)
+    }
+
+    /// Accept a client redirected by TPROXY, whose original destination is
+    /// the local address of the socket. `listen_port` is the port number
+    /// of the listener accepted it.
+    #[instrument(name = "retrieve_dest", skip_all)]
+    pub fn from_tproxy_socket(
+        left: TcpStream,
+        listen_port: u16,
+        keep_ipv4_mapped: bool,
+    ) -> io::Result<Self> {
+        let mut dest: Destination = left.local_addr()?.into();
+        debug!(?dest, "Retrived destination via TPROXY");
+        if !keep_ipv4_mapped {
+            dest.canonicalize();
+        }
+        let dest_ip_addr = match dest.host {
+            Address::Ip(ip) => Some(ip),
+            Address::Domain(_) => None,
+        };
+        Ok(NewClient {
+            left,
+            dest,
+            dest_ip_addr,
+            from_port: listen_port,
             tls: None,
+            connect_timeout: None,
+            upstream_auth: None,
+            prefer_family: None,
+            username: None,
+            hints: Default::default(),
+            pending_reply: None,
+            advertised_addr: None,
+            fingerprint_tls: false,
+            replay: BytesMut::new(),
+            replay_lease: BUFFERS.lease_pending(),
+            last_error: None,
         })
     }
 
+    /// Close a client without handling it. If it looks like a SOCKSv5
+    /// greeting has arrived, reply with a general failure before that.
+    /// Never wait for the client.
+    pub fn refuse(left: TcpStream) {
+        // Read the whole greeting, avoid RST on close due to unread data
+        let mut buf = [0u8; 257];
+        if left.try_read(&mut buf).is_ok_and(|n| n > 0) && buf[0] == 5 {
+            // no auth, then a failure reply to the following request
+            let mut reply = vec![5, 0];
+            reply.extend(socks5_reply(Socks5Reply::GeneralFailure, None));
+            let _ = left.try_write(&reply);
+        }
+    }
+
+    /// Close a client refused by rules. Unlike `refuse()`, wait a moment
+    /// for the greeting to peek if it's SOCKSv5, then reply "connection
+    /// not allowed by ruleset" to its request, so intended to be spawned.
+    pub async fn refuse_not_allowed(mut left: TcpStream) {
+        let mut buf = [0u8; 1];
+        match timeout(REFUSE_WAIT, left.peek(&mut buf)).await {
+            Ok(Ok(1)) if buf[0] == 5 => (),
+            _ => return,
+        }
+        let mut buf = [0u8; 512];
+        let _ = left.try_read(&mut buf);
+        if left.write_all(&[5, 0]).await.is_err() {
+            return;
+        }
+        if let Ok(Ok(n)) = timeout(REFUSE_WAIT, left.read(&mut buf)).await {
+            if n > 0 {
+                let reply = socks5_reply(Socks5Reply::NotAllowed, None);
+                let _ = left.write_all(&reply).await;
+            }
+        }
+    }
+
+    /// Return true if the client closed (or reset) the connection without
+    /// sending anything in `window`, like TCP health checks do.
+    pub async fn is_health_check(left: &TcpStream, window: Duration) -> bool {
+        let mut buf = [0u8; 1];
+        match timeout(window, left.peek(&mut buf)).await {
+            Ok(Ok(0)) => true,
+            Ok(Err(err)) => err.kind() == io::ErrorKind::ConnectionReset,
+            Ok(Ok(_)) | Err(_) => false,
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.left.peer_addr()
+    }
+
+    /// Tag of the server that the client asked for with username
+    /// `tag:SERVER-TAG` or the `X-Moproxy-Server` hint, if any.
+    pub fn pinned_tag(&self) -> Option<&str> {
+        if let Some(tag) = &self.hints.server {
+            return Some(tag);
+        }
+        self.username.as_deref()?.strip_prefix(PIN_TAG_PREFIX)
+    }
+
+    /// Update the accounting after `replay` changed.
+    fn account_replay(&mut self) {
+        self.replay_lease.resize(self.replay.capacity());
+    }
+
     fn pending_data(&self) -> Option<Bytes> {
-        Some(self.tls.as_ref()?.pending_data.as_ref()?.clone())
+        (!self.replay.is_empty()).then(|| Bytes::copy_from_slice(&self.replay))
+    }
+
+    /// Send the SOCKSv5 (or equivalent HTTP) reply if it's pending,
+    /// otherwise do nothing, so a request is replied at most once.
+    /// `bound` is the local address of the upstream-facing socket, its IP
+    /// address is replaced by `advertised_addr` if set.
+    async fn reply(&mut self, reply: Socks5Reply, bound: Option<SocketAddr>) -> io::Result<()> {
+        let pending = match self.pending_reply.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        match pending {
+            PendingReply::Http => {
+                debug!(?reply, "Reply HTTP CONNECT request");
+                let response = match reply {
+                    Socks5Reply::Succeeded => format!("HTTP/1.1 {}\r\n\r\n", reply.http_status()),
+                    _ => format!(
+                        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        reply.http_status()
+                    ),
+                };
+                self.left.write_all(response.as_bytes()).await
+            }
+            PendingReply::Socks5 => {
+                let bound = match self.advertised_addr {
+                    _ if reply != Socks5Reply::Succeeded => None,
+                    Some(ip) => Some(SocketAddr::new(ip, bound.map(|a| a.port()).unwrap_or(0))),
+                    None => bound,
+                };
+                debug!(?reply, ?bound, "Reply SOCKSv5 request");
+                self.left.write_all(&socks5_reply(reply, bound)).await
+            }
+        }
+    }
+
+    /// Send success reply with local address of `right`, if known.
+    async fn reply_succeeded(&mut self, right: &TcpStream) -> io::Result<()> {
+        let bound = right.local_addr().ok();
+        self.reply(Socks5Reply::Succeeded, bound).await
+    }
+
+    /// Tell SOCKSv5 client that it's not allowed (by policy), then close.
+    pub async fn reply_rejected(mut self) -> io::Result<()> {
+        self.reply(Socks5Reply::NotAllowed, None).await
+    }
+
+    /// Like `reply_rejected()`, but answer a plain HTTP request of NATed
+    /// client to port 80 with `page` as `403 Forbidden`, then close.
+    /// `rule` is the text of the rule rejected it.
+    pub async fn reply_rejected_page(mut self, page: &RejectPage, rule: &str) -> io::Result<()> {
+        if self.pending_reply.is_some() || self.dest.port != 80 {
+            return self.reply_rejected().await;
+        }
+        if BUFFERS.pending_exceeded() {
+            BUFFERS.add_sniff_skipped();
+            debug!("Pending buffers exceed the limit, reject without the page");
+            return Ok(());
+        }
+        let wait = Duration::from_millis(500);
+        let host = match sniff_http_host(&mut self.left, wait, &mut self.replay).await? {
+            Some(host) => host,
+            None => {
+                REJECT_PAGES.add(false);
+                debug!("not a HTTP request, reject without the page");
+                return Ok(());
+            }
+        };
+        let host = host.unwrap_or_else(|| self.dest.host.to_string());
+        REJECT_PAGES.add(true);
+        debug!(host = %HOSTNAMES.name(&host), "reply the reject page");
+        self.left
+            .write_all(page.response(&host, rule).as_bytes())
+            .await?;
+        self.left.shutdown().await
+    }
+
+    /// Tell SOCKSv5 client that connecting failed with the last error,
+    /// then close.
+    pub async fn reply_failed(mut self) -> io::Result<()> {
+        let reply = self
+            .last_error
+            .map(Socks5Reply::from)
+            .unwrap_or(Socks5Reply::GeneralFailure);
+        self.reply(reply, None).await
+    }
+
+    /// Reply success to SOCKSv5 client, then return its stream to be
+    /// served in process. Fail if any data has been sniffed from it.
+    pub async fn reply_local(mut self) -> io::Result<TcpStream> {
+        if !self.replay.is_empty() {
+            self.reply(Socks5Reply::GeneralFailure, None).await?;
+            return Err(io::Error::other("data sniffed from client"));
+        }
+        let bound = self.left.local_addr().ok();
+        self.reply(Socks5Reply::Succeeded, bound).await?;
+        Ok(self.left)
+    }
+
+    /// Whether any data has arrived after the request, without waiting.
+    fn has_sent_data(&self) -> bool {
+        use futures_util::FutureExt;
+        let mut buf = [0u8; 1];
+        !self.replay.is_empty() || matches!(self.left.peek(&mut buf).now_or_never(), Some(Ok(1..)))
+    }
+
+    /// Whether the client has shut down its write half (or reset) and
+    /// sent nothing more, without consuming any data. Never if there is
+    /// data pending to be sent to upstream, e.g. read on sniffing, since
+    /// half-closed clients may still wait for the response.
+    pub fn has_gone(&self) -> bool {
+        if !self.replay.is_empty() {
+            return false;
+        }
+        #[cfg(target_os = "linux")]
+        return self.left.is_read_closed().unwrap_or(true);
+        #[cfg(not(target_os = "linux"))]
+        {
+            use futures_util::FutureExt;
+            let mut buf = [0u8; 1];
+            matches!(
+                self.left.peek(&mut buf).now_or_never(),
+                Some(Ok(0) | Err(_))
+            )
+        }
     }
 
     pub fn features(&self) -> RequestFeatures<SharedStr> {
         RequestFeatures {
             listen_port: Some(self.from_port),
-            dst_domain: self.dest.host.domain(),
+            dst_domain: self
+                .dest
+                .host
+                .domain()
+                .map(|name| DOMAINS.normalize(&name).unwrap_or(name)),
             dst_ip: self.dest_ip_addr,
         }
     }
 
+    /// Whether a full TLS ClientHello has been sniffed, which is safe to
+    /// send to multiple servers in parallel.
+    pub fn has_full_tls_hello(&self) -> bool {
+        self.tls.as_ref().is_some_and(|tls| tls.has_full_tls_hello)
+    }
+
+    /// Whether the sniffed data is conclusively not TLS. Remote DNS with
+    /// SNI and parallel connecting are skipped for such clients.
+    pub fn is_not_tls(&self) -> bool {
+        self.tls.as_ref().is_some_and(|tls| tls.not_tls)
+    }
+
     pub fn override_dest_with_sni(&mut self) -> bool {
+        if self.is_not_tls() {
+            return false;
+        }
         match (
             &mut self.dest.host,
             &self.tls.as_ref().and_then(|tls| tls.sni.clone()),
@@ -203,24 +1211,59 @@ impl NewClient {
             (Address::Domain(_), _) => false,
             (_, None) => false,
             (dst, Some(host)) => {
-                *dst = Address::Domain(host.clone());
+                *dst = Address::Domain(DOMAINS.normalize(host).unwrap_or_else(|_| host.clone()));
                 true
             }
         }
     }
 
-    #[instrument(level = "error", skip_all, fields(dest=?self.dest))]
+    /// Connect to the destination without proxy. SOCKSv5 client is
+    /// replied with the result.
+    ///
+    /// Resolving & connecting is limited by `connect_timeout` if set or
+    /// `max_wait` of `pseudo_server`, failures are counted on it.
+    #[instrument(level = "error", skip_all, fields(dest = %HOSTNAMES.dest(&self.dest)))]
     pub async fn direct_connect(
-        self,
+        mut self,
         pseudo_server: Arc<ProxyServer>,
     ) -> io::Result<ConnectedClient> {
-        let mut right = match self.dest.host {
-            Address::Ip(addr) => TcpStream::connect((addr, self.dest.port)).await?,
-            Address::Domain(ref name) => {
-                TcpStream::connect((name.as_ref(), self.dest.port)).await?
+        let connect = async {
+            match self.dest.host {
+                Address::Ip(ip) => {
+                    let mut addr = SocketAddr::new(ip, self.dest.port);
+                    if let SocketAddr::V6(ref mut addr) = addr {
+                        addr.set_scope_id(self.dest.scope_id);
+                    }
+                    TcpStream::connect(addr).await
+                }
+                Address::Domain(ref name) => {
+                    let mut addrs = RESOLVER.lookup(name, self.dest.port).await?;
+                    if let Some(family) = self.prefer_family {
+                        family.sort_first(&mut addrs);
+                    }
+                    TcpStream::connect(&addrs[..]).await
+                }
+            }
+        };
+        let wait = self
+            .connect_timeout
+            .unwrap_or_else(|| pseudo_server.max_wait());
+        let result = timeout(wait, connect)
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+        let mut right = match result {
+            Ok(right) => right,
+            Err(err) => {
+                pseudo_server.update_stats_conn_failed();
+                self.reply(err.kind().into(), None).await?;
+                return Err(err);
             }
         };
-        right.set_nodelay(true)?;
+        if let Err(err) = pseudo_server.tcp_options().apply(&right) {
+            self.reply(Socks5Reply::GeneralFailure, None).await?;
+            return Err(err);
+        }
+        self.reply_succeeded(&right).await?;
 
         if let Some(data) = self.pending_data() {
             right.write_all(&data).await?;
@@ -229,84 +1272,217 @@ impl NewClient {
         info!(remote = %right.peer_addr()?, "Connected w/o proxy");
         Ok(ConnectedClient {
             orig: self,
-            right,
+            right: right.into(),
             server: pseudo_server,
+            retried: false,
+            losers: vec![],
+            handshaked_at: Some(Instant::now()),
         })
     }
 
-    #[instrument(level = "error", skip_all, fields(dest=?self.dest))]
+    /// Sniff TLS ClientHello for SNI. SOCKSv5 and HTTP CONNECT clients are
+    /// never replied before connected, so they're sniffed only if they
+    /// have sent data along with the request, as if no SNI found otherwise.
+    #[instrument(level = "error", skip_all, fields(dest = %HOSTNAMES.dest(&self.dest)))]
     pub async fn retrieve_dest_from_sni(&mut self) -> io::Result<()> {
         if self.tls.is_some() {
             return Ok(());
         }
-        let mut tls = TlsData::default();
+        if BUFFERS.pending_exceeded() {
+            // Proceed as if no SNI found, without buffering anything
+            BUFFERS.add_sniff_skipped();
+            debug!("Pending buffers exceed the limit, skip sniffing");
+            self.tls = Some(Default::default());
+            return Ok(());
+        }
+        if self.pending_reply.is_some() && !self.has_sent_data() {
+            debug!("Nothing sent before the reply, skip sniffing");
+            self.tls = Some(Default::default());
+            return Ok(());
+        }
         let wait = Duration::from_millis(500);
-        let mut buf = BytesMut::with_capacity(2048);
-        buf.resize(buf.capacity(), 0);
-        if let Ok(len) = timeout(wait, self.left.read(&mut buf)).await {
-            buf.truncate(len?);
-            // only TLS is safe to duplicate requests.
-            match tls_parser::parse_client_hello(&buf) {
-                Err(err) => info!("fail to parse hello: {}", err),
-                Ok(hello) => {
-                    tls.has_full_tls_hello = true;
-                    if let Some(name) = hello.server_name {
-                        tls.sni = Some(name.into());
-                        debug!(sni = name, "SNI found");
-                    }
-                    if hello.early_data {
-                        debug!("TLS with early data");
-                    }
-                }
-            }
-            tls.pending_data = Some(buf.freeze());
-        } else {
-            info!("no tls request received before timeout");
+        let tls = sniff_tls_hello(
+            &mut self.left,
+            wait,
+            &TLS_SNIFF_STATS,
+            self.fingerprint_tls,
+            &mut self.replay,
+        )
+        .await;
+        self.account_replay();
+        let tls = tls?;
+        if !self.replay.is_empty() && !tls.has_full_tls_hello && !tls.not_tls {
+            debug!(dest_ip = ?self.dest_ip_addr, "non-TLS or malformed hello");
+        }
+        if let Some(fingerprint) = &tls.fingerprint {
+            TLS_FINGERPRINTS.add(self.from_port, fingerprint);
         }
         self.tls = Some(tls);
         Ok(())
     }
 
-    #[instrument(level = "error", skip_all, fields(dest=?self.dest))]
+    /// Connect to the destination via one of `proxies`. SOCKSv5 client is
+    /// replied on success. On failure, call `reply_failed()` or try other
+    /// methods on the returned client.
+    #[instrument(level = "error", skip_all, fields(dest = %HOSTNAMES.dest(&self.dest)))]
     pub async fn connect_server(
-        self,
+        mut self,
         proxies: Vec<Arc<ProxyServer>>,
         n_parallel: usize,
+        retries: usize,
     ) -> Result<ConnectedClient, FailedClient> {
+        let port = self.dest.port;
+        let proxies: Vec<_> = proxies
+            .into_iter()
+            .filter(|server| server.allows_port(port))
+            .collect();
         if proxies.is_empty() {
             warn!("No avaiable proxy");
             return Err(FailedClient::Recoverable(self));
         }
-        let (n_parallel, wait_response) = match self.tls {
-            Some(ref tls) if tls.has_full_tls_hello => (n_parallel.clamp(1, proxies.len()), true),
-            _ => (1, false),
+        let (n_parallel, wait_response) = if self.has_full_tls_hello() {
+            // Servers without early payload never race with others
+            let racers = proxies.iter().filter(|s| !s.no_early_payload()).count();
+            (n_parallel.clamp(1, racers.max(1)), true)
+        } else {
+            (1, false)
         };
+        // e.g. scanners and health checks that don't wait for the reply
+        if self.has_gone() {
+            incr(&CLIENT_GONE_EARLY.before_connect);
+            debug!("Client gone before connecting");
+            return Err(FailedClient::Unrecoverable(client_gone()));
+        }
         let proxies_len = proxies.len();
-        match try_connect_all(
+        let connect = try_connect_all(
             &self.dest,
             proxies,
             n_parallel,
             wait_response,
             self.pending_data(),
+            retries,
+            self.connect_timeout,
         )
-        .await
-        {
-            Ok((server, right)) => {
-                info!(proxy = %server.tag, "Proxy connected");
+        .with_auth(self.upstream_auth.clone());
+        let result = tokio::select! {
+            result = connect => result,
+            _ = wait_gone(&self.left), if self.replay.is_empty() => {
+                incr(&CLIENT_GONE_EARLY.during_connect);
+                debug!("Client gone during connecting, aborted");
+                return Err(FailedClient::Unrecoverable(client_gone()));
+            }
+        };
+        match result {
+            Ok(connected) => {
+                let server = connected.server;
+                let retried = connected.retried;
+                let racers = connected.losers.len() + 1;
+                info!(proxy = %server.tag(), retried, racers, "Proxy connected");
+                self.reply_succeeded(connected.stream.tcp()).await?;
                 Ok(ConnectedClient {
                     orig: self,
-                    right,
+                    right: connected.stream,
                     server,
+                    retried,
+                    losers: connected.losers,
+                    handshaked_at: (!wait_response).then(Instant::now),
                 })
             }
             Err(err) => {
                 warn!("Tried {} proxies but failed: {}", proxies_len, err);
+                self.last_error = Some(err.kind());
                 Err(FailedClient::Recoverable(self))
             }
         }
     }
 }
 
+fn client_gone() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "client gone")
+}
+
+/// Resolve once the client has shut down its write half (or reset). Never
+/// resolve if there is data (to be sent to upstream) ahead of that.
+async fn wait_gone(left: &TcpStream) {
+    let mut buf = [0u8; 1];
+    if let Ok(1..) = left.peek(&mut buf).await {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Max bytes read on sniffing TLS ClientHello, a full TLS record.
+const MAX_SNIFF_LEN: usize = 5 + (1 << 14);
+
+/// Read the first packet from client and try to parse it as a TLS
+/// ClientHello. Keep reading in `wait` if the record is incomplete, but
+/// return immediately once the data fails TLS record validation.
+/// Read data is appended to `replay`, whose data already there (e.g. sent
+/// along with HTTP CONNECT) is taken as the beginning of the packet.
+async fn sniff_tls_hello<R>(
+    reader: &mut R,
+    wait: Duration,
+    stats: &TlsSniffStats,
+    fingerprint: bool,
+    replay: &mut BytesMut,
+) -> io::Result<TlsData>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut tls = TlsData::default();
+    let deadline = Instant::now() + wait;
+    while replay.is_empty()
+        || (replay.len() < MAX_SNIFF_LEN && tls_parser::is_partial_handshake(replay))
+    {
+        replay.reserve(2048);
+        match timeout_at(deadline, reader.read_buf(replay)).await {
+            Ok(Ok(0)) => break,
+            Ok(len) => drop(len?),
+            Err(_) if replay.is_empty() => {
+                incr(&stats.timed_out);
+                info!("no tls request received before timeout");
+                return Ok(tls);
+            }
+            Err(_) => break,
+        }
+    }
+    if tls_parser::is_not_handshake(replay) {
+        incr(&stats.not_tls);
+        tls.not_tls = true;
+        debug!("not a TLS handshake");
+        return Ok(tls);
+    }
+    // only TLS is safe to duplicate requests.
+    match tls_parser::parse_client_hello(replay, fingerprint) {
+        Err(err) => {
+            incr(&stats.parse_error);
+            info!("fail to parse hello: {}", err);
+        }
+        Ok(hello) => {
+            tls.has_full_tls_hello = true;
+            if let Some(name) = hello.server_name {
+                incr(&stats.hello_with_sni);
+                tls.sni = Some(DOMAINS.intern(name));
+                debug!(sni = %HOSTNAMES.name(name), "SNI found");
+            } else {
+                incr(&stats.hello_without_sni);
+            }
+            if hello.early_data {
+                incr(&stats.early_data);
+                debug!("TLS with early data");
+            }
+            if let Some(fingerprint) = hello.fingerprint {
+                debug!(
+                    ja3 = fingerprint.ja3,
+                    hash = fingerprint.hash_hex(),
+                    "TLS fingerprint"
+                );
+                tls.fingerprint = Some(fingerprint);
+            }
+        }
+    }
+    Ok(tls)
+}
+
 impl FailedClient {
     pub fn recovery(self) -> io::Result<NewClient> {
         match self {
@@ -317,12 +1493,37 @@ impl FailedClient {
 }
 
 impl ConnectedClient {
-    #[instrument(level = "error", skip_all, fields(dest=?self.orig.dest, proxy=%self.server.tag))]
+    pub fn server(&self) -> &Arc<ProxyServer> {
+        &self.server
+    }
+
+    /// Whether the server connected after transient errors.
+    pub fn retried(&self) -> bool {
+        self.retried
+    }
+
+    /// Whether `server` was still connecting when lost the race, rather
+    /// than failed.
+    pub fn lost_race(&self, server: &Arc<ProxyServer>) -> bool {
+        self.losers.iter().any(|s| Arc::ptr_eq(s, server))
+    }
+
+    pub fn dest(&self) -> &Destination {
+        &self.orig.dest
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.orig.left.peer_addr()
+    }
+
+    #[instrument(level = "error", skip_all, fields(dest = %HOSTNAMES.dest(&self.orig.dest), proxy=%self.server.tag()))]
     pub async fn serve(self) -> io::Result<()> {
         let ConnectedClient {
             orig,
             right,
             server,
+            retried,
+            handshaked_at,
             ..
         } = self;
         // TODO: make keepalive configurable
@@ -339,8 +1540,18 @@ impl ConnectedClient {
             warn!("fail to set keepalive: {}", e);
         }
         */
-        server.update_stats_conn_open();
-        match pipe(orig.left, right, server.clone()).await {
+        // Nothing to replay once connected
+        drop((orig.replay, orig.replay_lease));
+        server.update_stats_conn_open(retried);
+        let pipe = pipe(orig.left, right, server.clone())
+            .ttfb_since(handshaked_at)
+            .account_port(ACCOUNTING.is_enabled().then_some(orig.from_port))
+            .account_destination(
+                DESTINATIONS
+                    .is_enabled()
+                    .then(|| destination_key(&orig.dest.host)),
+            );
+        match pipe.await {
             Ok(Traffic { tx_bytes, rx_bytes }) => {
                 server.update_stats_conn_close(false);
                 debug!(tx_bytes, rx_bytes, "Closed");
@@ -354,3 +1565,468 @@ impl ConnectedClient {
         }
     }
 }
+
+#[cfg(test)]
+fn test_client_hello(exts: &[u8]) -> Vec<u8> {
+    let mut hello = vec![3, 3];
+    hello.extend_from_slice(&[0; 32]); // random
+    hello.extend_from_slice(&[0, 0, 2, 0xc0, 0x2f, 1, 0]); // session id, ciphers, compression
+    hello.extend_from_slice(&(exts.len() as u16).to_be_bytes());
+    hello.extend_from_slice(exts);
+    let mut data = vec![22, 3, 1];
+    data.extend_from_slice(&(hello.len() as u16 + 4).to_be_bytes());
+    data.extend_from_slice(&[1, 0]);
+    data.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+    data.extend_from_slice(&hello);
+    data
+}
+
+#[tokio::test]
+async fn test_sniff_tls_hello() {
+    let stats = TlsSniffStats::new();
+    let wait = Duration::from_millis(50);
+    let sniff = |data: Vec<u8>| {
+        let stats = &stats;
+        async move {
+            let (mut client, mut server) = tokio::io::duplex(4096);
+            if !data.is_empty() {
+                client.write_all(&data).await.unwrap();
+            }
+            let mut replay = BytesMut::new();
+            let tls = sniff_tls_hello(&mut server, wait, stats, false, &mut replay)
+                .await
+                .unwrap();
+            drop(client);
+            (tls, replay)
+        }
+    };
+
+    let (tls, _) = sniff(tls_parser::build_client_hello("example.com")).await;
+    assert!(tls.has_full_tls_hello);
+    assert_eq!(Some("example.com"), tls.sni.as_deref());
+
+    let (tls, _) = sniff(test_client_hello(&[])).await;
+    assert!(tls.has_full_tls_hello);
+    assert!(tls.sni.is_none());
+
+    let (tls, _) = sniff(test_client_hello(&[0, 42, 0, 0])).await;
+    assert!(tls.has_full_tls_hello);
+
+    let (tls, replay) = sniff(b"GET / HTTP/1.1\r\n".to_vec()).await;
+    assert!(!tls.has_full_tls_hello);
+    assert_eq!(&b"GET / HTTP/1.1\r\n"[..], &replay[..]);
+
+    let (_, replay) = sniff(vec![]).await;
+    assert!(replay.is_empty());
+
+    // ClientHello split into three packets, the first one read already
+    let hello = tls_parser::build_client_hello("example.com");
+    let (mut client, mut server) = tokio::io::duplex(4096);
+    client.write_all(&hello[4..20]).await.unwrap();
+    let rest = hello[20..].to_vec();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        client.write_all(&rest).await.unwrap();
+        client.write_all(b"more").await.unwrap();
+    });
+    let mut replay = BytesMut::from(&hello[..4]);
+    let tls = sniff_tls_hello(&mut server, wait, &stats, false, &mut replay)
+        .await
+        .unwrap();
+    assert!(tls.has_full_tls_hello);
+    assert_eq!(&hello[..], &replay[..hello.len()]);
+
+    // All read already, not waiting more
+    let (_client, mut server) = tokio::io::duplex(4096);
+    let mut replay = BytesMut::from(&hello[..]);
+    let start = Instant::now();
+    let tls = sniff_tls_hello(&mut server, wait, &stats, false, &mut replay)
+        .await
+        .unwrap();
+    assert!(tls.has_full_tls_hello);
+    assert!(start.elapsed() < wait);
+
+    assert_eq!(
+        TlsSniffCounters {
+            hello_with_sni: 3,
+            hello_without_sni: 2,
+            parse_error: 0,
+            not_tls: 1,
+            timed_out: 1,
+            early_data: 1,
+        },
+        stats.snapshot()
+    );
+}
+
+#[test]
+fn test_socks5_reply() {
+    let reply = socks5_reply(
+        Socks5Reply::Succeeded,
+        Some("[::ffff:1.2.3.4]:80".parse().unwrap()),
+    );
+    assert_eq!(vec![5, 0, 0, 1, 1, 2, 3, 4, 0, 80], reply);
+    let reply = socks5_reply(Socks5Reply::Succeeded, Some("[::1]:80".parse().unwrap()));
+    assert_eq!(22, reply.len());
+    assert_eq!(&[5, 0, 0, 4], &reply[..4]);
+    let reply = socks5_reply(Socks5Reply::HostUnreachable, None);
+    assert_eq!(vec![5, 4, 0, 1, 0, 0, 0, 0, 0, 0], reply);
+}
+
+#[test]
+fn test_tls_fingerprint_stats() {
+    let stats = TlsFingerprintStats::new();
+    let hello = tls_parser::build_client_hello("example.com");
+    let fingerprint = tls_parser::parse_client_hello(&hello, true)
+        .unwrap()
+        .fingerprint
+        .unwrap();
+    stats.add(443, &fingerprint);
+    stats.add(443, &fingerprint);
+    stats.add(8443, &fingerprint);
+    let counts = stats.snapshot();
+    assert_eq!(2, counts.len());
+    assert_eq!((443, 2), (counts[0].listen_port, counts[0].count));
+    assert_eq!(fingerprint.hash_hex(), counts[0].hash);
+
+    for port in 0..MAX_TLS_FINGERPRINTS as u16 {
+        stats.add(port, &fingerprint);
+    }
+    let counts = stats.snapshot();
+    assert_eq!(MAX_TLS_FINGERPRINTS, counts.len());
+    assert_eq!(443, counts[0].listen_port);
+}
+
+#[cfg(test)]
+async fn accept_with(
+    options: InboundOptions,
+    data: impl Into<Vec<u8>>,
+) -> (io::Result<NewClient>, TcpStream) {
+    let data = data.into();
+    // Listen on IPv6 as the default `--host ::` does
+    let listener = tokio::net::TcpListener::bind("[::1]:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let request = tokio::spawn(async move {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        stream
+    });
+    let (sock, _) = listener.accept().await.unwrap();
+    let client = NewClient::accept(sock, &options).await;
+    (client, request.await.unwrap())
+}
+
+#[test]
+fn test_parse_http_authority() {
+    let parse = |s| parse_http_authority(s).map(|dest| dest.to_string());
+    assert_eq!(Some("example.com:443".into()), parse("Example.com:443"));
+    assert_eq!(Some("::1:80".into()), parse("[::1]:80"));
+    assert!(matches!(
+        parse_http_authority("1.2.3.4:80").unwrap().host,
+        Address::Ip(_)
+    ));
+    assert!(parse_http_authority("example.com").is_none());
+    assert!(parse_http_authority(":80").is_none());
+    assert!(parse_http_authority("[::1:80").is_none());
+}
+
+#[tokio::test]
+async fn test_reply_rejected_page() {
+    async fn reject(data: &'static [u8], port: u16) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let browser = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(data).await.unwrap();
+            let mut response = String::new();
+            // May be reset if closed with data unread
+            let _ = stream.read_to_string(&mut response).await;
+            response
+        });
+        let (sock, _) = listener.accept().await.unwrap();
+        let mut client = NewClient::from_tproxy_socket(sock, addr.port(), false).unwrap();
+        client.dest = SocketAddr::new([192, 0, 2, 1].into(), port).into();
+        let page = RejectPage::default();
+        client
+            .reply_rejected_page(&page, "dst domain example.com reject")
+            .await
+            .unwrap();
+        browser.await.unwrap()
+    }
+
+    let before = REJECT_PAGES.snapshot();
+    let request = b"GET /index.html HTTP/1.1\r\nHost: www.example.com\r\n\
+        User-Agent: Mozilla/5.0\r\nAccept: text/html\r\n\r\n";
+    let response = reject(request, 80).await;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let mut head = head.lines();
+    assert_eq!(Some("HTTP/1.1 403 Forbidden"), head.next());
+    let head: Vec<_> = head.collect();
+    assert!(head.contains(&"Connection: close"));
+    assert!(head.contains(&&*format!("Content-Length: {}", body.len())));
+    assert!(body.contains("Access to www.example.com is blocked"));
+    assert!(body.contains("dst domain example.com reject"));
+
+    // Destination address without Host
+    let response = reject(b"GET / HTTP/1.0\r\n\r\n", 80).await;
+    assert!(response.contains("Access to 192.0.2.1 is blocked"));
+
+    // Closed silently for others
+    assert_eq!("", reject(b"\x16\x03\x01\x00\x05hello", 80).await);
+    assert_eq!("", reject(b"GET / HTTP/1.1\r\n\r\n", 8080).await);
+    let after = REJECT_PAGES.snapshot();
+    assert!(after.served >= before.served + 2);
+    assert!(after.not_http > before.not_http);
+}
+
+#[tokio::test]
+async fn test_accept_http_connect() {
+    let options = InboundOptions {
+        http_connect: true,
+        ..Default::default()
+    };
+    let request = b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\nhello";
+    let (client, mut stream) = accept_with(options.clone(), request).await;
+    let mut client = client.unwrap();
+    assert_eq!("example.com:443", client.dest.to_string());
+    assert_eq!(&b"hello"[..], &client.replay[..]);
+
+    client.reply(Socks5Reply::NotAllowed, None).await.unwrap();
+    // Replied once only
+    client.reply(Socks5Reply::Succeeded, None).await.unwrap();
+    drop(client);
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert_eq!(
+        "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        response
+    );
+
+    let (client, _) = accept_with(options, b"GET / HTTP/1.1\r\n\r\n").await;
+    assert!(client.is_err());
+}
+
+#[tokio::test]
+async fn test_accept_http_connect_hints() {
+    let options = InboundOptions {
+        http_connect: true,
+        client_hints: true,
+        ..Default::default()
+    };
+    let request = b"CONNECT example.com:443 HTTP/1.1\r\n\
+        x-moproxy-require: jp\r\nX-Moproxy-Server:  a \r\n\r\n";
+    let (client, _) = accept_with(options.clone(), request).await;
+    let client = client.unwrap();
+    assert_eq!(Some("jp"), client.hints.require.as_deref());
+    assert_eq!(Some("a"), client.pinned_tag());
+    assert!(client.replay.is_empty());
+
+    // Invalid ones are ignored
+    let invalid_before = CLIENT_HINTS.snapshot().invalid;
+    let request = b"CONNECT example.com:443 HTTP/1.1\r\n\
+        X-Moproxy-Require: jp or us\r\nX-Moproxy-Server: a/b\r\n\r\n";
+    let (client, _) = accept_with(options.clone(), request).await;
+    let client = client.unwrap();
+    assert_eq!(ClientHints::default(), client.hints);
+    assert_eq!(None, client.pinned_tag());
+    assert!(CLIENT_HINTS.snapshot().invalid >= invalid_before + 2);
+
+    // Not read unless enabled
+    let options = InboundOptions {
+        client_hints: false,
+        ..options
+    };
+    let request = b"CONNECT example.com:443 HTTP/1.1\r\nX-Moproxy-Require: jp\r\n\r\n";
+    let (client, _) = accept_with(options, request).await;
+    assert_eq!(ClientHints::default(), client.unwrap().hints);
+}
+
+#[tokio::test]
+async fn test_accept_raw_tls() {
+    let options = InboundOptions {
+        raw_tls_port: Some(8443),
+        ..Default::default()
+    };
+    let hello = tls_parser::build_client_hello("example.com");
+    let (client, _) = accept_with(options.clone(), hello.clone()).await;
+    let client = client.unwrap();
+    assert_eq!("example.com:8443", client.dest.to_string());
+    assert!(client.tls.is_some());
+    assert_eq!(hello, client.replay[..]);
+
+    let (client, _) = accept_with(Default::default(), hello).await;
+    let err = client.err().unwrap();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    assert!(err.to_string().contains("Tls"));
+
+    assert!(INBOUND_REJECTS.snapshot().tls > 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_sniff_not_tls_immediately() {
+    let stats = TlsSniffStats::new();
+    let wait = Duration::from_millis(500);
+    let sniff = |data: &'static [u8]| {
+        let stats = &stats;
+        async move {
+            // Keep the client open, as a real one waiting for response
+            let (mut client, mut server) = tokio::io::duplex(4096);
+            client.write_all(data).await.unwrap();
+            let start = Instant::now();
+            let mut replay = BytesMut::new();
+            let tls = sniff_tls_hello(&mut server, wait, stats, false, &mut replay)
+                .await
+                .unwrap();
+            assert_eq!(data, &replay[..]);
+            (tls, start.elapsed())
+        }
+    };
+
+    let (tls, elapsed) = sniff(b"GET / HTTP/1.1\r\n").await;
+    assert!(tls.not_tls);
+    assert!(elapsed < wait / 10);
+
+    // Record header with impossible version
+    let (tls, elapsed) = sniff(&[22, 0x47, 0x45]).await;
+    assert!(tls.not_tls);
+    assert!(elapsed < wait / 10);
+
+    // Truncated ClientHello
+    let (tls, elapsed) = sniff(&[22, 3, 1, 0, 200, 1]).await;
+    assert!(!tls.not_tls);
+    assert!(elapsed >= wait);
+
+    assert_eq!(2, stats.snapshot().not_tls);
+}
+
+#[tokio::test]
+async fn test_accept_socks5_username() {
+    let options = InboundOptions {
+        socks_username: true,
+        ..Default::default()
+    };
+    // Offer no auth & username/password
+    let request = b"\x05\x02\x00\x02\x01\x05tag:a\x01x\x05\x01\x00\x03\x0bexample.com\x01\xbb";
+    let (client, mut stream) = accept_with(options.clone(), request).await;
+    let client = client.unwrap();
+    assert_eq!("example.com:443", client.dest.to_string());
+    assert_eq!(Some("tag:a"), client.username.as_deref());
+    assert_eq!(Some("a"), client.pinned_tag());
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!([5, 2, 1, 0], reply);
+
+    // Not pinned by other usernames
+    let request = b"\x05\x01\x02\x01\x04user\x00\x05\x01\x00\x03\x0bexample.com\x01\xbb";
+    let (client, _) = accept_with(options, request).await;
+    let client = client.unwrap();
+    assert_eq!(Some("user"), client.username.as_deref());
+    assert_eq!(None, client.pinned_tag());
+
+    // Username/password is not selected unless enabled
+    let request = b"\x05\x02\x00\x02\x05\x01\x00\x03\x0bexample.com\x01\xbb";
+    let (client, mut stream) = accept_with(Default::default(), request).await;
+    assert_eq!(None, client.unwrap().username);
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!([5, 0], reply);
+}
+
+#[tokio::test]
+async fn test_is_health_check() {
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let window = Duration::from_millis(200);
+
+    // Connected then closed
+    drop(TcpStream::connect(addr).await.unwrap());
+    let (left, _) = listener.accept().await.unwrap();
+    assert!(NewClient::is_health_check(&left, window).await);
+
+    // Sent something, which is kept for the handshake
+    let mut right = TcpStream::connect(addr).await.unwrap();
+    right.write_all(b"\x05").await.unwrap();
+    let (mut left, _) = listener.accept().await.unwrap();
+    assert!(!NewClient::is_health_check(&left, window).await);
+    let mut buf = [0u8; 1];
+    left.read_exact(&mut buf).await.unwrap();
+    assert_eq!([5], buf);
+
+    // Kept open but silent
+    let _right = TcpStream::connect(addr).await.unwrap();
+    let (left, _) = listener.accept().await.unwrap();
+    assert!(!NewClient::is_health_check(&left, window).await);
+}
+
+#[tokio::test]
+async fn test_accept_socks5_negotiation() {
+    let options = InboundOptions {
+        socks_username: true,
+        ..Default::default()
+    };
+    let negotiation_error = |client: io::Result<NewClient>| {
+        let err = client.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        *Socks5NegotiationError::from_io(&err).unwrap()
+    };
+    let read_to_end = |mut stream: TcpStream| async move {
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await.unwrap();
+        reply
+    };
+    let before = SOCKS5_NEGOTIATION.snapshot();
+
+    // No common method: GSSAPI only, or username/password not enabled
+    let (client, stream) = accept_with(options.clone(), b"\x05\x01\x01").await;
+    assert_eq!(
+        Socks5NegotiationError::NoAcceptableMethod,
+        negotiation_error(client)
+    );
+    assert_eq!(vec![5, 0xff], read_to_end(stream).await);
+    let (client, stream) = accept_with(Default::default(), b"\x05\x01\x02").await;
+    assert_eq!(
+        Socks5NegotiationError::NoAcceptableMethod,
+        negotiation_error(client)
+    );
+    assert_eq!(vec![5, 0xff], read_to_end(stream).await);
+
+    // Empty method list
+    let (client, stream) = accept_with(options.clone(), b"\x05\x00").await;
+    assert_eq!(Socks5NegotiationError::NoMethods, negotiation_error(client));
+    assert_eq!(vec![5, 0xff], read_to_end(stream).await);
+
+    // All 255 methods
+    let mut request = vec![5, 255];
+    request.extend(1..=255);
+    request.extend(b"\x01\x01x\x00\x05\x01\x00\x01\xc0\x00\x02\x01\x00\x50");
+    let (client, mut stream) = accept_with(options.clone(), request).await;
+    let client = client.unwrap();
+    assert_eq!("192.0.2.1:80", client.dest.to_string());
+    assert_eq!(Some("x"), client.username.as_deref());
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!([5, 2, 1, 0], reply);
+
+    // Auth failures
+    let request = b"\x05\x01\x02\x05\x01x\x00";
+    let (client, _) = accept_with(options.clone(), request).await;
+    assert_eq!(
+        Socks5NegotiationError::AuthVersion,
+        negotiation_error(client)
+    );
+    let request = b"\x05\x01\x02\x01\x01\xff\x00";
+    let (client, stream) = accept_with(options, request).await;
+    assert_eq!(
+        Socks5NegotiationError::AuthRejected,
+        negotiation_error(client)
+    );
+    assert_eq!(vec![5, 2, 1, 1], read_to_end(stream).await);
+
+    let after = SOCKS5_NEGOTIATION.snapshot();
+    assert!(after.no_acceptable_method >= before.no_acceptable_method + 2);
+    assert!(after.no_methods > before.no_methods);
+    assert!(after.auth_version > before.auth_version);
+    assert!(after.auth_rejected > before.auth_rejected);
+}
@@ -0,0 +1,173 @@
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::incr;
+
+/// Max size of the page file.
+pub const MAX_PAGE_LEN: usize = 64 * 1024;
+
+const DEFAULT_PAGE: &str = "<!DOCTYPE html>
+<html>
+<head><meta charset=\"utf-8\"><title>Blocked</title></head>
+<body>
+<h1>Blocked</h1>
+<p>Access to {{host}} is blocked by the proxy policy.</p>
+<p><small>{{rule}}</small></p>
+</body>
+</html>
+";
+
+/// Page answering plain HTTP requests rejected by the policy, see
+/// `NewClient::reply_rejected_page()`.
+///
+/// `{{host}}` and `{{rule}}` in it are replaced with the requested host
+/// and the text of the rule rejected it, HTML-escaped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectPage {
+    template: String,
+}
+
+/// Counters of `NewClient::reply_rejected_page()`, see `REJECT_PAGES`.
+#[derive(Debug)]
+pub struct RejectPageStats {
+    served: AtomicUsize,
+    /// Rejected without the page as not a HTTP request.
+    not_http: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct RejectPageCounters {
+    pub served: usize,
+    pub not_http: usize,
+}
+
+pub static REJECT_PAGES: RejectPageStats = RejectPageStats::new();
+
+impl RejectPageStats {
+    const fn new() -> Self {
+        Self {
+            served: AtomicUsize::new(0),
+            not_http: AtomicUsize::new(0),
+        }
+    }
+
+    pub(super) fn add(&self, served: bool) {
+        incr(if served { &self.served } else { &self.not_http })
+    }
+
+    pub fn snapshot(&self) -> RejectPageCounters {
+        RejectPageCounters {
+            served: self.served.load(Ordering::Relaxed),
+            not_http: self.not_http.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for RejectPage {
+    fn default() -> Self {
+        Self {
+            template: DEFAULT_PAGE.into(),
+        }
+    }
+}
+
+impl RejectPage {
+    /// Read the page from `path`, in UTF-8 and up to `MAX_PAGE_LEN` bytes.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut buf = Vec::new();
+        File::open(path)?
+            .take(MAX_PAGE_LEN as u64 + 1)
+            .read_to_end(&mut buf)?;
+        if buf.len() > MAX_PAGE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("larger than {} KiB", MAX_PAGE_LEN / 1024),
+            ));
+        }
+        let template = String::from_utf8(buf)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "not in UTF-8"))?;
+        Ok(Self { template })
+    }
+
+    pub fn render(&self, host: &str, rule: &str) -> String {
+        let mut page = String::with_capacity(self.template.len());
+        let mut rest = self.template.as_str();
+        // In one pass, never substitute in substituted values
+        while let Some(start) = rest.find("{{") {
+            page.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let var = [("{{host}}", host), ("{{rule}}", rule)]
+                .into_iter()
+                .find(|(name, _)| rest.starts_with(name));
+            match var {
+                Some((name, value)) => {
+                    page.push_str(&escape_html(value));
+                    rest = &rest[name.len()..];
+                }
+                None => {
+                    page.push_str("{{");
+                    rest = &rest[2..];
+                }
+            }
+        }
+        page.push_str(rest);
+        page
+    }
+
+    /// Full `403 Forbidden` response with the page.
+    pub(super) fn response(&self, host: &str, rule: &str) -> String {
+        let body = self.render(host, rule);
+        format!(
+            "HTTP/1.1 403 Forbidden\r\n\
+            Content-Type: text/html; charset=utf-8\r\n\
+            Content-Length: {}\r\n\
+            Cache-Control: no-store\r\n\
+            Connection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[test]
+fn test_reject_page() {
+    let page = RejectPage::default();
+    let html = page.render("<a>.example.com", "dst domain example.com reject");
+    assert!(html.contains("Access to &lt;a&gt;.example.com is blocked"));
+    assert!(html.contains("<small>dst domain example.com reject</small>"));
+
+    let path = std::env::temp_dir().join(format!("moproxy-test-page-{}.html", std::process::id()));
+    std::fs::write(&path, "{{host}} & {{host}}: {{rule}} {{other}}").unwrap();
+    let page = RejectPage::load(&path).unwrap();
+    assert_eq!("a & a: r&amp;r {{other}}", page.render("a", "r&r"));
+    assert_eq!(
+        "{{rule}} & {{rule}}: r {{other}}",
+        page.render("{{rule}}", "r")
+    );
+    std::fs::write(&path, vec![b'a'; MAX_PAGE_LEN + 1]).unwrap();
+    let err = RejectPage::load(&path).unwrap_err();
+    assert!(err.to_string().contains("larger than 64 KiB"));
+    std::fs::write(&path, [0xff, 0xfe]).unwrap();
+    assert!(RejectPage::load(&path).is_err());
+    std::fs::remove_file(path).unwrap();
+}
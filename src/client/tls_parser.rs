@@ -2,11 +2,68 @@ use std::ops::Range;
 use std::str::from_utf8;
 
 const EXT_SERVER_NAME: &[u8] = &[0, 0];
+const EXT_SUPPORTED_GROUPS: &[u8] = &[0, 10];
+const EXT_EC_POINT_FORMATS: &[u8] = &[0, 11];
 const EXT_EARLY_DATA: &[u8] = &[0, 42];
 
 pub struct TlsClientHello<'a> {
     pub server_name: Option<&'a str>,
     pub early_data: bool,
+    /// Set if requested on `parse_client_hello()`.
+    pub fingerprint: Option<TlsFingerprint>,
+}
+
+/// JA3-style fingerprint of a ClientHello, with GREASE values excluded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsFingerprint {
+    /// `version,ciphers,extensions,curves,point-formats` in decimal, with
+    /// items of each list joined by `-`.
+    pub ja3: String,
+    /// 64-bit FNV-1a hash of `ja3`. Unlike the original JA3, MD5 is not
+    /// used, so they are not comparable.
+    pub hash: u64,
+}
+
+impl TlsFingerprint {
+    fn new(version: u16, ciphers: &[u16], exts: &[u16], curves: &[u16], formats: &[u8]) -> Self {
+        fn join<T: ToString>(items: &[T]) -> String {
+            items
+                .iter()
+                .map(|n| n.to_string())
+                .collect::<Vec<_>>()
+                .join("-")
+        }
+        let ja3 = format!(
+            "{},{},{},{},{}",
+            version,
+            join(ciphers),
+            join(exts),
+            join(curves),
+            join(formats)
+        );
+        let hash = ja3.bytes().fold(0xcbf29ce484222325u64, |hash, b| {
+            (hash ^ b as u64).wrapping_mul(0x100000001b3)
+        });
+        Self { ja3, hash }
+    }
+
+    /// `hash` in 16 hex digits.
+    pub fn hash_hex(&self) -> String {
+        format!("{:016x}", self.hash)
+    }
+}
+
+/// GREASE values (RFC 8701) are 0x?a?a with identical bytes.
+fn is_grease(n: u16) -> bool {
+    n & 0x0f0f == 0x0a0a && n >> 8 == n & 0xff
+}
+
+/// Read a list of big-endian u16 without GREASE values.
+fn parse_u16_list(data: &[u8]) -> Vec<u16> {
+    data.chunks_exact(2)
+        .map(|n| u16::from_be_bytes([n[0], n[1]]))
+        .filter(|n| !is_grease(*n))
+        .collect()
 }
 
 struct TlsRecord<'a> {
@@ -34,7 +91,7 @@ fn drop_before(data: &[u8], len_pos: Range<usize>) -> Result<&[u8], &'static str
     Ok(&data[len_pos.end + len..])
 }
 
-fn parse_tls_record(data: &[u8]) -> Result<TlsRecord, &'static str> {
+fn parse_tls_record(data: &[u8]) -> Result<TlsRecord<'_>, &'static str> {
     let fragment = truncate(data, 3..5)?;
     Ok(TlsRecord {
         content_type: &data[0],
@@ -44,7 +101,35 @@ fn parse_tls_record(data: &[u8]) -> Result<TlsRecord, &'static str> {
     })
 }
 
-pub fn parse_client_hello(data: &[u8]) -> Result<TlsClientHello, &'static str> {
+/// Whether `data` conclusively fails TLS handshake record validation:
+/// wrong content type, impossible version, or oversized record.
+pub fn is_not_handshake(data: &[u8]) -> bool {
+    match *data {
+        [] => false,
+        [ctype, ..] if ctype != 22 => true,
+        [_, major, ..] if major != 3 => true,
+        [_, _, minor, ..] if minor > 4 => true,
+        [_, _, _, hi, lo, ..] => u16::from_be_bytes([hi, lo]) > 1 << 14,
+        _ => false,
+    }
+}
+
+/// Whether `data` is the beginning of a TLS handshake record but not
+/// the whole of it.
+pub fn is_partial_handshake(data: &[u8]) -> bool {
+    match data.get(3..5) {
+        _ if data.is_empty() || is_not_handshake(data) => false,
+        None => true,
+        Some(len) => data.len() < 5 + u16::from_be_bytes([len[0], len[1]]) as usize,
+    }
+}
+
+/// Parse TLS ClientHello record. Compute its fingerprint if `fingerprint`
+/// is true.
+pub fn parse_client_hello(
+    data: &[u8],
+    fingerprint: bool,
+) -> Result<TlsClientHello<'_>, &'static str> {
     let TlsRecord {
         content_type: &ctype,
         version_major: &version,
@@ -67,10 +152,12 @@ pub fn parse_client_hello(data: &[u8]) -> Result<TlsClientHello, &'static str> {
     if hello.first() != Some(&3) {
         return Err("unsupported client version");
     }
+    let version = u16::from_be_bytes([hello[0], *hello.get(1).ok_or("not enough data")?]);
     // 2..34: 32-bytes random, dropped
     // 34+: session id, dropped
     let remaining = drop_before(hello, 34..35)?;
-    // cipher suite, dropped
+    // cipher suite, dropped unless fingerprinting
+    let ciphers = truncate(remaining, 0..2)?;
     let remaining = drop_before(remaining, 0..2)?;
     // compression methods, dropped
     let remaining = drop_before(remaining, 0..1)?;
@@ -78,6 +165,9 @@ pub fn parse_client_hello(data: &[u8]) -> Result<TlsClientHello, &'static str> {
     let mut exts = truncate(remaining, 0..2)?;
     let mut server_name = None;
     let mut early_data = false;
+    let mut ext_types = vec![];
+    let mut curves = vec![];
+    let mut formats = vec![];
     while exts.len() >= 4 {
         // 0..2: extension type
         let ext_type = &exts[0..2];
@@ -89,11 +179,25 @@ pub fn parse_client_hello(data: &[u8]) -> Result<TlsClientHello, &'static str> {
         } else if ext_type == EXT_EARLY_DATA {
             early_data = true;
         }
+        if !fingerprint {
+            continue;
+        }
+        ext_types.extend(parse_u16_list(ext_type));
+        if ext_type == EXT_SUPPORTED_GROUPS {
+            curves = parse_u16_list(truncate(ext_data, 0..2)?);
+        } else if ext_type == EXT_EC_POINT_FORMATS {
+            formats = truncate(ext_data, 0..1)?.to_vec();
+        }
     }
+    let fingerprint = fingerprint.then(|| {
+        let ciphers = parse_u16_list(ciphers);
+        TlsFingerprint::new(version, &ciphers, &ext_types, &curves, &formats)
+    });
 
     Ok(TlsClientHello {
         server_name,
         early_data,
+        fingerprint,
     })
 }
 
@@ -107,7 +211,7 @@ fn parse_server_name_ext(ext_data: &[u8]) -> Result<Option<&str>, &'static str>
         if name_type == 0 {
             // hostname
             let name = from_utf8(value).map_err(|_| "server name not utf-8 string")?;
-            if name.as_bytes().len() > 255 {
+            if name.len() > 255 {
                 return Err("server name too long");
             }
             if !name
@@ -122,6 +226,124 @@ fn parse_server_name_ext(ext_data: &[u8]) -> Result<Option<&str>, &'static str>
     Ok(None)
 }
 
+const CIPHER_SUITES: &[u16] = &[
+    0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc009, 0xc013, 0xc00a, 0xc014, 0x009c, 0x009d,
+    0x002f, 0x0035,
+];
+const SUPPORTED_GROUPS: &[u16] = &[0x001d, 0x0017, 0x0018];
+const SIGNATURE_ALGORITHMS: &[u16] = &[
+    0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601, 0x0201,
+];
+
+fn push_u16(buf: &mut Vec<u8>, n: u16) {
+    buf.extend_from_slice(&n.to_be_bytes());
+}
+
+/// Fill in the big-endian length of `buf[len_pos.end..]` into `len_pos`.
+fn fill_length(buf: &mut [u8], len_pos: Range<usize>) {
+    let len = (buf.len() - len_pos.end).to_be_bytes();
+    let n = len_pos.len();
+    buf[len_pos].copy_from_slice(&len[len.len() - n..]);
+}
+
+/// Build a TLS 1.2 ClientHello record with SNI set to `server_name`.
+///
+/// TLS 1.3 is deliberately not offered, so that the server's certificate
+/// is sent in plain text and can be inspected by `parse_server_certificate`.
+pub fn build_client_hello(server_name: &str) -> Vec<u8> {
+    let mut exts = vec![];
+    // server name
+    let name = server_name.as_bytes();
+    push_u16(&mut exts, 0);
+    push_u16(&mut exts, name.len() as u16 + 5);
+    push_u16(&mut exts, name.len() as u16 + 3);
+    exts.push(0); // hostname
+    push_u16(&mut exts, name.len() as u16);
+    exts.extend_from_slice(name);
+    // supported groups
+    push_u16(&mut exts, 0x000a);
+    push_u16(&mut exts, SUPPORTED_GROUPS.len() as u16 * 2 + 2);
+    push_u16(&mut exts, SUPPORTED_GROUPS.len() as u16 * 2);
+    SUPPORTED_GROUPS
+        .iter()
+        .for_each(|g| push_u16(&mut exts, *g));
+    // ec point formats: uncompressed
+    exts.extend_from_slice(&[0x00, 0x0b, 0x00, 0x02, 0x01, 0x00]);
+    // signature algorithms
+    push_u16(&mut exts, 0x000d);
+    push_u16(&mut exts, SIGNATURE_ALGORITHMS.len() as u16 * 2 + 2);
+    push_u16(&mut exts, SIGNATURE_ALGORITHMS.len() as u16 * 2);
+    SIGNATURE_ALGORITHMS
+        .iter()
+        .for_each(|a| push_u16(&mut exts, *a));
+    // extended master secret
+    exts.extend_from_slice(&[0x00, 0x17, 0x00, 0x00]);
+    // renegotiation info
+    exts.extend_from_slice(&[0xff, 0x01, 0x00, 0x01, 0x00]);
+
+    // record header & handshake header, length filled in later
+    let mut buf = vec![22, 3, 1, 0, 0, 1, 0, 0, 0];
+    buf.extend_from_slice(&[3, 3]); // client version: TLS 1.2
+    buf.extend_from_slice(&rand::random::<[u8; 32]>());
+    buf.push(0); // empty session id
+    push_u16(&mut buf, CIPHER_SUITES.len() as u16 * 2);
+    CIPHER_SUITES.iter().for_each(|c| push_u16(&mut buf, *c));
+    buf.extend_from_slice(&[1, 0]); // compression methods: null
+    push_u16(&mut buf, exts.len() as u16);
+    buf.extend_from_slice(&exts);
+    fill_length(&mut buf, 6..9);
+    fill_length(&mut buf, 3..5);
+    buf
+}
+
+/// Parse server's response to our ClientHello, return the DER of the
+/// end-entity certificate.
+///
+/// Return `Ok(None)` if more data is required.
+pub fn parse_server_certificate(data: &[u8]) -> Result<Option<Vec<u8>>, &'static str> {
+    // Reassemble handshake messages, which may span multiple records
+    let mut handshake = Vec::with_capacity(data.len());
+    let mut records = data;
+    while records.len() >= 5 {
+        let record = match parse_tls_record(records) {
+            Ok(record) => record,
+            Err(_) => break, // incomplete
+        };
+        if *record.version_major != 3 {
+            return Err("unknown tls version");
+        }
+        match record.content_type {
+            22 => handshake.extend_from_slice(record.fragment),
+            21 => return Err("alert from server"),
+            _ => return Err("not handshake"),
+        }
+        records = &records[5 + record.fragment.len()..];
+    }
+    if records.first().is_some_and(|t| *t != 22 && *t != 21) {
+        return Err("not handshake");
+    }
+
+    let mut msgs = &handshake[..];
+    while msgs.len() >= 4 {
+        let msg = match truncate(msgs, 1..4) {
+            Ok(msg) => msg,
+            Err(_) => return Ok(None),
+        };
+        match msgs[0] {
+            // server hello
+            2 => (),
+            // certificate
+            11 => {
+                let certs = truncate(msg, 0..3)?;
+                return Ok(Some(truncate(certs, 0..3)?.to_vec()));
+            }
+            _ => return Err("no certificate from server"),
+        }
+        msgs = &msgs[4 + msg.len()..];
+    }
+    Ok(None)
+}
+
 #[test]
 fn test_parse_without_server_name() {
     let data = [
@@ -151,7 +373,7 @@ fn test_parse_without_server_name() {
     assert_eq!(1, fragment[0]);
     assert_eq!(Some(&1), fragment.last());
 
-    let TlsClientHello { server_name, .. } = parse_client_hello(&data).unwrap();
+    let TlsClientHello { server_name, .. } = parse_client_hello(&data, false).unwrap();
     assert_eq!(None, server_name);
 }
 
@@ -172,6 +394,95 @@ fn test_parse_with_server_name() {
         0x00, 0x18, 0x00, 0x16, 0x04, 0x03, 0x05, 0x03, 0x06, 0x03, 0x08, 0x04, 0x08, 0x05, 0x08,
         0x06, 0x04, 0x01, 0x05, 0x01, 0x06, 0x01, 0x02, 0x03, 0x02, 0x01,
     ];
-    let TlsClientHello { server_name, .. } = parse_client_hello(&data).unwrap();
+    let TlsClientHello { server_name, .. } = parse_client_hello(&data, false).unwrap();
     assert_eq!(Some("www.google.com"), server_name);
 }
+
+#[test]
+fn test_build_client_hello() {
+    let data = build_client_hello("www.example.com");
+    assert_eq!(data.len(), truncate(&data, 3..5).unwrap().len() + 5);
+    let TlsClientHello {
+        server_name,
+        early_data,
+        ..
+    } = parse_client_hello(&data, false).unwrap();
+    assert_eq!(Some("www.example.com"), server_name);
+    assert!(!early_data);
+}
+
+#[test]
+fn test_parse_server_certificate() {
+    let cert = crate::client::x509::TEST_CERT;
+    let mut hello = vec![2, 0, 0, 38, 3, 3];
+    hello.extend_from_slice(&[0; 32]); // random
+    hello.extend_from_slice(&[0, 0xc0, 0x2b, 0]); // session id, cipher, compression
+    let mut certificate = vec![11, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    certificate.extend_from_slice(cert);
+    let len = cert.len();
+    certificate[3] = (len + 6) as u8;
+    certificate[2] = ((len + 6) >> 8) as u8;
+    certificate[6] = (len + 3) as u8;
+    certificate[5] = ((len + 3) >> 8) as u8;
+    certificate[9] = len as u8;
+    certificate[8] = (len >> 8) as u8;
+
+    // ServerHello & Certificate on separated records
+    let mut data = vec![22, 3, 3, 0, hello.len() as u8];
+    data.extend_from_slice(&hello);
+    data.extend_from_slice(&[22, 3, 3]);
+    data.extend_from_slice(&(certificate.len() as u16).to_be_bytes());
+    data.extend_from_slice(&certificate);
+    assert_eq!(Ok(None), parse_server_certificate(&data[..60]));
+    assert_eq!(Ok(Some(cert.to_vec())), parse_server_certificate(&data));
+
+    assert!(parse_server_certificate(b"HTTP/1.1 200 OK\r\n").is_err());
+    assert!(parse_server_certificate(&[21, 3, 3, 0, 2, 2, 40]).is_err());
+}
+
+#[test]
+fn test_fingerprint_client_hello() {
+    let mut data = build_client_hello("www.example.com");
+    let TlsClientHello { fingerprint, .. } = parse_client_hello(&data, true).unwrap();
+    let fingerprint = fingerprint.unwrap();
+    assert_eq!(
+        "771,49195-49199-49196-49200-52393-52392-49161-49171-49162-49172-156-157-47-53,\
+         0-10-11-13-23-65281,29-23-24,0",
+        fingerprint.ja3
+    );
+    assert_eq!(16, fingerprint.hash_hex().len());
+    assert!(parse_client_hello(&data, false)
+        .unwrap()
+        .fingerprint
+        .is_none());
+
+    // GREASE on cipher suites, extensions & groups is ignored
+    assert!(is_grease(0x0a0a) && is_grease(0xfafa) && !is_grease(0x0a1a));
+    let pos = data.windows(2).position(|w| w == [0xc0, 0x2b]).unwrap();
+    data[pos..pos + 2].copy_from_slice(&[0x3a, 0x3a]);
+    let groups = data
+        .windows(4)
+        .position(|w| w == [0, 0x1d, 0, 0x17])
+        .unwrap();
+    data[groups..groups + 2].copy_from_slice(&[0x7a, 0x7a]);
+    let greased = parse_client_hello(&data, true)
+        .unwrap()
+        .fingerprint
+        .unwrap();
+    assert!(greased.ja3.starts_with("771,49199-"));
+    assert!(greased.ja3.ends_with(",23-24,0"));
+}
+
+#[test]
+fn test_is_not_handshake() {
+    let hello = build_client_hello("example.com");
+    assert!(!is_not_handshake(&hello));
+    assert!(!is_not_handshake(&hello[..3]));
+    assert!(!is_not_handshake(&[]));
+    assert!(is_not_handshake(b"GET / HTTP/1.1\r\n"));
+    assert!(is_not_handshake(&[22, 0x47]));
+    assert!(is_not_handshake(&[22, 3, 9]));
+    assert!(is_not_handshake(&[22, 3, 1, 0xff, 0xff]));
+    assert!(!is_partial_handshake(&[22, 0x47]));
+    assert!(is_partial_handshake(&hello[..3]));
+}
@@ -0,0 +1,179 @@
+//! Just enough DER decoding to read the names on a X.509 certificate.
+use std::str::from_utf8;
+
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+const TAG_OID: u8 = 0x06;
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_VERSION: u8 = 0xa0;
+const TAG_EXTENSIONS: u8 = 0xa3;
+const TAG_DNS_NAME: u8 = 0x82;
+
+/// Split `data` into (tag, content, remaining).
+fn read_tlv(data: &[u8]) -> Result<(u8, &[u8], &[u8]), &'static str> {
+    let tag = *data.first().ok_or("lack data to decode tag")?;
+    let len_byte = *data.get(1).ok_or("lack data to decode length")?;
+    let (len, header_len) = if len_byte < 0x80 {
+        (len_byte as usize, 2)
+    } else {
+        let n = (len_byte & 0x7f) as usize;
+        if n == 0 || n > 4 {
+            return Err("unsupported length encoding");
+        }
+        let len_bits = data.get(2..2 + n).ok_or("lack data to decode length")?;
+        let len = len_bits
+            .iter()
+            .fold(0usize, |acc, b| acc << 8 | *b as usize);
+        (len, 2 + n)
+    };
+    let content = data
+        .get(header_len..header_len + len)
+        .ok_or("not enough data")?;
+    Ok((tag, content, &data[header_len + len..]))
+}
+
+fn expect_tlv(data: &[u8], expected: u8) -> Result<(&[u8], &[u8]), &'static str> {
+    match read_tlv(data)? {
+        (tag, content, rem) if tag == expected => Ok((content, rem)),
+        _ => Err("unexpected tag"),
+    }
+}
+
+/// Return common names (CN) in the subject and DNS names in the subject
+/// alternative name extension.
+pub fn certificate_names(der: &[u8]) -> Result<Vec<&str>, &'static str> {
+    let (cert, _) = expect_tlv(der, TAG_SEQUENCE)?;
+    let (tbs, _) = expect_tlv(cert, TAG_SEQUENCE)?;
+    let mut remaining = tbs;
+    if remaining.first() == Some(&TAG_VERSION) {
+        remaining = read_tlv(remaining)?.2;
+    }
+    // serial number, signature algorithm, issuer, validity: dropped
+    for _ in 0..4 {
+        remaining = read_tlv(remaining)?.2;
+    }
+    let (subject, mut remaining) = expect_tlv(remaining, TAG_SEQUENCE)?;
+    let mut names = common_names(subject)?;
+
+    // subject public key info, issuer/subject unique id: dropped
+    while !remaining.is_empty() {
+        let (tag, content, rem) = read_tlv(remaining)?;
+        remaining = rem;
+        if tag == TAG_EXTENSIONS {
+            names.extend(subject_alt_names(content)?);
+        }
+    }
+    Ok(names)
+}
+
+fn common_names(name: &[u8]) -> Result<Vec<&str>, &'static str> {
+    let mut names = vec![];
+    let mut rdns = name;
+    while !rdns.is_empty() {
+        let (set, rem) = expect_tlv(rdns, TAG_SET)?;
+        rdns = rem;
+        let mut attrs = set;
+        while !attrs.is_empty() {
+            let (attr, rem) = expect_tlv(attrs, TAG_SEQUENCE)?;
+            attrs = rem;
+            let (oid, value) = expect_tlv(attr, TAG_OID)?;
+            if oid == OID_COMMON_NAME {
+                let (_, value, _) = read_tlv(value)?;
+                names.push(from_utf8(value).map_err(|_| "common name not utf-8 string")?);
+            }
+        }
+    }
+    Ok(names)
+}
+
+fn subject_alt_names(extensions: &[u8]) -> Result<Vec<&str>, &'static str> {
+    let mut names = vec![];
+    let (mut exts, _) = expect_tlv(extensions, TAG_SEQUENCE)?;
+    while !exts.is_empty() {
+        let (ext, rem) = expect_tlv(exts, TAG_SEQUENCE)?;
+        exts = rem;
+        let (oid, mut ext) = expect_tlv(ext, TAG_OID)?;
+        if oid != OID_SUBJECT_ALT_NAME {
+            continue;
+        }
+        if ext.first() == Some(&TAG_BOOLEAN) {
+            ext = read_tlv(ext)?.2; // critical flag
+        }
+        let (value, _) = expect_tlv(ext, TAG_OCTET_STRING)?;
+        let (mut general_names, _) = expect_tlv(value, TAG_SEQUENCE)?;
+        while !general_names.is_empty() {
+            let (tag, name, rem) = read_tlv(general_names)?;
+            general_names = rem;
+            if tag == TAG_DNS_NAME {
+                names.push(from_utf8(name).map_err(|_| "dns name not utf-8 string")?);
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Check if `host` is covered by certificate `name`, which may contain
+/// a wildcard on its left-most label.
+pub fn name_matches(name: &str, host: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    match name.strip_prefix("*.") {
+        Some(suffix) => match host.split_once('.') {
+            Some((label, rest)) => !label.is_empty() && rest.eq_ignore_ascii_case(suffix),
+            None => false,
+        },
+        None => name.eq_ignore_ascii_case(host),
+    }
+}
+
+#[cfg(test)]
+pub(crate) const TEST_CERT: &[u8] = &[
+    0x30, 0x82, 0x01, 0xa8, 0x30, 0x82, 0x01, 0x4e, 0xa0, 0x03, 0x02, 0x01, 0x02, 0x02, 0x14, 0x4a,
+    0xe0, 0x87, 0x70, 0xb1, 0xc3, 0x45, 0xd3, 0xe6, 0x92, 0xbe, 0x71, 0xd3, 0xee, 0x85, 0x50, 0x56,
+    0xec, 0x74, 0x01, 0x30, 0x0a, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02, 0x30,
+    0x16, 0x31, 0x14, 0x30, 0x12, 0x06, 0x03, 0x55, 0x04, 0x03, 0x0c, 0x0b, 0x65, 0x78, 0x61, 0x6d,
+    0x70, 0x6c, 0x65, 0x2e, 0x63, 0x6f, 0x6d, 0x30, 0x1e, 0x17, 0x0d, 0x32, 0x36, 0x31, 0x30, 0x31,
+    0x34, 0x31, 0x31, 0x34, 0x33, 0x33, 0x36, 0x5a, 0x17, 0x0d, 0x33, 0x36, 0x31, 0x30, 0x31, 0x31,
+    0x31, 0x31, 0x34, 0x33, 0x33, 0x36, 0x5a, 0x30, 0x16, 0x31, 0x14, 0x30, 0x12, 0x06, 0x03, 0x55,
+    0x04, 0x03, 0x0c, 0x0b, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e, 0x63, 0x6f, 0x6d, 0x30,
+    0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a, 0x86,
+    0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00, 0x04, 0x2a, 0x17, 0xe3, 0x08, 0xfd, 0x12,
+    0x7c, 0x81, 0xf5, 0x75, 0x94, 0x9f, 0x94, 0x53, 0xc8, 0x26, 0x8a, 0xa4, 0xcc, 0x4f, 0xbc, 0xec,
+    0xf4, 0xea, 0xf8, 0x20, 0xee, 0xd3, 0x22, 0x20, 0x90, 0x55, 0xb2, 0x53, 0x70, 0xfa, 0x4d, 0x65,
+    0x23, 0xd7, 0x53, 0x5e, 0xb0, 0x7e, 0x89, 0x51, 0xd7, 0x37, 0x4b, 0xd4, 0x47, 0x16, 0xb7, 0xf2,
+    0x6f, 0x53, 0x9a, 0xfe, 0x9f, 0xb6, 0x50, 0x78, 0x16, 0x3c, 0xa3, 0x7a, 0x30, 0x78, 0x30, 0x1d,
+    0x06, 0x03, 0x55, 0x1d, 0x0e, 0x04, 0x16, 0x04, 0x14, 0xb8, 0x95, 0xa4, 0xc7, 0x8e, 0xb4, 0x42,
+    0x6b, 0x70, 0xb6, 0xf8, 0xf8, 0x43, 0xb2, 0xb1, 0x48, 0xcd, 0x02, 0x1b, 0xba, 0x30, 0x1f, 0x06,
+    0x03, 0x55, 0x1d, 0x23, 0x04, 0x18, 0x30, 0x16, 0x80, 0x14, 0xb8, 0x95, 0xa4, 0xc7, 0x8e, 0xb4,
+    0x42, 0x6b, 0x70, 0xb6, 0xf8, 0xf8, 0x43, 0xb2, 0xb1, 0x48, 0xcd, 0x02, 0x1b, 0xba, 0x30, 0x0f,
+    0x06, 0x03, 0x55, 0x1d, 0x13, 0x01, 0x01, 0xff, 0x04, 0x05, 0x30, 0x03, 0x01, 0x01, 0xff, 0x30,
+    0x25, 0x06, 0x03, 0x55, 0x1d, 0x11, 0x04, 0x1e, 0x30, 0x1c, 0x82, 0x0b, 0x65, 0x78, 0x61, 0x6d,
+    0x70, 0x6c, 0x65, 0x2e, 0x63, 0x6f, 0x6d, 0x82, 0x0d, 0x2a, 0x2e, 0x65, 0x78, 0x61, 0x6d, 0x70,
+    0x6c, 0x65, 0x2e, 0x6f, 0x72, 0x67, 0x30, 0x0a, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04,
+    0x03, 0x02, 0x03, 0x48, 0x00, 0x30, 0x45, 0x02, 0x21, 0x00, 0x89, 0x25, 0xae, 0x79, 0xbb, 0xb5,
+    0x77, 0x13, 0x7c, 0xd7, 0xef, 0x97, 0x89, 0x5a, 0x60, 0x07, 0x02, 0xc5, 0xb3, 0x39, 0xb7, 0x43,
+    0xef, 0x8a, 0x2c, 0xc5, 0xdf, 0x73, 0x9d, 0xdb, 0x71, 0x38, 0x02, 0x20, 0x23, 0xab, 0x25, 0xbb,
+    0x51, 0x27, 0x87, 0x3c, 0x35, 0xfa, 0x23, 0x5a, 0xd1, 0x24, 0xdc, 0x03, 0x7c, 0x3f, 0xeb, 0xfa,
+    0x71, 0x02, 0x3c, 0x36, 0x04, 0xe5, 0xd3, 0xf1, 0xf3, 0xe3, 0x89, 0x89,
+];
+
+#[test]
+fn test_certificate_names() {
+    // Self-signed, CN=example.com, SAN=example.com,*.example.org
+    let names = certificate_names(TEST_CERT).unwrap();
+    assert_eq!(vec!["example.com", "example.com", "*.example.org"], names);
+    assert!(certificate_names(&TEST_CERT[..100]).is_err());
+}
+
+#[test]
+fn test_name_matches() {
+    assert!(name_matches("example.com", "example.com"));
+    assert!(name_matches("Example.COM", "example.com."));
+    assert!(!name_matches("example.com", "www.example.com"));
+    assert!(name_matches("*.example.org", "www.example.org"));
+    assert!(!name_matches("*.example.org", "example.org"));
+    assert!(!name_matches("*.example.org", "a.b.example.org"));
+}
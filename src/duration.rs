@@ -0,0 +1,156 @@
+use std::{error::Error, fmt, fmt::Write, time::Duration};
+
+/// Error of `parse_duration()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseDurationError {
+    input: String,
+    reason: &'static str,
+}
+
+impl fmt::Display for ParseDurationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "`{}` isn't a valid duration: {}",
+            self.input, self.reason
+        )
+    }
+}
+
+impl Error for ParseDurationError {}
+
+/// Parse duration like `500ms`, `30s`, `1m30s` or `2h`. Units (`ms`,
+/// `s`, `m`, `h`) are case insensitive and must go from the largest to
+/// the smallest, each at most once.
+pub fn parse_duration(s: &str) -> Result<Duration, ParseDurationError> {
+    let err = |reason| ParseDurationError {
+        input: s.into(),
+        reason,
+    };
+    let units = [
+        ("h", Duration::from_secs(3600)),
+        ("m", Duration::from_secs(60)),
+        ("s", Duration::from_secs(1)),
+        ("ms", Duration::from_millis(1)),
+    ];
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err(err("empty"));
+    }
+    let mut total = Duration::ZERO;
+    let mut next_unit = 0;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if digits == 0 {
+            return Err(err("expected a number"));
+        }
+        let n: u32 = rest[..digits]
+            .parse()
+            .map_err(|_| err("number too large"))?;
+        rest = &rest[digits..];
+        let len = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let unit = &rest[..len];
+        rest = &rest[len..];
+        if unit.is_empty() {
+            return Err(err("missing unit (ms, s, m, or h)"));
+        }
+        let i = match units.iter().position(|(u, _)| u.eq_ignore_ascii_case(unit)) {
+            Some(i) => i,
+            None => return Err(err("unknown unit, expected ms, s, m, or h")),
+        };
+        if i < next_unit {
+            return Err(err("units out of order or repeated"));
+        }
+        next_unit = i + 1;
+        total = units[i]
+            .1
+            .checked_mul(n)
+            .and_then(|d| total.checked_add(d))
+            .ok_or_else(|| err("too long"))?;
+    }
+    Ok(total)
+}
+
+/// Like `parse_duration()`, but a plain number is in `unit`.
+pub fn parse_duration_or_in(s: &str, unit: Duration) -> Result<Duration, ParseDurationError> {
+    match s.trim().parse::<u32>() {
+        Ok(n) => Ok(unit * n),
+        Err(_) => parse_duration(s),
+    }
+}
+
+pub trait DurationExt {
+    /// At most two largest units, e.g. `1d2h` or `5m`, empty if less
+    /// than one second.
+    fn format(&self) -> String;
+    fn format_millis(&self) -> String;
+}
+
+impl DurationExt for Duration {
+    fn format(&self) -> String {
+        let secs = self.as_secs();
+        let d = secs / 86400;
+        let h = (secs % 86400) / 3600;
+        let m = (secs % 3600) / 60;
+        let s = secs % 60;
+        let mut buf = String::new();
+        vec![(d, 'd'), (h, 'h'), (m, 'm'), (s, 's')]
+            .into_iter()
+            .filter(|(v, _)| *v > 0)
+            .take(2)
+            .for_each(|(v, u)| {
+                write!(&mut buf, "{}{}", v, u).unwrap();
+            });
+        buf
+    }
+
+    fn format_millis(&self) -> String {
+        format!("{} ms", self.as_millis())
+    }
+}
+
+#[test]
+fn test_parse_duration() {
+    let ms = Duration::from_millis;
+    let secs = Duration::from_secs;
+    assert_eq!(Ok(ms(500)), parse_duration("500ms"));
+    assert_eq!(Ok(secs(30)), parse_duration("30s"));
+    assert_eq!(Ok(secs(90)), parse_duration("1m30s"));
+    assert_eq!(Ok(secs(7200)), parse_duration("2H"));
+    assert_eq!(Ok(ms(3_723_004)), parse_duration(" 1h2m3s4ms "));
+    assert_eq!(Ok(Duration::ZERO), parse_duration("0s"));
+
+    for (input, reason) in [
+        ("", "empty"),
+        ("10", "missing unit"),
+        ("s", "expected a number"),
+        ("1.5s", "unknown unit"),
+        ("10x", "unknown unit"),
+        ("-1s", "expected a number"),
+        ("30s1m", "out of order"),
+        ("1s1s", "out of order"),
+        ("99999999999s", "too large"),
+    ] {
+        let err = parse_duration(input).unwrap_err().to_string();
+        assert!(err.contains(reason), "{}: {}", input, err);
+        assert!(err.starts_with(&format!("`{}` ", input)), "{}", err);
+    }
+
+    assert_eq!(Ok(secs(4)), parse_duration_or_in("4", secs(1)));
+    assert_eq!(Ok(ms(4)), parse_duration_or_in("4", ms(1)));
+    assert_eq!(Ok(ms(1500)), parse_duration_or_in("1s500ms", ms(1)));
+    assert!(parse_duration_or_in("4 s", secs(1)).is_err());
+}
+
+#[test]
+fn test_format_duration() {
+    assert_eq!("", Duration::from_millis(999).format());
+    assert_eq!("5m", Duration::from_secs(300).format());
+    assert_eq!("1d2h", Duration::from_secs(86400 + 7200 + 5).format());
+    assert_eq!("1m30s", Duration::from_secs(90).format());
+    assert_eq!("1500 ms", Duration::from_millis(1500).format_millis());
+}
@@ -0,0 +1,115 @@
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+
+/// Sample counts in fixed buckets, given by their upper bounds, plus an
+/// extra overflow bucket for larger ones. Units of bounds and samples are
+/// up to the user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buckets<const N: usize> {
+    bounds: &'static [u64; N],
+    counts: [u64; N],
+    overflow: u64,
+    count: u64,
+    sum: u64,
+}
+
+impl<const N: usize> Buckets<N> {
+    pub const fn new(bounds: &'static [u64; N]) -> Self {
+        Self {
+            bounds,
+            counts: [0; N],
+            overflow: 0,
+            count: 0,
+            sum: 0,
+        }
+    }
+
+    pub fn add(&mut self, value: u64) {
+        let i = self.bounds.partition_point(|bound| *bound < value);
+        match self.counts.get_mut(i) {
+            Some(n) => *n += 1,
+            None => self.overflow += 1,
+        }
+        self.count += 1;
+        self.sum = self.sum.saturating_add(value);
+    }
+
+    /// Halve all counts and the sum, for rolling windows.
+    pub fn halve(&mut self) {
+        self.counts.iter_mut().for_each(|n| *n /= 2);
+        self.overflow /= 2;
+        self.count = self.counts.iter().sum::<u64>() + self.overflow;
+        self.sum /= 2;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+
+    pub fn bounds(&self) -> &'static [u64; N] {
+        self.bounds
+    }
+
+    /// Samples in each bucket, not cumulative, the overflow one at last.
+    pub fn counts(&self) -> impl Iterator<Item = u64> + '_ {
+        self.counts.iter().copied().chain([self.overflow])
+    }
+
+    /// Upper bounds with cumulative counts, the last one is the overflow
+    /// (`None`) with the total count.
+    pub fn cumulative(&self) -> impl Iterator<Item = (Option<u64>, u64)> + '_ {
+        let bounds = self.bounds.iter().copied().map(Some).chain([None]);
+        bounds.zip(self.counts().scan(0, |seen, n| {
+            *seen += n;
+            Some(*seen)
+        }))
+    }
+
+    /// Upper bound of the bucket where the given percentile falls, capped
+    /// at the largest bound.
+    pub fn percentile(&self, percent: u64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = (self.count * percent).div_ceil(100);
+        self.cumulative()
+            .find(|(_, seen)| *seen >= rank)
+            .and_then(|(bound, _)| bound)
+            .or(self.bounds.last().copied())
+    }
+}
+
+impl<const N: usize> Serialize for Buckets<N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Buckets", 3)?;
+        state.serialize_field("buckets", &self.counts().collect::<Vec<_>>())?;
+        state.serialize_field("count", &self.count)?;
+        state.serialize_field("sum", &self.sum)?;
+        state.end()
+    }
+}
+
+#[test]
+fn test_buckets() {
+    let mut buckets = Buckets::new(&[10, 100]);
+    assert_eq!(None, buckets.percentile(50));
+    for value in [0, 10, 11, 100, 101, 1_000] {
+        buckets.add(value);
+    }
+    assert_eq!(vec![2, 2, 2], buckets.counts().collect::<Vec<_>>());
+    assert_eq!(
+        vec![(Some(10), 2), (Some(100), 4), (None, 6)],
+        buckets.cumulative().collect::<Vec<_>>()
+    );
+    assert_eq!((6, 1_222), (buckets.count(), buckets.sum()));
+    assert_eq!(Some(10), buckets.percentile(30));
+    assert_eq!(Some(100), buckets.percentile(60));
+    assert_eq!(Some(100), buckets.percentile(95));
+
+    buckets.halve();
+    assert_eq!(vec![1, 1, 1], buckets.counts().collect::<Vec<_>>());
+    assert_eq!((3, 611), (buckets.count(), buckets.sum()));
+}
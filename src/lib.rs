@@ -1,9 +1,13 @@
 pub mod client;
+pub mod duration;
 pub mod futures_stream;
+pub mod histogram;
 #[cfg(target_os = "linux")]
 pub mod linux;
 pub mod monitor;
 pub mod policy;
+pub mod privacy;
 pub mod proxy;
+pub mod sha256;
 #[cfg(feature = "web_console")]
 pub mod web;
@@ -1,3 +1,19 @@
 #[cfg(feature = "systemd")]
 pub mod systemd;
 pub mod tcp;
+
+use std::{io, time::Duration};
+
+/// Time since boot, including the time suspended, unlike the monotonic
+/// clock.
+pub fn boot_time() -> io::Result<Duration> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    let ret = unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut ts) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
@@ -1,9 +1,21 @@
 use libc::{dev_t as Dev, ino_t as Inode};
 use nix::sys::stat::fstat;
 use sd_notify::{notify, NotifyState};
-use std::{borrow::Cow, env, io, os::unix::prelude::AsRawFd, process, time::Duration};
+use std::{
+    borrow::Cow,
+    env, io,
+    os::unix::prelude::AsRawFd,
+    process,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::time::sleep;
-use tracing::{info, instrument, trace, warn};
+use tracing::{error, info, instrument, trace, warn};
+
+use crate::{
+    duration::DurationExt,
+    monitor::{check_deadlocks, Liveness, LivenessSignal},
+};
 
 fn notify_enabled() -> bool {
     env::var_os("NOTIFY_SOCKET").is_some()
@@ -45,15 +57,35 @@ pub fn watchdog_timeout() -> Option<Duration> {
     Some(Duration::from_micros(usec))
 }
 
+/// Poke the watchdog every `interval` as long as there is no deadlock
+/// and all signals of `liveness` are within their thresholds, so that
+/// systemd restarts us once stalled.
 #[instrument(skip_all)]
-pub async fn watchdog_loop(timeout: Duration) {
-    info!("Watchdog enabled, poke for every {}ms", timeout.as_millis());
+pub async fn watchdog_loop(
+    interval: Duration,
+    liveness: Arc<Liveness>,
+    thresholds: Vec<(LivenessSignal, Duration)>,
+) {
+    info!(
+        "Watchdog enabled, poke for every {}ms",
+        interval.as_millis()
+    );
     loop {
-        trace!("poke the watchdog");
-        if notify(false, &[NotifyState::Watchdog]).is_err() {
-            warn!("fail to poke watchdog");
+        let stale = liveness.stale_at(Instant::now(), &thresholds);
+        for (signal, age) in &stale {
+            error!(
+                "{} stalled for {}, stop poking watchdog",
+                signal,
+                age.format()
+            );
+        }
+        if stale.is_empty() && check_deadlocks() == 0 {
+            trace!("poke the watchdog");
+            if notify(false, &[NotifyState::Watchdog]).is_err() {
+                warn!("fail to poke watchdog");
+            }
         }
-        sleep(timeout).await;
+        sleep(interval).await;
     }
 }
 
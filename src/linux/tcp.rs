@@ -1,17 +1,37 @@
-use nix::sys::socket::{
-    getsockopt, setsockopt,
-    sockopt::{Ip6tOriginalDst, OriginalDst, TcpCongestion},
+use nix::{
+    errno::Errno,
+    sys::socket::{
+        getsockopt, recv, setsockopt,
+        sockopt::{Ip6tOriginalDst, OriginalDst, TcpCongestion},
+        MsgFlags,
+    },
 };
+use socket2::{Domain, Protocol, Socket, Type};
 use std::{
     ffi::OsStr,
     io::{self, ErrorKind},
     net::{SocketAddr, SocketAddrV4, SocketAddrV6},
-    os::fd::AsFd,
+    os::fd::{AsFd, AsRawFd},
+    sync::atomic::{AtomicBool, Ordering},
 };
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tracing::info;
+
+/// Set once the kernel refused MPTCP, so we stop trying.
+static MPTCP_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+/// Set once the kernel refused TCP_FASTOPEN_CONNECT, so we stop trying.
+static FASTOPEN_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
 
 pub trait TcpStreamExt {
     fn get_original_dest(&self) -> io::Result<Option<SocketAddr>>;
+    fn set_notsent_lowat(&self, bytes: u32) -> io::Result<()>;
+    fn notsent_lowat(&self) -> io::Result<u32>;
+    /// Note that the kernel may reset it later.
+    fn set_quickack(&self, enable: bool) -> io::Result<()>;
+    fn quickack(&self) -> io::Result<bool>;
+    /// Whether the peer has shut down its write half and everything sent
+    /// before has been read. Nothing is consumed, never blocks.
+    fn is_read_closed(&self) -> io::Result<bool>;
 }
 
 pub trait TcpListenerExt {
@@ -30,6 +50,137 @@ impl TcpStreamExt for TcpStream {
             Err(err) => Err(err),
         }
     }
+
+    fn set_notsent_lowat(&self, bytes: u32) -> io::Result<()> {
+        set_tcp_opt(self, libc::TCP_NOTSENT_LOWAT, bytes as libc::c_int)
+    }
+
+    fn notsent_lowat(&self) -> io::Result<u32> {
+        get_tcp_opt(self, libc::TCP_NOTSENT_LOWAT).map(|n| n as u32)
+    }
+
+    fn set_quickack(&self, enable: bool) -> io::Result<()> {
+        set_tcp_opt(self, libc::TCP_QUICKACK, enable as libc::c_int)
+    }
+
+    fn quickack(&self) -> io::Result<bool> {
+        get_tcp_opt(self, libc::TCP_QUICKACK).map(|n| n != 0)
+    }
+
+    fn is_read_closed(&self) -> io::Result<bool> {
+        let mut buf = [0u8; 1];
+        let flags = MsgFlags::MSG_PEEK | MsgFlags::MSG_DONTWAIT;
+        match recv(self.as_raw_fd(), &mut buf, flags) {
+            Ok(n) => Ok(n == 0),
+            Err(Errno::EAGAIN) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Create a socket for connecting to `addr`, optionally with MPTCP and
+/// TCP Fast Open (data of the first write is sent along with SYN).
+/// Fallback to plain TCP if they are not supported by the kernel.
+pub fn new_socket(addr: &SocketAddr, mptcp: bool, fastopen: bool) -> io::Result<TcpSocket> {
+    let domain = Domain::for_address(*addr);
+    let mut socket = None;
+    if mptcp && !MPTCP_UNSUPPORTED.load(Ordering::Relaxed) {
+        match Socket::new(domain, Type::STREAM, Some(Protocol::MPTCP)) {
+            Ok(s) => socket = Some(s),
+            Err(err) if is_unsupported(&err) => {
+                info!("MPTCP not supported, fallback to TCP: {}", err);
+                MPTCP_UNSUPPORTED.store(true, Ordering::Relaxed);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    let socket = match socket {
+        Some(socket) => socket,
+        None => Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?,
+    };
+    if fastopen && !FASTOPEN_UNSUPPORTED.load(Ordering::Relaxed) {
+        match set_tcp_opt(&socket, libc::TCP_FASTOPEN_CONNECT, 1) {
+            Ok(()) => (),
+            Err(err) if is_unsupported(&err) => {
+                info!("TCP Fast Open not supported, disabled: {}", err);
+                FASTOPEN_UNSUPPORTED.store(true, Ordering::Relaxed);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    socket.set_nonblocking(true)?;
+    Ok(TcpSocket::from_std_stream(socket.into()))
+}
+
+fn is_unsupported(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(
+            libc::EPROTONOSUPPORT
+                | libc::ENOPROTOOPT
+                | libc::EINVAL
+                | libc::EOPNOTSUPP
+                | libc::EAFNOSUPPORT
+        )
+    )
+}
+
+/// Bind a listener with IP_TRANSPARENT (or IPV6_TRANSPARENT) set, which
+/// is required by TPROXY. Require CAP_NET_ADMIN.
+pub fn bind_transparent(addr: SocketAddr) -> io::Result<TcpListener> {
+    let (socket, level, name) = match addr {
+        SocketAddr::V4(_) => (TcpSocket::new_v4()?, libc::SOL_IP, libc::IP_TRANSPARENT),
+        SocketAddr::V6(_) => (TcpSocket::new_v6()?, libc::SOL_IPV6, libc::IPV6_TRANSPARENT),
+    };
+    socket.set_reuseaddr(true)?;
+    set_sock_opt(&socket, level, name, 1)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+fn set_tcp_opt<F: AsRawFd>(fd: &F, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    set_sock_opt(fd, libc::IPPROTO_TCP, name, value)
+}
+
+fn set_sock_opt<F: AsRawFd>(
+    fd: &F,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn get_tcp_opt<F: AsRawFd>(fd: &F, name: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of_val(&value) as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            name,
+            &mut value as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret == 0 {
+        Ok(value)
+    } else {
+        Err(io::Error::last_os_error())
+    }
 }
 
 impl TcpListenerExt for TcpListener {
@@ -63,3 +214,83 @@ where
         sockaddr.sin6_scope_id,
     ))
 }
+
+#[tokio::test]
+async fn test_tcp_options() {
+    use crate::proxy::TcpOptions;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+
+    TcpOptions::default().apply(&stream).unwrap();
+    assert!(stream.nodelay().unwrap());
+
+    let opts = TcpOptions {
+        nodelay: false,
+        notsent_lowat: Some(16384),
+        quickack: true,
+        ..Default::default()
+    };
+    opts.apply(&stream).unwrap();
+    assert!(!stream.nodelay().unwrap());
+    assert_eq!(16384, stream.notsent_lowat().unwrap());
+    assert!(stream.quickack().unwrap());
+}
+
+#[tokio::test]
+async fn test_is_read_closed() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (mut stream, _) = listener.accept().await.unwrap();
+    assert!(!stream.is_read_closed().unwrap());
+
+    // Not until pending data read
+    client.write_all(b"x").await.unwrap();
+    client.shutdown().await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    assert!(!stream.is_read_closed().unwrap());
+    assert_eq!(1, stream.read(&mut [0; 4]).await.unwrap());
+    assert!(stream.is_read_closed().unwrap());
+}
+
+#[tokio::test]
+async fn test_new_socket_fallback() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    assert!(is_unsupported(&io::Error::from_raw_os_error(
+        libc::EPROTONOSUPPORT
+    )));
+    assert!(!is_unsupported(&io::Error::from_raw_os_error(
+        libc::ECONNREFUSED
+    )));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let listener = &listener;
+    let echo = |mptcp, fastopen| async move {
+        let mut stream = new_socket(&addr, mptcp, fastopen)
+            .unwrap()
+            .connect(addr)
+            .await
+            .unwrap();
+        // With TFO, SYN is sent on the first write
+        stream.write_all(b"hello").await.unwrap();
+        let (mut peer, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 5];
+        peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"hello", &buf);
+    };
+    // Work whether or not the kernel support them
+    echo(true, true).await;
+    // And after fallback is remembered
+    MPTCP_UNSUPPORTED.store(true, Ordering::Relaxed);
+    FASTOPEN_UNSUPPORTED.store(true, Ordering::Relaxed);
+    echo(true, true).await;
+    echo(false, false).await;
+}
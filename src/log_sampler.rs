@@ -0,0 +1,112 @@
+use parking_lot::Mutex;
+use std::{collections::HashMap, hash::Hash, time::Duration};
+use tokio::time::Instant;
+
+/// Rate limiter for logging, allowing one message per key in a period.
+pub(crate) struct LogSampler<K> {
+    period: Duration,
+    entries: Mutex<HashMap<K, Entry>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    logged_at: Instant,
+    suppressed: usize,
+}
+
+impl<K: Hash + Eq + Clone> LogSampler<K> {
+    /// Zero `period` disables sampling.
+    pub(crate) fn new(period: Duration) -> Self {
+        Self {
+            period,
+            entries: Default::default(),
+        }
+    }
+
+    pub(crate) fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Return `Some(n)` if the message should be logged, where `n` is the
+    /// number of messages suppressed since last logged. Return `None` if
+    /// it should be suppressed.
+    pub(crate) fn check(&self, key: &K) -> Option<usize> {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: &K, now: Instant) -> Option<usize> {
+        if self.period.is_zero() {
+            return Some(0);
+        }
+        let mut entries = self.entries.lock();
+        match entries.get_mut(key) {
+            Some(entry) if now.duration_since(entry.logged_at) < self.period => {
+                entry.suppressed += 1;
+                None
+            }
+            Some(entry) => {
+                entry.logged_at = now;
+                Some(std::mem::take(&mut entry.suppressed))
+            }
+            None => {
+                let entry = Entry {
+                    logged_at: now,
+                    suppressed: 0,
+                };
+                entries.insert(key.clone(), entry);
+                Some(0)
+            }
+        }
+    }
+
+    /// Remove expired keys, return those with messages suppressed and
+    /// the number of them.
+    /// Should be called periodically to bound the memory usage.
+    pub(crate) fn flush(&self) -> Vec<(K, usize)> {
+        self.flush_at(Instant::now())
+    }
+
+    fn flush_at(&self, now: Instant) -> Vec<(K, usize)> {
+        let mut suppressed = vec![];
+        self.entries.lock().retain(|key, entry| {
+            if now.duration_since(entry.logged_at) < self.period {
+                return true;
+            }
+            if entry.suppressed > 0 {
+                suppressed.push((key.clone(), entry.suppressed));
+            }
+            false
+        });
+        suppressed
+    }
+}
+
+#[test]
+fn test_log_sampler() {
+    let sampler = LogSampler::new(Duration::from_secs(10));
+    let t0 = Instant::now();
+    let secs = |n| t0 + Duration::from_secs(n);
+
+    assert_eq!(Some(0), sampler.check_at(&"a", t0));
+    assert_eq!(None, sampler.check_at(&"a", secs(1)));
+    assert_eq!(None, sampler.check_at(&"a", secs(2)));
+    assert_eq!(Some(0), sampler.check_at(&"b", secs(2)));
+    // Summary on next logged message
+    assert_eq!(Some(2), sampler.check_at(&"a", secs(10)));
+    assert_eq!(None, sampler.check_at(&"a", secs(11)));
+
+    assert!(sampler.flush_at(secs(15)).is_empty());
+    // Or on flush, "b" is dropped w/o summary
+    assert_eq!(vec![("a", 1)], sampler.flush_at(secs(20)));
+    assert!(sampler.entries.lock().is_empty());
+    assert_eq!(Some(0), sampler.check_at(&"a", secs(21)));
+}
+
+#[test]
+fn test_log_sampler_disabled() {
+    let sampler = LogSampler::new(Duration::ZERO);
+    for _ in 0..3 {
+        assert_eq!(Some(0), sampler.check(&"a"));
+    }
+    assert!(sampler.flush().is_empty());
+}
@@ -1,17 +1,26 @@
 mod cli;
+mod log_sampler;
 mod server;
+mod simulate;
 
 use clap::Parser;
 use cli::{Commands, PolicyCommands};
-use moproxy::policy::{ActionType, RequestFeatures};
+use moproxy::{
+    monitor::check_deadlocks,
+    policy::{ActionType, RequestFeatures},
+};
 use server::MoProxy;
 use std::str::FromStr;
 #[cfg(unix)]
 use tokio::signal::unix::{signal, SignalKind};
+#[cfg(windows)]
+use tokio::signal::windows::ctrl_break;
 use tracing::{debug, error, info, instrument, warn};
 
 #[cfg(all(feature = "systemd", target_os = "linux"))]
 use moproxy::linux::systemd;
+#[cfg(feature = "web_console")]
+use moproxy::web::log_tail::{LogTailLayer, LOG_TAIL};
 use tracing_subscriber::prelude::*;
 
 trait FromOptionStr<E, T: FromStr<Err = E>> {
@@ -37,13 +46,23 @@ where
 async fn main() {
     let mut args = cli::CliArgs::parse();
     let command = args.command.take();
-    let mut log_registry: Option<_> = tracing_subscriber::registry().with(args.log_level).into();
+    // Captured at its own level, so filter other layers one by one
+    #[cfg(feature = "web_console")]
+    let log_tail = args.debug_log_token.is_some().then(|| {
+        LOG_TAIL.enable(args.debug_log_lines);
+        LogTailLayer::new(&LOG_TAIL).with_filter(args.debug_log_level)
+    });
+    #[cfg(not(feature = "web_console"))]
+    let log_tail: Option<tracing_subscriber::layer::Identity> = None;
+    #[cfg_attr(not(all(feature = "systemd", target_os = "linux")), allow(unused_mut))]
+    let mut log_registry: Option<_> = tracing_subscriber::registry().with(log_tail).into();
 
     #[cfg(all(feature = "systemd", target_os = "linux"))]
     {
         if systemd::is_stderr_connected_to_journal() {
             match tracing_journald::layer() {
                 Ok(layer) => {
+                    let layer = layer.with_filter(args.log_level);
                     log_registry.take().unwrap().with(layer).init();
                     debug!("Use native journal protocol");
                 }
@@ -55,9 +74,13 @@ async fn main() {
         }
     }
     if let Some(registry) = log_registry {
-        registry.with(tracing_subscriber::fmt::layer()).init();
+        let layer = tracing_subscriber::fmt::layer().with_filter(args.log_level);
+        registry.with(layer).init();
     }
 
+    #[cfg(all(feature = "systemd", target_os = "linux"))]
+    let watchdog_thresholds = args.watchdog_thresholds();
+
     // Init moproxy (read config files, etc.)
     let moproxy = MoProxy::new(args).await.expect("failed to start moproxy");
 
@@ -72,6 +95,17 @@ async fn main() {
             }
         });
     }
+    // No SIGHUP on Windows, reload on Ctrl+Break instead
+    #[cfg(windows)]
+    {
+        let moproxy = moproxy.clone();
+        let mut signals = ctrl_break().expect("cannot catch Ctrl+Break");
+        tokio::spawn(async move {
+            while signals.recv().await.is_some() {
+                reload_daemon(&moproxy);
+            }
+        });
+    }
 
     match &command {
         Some(Commands::Check { no_bind }) if *no_bind => {
@@ -92,13 +126,16 @@ async fn main() {
                 };
                 let action = policy.matches(&features);
                 println!("Policy: {action}");
+                for rule in action.rules().iter().filter_map(|n| policy.rule_text(*n)) {
+                    println!("Matched: {rule}");
+                }
                 if let ActionType::Require(caps) = action.action {
                     let mut tags: Vec<_> = moproxy
                         .monitor
                         .servers()
                         .iter()
                         .filter(|s| caps.iter().all(|c| s.capable_anyof(c)))
-                        .map(|s| s.tag.clone())
+                        .map(|s| s.tag().clone())
                         .collect();
                     tags.sort();
                     println!("Allowed: {}", tags.join(", "));
@@ -106,6 +143,16 @@ async fn main() {
                 return;
             }
         },
+        Some(Commands::Simulate {
+            policy,
+            input,
+            diff,
+        }) => {
+            let report = simulate::run(&moproxy, policy.as_deref(), input, diff.as_deref())
+                .expect("failed to simulate");
+            print!("{report}");
+            return;
+        }
         _ => {}
     }
 
@@ -119,7 +166,9 @@ async fn main() {
     #[cfg(all(feature = "systemd", target_os = "linux"))]
     {
         if let Some(timeout) = systemd::watchdog_timeout() {
-            tokio::spawn(systemd::watchdog_loop(timeout / 2));
+            let liveness = moproxy.monitor.liveness().clone();
+            let watchdog = systemd::watchdog_loop(timeout / 2, liveness, watchdog_thresholds);
+            tokio::spawn(watchdog);
         }
     }
 
@@ -140,20 +189,10 @@ fn reload_daemon(moproxy: &MoProxy) {
     systemd::notify_realoding();
 
     // feature: check deadlocks on signal
-    let deadlocks = parking_lot::deadlock::check_deadlock();
-    if !deadlocks.is_empty() {
-        error!("{} deadlocks detected!", deadlocks.len());
-        for (i, threads) in deadlocks.iter().enumerate() {
-            debug!("Deadlock #{}", i);
-            for t in threads {
-                debug!("Thread Id {:#?}", t.thread_id());
-                debug!("{:#?}", t.backtrace());
-            }
-        }
-    }
+    check_deadlocks();
 
     // actual reload
-    debug!("SIGHUP received, reload server list.");
+    debug!("Reload signal received, reload server list.");
     if let Err(err) = moproxy.reload() {
         error!("fail to reload servers: {}", err);
     }
@@ -0,0 +1,261 @@
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, time::interval};
+use tracing::{info, warn};
+
+use crate::proxy::Traffic;
+
+/// Days kept if not specified.
+pub const DEFAULT_KEEP_DAYS: usize = 31;
+/// How often to check for finished days to write out.
+const CSV_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const CSV_HEADER: &str = "date,port,tx_bytes,rx_bytes\n";
+
+/// Traffic per listen port per (UTC) calendar day, see `ACCOUNTING`.
+#[derive(Debug)]
+pub struct Accounting {
+    /// Days since Unix epoch => listen port => traffic.
+    days: Mutex<BTreeMap<u32, BTreeMap<u16, Traffic>>>,
+    /// Number of days kept, 0 for disabled.
+    keep_days: AtomicUsize,
+    clock: fn() -> SystemTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DailyTraffic {
+    pub date: Date,
+    pub port: u16,
+    #[serde(flatten)]
+    pub traffic: Traffic,
+}
+
+/// Date in UTC, formatted as `YYYY-MM-DD`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Date(u32);
+
+/// Global accounting of all connections, kept across reloads.
+pub static ACCOUNTING: Accounting = Accounting::new(SystemTime::now);
+
+impl Accounting {
+    pub const fn new(clock: fn() -> SystemTime) -> Self {
+        Self {
+            days: Mutex::new(BTreeMap::new()),
+            keep_days: AtomicUsize::new(0),
+            clock,
+        }
+    }
+
+    /// Start accounting, keep traffic of the last `days` days.
+    pub fn enable(&self, days: usize) {
+        self.keep_days.store(days, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.keep_days.load(Ordering::Relaxed) > 0
+    }
+
+    /// Days since Unix epoch, from the clock on each call so that jumps
+    /// of the clock are followed.
+    fn today(&self) -> u32 {
+        let secs = (self.clock)()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_secs())
+            .unwrap_or_default();
+        (secs / 86400) as u32
+    }
+
+    /// Count traffic for the listen port on today, or on the latest day
+    /// seen if the clock has jumped backward.
+    pub fn add(&self, port: u16, traffic: Traffic) {
+        let keep = self.keep_days.load(Ordering::Relaxed);
+        if keep == 0 {
+            return;
+        }
+        let today = self.today();
+        let mut days = self.days.lock();
+        // Never count on a day before the latest one, which may have been
+        // written out as a finished day, or be pruned right away
+        let latest = days.keys().next_back().map_or(today, |day| today.max(*day));
+        *days.entry(latest).or_default().entry(port).or_default() += traffic;
+        let oldest = latest.saturating_sub(keep as u32 - 1);
+        while days.first_key_value().is_some_and(|(day, _)| *day < oldest) {
+            days.pop_first();
+        }
+    }
+
+    /// Traffic of last `n` days including today, order by date then port.
+    /// Nothing for `n` of 0.
+    pub fn recent(&self, n: usize) -> Vec<DailyTraffic> {
+        if n == 0 {
+            return vec![];
+        }
+        let today = self.today();
+        let since = today.saturating_sub((n - 1).min(u32::MAX as usize) as u32);
+        self.between(since, today)
+    }
+
+    /// Traffic of days `since..=until`, order by date then port.
+    fn between(&self, since: u32, until: u32) -> Vec<DailyTraffic> {
+        if since > until {
+            return vec![];
+        }
+        let days = self.days.lock();
+        days.range(since..=until)
+            .flat_map(|(day, ports)| {
+                ports.iter().map(|(port, traffic)| DailyTraffic {
+                    date: Date(*day),
+                    port: *port,
+                    traffic: *traffic,
+                })
+            })
+            .collect()
+    }
+
+    /// Append traffic of each day to the CSV file `path` once the day is
+    /// over. Days before started are not written.
+    pub async fn append_csv_daily(&self, path: PathBuf) {
+        let mut written = self.today().saturating_sub(1);
+        let mut interval = interval(CSV_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let yesterday = self.today().saturating_sub(1);
+            if yesterday <= written {
+                // Not yet, or the clock jumped backward
+                continue;
+            }
+            let rows = self.between(written + 1, yesterday);
+            match append_csv(&path, &rows).await {
+                Ok(()) => {
+                    info!(n = rows.len(), "Accounting appended to {}", path.display());
+                    written = yesterday;
+                }
+                Err(err) => warn!("fail to append accounting to {}: {}", path.display(), err),
+            }
+        }
+    }
+}
+
+async fn append_csv(path: &Path, rows: &[DailyTraffic]) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    let mut buf = String::new();
+    if file.metadata().await?.len() == 0 {
+        buf.push_str(CSV_HEADER);
+    }
+    write_csv_rows(&mut buf, rows);
+    file.write_all(buf.as_bytes()).await?;
+    file.flush().await
+}
+
+fn write_csv_rows(buf: &mut String, rows: &[DailyTraffic]) {
+    for row in rows {
+        writeln!(
+            buf,
+            "{},{},{},{}",
+            row.date, row.port, row.traffic.tx_bytes, row.traffic.rx_bytes
+        )
+        .unwrap();
+    }
+}
+
+impl std::fmt::Display for Date {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Civil from days, see http://howardhinnant.github.io/date_algorithms.html
+        let z = self.0 as i64 + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + (month <= 2) as i64;
+        write!(f, "{:04}-{:02}-{:02}", year, month, day)
+    }
+}
+
+impl Serialize for Date {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+static FAKE_NOW: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+#[cfg(test)]
+fn fake_now() -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(FAKE_NOW.load(Ordering::Relaxed))
+}
+
+#[test]
+fn test_date_display() {
+    assert_eq!("1970-01-01", Date(0).to_string());
+    assert_eq!("2000-02-29", Date(11016).to_string());
+    assert_eq!("2024-12-31", Date(20088).to_string());
+}
+
+#[test]
+fn test_accounting() {
+    let day = 20088 * 86400; // 2024-12-31
+    FAKE_NOW.store(day + 86400 - 1, Ordering::Relaxed);
+    let accounting = Accounting::new(fake_now);
+    accounting.add(2080, (1, 2).into());
+    assert!(accounting.recent(7).is_empty());
+    accounting.enable(2);
+
+    accounting.add(2080, (1, 2).into());
+    accounting.add(2080, (10, 20).into());
+    accounting.add(2081, (5, 0).into());
+    // Midnight passed
+    FAKE_NOW.store(day + 86400, Ordering::Relaxed);
+    accounting.add(2080, (100, 200).into());
+    let recent = accounting.recent(7);
+    assert_eq!(3, recent.len());
+    assert_eq!(
+        DailyTraffic {
+            date: Date(20088),
+            port: 2080,
+            traffic: (11, 22).into(),
+        },
+        recent[0]
+    );
+    assert_eq!("2025-01-01", recent[2].date.to_string());
+    assert_eq!(1, accounting.recent(1).len());
+    assert!(accounting.recent(0).is_empty());
+
+    // Clock jumped backward, counted on the latest day
+    FAKE_NOW.store(day - 86400 * 5, Ordering::Relaxed);
+    accounting.add(2080, (1, 1).into());
+    FAKE_NOW.store(day + 86400, Ordering::Relaxed);
+    let recent = accounting.recent(10);
+    assert_eq!(3, recent.len());
+    assert_eq!(Traffic::from((101, 201)), recent[2].traffic);
+
+    // Pruned to the last 2 days
+    FAKE_NOW.store(day + 86400 * 2, Ordering::Relaxed);
+    accounting.add(2081, (1, 1).into());
+    let recent = accounting.recent(10);
+    assert_eq!(2, recent.len());
+    assert_eq!("2025-01-01", recent[0].date.to_string());
+
+    let mut csv = String::new();
+    write_csv_rows(&mut csv, &recent);
+    assert_eq!("2025-01-01,2080,101,201\n2025-01-02,2081,1,1\n", csv);
+    let json = serde_json::to_string(&recent[0]).unwrap();
+    assert_eq!(
+        r#"{"date":"2025-01-01","port":2080,"tx_bytes":101,"rx_bytes":201}"#,
+        json
+    );
+}
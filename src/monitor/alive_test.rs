@@ -1,5 +1,5 @@
 use futures_util::future::join_all;
-use std::{self, io, net::Shutdown, time::Duration};
+use std::{self, io, net::Shutdown, sync::Arc, time::Duration};
 #[cfg(all(feature = "systemd", target_os = "linux"))]
 use std::{
     fmt,
@@ -9,12 +9,25 @@ use tokio::{
     io::AsyncReadExt,
     time::{timeout, Instant},
 };
-use tracing::{debug, instrument, warn};
+use tracing::{debug, info, instrument, warn};
 
-use super::Monitor;
+use super::{
+    probe_capture::{ProbeCapture, ProbeRecord},
+    probe_log, Monitor, ServerEvent, TaskKind,
+};
 #[cfg(all(feature = "systemd", target_os = "linux"))]
 use crate::linux::systemd;
-use crate::proxy::ProxyServer;
+use crate::{
+    client::{tls_parser, x509},
+    proxy::{error::HandshakePhase, Delay, ProbeTimings, ProxyServer, TlsVerification},
+};
+
+/// Give up on TLS verification if response exceed this size.
+const MAX_TLS_RESPONSE_SIZE: usize = 64 * 1024;
+
+/// Delay between starts of probes in the round after resumed from suspend,
+/// not to burst into a network just back.
+const RESUME_STAGGER: Duration = Duration::from_millis(100);
 
 #[cfg(all(feature = "systemd", target_os = "linux"))]
 struct TestProgress {
@@ -59,41 +72,41 @@ impl fmt::Display for TestProgress {
     }
 }
 
+/// Probe `servers`, which may be a part of all servers in `monitor`.
+pub(crate) async fn test_all(monitor: &Monitor, servers: Vec<Arc<ProxyServer>>) {
+    test_all_with(monitor, servers, Duration::ZERO, false).await
+}
+
+/// Probe `servers` the first round after resumed from suspend: started
+/// `RESUME_STAGGER` apart, and failures are not scored, see `test_one_with()`.
+pub(crate) async fn test_all_after_resume(monitor: &Monitor, servers: Vec<Arc<ProxyServer>>) {
+    test_all_with(monitor, servers, RESUME_STAGGER, true).await
+}
+
 #[instrument(skip_all)]
-pub(crate) async fn test_all(monitor: &Monitor) {
-    debug!("Start testing all servers");
+async fn test_all_with(
+    monitor: &Monitor,
+    servers: Vec<Arc<ProxyServer>>,
+    stagger: Duration,
+    lenient: bool,
+) {
+    debug!("Start testing {} servers", servers.len());
     #[cfg(all(feature = "systemd", target_os = "linux"))]
-    let progress = TestProgress::new(monitor.servers().len());
+    let progress = TestProgress::new(servers.len());
     #[cfg(all(feature = "systemd", target_os = "linux"))]
     let progress_ref = &progress;
-    let tests: Vec<_> = monitor
-        .servers()
+    let capture = monitor.probe_capture.as_ref().filter(|c| c.sample());
+    let tests: Vec<_> = servers
         .into_iter()
-        .map(move |server| {
+        .enumerate()
+        .map(move |(i, server)| {
             Box::pin(async move {
-                let delay = alive_test(&server).await.ok();
-
-                #[cfg(all(feature = "systemd", target_os = "linux"))]
-                progress_ref.increase(delay.is_some());
-
-                #[cfg(feature = "score_script")]
-                {
-                    let mut caculated = false;
-                    if let Some(lua) = &monitor.lua {
-                        match lua
-                            .lock()
-                            .context(|ctx| server.update_delay_with_lua(delay, ctx))
-                        {
-                            Ok(()) => caculated = true,
-                            Err(err) => warn!("fail to update score w/ Lua script: {}", err),
-                        }
-                    }
-                    if !caculated {
-                        server.update_delay(delay);
-                    }
+                if !stagger.is_zero() {
+                    tokio::time::sleep(stagger * i as u32).await;
                 }
-                #[cfg(not(feature = "score_script"))]
-                server.update_delay(delay);
+                let _passed = test_one_with(monitor, &server, capture, lenient).await;
+                #[cfg(all(feature = "systemd", target_os = "linux"))]
+                progress_ref.increase(_passed);
             })
         })
         .collect();
@@ -102,8 +115,164 @@ pub(crate) async fn test_all(monitor: &Monitor) {
     monitor.resort();
 }
 
-#[instrument(skip_all, fields(proxy = %server.tag))]
-async fn alive_test(server: &ProxyServer) -> io::Result<Duration> {
+/// Probe a single server and update its score, captured if `capture` is
+/// set. Return true if passed.
+pub(crate) async fn test_one(
+    monitor: &Monitor,
+    server: &ProxyServer,
+    capture: Option<&Arc<ProbeCapture>>,
+) -> bool {
+    test_one_with(monitor, server, capture, false).await
+}
+
+/// `test_one()`, but leave the score and failure counts as is if failed
+/// and `lenient` is set, e.g. while the network is not yet up.
+pub(crate) async fn test_one_with(
+    monitor: &Monitor,
+    server: &ProxyServer,
+    capture: Option<&Arc<ProbeCapture>>,
+    lenient: bool,
+) -> bool {
+    let _task = monitor.track_task(TaskKind::Probe);
+    let mut record = capture.map(|_| ProbeRecord::new());
+    let mut timings = ProbeTimings::default();
+    let result = alive_test(server, record.as_mut(), &mut timings)
+        .await
+        .map(|_| timings.total());
+    let timed_out = matches!(&result, Err(e) if e.kind() == io::ErrorKind::TimedOut);
+    let failure = (timings.phase, timed_out);
+    if let (Some(capture), Some(record)) = (capture, record) {
+        let capture = capture.clone();
+        let tag = server.tag();
+        // io::Error is not Clone
+        let result = match &result {
+            Ok(t) => Ok(*t),
+            Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+        };
+        tokio::task::spawn_blocking(move || {
+            if let Err(err) = capture.save(&tag, &record, &result) {
+                warn!(proxy = %tag, "fail to save probe capture: {}", err);
+            }
+        });
+    }
+    let delay = result.as_ref().ok().copied();
+    if let (Some(_), Some(host)) = (delay, server.probe_verify_tls()) {
+        match verify_tls(server, &host).await {
+            Ok(true) => server.set_tls_verification(TlsVerification::Verified),
+            Ok(false) => {
+                warn!(proxy = %server.tag(), "TLS to {} intercepted", host);
+                server.set_tls_verification(TlsVerification::Intercepted);
+            }
+            Err(err) => {
+                info!(proxy = %server.tag(), "fail to verify TLS: {}", err);
+                server.set_tls_verification(TlsVerification::Unknown);
+            }
+        }
+    }
+    if lenient && delay.is_none() {
+        debug!(proxy = %server.tag(), "probe failed, not scored");
+        return false;
+    }
+    let last = server.status_snapshot();
+    update_score(monitor, server, delay.ok_or(failure));
+    probe_log::log_probe(
+        server,
+        last.delay,
+        last.score,
+        &result,
+        monitor.probe_log_changes_only,
+    );
+    delay.is_some()
+}
+
+/// Update score with the probe result, either the delay or the phase it
+/// failed in and whether it timed out, emit `ServerUp/Down` on changes.
+///
+/// Lua `calc_score()` gets `nil` delays for all failures, timed out or
+/// not, as before failures were told apart.
+fn update_score(
+    monitor: &Monitor,
+    server: &ProxyServer,
+    result: Result<Duration, (HandshakePhase, bool)>,
+) {
+    let last_delay = server.status_snapshot().delay;
+    #[cfg(feature = "score_script")]
+    let caculated = match &monitor.lua {
+        Some(lua) => match lua
+            .lock()
+            .context(|ctx| server.update_delay_with_lua(result.ok(), ctx))
+        {
+            Ok(()) => true,
+            Err(err) => {
+                warn!("fail to update score w/ Lua script: {}", err);
+                false
+            }
+        },
+        None => false,
+    };
+    #[cfg(not(feature = "score_script"))]
+    let caculated = false;
+    match result {
+        Ok(delay) if !caculated => server.update_delay(Some(delay)),
+        Ok(_) => (),
+        Err((phase, timed_out)) if !caculated => server.update_connect_failure(phase, timed_out),
+        Err((phase, timed_out)) => server.add_connect_failure(phase, timed_out),
+    }
+    match (last_delay, result) {
+        (Delay::Some(_), Ok(_)) | (Delay::TimedOut | Delay::Failed, Err(_)) => (),
+        (_, Ok(_)) => monitor.events.emit(ServerEvent::ServerUp(server.tag())),
+        (_, Err(_)) => monitor.events.emit(ServerEvent::ServerDown(server.tag())),
+    }
+}
+
+#[instrument(skip_all, fields(proxy = %server.tag()))]
+async fn alive_test(
+    server: &ProxyServer,
+    record: Option<&mut ProbeRecord>,
+    timings: &mut ProbeTimings,
+) -> io::Result<()> {
+    let result = match server.probe_port() {
+        Some(port) => connect_test(server, port, record, timings).await,
+        None => dns_test(server, record, timings).await,
+    };
+    server.set_probe_timings(result.as_ref().ok().map(|_| *timings));
+    result
+}
+
+/// Connect to `port` of the test DNS server, measure the time taken to
+/// complete the proxy handshake. For servers that cannot reach port 53.
+async fn connect_test(
+    server: &ProxyServer,
+    port: u16,
+    mut record: Option<&mut ProbeRecord>,
+    timings: &mut ProbeTimings,
+) -> io::Result<()> {
+    let mut addr = server.test_dns();
+    addr.set_port(port);
+    let dest = addr.into();
+    let result = timeout(server.max_wait(), async {
+        let stream = server
+            .connect_timed::<&[u8]>(&dest, None, None, Some(&mut *timings))
+            .await?;
+        if let Some(record) = record.as_deref_mut() {
+            record.connected(stream.tcp());
+        }
+        stream.into_tcp().into_std()?.shutdown(Shutdown::Both)
+    })
+    .await;
+
+    match result {
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "test timeout")),
+        Ok(Err(e)) => Err(e),
+        Ok(Ok(_)) => Ok(()),
+    }
+}
+
+async fn dns_test(
+    server: &ProxyServer,
+    mut record: Option<&mut ProbeRecord>,
+    timings: &mut ProbeTimings,
+) -> io::Result<()> {
     let request = [
         0,
         17, // length
@@ -127,28 +296,288 @@ async fn alive_test(server: &ProxyServer) -> io::Result<Duration> {
     ];
     let tid = |req: &[u8]| (req[2] as u16) << 8 | (req[3] as u16);
     let req_tid = tid(&request);
-    let now = Instant::now();
 
     let mut buf = [0u8; 12];
     let test_dns = server.test_dns().into();
     let result = timeout(server.max_wait(), async {
-        let mut stream = server.connect(&test_dns, Some(request)).await?;
+        let mut stream = server
+            .connect_timed(&test_dns, Some(request), None, Some(&mut *timings))
+            .await?;
+        let handshaked = Instant::now();
+        if let Some(record) = record.as_deref_mut() {
+            // Sent along with the handshake
+            record.connected(stream.tcp());
+            record.sent(&request);
+        }
         stream.read_exact(&mut buf).await?;
-        stream.into_std()?.shutdown(Shutdown::Both)
+        if let Some(record) = record.as_deref_mut() {
+            record.received(&buf);
+        }
+        stream.into_tcp().into_std()?.shutdown(Shutdown::Both)?;
+        Ok::<_, io::Error>(handshaked.elapsed())
     })
     .await;
 
-    match result {
+    timings.probe_query = match result {
         Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "test timeout")),
         Ok(Err(e)) => return Err(e),
-        Ok(Ok(_)) => (),
-    }
+        Ok(Ok(elapsed)) => elapsed,
+    };
 
     if req_tid == tid(&buf) {
-        let t = now.elapsed();
-        debug!("{}ms", t.as_millis());
-        Ok(t)
+        Ok(())
     } else {
-        Err(io::Error::new(io::ErrorKind::Other, "unknown response"))
+        Err(io::Error::other("unknown response"))
     }
 }
+
+/// Send TLS ClientHello to `host` via the proxy, check the certificate
+/// returned against the host name. The handshake is not completed.
+///
+/// Return `Ok(false)` if a mismatched certificate or non-TLS response is
+/// received, `Err(_)` if the result is inconclusive.
+#[instrument(skip_all, fields(proxy = %server.tag()))]
+async fn verify_tls(server: &ProxyServer, host: &str) -> io::Result<bool> {
+    let hello = tls_parser::build_client_hello(host);
+    let mut buf = Vec::with_capacity(4096);
+    let result = timeout(server.max_wait(), async {
+        let mut stream = server.connect(&(host, 443).into(), Some(hello)).await?;
+        loop {
+            if stream.read_buf(&mut buf).await? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed before certificate received",
+                ));
+            }
+            // Alerts are likely caused by our limited ClientHello,
+            // not a sign of interception.
+            if buf[0] == 21 {
+                return Err(io::Error::other("alert received from server"));
+            }
+            match tls_parser::parse_server_certificate(&buf) {
+                Ok(Some(cert)) => return Ok(Some(cert)),
+                Ok(None) if buf.len() < MAX_TLS_RESPONSE_SIZE => continue,
+                Ok(None) => return Err(io::Error::other("no certificate found")),
+                Err(err) => {
+                    debug!("not a TLS response: {}", err);
+                    return Ok(None);
+                }
+            }
+        }
+    })
+    .await;
+
+    let cert = match result {
+        Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "test timeout")),
+        Ok(Err(e)) => return Err(e),
+        Ok(Ok(None)) => return Ok(false),
+        Ok(Ok(Some(cert))) => cert,
+    };
+    match x509::certificate_names(&cert) {
+        Ok(names) => {
+            debug!("certificate names: {:?}", names);
+            Ok(names.iter().any(|name| x509::name_matches(name, host)))
+        }
+        Err(err) => {
+            debug!("fail to parse certificate: {}", err);
+            Ok(false)
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_alive_test_probe_port() {
+    use crate::proxy::ProxyProto;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = ProxyServer::new(
+        listener.local_addr().unwrap(),
+        ProxyProto::http(false, None),
+        "192.0.2.53:53".parse().unwrap(),
+        Duration::from_secs(1),
+        None,
+        None,
+        None,
+    )
+    .unwrap();
+    server.update_config(|config| config.probe_port = Some(443));
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        let mut line = String::new();
+        stream.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("CONNECT 192.0.2.53:443 "));
+        while line != "\r\n" {
+            line.clear();
+            stream.read_line(&mut line).await.unwrap();
+        }
+        stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await
+            .unwrap();
+    });
+    alive_test(&server, None, &mut Default::default())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_alive_test_timings() {
+    use tokio::{io::AsyncWriteExt, net::TcpListener, time::sleep};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = ProxyServer::test_socks5(listener.local_addr().unwrap(), None);
+    server.update_config(|c| c.max_wait = Duration::from_secs(2));
+    // SOCKSv5 server slow on the handshake only
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 19];
+        stream.read_exact(&mut buf[..3]).await.unwrap();
+        sleep(Duration::from_millis(200)).await;
+        stream.write_all(&[5, 0]).await.unwrap();
+        stream.read_exact(&mut buf[..10]).await.unwrap();
+        stream
+            .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        stream.read_exact(&mut buf).await.unwrap();
+        let mut response = [0u8; 12];
+        response[2..4].copy_from_slice(&buf[2..4]);
+        stream.write_all(&response).await.unwrap();
+    });
+
+    let mut timings = ProbeTimings::default();
+    alive_test(&server, None, &mut timings).await.unwrap();
+    assert!(timings.handshake >= Duration::from_millis(200));
+    assert!(timings.tcp_connect < Duration::from_millis(100));
+    assert!(timings.probe_query < Duration::from_millis(100));
+    assert_eq!(Some(timings), server.status_snapshot().probe_timings);
+}
+
+#[tokio::test]
+async fn test_connect_failure_phases() {
+    use tokio::{io::AsyncWriteExt, net::TcpListener, time::sleep};
+
+    // SOCKSv5 server fails a probe in the way of `close`
+    let server = |close: u8| async move {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = ProxyServer::test_socks5(listener.local_addr().unwrap(), None);
+        server.update_config(|c| c.max_wait = Duration::from_millis(300));
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 3];
+            match close {
+                // Right after accepted
+                0 => (),
+                // After the method selected
+                1 => {
+                    stream.read_exact(&mut buf).await.unwrap();
+                    stream.write_all(&[5, 0]).await.unwrap();
+                }
+                // Never, until timed out
+                _ => sleep(Duration::from_secs(1)).await,
+            }
+        });
+        Arc::new(server)
+    };
+    let (accepted, selected, stuck) = (server(0).await, server(1).await, server(2).await);
+    let monitor = Monitor::new(vec![accepted.clone(), selected.clone()], None);
+    for server in [&accepted, &selected, &stuck] {
+        assert!(!test_one(&monitor, server, None).await);
+        assert_eq!(None, server.score());
+    }
+
+    let failures = accepted.status_snapshot().connect_failures;
+    assert_eq!((1, 0), (failures.negotiate, failures.timed_out));
+    let failures = selected.status_snapshot().connect_failures;
+    assert_eq!((1, 0), (failures.request, failures.timed_out));
+    let failures = stuck.status_snapshot().connect_failures;
+    assert_eq!((1, 1), (failures.negotiate, failures.timed_out));
+    assert!(matches!(accepted.status_snapshot().delay, Delay::Failed));
+    assert!(matches!(stuck.status_snapshot().delay, Delay::TimedOut));
+
+    // Failed fast: back at once, scored by the new delay
+    let delay = Some(Duration::from_millis(10));
+    accepted.update_delay(delay);
+    selected.update_delay(delay);
+    assert_eq!(Some(10), accepted.score());
+    assert_eq!(Some(10), selected.score());
+    // Timed out: recovering, then scored from `max_wait`
+    stuck.update_delay(delay);
+    assert_eq!(None, stuck.candidate_score());
+    stuck.update_delay(delay);
+    assert!(stuck.candidate_score().unwrap() > 100);
+}
+
+#[test]
+fn test_server_events() {
+    use std::sync::Arc;
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    let server = |port: u16, tag: &str| {
+        Arc::new(ProxyServer::test_socks5(
+            ([127, 0, 0, 1], port).into(),
+            Some(tag),
+        ))
+    };
+    let (a, b) = (server(1, "a"), server(2, "b"));
+    let monitor = Monitor::new(vec![a.clone(), b.clone()], None);
+    let mut events = monitor.subscribe();
+    let ms = |n| Ok(Duration::from_millis(n));
+    let timed_out = Err((HandshakePhase::Request, true));
+
+    update_score(&monitor, &a, ms(100));
+    monitor.resort();
+    assert_eq!(
+        ServerEvent::ServerUp("a".into()),
+        events.try_recv().unwrap()
+    );
+    assert_eq!(
+        ServerEvent::BestServerChanged("a".into()),
+        events.try_recv().unwrap()
+    );
+
+    // Slightly better, within the hysteresis
+    update_score(&monitor, &b, ms(90));
+    monitor.resort();
+    assert_eq!(
+        ServerEvent::ServerUp("b".into()),
+        events.try_recv().unwrap()
+    );
+    assert_eq!(Err(TryRecvError::Empty), events.try_recv());
+
+    // Best server down
+    update_score(&monitor, &a, timed_out);
+    monitor.resort();
+    assert_eq!(
+        ServerEvent::ServerDown("a".into()),
+        events.try_recv().unwrap()
+    );
+    assert_eq!(
+        ServerEvent::BestServerChanged("b".into()),
+        events.try_recv().unwrap()
+    );
+
+    // Only changes are emitted
+    update_score(&monitor, &b, timed_out);
+    update_score(&monitor, &b, timed_out);
+    assert_eq!(
+        ServerEvent::ServerDown("b".into()),
+        events.try_recv().unwrap()
+    );
+    assert_eq!(Err(TryRecvError::Empty), events.try_recv());
+
+    monitor.update_servers(vec![b.clone(), server(3, "c")]);
+    assert_eq!(
+        ServerEvent::ServersReloaded {
+            added: 1,
+            removed: 1
+        },
+        events.try_recv().unwrap()
+    );
+    assert_eq!(Err(TryRecvError::Empty), events.try_recv());
+}
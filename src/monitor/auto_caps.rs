@@ -0,0 +1,128 @@
+use flexstr::SharedStr;
+use std::{collections::HashMap, sync::Arc};
+use tracing::info;
+
+use crate::{
+    policy::{capabilities::CapSet, parser::AutoCapRule},
+    proxy::ProxyServer,
+};
+
+/// Rounds of probing a dynamic capability is kept after its rule stop
+/// matching.
+const RELEASE_ROUNDS: u8 = 3;
+
+/// Number of consecutive rounds each dynamic capability not matched.
+type Misses = HashMap<SharedStr, u8>;
+
+/// Evaluate `auto capability` rules on the scores of servers.
+#[derive(Default)]
+pub(crate) struct AutoCaps {
+    rules: Vec<AutoCapRule>,
+    misses: HashMap<Arc<ProxyServer>, Misses>,
+}
+
+impl AutoCaps {
+    pub(crate) fn set_rules(&mut self, rules: Vec<AutoCapRule>) {
+        self.rules = rules;
+    }
+
+    /// Update dynamic capabilities of `servers` after a probe round.
+    pub(crate) fn update(&mut self, servers: &[Arc<ProxyServer>]) {
+        let mut all_misses = HashMap::new();
+        for server in servers {
+            let old = server.dynamic_capabilities();
+            let mut misses = self.misses.remove(server).unwrap_or_default();
+            let new = self.eval(server.score(), &old, &mut misses);
+            if new != old {
+                for cap in new.iter().filter(|c| !old.contains(c)) {
+                    info!(proxy = %server.tag(), "capability {} added", cap);
+                }
+                for cap in old.iter().filter(|c| !new.contains(c)) {
+                    info!(proxy = %server.tag(), "capability {} removed", cap);
+                }
+                server.set_dynamic_capabilities(new);
+            }
+            if !misses.is_empty() {
+                all_misses.insert(server.clone(), misses);
+            }
+        }
+        self.misses = all_misses;
+    }
+
+    /// Return the dynamic capabilities for a server with `score` and
+    /// `current` dynamic capabilities.
+    fn eval(&self, score: Option<i32>, current: &CapSet, misses: &mut Misses) -> CapSet {
+        let mut names: Vec<_> = self.rules.iter().map(|r| &r.cap).collect();
+        names.sort();
+        names.dedup();
+        let mut caps = Vec::with_capacity(names.len());
+        for cap in names {
+            let matched = self
+                .rules
+                .iter()
+                .any(|r| &r.cap == cap && r.bound.matches(score));
+            if matched {
+                misses.remove(cap);
+                caps.push(cap.clone());
+            } else if current.contains(cap) {
+                let n = misses.entry(cap.clone()).or_default();
+                *n += 1;
+                if *n < RELEASE_ROUNDS {
+                    caps.push(cap.clone());
+                } else {
+                    misses.remove(cap);
+                }
+            }
+        }
+        CapSet::new(caps.into_iter())
+    }
+}
+
+#[test]
+fn test_auto_caps_release() {
+    use crate::policy::parser::{line_no_ending, Line};
+
+    let rule = |text| match line_no_ending(text).unwrap().1 {
+        Some(Line::AutoCap(rule)) => rule,
+        _ => unreachable!(),
+    };
+    let mut auto_caps = AutoCaps::default();
+    auto_caps.set_rules(vec![
+        rule("auto capability fast if score < 150"),
+        rule("auto capability slow if score > 500"),
+    ]);
+
+    let mut caps = CapSet::default();
+    let mut misses = Misses::default();
+    let mut history = vec![];
+    let scores = [
+        Some(100),
+        Some(200),
+        None,
+        Some(120),
+        Some(200),
+        Some(600),
+        Some(600),
+    ];
+    for score in scores {
+        caps = auto_caps.eval(score, &caps, &mut misses);
+        history.push(caps.to_string());
+    }
+    assert_eq!(
+        vec![
+            "fast",
+            "fast",
+            "fast",
+            "fast",
+            "fast",
+            "(fast OR slow)",
+            "slow"
+        ],
+        history
+    );
+    assert!(misses.is_empty());
+
+    // Removed on the next round without matching rule
+    auto_caps.set_rules(vec![]);
+    assert!(auto_caps.eval(Some(600), &caps, &mut misses).is_empty());
+}
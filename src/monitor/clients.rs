@@ -0,0 +1,189 @@
+use serde::Serialize;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Number of clients being handled, limited by `max` if set.
+#[derive(Debug, Default)]
+pub(crate) struct ClientCounter {
+    max: Option<usize>,
+    alive: AtomicUsize,
+    refused: AtomicUsize,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ClientStats {
+    pub alive: usize,
+    pub refused: usize,
+    pub max: Option<usize>,
+}
+
+/// Count a client as alive until dropped, see `Monitor::client_permit()`.
+#[derive(Debug)]
+pub struct ClientPermit {
+    counter: Arc<ClientCounter>,
+}
+
+impl ClientCounter {
+    pub(crate) fn new(max: Option<usize>) -> Self {
+        Self {
+            max,
+            ..Default::default()
+        }
+    }
+
+    /// Return `None` and count it as refused if `max` is reached.
+    pub(crate) fn acquire(self: &Arc<Self>) -> Option<ClientPermit> {
+        let max = self.max.unwrap_or(usize::MAX);
+        let acquired = self
+            .alive
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .is_ok();
+        if acquired {
+            Some(ClientPermit {
+                counter: self.clone(),
+            })
+        } else {
+            self.refused.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> ClientStats {
+        ClientStats {
+            alive: self.alive.load(Ordering::Relaxed),
+            refused: self.refused.load(Ordering::Relaxed),
+            max: self.max,
+        }
+    }
+}
+
+impl Drop for ClientPermit {
+    fn drop(&mut self) {
+        self.counter.alive.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Number of clients accepted but not yet piped, limited by `max` if set.
+/// These are the cheapest to open for an attacker.
+#[derive(Debug)]
+pub(crate) struct HandshakeCounter {
+    max: Option<usize>,
+    semaphore: Arc<Semaphore>,
+    shed: AtomicUsize,
+    timeouts: AtomicUsize,
+    health_checks: AtomicUsize,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct HandshakeStats {
+    pub pending: usize,
+    /// Number of clients closed due to `max`.
+    pub shed: usize,
+    /// Number of clients timed out before handshake done.
+    pub timeouts: usize,
+    /// Number of health checks closed silently, see
+    /// `NewClient::is_health_check()`.
+    pub health_checks: usize,
+    pub max: Option<usize>,
+}
+
+/// Count a client as handshaking until dropped, see
+/// `Monitor::handshake_permit()`.
+#[derive(Debug)]
+pub struct HandshakePermit {
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Default for HandshakeCounter {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl HandshakeCounter {
+    pub(crate) fn new(max: Option<usize>) -> Self {
+        Self {
+            max,
+            semaphore: Arc::new(Semaphore::new(max.unwrap_or(Semaphore::MAX_PERMITS))),
+            shed: AtomicUsize::new(0),
+            timeouts: AtomicUsize::new(0),
+            health_checks: AtomicUsize::new(0),
+        }
+    }
+
+    /// Return `None` and count it as shed if `max` is reached.
+    pub(crate) fn acquire(&self) -> Option<HandshakePermit> {
+        match self.semaphore.clone().try_acquire_owned() {
+            Ok(permit) => Some(HandshakePermit { _permit: permit }),
+            Err(_) => {
+                self.shed.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn add_timeout(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_health_check(&self) {
+        self.health_checks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> HandshakeStats {
+        let max = self.max.unwrap_or(Semaphore::MAX_PERMITS);
+        HandshakeStats {
+            pending: max - self.semaphore.available_permits(),
+            shed: self.shed.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            health_checks: self.health_checks.load(Ordering::Relaxed),
+            max: self.max,
+        }
+    }
+}
+
+#[test]
+fn test_client_counter() {
+    let counter = Arc::new(ClientCounter::new(Some(2)));
+    let a = counter.acquire().unwrap();
+    let _b = counter.acquire().unwrap();
+    assert!(counter.acquire().is_none());
+    drop(a);
+    let _c = counter.acquire().unwrap();
+    assert_eq!(
+        ClientStats {
+            alive: 2,
+            refused: 1,
+            max: Some(2),
+        },
+        counter.snapshot()
+    );
+}
+
+#[test]
+fn test_handshake_counter() {
+    let counter = HandshakeCounter::new(Some(2));
+    let a = counter.acquire().unwrap();
+    let _b = counter.acquire().unwrap();
+    assert!(counter.acquire().is_none());
+    drop(a);
+    let _c = counter.acquire().unwrap();
+    counter.add_timeout();
+    counter.add_health_check();
+    assert_eq!(
+        HandshakeStats {
+            pending: 2,
+            shed: 1,
+            timeouts: 1,
+            health_checks: 1,
+            max: Some(2),
+        },
+        counter.snapshot()
+    );
+    assert_eq!(0, HandshakeCounter::default().snapshot().pending);
+}
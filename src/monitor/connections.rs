@@ -0,0 +1,109 @@
+use flexstr::SharedStr;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Max number of connections kept in `ConnectionRegistry`, further ones are
+/// not registered.
+const MAX_CONNECTIONS: usize = 4096;
+
+/// A live connection, keyed by the client address.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConnectionInfo {
+    pub client: SocketAddr,
+    /// Tag of the upstream proxy, or `__DIRECT__`.
+    pub server: SharedStr,
+    pub dest: String,
+    /// Unix timestamp in seconds.
+    pub since: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionRegistry {
+    connections: Mutex<HashMap<SocketAddr, ConnectionInfo>>,
+}
+
+/// Keep the connection in `ConnectionRegistry` until dropped, see
+/// `Monitor::register_connection()`.
+#[derive(Debug)]
+pub struct ConnectionEntry {
+    registry: Arc<ConnectionRegistry>,
+    client: SocketAddr,
+}
+
+impl ConnectionRegistry {
+    /// Return `None` if the registry is full or `client` is already there.
+    pub(crate) fn register(
+        self: &Arc<Self>,
+        client: SocketAddr,
+        server: SharedStr,
+        dest: String,
+    ) -> Option<ConnectionEntry> {
+        let mut connections = self.connections.lock();
+        if connections.len() >= MAX_CONNECTIONS || connections.contains_key(&client) {
+            return None;
+        }
+        let since = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_secs())
+            .unwrap_or_default();
+        let info = ConnectionInfo {
+            client,
+            server,
+            dest,
+            since,
+        };
+        connections.insert(client, info);
+        Some(ConnectionEntry {
+            registry: self.clone(),
+            client,
+        })
+    }
+
+    /// Connections from the source port, maybe of different client IPs.
+    pub(crate) fn by_source_port(&self, port: u16) -> Vec<ConnectionInfo> {
+        self.connections
+            .lock()
+            .values()
+            .filter(|info| info.client.port() == port)
+            .cloned()
+            .collect()
+    }
+}
+
+impl Drop for ConnectionEntry {
+    fn drop(&mut self) {
+        self.registry.connections.lock().remove(&self.client);
+    }
+}
+
+#[test]
+fn test_connection_registry() {
+    let registry = Arc::new(ConnectionRegistry::default());
+    let client: SocketAddr = "192.0.2.1:50000".parse().unwrap();
+    let entry = registry
+        .register(client, "a".into(), "example.com:443".into())
+        .unwrap();
+    assert!(registry.register(client, "b".into(), "".into()).is_none());
+    let other: SocketAddr = "192.0.2.2:50000".parse().unwrap();
+    let _other = registry.register(other, "b".into(), "".into()).unwrap();
+
+    let found = registry.by_source_port(50000);
+    assert_eq!(2, found.len());
+    assert!(registry.by_source_port(50001).is_empty());
+    drop(entry);
+    let found = registry.by_source_port(50000);
+    assert_eq!(1, found.len());
+    assert_eq!(other, found[0].client);
+    assert_eq!("b", found[0].server.as_str());
+
+    let _entries: Vec<_> = (0..MAX_CONNECTIONS as u16)
+        .filter_map(|port| registry.register(([10, 0, 0, 1], port).into(), "c".into(), "".into()))
+        .collect();
+    assert_eq!(MAX_CONNECTIONS, registry.connections.lock().len());
+}
@@ -0,0 +1,238 @@
+use flexstr::SharedStr;
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{sync::watch, time::Instant};
+
+use crate::proxy::ProxyServer;
+
+/// Max number of connections tracked at the same time. Beyond that, new
+/// connections are never delayed.
+const MAX_INFLIGHT: usize = 4096;
+
+/// Source IP address, destination host and port of a connection.
+pub type DedupKey = (IpAddr, SharedStr, u16);
+
+/// How the first connection of a key ended up, see `ConnectDedup`.
+#[derive(Debug, Clone)]
+pub enum DedupOutcome {
+    /// Connected via this server.
+    Connected(Arc<ProxyServer>),
+    /// Tried all servers but failed.
+    Failed,
+    /// Gave up before any outcome, e.g. the client has gone.
+    Abandoned,
+}
+
+/// Result of `ConnectDedup::enter()`.
+#[derive(Debug)]
+pub enum DedupTicket {
+    /// The first one, report its outcome with the guard.
+    Leader(DedupGuard),
+    /// Delayed until the first one of the same key ended up.
+    Followed(DedupOutcome),
+    /// Not coalesced, e.g. the first one has been connecting too long.
+    Bypass,
+}
+
+/// Coalesce identical connections arriving while the first one is still
+/// connecting, for clients retrying aggressively on slow upstreams.
+///
+/// A connection with the same `DedupKey` as an in-flight one started in
+/// `window` waits for its outcome before connecting, then tries the
+/// server it connected via first, or skips the best server if it failed.
+#[derive(Debug)]
+pub struct ConnectDedup {
+    window: Duration,
+    inflight: Mutex<HashMap<DedupKey, Inflight>>,
+    next_id: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Inflight {
+    id: u64,
+    started: Instant,
+    outcome: watch::Receiver<Option<DedupOutcome>>,
+}
+
+/// Keep the connection in-flight until dropped, see `DedupTicket`.
+#[derive(Debug)]
+pub struct DedupGuard {
+    dedup: Arc<ConnectDedup>,
+    key: DedupKey,
+    id: u64,
+    outcome: watch::Sender<Option<DedupOutcome>>,
+}
+
+impl ConnectDedup {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            inflight: Default::default(),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Become the leader of `key`, or wait for the current one.
+    pub async fn enter(self: &Arc<Self>, key: DedupKey) -> DedupTicket {
+        let mut outcome = {
+            let mut inflight = self.inflight.lock();
+            match inflight.get(&key) {
+                Some(first) if first.started.elapsed() < self.window => first.outcome.clone(),
+                Some(_) => return DedupTicket::Bypass,
+                None if inflight.len() >= MAX_INFLIGHT => return DedupTicket::Bypass,
+                None => {
+                    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                    let (sender, receiver) = watch::channel(None);
+                    let first = Inflight {
+                        id,
+                        started: Instant::now(),
+                        outcome: receiver,
+                    };
+                    inflight.insert(key.clone(), first);
+                    return DedupTicket::Leader(DedupGuard {
+                        dedup: self.clone(),
+                        key,
+                        id,
+                        outcome: sender,
+                    });
+                }
+            }
+        };
+        let outcome = match outcome.wait_for(Option::is_some).await {
+            Ok(outcome) => outcome.clone().unwrap_or(DedupOutcome::Abandoned),
+            Err(_) => DedupOutcome::Abandoned,
+        };
+        DedupTicket::Followed(outcome)
+    }
+
+    /// Number of connections being the leader of their keys.
+    pub fn inflight(&self) -> usize {
+        self.inflight.lock().len()
+    }
+}
+
+impl DedupGuard {
+    /// Release the waiting connections with `outcome`.
+    pub fn finish(self, outcome: DedupOutcome) {
+        self.outcome.send_replace(Some(outcome));
+    }
+}
+
+impl Drop for DedupGuard {
+    fn drop(&mut self) {
+        let mut inflight = self.dedup.inflight.lock();
+        if inflight
+            .get(&self.key)
+            .is_some_and(|first| first.id == self.id)
+        {
+            inflight.remove(&self.key);
+        }
+        drop(inflight);
+        self.outcome.send_if_modified(|outcome| {
+            outcome.get_or_insert(DedupOutcome::Abandoned);
+            true
+        });
+    }
+}
+
+impl DedupOutcome {
+    /// Reorder `servers` for a connection waited for this outcome.
+    pub fn reorder(&self, servers: &mut [Arc<ProxyServer>]) {
+        match self {
+            Self::Connected(server) => {
+                if let Some(i) = servers.iter().position(|s| s == server) {
+                    servers[..=i].rotate_right(1);
+                }
+            }
+            Self::Failed if !servers.is_empty() => servers.rotate_left(1),
+            Self::Failed | Self::Abandoned => (),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_connect_dedup() {
+    let dedup = Arc::new(ConnectDedup::new(Duration::from_millis(100)));
+    let key = |port| -> DedupKey { ([192, 0, 2, 1].into(), "a.test".into(), port) };
+
+    let leader = match dedup.enter(key(80)).await {
+        DedupTicket::Leader(guard) => guard,
+        ticket => panic!("{:?}", ticket),
+    };
+    // Other keys are not coalesced
+    assert!(matches!(
+        dedup.enter(key(443)).await,
+        DedupTicket::Leader(_)
+    ));
+    let followers: Vec<_> = (0..3)
+        .map(|_| {
+            let dedup = dedup.clone();
+            tokio::spawn(async move { dedup.enter(key(80)).await })
+        })
+        .collect();
+    tokio::task::yield_now().await;
+    leader.finish(DedupOutcome::Failed);
+    for follower in followers {
+        let ticket = follower.await.unwrap();
+        assert!(matches!(
+            ticket,
+            DedupTicket::Followed(DedupOutcome::Failed)
+        ));
+    }
+    assert_eq!(0, dedup.inflight());
+
+    // Dropped without an outcome
+    let leader = dedup.enter(key(80)).await;
+    let dedup2 = dedup.clone();
+    let follower = tokio::spawn(async move { dedup2.enter(key(80)).await });
+    tokio::task::yield_now().await;
+    drop(leader);
+    let ticket = follower.await.unwrap();
+    assert!(matches!(
+        ticket,
+        DedupTicket::Followed(DedupOutcome::Abandoned)
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_connect_dedup_window() {
+    let dedup = Arc::new(ConnectDedup::new(Duration::from_millis(100)));
+    let key: DedupKey = ([192, 0, 2, 1].into(), "a.test".into(), 80);
+    let _leader = dedup.enter(key.clone()).await;
+    tokio::time::advance(Duration::from_millis(100)).await;
+    assert!(matches!(dedup.enter(key).await, DedupTicket::Bypass));
+}
+
+#[test]
+fn test_dedup_outcome_reorder() {
+    let server = |port| {
+        let addr = ([127, 0, 0, 1], port).into();
+        let proto = crate::proxy::ProxyProto::socks5(false);
+        let dns = "127.0.0.1:53".parse().unwrap();
+        let server = ProxyServer::new(addr, proto, dns, Duration::from_secs(1), None, None, None);
+        Arc::new(server.unwrap())
+    };
+    let servers = vec![server(1), server(2), server(3)];
+    let ports = |servers: &[Arc<ProxyServer>]| -> Vec<_> {
+        servers.iter().map(|s| s.addr.port()).collect()
+    };
+
+    let mut reordered = servers.clone();
+    DedupOutcome::Connected(servers[2].clone()).reorder(&mut reordered);
+    assert_eq!(vec![3, 1, 2], ports(&reordered));
+    let mut reordered = servers.clone();
+    DedupOutcome::Failed.reorder(&mut reordered);
+    assert_eq!(vec![2, 3, 1], ports(&reordered));
+    let mut reordered = servers.clone();
+    DedupOutcome::Abandoned.reorder(&mut reordered);
+    assert_eq!(vec![1, 2, 3], ports(&reordered));
+    DedupOutcome::Failed.reorder(&mut []);
+}
@@ -0,0 +1,200 @@
+use flexstr::SharedStr;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    privacy::HOSTNAMES,
+    proxy::{Address, Traffic},
+};
+
+/// Top destinations by bytes, see `DESTINATIONS`.
+///
+/// Counted with the Space-Saving algorithm: at most `capacity`
+/// destinations are kept, a new one replaces the least counted one and
+/// inherits its bytes as the error. So memory is bounded, and any
+/// destination with more than `1 / capacity` of all bytes is always kept.
+#[derive(Debug)]
+pub struct TopDestinations {
+    counters: Mutex<BTreeMap<SharedStr, Counter>>,
+    /// Max number of destinations kept, 0 for disabled.
+    capacity: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Counter {
+    /// Traffic since counted, without the error.
+    traffic: Traffic,
+    connections: usize,
+    /// Bytes to rank by, including the error.
+    bytes: usize,
+    /// Bytes inherited from the replaced destination.
+    error: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DestinationTraffic {
+    pub destination: SharedStr,
+    #[serde(flatten)]
+    pub traffic: Traffic,
+    pub connections: usize,
+    /// Upper bound of bytes overcounted on ranking, those may belong to
+    /// other destinations.
+    pub error_bytes: usize,
+}
+
+/// Global top destinations of all connections, kept across reloads.
+pub static DESTINATIONS: TopDestinations = TopDestinations::new();
+
+impl TopDestinations {
+    pub const fn new() -> Self {
+        Self {
+            counters: Mutex::new(BTreeMap::new()),
+            capacity: AtomicUsize::new(0),
+        }
+    }
+
+    /// Start counting, keep at most `capacity` destinations.
+    pub fn enable(&self, capacity: usize) {
+        self.capacity.store(capacity, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity.load(Ordering::Relaxed) > 0
+    }
+
+    /// Count `traffic` and `connections` on the destination.
+    pub fn add(&self, dest: &SharedStr, traffic: Traffic, connections: usize) {
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        if capacity == 0 {
+            return;
+        }
+        let bytes = traffic.tx_bytes + traffic.rx_bytes;
+        let mut counters = self.counters.lock();
+        if !counters.contains_key(dest) && counters.len() >= capacity {
+            let min = counters
+                .iter()
+                .min_by_key(|(_, counter)| counter.bytes)
+                .map(|(dest, counter)| (dest.clone(), counter.bytes));
+            if let Some((min_dest, min_bytes)) = min {
+                counters.remove(&min_dest);
+                let counter = Counter {
+                    bytes: min_bytes,
+                    error: min_bytes,
+                    ..Default::default()
+                };
+                counters.insert(dest.clone(), counter);
+            }
+        }
+        let counter = counters.entry(dest.clone()).or_default();
+        counter.traffic += traffic;
+        counter.connections += connections;
+        counter.bytes += bytes;
+    }
+
+    /// At most `n` destinations with the most bytes, the most first.
+    pub fn top(&self, n: usize) -> Vec<DestinationTraffic> {
+        let counters = self.counters.lock();
+        let mut top: Vec<_> = counters.iter().collect();
+        top.sort_by_key(|(_, counter)| std::cmp::Reverse(counter.bytes));
+        top.into_iter()
+            .take(n)
+            .map(|(dest, counter)| DestinationTraffic {
+                destination: dest.clone(),
+                traffic: counter.traffic,
+                connections: counter.connections,
+                error_bytes: counter.error,
+            })
+            .collect()
+    }
+
+    /// Drop all counters, return the number of destinations dropped.
+    pub fn reset(&self) -> usize {
+        let mut counters = self.counters.lock();
+        let n = counters.len();
+        counters.clear();
+        n
+    }
+}
+
+impl Default for TopDestinations {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Count by domain name (filtered by `HOSTNAMES`), or by /24 (IPv4) or
+/// /48 (IPv6) for IP addresses.
+pub fn destination_key(host: &Address) -> SharedStr {
+    let ip = match host {
+        Address::Domain(name) => {
+            let name = name.trim_end_matches('.').to_lowercase();
+            return HOSTNAMES.name(&name).as_ref().into();
+        }
+        Address::Ip(IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(*ip),
+        },
+        Address::Ip(ip) => *ip,
+    };
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{}/24", Ipv4Addr::new(a, b, c, 0)).into()
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            format!("{}/48", Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0)).into()
+        }
+    }
+}
+
+#[test]
+fn test_top_destinations_skewed() {
+    let top = TopDestinations::new();
+    top.add(&"a.test".into(), (1, 1).into(), 1);
+    assert!(top.top(10).is_empty());
+
+    top.enable(4);
+    // Zipf-like: "heavy" gets half of all bytes, others are long tail
+    for i in 0..1000 {
+        top.add(&"heavy.test".into(), (90, 10).into(), 1);
+        if i % 10 == 0 {
+            top.add(&"medium.test".into(), (200, 100).into(), 1);
+        }
+        let tail = format!("tail-{}.test", i).into();
+        top.add(&tail, (10, 0).into(), 1);
+    }
+    let result = top.top(10);
+    assert_eq!(4, result.len());
+    assert_eq!(result[0].destination, "heavy.test");
+    assert_eq!(1000, result[0].connections);
+    assert_eq!(90_000, result[0].traffic.tx_bytes);
+    assert_eq!(0, result[0].error_bytes);
+    assert_eq!(result[1].destination, "medium.test");
+    assert_eq!(100, result[1].connections);
+    // The tail churns on the other two counters
+    assert!(result[2..]
+        .iter()
+        .all(|d| d.destination.starts_with("tail-")));
+    assert!(result[3].error_bytes > 0);
+    assert_eq!(1, top.top(1).len());
+
+    assert_eq!(4, top.reset());
+    assert!(top.top(10).is_empty());
+}
+
+#[test]
+fn test_destination_key() {
+    let key = |host: Address| destination_key(&host).to_string();
+    assert_eq!("example.com", key(Address::Domain("Example.COM.".into())));
+    assert_eq!("192.0.2.0/24", key([192, 0, 2, 77].into()));
+    let ip: Ipv6Addr = "2001:db8:1:2::3".parse().unwrap();
+    assert_eq!("2001:db8:1::/48", key(ip.octets().into()));
+    let mapped: Ipv6Addr = "::ffff:192.0.2.1".parse().unwrap();
+    assert_eq!("192.0.2.0/24", key(mapped.octets().into()));
+}
@@ -0,0 +1,123 @@
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+use crate::histogram::Buckets;
+
+/// Upper bounds of histogram buckets in microseconds.
+pub const BUCKET_BOUNDS_US: [u64; 10] = [
+    100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000, 5_000_000,
+];
+/// One in this many accepted clients has its spawn-to-poll latency sampled.
+const SPAWN_SAMPLE_EVERY: usize = 16;
+
+/// Histogram with fixed buckets of `BUCKET_BOUNDS_US`.
+#[derive(Debug)]
+pub struct Histogram(Mutex<HistogramCounts>);
+
+pub type HistogramCounts = Buckets<{ BUCKET_BOUNDS_US.len() }>;
+
+/// Self-instrumentation of the runtime, telling local executor saturation
+/// apart from slow upstreams.
+#[derive(Debug)]
+pub struct EventLoopStats {
+    /// From a client accepted to its task first polled, sampled.
+    spawn_delay: Histogram,
+    /// How late probe rounds start beyond their schedule.
+    probe_lag: Histogram,
+    accepted: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct EventLoopCounters {
+    pub spawn_delay: HistogramCounts,
+    pub probe_lag: HistogramCounts,
+}
+
+pub static EVENT_LOOP: EventLoopStats = EventLoopStats::new();
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Self(Mutex::new(Buckets::new(&BUCKET_BOUNDS_US)))
+    }
+
+    pub fn add(&self, value: Duration) {
+        let us = value.as_micros().min(u64::MAX as u128) as u64;
+        self.0.lock().add(us);
+    }
+
+    pub fn snapshot(&self) -> HistogramCounts {
+        *self.0.lock()
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventLoopStats {
+    const fn new() -> Self {
+        Self {
+            spawn_delay: Histogram::new(),
+            probe_lag: Histogram::new(),
+            accepted: AtomicUsize::new(0),
+        }
+    }
+
+    /// Return the time of accepting if this client is sampled, to be
+    /// passed to `add_spawn_delay()` once its task starts.
+    pub fn sample_spawn(&self) -> Option<Instant> {
+        let n = self.accepted.fetch_add(1, Ordering::Relaxed);
+        (n % SPAWN_SAMPLE_EVERY == 0).then(Instant::now)
+    }
+
+    pub fn add_spawn_delay(&self, accepted_at: Instant) {
+        self.spawn_delay.add(accepted_at.elapsed());
+    }
+
+    pub fn add_probe_lag(&self, lag: Duration) {
+        self.probe_lag.add(lag);
+    }
+
+    pub fn snapshot(&self) -> EventLoopCounters {
+        EventLoopCounters {
+            spawn_delay: self.spawn_delay.snapshot(),
+            probe_lag: self.probe_lag.snapshot(),
+        }
+    }
+}
+
+#[test]
+fn test_histogram() {
+    let histogram = Histogram::new();
+    for us in [0, 100, 101, 4_000, 5_000_000, 60_000_000] {
+        histogram.add(Duration::from_micros(us));
+    }
+    let counts = histogram.snapshot();
+    assert_eq!(
+        vec![2, 1, 0, 1, 0, 0, 0, 0, 0, 1, 1],
+        counts.counts().collect::<Vec<_>>()
+    );
+    assert_eq!(6, counts.count());
+    assert_eq!(65_004_201, counts.sum());
+}
+
+#[test]
+fn test_spawn_sampling() {
+    let stats = EventLoopStats::new();
+    let sampled = (0..SPAWN_SAMPLE_EVERY * 4)
+        .filter_map(|_| stats.sample_spawn())
+        .count();
+    assert_eq!(4, sampled);
+    stats.add_spawn_delay(Instant::now());
+    stats.add_probe_lag(Duration::from_millis(20));
+    let counters = stats.snapshot();
+    assert_eq!(1, counters.spawn_delay.count());
+    assert_eq!(Some(50_000), counters.probe_lag.percentile(100));
+}
@@ -0,0 +1,80 @@
+use flexstr::SharedStr;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+use super::ServerList;
+use crate::proxy::ProxyServer;
+
+/// Events lagged behind this are dropped for slow subscribers.
+const CHANNEL_CAPACITY: usize = 64;
+/// The best server is replaced only if the new one's score is lower by this,
+/// to avoid flapping on similar scores.
+const BEST_SCORE_HYSTERESIS: i32 = 30;
+
+/// Server state changes, see `Monitor::subscribe()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerEvent {
+    /// Passed a probe after being down or unknown.
+    ServerUp(SharedStr),
+    /// Failed a probe after being up or unknown.
+    ServerDown(SharedStr),
+    ServersReloaded {
+        added: usize,
+        removed: usize,
+    },
+    /// The server with the lowest score changed.
+    BestServerChanged(SharedStr),
+}
+
+pub(crate) struct EventBus {
+    sender: broadcast::Sender<ServerEvent>,
+    best: Mutex<Option<Arc<ProxyServer>>>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(CHANNEL_CAPACITY).0,
+            best: Default::default(),
+        }
+    }
+}
+
+impl EventBus {
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.sender.subscribe()
+    }
+
+    pub(crate) fn emit(&self, event: ServerEvent) {
+        // Error if no one subscribed, that's fine
+        let _ = self.sender.send(event);
+    }
+
+    /// Emit `BestServerChanged` if the best one in `servers` should replace
+    /// the current one.
+    pub(crate) fn check_best(&self, servers: &ServerList) {
+        let candidate = servers
+            .iter()
+            .filter_map(|server| Some((server, server.candidate_score()?)))
+            .min_by_key(|(_, score)| *score);
+        let (candidate, score) = match candidate {
+            Some(candidate) => candidate,
+            None => return,
+        };
+        let mut best = self.best.lock();
+        let replace = match best.as_ref() {
+            None => true,
+            Some(current) if Arc::ptr_eq(current, candidate) => false,
+            Some(current) if !servers.iter().any(|s| Arc::ptr_eq(s, current)) => true,
+            Some(current) => match current.candidate_score() {
+                None => true,
+                Some(current) => score + BEST_SCORE_HYSTERESIS < current,
+            },
+        };
+        if replace {
+            best.replace(candidate.clone());
+            self.emit(ServerEvent::BestServerChanged(candidate.tag()));
+        }
+    }
+}
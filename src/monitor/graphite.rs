@@ -1,17 +1,176 @@
+use futures_util::FutureExt;
+#[cfg(feature = "graphite_tls")]
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
 use std::{
     io::{self, Write},
     net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
     time::{Duration, SystemTime},
 };
-use tokio::{io::AsyncWriteExt, net::TcpStream, time::timeout};
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+#[cfg(feature = "graphite_tls")]
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{
+        self,
+        client::{ServerCertVerified, ServerCertVerifier},
+        Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName,
+    },
+    TlsConnector,
+};
 use tracing::{debug, instrument, warn};
 
 static GRAPHITE_TIMEOUT_SECS: u64 = 5;
 
+/// TLS settings for connecting the Graphite server.
+#[cfg(feature = "graphite_tls")]
+#[derive(Clone)]
+pub struct GraphiteTls {
+    connector: TlsConnector,
+    server_name: ServerName,
+}
+
+#[cfg(feature = "graphite_tls")]
+impl std::fmt::Debug for GraphiteTls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GraphiteTls")
+            .field("server_name", &self.server_name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Accept any certificate, for `--graphite-tls-insecure`.
+#[cfg(feature = "graphite_tls")]
+struct NoVerifier;
+
+#[cfg(feature = "graphite_tls")]
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(feature = "graphite_tls")]
+impl GraphiteTls {
+    /// Verify the server certificate against `server_name` with CAs from
+    /// `ca_file` (PEM), or the bundled Mozilla roots if not set. Skip the
+    /// verification if `insecure`.
+    pub fn new(server_name: &str, ca_file: Option<&Path>, insecure: bool) -> io::Result<Self> {
+        let server_name = ServerName::try_from(server_name).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid server name \"{}\"", server_name),
+            )
+        })?;
+        let mut roots = RootCertStore::empty();
+        match ca_file {
+            Some(path) => {
+                let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+                if let (0, _) = roots.add_parsable_certificates(&certs) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "no valid certificate in CA file",
+                    ));
+                }
+            }
+            None => roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            })),
+        }
+        let mut config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        if insecure {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(NoVerifier));
+        }
+        Ok(Self {
+            connector: Arc::new(config).into(),
+            server_name,
+        })
+    }
+}
+
+#[derive(Debug)]
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(feature = "graphite_tls")]
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Stream {
+    /// Return false if the server has closed or reset the connection.
+    /// Graphite servers send nothing, anything received (other than TLS
+    /// handshake messages, handled by rustls) is discarded.
+    fn is_alive(&mut self) -> bool {
+        let mut buf = [0u8; 512];
+        loop {
+            let read = match self {
+                Stream::Tcp(stream) => stream.read(&mut buf).now_or_never(),
+                #[cfg(feature = "graphite_tls")]
+                Stream::Tls(stream) => stream.read(&mut buf).now_or_never(),
+            };
+            match read {
+                None => return true,
+                Some(Ok(0)) | Some(Err(_)) => return false,
+                Some(Ok(_)) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "graphite_tls")]
+            Stream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "graphite_tls")]
+            Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "graphite_tls")]
+            Stream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Connection to a Graphite server, kept open across `write_records()`.
 #[derive(Debug)]
 pub struct Graphite {
     server_addr: SocketAddr,
-    stream: Option<TcpStream>,
+    #[cfg(feature = "graphite_tls")]
+    tls: Option<GraphiteTls>,
+    stream: Option<Stream>,
 }
 
 #[derive(Clone, Debug)]
@@ -25,54 +184,102 @@ impl Graphite {
     pub fn new(server_addr: SocketAddr) -> Self {
         Graphite {
             stream: None,
+            #[cfg(feature = "graphite_tls")]
+            tls: None,
+            server_addr,
+        }
+    }
+
+    #[cfg(feature = "graphite_tls")]
+    pub fn with_tls(server_addr: SocketAddr, tls: GraphiteTls) -> Self {
+        Graphite {
+            stream: None,
+            tls: Some(tls),
             server_addr,
         }
     }
 
+    async fn connect(&self) -> io::Result<Stream> {
+        debug!("start new connection to graphite server");
+        let stream = TcpStream::connect(&self.server_addr).await?;
+        #[cfg(feature = "graphite_tls")]
+        if let Some(tls) = &self.tls {
+            let stream = tls
+                .connector
+                .connect(tls.server_name.clone(), stream)
+                .await?;
+            return Ok(Stream::Tls(stream.into()));
+        }
+        Ok(Stream::Tcp(stream))
+    }
+
+    /// Write records on the kept connection. Retry once on a fresh one if
+    /// the kept connection is broken.
     #[instrument(skip_all)]
     pub async fn write_records(&mut self, records: Vec<Record>) -> io::Result<()> {
-        let Graphite {
-            ref server_addr,
-            stream: ref mut stream_opt,
-        } = self;
-
-        let mut stream = if let Some(stream) = stream_opt.take() {
-            stream
-        } else {
-            debug!("start new connection to graphite server");
-            TcpStream::connect(&server_addr).await?
-        };
-
         let mut buf = Vec::new();
         for record in records {
             record.write_paintext(&mut buf).unwrap();
         }
 
         let max_wait = Duration::from_secs(GRAPHITE_TIMEOUT_SECS);
-        match timeout(max_wait, stream.write_all(&buf)).await {
-            Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut)),
-            Ok(Err(err)) => {
-                warn!("fail to send metrics: {}", err);
-                self.stream = None;
-                Err(err)
+        let kept = self.stream.take().and_then(|mut stream| {
+            if stream.is_alive() {
+                Some(stream)
+            } else {
+                debug!("graphite connection closed by server");
+                None
             }
-            Ok(Ok(_)) => {
-                stream_opt.replace(stream);
-                Ok(())
+        });
+        let mut retry = kept.is_some();
+        let mut stream = kept;
+        loop {
+            let mut conn = match stream.take() {
+                Some(conn) => conn,
+                None => match timeout(max_wait, self.connect()).await {
+                    Err(_) => return Err(io::Error::from(io::ErrorKind::TimedOut)),
+                    Ok(conn) => conn?,
+                },
+            };
+            let result = timeout(max_wait, async {
+                conn.write_all(&buf).await?;
+                conn.flush().await
+            })
+            .await
+            .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut)));
+            match result {
+                Ok(()) => {
+                    self.stream = Some(conn);
+                    return Ok(());
+                }
+                // Records may be partially written, resend them all,
+                // Graphite keeps the latest value of the same timestamp.
+                Err(err) if retry => {
+                    debug!("fail to send metrics, retry: {}", err);
+                    retry = false;
+                }
+                Err(err) => {
+                    warn!("fail to send metrics: {}", err);
+                    return Err(err);
+                }
             }
         }
     }
 }
 
 impl Record {
-    pub fn new(path: String, value: u64, time: Option<SystemTime>) -> Self {
+    pub fn new(path: String, value: u64, time: Option<SystemTime>) -> io::Result<Self> {
         if !path.is_ascii() || path.contains(' ') || path.contains('\n') {
-            panic!(
-                "Graphite path contains space, line break, \
-                 or non-ASCII characters."
-            );
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Graphite path \"{}\" contains space, line break, \
+                     or non-ASCII characters.",
+                    path.escape_debug()
+                ),
+            ));
         }
-        Record { path, value, time }
+        Ok(Record { path, value, time })
     }
 
     fn write_paintext<B>(&self, buf: &mut B) -> io::Result<()>
@@ -91,3 +298,128 @@ impl Record {
         writeln!(buf, "{} {} {}", self.path, self.value, time)
     }
 }
+
+#[test]
+fn test_record_new() {
+    assert!(Record::new("moproxy.a.delay".into(), 1, None).is_ok());
+    assert!(Record::new("moproxy.a b.delay".into(), 1, None).is_err());
+    assert!(Record::new("moproxy.東京.delay".into(), 1, None).is_err());
+}
+
+#[cfg(test)]
+async fn read_sink<S>(stream: S) -> Vec<String>
+where
+    S: tokio::io::AsyncRead + Unpin,
+{
+    use tokio::io::AsyncBufReadExt;
+
+    let mut lines = tokio::io::BufReader::new(stream).lines();
+    let mut paths = Vec::new();
+    while let Ok(Some(line)) = lines.next_line().await {
+        paths.push(line.split(' ').next().unwrap().to_string());
+    }
+    paths
+}
+
+/// Write `a` and `b` separately, the first connection is closed by the
+/// server after `a` received. Return paths received on each connection.
+#[cfg(test)]
+async fn write_on_broken_connection<F, G, S>(
+    mut graphite: Graphite,
+    listener: tokio::net::TcpListener,
+    accept: F,
+) -> (Vec<String>, Vec<String>)
+where
+    F: Fn(TcpStream) -> G + Send + Sync + 'static,
+    G: std::future::Future<Output = S> + Send,
+    S: tokio::io::AsyncRead + Unpin + Send,
+{
+    use tokio::{io::AsyncBufReadExt, sync::oneshot};
+
+    let record = |path: &str| Record::new(path.into(), 1, None).unwrap();
+    let (closed_tx, closed_rx) = oneshot::channel();
+    let sink = tokio::spawn(async move {
+        let first = accept(listener.accept().await.unwrap().0).await;
+        let mut line = String::new();
+        let mut first = tokio::io::BufReader::new(first);
+        first.read_line(&mut line).await.unwrap();
+        drop(first);
+        closed_tx.send(()).unwrap();
+        let second = accept(listener.accept().await.unwrap().0).await;
+        (
+            vec![line.split(' ').next().unwrap().to_string()],
+            read_sink(second).await,
+        )
+    });
+
+    graphite.write_records(vec![record("a")]).await.unwrap();
+    closed_rx.await.unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    graphite.write_records(vec![record("b")]).await.unwrap();
+    drop(graphite);
+    timeout(Duration::from_secs(5), sink)
+        .await
+        .expect("second connection not made")
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_graphite_reconnect() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let graphite = Graphite::new(listener.local_addr().unwrap());
+    let (first, second) =
+        write_on_broken_connection(graphite, listener, |stream| async { stream }).await;
+    assert_eq!(vec!["a"], first);
+    assert_eq!(vec!["b"], second);
+}
+
+#[cfg(feature = "graphite_tls")]
+#[tokio::test]
+async fn test_graphite_tls_reconnect() {
+    use tokio_rustls::{
+        rustls::{PrivateKey, ServerConfig},
+        TlsAcceptor,
+    };
+
+    let fixture = |name| {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    };
+    let read_pem = |name| BufReader::new(File::open(fixture(name)).unwrap());
+    let certs = rustls_pemfile::certs(&mut read_pem("graphite-cert.pem")).unwrap();
+    let key = rustls_pemfile::pkcs8_private_keys(&mut read_pem("graphite-key.pem"))
+        .unwrap()
+        .remove(0);
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            certs.into_iter().map(Certificate).collect(),
+            PrivateKey(key),
+        )
+        .unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let ca = fixture("graphite-ca.pem");
+    let tls = GraphiteTls::new("localhost", Some(&ca), false).unwrap();
+    let (first, second) =
+        write_on_broken_connection(Graphite::with_tls(addr, tls), listener, move |stream| {
+            let acceptor = acceptor.clone();
+            async move { acceptor.accept(stream).await.unwrap() }
+        })
+        .await;
+    assert_eq!(vec!["a"], first);
+    assert_eq!(vec!["b"], second);
+
+    // Certificate not for this name
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { while listener.accept().await.is_ok() {} });
+    let tls = GraphiteTls::new("example.com", Some(&ca), false).unwrap();
+    let record = Record::new("a".into(), 1, None).unwrap();
+    let mut graphite = Graphite::with_tls(addr, tls);
+    assert!(graphite.write_records(vec![record]).await.is_err());
+}
@@ -0,0 +1,72 @@
+use parking_lot::Mutex;
+use tracing::{error, info};
+
+/// Rounds of probing with enough healthy servers required to leave the
+/// degraded state.
+const RECOVERY_ROUNDS: u8 = 2;
+
+/// Watch the number of healthy (scored) servers after each probe round.
+pub(crate) struct HealthWatch {
+    min_healthy: usize,
+    state: Mutex<HealthState>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct HealthState {
+    degraded: bool,
+    recovered_rounds: u8,
+}
+
+impl HealthWatch {
+    pub(crate) fn new(min_healthy: usize) -> Self {
+        Self {
+            min_healthy,
+            state: Default::default(),
+        }
+    }
+
+    #[cfg(all(feature = "systemd", target_os = "linux"))]
+    pub(crate) fn min_healthy(&self) -> usize {
+        self.min_healthy
+    }
+
+    pub(crate) fn is_degraded(&self) -> bool {
+        self.state.lock().degraded
+    }
+
+    /// Update the state with the result of a probe round.
+    /// Return true if it's degraded after update.
+    pub(crate) fn update(&self, healthy: usize) -> bool {
+        let mut state = self.state.lock();
+        if healthy < self.min_healthy {
+            if !state.degraded {
+                error!(
+                    "only {} healthy server(s), less than {} required",
+                    healthy, self.min_healthy
+                );
+            }
+            state.degraded = true;
+            state.recovered_rounds = 0;
+        } else if state.degraded {
+            state.recovered_rounds += 1;
+            if state.recovered_rounds >= RECOVERY_ROUNDS {
+                info!("recovered with {} healthy server(s)", healthy);
+                *state = Default::default();
+            }
+        }
+        state.degraded
+    }
+}
+
+#[test]
+fn test_health_watch_hysteresis() {
+    let watch = HealthWatch::new(2);
+    assert!(!watch.update(2));
+    assert!(watch.update(1));
+    // Need 2 rounds to recover
+    assert!(watch.update(3));
+    assert!(watch.update(1));
+    assert!(watch.update(2));
+    assert!(!watch.update(2));
+    assert!(!watch.is_degraded());
+}
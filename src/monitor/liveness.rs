@@ -0,0 +1,125 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+use tracing::{debug, error};
+
+/// Loops expected to run all the time, see `Liveness`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LivenessSignal {
+    /// Accepting clients, beat on each client and once a while if idle.
+    Accept,
+    /// Beat on each probe round.
+    Probe,
+}
+
+/// Last time each loop made progress, so that the systemd watchdog
+/// stops poking once any of them stalls.
+#[derive(Debug)]
+pub struct Liveness {
+    since: Instant,
+    /// Milliseconds since `since` of the last beat, per signal.
+    accept: AtomicU64,
+    probe: AtomicU64,
+}
+
+impl fmt::Display for LivenessSignal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Accept => write!(f, "accept loop"),
+            Self::Probe => write!(f, "probe loop"),
+        }
+    }
+}
+
+impl Liveness {
+    /// All signals are fresh on creation.
+    pub fn new(now: Instant) -> Self {
+        Self {
+            since: now,
+            accept: AtomicU64::new(0),
+            probe: AtomicU64::new(0),
+        }
+    }
+
+    fn last_beat(&self, signal: LivenessSignal) -> &AtomicU64 {
+        match signal {
+            LivenessSignal::Accept => &self.accept,
+            LivenessSignal::Probe => &self.probe,
+        }
+    }
+
+    pub fn beat(&self, signal: LivenessSignal) {
+        self.beat_at(signal, Instant::now())
+    }
+
+    pub fn beat_at(&self, signal: LivenessSignal, now: Instant) {
+        let millis = now.saturating_duration_since(self.since).as_millis() as u64;
+        self.last_beat(signal).fetch_max(millis, Ordering::Relaxed);
+    }
+
+    /// Signals older than their thresholds at `now`, with their ages.
+    pub fn stale_at(
+        &self,
+        now: Instant,
+        thresholds: &[(LivenessSignal, Duration)],
+    ) -> Vec<(LivenessSignal, Duration)> {
+        let elapsed = now.saturating_duration_since(self.since);
+        thresholds
+            .iter()
+            .filter_map(|(signal, threshold)| {
+                let last = self.last_beat(*signal).load(Ordering::Relaxed);
+                let age = elapsed.saturating_sub(Duration::from_millis(last));
+                (age > *threshold).then_some((*signal, age))
+            })
+            .collect()
+    }
+}
+
+/// Log deadlocks detected by `parking_lot`, return the number of them.
+pub fn check_deadlocks() -> usize {
+    let deadlocks = parking_lot::deadlock::check_deadlock();
+    if !deadlocks.is_empty() {
+        error!("{} deadlocks detected!", deadlocks.len());
+        for (i, threads) in deadlocks.iter().enumerate() {
+            debug!("Deadlock #{}", i);
+            for t in threads {
+                debug!("Thread Id {:#?}", t.thread_id());
+                debug!("{:#?}", t.backtrace());
+            }
+        }
+    }
+    deadlocks.len()
+}
+
+#[test]
+fn test_liveness() {
+    use LivenessSignal::*;
+
+    let start = Instant::now();
+    let secs = |n| start + Duration::from_secs(n);
+    let liveness = Liveness::new(start);
+    let thresholds = [
+        (Accept, Duration::from_secs(10)),
+        (Probe, Duration::from_secs(60)),
+    ];
+    assert!(liveness.stale_at(secs(10), &thresholds).is_empty());
+    assert_eq!(
+        vec![(Accept, Duration::from_secs(11))],
+        liveness.stale_at(secs(11), &thresholds)
+    );
+
+    liveness.beat_at(Accept, secs(20));
+    liveness.beat_at(Probe, secs(20));
+    // Beats never go backward
+    liveness.beat_at(Accept, secs(5));
+    assert!(liveness.stale_at(secs(30), &thresholds).is_empty());
+    let stale = liveness.stale_at(secs(81), &thresholds);
+    assert_eq!(vec![Accept, Probe], {
+        stale.iter().map(|(signal, _)| *signal).collect::<Vec<_>>()
+    });
+    // Signals not given are not checked
+    assert!(liveness.stale_at(secs(81), &[]).is_empty());
+    assert_eq!("probe loop", Probe.to_string());
+}
@@ -1,50 +1,149 @@
 mod graphite;
 #[cfg(feature = "score_script")]
 use rlua::prelude::*;
+mod accounting;
 mod alive_test;
+mod auto_caps;
+mod clients;
+mod connections;
+mod dedup;
+mod destinations;
+mod event_loop;
+mod events;
+mod health;
+mod liveness;
+mod parallel;
+mod probe_capture;
+mod probe_log;
+mod reload;
+mod schedule;
+mod sources;
+mod suspend;
+mod tasks;
 mod traffic;
-use parking_lot::Mutex;
+use flexstr::SharedStr;
+use parking_lot::{Mutex, RwLock};
 use rand::{self, Rng};
 use std::{
     self,
     collections::{HashMap, HashSet},
     io,
-    net::SocketAddr,
-    sync::Arc,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::{Duration, SystemTime},
 };
 #[cfg(feature = "score_script")]
 use std::{fs::File, io::Read, path::Path};
-use tokio::time::{interval_at, Instant};
-use tracing::{debug, instrument, warn};
+use tokio::{
+    sync::{broadcast, mpsc},
+    time::{interval_at, sleep_until, Instant},
+};
+use tracing::{debug, info, instrument, warn};
 
-pub use self::traffic::Throughput;
+#[cfg(feature = "graphite_tls")]
+pub use self::graphite::GraphiteTls;
+pub use self::{
+    accounting::{Accounting, DailyTraffic, ACCOUNTING, DEFAULT_KEEP_DAYS},
+    clients::{ClientPermit, ClientStats, HandshakePermit, HandshakeStats},
+    connections::{ConnectionEntry, ConnectionInfo},
+    dedup::{ConnectDedup, DedupGuard, DedupKey, DedupOutcome, DedupTicket},
+    destinations::{destination_key, DestinationTraffic, TopDestinations, DESTINATIONS},
+    event_loop::{EventLoopCounters, EventLoopStats, Histogram, HistogramCounts, EVENT_LOOP},
+    events::ServerEvent,
+    liveness::{check_deadlocks, Liveness, LivenessSignal},
+    parallel::{AdaptiveParallel, AdaptiveParallelStats, ParallelKey},
+    reload::{ConfigHash, ReloadHistory, ReloadRecord, ServerListDiff, SkippedSection},
+    sources::{Offender, SourcePermit, SourceStats},
+    tasks::{TaskGuard, TaskInfo, TaskKind, TaskStats},
+    traffic::Throughput,
+};
 use self::{
+    auto_caps::AutoCaps,
+    clients::{ClientCounter, HandshakeCounter},
+    connections::ConnectionRegistry,
+    events::EventBus,
     graphite::{Graphite, Record},
-    traffic::Meter,
+    health::HealthWatch,
+    probe_capture::ProbeCapture,
+    schedule::ProbeSchedule,
+    sources::SourceCounter,
+    suspend::SuspendDetector,
+    tasks::TaskRegistry,
+    traffic::{Meter, DEFAULT_HALF_LIFE},
 };
 #[cfg(all(feature = "systemd", target_os = "linux"))]
 use crate::linux::systemd;
-use crate::proxy::ProxyServer;
+#[cfg(feature = "score_script")]
+use crate::policy::RequestFeatures;
+use crate::{
+    duration::DurationExt,
+    policy::{dns::PolicyDns, parser::AutoCapRule},
+    proxy::ProxyServer,
+};
 
-static THROUGHPUT_INTERVAL_SECS: u64 = 1;
+/// Default interval of throughput sampling.
+pub const DEFAULT_THROUGHPUT_INTERVAL: Duration = Duration::from_secs(1);
+/// Interval of probes waiting for the network after resumed from suspend.
+const RESUME_RETRY: Duration = Duration::from_secs(2);
+#[cfg(feature = "score_script")]
+const LUA_PICK_SERVER_TIMEOUT: Duration = Duration::from_millis(50);
 
 pub type ServerList = Vec<Arc<ProxyServer>>;
 
 #[derive(Clone)]
 pub struct Monitor {
-    servers: Arc<Mutex<ServerList>>,
+    servers: Arc<ServerListCell>,
+    by_tag: Arc<RwLock<HashMap<SharedStr, Arc<ProxyServer>>>>,
     meters: Arc<Mutex<HashMap<Arc<ProxyServer>, Meter>>>,
     graphite: Option<SocketAddr>,
+    #[cfg(feature = "graphite_tls")]
+    graphite_tls: Option<GraphiteTls>,
+    on_demand: Option<Arc<ProbeOnDemand>>,
+    health: Option<Arc<HealthWatch>>,
+    reloads: Arc<Mutex<ReloadHistory>>,
+    config_hashes: Arc<Mutex<Vec<ConfigHash>>>,
+    auto_caps: Arc<Mutex<AutoCaps>>,
+    throughput_started: Arc<AtomicBool>,
+    throughput_interval: Duration,
+    throughput_half_life: Duration,
+    clients: Arc<ClientCounter>,
+    handshakes: Arc<HandshakeCounter>,
+    sources: Arc<SourceCounter>,
+    connections: Arc<ConnectionRegistry>,
+    tasks: Arc<TaskRegistry>,
+    liveness: Arc<Liveness>,
+    direct: Option<Arc<ProxyServer>>,
+    events: Arc<EventBus>,
+    probe_log_changes_only: bool,
+    probe_capture: Option<Arc<ProbeCapture>>,
+    policy_dns: Option<Arc<PolicyDns>>,
+    connect_dedup: Option<Arc<ConnectDedup>>,
+    adaptive_parallel: Option<Arc<AdaptiveParallel>>,
     #[cfg(feature = "score_script")]
     lua: Option<Arc<Mutex<Lua>>>,
+    #[cfg(feature = "score_script")]
+    lua_pick_server: bool,
+    #[cfg(feature = "score_script")]
+    lua_deadline: Arc<Mutex<Option<std::time::Instant>>>,
+}
+
+/// Probe requests from the connecting path, de-duplicated by `pending`.
+struct ProbeOnDemand {
+    stale_after: Duration,
+    pending: Mutex<HashSet<Arc<ProxyServer>>>,
+    sender: mpsc::UnboundedSender<Arc<ProxyServer>>,
+    receiver: Mutex<Option<mpsc::UnboundedReceiver<Arc<ProxyServer>>>>,
 }
 
 impl Monitor {
     pub fn new(servers: Vec<Arc<ProxyServer>>, graphite: Option<SocketAddr>) -> Monitor {
         let meters = servers
             .iter()
-            .map(|server| (server.clone(), Meter::new()))
+            .map(|server| (server.clone(), Meter::new(DEFAULT_HALF_LIFE)))
             .collect();
         #[cfg(all(feature = "systemd", target_os = "linux"))]
         systemd::set_status(
@@ -59,12 +158,349 @@ impl Monitor {
             )
             .into(),
         );
+        let by_tag = index_by_tag(&servers);
         Monitor {
-            servers: Arc::new(Mutex::new(servers)),
+            servers: Arc::new(ServerListCell::new(servers)),
+            by_tag: Arc::new(RwLock::new(by_tag)),
             meters: Arc::new(Mutex::new(meters)),
             graphite,
+            #[cfg(feature = "graphite_tls")]
+            graphite_tls: None,
+            on_demand: None,
+            health: None,
+            reloads: Default::default(),
+            config_hashes: Default::default(),
+            auto_caps: Default::default(),
+            throughput_started: Default::default(),
+            throughput_interval: DEFAULT_THROUGHPUT_INTERVAL,
+            throughput_half_life: DEFAULT_HALF_LIFE,
+            clients: Default::default(),
+            handshakes: Default::default(),
+            sources: Default::default(),
+            connections: Default::default(),
+            tasks: Default::default(),
+            liveness: Arc::new(Liveness::new(std::time::Instant::now())),
+            direct: None,
+            events: Default::default(),
+            probe_log_changes_only: false,
+            probe_capture: None,
+            policy_dns: None,
+            connect_dedup: None,
+            adaptive_parallel: None,
             #[cfg(feature = "score_script")]
             lua: None,
+            #[cfg(feature = "score_script")]
+            lua_pick_server: false,
+            #[cfg(feature = "score_script")]
+            lua_deadline: Default::default(),
+        }
+    }
+
+    /// Probe a server right before it's used as the primary candidate,
+    /// if its last probe is older than `stale_after`.
+    /// Should start `monitor_on_demand()` task after call this.
+    pub fn enable_probe_on_demand(&mut self, stale_after: Duration) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.on_demand.replace(Arc::new(ProbeOnDemand {
+            stale_after,
+            pending: Default::default(),
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+        }));
+    }
+
+    /// Request probing if the primary candidate is stale, then move the
+    /// first freshly probed candidate (if any) ahead of it.
+    /// Never wait for the probe.
+    pub fn probe_on_demand(&self, candidates: &mut [Arc<ProxyServer>]) {
+        let on_demand = match &self.on_demand {
+            Some(on_demand) => on_demand,
+            None => return,
+        };
+        let is_stale = |server: &ProxyServer| match server.last_probe_at() {
+            Some(t) => t.elapsed() > on_demand.stale_after,
+            None => true,
+        };
+        match candidates.first() {
+            Some(primary) if is_stale(primary) => {
+                if on_demand.pending.lock().insert(primary.clone()) {
+                    debug!(proxy = %primary.tag(), "request probe on demand");
+                    // Receiver is dropped only if no task is running
+                    let _ = on_demand.sender.send(primary.clone());
+                }
+            }
+            _ => return,
+        }
+        if let Some(n) = candidates.iter().position(|s| !is_stale(s)) {
+            candidates[..=n].rotate_right(1);
+        }
+    }
+
+    /// Enter the degraded state after a probe round if less than
+    /// `min_healthy` servers have a score.
+    pub fn set_min_healthy(&mut self, min_healthy: usize) {
+        self.health.replace(Arc::new(HealthWatch::new(min_healthy)));
+    }
+
+    /// Return true if `set_min_healthy()` is called and there are not
+    /// enough healthy servers on recent probe rounds.
+    pub fn is_degraded(&self) -> bool {
+        self.health.as_ref().is_some_and(|h| h.is_degraded())
+    }
+
+    /// Limit the number of clients being handled at the same time,
+    /// see `client_permit()`.
+    pub fn set_max_clients(&mut self, max: usize) {
+        self.clients = Arc::new(ClientCounter::new(Some(max)));
+    }
+
+    /// Limit the number of clients still handshaking (i.e. not yet piped)
+    /// at the same time, see `handshake_permit()`.
+    pub fn set_max_pending_handshakes(&mut self, max: usize) {
+        self.handshakes = Arc::new(HandshakeCounter::new(Some(max)));
+    }
+
+    /// Log probe results only on changes of server up/down state or score
+    /// band, plus servers still down every hour.
+    pub fn set_probe_log_changes_only(&mut self) {
+        self.probe_log_changes_only = true;
+    }
+
+    /// Capture probes of `rate` of probe rounds into `dir`, see
+    /// `ProbeCapture`.
+    pub fn set_probe_capture(&mut self, dir: PathBuf, rate: f64, max_bytes: u64) {
+        let capture = ProbeCapture::new(dir, rate, max_bytes);
+        self.probe_capture = Some(Arc::new(capture));
+    }
+
+    /// Resolve domain names of clients for policy rules on `dst ip`.
+    pub fn set_policy_dns(&mut self, dns: PolicyDns) {
+        self.policy_dns = Some(Arc::new(dns));
+    }
+
+    pub fn policy_dns(&self) -> Option<&Arc<PolicyDns>> {
+        self.policy_dns.as_ref()
+    }
+
+    /// Delay identical connections arriving in `window` after an
+    /// in-flight one, see `ConnectDedup`.
+    pub fn set_dedup_window(&mut self, window: Duration) {
+        self.connect_dedup = Some(Arc::new(ConnectDedup::new(window)));
+    }
+
+    pub fn connect_dedup(&self) -> Option<&Arc<ConnectDedup>> {
+        self.connect_dedup.as_ref()
+    }
+
+    /// Choose `n_parallel` up to `max` by destination history, see
+    /// `AdaptiveParallel`.
+    pub fn set_adaptive_parallel(&mut self, max: usize) {
+        self.adaptive_parallel = Some(Arc::new(AdaptiveParallel::new(max)));
+    }
+
+    pub fn adaptive_parallel(&self) -> Option<&Arc<AdaptiveParallel>> {
+        self.adaptive_parallel.as_ref()
+    }
+
+    /// Pseudo server of direct connections, for stats only.
+    pub fn set_direct_server(&mut self, server: Arc<ProxyServer>) {
+        self.direct = Some(server);
+    }
+
+    pub fn direct_server(&self) -> Option<Arc<ProxyServer>> {
+        self.direct.clone()
+    }
+
+    /// Return a permit counting the client as alive until it's dropped,
+    /// or `None` if the limit of `set_max_clients()` is reached.
+    pub fn client_permit(&self) -> Option<ClientPermit> {
+        self.clients.acquire()
+    }
+
+    pub fn client_stats(&self) -> ClientStats {
+        self.clients.snapshot()
+    }
+
+    /// Return a permit counting the client as connected from `ip` until
+    /// dropped, or `None` (recorded as an offender) if there are `limit`
+    /// clients from it already.
+    pub fn source_permit(&self, ip: IpAddr, limit: usize) -> Option<SourcePermit> {
+        self.sources.acquire(ip, limit)
+    }
+
+    /// Source addresses refused by `source_permit()` the most, up to `limit`.
+    pub fn offenders(&self, limit: usize) -> Vec<Offender> {
+        self.sources.offenders(limit)
+    }
+
+    pub fn source_stats(&self) -> SourceStats {
+        self.sources.snapshot()
+    }
+
+    /// Return a permit counting the client as handshaking until dropped,
+    /// or `None` (counted as shed) if the limit of
+    /// `set_max_pending_handshakes()` is reached.
+    pub fn handshake_permit(&self) -> Option<HandshakePermit> {
+        self.handshakes.acquire()
+    }
+
+    /// Count a client timed out before its handshake done.
+    pub fn add_handshake_timeout(&self) {
+        self.handshakes.add_timeout();
+    }
+
+    /// Count a connection closed silently as a health check.
+    pub fn add_health_check(&self) {
+        self.handshakes.add_health_check();
+    }
+
+    pub fn handshake_stats(&self) -> HandshakeStats {
+        self.handshakes.snapshot()
+    }
+
+    /// Keep the connection findable by `connections_by_source_port()`
+    /// until the returned entry is dropped. Return `None` if too many
+    /// connections registered.
+    pub fn register_connection(
+        &self,
+        client: SocketAddr,
+        server: SharedStr,
+        dest: String,
+    ) -> Option<ConnectionEntry> {
+        self.connections.register(client, server, dest)
+    }
+
+    pub fn connections_by_source_port(&self, port: u16) -> Vec<ConnectionInfo> {
+        self.connections.by_source_port(port)
+    }
+
+    /// Progress of the accept & probe loops, for the systemd watchdog.
+    pub fn liveness(&self) -> &Arc<Liveness> {
+        &self.liveness
+    }
+
+    /// Count the task as alive until the returned guard is dropped. Client
+    /// ones are also listed by `oldest_tasks()` if not too many.
+    pub fn track_task(&self, kind: TaskKind) -> TaskGuard {
+        self.tasks.track(kind)
+    }
+
+    pub fn task_stats(&self) -> TaskStats {
+        self.tasks.snapshot()
+    }
+
+    /// Return up to `limit` client tasks alive, oldest first.
+    pub fn oldest_tasks(&self, limit: usize) -> Vec<TaskInfo> {
+        self.tasks.oldest_clients(limit)
+    }
+
+    /// Return the number of alive connections to all servers, including
+    /// direct ones.
+    pub fn alive_connections(&self) -> usize {
+        self.servers()
+            .iter()
+            .chain(&self.direct)
+            .map(|s| s.status_snapshot().conn_alive as usize)
+            .sum()
+    }
+
+    /// Log a warning and return true if client tasks are more than
+    /// `factor` times of alive connections, indicating leaked tasks.
+    pub fn check_task_leak(&self, factor: f64) -> bool {
+        let tasks = self.tasks.snapshot().client;
+        let connections = self.alive_connections();
+        if !tasks::suspect_leak(tasks, connections, factor) {
+            return false;
+        }
+        let oldest = self.oldest_tasks(1);
+        warn!(
+            tasks,
+            connections,
+            oldest_since = oldest.first().map(|t| t.since),
+            "client tasks much more than connections, maybe leaked"
+        );
+        true
+    }
+
+    /// Run `check_task_leak()` every `interval` forever.
+    pub async fn monitor_task_leak(self, factor: f64, interval: Duration) {
+        let mut interval = interval_at(Instant::now() + interval, interval);
+        loop {
+            interval.tick().await;
+            self.check_task_leak(factor);
+        }
+    }
+
+    /// Return the number of servers with a score.
+    pub fn healthy_servers(&self) -> usize {
+        self.servers()
+            .iter()
+            .filter(|s| s.score().is_some())
+            .count()
+    }
+
+    fn check_health(&self) {
+        let health = match &self.health {
+            Some(health) => health,
+            None => return,
+        };
+        let healthy = self.healthy_servers();
+        let _was_degraded = health.is_degraded();
+        let _degraded = health.update(healthy);
+        #[cfg(all(feature = "systemd", target_os = "linux"))]
+        if _degraded {
+            systemd::set_status(
+                format!(
+                    "degraded ({}/{} upstream healthy, {} required)",
+                    healthy,
+                    self.servers().len(),
+                    health.min_healthy()
+                )
+                .into(),
+            );
+        } else if _was_degraded {
+            systemd::set_status(
+                format!(
+                    "serving ({}/{} upstream healthy)",
+                    healthy,
+                    self.servers().len()
+                )
+                .into(),
+            );
+        }
+    }
+
+    /// Set `auto capability` rules, evaluated after each probe round.
+    /// Capabilities no longer in any rule are removed on the next round.
+    pub fn set_auto_capabilities(&self, rules: Vec<AutoCapRule>) {
+        self.auto_caps.lock().set_rules(rules);
+    }
+
+    fn update_auto_caps(&self) {
+        self.auto_caps.lock().update(&self.servers());
+    }
+
+    /// Start serving probe-on-demand requests.
+    /// Returned Future won't return unless `enable_probe_on_demand()` is
+    /// not called or this is called twice.
+    #[instrument(skip_all)]
+    pub async fn monitor_on_demand(self) {
+        let on_demand = match &self.on_demand {
+            Some(on_demand) => on_demand.clone(),
+            None => return,
+        };
+        let mut receiver = match on_demand.receiver.lock().take() {
+            Some(receiver) => receiver,
+            None => return,
+        };
+        while let Some(server) = receiver.recv().await {
+            let monitor = self.clone();
+            let on_demand = on_demand.clone();
+            tokio::spawn(async move {
+                alive_test::test_one(&monitor, &server, None).await;
+                on_demand.pending.lock().remove(&server);
+                monitor.resort();
+            });
         }
     }
 
@@ -76,7 +512,19 @@ impl Monitor {
         File::open(path)?.take(2u64.pow(26)).read_to_end(&mut buf)?;
 
         let lua = Lua::new();
-        lua.context(|ctx| -> anyhow::Result<()> {
+        // Abort any Lua call that run over its deadline
+        let deadline = self.lua_deadline.clone();
+        let triggers = LuaHookTriggers {
+            every_nth_instruction: Some(10_000),
+            ..Default::default()
+        };
+        lua.set_hook(triggers, move |_, _| match *deadline.lock() {
+            Some(t) if t < std::time::Instant::now() => {
+                Err(LuaError::RuntimeError("deadline exceeded".into()))
+            }
+            _ => Ok(()),
+        });
+        let pick_server = lua.context(|ctx| -> anyhow::Result<bool> {
             let globals = ctx.globals();
             ctx.load(&buf).exec().context("failed to load Lua script")?;
             if !globals.contains_key("calc_score")? {
@@ -88,21 +536,108 @@ impl Monitor {
                 }
                 other => other,
             }?;
-            Ok(())
+            if !globals.contains_key("pick_server")? {
+                return Ok(false);
+            }
+            let _: LuaFunction = match globals.get("pick_server") {
+                Err(LuaError::FromLuaConversionError { .. }) => {
+                    bail!("pick_server is not a function");
+                }
+                other => other,
+            }?;
+            Ok(true)
         })?;
 
         self.lua.replace(Arc::new(Mutex::new(lua)));
+        self.lua_pick_server = pick_server;
         Ok(())
     }
 
-    /// Return an ordered list of servers.
-    pub fn servers(&self) -> ServerList {
-        self.servers.lock().clone()
+    /// Reorder `candidates` with `pick_server()` from Lua script, if it's
+    /// defined. Keep the order as is on any error, or if the script is
+    /// busy (e.g. scoring), never blocking the caller waiting for it.
+    #[cfg(feature = "score_script")]
+    pub fn pick_server<S>(
+        &self,
+        features: &RequestFeatures<S>,
+        candidates: &mut Vec<Arc<ProxyServer>>,
+    ) where
+        S: AsRef<str>,
+    {
+        let lua = match &self.lua {
+            Some(lua) if self.lua_pick_server && !candidates.is_empty() => lua,
+            _ => return,
+        };
+        let result = {
+            let lua = match lua.try_lock() {
+                Some(lua) => lua,
+                None => {
+                    debug!("Lua script busy, skip pick_server()");
+                    return;
+                }
+            };
+            *self.lua_deadline.lock() = Some(std::time::Instant::now() + LUA_PICK_SERVER_TIMEOUT);
+            let result = lua.context(|ctx| -> LuaResult<Option<Vec<String>>> {
+                let func: LuaFunction = ctx.globals().get("pick_server")?;
+                let request = ctx.create_table()?;
+                request.set("port", features.listen_port)?;
+                request.set("ip", features.dst_ip.map(|ip| ip.to_string()))?;
+                request.set("domain", features.dst_domain.as_ref().map(|d| d.as_ref()))?;
+                let tags =
+                    ctx.create_sequence_from(candidates.iter().map(|s| s.tag().to_string()))?;
+                match func.call((request, tags))? {
+                    LuaValue::Nil => Ok(None),
+                    LuaValue::String(tag) => Ok(Some(vec![tag.to_str()?.to_string()])),
+                    value @ LuaValue::Table(_) => Ok(Some(Vec::from_lua(value, ctx)?)),
+                    _ => Err(LuaError::RuntimeError(
+                        "return neither tag(s) nor nil".into(),
+                    )),
+                }
+            });
+            *self.lua_deadline.lock() = None;
+            result
+        };
+        let tags = match result {
+            Ok(Some(tags)) => tags,
+            Ok(None) => return,
+            Err(err) => {
+                warn!("fail to pick server w/ Lua script: {}", err);
+                return;
+            }
+        };
+        // Move picked servers to front, keep others followed
+        let mut rest = candidates.clone();
+        let mut picked = Vec::with_capacity(candidates.len());
+        for tag in tags {
+            match rest.iter().position(|s| s.tag() == tag) {
+                Some(n) => picked.push(rest.remove(n)),
+                None => {
+                    warn!("Lua pick_server() returned unknown tag \"{}\"", tag);
+                    return;
+                }
+            }
+        }
+        picked.append(&mut rest);
+        *candidates = picked;
+    }
+
+    /// Return a snapshot of the ordered list of servers.
+    /// The snapshot won't change even if servers are resorted later.
+    pub fn servers(&self) -> Arc<ServerList> {
+        self.servers.load()
+    }
+
+    /// Return the server with the tag. If multiple servers share the same
+    /// tag, the first one on the list loaded is returned.
+    pub fn server_by_tag(&self, tag: &str) -> Option<Arc<ProxyServer>> {
+        self.by_tag.read().get(tag).cloned()
     }
 
     /// Replace internal servers with provided list.
-    pub fn update_servers(&self, new_servers: Vec<Arc<ProxyServer>>) {
-        let oldset: HashSet<_> = self.servers().into_iter().collect();
+    /// Return the number of servers added and removed.
+    pub fn update_servers(&self, new_servers: Vec<Arc<ProxyServer>>) -> ServerListDiff {
+        let _writer = self.servers.writer.lock();
+        let oldset: HashSet<_> = self.servers().iter().cloned().collect();
         let newset = HashSet::from_iter(new_servers);
         let mut new_servers = Vec::with_capacity(newset.len());
 
@@ -112,45 +647,166 @@ impl Monitor {
             let old = oldset.get(server).unwrap();
             let new = newset.get(server).unwrap();
             old.copy_config_from(new);
+            old.set_auth_failed(false, "reloaded");
             new_servers.push(old.clone());
         }
 
+        // Servers with the same address & protocol but a different tag are
+        // renamed rather than replaced, keeping their status & traffic.
+        let mut removed: Vec<_> = oldset.difference(&newset).cloned().collect();
+        let mut added = Vec::new();
+        let mut renamed = 0;
+        for new in newset.difference(&oldset) {
+            let same = |old: &Arc<ProxyServer>| old.addr == new.addr && old.proto == new.proto;
+            match removed.iter().position(same) {
+                Some(i) => {
+                    let old = removed.swap_remove(i);
+                    debug!("server {} renamed to {}", old.tag(), new.tag());
+                    old.copy_config_from(new);
+                    old.set_auth_failed(false, "reloaded");
+                    new_servers.push(old);
+                    renamed += 1;
+                }
+                None => added.push(new.clone()),
+            }
+        }
+
         // Add brand new server objects
-        new_servers.extend(newset.difference(&oldset).cloned());
+        let diff = ServerListDiff {
+            added: added.len(),
+            removed: removed.len(),
+            renamed,
+        };
+        new_servers.extend(added);
 
-        // Create new meters
+        // Keep meters of retained servers, create new ones for others
         let mut meters = self.meters.lock();
-        meters.clear();
+        let mut old_meters = std::mem::take(&mut *meters);
         for server in new_servers.iter() {
-            meters.insert(server.clone(), Meter::new());
+            let meter = old_meters
+                .remove(server)
+                .unwrap_or_else(|| Meter::new(self.throughput_half_life));
+            meters.insert(server.clone(), meter);
         }
 
-        *self.servers.lock() = new_servers;
-        self.resort();
+        drop(meters);
+        *self.by_tag.write() = index_by_tag(&new_servers);
+        self.sort_and_store(new_servers);
+        self.events.emit(ServerEvent::ServersReloaded {
+            added: diff.added,
+            removed: diff.removed,
+        });
+        diff
+    }
+
+    /// Subscribe to server state changes. Events are dropped for lagged
+    /// receivers, see `tokio::sync::broadcast`.
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Set sections skipped in the server list just loaded, recorded
+    /// with the next `reload_succeeded()`.
+    pub fn set_skipped_sections(&self, skipped: Vec<SkippedSection>) {
+        self.reloads.lock().skipped = skipped;
+    }
+
+    /// Record a successful reload and increase the config generation.
+    pub fn reload_succeeded(&self, diff: ServerListDiff, rules_delta: isize, duration: Duration) {
+        self.reloads.lock().succeeded(diff, rules_delta, duration);
+    }
+
+    /// Record a failed reload, the config generation is unchanged.
+    pub fn reload_failed(&self, error: String, duration: Duration) {
+        self.reloads.lock().failed(error, duration);
+    }
+
+    pub fn reload_history(&self) -> ReloadHistory {
+        self.reloads.lock().clone()
+    }
+
+    /// Replace checksums of the config currently loaded.
+    pub fn set_config_hashes(&self, hashes: Vec<ConfigHash>) {
+        *self.config_hashes.lock() = hashes;
+    }
+
+    pub fn config_hashes(&self) -> Vec<ConfigHash> {
+        self.config_hashes.lock().clone()
     }
 
     fn resort(&self) {
+        let _writer = self.servers.writer.lock();
+        self.sort_and_store(self.servers().to_vec());
+    }
+
+    /// Sort then publish `servers`. Caller must hold the writer lock.
+    fn sort_and_store(&self, mut servers: ServerList) {
         let mut rng = rand::thread_rng();
-        let mut servers = self.servers.lock();
         servers.sort_by_key(move |server| {
-            server.score().unwrap_or(std::i32::MAX) - (rng.gen::<u8>() % 30) as i32
+            server.candidate_score().unwrap_or(i32::MAX) - (rng.gen::<u8>() % 30) as i32
         });
         debug!("scores:{}", info_stats(&servers));
+        self.events.check_best(&servers);
+        self.servers.store(servers);
+    }
+
+    /// Connect the Graphite server with TLS.
+    #[cfg(feature = "graphite_tls")]
+    pub fn set_graphite_tls(&mut self, tls: GraphiteTls) {
+        self.graphite_tls = Some(tls);
+    }
+
+    fn new_graphite(&self, addr: SocketAddr) -> Graphite {
+        #[cfg(feature = "graphite_tls")]
+        if let Some(tls) = &self.graphite_tls {
+            return Graphite::with_tls(addr, tls.clone());
+        }
+        Graphite::new(addr)
     }
 
     /// Start monitoring delays.
     /// Returned Future won't return unless error on timer.
     #[instrument(skip_all)]
-    pub async fn monitor_delay(self, probe: u64) {
-        let mut graphite = self.graphite.map(Graphite::new);
-        let interval = Duration::from_secs(probe);
+    pub async fn monitor_delay(self, probe: Duration) {
+        let mut graphite = self.graphite.map(|addr| self.new_graphite(addr));
+        let mut schedule = ProbeSchedule::new(probe);
 
-        alive_test::test_all(&self).await;
+        let now = Instant::now();
+        alive_test::test_all(&self, schedule.take_due(&self.servers(), now)).await;
+        self.check_health();
+        self.update_auto_caps();
+        self.liveness.beat(LivenessSignal::Probe);
 
-        let mut interval = interval_at(Instant::now() + interval, interval);
+        let mut next_round = schedule.next_round(now);
+        let mut summarized_at = Instant::now();
+        let mut suspend = SuspendDetector::new(probe);
         loop {
-            interval.tick().await;
-            alive_test::test_all(&self).await;
+            suspend.arm(Instant::now(), suspend::boot_time());
+            sleep_until(next_round).await;
+            let now = Instant::now();
+            if let Some(late) = suspend.check(next_round, now, suspend::boot_time()) {
+                warn!(
+                    "probe round {} late, assume resumed from suspend",
+                    late.format()
+                );
+                self.probe_after_resume(&mut schedule, probe).await;
+                next_round = schedule.next_round(Instant::now());
+                continue;
+            }
+            EVENT_LOOP.add_probe_lag(now.saturating_duration_since(next_round));
+            next_round = schedule.next_round(now);
+            let due = schedule.take_due(&self.servers(), now);
+            alive_test::test_all(&self, due).await;
+            self.check_health();
+            self.update_auto_caps();
+            self.liveness.beat(LivenessSignal::Probe);
+            if self.probe_log_changes_only && summarized_at.elapsed() >= probe_log::SUMMARY_INTERVAL
+            {
+                summarized_at = Instant::now();
+                if let Some(summary) = probe_log::down_summary(&self.servers()) {
+                    info!("{}", summary);
+                }
+            }
             if let Some(ref mut graphite) = graphite {
                 match send_metrics(&self, graphite).await {
                     Ok(_) => debug!("metrics sent"),
@@ -160,10 +816,60 @@ impl Monitor {
         }
     }
 
+    /// Invalidate stale scores, wait for the network back up to `timeout`,
+    /// then probe all servers in a fresh round.
+    async fn probe_after_resume(&self, schedule: &mut ProbeSchedule, timeout: Duration) {
+        let servers = self.servers();
+        for server in servers.iter() {
+            server.invalidate_score();
+        }
+        self.resort();
+        if self.wait_for_network(&servers, timeout).await {
+            info!("network is up after resume, probe all servers");
+        } else {
+            info!("network is still down after resume, probe all servers anyway");
+        }
+        schedule.reset();
+        let due = schedule.take_due(&servers, Instant::now());
+        alive_test::test_all_after_resume(self, due).await;
+        self.check_health();
+        self.update_auto_caps();
+        self.liveness.beat(LivenessSignal::Probe);
+    }
+
+    /// Probe `servers` one by one, until one of them passed or connected
+    /// for clients. Return false if none did in `timeout`.
+    async fn wait_for_network(&self, servers: &[Arc<ProxyServer>], timeout: Duration) -> bool {
+        let connected = || -> u64 {
+            servers
+                .iter()
+                .map(|s| s.status_snapshot().conn_total as u64)
+                .sum()
+        };
+        let before = connected();
+        let deadline = Instant::now() + timeout;
+        for server in servers.iter().cycle() {
+            if alive_test::test_one_with(self, server, None, true).await || connected() != before {
+                return true;
+            }
+            self.liveness.beat(LivenessSignal::Probe);
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            sleep_until(deadline.min(now + RESUME_RETRY)).await;
+        }
+        false
+    }
+
     /// Start monitoring throughput.
-    /// Returned Future won't return unless error on timer.
+    /// Returned Future won't return unless error on timer or this is
+    /// called twice.
     pub async fn monitor_throughput(self) {
-        let interval = Duration::from_secs(THROUGHPUT_INTERVAL_SECS);
+        if self.throughput_started.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let interval = self.throughput_interval;
         let mut interval = interval_at(Instant::now() + interval, interval);
         loop {
             interval.tick().await;
@@ -173,8 +879,19 @@ impl Monitor {
         }
     }
 
+    /// Sample traffic every `interval` for throughput, and smooth it with
+    /// `half_life`. Should be called before `monitor_throughput()`.
+    pub fn set_throughput_meter(&mut self, interval: Duration, half_life: Duration) {
+        self.throughput_interval = interval;
+        self.throughput_half_life = half_life;
+        for meter in self.meters.lock().values_mut() {
+            *meter = Meter::new(half_life);
+        }
+    }
+
     /// Return average throughputs of all servers in the recent monitor
-    /// period. Should start `monitor_throughput()` task before call this.
+    /// period, along with the smoothed ones.
+    /// Should start `monitor_throughput()` task before call this.
     pub fn throughputs(&self) -> HashMap<Arc<ProxyServer>, Throughput> {
         self.meters
             .lock()
@@ -184,12 +901,49 @@ impl Monitor {
     }
 }
 
+/// Current server list, published as immutable snapshots.
+///
+/// Readers only clone the `Arc` (the read lock is held for a pointer copy),
+/// writers build new lists on their own and serialize with `writer`, so
+/// sorting never blocks the accept path.
+struct ServerListCell {
+    current: RwLock<Arc<ServerList>>,
+    writer: Mutex<()>,
+}
+
+impl ServerListCell {
+    fn new(servers: ServerList) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(servers)),
+            writer: Default::default(),
+        }
+    }
+
+    fn load(&self) -> Arc<ServerList> {
+        self.current.read().clone()
+    }
+
+    fn store(&self, servers: ServerList) {
+        let old = std::mem::replace(&mut *self.current.write(), Arc::new(servers));
+        // Drop the old snapshot after releasing the lock
+        drop(old);
+    }
+}
+
+fn index_by_tag(servers: &[Arc<ProxyServer>]) -> HashMap<SharedStr, Arc<ProxyServer>> {
+    let mut index = HashMap::with_capacity(servers.len());
+    for server in servers {
+        index.entry(server.tag()).or_insert_with(|| server.clone());
+    }
+    index
+}
+
 fn info_stats(infos: &[Arc<ProxyServer>]) -> String {
     let mut stats = String::new();
     for info in infos.iter().take(5) {
         stats += &match info.score() {
-            None => format!(" {}: --,", info.tag),
-            Some(t) => format!(" {}/{},", info.tag, t),
+            None => format!(" {}: --,", info.tag()),
+            Some(t) => format!(" {}/{},", info.tag(), t),
         };
     }
     stats.pop();
@@ -218,6 +972,305 @@ async fn send_metrics(monitor: &Monitor, graphite: &mut Graphite) -> io::Result<
             ]
         })
         .flatten()
+        .filter_map(|record| match record {
+            Ok(record) => Some(record),
+            Err(err) => {
+                warn!("skip metric record: {}", err);
+                None
+            }
+        })
         .collect(); // FIXME: avoid allocate large memory
     graphite.write_records(records).await
 }
+
+#[test]
+fn test_servers_snapshot_concurrent_update() {
+    let server = |port: u16| {
+        Arc::new(ProxyServer::test_socks5(
+            ([127, 0, 0, 1], port).into(),
+            None,
+        ))
+    };
+    let list_a: ServerList = (1..=8).map(server).collect();
+    let list_b: ServerList = (11..=14).map(server).collect();
+    let monitor = Monitor::new(list_a.clone(), None);
+
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                for _ in 0..10_000 {
+                    let servers = monitor.servers();
+                    assert!(servers.len() == 8 || servers.len() == 4);
+                    let ports: HashSet<_> = servers.iter().map(|s| s.addr.port()).collect();
+                    assert_eq!(servers.len(), ports.len());
+                }
+            });
+        }
+        scope.spawn(|| {
+            for i in 0..500 {
+                let list = if i % 2 == 0 { &list_b } else { &list_a };
+                monitor.update_servers(list.clone());
+                monitor.resort();
+            }
+        });
+    });
+    assert_eq!(8, monitor.servers().len());
+}
+
+#[test]
+fn test_server_by_tag() {
+    let server = |port: u16, tag: &str| {
+        Arc::new(ProxyServer::test_socks5(
+            ([127, 0, 0, 1], port).into(),
+            Some(tag),
+        ))
+    };
+    let monitor = Monitor::new(vec![server(1, "a"), server(2, "b")], None);
+    assert_eq!(1, monitor.server_by_tag("a").unwrap().addr.port());
+    assert!(monitor.server_by_tag("c").is_none());
+
+    let diff = monitor.update_servers(vec![server(2, "b"), server(3, "c")]);
+    assert_eq!(
+        ServerListDiff {
+            added: 1,
+            removed: 1,
+            renamed: 0,
+        },
+        diff
+    );
+    assert!(monitor.server_by_tag("a").is_none());
+    assert_eq!(3, monitor.server_by_tag("c").unwrap().addr.port());
+}
+
+#[test]
+fn test_rename_server() {
+    use crate::proxy::Traffic;
+
+    let server = |tag: &str| {
+        Arc::new(ProxyServer::test_socks5(
+            ([127, 0, 0, 1], 1).into(),
+            Some(tag),
+        ))
+    };
+    let old = server("old");
+    old.update_delay(Some(Duration::from_millis(100)));
+    old.add_traffic(Traffic {
+        tx_bytes: 1,
+        rx_bytes: 2,
+    });
+    let score = old.score();
+    assert!(score.is_some());
+    let monitor = Monitor::new(vec![old.clone()], None);
+
+    let diff = monitor.update_servers(vec![server("new")]);
+    assert_eq!(
+        ServerListDiff {
+            added: 0,
+            removed: 0,
+            renamed: 1,
+        },
+        diff
+    );
+    let renamed = monitor.server_by_tag("new").unwrap();
+    assert!(Arc::ptr_eq(&old, &renamed));
+    assert_eq!("new", renamed.tag().as_str());
+    assert_eq!(score, renamed.score());
+    assert_eq!(
+        Traffic {
+            tx_bytes: 1,
+            rx_bytes: 2
+        },
+        renamed.traffic()
+    );
+    assert!(monitor.server_by_tag("old").is_none());
+    assert!(monitor.throughputs().contains_key(&renamed));
+}
+
+#[test]
+fn test_probe_on_demand() {
+    let server = |port: u16| {
+        Arc::new(ProxyServer::test_socks5(
+            ([127, 0, 0, 1], port).into(),
+            None,
+        ))
+    };
+    let (stale, fresh) = (server(1), server(2));
+    fresh.update_delay(Some(Duration::from_millis(100)));
+    let mut monitor = Monitor::new(vec![stale.clone(), fresh.clone()], None);
+    monitor.enable_probe_on_demand(Duration::from_secs(60));
+    let mut receiver = monitor
+        .on_demand
+        .as_ref()
+        .unwrap()
+        .receiver
+        .lock()
+        .take()
+        .unwrap();
+
+    // Probe requested once, fresh server used meanwhile
+    for _ in 0..3 {
+        let mut candidates = vec![stale.clone(), fresh.clone()];
+        monitor.probe_on_demand(&mut candidates);
+        assert_eq!(vec![fresh.clone(), stale.clone()], candidates);
+    }
+    assert_eq!(stale, receiver.try_recv().unwrap());
+    assert!(receiver.try_recv().is_err());
+
+    // No more probe once it's done
+    stale.update_delay(Some(Duration::from_millis(100)));
+    monitor
+        .on_demand
+        .as_ref()
+        .unwrap()
+        .pending
+        .lock()
+        .remove(&stale);
+    let mut candidates = vec![stale.clone(), fresh.clone()];
+    monitor.probe_on_demand(&mut candidates);
+    assert_eq!(vec![stale.clone(), fresh.clone()], candidates);
+    assert!(receiver.try_recv().is_err());
+}
+
+#[cfg(feature = "score_script")]
+#[test]
+fn test_lua_pick_server() {
+    let server = |port: u16, tag: &str| {
+        Arc::new(ProxyServer::test_socks5(
+            ([127, 0, 0, 1], port).into(),
+            Some(tag),
+        ))
+    };
+    let script = r#"
+        function calc_score(proxy, delay) return 0 end
+        function pick_server(req, tags)
+            if req.domain == "www.example.com" then
+                return "b"
+            elseif req.domain == "reverse.example.com" then
+                return {tags[3], tags[2], tags[1]}
+            elseif req.domain == "unknown.example.com" then
+                return "x"
+            elseif req.domain == "loop.example.com" then
+                while true do end
+            end
+        end
+    "#;
+    let path = std::env::temp_dir().join(format!("moproxy-test-{}.lua", std::process::id()));
+    std::fs::write(&path, script).unwrap();
+    let servers = vec![server(1, "a"), server(2, "b"), server(3, "c")];
+    let mut monitor = Monitor::new(servers.clone(), None);
+    monitor.load_score_script(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let pick = |domain: &str| {
+        let features = RequestFeatures {
+            listen_port: Some(1080),
+            dst_ip: None,
+            dst_domain: Some(domain),
+        };
+        let mut candidates = servers.clone();
+        monitor.pick_server(&features, &mut candidates);
+        candidates
+            .iter()
+            .map(|s| s.tag().to_string())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(["b", "a", "c"], pick("www.example.com")[..]);
+    assert_eq!(["c", "b", "a"], pick("reverse.example.com")[..]);
+    assert_eq!(["a", "b", "c"], pick("other.example.com")[..]);
+    assert_eq!(["a", "b", "c"], pick("unknown.example.com")[..]);
+    assert_eq!(["a", "b", "c"], pick("loop.example.com")[..]);
+    // Still work after timed out
+    assert_eq!(["b", "a", "c"], pick("www.example.com")[..]);
+    // Not waiting for the script busy elsewhere
+    let busy = monitor.lua.as_ref().unwrap().lock();
+    assert_eq!(["a", "b", "c"], pick("www.example.com")[..]);
+    drop(busy);
+    assert_eq!(["b", "a", "c"], pick("www.example.com")[..]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_probe_after_resume() {
+    use crate::proxy::Delay;
+
+    // Nothing listening on it
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let server = ProxyServer::test_socks5(addr, None);
+    server.update_config(|c| c.max_wait = Duration::from_millis(300));
+    let server = Arc::new(server);
+    server.update_delay(Some(Duration::from_millis(100)));
+    server.update_stats_conn_open(false);
+    server.update_stats_conn_close(true);
+    let monitor = Monitor::new(vec![server.clone()], None);
+
+    // Network still down, waited for the timeout
+    let mut schedule = ProbeSchedule::new(Duration::from_secs(60));
+    let started = Instant::now();
+    monitor
+        .probe_after_resume(&mut schedule, Duration::from_secs(10))
+        .await;
+    assert!(started.elapsed() >= Duration::from_secs(10));
+    // Unknown rather than timed out, without penalties
+    let status = server.status_snapshot();
+    assert!(matches!(status.delay, Delay::Unknown));
+    assert_eq!((None, 0), (status.score, status.close_history));
+    let failures = status.connect_failures;
+    assert_eq!(0, failures.connect + failures.timed_out);
+
+    // Back once a client connected through it
+    let connecting = server.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        connecting.update_stats_conn_open(false);
+    });
+    let started = Instant::now();
+    let servers = monitor.servers();
+    assert!(
+        monitor
+            .wait_for_network(&servers, Duration::from_secs(60))
+            .await
+    );
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_secs(5) && elapsed < Duration::from_secs(60));
+}
+
+#[tokio::test]
+async fn test_task_leak_watchdog() {
+    let server = Arc::new(ProxyServer::test_socks5(
+        "127.0.0.1:1080".parse().unwrap(),
+        None,
+    ));
+    let monitor = Monitor::new(vec![server.clone()], None);
+    for _ in 0..10 {
+        server.update_stats_conn_open(false);
+    }
+    assert_eq!(10, monitor.alive_connections());
+
+    // Pipes stuck after their connections are counted as closed
+    let stuck: Vec<_> = (0..64)
+        .map(|i| {
+            let task = monitor.track_task(TaskKind::Client);
+            task.set_dest(format!("192.0.2.1:{}", i));
+            tokio::spawn(async move {
+                let _task = task;
+                std::future::pending::<()>().await
+            })
+        })
+        .collect();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(64, monitor.task_stats().client);
+    assert!(monitor.check_task_leak(4.0));
+    assert!(!monitor.check_task_leak(8.0));
+    let oldest = monitor.oldest_tasks(2);
+    assert_eq!(Some("192.0.2.1:0"), oldest[0].dest.as_deref());
+    assert_eq!(Some("192.0.2.1:1"), oldest[1].dest.as_deref());
+
+    for task in stuck {
+        task.abort();
+        assert!(task.await.unwrap_err().is_cancelled());
+    }
+    assert_eq!(0, monitor.task_stats().client);
+    assert!(!monitor.check_task_leak(4.0));
+}
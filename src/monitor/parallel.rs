@@ -0,0 +1,173 @@
+use flexstr::SharedStr;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Max number of destinations remembered. The least recently used one is
+/// forgotten beyond that.
+const MAX_DESTINATIONS: usize = 1024;
+
+/// Destination host and port of a connection.
+pub type ParallelKey = (SharedStr, u16);
+
+/// Choose `n_parallel` per destination for `--n-parallel auto`.
+///
+/// Destinations whose first-choice server succeeded in each of the last
+/// 8 connects (or never seen) connect to one server only,
+/// others race up to `max` servers until first choices succeed again.
+#[derive(Debug)]
+pub struct AdaptiveParallel {
+    max: usize,
+    history: Mutex<History>,
+    decisions: AtomicUsize,
+    escalations: AtomicUsize,
+}
+
+#[derive(Debug, Default)]
+struct History {
+    /// Bit set for each failed first choice, the latest at the lowest bit,
+    /// and the sequence number of the last use.
+    entries: HashMap<ParallelKey, (u8, u64)>,
+    /// Sequence number of the last use to destination, for eviction.
+    by_use: BTreeMap<u64, ParallelKey>,
+    next_seq: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct AdaptiveParallelStats {
+    pub max: usize,
+    /// Connects `n_parallel` chosen for.
+    pub decisions: usize,
+    /// Those chosen `max` for.
+    pub escalations: usize,
+    /// Destinations remembered, and those with recent failures.
+    pub destinations: usize,
+    pub escalated: usize,
+}
+
+impl History {
+    /// Mark `key` as the most recently used one, return its entry.
+    fn touch(&mut self, key: ParallelKey) -> &mut u8 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        if !self.entries.contains_key(&key) && self.entries.len() >= MAX_DESTINATIONS {
+            if let Some((_, oldest)) = self.by_use.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.by_use.insert(seq, key.clone());
+        let entry = self.entries.entry(key).or_insert((0, seq));
+        if entry.1 != seq {
+            self.by_use.remove(&std::mem::replace(&mut entry.1, seq));
+        }
+        &mut entry.0
+    }
+}
+
+impl AdaptiveParallel {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max: max.max(1),
+            history: Default::default(),
+            decisions: AtomicUsize::new(0),
+            escalations: AtomicUsize::new(0),
+        }
+    }
+
+    /// `n_parallel` to connect `key` with.
+    pub fn n_parallel(&self, key: &ParallelKey) -> usize {
+        let escalated = self
+            .history
+            .lock()
+            .entries
+            .get(key)
+            .is_some_and(|(failures, _)| *failures != 0);
+        self.decisions.fetch_add(1, Ordering::Relaxed);
+        if escalated {
+            self.escalations.fetch_add(1, Ordering::Relaxed);
+            self.max
+        } else {
+            1
+        }
+    }
+
+    /// Record whether the first-choice server connected `key` without
+    /// retries. Not if it failed to connect any servers, nor if it lost
+    /// the race to another one, which tells nothing about it.
+    pub fn record(&self, key: ParallelKey, first_choice: bool) {
+        let mut history = self.history.lock();
+        let failures = history.touch(key);
+        *failures = *failures << 1 | !first_choice as u8;
+    }
+
+    pub fn stats(&self) -> AdaptiveParallelStats {
+        let history = self.history.lock();
+        AdaptiveParallelStats {
+            max: self.max,
+            decisions: self.decisions.load(Ordering::Relaxed),
+            escalations: self.escalations.load(Ordering::Relaxed),
+            destinations: history.entries.len(),
+            escalated: history.entries.values().filter(|(f, _)| *f != 0).count(),
+        }
+    }
+}
+
+impl AdaptiveParallelStats {
+    /// Ratio of connects escalated to `max`, zero if none.
+    pub fn escalation_ratio(&self) -> f64 {
+        if self.decisions == 0 {
+            0.0
+        } else {
+            self.escalations as f64 / self.decisions as f64
+        }
+    }
+}
+
+#[test]
+fn test_adaptive_parallel() {
+    let parallel = AdaptiveParallel::new(3);
+    let key = |host: &str| -> ParallelKey { (host.into(), 443) };
+    // Unknown ones
+    assert_eq!(1, parallel.n_parallel(&key("a.test")));
+
+    parallel.record(key("a.test"), true);
+    parallel.record(key("b.test"), false);
+    assert_eq!(1, parallel.n_parallel(&key("a.test")));
+    assert_eq!(3, parallel.n_parallel(&key("b.test")));
+    assert_eq!(1, parallel.n_parallel(&("b.test".into(), 80)));
+
+    // Back once the failure is out of the history
+    for _ in 0..u8::BITS - 1 {
+        parallel.record(key("b.test"), true);
+        assert_eq!(3, parallel.n_parallel(&key("b.test")));
+    }
+    parallel.record(key("b.test"), true);
+    assert_eq!(1, parallel.n_parallel(&key("b.test")));
+
+    let stats = parallel.stats();
+    assert_eq!(2 + u8::BITS as usize + 2, stats.decisions);
+    assert_eq!(u8::BITS as usize, stats.escalations);
+    assert_eq!((2, 0), (stats.destinations, stats.escalated));
+}
+
+#[test]
+fn test_adaptive_parallel_eviction() {
+    let parallel = AdaptiveParallel::new(2);
+    let key = |n: usize| -> ParallelKey { (format!("{}.test", n).into(), 443) };
+    parallel.record(key(0), false);
+    parallel.record(key(1), false);
+    for n in 2..=MAX_DESTINATIONS {
+        parallel.record(key(n), true);
+        // Keep the first one being used
+        parallel.record(key(0), false);
+    }
+    let stats = parallel.stats();
+    assert_eq!(MAX_DESTINATIONS, stats.destinations);
+    assert_eq!(2, parallel.n_parallel(&key(0)));
+    // The least recently used one is forgotten
+    assert_eq!(1, parallel.n_parallel(&key(1)));
+    assert_eq!(1, stats.escalated);
+}
@@ -0,0 +1,386 @@
+//! Sampled captures of probes for offline analysis, see `--probe-capture`.
+//!
+//! Each captured probe is written as a pcap file with the payload we sent
+//! to and received from the server, wrapped in synthesized IP/TCP headers
+//! of the real connection, plus a JSON sidecar with timings and the
+//! computed delay. Bytes of the proxy handshake itself are not included.
+
+use rand::Rng;
+use serde_json::json;
+use std::{
+    fs, io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{net::TcpStream, time::Instant};
+
+/// Files not named with this prefix in the directory are left untouched.
+const FILE_PREFIX: &str = "probe-";
+/// pcap link type of raw IPv4/IPv6 packets.
+const LINKTYPE_RAW: u32 = 101;
+/// Payload is split into packets of at most this size.
+const MAX_SEGMENT: usize = 1400;
+
+#[derive(Debug)]
+pub(crate) struct ProbeCapture {
+    dir: PathBuf,
+    rate: f64,
+    /// Oldest captures are removed once files in `dir` exceed this.
+    max_bytes: u64,
+}
+
+#[derive(Debug)]
+struct Packet {
+    at: Duration,
+    outbound: bool,
+    payload: Vec<u8>,
+}
+
+/// Traffic of a single probe, filled by `alive_test()`.
+#[derive(Debug)]
+pub(crate) struct ProbeRecord {
+    started_at: SystemTime,
+    started: Instant,
+    local: Option<SocketAddr>,
+    peer: Option<SocketAddr>,
+    /// Time taken to connect and handshake with the server.
+    connected: Option<Duration>,
+    packets: Vec<Packet>,
+}
+
+impl ProbeCapture {
+    pub(crate) fn new(dir: PathBuf, rate: f64, max_bytes: u64) -> Self {
+        Self {
+            dir,
+            rate: rate.clamp(0.0, 1.0),
+            max_bytes,
+        }
+    }
+
+    /// Whether to capture the probe round about to start.
+    pub(crate) fn sample(&self) -> bool {
+        rand::thread_rng().gen_bool(self.rate)
+    }
+
+    /// Write the pcap and JSON files of `record`, then evict old ones.
+    /// Blocking.
+    pub(crate) fn save(
+        &self,
+        tag: &str,
+        record: &ProbeRecord,
+        result: &io::Result<Duration>,
+    ) -> io::Result<()> {
+        let millis = record
+            .started_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let name = format!("{}{:013}-{}", FILE_PREFIX, millis, tag);
+        fs::write(self.dir.join(format!("{}.pcap", name)), record.to_pcap())?;
+        let json = record.to_json(tag, result).to_string();
+        fs::write(self.dir.join(format!("{}.json", name)), json)?;
+        self.evict()
+    }
+
+    /// Remove oldest captures until total size is within `max_bytes`.
+    fn evict(&self) -> io::Result<()> {
+        let mut files = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if name.to_string_lossy().starts_with(FILE_PREFIX) {
+                files.push((name, entry.metadata()?.len()));
+            }
+        }
+        // Names start with timestamps
+        files.sort_unstable();
+        let mut total: u64 = files.iter().map(|(_, len)| len).sum();
+        for (name, len) in files {
+            if total <= self.max_bytes {
+                break;
+            }
+            fs::remove_file(self.dir.join(name))?;
+            total -= len;
+        }
+        Ok(())
+    }
+}
+
+impl ProbeRecord {
+    pub(crate) fn new() -> Self {
+        Self {
+            started_at: SystemTime::now(),
+            started: Instant::now(),
+            local: None,
+            peer: None,
+            connected: None,
+            packets: vec![],
+        }
+    }
+
+    /// Called once the handshake with the server completed.
+    pub(crate) fn connected(&mut self, stream: &TcpStream) {
+        self.local = stream.local_addr().ok();
+        self.peer = stream.peer_addr().ok();
+        self.connected = Some(self.started.elapsed());
+    }
+
+    pub(crate) fn sent(&mut self, payload: &[u8]) {
+        self.add(true, payload);
+    }
+
+    pub(crate) fn received(&mut self, payload: &[u8]) {
+        self.add(false, payload);
+    }
+
+    fn add(&mut self, outbound: bool, payload: &[u8]) {
+        let at = self.started.elapsed();
+        for payload in payload.chunks(MAX_SEGMENT) {
+            self.packets.push(Packet {
+                at,
+                outbound,
+                payload: payload.to_vec(),
+            });
+        }
+    }
+
+    fn to_pcap(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        // Global header, microsecond resolution
+        buf.extend(0xa1b2c3d4u32.to_le_bytes());
+        buf.extend(2u16.to_le_bytes());
+        buf.extend(4u16.to_le_bytes());
+        buf.extend([0; 8]); // time zone & accuracy
+        buf.extend(65535u32.to_le_bytes());
+        buf.extend(LINKTYPE_RAW.to_le_bytes());
+
+        let (local, peer) = match (self.local, self.peer) {
+            (Some(local), Some(peer)) => (local, peer),
+            _ => return buf,
+        };
+        // Next sequence numbers of both sides, start from 1 as Wireshark
+        // shows relative ones
+        let (mut local_seq, mut peer_seq) = (1u32, 1u32);
+        for packet in &self.packets {
+            let (src, dst, seq, ack) = match packet.outbound {
+                true => (local, peer, &mut local_seq, peer_seq),
+                false => (peer, local, &mut peer_seq, local_seq),
+            };
+            let data = ip_packet(src, dst, *seq, ack, &packet.payload);
+            *seq = seq.wrapping_add(packet.payload.len() as u32);
+
+            let at = self.started_at + packet.at;
+            let at = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+            buf.extend((at.as_secs() as u32).to_le_bytes());
+            buf.extend(at.subsec_micros().to_le_bytes());
+            buf.extend((data.len() as u32).to_le_bytes());
+            buf.extend((data.len() as u32).to_le_bytes());
+            buf.extend(data);
+        }
+        buf
+    }
+
+    fn to_json(&self, tag: &str, result: &io::Result<Duration>) -> serde_json::Value {
+        let millis = |d: Duration| d.as_secs_f64() * 1000.0;
+        let packets: Vec<_> = self
+            .packets
+            .iter()
+            .map(|p| {
+                json!({
+                    "at_ms": millis(p.at),
+                    "direction": if p.outbound { "sent" } else { "received" },
+                    "len": p.payload.len(),
+                })
+            })
+            .collect();
+        json!({
+            "server": tag,
+            "started": self.started_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64(),
+            "local": self.local.map(|a| a.to_string()),
+            "peer": self.peer.map(|a| a.to_string()),
+            "connected_ms": self.connected.map(millis),
+            "packets": packets,
+            "delay_ms": result.as_ref().ok().copied().map(millis),
+            "error": result.as_ref().err().map(|e| e.to_string()),
+        })
+    }
+}
+
+/// One's complement sum of 16-bit words, not yet folded.
+fn checksum_add(sum: u32, data: &[u8]) -> u32 {
+    data.chunks(2).fold(sum, |sum, word| {
+        sum + ((word[0] as u32) << 8 | *word.get(1).unwrap_or(&0) as u32)
+    })
+}
+
+fn checksum_fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Build an IPv4/IPv6 packet of a TCP segment carrying `payload`.
+fn ip_packet(src: SocketAddr, dst: SocketAddr, seq: u32, ack: u32, payload: &[u8]) -> Vec<u8> {
+    let mut tcp = Vec::with_capacity(20 + payload.len());
+    tcp.extend(src.port().to_be_bytes());
+    tcp.extend(dst.port().to_be_bytes());
+    tcp.extend(seq.to_be_bytes());
+    tcp.extend(ack.to_be_bytes());
+    tcp.extend([5 << 4, 0x18]); // header length, PSH & ACK
+    tcp.extend(65535u16.to_be_bytes()); // window
+    tcp.extend([0; 4]); // checksum & urgent pointer
+    tcp.extend(payload);
+    let tcp_len = tcp.len() as u32;
+
+    let (src_ip, dst_ip) = (src.ip().to_canonical(), dst.ip().to_canonical());
+    let mut packet = match (src_ip, dst_ip) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let mut ip = Vec::with_capacity(20);
+            ip.extend([0x45, 0]);
+            ip.extend(((20 + tcp_len) as u16).to_be_bytes());
+            ip.extend([0, 0, 0x40, 0]); // ID, don't fragment
+            ip.extend([64, 6, 0, 0]); // TTL, TCP, checksum
+            ip.extend(src_ip.octets());
+            ip.extend(dst_ip.octets());
+            let sum = checksum_fold(checksum_add(0, &ip));
+            ip[10..12].copy_from_slice(&sum.to_be_bytes());
+            let pseudo = checksum_add(checksum_add(0, &ip[12..20]), &[0, 6]) + tcp_len;
+            let sum = checksum_fold(checksum_add(pseudo, &tcp));
+            tcp[16..18].copy_from_slice(&sum.to_be_bytes());
+            ip
+        }
+        _ => {
+            let (src_ip, dst_ip) = (to_ipv6(src_ip), to_ipv6(dst_ip));
+            let mut ip = Vec::with_capacity(40);
+            ip.extend([0x60, 0, 0, 0]);
+            ip.extend((tcp_len as u16).to_be_bytes());
+            ip.extend([6, 64]); // TCP, hop limit
+            ip.extend(src_ip);
+            ip.extend(dst_ip);
+            let pseudo = checksum_add(0, &ip[8..40]) + tcp_len + 6;
+            let sum = checksum_fold(checksum_add(pseudo, &tcp));
+            tcp[16..18].copy_from_slice(&sum.to_be_bytes());
+            ip
+        }
+    };
+    packet.extend(tcp);
+    packet
+}
+
+fn to_ipv6(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+#[cfg(test)]
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "moproxy-test-capture-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[cfg(test)]
+fn test_record() -> ProbeRecord {
+    let mut record = ProbeRecord::new();
+    record.local = Some("192.0.2.1:50000".parse().unwrap());
+    record.peer = Some("192.0.2.2:1080".parse().unwrap());
+    record.connected = Some(Duration::from_millis(20));
+    record.sent(b"query");
+    record.received(&[1; 3000]);
+    record
+}
+
+#[test]
+fn test_probe_record_pcap() {
+    let pcap = test_record().to_pcap();
+    assert_eq!([0xd4, 0xc3, 0xb2, 0xa1], pcap[..4]);
+    assert_eq!(LINKTYPE_RAW.to_le_bytes(), pcap[20..24]);
+
+    // Sent one, received three split
+    let mut packets = vec![];
+    let mut rest = &pcap[24..];
+    while !rest.is_empty() {
+        let len = u32::from_le_bytes(rest[8..12].try_into().unwrap()) as usize;
+        packets.push(&rest[16..16 + len]);
+        rest = &rest[16 + len..];
+    }
+    assert_eq!(4, packets.len());
+    let query = packets[0];
+    assert_eq!(45, query.len());
+    assert_eq!(b"query", &query[40..]);
+    assert_eq!([192, 0, 2, 1], query[12..16]);
+    assert_eq!(1080u16.to_be_bytes(), query[22..24]);
+    // Valid checksums sum up to zero
+    assert_eq!(0, checksum_fold(checksum_add(0, &query[..20])));
+    let pseudo = checksum_add(checksum_add(0, &query[12..20]), &[0, 6]) + 25;
+    assert_eq!(0, checksum_fold(checksum_add(pseudo, &query[20..])));
+
+    // Sequence numbers follow payload lengths
+    let seq = |p: &[u8]| u32::from_be_bytes(p[24..28].try_into().unwrap());
+    let ack = |p: &[u8]| u32::from_be_bytes(p[28..32].try_into().unwrap());
+    assert_eq!((1, 6), (seq(packets[1]), ack(packets[1])));
+    assert_eq!(1 + MAX_SEGMENT as u32, seq(packets[2]));
+    assert_eq!(6, ack(packets[3]));
+
+    let mut record = test_record();
+    record.local = Some("[2001:db8::1]:50000".parse().unwrap());
+    record.peer = Some("[2001:db8::2]:1080".parse().unwrap());
+    let pcap = record.to_pcap();
+    assert_eq!(0x60, pcap[24 + 16]);
+}
+
+#[test]
+fn test_probe_capture_save() {
+    let dir = test_dir("save");
+    let record = test_record();
+    let one_capture = {
+        let capture = ProbeCapture::new(dir.clone(), 1.0, u64::MAX);
+        assert!(capture.sample());
+        capture
+            .save("a", &record, &Ok(Duration::from_millis(30)))
+            .unwrap();
+        fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().metadata().unwrap().len())
+            .sum::<u64>()
+    };
+    let mut names: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(2, names.len());
+    assert!(names[0].starts_with("probe-") && names[0].ends_with("-a.json"));
+    let json = fs::read(dir.join(&names[0])).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(30.0, json["delay_ms"]);
+    assert_eq!(4, json["packets"].as_array().unwrap().len());
+
+    // Oldest ones are evicted, other files are left untouched
+    fs::write(dir.join("other"), [0; 4096]).unwrap();
+    let capture = ProbeCapture::new(dir.clone(), 0.0, one_capture * 5 / 2);
+    assert!(!capture.sample());
+    let mut record = test_record();
+    for _ in 0..3 {
+        record.started_at += Duration::from_secs(1);
+        let err = io::Error::new(io::ErrorKind::TimedOut, "test timeout");
+        capture.save("b", &record, &Err(err)).unwrap();
+    }
+    let names: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert_eq!(5, names.len(), "{:?}", names);
+    assert!(names.iter().all(|name| !name.ends_with("-a.json")));
+    assert!(names.contains(&"other".to_string()));
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,174 @@
+use std::{io, sync::Arc, time::Duration};
+use tracing::{debug, info, warn};
+
+use crate::proxy::{Delay, ProxyServer};
+
+/// How often to log servers still down, if only changes are logged.
+pub(crate) const SUMMARY_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Scores within the same power of two are in the same band.
+fn score_band(score: Option<i32>) -> Option<u32> {
+    score.map(|score| u32::BITS - (score.max(1) as u32).leading_zeros())
+}
+
+/// Log the probe result of `server`, `last_delay` and `last_score` are of
+/// before the probe. If `changes_only`, only log changes of its up/down
+/// state or score band.
+pub(crate) fn log_probe(
+    server: &ProxyServer,
+    last_delay: Delay,
+    last_score: Option<i32>,
+    result: &io::Result<Duration>,
+    changes_only: bool,
+) {
+    let tag = server.tag();
+    if !changes_only {
+        match result {
+            Ok(t) => debug!(proxy = %tag, "{}ms", t.as_millis()),
+            Err(err) => debug!(proxy = %tag, "probe failed: {}", err),
+        }
+        return;
+    }
+    match (last_delay, result) {
+        (Delay::TimedOut | Delay::Failed, Err(_)) => (),
+        (Delay::Some(_), Ok(_)) => {
+            let score = server.score();
+            if score_band(last_score) != score_band(score) {
+                info!(
+                    proxy = %tag,
+                    "score changed from {} to {}",
+                    last_score.unwrap_or_default(),
+                    score.unwrap_or_default()
+                );
+            }
+        }
+        (_, Ok(t)) => info!(proxy = %tag, "server up ({}ms)", t.as_millis()),
+        (_, Err(err)) => warn!(proxy = %tag, "server down: {}", err),
+    }
+}
+
+/// Return the line listing servers still down, `None` if all up.
+pub(crate) fn down_summary(servers: &[Arc<ProxyServer>]) -> Option<String> {
+    let down: Vec<_> = servers
+        .iter()
+        .filter(|server| {
+            matches!(
+                server.status_snapshot().delay,
+                Delay::TimedOut | Delay::Failed
+            )
+        })
+        .map(|server| server.tag())
+        .collect();
+    if down.is_empty() {
+        return None;
+    }
+    let tags: Vec<_> = down.iter().map(|tag| tag.as_str()).collect();
+    Some(format!(
+        "{} server(s) still down: {}",
+        down.len(),
+        tags.join(", ")
+    ))
+}
+
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<parking_lot::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl CapturedLogs {
+    /// Run `func` with logs captured, return lines logged.
+    fn capture(&self, func: impl FnOnce()) -> Vec<String> {
+        let writer = self.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, func);
+        let logs = std::mem::take(&mut *self.0.lock());
+        String::from_utf8(logs)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+}
+
+#[test]
+fn test_log_probe() {
+    let server = Arc::new(ProxyServer::test_socks5(
+        ([127, 0, 0, 1], 1).into(),
+        Some("a"),
+    ));
+    let logs = CapturedLogs::default();
+    // Probe then log as `test_one()` does
+    let probe = |result: io::Result<Duration>, changes_only| {
+        let status = server.status_snapshot();
+        server.update_delay(result.as_ref().ok().copied());
+        log_probe(&server, status.delay, status.score, &result, changes_only);
+    };
+    let ms = |n| Ok(Duration::from_millis(n));
+    let down = || Err(io::Error::from(io::ErrorKind::ConnectionRefused));
+
+    // Every probe by default
+    let lines = logs.capture(|| {
+        probe(down(), false);
+        probe(down(), false);
+    });
+    assert_eq!(2, lines.len());
+    assert!(lines[1].contains("probe failed"));
+
+    // Known-down server is not logged again
+    let lines = logs.capture(|| {
+        for _ in 0..10 {
+            probe(down(), true);
+        }
+    });
+    assert!(lines.is_empty(), "{:?}", lines);
+    assert_eq!(
+        Some("1 server(s) still down: a".to_string()),
+        down_summary(std::slice::from_ref(&server))
+    );
+
+    let lines = logs.capture(|| {
+        probe(ms(100), true);
+        probe(ms(100), true);
+        probe(ms(110), true);
+    });
+    assert_eq!(1, lines.len(), "{:?}", lines);
+    assert!(lines[0].contains("server up"));
+    assert_eq!(None, down_summary(std::slice::from_ref(&server)));
+
+    // Score band changed
+    let lines = logs.capture(|| {
+        for _ in 0..5 {
+            probe(ms(3_000), true);
+        }
+    });
+    assert!(!lines.is_empty());
+    assert!(lines.iter().all(|line| line.contains("score changed")));
+
+    let lines = logs.capture(|| probe(down(), true));
+    assert_eq!(1, lines.len());
+    assert!(lines[0].contains("WARN") && lines[0].contains("server down"));
+}
+
+#[test]
+fn test_score_band() {
+    assert_eq!(None, score_band(None));
+    assert_eq!(Some(1), score_band(Some(-5)));
+    assert_eq!(score_band(Some(1024)), score_band(Some(2047)));
+    assert_ne!(score_band(Some(1023)), score_band(Some(1024)));
+}
@@ -0,0 +1,151 @@
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime},
+};
+
+/// Number of recent reloads kept.
+const HISTORY_LEN: usize = 5;
+
+/// Difference between two server lists, see `Monitor::update_servers()`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ServerListDiff {
+    pub added: usize,
+    pub removed: usize,
+    /// Kept servers (same address & protocol) with a new tag.
+    pub renamed: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReloadRecord {
+    /// UNIX timestamp in seconds.
+    pub time: u64,
+    pub success: bool,
+    /// Set if failed.
+    pub error: Option<String>,
+    /// Config generation after this reload.
+    pub generation: u64,
+    /// Time spent on loading and applying the config.
+    pub duration_secs: f64,
+    pub servers_added: usize,
+    pub servers_removed: usize,
+    pub servers_renamed: usize,
+    /// Number of policy rules after reload minus that before it.
+    pub rules_delta: isize,
+    /// Sections of the server list skipped for errors.
+    pub skipped_sections: Vec<String>,
+}
+
+/// A section of the server list skipped for its error, see
+/// `--server-list-lenient`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SkippedSection {
+    pub section: String,
+    pub error: String,
+}
+
+/// Checksum of a loaded config, for telling apart instances loaded
+/// different ones.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConfigHash {
+    /// Path of the file.
+    pub file: String,
+    /// `file` for SHA-256 of the content, or `structural` for that of the
+    /// parsed policy, see `Policy::structural_hash()`.
+    pub kind: &'static str,
+    pub hash: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReloadHistory {
+    /// Increased on each successful reload, 0 for the initial config.
+    pub generation: u64,
+    /// Recent reloads, the latest one at the end.
+    pub recent: VecDeque<ReloadRecord>,
+    /// Sections skipped in the server list currently loaded.
+    pub skipped: Vec<SkippedSection>,
+}
+
+impl ReloadRecord {
+    fn new(success: bool, generation: u64, duration: Duration) -> Self {
+        Self {
+            time: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|t| t.as_secs())
+                .unwrap_or_default(),
+            success,
+            error: None,
+            generation,
+            duration_secs: duration.as_secs_f64(),
+            servers_added: 0,
+            servers_removed: 0,
+            servers_renamed: 0,
+            rules_delta: 0,
+            skipped_sections: vec![],
+        }
+    }
+}
+
+impl ReloadHistory {
+    pub(crate) fn succeeded(
+        &mut self,
+        diff: ServerListDiff,
+        rules_delta: isize,
+        duration: Duration,
+    ) {
+        self.generation += 1;
+        self.push(ReloadRecord {
+            servers_added: diff.added,
+            servers_removed: diff.removed,
+            servers_renamed: diff.renamed,
+            rules_delta,
+            skipped_sections: self.skipped.iter().map(|s| s.section.clone()).collect(),
+            ..ReloadRecord::new(true, self.generation, duration)
+        });
+    }
+
+    pub(crate) fn failed(&mut self, error: String, duration: Duration) {
+        self.push(ReloadRecord {
+            error: Some(error),
+            ..ReloadRecord::new(false, self.generation, duration)
+        });
+    }
+
+    fn push(&mut self, record: ReloadRecord) {
+        if self.recent.len() >= HISTORY_LEN {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(record);
+    }
+}
+
+#[test]
+fn test_reload_history() {
+    let mut history = ReloadHistory::default();
+    history.failed("bad config".into(), Duration::from_millis(500));
+    assert_eq!(0, history.generation);
+    assert_eq!(0.5, history.recent[0].duration_secs);
+    for n in 0..HISTORY_LEN {
+        let diff = ServerListDiff {
+            added: n,
+            removed: 1,
+            renamed: 0,
+        };
+        history.succeeded(diff, -1, Duration::from_millis(n as u64));
+    }
+    assert_eq!(HISTORY_LEN as u64, history.generation);
+    assert_eq!(HISTORY_LEN, history.recent.len());
+    assert!(history.recent.iter().all(|r| r.success));
+    let last = history.recent.back().unwrap();
+    assert_eq!(HISTORY_LEN - 1, last.servers_added);
+    assert_eq!(-1, last.rules_delta);
+    assert_eq!(HISTORY_LEN as u64, last.generation);
+    assert_eq!(1, history.recent[0].generation);
+
+    history.skipped = vec![SkippedSection {
+        section: "a".into(),
+        error: "address not specified".into(),
+    }];
+    history.succeeded(Default::default(), 0, Duration::ZERO);
+    assert_eq!(["a"], history.recent.back().unwrap().skipped_sections[..]);
+}
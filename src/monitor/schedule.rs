@@ -0,0 +1,110 @@
+use rand::Rng;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::time::Instant;
+
+use crate::proxy::ProxyServer;
+
+/// Start of each probe round is randomly shifted by up to this ratio of
+/// the interval, so that probes don't hit upstreams at fixed instants.
+const JITTER: f64 = 0.2;
+
+/// Next-due time of each server, probed every `interval` unless it has
+/// its own `probe interval`.
+pub(crate) struct ProbeSchedule {
+    interval: Duration,
+    due: HashMap<Arc<ProxyServer>, Instant>,
+}
+
+impl ProbeSchedule {
+    pub(crate) fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            due: Default::default(),
+        }
+    }
+
+    /// Return servers due at the round starting at `now`, and schedule
+    /// their next probes. New servers are always due.
+    pub(crate) fn take_due(
+        &mut self,
+        servers: &[Arc<ProxyServer>],
+        now: Instant,
+    ) -> Vec<Arc<ProxyServer>> {
+        // Would be more overdue if left to the next round
+        let horizon = now + self.interval / 2;
+        self.due.retain(|server, _| servers.contains(server));
+        servers
+            .iter()
+            .filter(|server| {
+                let interval = server.probe_interval().unwrap_or(self.interval);
+                match self.due.get(*server) {
+                    Some(due) if *due > horizon => false,
+                    _ => {
+                        self.due.insert((*server).clone(), now + interval);
+                        true
+                    }
+                }
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Make all servers due at the next round.
+    pub(crate) fn reset(&mut self) {
+        self.due.clear();
+    }
+
+    /// Start of the round after the one started at `now`.
+    pub(crate) fn next_round(&self, now: Instant) -> Instant {
+        let ratio = rand::thread_rng().gen_range(1.0 - JITTER..=1.0 + JITTER);
+        now + self.interval.mul_f64(ratio)
+    }
+}
+
+#[test]
+fn test_probe_schedule() {
+    let server = |port, interval: Option<u64>| {
+        let server = ProxyServer::test_socks5(([127, 0, 0, 1], port).into(), None);
+        server.update_config(|config| config.probe_interval = interval.map(Duration::from_secs));
+        Arc::new(server)
+    };
+    let servers = vec![server(1, None), server(2, Some(300)), server(3, Some(10))];
+    let mut schedule = ProbeSchedule::new(Duration::from_secs(60));
+    let start = Instant::now();
+    let ports =
+        |due: Vec<Arc<ProxyServer>>| -> Vec<_> { due.iter().map(|s| s.addr.port()).collect() };
+
+    // All due at first, then each on its own interval; rounds are jittered
+    let mut now = start;
+    let mut probed = HashMap::<_, u32>::new();
+    while now < start + Duration::from_secs(3600) {
+        for port in ports(schedule.take_due(&servers, now)) {
+            *probed.entry(port).or_default() += 1;
+        }
+        let next = schedule.next_round(now);
+        let elapsed = next - now;
+        assert!(elapsed >= Duration::from_secs(48) && elapsed <= Duration::from_secs(72));
+        now = next;
+    }
+    let (default, metered, frequent) = (probed[&1], probed[&2], probed[&3]);
+    assert!((50..=75).contains(&default), "{}", default);
+    assert!((10..=15).contains(&metered), "{}", metered);
+    // Not more often than rounds
+    assert_eq!(default, frequent);
+
+    // Exact due times without jitter
+    let mut schedule = ProbeSchedule::new(Duration::from_secs(60));
+    let at = |secs| start + Duration::from_secs(secs);
+    assert_eq!(vec![1, 2, 3], ports(schedule.take_due(&servers, at(0))));
+    assert_eq!(vec![1, 3], ports(schedule.take_due(&servers, at(60))));
+    assert_eq!(vec![3], ports(schedule.take_due(&servers, at(70))));
+    assert_eq!(vec![1, 3], ports(schedule.take_due(&servers, at(120))));
+    assert_eq!(vec![1, 2, 3], ports(schedule.take_due(&servers, at(280))));
+
+    // Removed servers are forgotten, re-added ones are due at once
+    schedule.take_due(&servers[..1], at(300));
+    assert_eq!(vec![2], ports(schedule.take_due(&servers[1..2], at(301))));
+
+    schedule.reset();
+    assert_eq!(vec![1, 2, 3], ports(schedule.take_due(&servers, at(302))));
+}
@@ -0,0 +1,214 @@
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+/// Max number of source addresses kept in the offenders table. The one
+/// refused the least is forgotten beyond that.
+const MAX_OFFENDERS: usize = 256;
+
+/// Concurrent connections per source address, limited by
+/// `--max-conn-per-ip` or `src ip ... limit N` rules.
+///
+/// Only connections with a limit are counted. Addresses are dropped once
+/// their last connection is closed.
+#[derive(Debug, Default)]
+pub(crate) struct SourceCounter {
+    alive: Mutex<HashMap<IpAddr, usize>>,
+    offenders: Mutex<HashMap<IpAddr, Offender>>,
+    refused: AtomicUsize,
+}
+
+/// Count a connection from `ip` as alive until dropped, including on
+/// panics of the task holding it, see `Monitor::source_permit()`.
+#[derive(Debug)]
+pub struct SourcePermit {
+    counter: Arc<SourceCounter>,
+    ip: IpAddr,
+}
+
+/// A source address refused for exceeding its limit.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Offender {
+    pub ip: IpAddr,
+    pub refused: usize,
+    /// The limit applied at the last refusal.
+    pub limit: usize,
+    /// Unix timestamp of the last refusal.
+    pub last_refused: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SourceStats {
+    /// Source addresses with connections counted.
+    pub sources: usize,
+    pub refused: usize,
+}
+
+impl SourceCounter {
+    /// Return `None` and record `ip` as an offender if it has `limit`
+    /// connections already.
+    pub(crate) fn acquire(self: &Arc<Self>, ip: IpAddr, limit: usize) -> Option<SourcePermit> {
+        // IPv4-mapped IPv6 as IPv4, so that both count as the same source
+        let ip = ip.to_canonical();
+        {
+            let mut alive = self.alive.lock();
+            let n = alive.entry(ip).or_default();
+            if *n < limit {
+                *n += 1;
+                return Some(SourcePermit {
+                    counter: self.clone(),
+                    ip,
+                });
+            }
+            if *n == 0 {
+                alive.remove(&ip);
+            }
+        }
+        self.refused.fetch_add(1, Ordering::Relaxed);
+        self.add_offender(ip, limit);
+        None
+    }
+
+    fn add_offender(&self, ip: IpAddr, limit: usize) {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |t| t.as_secs());
+        let mut offenders = self.offenders.lock();
+        if !offenders.contains_key(&ip) && offenders.len() >= MAX_OFFENDERS {
+            let least = offenders
+                .values()
+                .min_by_key(|o| (o.refused, o.last_refused))
+                .map(|o| o.ip);
+            if let Some(least) = least {
+                offenders.remove(&least);
+            }
+        }
+        let offender = offenders.entry(ip).or_insert(Offender {
+            ip,
+            refused: 0,
+            limit,
+            last_refused: now,
+        });
+        offender.refused += 1;
+        offender.limit = limit;
+        offender.last_refused = now;
+    }
+
+    /// Number of connections counted from `ip`.
+    #[cfg(test)]
+    fn alive(&self, ip: IpAddr) -> usize {
+        self.alive
+            .lock()
+            .get(&ip.to_canonical())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Offenders refused the most first, up to `limit`.
+    pub(crate) fn offenders(&self, limit: usize) -> Vec<Offender> {
+        let mut offenders: Vec<_> = self.offenders.lock().values().cloned().collect();
+        offenders.sort_by(|a, b| {
+            b.refused
+                .cmp(&a.refused)
+                .then(b.last_refused.cmp(&a.last_refused))
+        });
+        offenders.truncate(limit);
+        offenders
+    }
+
+    pub(crate) fn snapshot(&self) -> SourceStats {
+        SourceStats {
+            sources: self.alive.lock().len(),
+            refused: self.refused.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for SourcePermit {
+    fn drop(&mut self) {
+        let mut alive = self.counter.alive.lock();
+        if let Some(n) = alive.get_mut(&self.ip) {
+            *n -= 1;
+            if *n == 0 {
+                alive.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_source_counter() {
+    let counter = Arc::new(SourceCounter::default());
+    let a: IpAddr = [192, 0, 2, 1].into();
+    let b: IpAddr = [192, 0, 2, 2].into();
+    let p1 = counter.acquire(a, 2).unwrap();
+    let p2 = counter
+        .acquire("::ffff:192.0.2.1".parse().unwrap(), 2)
+        .unwrap();
+    assert!(counter.acquire(a, 2).is_none());
+    // Others are unaffected
+    let p3 = counter.acquire(b, 2).unwrap();
+    assert_eq!((2, 1), (counter.alive(a), counter.alive(b)));
+    drop(p1);
+    let p4 = counter.acquire(a, 2).unwrap();
+    assert!(counter.acquire(a, 1).is_none());
+    assert!(counter.acquire(b, 0).is_none());
+    drop((p2, p3, p4));
+    assert_eq!(
+        SourceStats {
+            sources: 0,
+            refused: 3
+        },
+        counter.snapshot()
+    );
+
+    let offenders = counter.offenders(10);
+    assert_eq!(2, offenders.len());
+    assert_eq!(
+        (a, 2, 1),
+        (offenders[0].ip, offenders[0].refused, offenders[0].limit)
+    );
+    assert_eq!(
+        (b, 1, 0),
+        (offenders[1].ip, offenders[1].refused, offenders[1].limit)
+    );
+    assert_eq!(1, counter.offenders(1).len());
+}
+
+#[test]
+fn test_source_permit_on_panic() {
+    let counter = Arc::new(SourceCounter::default());
+    let ip: IpAddr = [192, 0, 2, 1].into();
+    let permit = counter.acquire(ip, 1).unwrap();
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || {
+        let _permit = permit;
+        panic!("in task");
+    }));
+    assert!(result.is_err());
+    assert_eq!(0, counter.alive(ip));
+    assert!(counter.acquire(ip, 1).is_some());
+}
+
+#[test]
+fn test_offenders_bounded() {
+    let counter = Arc::new(SourceCounter::default());
+    let ip = |n: usize| IpAddr::from([10, 0, (n >> 8) as u8, n as u8]);
+    counter.acquire(ip(0), 0);
+    counter.acquire(ip(0), 0);
+    for n in 1..=MAX_OFFENDERS {
+        counter.acquire(ip(n), 0);
+    }
+    let offenders = counter.offenders(usize::MAX);
+    assert_eq!(MAX_OFFENDERS, offenders.len());
+    assert_eq!((ip(0), 2), (offenders[0].ip, offenders[0].refused));
+    // The latest one is kept
+    assert!(offenders.iter().any(|o| o.ip == ip(MAX_OFFENDERS)));
+}
@@ -0,0 +1,102 @@
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// A probe round started later than scheduled by at least this (or the
+/// probe interval if longer) is taken as resumed from suspend.
+const MIN_CLOCK_JUMP: Duration = Duration::from_secs(30);
+
+/// Detect suspend/resume by rounds of `Monitor::monitor_delay()` started
+/// far later than scheduled.
+///
+/// The monotonic clock stops during suspend on some platforms, such as
+/// Linux, where the boot time (`boot_time()`) is checked as well. Never
+/// the wall clock, which may be stepped by NTP or users at any time.
+#[derive(Debug)]
+pub(crate) struct SuspendDetector {
+    threshold: Duration,
+    armed: Option<(Instant, Option<Duration>)>,
+}
+
+/// Time since boot including the time suspended, where supported.
+pub(crate) fn boot_time() -> Option<Duration> {
+    #[cfg(target_os = "linux")]
+    return crate::linux::boot_time().ok();
+    #[cfg(not(target_os = "linux"))]
+    return None;
+}
+
+impl SuspendDetector {
+    pub(crate) fn new(interval: Duration) -> Self {
+        Self {
+            threshold: interval.max(MIN_CLOCK_JUMP),
+            armed: None,
+        }
+    }
+
+    /// Record the time going to sleep until the next round.
+    pub(crate) fn arm(&mut self, now: Instant, boot: Option<Duration>) {
+        self.armed = Some((now, boot));
+    }
+
+    /// Return how late the round scheduled at `due` started, if it's a
+    /// jump. Nothing the first time without `arm()`.
+    pub(crate) fn check(
+        &mut self,
+        due: Instant,
+        now: Instant,
+        boot: Option<Duration>,
+    ) -> Option<Duration> {
+        let (armed_at, armed_boot) = self.armed.take()?;
+        let scheduled = due.saturating_duration_since(armed_at);
+        let boot_elapsed = match (armed_boot, boot) {
+            (Some(armed), Some(now)) => now.saturating_sub(armed),
+            _ => Duration::ZERO,
+        };
+        let elapsed = now.duration_since(armed_at).max(boot_elapsed);
+        let late = elapsed.saturating_sub(scheduled);
+        (late >= self.threshold).then_some(late)
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_suspend_detector() {
+    use tokio::time::{advance, sleep_until};
+
+    let interval = Duration::from_secs(60);
+    let mut detector = SuspendDetector::new(interval);
+    assert_eq!(None, detector.check(Instant::now(), Instant::now(), None));
+
+    // On time, and somewhat late as probes took a while
+    for late in [0, 20] {
+        let due = Instant::now() + interval;
+        detector.arm(Instant::now(), None);
+        sleep_until(due).await;
+        advance(Duration::from_secs(late)).await;
+        assert_eq!(None, detector.check(due, Instant::now(), None));
+    }
+
+    // Monotonic clock jumped
+    let due = Instant::now() + interval;
+    detector.arm(Instant::now(), None);
+    advance(Duration::from_secs(3600)).await;
+    let late = detector.check(due, Instant::now(), None);
+    assert_eq!(Some(Duration::from_secs(3540)), late);
+
+    // Only the boot time did, as the monotonic clock stopped
+    let boot = Duration::from_secs(1000);
+    let due = Instant::now() + interval;
+    detector.arm(Instant::now(), Some(boot));
+    sleep_until(due).await;
+    let boot = boot + Duration::from_secs(600);
+    let late = detector.check(due, Instant::now(), Some(boot));
+    assert_eq!(Some(Duration::from_secs(540)), late);
+    // Not checked again without arm()
+    assert_eq!(None, detector.check(due, Instant::now(), Some(boot)));
+
+    // Real boot times, unlike the wall clock, never step: they barely
+    // moved during the paused sleep, which is on time
+    let due = Instant::now() + interval;
+    detector.arm(Instant::now(), boot_time());
+    sleep_until(due).await;
+    assert_eq!(None, detector.check(due, Instant::now(), boot_time()));
+}
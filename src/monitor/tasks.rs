@@ -0,0 +1,166 @@
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Max number of client tasks kept in `TaskRegistry` for `/debug/tasks`,
+/// further ones are counted but not registered.
+const MAX_TASKS: usize = 4096;
+
+/// Leaks are not suspected below this number of client tasks, so that a
+/// few clients in handshake don't look like leaks on an idle instance.
+pub(crate) const MIN_LEAK_TASKS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskKind {
+    /// `handle_client()` and all connections it made.
+    Client,
+    Probe,
+    /// Connections to the web console.
+    Web,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TaskStats {
+    pub client: usize,
+    pub probe: usize,
+    pub web: usize,
+}
+
+/// A client task alive, sampled by `/debug/tasks`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TaskInfo {
+    /// Unix timestamp in seconds.
+    pub since: u64,
+    /// `None` until the client's handshake done.
+    pub dest: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct TaskRegistry {
+    client: AtomicUsize,
+    probe: AtomicUsize,
+    web: AtomicUsize,
+    next_id: AtomicU64,
+    clients: Mutex<HashMap<u64, TaskInfo>>,
+}
+
+/// Count the task as alive until dropped, see `Monitor::track_task()`.
+#[derive(Debug)]
+pub struct TaskGuard {
+    registry: Arc<TaskRegistry>,
+    kind: TaskKind,
+    /// Key in `TaskRegistry::clients` if registered.
+    id: Option<u64>,
+}
+
+impl TaskRegistry {
+    fn counter(&self, kind: TaskKind) -> &AtomicUsize {
+        match kind {
+            TaskKind::Client => &self.client,
+            TaskKind::Probe => &self.probe,
+            TaskKind::Web => &self.web,
+        }
+    }
+
+    pub(crate) fn track(self: &Arc<Self>, kind: TaskKind) -> TaskGuard {
+        self.counter(kind).fetch_add(1, Ordering::AcqRel);
+        let mut id = None;
+        if kind == TaskKind::Client {
+            let mut clients = self.clients.lock();
+            if clients.len() < MAX_TASKS {
+                let since = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|t| t.as_secs())
+                    .unwrap_or_default();
+                let key = self.next_id.fetch_add(1, Ordering::Relaxed);
+                clients.insert(key, TaskInfo { since, dest: None });
+                id = Some(key);
+            }
+        }
+        TaskGuard {
+            registry: self.clone(),
+            kind,
+            id,
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> TaskStats {
+        TaskStats {
+            client: self.client.load(Ordering::Relaxed),
+            probe: self.probe.load(Ordering::Relaxed),
+            web: self.web.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Registered client tasks, oldest first, at most `limit` of them.
+    pub(crate) fn oldest_clients(&self, limit: usize) -> Vec<TaskInfo> {
+        let clients = self.clients.lock();
+        let mut ids: Vec<_> = clients.keys().copied().collect();
+        ids.sort_unstable();
+        ids.into_iter()
+            .take(limit)
+            .map(|id| clients[&id].clone())
+            .collect()
+    }
+}
+
+impl TaskGuard {
+    pub fn set_dest(&self, dest: String) {
+        if let Some(id) = self.id {
+            if let Some(info) = self.registry.clients.lock().get_mut(&id) {
+                info.dest = Some(dest);
+            }
+        }
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.registry
+            .counter(self.kind)
+            .fetch_sub(1, Ordering::AcqRel);
+        if let Some(id) = self.id {
+            self.registry.clients.lock().remove(&id);
+        }
+    }
+}
+
+/// Whether `tasks` client tasks are too many for `connections` alive
+/// connections, i.e. more than `factor` times of it.
+pub(crate) fn suspect_leak(tasks: usize, connections: usize, factor: f64) -> bool {
+    tasks >= MIN_LEAK_TASKS && tasks as f64 > connections as f64 * factor
+}
+
+#[test]
+fn test_task_registry() {
+    let registry = Arc::new(TaskRegistry::default());
+    let a = registry.track(TaskKind::Client);
+    let b = registry.track(TaskKind::Client);
+    let probe = registry.track(TaskKind::Probe);
+    b.set_dest("example.com:443".into());
+    let stats = registry.snapshot();
+    assert_eq!((2, 1, 0), (stats.client, stats.probe, stats.web));
+
+    let tasks = registry.oldest_clients(10);
+    assert_eq!(vec![None, Some("example.com:443".into())], {
+        tasks.iter().map(|t| t.dest.clone()).collect::<Vec<_>>()
+    });
+    assert_eq!(1, registry.oldest_clients(1).len());
+    drop(a);
+    drop(probe);
+    let stats = registry.snapshot();
+    assert_eq!((1, 0), (stats.client, stats.probe));
+    assert_eq!(1, registry.oldest_clients(10).len());
+
+    assert!(!suspect_leak(MIN_LEAK_TASKS - 1, 0, 2.0));
+    assert!(suspect_leak(MIN_LEAK_TASKS, 0, 2.0));
+    assert!(!suspect_leak(200, 100, 2.0));
+    assert!(suspect_leak(201, 100, 2.0));
+}
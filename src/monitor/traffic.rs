@@ -1,11 +1,22 @@
 use crate::proxy::Traffic;
 use serde_derive::Serialize;
-use std::{collections::VecDeque, ops::Add, time::Instant};
+use std::{
+    collections::VecDeque,
+    ops::Add,
+    time::{Duration, Instant},
+};
+
+/// Default of `Meter` half-life.
+pub const DEFAULT_HALF_LIFE: Duration = Duration::from_secs(5);
 
 /// Monitor & caculate throughtput using traffic samples.
 #[derive(Debug)]
 pub struct Meter {
     samples: VecDeque<TrafficSample>,
+    /// Of the exponentially weighted moving average.
+    half_life: Duration,
+    /// Smoothed (tx, rx) bps, set since the second sample.
+    avg: Option<(f64, f64)>,
 }
 
 #[derive(Debug)]
@@ -18,6 +29,9 @@ pub struct TrafficSample {
 pub struct Throughput {
     pub tx_bps: usize,
     pub rx_bps: usize,
+    /// Smoothed with EWMA over samples.
+    pub tx_bps_avg: usize,
+    pub rx_bps_avg: usize,
 }
 
 impl From<Traffic> for TrafficSample {
@@ -29,16 +43,17 @@ impl From<Traffic> for TrafficSample {
     }
 }
 
-impl Throughput {
-    fn from_samples(t0: &TrafficSample, t1: &TrafficSample) -> Self {
-        let t = t1.time - t0.time;
-        let t = t.as_secs() as f64 + t.subsec_nanos() as f64 / 1e9;
-        let f = |x0, x1| (((x1 - x0) as f64) / t * 8.0).round() as usize;
-        Throughput {
-            tx_bps: f(t0.amt.tx_bytes, t1.amt.tx_bytes),
-            rx_bps: f(t0.amt.rx_bytes, t1.amt.rx_bytes),
-        }
+/// Return (tx, rx) bps between two samples.
+fn bps(t0: &TrafficSample, t1: &TrafficSample) -> (f64, f64) {
+    let t = (t1.time - t0.time).as_secs_f64();
+    if t == 0.0 {
+        return (0.0, 0.0);
     }
+    let f = |x0, x1| ((x1 - x0) as f64) / t * 8.0;
+    (
+        f(t0.amt.tx_bytes, t1.amt.tx_bytes),
+        f(t0.amt.rx_bytes, t1.amt.rx_bytes),
+    )
 }
 
 impl Add for Throughput {
@@ -48,14 +63,18 @@ impl Add for Throughput {
         Throughput {
             tx_bps: self.tx_bps + other.tx_bps,
             rx_bps: self.rx_bps + other.rx_bps,
+            tx_bps_avg: self.tx_bps_avg + other.tx_bps_avg,
+            rx_bps_avg: self.rx_bps_avg + other.rx_bps_avg,
         }
     }
 }
 
 impl Meter {
-    pub fn new() -> Self {
+    pub fn new(half_life: Duration) -> Self {
         Meter {
             samples: VecDeque::with_capacity(2),
+            half_life,
+            avg: None,
         }
     }
 
@@ -63,8 +82,30 @@ impl Meter {
     where
         T: Into<TrafficSample>,
     {
+        let sample = sample.into();
+        if let Some(last) = self.samples.front() {
+            let (tx, rx) = bps(last, &sample);
+            self.avg = Some(match self.avg {
+                None => (tx, rx),
+                Some((tx_avg, rx_avg)) => {
+                    // Weight of the new sample, so that samples older than
+                    // one half-life contribute to half of the average.
+                    let dt = (sample.time - last.time).as_secs_f64();
+                    let half_life = self.half_life.as_secs_f64();
+                    let alpha = if half_life > 0.0 {
+                        1.0 - 0.5f64.powf(dt / half_life)
+                    } else {
+                        1.0
+                    };
+                    (
+                        tx_avg + alpha * (tx - tx_avg),
+                        rx_avg + alpha * (rx - rx_avg),
+                    )
+                }
+            });
+        }
         self.samples.truncate(1);
-        self.samples.push_front(sample.into());
+        self.samples.push_front(sample);
     }
 
     pub fn throughput<T>(&self, sample: T) -> Throughput
@@ -72,10 +113,52 @@ impl Meter {
         T: Into<TrafficSample>,
     {
         let current = sample.into();
-        if let Some(oldest) = self.samples.back() {
-            Throughput::from_samples(oldest, &current)
-        } else {
-            Default::default()
+        let (tx_bps, rx_bps) = match self.samples.back() {
+            Some(oldest) => bps(oldest, &current),
+            None => (0.0, 0.0),
+        };
+        let (tx_bps_avg, rx_bps_avg) = self.avg.unwrap_or_default();
+        Throughput {
+            tx_bps: tx_bps.round() as usize,
+            rx_bps: rx_bps.round() as usize,
+            tx_bps_avg: tx_bps_avg.round() as usize,
+            rx_bps_avg: rx_bps_avg.round() as usize,
         }
     }
 }
+
+#[cfg(test)]
+fn sample_at(t0: Instant, secs: u64, bytes: usize) -> TrafficSample {
+    TrafficSample {
+        time: t0 + Duration::from_secs(secs),
+        amt: (bytes, bytes).into(),
+    }
+}
+
+#[test]
+fn test_meter_ewma() {
+    let t0 = Instant::now();
+    let mut meter = Meter::new(Duration::from_secs(2));
+    meter.add_sample(sample_at(t0, 0, 0));
+    assert_eq!(0, meter.throughput(sample_at(t0, 0, 0)).tx_bps_avg);
+
+    // First rate taken as is: 100 B/s
+    meter.add_sample(sample_at(t0, 1, 100));
+    let tp = meter.throughput(sample_at(t0, 1, 100));
+    assert_eq!((800, 800), (tp.tx_bps_avg, tp.rx_bps_avg));
+
+    // Drop to zero for one half-life: average halved
+    meter.add_sample(sample_at(t0, 3, 100));
+    let tp = meter.throughput(sample_at(t0, 3, 100));
+    assert_eq!(400, tp.tx_bps_avg);
+    // Instantaneous rate since the previous sample
+    assert_eq!(0, tp.tx_bps);
+
+    // 1s = half of half-life: weight 1 - 2^-0.5
+    meter.add_sample(sample_at(t0, 4, 300));
+    let tp = meter.throughput(sample_at(t0, 4, 300));
+    let alpha = 1.0 - 0.5f64.sqrt();
+    let expected = 400.0 + alpha * (1600.0 - 400.0);
+    assert_eq!(expected.round() as usize, tp.rx_bps_avg);
+    assert_eq!(1600, tp.tx_bps);
+}
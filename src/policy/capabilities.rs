@@ -35,9 +35,17 @@ impl CapSet {
         false
     }
 
+    pub fn contains(&self, cap: &str) -> bool {
+        self.0.binary_search_by(|c| c.as_str().cmp(cap)).is_ok()
+    }
+
     pub fn is_empty(&self) -> bool {
         self.0.is_empty()
     }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SharedStr> {
+        self.0.iter()
+    }
 }
 
 impl Display for CapSet {
@@ -87,6 +95,15 @@ fn test_capset_intersection() {
     assert!(abc.has_intersection(&aeg));
 }
 
+#[test]
+fn test_capset_contains() {
+    let abc = CapSet::new(["c", "a", "b"].into_iter());
+    assert!(abc.contains("a"));
+    assert!(abc.contains("c"));
+    assert!(!abc.contains("d"));
+    assert!(!CapSet::default().contains("a"));
+}
+
 #[test]
 fn test_capset_display() {
     assert_eq!("(EMPTY)", CapSet::default().to_string());
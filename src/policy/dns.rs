@@ -0,0 +1,219 @@
+use futures_util::future::BoxFuture;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, io,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::time::timeout;
+use tracing::{debug, trace};
+
+use crate::{privacy::HOSTNAMES, proxy::resolver::RESOLVER};
+
+/// Keep resolved names for their TTL but no longer than this, or for
+/// this long if their TTL is unknown.
+const CACHE_TTL: Duration = Duration::from_secs(300);
+/// Names beyond this are not cached until some of the cached expire.
+const MAX_CACHE_ENTRIES: usize = 4096;
+
+/// Resolve `name` to its addresses, with their TTL if known.
+pub type LookupFn = Arc<
+    dyn Fn(String) -> BoxFuture<'static, io::Result<(Vec<IpAddr>, Option<Duration>)>> + Send + Sync,
+>;
+
+/// Resolve domain names of clients for `dst ip` rules, see
+/// `--resolve-sni-for-policy`. Names not resolved within the budget are
+/// left unresolved for this connection, but cached for later ones.
+pub struct PolicyDns {
+    lookup: LookupFn,
+    budget: Duration,
+    /// Lowercased name => the first address & when expire.
+    cache: Mutex<HashMap<String, (IpAddr, Instant)>>,
+    /// Names being resolved, to not resolve them twice at the same time.
+    pending: Mutex<HashSet<String>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    budget_misses: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct PolicyDnsStats {
+    pub hits: usize,
+    pub misses: usize,
+    /// Misses not resolved within the budget.
+    pub budget_misses: usize,
+    pub size: usize,
+}
+
+impl fmt::Debug for PolicyDns {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PolicyDns")
+            .field("budget", &self.budget)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl PolicyDns {
+    /// Resolve with the global `RESOLVER`.
+    pub fn new(budget: Duration) -> Self {
+        let lookup: LookupFn =
+            Arc::new(|name| Box::pin(async move { RESOLVER.lookup_ip(&name).await }));
+        Self::with_lookup(budget, lookup)
+    }
+
+    pub fn with_lookup(budget: Duration, lookup: LookupFn) -> Self {
+        Self {
+            lookup,
+            budget,
+            cache: Default::default(),
+            pending: Default::default(),
+            hits: Default::default(),
+            misses: Default::default(),
+            budget_misses: Default::default(),
+        }
+    }
+
+    pub fn stats(&self) -> PolicyDnsStats {
+        PolicyDnsStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            budget_misses: self.budget_misses.load(Ordering::Relaxed),
+            size: self.cache.lock().len(),
+        }
+    }
+
+    /// Return an address of `name` if cached or resolved within the budget.
+    pub async fn resolve(self: &Arc<Self>, name: &str) -> Option<IpAddr> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(ip) = self.cached(&name) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Some(ip);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        if !self.pending.lock().insert(name.clone()) {
+            // Resolving by another connection, don't wait for it
+            self.budget_misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        // Keep resolving after the budget, so that later ones hit
+        let this = self.clone();
+        let task = tokio::spawn(async move {
+            let result = (this.lookup)(name.clone()).await;
+            let ip = match result {
+                Ok((addrs, ttl)) => {
+                    let ip = addrs.first().copied();
+                    if let Some(ip) = ip {
+                        this.store(&name, ip, ttl);
+                    }
+                    ip
+                }
+                Err(err) => {
+                    debug!(name = %HOSTNAMES.name(&name), "cannot resolve for policy: {}", err);
+                    None
+                }
+            };
+            this.pending.lock().remove(&name);
+            ip
+        });
+        match timeout(self.budget, task).await {
+            Ok(result) => result.ok().flatten(),
+            Err(_) => {
+                trace!("resolving for policy exceeded the budget");
+                self.budget_misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    fn cached(&self, name: &str) -> Option<IpAddr> {
+        match self.cache.lock().get(name) {
+            Some((ip, expire)) if *expire > Instant::now() => Some(*ip),
+            _ => None,
+        }
+    }
+
+    /// Failed names are not cached, since there's no TTL to follow.
+    fn store(&self, name: &str, ip: IpAddr, ttl: Option<Duration>) {
+        let ttl = ttl.map_or(CACHE_TTL, |ttl| ttl.min(CACHE_TTL));
+        if ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let expire = now + ttl;
+        let mut cache = self.cache.lock();
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            cache.retain(|_, (_, expire)| *expire > now);
+        }
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            return;
+        }
+        cache.insert(name.to_string(), (ip, expire));
+    }
+}
+
+#[tokio::test]
+async fn test_policy_dns() {
+    use super::{ActionType, Policy, RequestFeatures};
+
+    let lookup: LookupFn = Arc::new(|name: String| {
+        Box::pin(async move {
+            match name.as_str() {
+                "fast.test" => Ok((vec!["192.0.2.1".parse().unwrap()], None)),
+                "slow.test" => {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    Ok((
+                        vec!["192.0.2.2".parse().unwrap()],
+                        Some(Duration::from_secs(60)),
+                    ))
+                }
+                "short.test" => Ok((
+                    vec!["192.0.2.3".parse().unwrap()],
+                    Some(Duration::from_millis(50)),
+                )),
+                "zero.test" => Ok((vec!["192.0.2.4".parse().unwrap()], Some(Duration::ZERO))),
+                _ => Err(io::Error::new(io::ErrorKind::NotFound, "no address")),
+            }
+        })
+    });
+    let dns = Arc::new(PolicyDns::with_lookup(Duration::from_millis(30), lookup));
+    let fast: IpAddr = "192.0.2.1".parse().unwrap();
+    assert_eq!(Some(fast), dns.resolve("Fast.test.").await);
+    assert_eq!(Some(fast), dns.resolve("fast.test").await);
+    assert_eq!(None, dns.resolve("slow.test").await);
+    // Failed & zero TTL ones are not cached
+    assert_eq!(None, dns.resolve("nx.test").await);
+    assert_eq!(None, dns.resolve("nx.test").await);
+    let zero = Some("192.0.2.4".parse().unwrap());
+    assert_eq!(zero, dns.resolve("zero.test").await);
+    assert_eq!(zero, dns.resolve("zero.test").await);
+    let short = Some("192.0.2.3".parse().unwrap());
+    assert_eq!(short, dns.resolve("short.test").await);
+    assert_eq!(short, dns.resolve("short.test").await);
+    let stats = dns.stats();
+    assert_eq!((2, 7, 1), (stats.hits, stats.misses, stats.budget_misses));
+
+    // Resolved after the budget, cached for the next connection,
+    // while the short TTL one expired
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    let slow = dns.resolve("slow.test").await;
+    assert_eq!(Some("192.0.2.2".parse().unwrap()), slow);
+    assert_eq!(short, dns.resolve("short.test").await);
+    let stats = dns.stats();
+    assert_eq!((3, 8), (stats.hits, stats.misses));
+    assert_eq!(3, stats.size);
+
+    let policy = Policy::load("dst ip 192.0.2.0/24 reject".as_bytes()).unwrap();
+    let features = RequestFeatures {
+        listen_port: None,
+        dst_ip: slow,
+        dst_domain: Some("slow.test"),
+    };
+    assert_eq!(ActionType::Reject, policy.matches(&features).action);
+}
@@ -0,0 +1,201 @@
+//! Temporary overlays installed via the web console that reject or direct
+//! requests requiring certain capabilities, e.g. during upstream
+//! maintenance. They take precedence over rules from the policy file and
+//! are kept across reloads, but not restarts.
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::ActionType;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MaintenanceAction {
+    Reject,
+    Direct,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Maintenance {
+    /// Requests requiring any of them are affected, unless they accept
+    /// alternatives not in maintenance (e.g. `require a or b` with `a`).
+    pub caps: Vec<String>,
+    pub action: MaintenanceAction,
+    /// Expired at this time, in RFC 3339 (e.g. `2024-06-01T02:00:00Z`).
+    #[serde(serialize_with = "serialize_rfc3339")]
+    #[serde(deserialize_with = "deserialize_rfc3339")]
+    pub until: SystemTime,
+}
+
+impl Maintenance {
+    pub fn is_active(&self, now: SystemTime) -> bool {
+        self.until > now
+    }
+
+    fn covers(&self, cap: &str) -> bool {
+        self.caps.iter().any(|c| c == cap)
+    }
+}
+
+/// Return the action to replace `action` with, `None` if not affected by
+/// any of active `overlays`. The earliest added one wins if multiple apply.
+pub(super) fn apply(overlays: &[&Maintenance], action: &ActionType) -> Option<ActionType> {
+    let required = match action {
+        ActionType::Require(caps) => caps,
+        ActionType::Direct | ActionType::Reject => return None,
+    };
+    // Unless some alternatives are not in maintenance
+    let affected: Vec<_> = required
+        .iter()
+        .filter(|req| req.iter().all(|cap| overlays.iter().any(|m| m.covers(cap))))
+        .collect();
+    let overlay = overlays.iter().find(|m| {
+        affected
+            .iter()
+            .any(|req| req.iter().any(|cap| m.covers(cap)))
+    })?;
+    Some(match overlay.action {
+        MaintenanceAction::Reject => ActionType::Reject,
+        MaintenanceAction::Direct => ActionType::Direct,
+    })
+}
+
+fn serialize_rfc3339<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&format_rfc3339(*time))
+}
+
+fn deserialize_rfc3339<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    parse_rfc3339(&text).ok_or_else(|| de::Error::custom("not a RFC 3339 date-time"))
+}
+
+fn is_leap_year(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since Unix epoch from a civil date, see
+/// http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Parse `YYYY-MM-DDTHH:MM:SS[.frac](Z|±HH:MM)`.
+fn parse_rfc3339(text: &str) -> Option<SystemTime> {
+    let num = |s: &str| -> Option<i64> {
+        match s.bytes().all(|b| b.is_ascii_digit()) {
+            true => s.parse().ok(),
+            false => None,
+        }
+    };
+    let (date, time) = text.split_once(['T', 't', ' '])?;
+    let mut date = date.splitn(3, '-');
+    let (year, month, day) = (num(date.next()?)?, num(date.next()?)?, num(date.next()?)?);
+    let (time, offset) = match time.strip_suffix(['Z', 'z']) {
+        Some(time) => (time, 0),
+        None => {
+            let n = time.rfind(['+', '-'])?;
+            let (hours, minutes) = time[n + 1..].split_once(':')?;
+            let (hours, minutes) = (num(hours)?, num(minutes)?);
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            let offset = hours * 3600 + minutes * 60;
+            let sign = if time.as_bytes()[n] == b'-' { -1 } else { 1 };
+            (&time[..n], sign * offset)
+        }
+    };
+    let time = time.split('.').next()?;
+    let mut time = time.splitn(3, ':');
+    let (hour, minute, second) = (num(time.next()?)?, num(time.next()?)?, num(time.next()?)?);
+    if !(1970..=9999).contains(&year)
+        || !(1..=12).contains(&month)
+        || !(1..=days_in_month(year, month)).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+    // Cannot overflow with fields in range above, checked anyway
+    let secs = days_from_civil(year, month, day)
+        .checked_mul(86400)?
+        .checked_add(hour * 3600 + minute * 60 + second)?
+        .checked_sub(offset)?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs.try_into().ok()?))
+}
+
+/// Format as `YYYY-MM-DDTHH:MM:SSZ`.
+fn format_rfc3339(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_secs())
+        .unwrap_or_default() as i64;
+    // Civil from days, see `days_from_civil()`
+    let z = secs.div_euclid(86400) + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    let secs = secs.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[test]
+fn test_rfc3339() {
+    let at = |secs| Some(UNIX_EPOCH + Duration::from_secs(secs));
+    assert_eq!(at(0), parse_rfc3339("1970-01-01T00:00:00Z"));
+    assert_eq!(at(1717207200), parse_rfc3339("2024-06-01T02:00:00Z"));
+    assert_eq!(at(1717207200), parse_rfc3339("2024-06-01T10:00:00.5+08:00"));
+    assert_eq!(at(1717207200), parse_rfc3339("2024-05-31t23:30:00-02:30"));
+    assert_eq!(None, parse_rfc3339("2024-06-01"));
+    assert_eq!(None, parse_rfc3339("2024-13-01T00:00:00Z"));
+    assert_eq!(None, parse_rfc3339("2024-06-01T02:00:00"));
+    assert_eq!(None, parse_rfc3339("1969-12-31T23:59:59Z"));
+    assert_eq!(None, parse_rfc3339("2024-02-31T00:00:00Z"));
+    assert_eq!(None, parse_rfc3339("2023-02-29T00:00:00Z"));
+    assert!(parse_rfc3339("2024-02-29T00:00:00Z").is_some());
+    assert_eq!(None, parse_rfc3339("2024-04-31T00:00:00Z"));
+    assert_eq!(None, parse_rfc3339("10000-01-01T00:00:00Z"));
+    assert_eq!(None, parse_rfc3339("9223372036854775807-01-01T00:00:00Z"));
+    assert_eq!(
+        None,
+        parse_rfc3339("2024-06-01T00:00:00+9223372036854775807:00")
+    );
+    assert_eq!(None, parse_rfc3339("2024-06-01T00:00:00+24:00"));
+    assert!(parse_rfc3339("9999-12-31T23:59:59Z").is_some());
+    assert_eq!(
+        "2024-06-01T02:00:00Z",
+        format_rfc3339(at(1717207200).unwrap())
+    );
+    assert_eq!(
+        "2000-02-29T23:59:59Z",
+        format_rfc3339(at(951868799).unwrap())
+    );
+}
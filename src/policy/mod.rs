@@ -1,28 +1,55 @@
 pub mod capabilities;
+pub mod dns;
+pub mod maintenance;
 pub mod parser;
 
 use std::{
     collections::{HashMap, HashSet},
     fmt::Display,
-    fs::File,
     hash::Hash,
-    io::{self, BufRead, BufReader},
+    io::{self, BufRead},
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
     path::Path,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, SystemTime},
 };
 
 use flexstr::{SharedStr, ToSharedStr};
+use serde::Serialize;
 
 use capabilities::CapSet;
 use ip_network_table_deps_treebitmap::{address::Address, IpLookupTable};
 use tracing::info;
 
-use self::parser::{Filter, Rule};
+use self::maintenance::Maintenance;
+use self::parser::{AutoCapRule, DomainMatch, Filter, Line, Rule, RuleAuth, Secret, SrcLimitRule};
+use crate::{
+    proxy::{IpFamily, UserPassAuthCredential},
+    sha256::{self, Sha256},
+};
 
 #[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct Action {
     priority: u8,
     pub action: ActionType,
+    /// Index of rules that contribute to this action.
+    rules: Vec<usize>,
+    /// Override `max_wait` of servers for connecting.
+    pub timeout: Option<Duration>,
+    /// Prefer servers carrying less bulk connections, see
+    /// `proxy::prefer_non_bulk()`. Set if any of rules set it.
+    pub prefer_non_bulk: bool,
+    /// Dial addresses in this family first on direct connections, and
+    /// prefer servers in it. Taken like `timeout`.
+    pub prefer_family: Option<IpFamily>,
+    /// Override credentials of SOCKSv5/HTTP servers for connecting.
+    pub upstream_auth: Option<UserPassAuthCredential>,
+    /// Servers meeting these are tried first, but others are not filtered
+    /// out, see `proxy::sort_by_preference()`. Taken from all matched
+    /// `prefer` rules regardless of priority.
+    pub prefer: HashSet<CapSet>,
+    /// Index of `prefer` rules that contribute to this action.
+    prefer_rules: Vec<usize>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -43,6 +70,13 @@ impl ActionType {
         Action {
             priority,
             action: self,
+            rules: vec![],
+            timeout: None,
+            prefer_non_bulk: false,
+            prefer_family: None,
+            upstream_auth: None,
+            prefer: Default::default(),
+            prefer_rules: vec![],
         }
     }
 }
@@ -52,34 +86,97 @@ impl From<ActionType> for Action {
         Self {
             priority: 0,
             action,
+            rules: vec![],
+            timeout: None,
+            prefer_non_bulk: false,
+            prefer_family: None,
+            upstream_auth: None,
+            prefer: Default::default(),
+            prefer_rules: vec![],
         }
     }
 }
 
 impl Action {
+    /// Action of `prefer` rules, which doesn't affect other actions.
+    fn prefer(caps: CapSet) -> Self {
+        let mut action = Self::default();
+        action.prefer.insert(caps);
+        action
+    }
+
+    fn is_prefer_only(&self) -> bool {
+        !self.prefer.is_empty()
+            && self.rules.is_empty()
+            && matches!(&self.action, ActionType::Require(caps) if caps.is_empty())
+    }
+
+    /// Index of rules that contribute to this action, including `prefer`
+    /// ones, see `Policy::rule_text()`.
+    pub fn rules(&self) -> Vec<usize> {
+        let mut rules = [&self.rules[..], &self.prefer_rules[..]].concat();
+        rules.sort_unstable();
+        rules
+    }
+
     fn len(&self) -> usize {
-        match &self.action {
+        let len = match &self.action {
             ActionType::Direct | ActionType::Reject => 1,
             ActionType::Require(set) => set.len(),
-        }
+        };
+        len + self.prefer.len()
     }
 
     fn extend(&mut self, other: Self) {
-        if self.priority < other.priority {
+        // Timeout is taken from the latter (more specific) one unless
+        // it's overridden by priority, and fallback to the other.
+        let timeout = if self.priority > other.priority {
+            self.timeout.or(other.timeout)
+        } else {
+            other.timeout.or(self.timeout)
+        };
+        // So as the credential.
+        let upstream_auth = if self.priority > other.priority {
+            self.upstream_auth.take().or(other.upstream_auth.clone())
+        } else {
+            other.upstream_auth.clone().or(self.upstream_auth.take())
+        };
+        let prefer_family = if self.priority > other.priority {
+            self.prefer_family.or(other.prefer_family)
+        } else {
+            other.prefer_family.or(self.prefer_family)
+        };
+        let prefer_non_bulk = self.prefer_non_bulk || other.prefer_non_bulk;
+        // Preferences are kept whatever the priority is.
+        let mut prefer = std::mem::take(&mut self.prefer);
+        prefer.extend(other.prefer.iter().cloned());
+        let mut prefer_rules = std::mem::take(&mut self.prefer_rules);
+        prefer_rules.extend(&other.prefer_rules);
+        if other.is_prefer_only() {
+            // Leave the action as is
+        } else if self.priority < other.priority {
             *self = other;
         } else if self.priority == other.priority {
             match other.action {
                 ActionType::Direct | ActionType::Reject => *self = other,
                 ActionType::Require(new_caps) => {
                     if let ActionType::Require(caps) = &mut self.action {
-                        caps.extend(new_caps.into_iter())
+                        caps.extend(new_caps);
+                        self.rules.extend(other.rules);
                     } else {
-                        self.action = ActionType::Require(new_caps)
+                        self.action = ActionType::Require(new_caps);
+                        self.rules = other.rules;
                     }
                 }
             }
         }
         // Do nothing if self.priority > other.priority
+        self.timeout = timeout;
+        self.prefer_non_bulk = prefer_non_bulk;
+        self.prefer_family = prefer_family;
+        self.upstream_auth = upstream_auth;
+        self.prefer = prefer;
+        self.prefer_rules = prefer_rules;
     }
 }
 
@@ -87,7 +184,6 @@ impl Action {
 struct RuleSet<K: Eq + Hash>(HashMap<K, Action>);
 
 type ListenPortRuleSet = RuleSet<u16>;
-type DstDomainRuleSet = RuleSet<SharedStr>;
 
 impl<K: Eq + Hash> RuleSet<K> {
     fn add(&mut self, key: K, action: Action) {
@@ -101,9 +197,47 @@ impl<K: Eq + Hash> RuleSet<K> {
     }
 }
 
+/// Actions on a domain name, one for each match mode.
+#[derive(Default)]
+struct DomainActions {
+    suffix: Option<Action>,
+    exact: Option<Action>,
+    wildcard: Option<Action>,
+}
+
+impl DomainActions {
+    fn get_mut(&mut self, mode: DomainMatch) -> &mut Option<Action> {
+        match mode {
+            DomainMatch::Suffix => &mut self.suffix,
+            DomainMatch::Exact => &mut self.exact,
+            DomainMatch::Wildcard => &mut self.wildcard,
+        }
+    }
+
+    fn actions(&self) -> impl Iterator<Item = &Action> {
+        [&self.suffix, &self.wildcard, &self.exact]
+            .into_iter()
+            .flatten()
+    }
+}
+
+#[derive(Default)]
+struct DstDomainRuleSet(HashMap<SharedStr, DomainActions>);
+
 impl DstDomainRuleSet {
+    fn add(&mut self, name: SharedStr, mode: DomainMatch, action: Action) {
+        let value = self.0.entry(name).or_default().get_mut(mode);
+        match value {
+            Some(value) => value.extend(action),
+            None => *value = Some(action),
+        }
+    }
+
+    /// Return actions from the least specific to the most. On the same
+    /// domain, suffix match is followed by wildcard, then exact match.
     fn get_recursive<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Action> {
         let name = name.trim_end_matches('.'); // Add back later
+        let labels = name.split('.').count();
         let mut skip = name.len() + 1; // pretend ending with dot
         let parts = name.rsplit('.').map(move |part| {
             skip -= part.len() + 1; // +1 for the dot
@@ -112,7 +246,21 @@ impl DstDomainRuleSet {
         ["."] // add back the dot
             .into_iter()
             .chain(parts)
-            .filter_map(|key| self.0.get(key))
+            .enumerate() // number of labels of the key
+            .filter_map(|(n, key)| self.0.get(key).map(|actions| (n, actions)))
+            .flat_map(move |(n, actions)| {
+                [
+                    actions.suffix.as_ref(),
+                    actions.wildcard.as_ref().filter(|_| n + 1 == labels),
+                    actions.exact.as_ref().filter(|_| n == labels),
+                ]
+                .into_iter()
+                .flatten()
+            })
+    }
+
+    fn actions(&self) -> impl Iterator<Item = &Action> {
+        self.0.values().flat_map(|actions| actions.actions())
     }
 }
 
@@ -155,45 +303,143 @@ pub struct RequestFeatures<S: AsRef<str>> {
     pub dst_domain: Option<S>,
 }
 
+#[derive(Debug)]
+struct RuleHits {
+    rule: SharedStr,
+    hits: AtomicUsize,
+    /// UNIX timestamp in seconds, 0 for never.
+    last_hit: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RuleStats {
+    pub rule: SharedStr,
+    pub hits: usize,
+    pub last_hit: Option<u64>,
+}
+
 #[derive(Default)]
 pub struct Policy {
+    rules: Vec<RuleHits>,
     default_action: Action,
     listen_port_ruleset: ListenPortRuleSet,
     dst_ipv4_ruleset: Ipv4RuleSet,
     dst_ipv6_ruleset: Ipv6RuleSet,
     dst_domain_ruleset: DstDomainRuleSet,
+    auto_caps: Vec<AutoCapRule>,
+    src_limits: Vec<SrcLimitRule>,
+    /// Consulted before all rules, see `add_maintenance()`.
+    maintenance: Vec<Maintenance>,
+    /// See `structural_hash()`.
+    structural_hash: String,
+    /// See `file_hash()`.
+    file_hash: String,
 }
 
 impl Policy {
     pub fn load<R: BufRead>(read: R) -> io::Result<Self> {
+        Self::load_named(read, "<input>")
+    }
+
+    pub fn load_from_file<T: AsRef<Path>>(path: T) -> io::Result<Self> {
+        let content = std::fs::read(&path)?;
+        let mut this = Self::load_named(&content[..], &path.as_ref().display().to_string())?;
+        this.file_hash = sha256::hex_digest(&content);
+        info!("policy: {} rule(s) loaded", this.rule_count());
+        Ok(this)
+    }
+
+    /// `name` is used in error messages to tell where the rules from.
+    fn load_named<R: BufRead>(read: R, name: &str) -> io::Result<Self> {
         let mut router: Self = Default::default();
-        for line in read.lines() {
-            match parser::line_no_ending(&line?) {
+        let mut hasher = Sha256::new();
+        for (line_no, line) in read.lines().enumerate() {
+            let line = line?;
+            match parser::line_no_ending(&line) {
                 Ok((_, None)) => (),
-                Ok((_, Some(rule))) => router.add_rule(rule),
-                Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err.to_owned())),
+                Ok((_, Some(Line::Rule(rule)))) => {
+                    hasher.update(format!("{}\n", rule).as_bytes());
+                    let mut text = line
+                        .split('#')
+                        .next()
+                        .unwrap_or_default()
+                        .trim()
+                        .to_string();
+                    if let Some(RuleAuth {
+                        username,
+                        password: Secret::Plain(password),
+                    }) = &rule.auth
+                    {
+                        let auth = format!("{}:{}", username, password);
+                        text = text.replace(&auth, &format!("{}:***", username));
+                    }
+                    router.add_rule(rule, text.into()).map_err(|err| {
+                        let msg = format!("{}:{}: {}", name, line_no + 1, err);
+                        io::Error::new(err.kind(), msg)
+                    })?
+                }
+                Ok((_, Some(Line::AutoCap(rule)))) => {
+                    hasher.update(format!("{}\n", rule).as_bytes());
+                    router.auto_caps.push(rule)
+                }
+                Ok((_, Some(Line::SrcLimit(rule)))) => {
+                    hasher.update(format!("{}\n", rule).as_bytes());
+                    router.src_limits.push(rule)
+                }
+                Err(_) => {
+                    let (offset, hint) = parser::diagnose(&line);
+                    let col = line[..offset].chars().count() + 1;
+                    let line = mask_secret(&line);
+                    let msg = parser::annotate_error(name, line_no + 1, col, &line, hint);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+                }
             }
         }
+        router.structural_hash = hasher.finish_hex();
         Ok(router)
     }
 
-    pub fn load_from_file<T: AsRef<Path>>(path: T) -> io::Result<Self> {
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
-        let this = Self::load(reader)?;
-        info!("policy: {} rule(s) loaded", this.rule_count());
-        Ok(this)
+    /// SHA-256 of the rules in canonical form, stay the same across
+    /// changes in comments, spaces, and case. Plain passwords are left
+    /// out so as not to be guessed from it. Empty if not loaded.
+    pub fn structural_hash(&self) -> &str {
+        &self.structural_hash
+    }
+
+    /// SHA-256 of the file exactly as parsed by `load_from_file()`. Empty
+    /// if not loaded from a file.
+    pub fn file_hash(&self) -> &str {
+        &self.file_hash
     }
 
-    fn add_rule(&mut self, rule: parser::Rule) {
-        let Rule { filter, action } = rule;
+    fn add_rule(&mut self, rule: parser::Rule, text: SharedStr) -> io::Result<()> {
+        let Rule {
+            filter,
+            mut action,
+            auth,
+        } = rule;
+        if let Some(RuleAuth { username, password }) = auth {
+            let password = password.resolve()?;
+            action.upstream_auth = Some(UserPassAuthCredential::new(username, password));
+        }
+        if action.is_prefer_only() {
+            action.prefer_rules = vec![self.rules.len()];
+        } else {
+            action.rules = vec![self.rules.len()];
+        }
+        self.rules.push(RuleHits {
+            rule: text,
+            hits: Default::default(),
+            last_hit: Default::default(),
+        });
         match filter {
             Filter::Default => self.default_action.extend(action),
             Filter::ListenPort(port) => {
                 self.listen_port_ruleset.add(port, action);
             }
-            Filter::DstSni(parts) => {
-                self.dst_domain_ruleset.add(parts.to_shared_str(), action);
+            Filter::DstSni(name, mode) => {
+                self.dst_domain_ruleset
+                    .add(name.to_shared_str(), mode, action);
             }
             Filter::DstIp((IpAddr::V4(ip), len)) => {
                 self.dst_ipv4_ruleset.add((ip, len), action);
@@ -202,13 +448,36 @@ impl Policy {
                 self.dst_ipv6_ruleset.add((ip, len), action);
             }
         }
+        Ok(())
+    }
+
+    /// Ports referenced by `listen port` rules, sorted.
+    pub fn listen_ports(&self) -> Vec<u16> {
+        let mut ports: Vec<_> = self.listen_port_ruleset.0.keys().copied().collect();
+        ports.sort_unstable();
+        ports
+    }
+
+    /// Rules of `auto capability`, which are evaluated by `Monitor`.
+    pub fn auto_capabilities(&self) -> &[AutoCapRule] {
+        &self.auto_caps
+    }
+
+    /// Max concurrent connections from `ip` by `src ip ... limit N`
+    /// rules, the longest prefix matched wins, then the last one.
+    pub fn src_ip_limit(&self, ip: IpAddr) -> Option<usize> {
+        self.src_limits
+            .iter()
+            .filter(|rule| prefix_contains(rule.net, ip))
+            .max_by_key(|rule| rule.net.1)
+            .map(|rule| rule.limit)
     }
 
     pub fn rule_count(&self) -> usize {
         self.listen_port_ruleset
             .0
             .values()
-            .chain(self.dst_domain_ruleset.0.values())
+            .chain(self.dst_domain_ruleset.actions())
             .chain(self.dst_ipv4_ruleset.actions())
             .chain(self.dst_ipv6_ruleset.actions())
             .fold(0, |acc, v| acc + v.len())
@@ -248,8 +517,109 @@ impl Policy {
                 .get_recursive(name.as_ref())
                 .for_each(|a| action.extend(a.clone()));
         }
+
+        let now = SystemTime::now();
+        let overlays: Vec<_> = self
+            .maintenance
+            .iter()
+            .filter(|m| m.is_active(now))
+            .collect();
+        if let Some(overlay) = maintenance::apply(&overlays, &action.action) {
+            action.action = overlay;
+        }
+
+        let now = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|t| t.as_secs())
+            .unwrap_or_default();
+        for rule in action.rules().iter().filter_map(|n| self.rules.get(*n)) {
+            rule.hits.fetch_add(1, Ordering::Relaxed);
+            rule.last_hit.store(now, Ordering::Relaxed);
+        }
         action
     }
+
+    /// Return the text of n-th rule.
+    pub fn rule_text(&self, n: usize) -> Option<&str> {
+        self.rules.get(n).map(|r| r.rule.as_str())
+    }
+
+    /// Return hit counters of all rules, in the order they are loaded.
+    pub fn rule_stats(&self) -> Vec<RuleStats> {
+        self.rules
+            .iter()
+            .map(|r| RuleStats {
+                rule: r.rule.clone(),
+                hits: r.hits.load(Ordering::Relaxed),
+                last_hit: match r.last_hit.load(Ordering::Relaxed) {
+                    0 => None,
+                    t => Some(t),
+                },
+            })
+            .collect()
+    }
+
+    /// Reject or direct requests requiring `maintenance.caps` until it
+    /// expires, regardless of rules.
+    pub fn add_maintenance(&mut self, maintenance: Maintenance) {
+        let now = SystemTime::now();
+        self.maintenance.retain(|m| m.is_active(now));
+        self.maintenance.push(maintenance);
+    }
+
+    /// Return non-expired ones of `add_maintenance()`.
+    pub fn maintenance(&self) -> Vec<Maintenance> {
+        let now = SystemTime::now();
+        self.maintenance
+            .iter()
+            .filter(|m| m.is_active(now))
+            .cloned()
+            .collect()
+    }
+
+    /// Remove all of `add_maintenance()`, return non-expired ones.
+    pub fn clear_maintenance(&mut self) -> Vec<Maintenance> {
+        let removed = self.maintenance();
+        self.maintenance.clear();
+        removed
+    }
+
+    /// Move `add_maintenance()` of `old` into this, on reloading.
+    pub fn inherit_maintenance(&mut self, old: &mut Policy) {
+        self.maintenance.append(&mut old.maintenance);
+    }
+
+    /// Carry over hit counters from `old` for rules with the same text.
+    pub fn inherit_stats(&self, old: &Policy) {
+        let old: HashMap<_, _> = old.rules.iter().map(|r| (&r.rule, r)).collect();
+        for rule in &self.rules {
+            if let Some(old) = old.get(&rule.rule) {
+                let hits = old.hits.load(Ordering::Relaxed);
+                rule.hits.store(hits, Ordering::Relaxed);
+                let last_hit = old.last_hit.load(Ordering::Relaxed);
+                rule.last_hit.store(last_hit, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Whether `ip` is in the network `prefix`, IPv4-mapped IPv6 as IPv4.
+pub fn prefix_contains((net, len): (IpAddr, u8), ip: IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    };
+    match (net, ip) {
+        (IpAddr::V4(net), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - len as u32).unwrap_or(0);
+            u32::from(net) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(net), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - len as u32).unwrap_or(0);
+            u128::from(net) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
 }
 
 impl Display for Action {
@@ -273,10 +643,51 @@ impl Display for Action {
                 write!(f, " AND {}", cap)?;
             }
         }
+        let mut prefer = Vec::from_iter(&self.prefer);
+        prefer.sort_unstable();
+        for (i, cap) in prefer.iter().enumerate() {
+            match i {
+                0 => write!(f, " PREFER {}", cap)?,
+                _ => write!(f, " AND {}", cap)?,
+            }
+        }
+        if let Some(timeout) = self.timeout {
+            write!(f, " TIMEOUT {}ms", timeout.as_millis())?;
+        }
+        if self.prefer_non_bulk {
+            write!(f, " PREFER-NON-BULK")?;
+        }
+        match self.prefer_family {
+            Some(IpFamily::V4) => write!(f, " PREFER-IPV4")?,
+            Some(IpFamily::V6) => write!(f, " PREFER-IPV6")?,
+            None => (),
+        }
+        if let Some(auth) = &self.upstream_auth {
+            write!(f, " WITH-AUTH {}", auth.username())?;
+        }
         Ok(())
     }
 }
 
+/// Replace the password of `with-auth USER:PASS` in `line` with `*`s,
+/// keeping the length so that columns stay the same.
+fn mask_secret(line: &str) -> String {
+    let lower = line.to_ascii_lowercase();
+    let start = match lower.find("with-auth") {
+        Some(at) => at + "with-auth".len(),
+        None => return line.to_string(),
+    };
+    let start = match line[start..].find(':') {
+        Some(at) => start + at + 1,
+        None => return line.to_string(),
+    };
+    let end = line[start..]
+        .find(char::is_whitespace)
+        .map_or(line.len(), |at| start + at);
+    let stars = "*".repeat(line[start..end].chars().count());
+    format!("{}{}{}", &line[..start], stars, &line[end..])
+}
+
 #[test]
 fn test_policy_listen_port() {
     use capabilities::CheckAllCapsMeet;
@@ -288,8 +699,10 @@ fn test_policy_listen_port() {
     ";
     let policy = Policy::load(rules.as_bytes()).unwrap();
     assert_eq!(3, policy.rule_count());
-    let mut features: RequestFeatures<&'static str> = Default::default();
-    features.listen_port = Some(1);
+    let mut features = RequestFeatures::<&'static str> {
+        listen_port: Some(1),
+        ..Default::default()
+    };
     let p1 = match policy.matches(&features).action {
         ActionType::Require(a) => a,
         _ => panic!(),
@@ -323,10 +736,11 @@ fn test_policy_dst_ip() {
     let policy = Policy::load(rules.as_bytes()).unwrap();
     assert_eq!(4, policy.rule_count());
 
-    let mut features: RequestFeatures<&str> = Default::default();
-
     // Match 0.0.0.0/0
-    features.dst_ip = IpAddr::from_str("1.2.3.4").ok();
+    let mut features = RequestFeatures::<&str> {
+        dst_ip: IpAddr::from_str("1.2.3.4").ok(),
+        ..Default::default()
+    };
     let action = policy.matches(&features).action;
     assert!(matches!(action, ActionType::Require(a) if a.len() == 1));
 
@@ -424,9 +838,10 @@ fn test_policy_action_priority() {
         dst domain a.a.a.a require!! aaaa #same-level append
     ";
     let policy = Policy::load(rules.as_bytes()).unwrap();
-    let mut features: RequestFeatures<&'static str> = Default::default();
-
-    features.listen_port = Some(10);
+    let mut features = RequestFeatures::<&'static str> {
+        listen_port: Some(10),
+        ..Default::default()
+    };
     let def = policy.matches(&features);
     assert!(matches!(&def.action, ActionType::Require(a) if a.len() == 1));
     assert_eq!(1, def.priority);
@@ -456,7 +871,8 @@ fn test_action_type_display() {
         "DIRECT",
         Action {
             action: ActionType::Direct,
-            priority: 0
+            priority: 0,
+            ..Default::default()
         }
         .to_string()
     );
@@ -464,7 +880,8 @@ fn test_action_type_display() {
         "REJECT!!",
         Action {
             action: ActionType::Reject,
-            priority: 2
+            priority: 2,
+            ..Default::default()
         }
         .to_string()
     );
@@ -478,8 +895,398 @@ fn test_action_type_display() {
         "REQUIRE! a AND (b OR c)",
         Action {
             action,
-            priority: 1
+            priority: 1,
+            ..Default::default()
         }
         .to_string()
     );
 }
+
+#[test]
+fn test_policy_rule_stats() {
+    let rules = "
+        default require def
+        listen port 1 require a # comment
+        dst domain test require c
+        dst domain d.test direct
+    ";
+    let policy = Policy::load(rules.as_bytes()).unwrap();
+    let action = policy.matches(&RequestFeatures {
+        listen_port: Some(1),
+        dst_domain: Some("a.test"),
+        ..Default::default()
+    });
+    let fired: Vec<_> = action
+        .rules()
+        .iter()
+        .map(|n| policy.rule_text(*n).unwrap())
+        .collect();
+    assert_eq!(
+        vec![
+            "default require def",
+            "listen port 1 require a",
+            "dst domain test require c"
+        ],
+        fired
+    );
+    // require rules are overrided by direct
+    policy.matches(&RequestFeatures {
+        listen_port: Some(1),
+        dst_domain: Some("d.test"),
+        ..Default::default()
+    });
+    let hits: Vec<_> = policy.rule_stats().iter().map(|s| s.hits).collect();
+    assert_eq!(vec![1, 1, 1, 1], hits);
+    assert!(policy.rule_stats()[0].last_hit.is_some());
+
+    let rules = "
+        dst domain d.test direct
+        dst domain new require new
+    ";
+    let new_policy = Policy::load(rules.as_bytes()).unwrap();
+    new_policy.inherit_stats(&policy);
+    let hits: Vec<_> = new_policy.rule_stats().iter().map(|s| s.hits).collect();
+    assert_eq!(vec![1, 0], hits);
+}
+
+#[test]
+fn test_policy_timeout() {
+    let rules = "
+        default require def timeout 5s
+        dst domain far.test require far timeout 30s
+        dst domain internal.test direct timeout 200ms
+        dst domain fast.far.test require! fast
+    ";
+    let policy = Policy::load(rules.as_bytes()).unwrap();
+    let timeout = |domain| {
+        policy
+            .matches(&RequestFeatures {
+                dst_domain: Some(domain),
+                ..Default::default()
+            })
+            .timeout
+    };
+    assert_eq!(Some(Duration::from_secs(5)), timeout("other.test"));
+    assert_eq!(Some(Duration::from_secs(30)), timeout("a.far.test"));
+    assert_eq!(Some(Duration::from_millis(200)), timeout("internal.test"));
+    // Fallback to the overridden one
+    assert_eq!(Some(Duration::from_secs(30)), timeout("fast.far.test"));
+    assert_eq!(
+        None,
+        Policy::default()
+            .matches(&RequestFeatures::<&str>::default())
+            .timeout
+    );
+}
+
+#[test]
+fn test_policy_domain_match_mode() {
+    let rules = "
+        dst domain example.com require suffix
+        dst domain =example.com require exact
+        dst domain *.img.example.com require wildcard
+        dst domain =img.example.com direct
+        dst domain *.example.com reject
+        dst domain =a.example.com require! top
+    ";
+    let policy = Policy::load(rules.as_bytes()).unwrap();
+    assert_eq!(6, policy.rule_count());
+    let matches = |domain| {
+        policy
+            .matches(&RequestFeatures {
+                dst_domain: Some(domain),
+                ..Default::default()
+            })
+            .to_string()
+    };
+    assert_eq!("REQUIRE exact AND suffix", matches("example.com"));
+    assert_eq!("REQUIRE suffix", matches("cdn.a.example.com"));
+    assert_eq!("REQUIRE suffix AND wildcard", matches("x.img.example.com"));
+    assert_eq!("REQUIRE suffix", matches("y.x.img.example.com"));
+    // Exact overrides wildcard on the same domain
+    assert_eq!("DIRECT", matches("img.example.com"));
+    assert_eq!("REJECT", matches("b.example.com"));
+    // Priority overrides all
+    assert_eq!("REQUIRE! top", matches("a.example.com"));
+    assert_eq!("REQUIRE", &matches("other.com")[..7]);
+}
+
+#[test]
+fn test_policy_prefer_non_bulk() {
+    let rules = "
+        default require def
+        listen port 1 require a prefer-non-bulk
+        listen port 1 require b timeout 5s PREFER-NON-BULK
+        dst domain video.test require! video timeout 60s
+    ";
+    let policy = Policy::load(rules.as_bytes()).unwrap();
+    let action = |port, domain| {
+        policy.matches(&RequestFeatures {
+            listen_port: Some(port),
+            dst_domain: Some(domain),
+            ..Default::default()
+        })
+    };
+    assert!(action(1, "other.test").prefer_non_bulk);
+    assert_eq!(
+        Some(Duration::from_secs(5)),
+        action(1, "other.test").timeout
+    );
+    assert!(action(1, "video.test").prefer_non_bulk);
+    assert!(!action(2, "other.test").prefer_non_bulk);
+}
+
+#[test]
+fn test_policy_prefer_family() {
+    let rules = "
+        default require def prefer-ipv4
+        dst domain v6.test direct prefer-ipv6
+        dst domain a.v6.test require a timeout 5s prefer-non-bulk PREFER-IPV4
+        dst domain b.v6.test direct
+    ";
+    let policy = Policy::load(rules.as_bytes()).unwrap();
+    let action = |domain| {
+        policy.matches(&RequestFeatures {
+            dst_domain: Some(domain),
+            ..Default::default()
+        })
+    };
+    assert_eq!(Some(IpFamily::V4), action("other.test").prefer_family);
+    assert_eq!(Some(IpFamily::V6), action("v6.test").prefer_family);
+    assert_eq!(Some(IpFamily::V4), action("a.v6.test").prefer_family);
+    // Inherited from the less specific rule
+    assert_eq!(Some(IpFamily::V6), action("b.v6.test").prefer_family);
+    assert!(action("v6.test").to_string().ends_with("PREFER-IPV6"));
+    assert!(Policy::load("default direct prefer-ipv5".as_bytes()).is_err());
+}
+
+#[test]
+fn test_policy_with_auth() {
+    std::env::set_var("MOPROXY_TEST_EXIT_B_PASS", "pass2");
+    let rules = "
+        default require exit
+        dst domain a.test require exit-a with-auth user1:pass1
+        dst domain b.test require exit-b with-auth user2:env:MOPROXY_TEST_EXIT_B_PASS
+        dst domain c.b.test require exit-c
+    ";
+    let policy = Policy::load(rules.as_bytes()).unwrap();
+    let action = |domain| {
+        policy.matches(&RequestFeatures {
+            dst_domain: Some(domain),
+            ..Default::default()
+        })
+    };
+    let auth = |domain| action(domain).upstream_auth;
+    assert_eq!(
+        Some(UserPassAuthCredential::new("user1", "pass1")),
+        auth("a.test")
+    );
+    assert_eq!(
+        Some(UserPassAuthCredential::new("user2", "pass2")),
+        auth("b.test")
+    );
+    // Inherited from the less specific rule
+    assert_eq!(
+        Some(UserPassAuthCredential::new("user2", "pass2")),
+        auth("c.b.test")
+    );
+    assert_eq!(None, auth("other.test"));
+    assert!(action("a.test").to_string().ends_with("WITH-AUTH user1"));
+
+    // Password is not kept in the rule text
+    let texts: Vec<_> = policy.rule_stats().into_iter().map(|r| r.rule).collect();
+    assert!(texts.iter().all(|t| !t.contains("pass1")));
+    assert!(texts.iter().any(|t| t.contains("with-auth user1:***")));
+
+    let rules = "default require a with-auth u:env:MOPROXY_TEST_NO_SUCH_VAR";
+    assert!(Policy::load(rules.as_bytes()).is_err());
+}
+
+#[test]
+fn test_policy_prefer() {
+    let rules = "
+        default require def
+        default prefer cheap
+        dst domain test require! a
+        dst domain a.test prefer fast or near
+        dst domain b.test direct!
+    ";
+    let policy = Policy::load(rules.as_bytes()).unwrap();
+    let action = |domain| {
+        policy.matches(&RequestFeatures {
+            dst_domain: Some(domain),
+            ..Default::default()
+        })
+    };
+    let cap = |caps: &[&'static str]| CapSet::new(caps.iter().copied());
+
+    let def = action("other");
+    assert!(matches!(&def.action, ActionType::Require(a) if a.len() == 1));
+    assert_eq!(HashSet::from([cap(&["cheap"])]), def.prefer);
+    assert_eq!("REQUIRE def PREFER cheap", def.to_string());
+
+    // Composed with higher-priority require
+    let a = action("a.test");
+    assert!(matches!(&a.action, ActionType::Require(c) if c == &HashSet::from([cap(&["a"])])));
+    assert_eq!(2, a.prefer.len());
+    assert_eq!("REQUIRE! a PREFER cheap AND (fast OR near)", a.to_string());
+    assert_eq!(vec![1, 2, 3], a.rules());
+    assert_eq!(
+        Some("dst domain a.test prefer fast or near"),
+        policy.rule_text(3)
+    );
+
+    assert_eq!(ActionType::Direct, action("b.test").action);
+}
+
+#[test]
+fn test_policy_maintenance() {
+    use maintenance::MaintenanceAction;
+
+    let rules = "
+        default require provider-y
+        dst domain x.test require! provider-x
+        dst domain any.test require provider-x or provider-y
+        dst domain direct.test direct
+    ";
+    let mut policy = Policy::load(rules.as_bytes()).unwrap();
+    let action = |policy: &Policy, domain| {
+        policy
+            .matches(&RequestFeatures {
+                dst_domain: Some(domain),
+                ..Default::default()
+            })
+            .action
+    };
+    let maintenance = |caps: &[&str], action, until| Maintenance {
+        caps: caps.iter().map(|c| c.to_string()).collect(),
+        action,
+        until,
+    };
+    let now = SystemTime::now();
+    let hour = Duration::from_secs(3600);
+
+    // Expired ones take no effect
+    policy.add_maintenance(maintenance(
+        &["provider-x"],
+        MaintenanceAction::Reject,
+        now - hour,
+    ));
+    assert!(matches!(action(&policy, "x.test"), ActionType::Require(_)));
+    assert!(policy.maintenance().is_empty());
+
+    // Precede rules of any priority
+    policy.add_maintenance(maintenance(
+        &["provider-x"],
+        MaintenanceAction::Reject,
+        now + hour,
+    ));
+    assert_eq!(ActionType::Reject, action(&policy, "x.test"));
+    assert!(matches!(
+        action(&policy, "any.test"),
+        ActionType::Require(_)
+    ));
+    assert!(matches!(action(&policy, "other"), ActionType::Require(_)));
+    assert_eq!(ActionType::Direct, action(&policy, "direct.test"));
+
+    policy.add_maintenance(maintenance(
+        &["provider-y"],
+        MaintenanceAction::Direct,
+        now + hour,
+    ));
+    // All alternatives in maintenance, the earliest added wins
+    assert_eq!(ActionType::Reject, action(&policy, "x.test"));
+    assert_eq!(ActionType::Reject, action(&policy, "any.test"));
+    assert_eq!(ActionType::Direct, action(&policy, "other"));
+    assert_eq!(2, policy.maintenance().len());
+
+    // Kept across reloads
+    let mut reloaded = Policy::load(rules.as_bytes()).unwrap();
+    reloaded.inherit_maintenance(&mut policy);
+    assert_eq!(ActionType::Reject, action(&reloaded, "x.test"));
+    assert_eq!(2, reloaded.clear_maintenance().len());
+    assert!(matches!(
+        action(&reloaded, "x.test"),
+        ActionType::Require(_)
+    ));
+}
+
+#[test]
+fn test_policy_syntax_error() {
+    let rules = "default direct\n\ndst domain example.com requir a\n";
+    let err = Policy::load(rules.as_bytes()).err().unwrap().to_string();
+    assert!(err.starts_with("<input>:3:24: unknown action, expected require/"));
+    assert!(err.contains("3 | dst domain example.com requir a\n"));
+    assert!(err.ends_with(&format!("  | {}^", " ".repeat(23))));
+
+    let rules = "dst ip 10.0.0.0/8 direct with-auth u:secret1 bogus";
+    let err = Policy::load(rules.as_bytes()).err().unwrap().to_string();
+    assert!(err.contains(":1:46: unknown effect"));
+    assert!(!err.contains("secret1"));
+    assert!(err.contains("with-auth u:******* bogus"));
+
+    let rules = "default direct\ndefault require a with-auth u:env:MOPROXY_TEST_NO_SUCH_VAR";
+    let err = Policy::load(rules.as_bytes()).err().unwrap().to_string();
+    assert!(err.starts_with("<input>:2: "));
+}
+
+#[test]
+fn test_structural_hash() {
+    let hash = |rules: &str| Policy::load(rules.as_bytes()).unwrap().structural_hash;
+    let rules = "dst domain example.com require a or b\nlisten port 1080 direct\n";
+    let base = hash(rules);
+    assert_eq!(64, base.len());
+    assert_eq!(
+        base,
+        hash(
+            "# Comment\n\n  DST DOMAIN Example.com.   require b OR a # a or b\n\
+            listen port\t1080 direct\n"
+        )
+    );
+    assert_ne!(
+        base,
+        hash("dst domain example.com require a\nlisten port 1080 direct\n")
+    );
+    assert_ne!(
+        base,
+        hash("listen port 1080 direct\ndst domain example.com require a or b\n")
+    );
+    // Passwords never hashed, nor leaked via the hash
+    assert_eq!(
+        hash("default require a with-auth u:pass1"),
+        hash("default require a with-auth u:pass2")
+    );
+    assert_ne!(
+        hash("default require a with-auth u:pass1"),
+        hash("default require a with-auth v:pass1")
+    );
+    assert_eq!("", Policy::default().structural_hash());
+}
+
+#[test]
+fn test_src_ip_limit() {
+    let rules = "src ip 10.0.0.0/8 limit 10\nsrc ip 10.0.5.0/24 limit 100\n\
+        src ip 10.0.0.0/8 limit 20\ndefault direct\n";
+    let policy = Policy::load(rules.as_bytes()).unwrap();
+    let limit = |ip: &str| policy.src_ip_limit(ip.parse().unwrap());
+    assert_eq!(Some(100), limit("10.0.5.1"));
+    assert_eq!(Some(20), limit("10.1.0.1"));
+    assert_eq!(Some(20), limit("::ffff:10.1.0.1"));
+    assert_eq!(None, limit("192.0.2.1"));
+}
+
+#[test]
+fn test_rule_count_cap_expression() {
+    let count = |rules: &str| Policy::load(rules.as_bytes()).unwrap().rule_count();
+    // Same as written in separate rules
+    assert_eq!(2, count("listen port 1 require (a or b) and c\n"));
+    assert_eq!(
+        2,
+        count("listen port 1 require a or b\nlisten port 1 require c\n")
+    );
+    assert_eq!(
+        2,
+        count("listen port 1 require (a or b) and c\nlisten port 1 require c\n")
+    );
+    assert_eq!(2, count("listen port 1 require a or b and c\n"));
+}
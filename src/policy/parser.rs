@@ -1,27 +1,41 @@
 use std::{
     collections::HashSet,
+    fmt, io,
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
     str::FromStr,
+    time::Duration,
 };
 
 use flexstr::{shared_str, SharedStr, ToCase};
 use nom::{
     branch::alt,
     bytes::complete::{tag, tag_no_case, take_till1},
-    character::complete::{char, hex_digit1, not_line_ending, space0, space1, u16, u8},
-    combinator::{eof, fail, opt, recognize, verify},
+    character::complete::{char, hex_digit1, i32, not_line_ending, space0, space1, u16, u32, u8},
+    combinator::{all_consuming, eof, fail, map_res, opt, recognize, verify},
     multi::{many0_count, many1, many_m_n, separated_list0, separated_list1},
     sequence::tuple,
     IResult, Parser,
 };
 
 use super::{capabilities::CapSet, Action, ActionType};
+use crate::{duration::parse_duration, proxy::IpFamily};
+
+/// How `dst domain` rules match the domain name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DomainMatch {
+    /// `example.com` matches itself and all its subdomains.
+    Suffix,
+    /// `=example.com` matches itself only.
+    Exact,
+    /// `*.example.com` matches its direct subdomains only.
+    Wildcard,
+}
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Filter {
     Default,
     ListenPort(u16),
-    DstSni(SharedStr),
+    DstSni(SharedStr, DomainMatch),
     DstIp((IpAddr, u8)),
 }
 
@@ -29,6 +43,169 @@ pub enum Filter {
 pub struct Rule {
     pub filter: Filter,
     pub action: Action,
+    /// Credential for upstream proxies, resolved on `Policy::load()`.
+    pub auth: Option<RuleAuth>,
+}
+
+/// `with-auth <username>:<password>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleAuth {
+    pub username: SharedStr,
+    pub password: Secret,
+}
+
+/// A secret given as is, or `env:NAME`, or `file:PATH`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Secret {
+    Plain(SharedStr),
+    Env(SharedStr),
+    File(SharedStr),
+}
+
+impl Secret {
+    pub fn resolve(&self) -> io::Result<SharedStr> {
+        match self {
+            Self::Plain(s) => Ok(s.clone()),
+            Self::Env(name) => std::env::var(name.as_str()).map(Into::into).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("environment variable {} not set", name),
+                )
+            }),
+            Self::File(path) => Ok(std::fs::read_to_string(path.as_str())?
+                .trim_end_matches(['\r', '\n'])
+                .into()),
+        }
+    }
+}
+
+/// Predicate on the score of a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScoreBound {
+    Below(i32),
+    Above(i32),
+}
+
+/// `auto capability <cap> if score < N`, see `Monitor::set_auto_capabilities()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AutoCapRule {
+    pub cap: SharedStr,
+    pub bound: ScoreBound,
+}
+
+/// `src ip <prefix> limit N`, max concurrent connections from each
+/// address in the network, see `Policy::src_ip_limit()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SrcLimitRule {
+    pub net: (IpAddr, u8),
+    pub limit: usize,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+#[allow(clippy::large_enum_variant)]
+pub enum Line {
+    Rule(Rule),
+    AutoCap(AutoCapRule),
+    SrcLimit(SrcLimitRule),
+}
+
+/// Canonical form of the rule, the same for rules differ only in case
+/// and spaces. Plain passwords are redacted as `<secret>`, while `env:`
+/// and `file:` references are kept.
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.filter {
+            Filter::Default => write!(f, "default"),
+            Filter::ListenPort(port) => write!(f, "listen port {}", port),
+            Filter::DstSni(name, DomainMatch::Suffix) => write!(f, "dst domain {}", name),
+            Filter::DstSni(name, DomainMatch::Exact) => write!(f, "dst domain ={}", name),
+            Filter::DstSni(name, DomainMatch::Wildcard) => write!(f, "dst domain *.{}", name),
+            Filter::DstIp((ip, len)) => write!(f, "dst ip {}/{}", ip, len),
+        }?;
+        let action = &self.action;
+        let priority = "!".repeat(action.priority as usize);
+        let join = |caps: &CapSet| {
+            caps.iter()
+                .map(|c| c.as_str())
+                .collect::<Vec<_>>()
+                .join(" or ")
+        };
+        match &action.action {
+            ActionType::Require(set) if set.is_empty() => {
+                let mut prefer: Vec<_> = action.prefer.iter().collect();
+                prefer.sort();
+                for caps in prefer {
+                    write!(f, " prefer {}", join(caps))?;
+                }
+            }
+            ActionType::Require(set) if set.len() == 1 => {
+                let caps = set.iter().next().unwrap();
+                write!(f, " require{} {}", priority, join(caps))?;
+            }
+            ActionType::Require(set) => {
+                let mut set: Vec<_> = set.iter().collect();
+                set.sort();
+                let clauses: Vec<_> = set
+                    .into_iter()
+                    .map(|caps| match caps.iter().count() {
+                        1 => join(caps),
+                        _ => format!("({})", join(caps)),
+                    })
+                    .collect();
+                write!(f, " require{} {}", priority, clauses.join(" and "))?;
+            }
+            ActionType::Direct => write!(f, " direct{}", priority)?,
+            ActionType::Reject => write!(f, " reject{}", priority)?,
+        }
+        if let Some(timeout) = action.timeout {
+            write!(f, " timeout {}ms", timeout.as_millis())?;
+        }
+        if action.prefer_non_bulk {
+            write!(f, " prefer-non-bulk")?;
+        }
+        match action.prefer_family {
+            Some(IpFamily::V4) => write!(f, " prefer-ipv4")?,
+            Some(IpFamily::V6) => write!(f, " prefer-ipv6")?,
+            None => (),
+        }
+        if let Some(RuleAuth { username, password }) = &self.auth {
+            let password = match password {
+                Secret::Plain(_) => "<secret>".to_string(),
+                Secret::Env(name) => format!("env:{}", name),
+                Secret::File(path) => format!("file:{}", path),
+            };
+            write!(f, " with-auth {}:{}", username, password)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for AutoCapRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (op, n) = match self.bound {
+            ScoreBound::Below(n) => ('<', n),
+            ScoreBound::Above(n) => ('>', n),
+        };
+        write!(f, "auto capability {} if score {} {}", self.cap, op, n)
+    }
+}
+
+impl fmt::Display for SrcLimitRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (ip, len) = self.net;
+        write!(f, "src ip {}/{} limit {}", ip, len, self.limit)
+    }
+}
+
+impl ScoreBound {
+    /// Servers without a score never match.
+    pub fn matches(&self, score: Option<i32>) -> bool {
+        match (self, score) {
+            (_, None) => false,
+            (Self::Below(n), Some(score)) => score < *n,
+            (Self::Above(n), Some(score)) => score > *n,
+        }
+    }
 }
 
 fn port_number(input: &str) -> IResult<&str, u16> {
@@ -63,6 +240,13 @@ fn ip_addr_prefix_len(input: &str) -> IResult<&str, (IpAddr, u8)> {
     }
 }
 
+/// Parse `IP[/LEN]` like `192.0.2.0/24`, as of `dst ip` rules.
+pub fn ip_prefix(input: &str) -> Option<(IpAddr, u8)> {
+    all_consuming(ip_addr_prefix_len)(input)
+        .ok()
+        .map(|(_, prefix)| prefix)
+}
+
 fn id_chars(input: &str) -> IResult<&str, &str> {
     take_till1(|c: char| !c.is_alphanumeric() && c != '-' && c != '_')(input)
 }
@@ -77,19 +261,33 @@ fn domain_name_root(input: &str) -> IResult<&str, ()> {
     char('.').map(|_| ()).parse(input)
 }
 
-fn domain_name(input: &str) -> IResult<&str, SharedStr> {
-    alt((
-        recognize(many1(domain_name_part)).map(|n| {
+fn domain_name_non_root(input: &str) -> IResult<&str, SharedStr> {
+    recognize(many1(domain_name_part))
+        .map(|n| {
             match n.strip_suffix('.') {
                 Some(n) => SharedStr::from(n),
                 None => n.into(),
             }
             .to_lower()
-        }),
+        })
+        .parse(input)
+}
+
+fn domain_name(input: &str) -> IResult<&str, SharedStr> {
+    alt((
+        domain_name_non_root,
         domain_name_root.map(|_| shared_str!(".")),
     ))(input)
 }
 
+fn domain_name_with_mode(input: &str) -> IResult<&str, (SharedStr, DomainMatch)> {
+    alt((
+        tuple((char('='), domain_name_non_root)).map(|(_, n)| (n, DomainMatch::Exact)),
+        tuple((tag("*."), domain_name_non_root)).map(|(_, n)| (n, DomainMatch::Wildcard)),
+        domain_name.map(|n| (n, DomainMatch::Suffix)),
+    ))(input)
+}
+
 fn filter_dst_ip(input: &str) -> IResult<&str, Filter> {
     tuple((tag_no_case("dst ip"), space1, ip_addr_prefix_len))
         .map(|(_, _, net)| Filter::DstIp(net))
@@ -97,8 +295,8 @@ fn filter_dst_ip(input: &str) -> IResult<&str, Filter> {
 }
 
 fn filter_dst_domain(input: &str) -> IResult<&str, Filter> {
-    tuple((tag_no_case("dst domain"), space1, domain_name))
-        .map(|(_, _, parts)| Filter::DstSni(parts))
+    tuple((tag_no_case("dst domain"), space1, domain_name_with_mode))
+        .map(|(_, _, (name, mode))| Filter::DstSni(name, mode))
         .parse(input)
 }
 
@@ -125,10 +323,98 @@ fn cap_name(input: &str) -> IResult<&str, SharedStr> {
     id_chars.map(SharedStr::from).parse(input)
 }
 
+/// Whether `name` is a single valid capability name.
+pub fn is_cap_name(name: &str) -> bool {
+    matches!(id_chars(name), Ok(("", _)))
+}
+
 fn caps1(input: &str) -> IResult<&str, Vec<SharedStr>> {
     separated_list1(tuple((space1, tag_no_case("or"), space1)), cap_name)(input)
 }
 
+/// Capabilities in conjunctive normal form, i.e. all clauses must be met
+/// and any capability in a clause meets it.
+type CapClauses = Vec<Vec<SharedStr>>;
+
+/// Max number of clauses an expression of `require` expands to.
+const MAX_CAP_CLAUSES: usize = 64;
+/// Max depth of nested parentheses in an expression of `require`.
+const MAX_CAP_DEPTH: usize = 8;
+
+/// `a or b`, distributed over clauses of both.
+fn or_clauses(a: CapClauses, b: CapClauses) -> CapClauses {
+    a.iter()
+        .flat_map(|x| b.iter().map(move |y| [&x[..], &y[..]].concat()))
+        .collect()
+}
+
+/// Capability expression with `and`, `or`, and parentheses, where `and`
+/// takes precedence over `or`.
+fn cap_expr(depth: usize) -> impl Fn(&str) -> IResult<&str, CapClauses> {
+    move |input| {
+        let (mut rest, mut clauses) = cap_term(depth)(input)?;
+        while let Ok((next, _)) = tuple((space1::<_, ()>, tag_no_case("or"), space1))(rest) {
+            let (next, other) = cap_term(depth)(next)?;
+            clauses = or_clauses(clauses, other);
+            if clauses.len() > MAX_CAP_CLAUSES {
+                return fail(input);
+            }
+            rest = next;
+        }
+        Ok((rest, clauses))
+    }
+}
+
+fn cap_term(depth: usize) -> impl Fn(&str) -> IResult<&str, CapClauses> {
+    move |input| {
+        let (mut rest, mut clauses) = cap_atom(depth)(input)?;
+        while let Ok((next, _)) = tuple((space1::<_, ()>, tag_no_case("and"), space1))(rest) {
+            let (next, other) = cap_atom(depth)(next)?;
+            clauses.extend(other);
+            if clauses.len() > MAX_CAP_CLAUSES {
+                return fail(input);
+            }
+            rest = next;
+        }
+        Ok((rest, clauses))
+    }
+}
+
+fn cap_atom(depth: usize) -> impl Fn(&str) -> IResult<&str, CapClauses> {
+    move |input| {
+        if input.starts_with('(') {
+            if depth >= MAX_CAP_DEPTH {
+                return fail(input);
+            }
+            tuple((char('('), space0, cap_expr(depth + 1), space0, char(')')))
+                .map(|(_, _, clauses, _, _)| clauses)
+                .parse(input)
+        } else {
+            cap_name.map(|cap| vec![vec![cap]]).parse(input)
+        }
+    }
+}
+
+/// Clauses into `CapSet`s, without those implied by others.
+fn cap_sets(clauses: CapClauses) -> HashSet<CapSet> {
+    let mut sets: Vec<_> = clauses
+        .into_iter()
+        .map(|mut caps| {
+            caps.sort();
+            caps.dedup();
+            CapSet::new(caps.into_iter())
+        })
+        .collect();
+    sets.sort();
+    sets.dedup();
+    // `a and (a or b)` is just `a`
+    let implied = |set: &CapSet| {
+        sets.iter()
+            .any(|other| other != set && other.iter().all(|cap| set.contains(cap)))
+    };
+    sets.iter().filter(|set| !implied(set)).cloned().collect()
+}
+
 fn action_priority(input: &str) -> IResult<&str, u8> {
     verify(many0_count(tag("!")), |n| *n <= 5)
         .map(|n| n as u8)
@@ -136,12 +422,8 @@ fn action_priority(input: &str) -> IResult<&str, u8> {
 }
 
 fn action_require(input: &str) -> IResult<&str, Action> {
-    tuple((tag_no_case("require"), action_priority, space1, caps1))
-        .map(|(_, priority, _, caps)| {
-            let mut set = HashSet::new();
-            set.insert(CapSet::new(caps.into_iter()));
-            ActionType::Require(set).wrap(priority)
-        })
+    tuple((tag_no_case("require"), action_priority, space1, cap_expr(0)))
+        .map(|(_, priority, _, clauses)| ActionType::Require(cap_sets(clauses)).wrap(priority))
         .parse(input)
 }
 
@@ -157,16 +439,142 @@ fn action_reject(input: &str) -> IResult<&str, Action> {
         .parse(input)
 }
 
+fn action_prefer(input: &str) -> IResult<&str, Action> {
+    tuple((tag_no_case("prefer"), space1, caps1))
+        .map(|(_, _, caps)| Action::prefer(CapSet::new(caps.into_iter())))
+        .parse(input)
+}
+
 fn rule_action(input: &str) -> IResult<&str, Action> {
-    alt((action_require, action_direct, action_reject)).parse(input)
+    alt((action_require, action_direct, action_reject, action_prefer)).parse(input)
+}
+
+/// Accepted range of `timeout` effect.
+const MIN_TIMEOUT: Duration = Duration::from_millis(50);
+const MAX_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Duration with units, e.g. `500ms` or `1m30s`, see `parse_duration()`.
+fn duration(input: &str) -> IResult<&str, Duration> {
+    map_res(
+        take_till1(|c: char| c.is_whitespace() || c == '#'),
+        parse_duration,
+    )(input)
+}
+
+fn effect_timeout(input: &str) -> IResult<&str, Duration> {
+    tuple((
+        tag_no_case("timeout"),
+        space1,
+        verify(duration, |t| (MIN_TIMEOUT..=MAX_TIMEOUT).contains(t)),
+    ))
+    .map(|(_, _, t)| t)
+    .parse(input)
+}
+
+fn effect_prefer_non_bulk(input: &str) -> IResult<&str, ()> {
+    tag_no_case("prefer-non-bulk").map(|_| ()).parse(input)
+}
+
+fn effect_prefer_family(input: &str) -> IResult<&str, IpFamily> {
+    alt((
+        tag_no_case("prefer-ipv4").map(|_| IpFamily::V4),
+        tag_no_case("prefer-ipv6").map(|_| IpFamily::V6),
+    ))(input)
+}
+
+fn secret_token(input: &str) -> IResult<&str, &str> {
+    take_till1(|c: char| c.is_whitespace() || c == '#')(input)
+}
+
+fn secret(input: &str) -> IResult<&str, Secret> {
+    alt((
+        tuple((tag("env:"), id_chars)).map(|(_, name)| Secret::Env(name.into())),
+        tuple((tag("file:"), secret_token)).map(|(_, path)| Secret::File(path.into())),
+        secret_token.map(|s| Secret::Plain(s.into())),
+    ))(input)
+}
+
+fn effect_with_auth(input: &str) -> IResult<&str, RuleAuth> {
+    let username = take_till1(|c: char| c == ':' || c.is_whitespace());
+    tuple((
+        tag_no_case("with-auth"),
+        space1,
+        username,
+        char(':'),
+        secret,
+    ))
+    .map(|(_, _, username, _, password)| RuleAuth {
+        username: username.into(),
+        password,
+    })
+    .parse(input)
 }
 
 fn rule(input: &str) -> IResult<&str, Rule> {
-    tuple((rule_filter, space1, rule_action))
-        .map(|(filter, _, action)| Rule { filter, action })
+    tuple((
+        rule_filter,
+        space1,
+        rule_action,
+        opt(tuple((space1, effect_timeout))),
+        opt(tuple((space1, effect_prefer_non_bulk))),
+        opt(tuple((space1, effect_prefer_family))),
+        opt(tuple((space1, effect_with_auth))),
+    ))
+    .map(
+        |(filter, _, mut action, timeout, prefer_non_bulk, family, auth)| {
+            action.timeout = timeout.map(|(_, t)| t);
+            action.prefer_non_bulk = prefer_non_bulk.is_some();
+            action.prefer_family = family.map(|(_, family)| family);
+            Rule {
+                filter,
+                action,
+                auth: auth.map(|(_, auth)| auth),
+            }
+        },
+    )
+    .parse(input)
+}
+
+fn score_bound(input: &str) -> IResult<&str, ScoreBound> {
+    let below = tuple((char('<'), space0, i32)).map(|(_, _, n)| ScoreBound::Below(n));
+    let above = tuple((char('>'), space0, i32)).map(|(_, _, n)| ScoreBound::Above(n));
+    tuple((tag_no_case("score"), space0, alt((below, above))))
+        .map(|(_, _, bound)| bound)
         .parse(input)
 }
 
+fn auto_cap_rule(input: &str) -> IResult<&str, AutoCapRule> {
+    tuple((
+        tag_no_case("auto capability"),
+        space1,
+        cap_name,
+        space1,
+        tag_no_case("if"),
+        space1,
+        score_bound,
+    ))
+    .map(|(_, _, cap, _, _, _, bound)| AutoCapRule { cap, bound })
+    .parse(input)
+}
+
+fn src_limit_rule(input: &str) -> IResult<&str, SrcLimitRule> {
+    tuple((
+        tag_no_case("src ip"),
+        space1,
+        ip_addr_prefix_len,
+        space1,
+        tag_no_case("limit"),
+        space1,
+        // Zero would refuse all, rejected as by --max-conn-per-ip
+        verify(u32, |&n| n != 0),
+    ))
+    .map(|(_, _, net, _, _, _, limit)| SrcLimitRule {
+        net,
+        limit: limit as usize,
+    })
+    .parse(input)
+}
+
 fn comment(input: &str) -> IResult<&str, ()> {
     tuple((char('#'), not_line_ending)).map(|_| ()).parse(input)
 }
@@ -180,14 +588,82 @@ pub fn capabilities(input: &str) -> IResult<&str, CapSet> {
     .parse(input)
 }
 
-pub fn line_no_ending(input: &str) -> IResult<&str, Option<Rule>> {
+pub fn line_no_ending(input: &str) -> IResult<&str, Option<Line>> {
+    let line = alt((
+        auto_cap_rule.map(Line::AutoCap),
+        src_limit_rule.map(Line::SrcLimit),
+        rule.map(Line::Rule),
+    ));
     alt((
         tuple((space0, opt(comment), space0, eof)).map(|_| None),
-        tuple((space0, rule, space0, opt(comment), eof)).map(|(_, rule, _, _, _)| Some(rule)),
+        tuple((space0, line, space0, opt(comment), eof)).map(|(_, line, _, _, _)| Some(line)),
     ))
     .parse(input)
 }
 
+/// Locate where `input`, a line failed `line_no_ending()`, goes wrong.
+/// Return the byte offset into `input` and a hint for the mistake.
+pub fn diagnose(input: &str) -> (usize, &'static str) {
+    let offset = |rest: &str| input.len() - rest.trim_start().len();
+    let (line, _) = space0::<_, ()>(input).unwrap_or((input, ""));
+    if tag_no_case::<_, _, ()>("auto")(line).is_ok() {
+        return (
+            offset(line),
+            "invalid auto capability, expected `auto capability CAP if score < N` (or `> N`)",
+        );
+    }
+    if tag_no_case::<_, _, ()>("src")(line).is_ok() {
+        return (
+            offset(line),
+            "invalid source limit, expected `src ip CIDR limit N` (N > 0)",
+        );
+    }
+    let rest = match tuple((rule_filter, space1))(line) {
+        Ok((rest, _)) => rest,
+        Err(_) => {
+            return (
+                offset(line),
+                "unknown filter, expected listen port/dst ip/dst domain/default",
+            )
+        }
+    };
+    let rest = match rule_action(rest) {
+        Ok((rest, _)) => rest,
+        Err(_) if tag_no_case::<_, _, ()>("require")(rest).is_ok() => {
+            return (
+                offset(rest),
+                "invalid capabilities, expected `require a or b` or `require (a or b) and c`",
+            )
+        }
+        Err(_) => {
+            return (
+                offset(rest),
+                "unknown action, expected require/direct/reject/prefer",
+            )
+        }
+    };
+    let rest = match rule(line) {
+        Ok((rest, _)) => rest,
+        Err(_) => rest,
+    };
+    (
+        offset(rest),
+        "unknown effect, expected timeout/prefer-non-bulk/prefer-ipv4/prefer-ipv6/with-auth, in that order",
+    )
+}
+
+/// Format a syntax error as `name:line:col: msg`, followed by the
+/// offending line with a caret under the column. `line_no` and `col`
+/// are 1-based.
+pub fn annotate_error(name: &str, line_no: usize, col: usize, line: &str, msg: &str) -> String {
+    let gutter = " ".repeat(line_no.to_string().len());
+    let caret = " ".repeat(col.saturating_sub(1));
+    format!(
+        "{}:{}:{}: {}\n{} |\n{} | {}\n{} | {}^",
+        name, line_no, col, msg, gutter, line_no, line, gutter, caret
+    )
+}
+
 #[test]
 fn test_parse_domain_name_root() {
     let (empty, parts) = domain_name(".").unwrap();
@@ -217,7 +693,23 @@ fn test_listen_port_filter() {
 fn test_dst_domain_filter() {
     let (rem, parts) = filter_dst_domain("dst domain test\n").unwrap();
     assert_eq!("\n", rem);
-    assert_eq!(Filter::DstSni(shared_str!("test")), parts);
+    assert_eq!(
+        Filter::DstSni(shared_str!("test"), DomainMatch::Suffix),
+        parts
+    );
+    let (_, parts) = filter_dst_domain("dst domain =Example.com").unwrap();
+    assert_eq!(
+        Filter::DstSni(shared_str!("example.com"), DomainMatch::Exact),
+        parts
+    );
+    let (_, parts) = filter_dst_domain("dst domain *.img.example.com.").unwrap();
+    assert_eq!(
+        Filter::DstSni(shared_str!("img.example.com"), DomainMatch::Wildcard),
+        parts
+    );
+    assert!(line_no_ending("dst domain =. direct").is_err());
+    assert!(line_no_ending("dst domain *. direct").is_err());
+    assert!(line_no_ending("dst domain *example.com direct").is_err());
 }
 
 #[test]
@@ -273,6 +765,8 @@ fn test_ip_addr_prefix_len() {
     assert_eq!(len, 128);
 
     assert!(ip_addr_prefix_len("0.0.0.0/33").is_err());
+    assert_eq!(Some(([192, 0, 2, 0].into(), 24)), ip_prefix("192.0.2.0/24"));
+    assert_eq!(None, ip_prefix("192.0.2.0/24 "));
     assert!(ip_addr_prefix_len("::/129").is_err());
 }
 
@@ -290,6 +784,80 @@ fn test_action() {
     assert_eq!(ActionType::Reject, action.action);
 }
 
+#[test]
+fn test_cap_expression() {
+    let require = |input: &str| match rule_action(input).unwrap() {
+        (
+            "",
+            Action {
+                action: ActionType::Require(set),
+                ..
+            },
+        ) => {
+            let mut sets: Vec<_> = set
+                .iter()
+                .map(|caps| {
+                    caps.iter()
+                        .map(|c| c.as_str())
+                        .collect::<Vec<_>>()
+                        .join("|")
+                })
+                .collect();
+            sets.sort();
+            sets
+        }
+        (rest, action) => panic!("{:?} left {:?}", action, rest),
+    };
+    assert_eq!(["a|b"], require("require a or b")[..]);
+    assert_eq!(["a|b", "c"], require("require (a or b) and c")[..]);
+    assert_eq!(["a", "b"], require("require a AND b")[..]);
+    // `and` goes first
+    assert_eq!(["a|b", "a|c"], require("require a or b and c")[..]);
+    assert_eq!(["a|c", "b|c"], require("require a and b or c")[..]);
+    // Nested
+    assert_eq!(
+        ["a|b|d", "a|c|d", "e"],
+        require("require ( (a or (b and c)) or d ) and e")[..]
+    );
+    assert_eq!(["a"], require("require (((a)))")[..]);
+    // Simplified
+    assert_eq!(["a"], require("require a and (a or b)")[..]);
+    assert_eq!(
+        ["a|b"],
+        require("require (a or b) and (b or a) and (a or a or b)")[..]
+    );
+    // A capability may still be named so
+    assert_eq!(["and|or"], require("require and or or")[..]);
+
+    for input in [
+        "require (a or b",
+        "require a or b)",
+        "require () and a",
+        "require a and",
+        "require (a or (b and ) c)",
+    ] {
+        assert!(
+            !matches!(rule_action(input), Ok(("", _))),
+            "{} parsed",
+            input
+        );
+    }
+    let deep = format!("require {}a{}", "(".repeat(8), ")".repeat(8));
+    assert_eq!(["a"], require(&deep)[..]);
+    let deep = format!("require {}a{}", "(".repeat(9), ")".repeat(9));
+    assert!(rule_action(&deep).is_err());
+    // Expanded too much
+    let wide = (0..7)
+        .map(|i| format!("(a{} and b{})", i, i))
+        .collect::<Vec<_>>()
+        .join(" or ");
+    assert!(rule_action(&format!("require {}", wide)).is_err());
+
+    let (offset, hint) = diagnose("default require (a or b");
+    assert_eq!(8, offset);
+    assert!(hint.starts_with("invalid capabilities"));
+}
+
 #[test]
 fn test_action_priority() {
     let (_, action) = rule_action("require a").unwrap();
@@ -312,7 +880,8 @@ fn test_rule() {
     assert_eq!(
         Rule {
             filter: Filter::ListenPort(1),
-            action: ActionType::Require(set).wrap(2)
+            action: ActionType::Require(set).wrap(2),
+            auth: None,
         },
         result
     );
@@ -320,6 +889,49 @@ fn test_rule() {
     assert!(rule("default require ! a\n").is_err());
 }
 
+#[test]
+fn test_rule_timeout() {
+    let (_, result) = rule("dst domain test require a timeout 10s\n").unwrap();
+    assert_eq!(Some(Duration::from_secs(10)), result.action.timeout);
+    let (_, result) = rule("dst ip 10.0.0.0/8 direct timeout 500ms").unwrap();
+    assert_eq!(ActionType::Direct, result.action.action);
+    assert_eq!(Some(Duration::from_millis(500)), result.action.timeout);
+    let (_, result) = rule("default require a or b").unwrap();
+    assert_eq!(None, result.action.timeout);
+
+    assert!(line_no_ending("default direct timeout 10ms").is_err());
+    assert!(line_no_ending("default direct timeout 121s").is_err());
+    assert!(line_no_ending("default direct timeout 10").is_err());
+    line_no_ending("default direct timeout 50ms").unwrap();
+    let (_, result) = rule("default direct timeout 1m30s").unwrap();
+    assert_eq!(Some(Duration::from_secs(90)), result.action.timeout);
+    line_no_ending("default direct timeout 120s # far").unwrap();
+}
+
+#[test]
+fn test_rule_with_auth() {
+    let (_, result) = rule("dst domain example.com require exit-a with-auth user1:pass1").unwrap();
+    assert_eq!(
+        Some(RuleAuth {
+            username: "user1".into(),
+            password: Secret::Plain("pass1".into()),
+        }),
+        result.auth
+    );
+    let (_, result) = rule("default require a timeout 5s with-auth u:env:EXIT_PASS").unwrap();
+    assert_eq!(
+        Secret::Env("EXIT_PASS".into()),
+        result.auth.unwrap().password
+    );
+    let (_, result) = rule("default require a with-auth u:file:/run/secret # c").unwrap();
+    assert_eq!(
+        Secret::File("/run/secret".into()),
+        result.auth.unwrap().password
+    );
+    assert!(line_no_ending("default require a with-auth user").is_err());
+    assert!(line_no_ending("default require a with-auth :pass").is_err());
+}
+
 #[test]
 fn test_comment() {
     comment("# test\n").unwrap();
@@ -347,6 +959,42 @@ fn test_line_no_ending_error() {
     assert!(line_no_ending("dst domain require a b").is_err());
 }
 
+#[test]
+fn test_auto_cap_rule() {
+    let (_, rule) = line_no_ending("auto capability fast if score < 150 # x").unwrap();
+    let expected = AutoCapRule {
+        cap: shared_str!("fast"),
+        bound: ScoreBound::Below(150),
+    };
+    assert_eq!(Some(Line::AutoCap(expected)), rule);
+    let (_, rule) = auto_cap_rule("Auto Capability slow if score>-1").unwrap();
+    assert_eq!(ScoreBound::Above(-1), rule.bound);
+    assert!(rule.bound.matches(Some(0)));
+    assert!(!rule.bound.matches(None));
+    assert!(line_no_ending("auto capability fast").is_err());
+    assert!(line_no_ending("auto capability fast if score = 1").is_err());
+}
+
+#[test]
+fn test_src_limit_rule() {
+    let (_, rule) = line_no_ending("src ip 10.0.5.0/24 limit 100 # x").unwrap();
+    let expected = SrcLimitRule {
+        net: ([10, 0, 5, 0].into(), 24),
+        limit: 100,
+    };
+    assert_eq!(Some(Line::SrcLimit(expected)), rule);
+    let (_, rule) = src_limit_rule("SRC IP 2001:db8::1 Limit 1").unwrap();
+    assert_eq!(("2001:db8::1".parse().unwrap(), 128), rule.net);
+    assert_eq!(1, rule.limit);
+    assert!(line_no_ending("src ip 10.0.0.0/8 limit 0").is_err());
+    assert!(line_no_ending("src ip 10.0.0.0/33 limit 1").is_err());
+    assert!(line_no_ending("src ip 10.0.0.0/8 limit").is_err());
+    assert!(line_no_ending("src ip 10.0.0.0/8 limit -1").is_err());
+    assert!(diagnose("src ip 10.0.0.0/8")
+        .1
+        .contains("src ip CIDR limit N"));
+}
+
 #[test]
 fn test_capabilities() {
     let (_, caps) = capabilities("a b  c ").unwrap();
@@ -355,10 +1003,105 @@ fn test_capabilities() {
         caps
     );
     let (_, caps) = capabilities("a, b  ,c,d,").unwrap();
-    assert_eq!(
-        CapSet::new("abcd".chars().into_iter().map(SharedStr::from)),
-        caps
-    );
+    assert_eq!(CapSet::new("abcd".chars().map(SharedStr::from)), caps);
     let (_, caps) = capabilities("  ").unwrap();
     assert!(caps.is_empty());
 }
+
+#[test]
+fn test_action_prefer() {
+    let (_, action) = rule_action("prefer a or b").unwrap();
+    assert!(action.is_prefer_only());
+    assert_eq!(1, action.prefer.len());
+    assert!(rule_action("prefer! a").is_err());
+    let (_, result) = rule("listen port 1 prefer cheap\n").unwrap();
+    assert_eq!(Filter::ListenPort(1), result.filter);
+}
+
+#[test]
+fn test_effect_prefer_family() {
+    let (_, result) = rule("default direct prefer-ipv6").unwrap();
+    assert_eq!(Some(IpFamily::V6), result.action.prefer_family);
+    let (_, result) =
+        rule("listen port 1 require a prefer-non-bulk prefer-ipv4 with-auth u:p").unwrap();
+    assert!(result.action.prefer_non_bulk);
+    assert_eq!(Some(IpFamily::V4), result.action.prefer_family);
+    assert!(result.auth.is_some());
+    let (rest, _) = rule("default direct prefer-ipv4 prefer-ipv6").unwrap();
+    assert_eq!(" prefer-ipv6", rest);
+}
+
+#[test]
+fn test_diagnose() {
+    let hint = |line| diagnose(line);
+    assert_eq!(0, hint("dst foo require a").0);
+    assert!(hint("dst foo require a").1.starts_with("unknown filter"));
+    let (offset, msg) = hint("  default requir a");
+    assert_eq!(10, offset);
+    assert!(msg.starts_with("unknown action"));
+    let (offset, msg) = hint("default direct timeout 1s prefer-non-bulk bogus");
+    assert_eq!(42, offset);
+    assert!(msg.starts_with("unknown effect"));
+    assert!(hint("auto capability a if bogus")
+        .1
+        .contains("auto capability"));
+
+    let text = annotate_error("p.conf", 3, 9, "default requir a", "bad");
+    assert_eq!(
+        "p.conf:3:9: bad\n  |\n3 | default requir a\n  |         ^",
+        text
+    );
+}
+
+#[test]
+fn test_canonical_rule() {
+    let canonical = |line: &str| match line_no_ending(line).unwrap().1.unwrap() {
+        Line::Rule(rule) => {
+            let text = rule.to_string();
+            // Parsed back into the same rule
+            match line_no_ending(&text).unwrap().1.unwrap() {
+                Line::Rule(again) => assert_eq!(rule, again, "{}", text),
+                line => panic!("{:?}", line),
+            }
+            text
+        }
+        Line::AutoCap(rule) => rule.to_string(),
+        Line::SrcLimit(rule) => rule.to_string(),
+    };
+    assert_eq!(
+        "dst domain =example.com require!! a or b timeout 1500ms prefer-ipv6",
+        canonical("DST DOMAIN =Example.COM.\tREQUIRE!! b OR a  timeout 1s500ms prefer-IPv6 # c")
+    );
+    assert_eq!(
+        "dst ip 10.0.0.0/8 prefer a prefer-non-bulk with-auth u:env:PASS",
+        canonical("dst ip 10.0.0.0/8 prefer a prefer-non-bulk with-auth u:env:PASS")
+    );
+    assert_eq!("default direct", canonical("default direct"));
+    // Never round trip, as plain passwords are redacted
+    let (_, result) = rule("default require a with-auth u:pass").unwrap();
+    assert_eq!("default require a with-auth u:<secret>", result.to_string());
+    assert_eq!(
+        "dst domain *.example.com reject!",
+        canonical(" dst domain *.example.com reject!")
+    );
+    assert_eq!(
+        "listen port 1080 require a",
+        canonical("listen port 1080 require a")
+    );
+    assert_eq!(
+        "auto capability slow if score > 100",
+        canonical("auto capability slow if score>100")
+    );
+    assert_eq!(
+        "dst domain example.com require!! (a or b) and c",
+        canonical("dst domain example.com require!! c AND (b or a)")
+    );
+    assert_eq!(
+        "default require (a or b) and (a or c)",
+        canonical("default require a or b and c")
+    );
+    assert_eq!(
+        "src ip 10.0.5.0/24 limit 100",
+        canonical("Src IP 10.0.5.0/24\tlimit 100")
+    );
+}
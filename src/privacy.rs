@@ -0,0 +1,215 @@
+use std::{
+    borrow::Cow,
+    fmt,
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+};
+
+use crate::proxy::{Address, Destination};
+
+/// Second-level suffixes under which registrable domains have three
+/// labels, a small subset of the Public Suffix List.
+const TWO_LABEL_SUFFIXES: &[&str] = &[
+    "ac.uk", "co.uk", "gov.uk", "org.uk", "me.uk", "com.au", "net.au", "org.au", "co.jp", "ne.jp",
+    "or.jp", "ac.jp", "com.cn", "net.cn", "org.cn", "edu.cn", "com.hk", "com.tw", "com.sg",
+    "co.kr", "co.nz", "co.in", "com.br", "com.mx", "co.za", "com.tr", "com.ru",
+];
+
+/// How destination host names appear in logs, metrics, and `/top`, see
+/// `HOSTNAMES`. Never affects routing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HostnamePrivacy {
+    /// Verbatim.
+    #[default]
+    Off,
+    /// Keep only the registrable domain, e.g. `example.co.uk` for
+    /// `www.example.co.uk`.
+    Truncate,
+    /// Replaced with a keyed short hash, e.g. `h-0123456789abcdef`.
+    Hash,
+}
+
+/// Apply `HostnamePrivacy` on host names. IP addresses are kept.
+#[derive(Debug)]
+pub struct HostnameFilter {
+    mode: AtomicU8,
+    key: [AtomicU64; 2],
+}
+
+/// Global filter applied wherever host names are logged or counted.
+pub static HOSTNAMES: HostnameFilter = HostnameFilter::new();
+
+impl HostnameFilter {
+    pub const fn new() -> Self {
+        Self {
+            mode: AtomicU8::new(0),
+            key: [AtomicU64::new(0), AtomicU64::new(0)],
+        }
+    }
+
+    /// Set the mode, with `key` for `HostnamePrivacy::Hash`.
+    pub fn set(&self, mode: HostnamePrivacy, key: &str) {
+        // Derive 128-bit SipHash key from the arbitrary string
+        let k0 = siphash24((0, 0), key.as_bytes());
+        let k1 = siphash24((0, 1), key.as_bytes());
+        self.key[0].store(k0, Ordering::Relaxed);
+        self.key[1].store(k1, Ordering::Relaxed);
+        self.mode.store(mode as u8, Ordering::Relaxed);
+    }
+
+    pub fn mode(&self) -> HostnamePrivacy {
+        match self.mode.load(Ordering::Relaxed) {
+            1 => HostnamePrivacy::Truncate,
+            2 => HostnamePrivacy::Hash,
+            _ => HostnamePrivacy::Off,
+        }
+    }
+
+    /// Domain `name` as it should be shown.
+    pub fn name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        let normalize = || name.trim_end_matches('.').to_lowercase();
+        match self.mode() {
+            HostnamePrivacy::Off => name.into(),
+            HostnamePrivacy::Truncate => registrable_domain(&normalize()).to_string().into(),
+            HostnamePrivacy::Hash => {
+                let key = (
+                    self.key[0].load(Ordering::Relaxed),
+                    self.key[1].load(Ordering::Relaxed),
+                );
+                format!("h-{:016x}", siphash24(key, normalize().as_bytes())).into()
+            }
+        }
+    }
+
+    /// Display `dest` as `host:port` with the host filtered.
+    pub fn dest<'a>(&'a self, dest: &'a Destination) -> FilteredDest<'a> {
+        FilteredDest { filter: self, dest }
+    }
+}
+
+impl Default for HostnameFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// See `HostnameFilter::dest()`.
+pub struct FilteredDest<'a> {
+    filter: &'a HostnameFilter,
+    dest: &'a Destination,
+}
+
+impl fmt::Display for FilteredDest<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.dest.host {
+            Address::Domain(name) => write!(f, "{}:{}", self.filter.name(name), self.dest.port),
+            Address::Ip(_) => write!(f, "{}", self.dest),
+        }
+    }
+}
+
+impl fmt::Debug for FilteredDest<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// The last two labels of `name`, or three under `TWO_LABEL_SUFFIXES`.
+fn registrable_domain(name: &str) -> &str {
+    let start_of = |labels: usize| {
+        name.rmatch_indices('.')
+            .nth(labels - 1)
+            .map_or(0, |(i, _)| i + 1)
+    };
+    let two = &name[start_of(2)..];
+    if TWO_LABEL_SUFFIXES.contains(&two) {
+        &name[start_of(3)..]
+    } else {
+        two
+    }
+}
+
+/// SipHash-2-4, stable across Rust versions unlike `DefaultHasher`.
+fn siphash24((k0, k1): (u64, u64), data: &[u8]) -> u64 {
+    let mut v = [
+        k0 ^ 0x736f6d6570736575,
+        k1 ^ 0x646f72616e646f6d,
+        k0 ^ 0x6c7967656e657261,
+        k1 ^ 0x7465646279746573,
+    ];
+    let round = |v: &mut [u64; 4]| {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    };
+    let compress = |v: &mut [u64; 4], m: u64| {
+        v[3] ^= m;
+        round(v);
+        round(v);
+        v[0] ^= m;
+    };
+    let chunks = data.chunks_exact(8);
+    let rest = chunks.remainder();
+    for chunk in chunks {
+        compress(&mut v, u64::from_le_bytes(chunk.try_into().unwrap()));
+    }
+    let mut last = [0u8; 8];
+    last[..rest.len()].copy_from_slice(rest);
+    last[7] = data.len() as u8;
+    compress(&mut v, u64::from_le_bytes(last));
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+#[test]
+fn test_siphash24() {
+    // Test vector from the SipHash paper
+    let key = (0x0706050403020100, 0x0f0e0d0c0b0a0908);
+    let data: Vec<u8> = (0..15).collect();
+    assert_eq!(0xa129ca6149be45e5, siphash24(key, &data));
+}
+
+#[test]
+fn test_registrable_domain() {
+    assert_eq!("example.com", registrable_domain("a.b.example.com"));
+    assert_eq!("example.com", registrable_domain("example.com"));
+    assert_eq!("localhost", registrable_domain("localhost"));
+    assert_eq!("example.co.uk", registrable_domain("www.example.co.uk"));
+    assert_eq!("co.uk", registrable_domain("co.uk"));
+}
+
+#[test]
+fn test_hostname_filter() {
+    let filter = HostnameFilter::new();
+    let dest: Destination = ("WWW.Example.com.", 443).into();
+    assert_eq!("WWW.Example.com.:443", filter.dest(&dest).to_string());
+
+    filter.set(HostnamePrivacy::Truncate, "");
+    assert_eq!("example.com:443", filter.dest(&dest).to_string());
+    let ip: Destination = "192.0.2.1:80"
+        .parse::<std::net::SocketAddr>()
+        .unwrap()
+        .into();
+    assert_eq!("192.0.2.1:80", filter.dest(&ip).to_string());
+
+    filter.set(HostnamePrivacy::Hash, "secret");
+    let hashed = filter.name("www.example.com");
+    assert!(hashed.starts_with("h-"));
+    assert_eq!(18, hashed.len());
+    // Consistent across case and the trailing dot, but keyed
+    assert_eq!(hashed, filter.name("WWW.example.com."));
+    assert_ne!(hashed, filter.name("example.com"));
+    let other = HostnameFilter::new();
+    other.set(HostnamePrivacy::Hash, "another");
+    assert_ne!(hashed, other.name("www.example.com"));
+    assert_eq!(HostnamePrivacy::Hash, filter.mode());
+}
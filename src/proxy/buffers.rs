@@ -0,0 +1,149 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Bytes held in per-connection buffers, see `BUFFERS`.
+#[derive(Debug)]
+pub struct BufferStats {
+    /// Client data read before connected (e.g. sniffed TLS ClientHello)
+    /// and waiting for being sent to upstream.
+    pending: AtomicUsize,
+    /// Private buffers of `BiPipe` for data not yet written out.
+    pipe: AtomicUsize,
+    /// Limit of `pending`, 0 for unlimited.
+    pending_limit: AtomicUsize,
+    /// Number of TLS sniffs skipped due to `pending_limit`.
+    sniff_skipped: AtomicUsize,
+}
+
+/// Snapshot of `BufferStats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct BufferUsage {
+    pub pending_bytes: usize,
+    pub pending_limit: Option<usize>,
+    pub pipe_bytes: usize,
+    pub sniff_skipped: usize,
+}
+
+/// Global accounting of all connections.
+pub static BUFFERS: BufferStats = BufferStats::new();
+
+impl BufferStats {
+    pub const fn new() -> Self {
+        Self {
+            pending: AtomicUsize::new(0),
+            pipe: AtomicUsize::new(0),
+            pending_limit: AtomicUsize::new(0),
+            sniff_skipped: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn set_pending_limit(&self, bytes: Option<usize>) {
+        self.pending_limit
+            .store(bytes.unwrap_or_default(), Ordering::Relaxed);
+    }
+
+    /// Whether the pending buffers have used up the limit. New connections
+    /// should not buffer anything more, and call `add_sniff_skipped()` if
+    /// skipping the sniff for it.
+    pub fn pending_exceeded(&self) -> bool {
+        match self.pending_limit.load(Ordering::Relaxed) {
+            0 => false,
+            limit => self.pending.load(Ordering::Relaxed) >= limit,
+        }
+    }
+
+    pub fn add_sniff_skipped(&self) {
+        self.sniff_skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Account a pending buffer, initially empty.
+    pub fn lease_pending(&'static self) -> BufferLease {
+        BufferLease::new(&self.pending)
+    }
+
+    /// Account a private buffer of `BiPipe`, initially empty.
+    pub fn lease_pipe(&'static self) -> BufferLease {
+        BufferLease::new(&self.pipe)
+    }
+
+    pub fn snapshot(&self) -> BufferUsage {
+        BufferUsage {
+            pending_bytes: self.pending.load(Ordering::Relaxed),
+            pending_limit: match self.pending_limit.load(Ordering::Relaxed) {
+                0 => None,
+                limit => Some(limit),
+            },
+            pipe_bytes: self.pipe.load(Ordering::Relaxed),
+            sniff_skipped: self.sniff_skipped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for BufferStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Bytes of a buffer accounted in `BufferStats`, released on drop.
+#[derive(Debug)]
+pub struct BufferLease {
+    counter: &'static AtomicUsize,
+    bytes: usize,
+}
+
+impl BufferLease {
+    fn new(counter: &'static AtomicUsize) -> Self {
+        Self { counter, bytes: 0 }
+    }
+
+    /// Update the size of the buffer.
+    pub fn resize(&mut self, bytes: usize) {
+        if bytes > self.bytes {
+            self.counter
+                .fetch_add(bytes - self.bytes, Ordering::Relaxed);
+        } else {
+            self.counter
+                .fetch_sub(self.bytes - bytes, Ordering::Relaxed);
+        }
+        self.bytes = bytes;
+    }
+}
+
+impl Drop for BufferLease {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn test_buffer_lease() {
+    let stats: &'static BufferStats = Box::leak(Box::default());
+    assert!(!stats.pending_exceeded());
+    stats.set_pending_limit(Some(100));
+
+    let mut a = stats.lease_pending();
+    a.resize(60);
+    let mut b = stats.lease_pending();
+    b.resize(30);
+    assert!(!stats.pending_exceeded());
+    b.resize(40);
+    assert!(stats.pending_exceeded());
+    b.resize(10);
+    assert!(!stats.pending_exceeded());
+    drop(a);
+    let mut pipe = stats.lease_pipe();
+    pipe.resize(8192);
+    assert_eq!(
+        BufferUsage {
+            pending_bytes: 10,
+            pending_limit: Some(100),
+            pipe_bytes: 8192,
+            sniff_skipped: 0,
+        },
+        stats.snapshot()
+    );
+    drop((b, pipe));
+    assert_eq!(0, stats.snapshot().pending_bytes);
+    assert_eq!(0, stats.snapshot().pipe_bytes);
+}
@@ -1,3 +1,4 @@
+use flexstr::SharedStr;
 use std::{
     cell::RefCell,
     cmp, fmt,
@@ -18,7 +19,12 @@ use tokio::{
 use tracing::{debug, trace};
 
 use self::Side::{Left, Right};
-use crate::proxy::{ProxyServer, Traffic};
+use crate::monitor::{ACCOUNTING, DESTINATIONS};
+use crate::proxy::{
+    buffers::{BufferLease, BUFFERS},
+    stream::ProxyStream,
+    BulkThreshold, ProxyServer, Traffic,
+};
 
 #[derive(Debug, Clone)]
 enum Side {
@@ -62,12 +68,15 @@ const SHARED_BUF_SIZE: usize = 1024 * 64;
 const PRIVATE_BUF_SIZE: usize = 1024 * 8;
 
 thread_local!(
-    static SHARED_BUFFER: RefCell<[u8; SHARED_BUF_SIZE]> = RefCell::new([0u8; SHARED_BUF_SIZE]);
+    static SHARED_BUFFER: RefCell<[u8; SHARED_BUF_SIZE]> =
+        const { RefCell::new([0u8; SHARED_BUF_SIZE]) };
 );
 
 struct StreamWithBuffer {
-    pub stream: TcpStream,
+    pub stream: ProxyStream,
     buf: Option<Box<[u8]>>,
+    /// Size of `buf` accounted in `BUFFERS`.
+    lease: BufferLease,
     pos: usize,
     cap: usize,
     pub read_eof: bool,
@@ -75,10 +84,11 @@ struct StreamWithBuffer {
 }
 
 impl StreamWithBuffer {
-    pub fn new(stream: TcpStream) -> Self {
+    pub fn new(stream: ProxyStream) -> Self {
         StreamWithBuffer {
             stream,
             buf: None,
+            lease: BUFFERS.lease_pipe(),
             pos: 0,
             cap: 0,
             read_eof: false,
@@ -117,7 +127,7 @@ impl StreamWithBuffer {
     pub fn poll_write_buffer_to(
         &mut self,
         cx: &mut Context,
-        writer: &mut TcpStream,
+        writer: &mut ProxyStream,
     ) -> Poll<io::Result<usize>> {
         let writer = Pin::new(writer);
 
@@ -135,7 +145,9 @@ impl StreamWithBuffer {
                         shared.extend_from_slice(buf);
                         self.pos = 0;
                         self.cap = n;
-                        self.buf = Some(shared.into_boxed_slice());
+                        let shared = shared.into_boxed_slice();
+                        self.lease.resize(shared.len());
+                        self.buf = Some(shared);
                         Poll::Pending
                     }
                     any => any,
@@ -165,44 +177,151 @@ impl StreamWithBuffer {
                     buf.len(),
                     PRIVATE_BUF_SIZE
                 );
-                *buf = vec![0; PRIVATE_BUF_SIZE].into_boxed_slice()
+                *buf = vec![0; PRIVATE_BUF_SIZE].into_boxed_slice();
+                self.lease.resize(PRIVATE_BUF_SIZE);
             }
         }
     }
 }
 
-// Pipe two TcpStream in both direction,
+// Pipe client & server stream in both direction,
 // update traffic amount to ProxyServer on the fly.
 pub struct BiPipe {
     left: StreamWithBuffer,
     right: StreamWithBuffer,
     server: Arc<ProxyServer>,
     traffic: Traffic,
+    half_close_timeout: Duration,
     half_close_deadline: Option<Pin<Box<Sleep>>>,
+    started_at: Instant,
+    bulk_threshold: Option<BulkThreshold>,
+    /// Counted as bulk on `server`.
+    bulk: bool,
+    /// Set until the first data read from remote, see `ttfb_since()`.
+    ttfb_since: Option<Instant>,
+    account: PendingAccount,
 }
 
-// Half-closed connections will be forcibly closed if there is no traffic
-// after the following duration.
-const HALF_CLOSE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Traffic not yet counted on `ACCOUNTING` and `DESTINATIONS`, so that
+/// their locks are taken once in a while rather than on every read.
+#[derive(Debug)]
+struct PendingAccount {
+    /// Listen port to count traffic on `ACCOUNTING`, if set.
+    port: Option<u16>,
+    /// Destination to count traffic on `DESTINATIONS`, if set.
+    dest: Option<SharedStr>,
+    traffic: Traffic,
+    flushed_at: Instant,
+}
 
-pub fn pipe(left: TcpStream, right: TcpStream, server: Arc<ProxyServer>) -> BiPipe {
-    let (left, right) = (StreamWithBuffer::new(left), StreamWithBuffer::new(right));
+/// Count pending traffic once this many bytes, or this long since last
+/// time, so that long-lived connections show up before closed.
+const ACCOUNT_FLUSH_BYTES: usize = 256 * 1024;
+const ACCOUNT_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+impl PendingAccount {
+    fn add(&mut self, amt: Traffic) {
+        if self.port.is_none() && self.dest.is_none() {
+            return;
+        }
+        self.traffic += amt;
+        if self.traffic.tx_bytes + self.traffic.rx_bytes >= ACCOUNT_FLUSH_BYTES
+            || self.flushed_at.elapsed() >= ACCOUNT_FLUSH_INTERVAL
+        {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.traffic == Traffic::default() {
+            return;
+        }
+        if let Some(port) = self.port {
+            ACCOUNTING.add(port, self.traffic);
+        }
+        if let Some(dest) = &self.dest {
+            DESTINATIONS.add(dest, self.traffic, 0);
+        }
+        self.traffic = Default::default();
+        self.flushed_at = Instant::now();
+    }
+}
+
+/// Half-closed connections will be forcibly closed if there is no traffic
+/// on the other direction for `ProxyServer::half_close_timeout()`.
+/// Connections are counted as bulk on the server once exceeding
+/// `ProxyServer::bulk_threshold()`, checked on traffic.
+pub fn pipe(left: TcpStream, right: ProxyStream, server: Arc<ProxyServer>) -> BiPipe {
+    let (left, right) = (
+        StreamWithBuffer::new(left.into()),
+        StreamWithBuffer::new(right),
+    );
     BiPipe {
         left,
         right,
+        half_close_timeout: server.half_close_timeout(),
+        bulk_threshold: server.bulk_threshold(),
         server,
         traffic: Default::default(),
         half_close_deadline: Default::default(),
+        started_at: Instant::now(),
+        bulk: false,
+        ttfb_since: Some(Instant::now()),
+        account: PendingAccount {
+            port: None,
+            dest: None,
+            traffic: Default::default(),
+            flushed_at: Instant::now(),
+        },
     }
 }
 
 impl BiPipe {
+    /// Time-to-first-byte is recorded on the server from `since` (the
+    /// upstream handshake finished), or the pipe created by default.
+    /// `None` to skip it, e.g. response data has been waited already.
+    pub fn ttfb_since(mut self, since: Option<Instant>) -> Self {
+        self.ttfb_since = since;
+        self
+    }
+
+    /// Count traffic for the listen port on `ACCOUNTING`, if set.
+    pub fn account_port(mut self, port: Option<u16>) -> Self {
+        self.account.port = port;
+        self
+    }
+
+    /// Count traffic and the connection for the destination (see
+    /// `destination_key()`) on `DESTINATIONS`, if set.
+    pub fn account_destination(mut self, dest: Option<SharedStr>) -> Self {
+        if let Some(dest) = &dest {
+            DESTINATIONS.add(dest, Default::default(), 1);
+        }
+        self.account.dest = dest;
+        self
+    }
+
+    fn check_bulk(&mut self) {
+        let threshold = match self.bulk_threshold {
+            Some(threshold) if !self.bulk => threshold,
+            _ => return,
+        };
+        let bytes = self.traffic.tx_bytes + self.traffic.rx_bytes;
+        if bytes > threshold.bytes || self.started_at.elapsed() > threshold.duration {
+            debug!(bytes, "(BiPipe) counted as bulk");
+            self.bulk = true;
+            self.server.update_stats_bulk(true);
+        }
+    }
+
     fn poll_one_side(&mut self, cx: &mut Context, side: Side) -> Poll<io::Result<()>> {
         let Self {
             ref mut left,
             ref mut right,
             ref mut server,
             ref mut traffic,
+            ref mut ttfb_since,
+            ref mut account,
             ..
         } = *self;
         let (reader, writer) = match side {
@@ -212,6 +331,8 @@ impl BiPipe {
         loop {
             // read something if buffer is empty
             if reader.is_empty() && !reader.read_eof {
+                // data may be buffered by the writer (e.g. encrypted chunks)
+                try_poll!(Pin::new(&mut writer.stream).poll_flush(cx));
                 let n = try_poll!(reader.poll_read_to_buffer(cx));
                 let amt = match side {
                     Left => (n, 0),
@@ -220,6 +341,13 @@ impl BiPipe {
                 .into();
                 server.add_traffic(amt);
                 *traffic += amt;
+                if n > 0 {
+                    account.add(amt);
+                }
+                if let (Right, true, Some(since)) = (&side, n > 0, *ttfb_since) {
+                    server.add_ttfb(since.elapsed());
+                    *ttfb_since = None;
+                }
             }
 
             // write out if buffer is not empty
@@ -247,6 +375,7 @@ impl Future for BiPipe {
     type Output = io::Result<Traffic>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<Traffic>> {
+        let traffic = self.traffic;
         if !self.left.all_done {
             trace!("(BiPipe) poll left");
             if let Poll::Ready(Err(err)) = self.poll_one_side(cx, Left) {
@@ -259,30 +388,192 @@ impl Future for BiPipe {
                 return Poll::Ready(Err(err));
             }
         }
+        if traffic != self.traffic {
+            self.check_bulk();
+        }
         match (self.left.all_done, self.right.all_done) {
-            (true, true) => Poll::Ready(Ok(self.traffic)),
-            (false, false) => Poll::Pending,
-            _ => {
-                // Half close
-                match &mut self.half_close_deadline {
-                    None => {
-                        // Setup a deadline then poll it
-                        let mut deadline = Box::pin(sleep(HALF_CLOSE_TIMEOUT));
-                        let _ = deadline.as_mut().poll(cx); // always return pending
-                        self.half_close_deadline = Some(deadline);
-                        Poll::Pending
-                    }
-                    Some(deadline) if !deadline.is_elapsed() => {
-                        trace!("(BiPipe) reset half-close timer");
-                        deadline.as_mut().reset(Instant::now() + HALF_CLOSE_TIMEOUT);
-                        Poll::Pending
-                    }
-                    Some(_) => {
-                        debug!("(BiPipe) half-close conn timed out");
-                        Poll::Ready(Ok(self.traffic))
-                    }
+            (true, true) => return Poll::Ready(Ok(self.traffic)),
+            (false, false) => return Poll::Pending,
+            _ => (),
+        }
+
+        // Half closed, wait for the other side until no traffic on it for
+        // a while.
+        let timeout = self.half_close_timeout;
+        let active = traffic != self.traffic;
+        let deadline = match &mut self.half_close_deadline {
+            Some(deadline) => {
+                if active {
+                    trace!("(BiPipe) traffic on half-closed conn, reset timer");
+                    deadline.as_mut().reset(Instant::now() + timeout);
                 }
+                deadline
+            }
+            None => {
+                trace!("(BiPipe) half closed, start timer");
+                self.half_close_deadline.insert(Box::pin(sleep(timeout)))
+            }
+        };
+        match deadline.as_mut().poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(()) => {
+                debug!("(BiPipe) half-closed conn timed out");
+                Poll::Ready(Ok(self.traffic))
             }
         }
     }
 }
+
+impl Drop for BiPipe {
+    fn drop(&mut self) {
+        self.account.flush();
+        if self.bulk {
+            self.server.update_stats_bulk(false);
+        }
+    }
+}
+
+#[cfg(test)]
+async fn connected_pair() -> (TcpStream, TcpStream) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let (a, b) = tokio::join!(
+        TcpStream::connect(listener.local_addr().unwrap()),
+        listener.accept()
+    );
+    (a.unwrap(), b.unwrap().0)
+}
+
+#[cfg(test)]
+async fn test_pipe() -> (TcpStream, TcpStream, BiPipe) {
+    let server = ProxyServer::test_socks5(([127, 0, 0, 1], 1).into(), None);
+    let (client, left) = connected_pair().await;
+    let (right, remote) = connected_pair().await;
+    (client, remote, pipe(left, right.into(), Arc::new(server)))
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_pipe_both_closed() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (mut client, mut remote, pipe) = test_pipe().await;
+    let peers = async {
+        client.write_all(b"ping").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut buf = vec![];
+        remote.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(b"ping", &buf[..]);
+        remote.write_all(b"pong").await.unwrap();
+        remote.shutdown().await.unwrap();
+        buf.clear();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(b"pong", &buf[..]);
+    };
+    let start = Instant::now();
+    let (traffic, _) = tokio::join!(pipe, peers);
+    assert_eq!(Traffic::from((4, 4)), traffic.unwrap());
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_pipe_half_closed_with_traffic() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (mut client, mut remote, pipe) = test_pipe().await;
+    let peers = async {
+        client.shutdown().await.unwrap();
+        // Keep sending slowly, longer than the timeout in total
+        for _ in 0..4 {
+            sleep(Duration::from_secs(40)).await;
+            remote.write_all(b"x").await.unwrap();
+        }
+        remote.shutdown().await.unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(b"xxxx", &buf[..]);
+    };
+    let start = Instant::now();
+    let (traffic, _) = tokio::join!(pipe, peers);
+    assert_eq!(Traffic::from((0, 4)), traffic.unwrap());
+    assert!(start.elapsed() >= Duration::from_secs(160));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_pipe_half_closed_timed_out() {
+    use tokio::io::AsyncWriteExt;
+
+    let (mut client, _remote, pipe) = test_pipe().await;
+    client.shutdown().await.unwrap();
+    let start = Instant::now();
+    pipe.await.unwrap();
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_secs(60));
+    assert!(elapsed < Duration::from_secs(61));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_pipe_bulk() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (mut client, mut remote, mut pipe) = test_pipe().await;
+    let server = pipe.server.clone();
+    pipe.bulk_threshold = Some(BulkThreshold {
+        bytes: 8,
+        duration: Duration::from_secs(60),
+    });
+    let handle = tokio::spawn(pipe);
+    let mut buf = [0u8; 8];
+    client.write_all(b"12345678").await.unwrap();
+    remote.read_exact(&mut buf).await.unwrap();
+    assert_eq!(0, server.status_snapshot().bulk_alive);
+
+    // Exceeded the duration
+    sleep(Duration::from_secs(61)).await;
+    client.write_all(b"9").await.unwrap();
+    remote.read_exact(&mut buf[..1]).await.unwrap();
+    let status = server.status_snapshot();
+    assert_eq!((1, 1), (status.bulk_alive, status.bulk_total));
+
+    drop((client, remote));
+    handle.await.unwrap().unwrap();
+    assert_eq!(0, server.status_snapshot().bulk_alive);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_pipe_ttfb() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (mut client, mut remote, pipe) = test_pipe().await;
+    let server = pipe.server.clone();
+    let peers = async {
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        remote.read_exact(&mut buf).await.unwrap();
+        // Slow upstream
+        sleep(Duration::from_millis(300)).await;
+        remote.write_all(b"pong").await.unwrap();
+        sleep(Duration::from_millis(300)).await;
+        remote.write_all(b"more").await.unwrap();
+        remote.shutdown().await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(b"pongmore", &buf[..]);
+    };
+    let (traffic, _) = tokio::join!(pipe, peers);
+    traffic.unwrap();
+    let ttfb = server.status_snapshot().ttfb;
+    assert_eq!(1, ttfb.count());
+    let mean = ttfb.mean().unwrap();
+    assert!(mean >= Duration::from_millis(300));
+    assert!(mean < Duration::from_millis(600));
+
+    let (_client, mut remote, pipe) = test_pipe().await;
+    let server = pipe.server.clone();
+    let pipe = tokio::spawn(pipe.ttfb_since(None));
+    remote.write_all(b"pong").await.unwrap();
+    remote.shutdown().await.unwrap();
+    drop(remote);
+    sleep(Duration::from_secs(61)).await;
+    pipe.await.unwrap().unwrap();
+    assert_eq!(0, server.status_snapshot().ttfb.count());
+}
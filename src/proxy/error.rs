@@ -0,0 +1,208 @@
+use serde::Serialize;
+use std::{error::Error, fmt, io};
+
+/// Failure on handshaking with upstream proxy servers.
+/// Converted into `io::Error` with the original error kept as its inner
+/// error, see `HandshakeError::from_io()`.
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// Server requires authentication but no credential is configured,
+    /// or no acceptable method.
+    AuthRequired,
+    /// Server refused our credential.
+    AuthRejected,
+    /// Server replied something we don't understand.
+    UnexpectedReply(&'static str),
+    /// Server returned an error code, the SOCKSv5 reply field or the HTTP
+    /// status code.
+    UpstreamCode(u16),
+    /// Connection closed before a complete reply received.
+    Truncated,
+    Io(io::Error),
+}
+
+impl HandshakeError {
+    /// Return the `HandshakeError` inside `err`, if any.
+    pub fn from_io(err: &io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+
+    /// Stable name used in logs and metric labels.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::AuthRequired => "auth_required",
+            Self::AuthRejected => "auth_rejected",
+            Self::UnexpectedReply(_) => "unexpected_reply",
+            Self::UpstreamCode(_) => "upstream_code",
+            Self::Truncated => "truncated",
+            Self::Io(_) => "io",
+        }
+    }
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AuthRequired => write!(f, "authentication required by proxy"),
+            Self::AuthRejected => write!(f, "proxy authentication failed"),
+            Self::UnexpectedReply(msg) => write!(f, "unexpected reply from proxy: {}", msg),
+            Self::UpstreamCode(code) => write!(f, "proxy return error: {}", code),
+            Self::Truncated => write!(f, "proxy closed connection during handshake"),
+            Self::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for HandshakeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for HandshakeError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => Self::Truncated,
+            _ => Self::Io(err),
+        }
+    }
+}
+
+impl From<HandshakeError> for io::Error {
+    fn from(err: HandshakeError) -> Self {
+        let kind = match &err {
+            HandshakeError::AuthRequired | HandshakeError::AuthRejected => {
+                io::ErrorKind::PermissionDenied
+            }
+            HandshakeError::UnexpectedReply(_) => io::ErrorKind::InvalidData,
+            HandshakeError::UpstreamCode(_) => io::ErrorKind::Other,
+            HandshakeError::Truncated => io::ErrorKind::UnexpectedEof,
+            HandshakeError::Io(err) => err.kind(),
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+/// Number of handshake errors by `HandshakeError::kind()`.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct HandshakeErrorCounts {
+    pub auth_required: u32,
+    pub auth_rejected: u32,
+    pub unexpected_reply: u32,
+    pub upstream_code: u32,
+    pub truncated: u32,
+    pub io: u32,
+}
+
+impl HandshakeErrorCounts {
+    pub fn add(&mut self, err: &HandshakeError) {
+        let counter = match err {
+            HandshakeError::AuthRequired => &mut self.auth_required,
+            HandshakeError::AuthRejected => &mut self.auth_rejected,
+            HandshakeError::UnexpectedReply(_) => &mut self.unexpected_reply,
+            HandshakeError::UpstreamCode(_) => &mut self.upstream_code,
+            HandshakeError::Truncated => &mut self.truncated,
+            HandshakeError::Io(_) => &mut self.io,
+        };
+        *counter += 1;
+    }
+
+    /// Return (kind, count) pairs.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, u32)> {
+        [
+            ("auth_required", self.auth_required),
+            ("auth_rejected", self.auth_rejected),
+            ("unexpected_reply", self.unexpected_reply),
+            ("upstream_code", self.upstream_code),
+            ("truncated", self.truncated),
+            ("io", self.io),
+        ]
+        .into_iter()
+    }
+}
+
+/// How far a connection to the proxy server got, see `ProbeTimings`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HandshakePhase {
+    /// Establishing the TCP connection.
+    #[default]
+    Connect,
+    /// Prelude, SOCKSv5 method selection.
+    Negotiate,
+    /// SOCKSv5 username/password authentication.
+    Auth,
+    /// Sending the request and waiting for the reply, the whole
+    /// handshake of protocols other than SOCKSv5.
+    Request,
+    /// Handshake done, waiting for the response of the probe.
+    Response,
+}
+
+impl HandshakePhase {
+    /// Stable name used in logs and metric labels.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Connect => "connect",
+            Self::Negotiate => "negotiate",
+            Self::Auth => "auth",
+            Self::Request => "request",
+            Self::Response => "response",
+        }
+    }
+}
+
+/// Number of failed probes by `HandshakePhase` they failed in, and those
+/// timed out in any phase.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ConnectFailureCounts {
+    pub connect: u32,
+    pub negotiate: u32,
+    pub auth: u32,
+    pub request: u32,
+    pub response: u32,
+    pub timed_out: u32,
+}
+
+impl ConnectFailureCounts {
+    pub fn add(&mut self, phase: HandshakePhase, timed_out: bool) {
+        let counter = match phase {
+            HandshakePhase::Connect => &mut self.connect,
+            HandshakePhase::Negotiate => &mut self.negotiate,
+            HandshakePhase::Auth => &mut self.auth,
+            HandshakePhase::Request => &mut self.request,
+            HandshakePhase::Response => &mut self.response,
+        };
+        *counter += 1;
+        self.timed_out += timed_out as u32;
+    }
+
+    /// Return (phase, count) pairs.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, u32)> {
+        [
+            (HandshakePhase::Connect, self.connect),
+            (HandshakePhase::Negotiate, self.negotiate),
+            (HandshakePhase::Auth, self.auth),
+            (HandshakePhase::Request, self.request),
+            (HandshakePhase::Response, self.response),
+        ]
+        .into_iter()
+        .map(|(phase, count)| (phase.name(), count))
+    }
+}
+
+#[test]
+fn test_handshake_error_into_io() {
+    let err: io::Error = HandshakeError::AuthRejected.into();
+    assert_eq!(io::ErrorKind::PermissionDenied, err.kind());
+    assert!(matches!(
+        HandshakeError::from_io(&err),
+        Some(HandshakeError::AuthRejected)
+    ));
+    let err: HandshakeError = io::Error::from(io::ErrorKind::UnexpectedEof).into();
+    assert!(matches!(err, HandshakeError::Truncated));
+    assert!(HandshakeError::from_io(&io::ErrorKind::Other.into()).is_none());
+}
@@ -1,6 +1,5 @@
 use base64::prelude::{Engine, BASE64_STANDARD};
 use httparse::{Response, Status, EMPTY_HEADER};
-use std::io::{self, ErrorKind};
 use std::net::IpAddr;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
@@ -10,21 +9,26 @@ use tracing::{debug, instrument, trace};
 
 use crate::proxy::{Address, Destination};
 
-use super::UserPassAuthCredential;
+use super::{error::HandshakeError, UserPassAuthCredential};
 
-macro_rules! ensure_200 {
-    ($code:expr) => {
-        if $code != 200 {
-            return Err(io::Error::new(
-                ErrorKind::Other,
-                format!("proxy return error: {}", $code),
-            ));
-        }
-    };
+const BUF_LEN: usize = 1024;
+const MAX_RESPONSE_LEN: usize = 64_000;
+
+/// Status, and headers we care about, of a HTTP response.
+#[derive(Debug)]
+struct ResponseHead {
+    code: u16,
+    basic_challenge: bool,
+    content_length: usize,
+    close: bool,
 }
 
-const BUF_LEN: usize = 1024;
+fn is_auth_required(code: u16) -> bool {
+    code == 401 || code == 407
+}
 
+/// Return data following the response header, which is sent by the
+/// destination and should be forwarded to the client.
 #[instrument(name = "http_handshake", skip_all)]
 pub async fn handshake<T>(
     stream: &mut TcpStream,
@@ -32,70 +36,148 @@ pub async fn handshake<T>(
     data: Option<T>,
     with_playload: bool,
     user_pass_auth: &Option<UserPassAuthCredential>,
-) -> io::Result<()>
+    auth_on_challenge: bool,
+) -> Result<Vec<u8>, HandshakeError>
 where
     T: AsRef<[u8]> + 'static,
 {
-    let mut buf = build_request(addr, user_pass_auth).into_bytes();
-    stream.write_all(&buf).await?;
+    let mut send_auth = !auth_on_challenge;
+    let mut payload_sent = false;
+    let mut buf = Vec::with_capacity(BUF_LEN);
+    loop {
+        let auth = user_pass_auth.as_ref().filter(|_| send_auth);
+        stream
+            .write_all(build_request(addr, auth).as_bytes())
+            .await?;
+        // Payload may be discarded with the request if challenged
+        let may_challenge = user_pass_auth.is_some() && !send_auth;
+        if with_playload && !may_challenge {
+            // violate the protocol but save latency
+            if let Some(ref data) = data {
+                stream.write_all(data.as_ref()).await?;
+            }
+            payload_sent = true;
+        }
+
+        let head = read_response_head(stream, &mut buf).await?;
+        trace!("response {:?}", head);
+        match head.code {
+            200 => break,
+            code if is_auth_required(code) => {
+                if may_challenge && head.basic_challenge && !head.close {
+                    debug!("challenged by proxy ({}), retry with credential", code);
+                    if head.content_length > MAX_RESPONSE_LEN {
+                        return Err(HandshakeError::UnexpectedReply("response too large"));
+                    }
+                    // Discard the body, part of it may have been read
+                    if buf.len() >= head.content_length {
+                        buf.drain(..head.content_length);
+                    } else {
+                        let mut body = vec![0u8; head.content_length - buf.len()];
+                        stream.read_exact(&mut body).await?;
+                        buf.clear();
+                    }
+                    send_auth = true;
+                    continue;
+                }
+                debug!("proxy authentication failed: {}", code);
+                return Err(if auth.is_some() {
+                    HandshakeError::AuthRejected
+                } else {
+                    HandshakeError::AuthRequired
+                });
+            }
+            code => return Err(HandshakeError::UpstreamCode(code)),
+        }
+    }
 
-    if with_playload {
-        // violate the protocol but save latency
+    // Write out payload if exist
+    if !payload_sent {
         if let Some(ref data) = data {
             stream.write_all(data.as_ref()).await?;
         }
     }
+    trace!(pending = buf.len(), "HTTP CONNECT handshaking done");
+    // Data from the destination that arrived along with the response
+    Ok(buf)
+}
 
-    // Parse HTTP response
-    buf.clear();
-    let mut bytes_read = 0;
-    let mut sink = [0u8; BUF_LEN];
+/// Read the response header into `buf`, then drain the header from it.
+/// Bytes following the header (body or tunneled data) are left in `buf`.
+async fn read_response_head(
+    stream: &mut TcpStream,
+    buf: &mut Vec<u8>,
+) -> Result<ResponseHead, HandshakeError> {
     loop {
-        let mut headers = [EMPTY_HEADER; 16];
-        let mut response = Response::new(&mut headers);
-        buf.resize(bytes_read + BUF_LEN, 0);
-        let peek_len = stream.peek(&mut buf).await?;
-        bytes_read += peek_len;
-        trace!("bytes peek: {}", bytes_read);
-
-        match response.parse(&buf[..bytes_read]) {
-            Err(e) => return Err(io::Error::new(ErrorKind::Other, e)),
-            Ok(Status::Partial) => {
-                debug!("partial http reponse read; wait for more data");
-                if let Some(code) = response.code {
-                    ensure_200!(code);
+        if !buf.is_empty() {
+            let mut headers = [EMPTY_HEADER; 16];
+            let mut response = Response::new(&mut headers);
+            match response.parse(buf) {
+                Err(e) => {
+                    debug!("malformed http response: {}", e);
+                    return Err(HandshakeError::UnexpectedReply("malformed HTTP response"));
                 }
-                if bytes_read > 64_000 {
-                    return Err(io::Error::new(ErrorKind::Other, "response too large"));
+                Ok(Status::Partial) => {
+                    debug!("partial http reponse read; wait for more data");
+                    match response.code {
+                        Some(code) if code != 200 && !is_auth_required(code) => {
+                            return Err(HandshakeError::UpstreamCode(code));
+                        }
+                        _ => (),
+                    }
+                    if buf.len() > MAX_RESPONSE_LEN {
+                        return Err(HandshakeError::UnexpectedReply("response too large"));
+                    }
+                }
+                Ok(Status::Complete(head_len)) => {
+                    let head = parse_head(&response)?;
+                    buf.drain(..head_len);
+                    return Ok(head);
                 }
-                // Drop peeked data from socket buffer
-                stream.read_exact(&mut sink[..peek_len]).await?;
-            }
-            Ok(Status::Complete(bytes_request)) => {
-                trace!(
-                    "response {}, {} bytes",
-                    response.code.unwrap(),
-                    bytes_request
-                );
-                ensure_200!(response.code.unwrap());
-                let len = peek_len - (bytes_read - bytes_request);
-                stream.read_exact(&mut sink[..len]).await?;
-                break;
             }
         }
-    }
-
-    // Write out payload if exist
-    if !with_playload {
-        if let Some(ref data) = data {
-            stream.write_all(data.as_ref()).await?;
+        buf.reserve(BUF_LEN);
+        let len = stream.read_buf(buf).await?;
+        if len == 0 {
+            return Err(HandshakeError::Truncated);
         }
+        trace!("bytes read: {}", buf.len());
     }
-    trace!("HTTP CONNECT handshaking done");
-    Ok(())
 }
 
-fn build_request(addr: &Destination, user_pass_auth: &Option<UserPassAuthCredential>) -> String {
+fn parse_head(response: &Response) -> Result<ResponseHead, HandshakeError> {
+    let header = |name: &'static str| {
+        response
+            .headers
+            .iter()
+            .filter(move |h| h.name.eq_ignore_ascii_case(name))
+            .filter_map(|h| std::str::from_utf8(h.value).ok())
+    };
+    let basic_challenge = header("Proxy-Authenticate")
+        .chain(header("WWW-Authenticate"))
+        .any(|v| {
+            v.trim_start()
+                .get(..5)
+                .is_some_and(|s| s.eq_ignore_ascii_case("basic"))
+        });
+    let content_length = header("Content-Length")
+        .next()
+        .map(|v| v.trim().parse())
+        .transpose()
+        .map_err(|_| HandshakeError::UnexpectedReply("invalid content-length"))?
+        .unwrap_or(0);
+    // Cannot tell where the body end if chunked
+    let close = header("Connection").any(|v| v.eq_ignore_ascii_case("close"))
+        || header("Transfer-Encoding").next().is_some();
+    Ok(ResponseHead {
+        code: response.code.unwrap(),
+        basic_challenge,
+        content_length,
+        close,
+    })
+}
+
+fn build_request(addr: &Destination, user_pass_auth: Option<&UserPassAuthCredential>) -> String {
     let port = addr.port;
     let host = match addr.host {
         Address::Ip(ip) => match ip {
@@ -0,0 +1,130 @@
+use flexstr::SharedStr;
+use parking_lot::Mutex;
+use std::{
+    collections::BTreeSet,
+    io,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::normalize_domain;
+
+/// Max number of names kept by `DOMAINS` in each generation.
+const DOMAINS_CAPACITY: usize = 4096;
+
+/// Share one `SharedStr` among repeated names, e.g. SNI of connections
+/// to the same site. Short names are inlined thus never kept.
+///
+/// Names are kept in two generations. Once the current one is full, it
+/// replaces the previous one, dropping names not seen since then. So names
+/// seen often are kept, while a scan can't grow it beyond twice the
+/// capacity. See tests/allocs.rs for allocations saved.
+#[derive(Debug)]
+pub struct Interner {
+    names: Mutex<Generations>,
+    capacity: usize,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct Generations {
+    current: BTreeSet<SharedStr>,
+    previous: BTreeSet<SharedStr>,
+}
+
+/// Global interner of destination domain names.
+pub static DOMAINS: Interner = Interner::new(DOMAINS_CAPACITY);
+
+impl Interner {
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            names: Mutex::new(Generations {
+                current: BTreeSet::new(),
+                previous: BTreeSet::new(),
+            }),
+            capacity,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn intern(&self, name: &str) -> SharedStr {
+        let name = match SharedStr::try_inline(name) {
+            Ok(inlined) => return inlined,
+            Err(name) => name,
+        };
+        let mut names = self.names.lock();
+        if let Some(interned) = names.current.get(name) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return interned.clone();
+        }
+        let interned = match names.previous.take(name) {
+            Some(interned) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                interned
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                SharedStr::from(name)
+            }
+        };
+        if names.current.len() >= self.capacity {
+            names.previous = std::mem::take(&mut names.current);
+        }
+        names.current.insert(interned.clone());
+        interned
+    }
+
+    /// Interned `normalize_domain(name)`, without allocating if `name` is
+    /// normalized and seen already.
+    pub fn normalize(&self, name: &str) -> io::Result<SharedStr> {
+        let trimmed = name.strip_suffix('.').unwrap_or(name);
+        if trimmed.is_ascii() && !trimmed.bytes().any(|b| b.is_ascii_uppercase()) {
+            return Ok(self.intern(trimmed));
+        }
+        normalize_domain(name).map(|name| self.intern(&name))
+    }
+
+    /// Numbers of `intern()` calls that reused a kept name, and that
+    /// added one.
+    pub fn stats(&self) -> (usize, usize) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+}
+
+#[test]
+fn test_interner() {
+    let interner = Interner::new(2);
+    let long = "a-long-name-not-inlined.example.com";
+    let a = interner.intern(long);
+    let b = interner.intern(long);
+    assert_eq!(long, &*a);
+    assert_eq!(a.as_ptr(), b.as_ptr());
+    assert_eq!((1, 1), interner.stats());
+
+    // Inlined
+    assert_eq!("a.test", &*interner.intern("a.test"));
+    assert_eq!((1, 1), interner.stats());
+
+    // Normalized
+    let c = interner
+        .normalize("A-LONG-NAME-NOT-INLINED.example.com.")
+        .unwrap();
+    assert_eq!(a.as_ptr(), c.as_ptr());
+    let d = interner.normalize(&format!("{}.", long)).unwrap();
+    assert_eq!(a.as_ptr(), d.as_ptr());
+
+    // Kept in the previous generation once full
+    let another = "another-long-name-not-inlined.example.com";
+    let f = interner.intern(another);
+    interner.intern("the-third-long-name-not-inlined.example.com");
+    let e = interner.intern(long);
+    assert_eq!(a.as_ptr(), e.as_ptr());
+    assert_eq!((4, 3), interner.stats());
+    // Dropped if not seen in a generation
+    interner.intern("the-fourth-long-name-not-inlined.example.com");
+    assert_ne!(f.as_ptr(), interner.intern(another).as_ptr());
+}
@@ -0,0 +1,127 @@
+use serde::Serialize;
+use std::time::Duration;
+
+use super::Delay;
+
+/// Number of recent probes kept in `DelayHistory`.
+const HISTORY_LEN: usize = 32;
+/// Min number of probes before `AutoMaxWait` takes effect.
+const MIN_SAMPLES: usize = 4;
+
+/// Delays of recent probes.
+#[derive(Debug, Clone, Default)]
+pub struct DelayHistory {
+    samples: [Delay; HISTORY_LEN],
+    next: usize,
+}
+
+impl DelayHistory {
+    pub fn add(&mut self, delay: Delay) {
+        self.samples[self.next] = delay;
+        self.next = (self.next + 1) % HISTORY_LEN;
+    }
+
+    /// Number of probes in the history.
+    pub fn len(&self) -> usize {
+        self.samples
+            .iter()
+            .filter(|d| !matches!(d, Delay::Unknown | Delay::Failed))
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 95th percentile of delays, timed-out ones are counted as `timed_out`.
+    pub fn p95(&self, timed_out: Duration) -> Option<Duration> {
+        let mut delays: Vec<_> = self
+            .samples
+            .iter()
+            .filter_map(|d| match d {
+                Delay::Unknown | Delay::Failed => None,
+                Delay::Some(d) => Some(*d),
+                Delay::TimedOut => Some(timed_out),
+            })
+            .collect();
+        if delays.is_empty() {
+            return None;
+        }
+        delays.sort_unstable();
+        let rank = (delays.len() * 95).div_ceil(100);
+        Some(delays[rank - 1])
+    }
+}
+
+/// Adjust `max wait` of a server to `factor` × p95 of its probe delays,
+/// within `min..=max`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct AutoMaxWait {
+    pub factor: f32,
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl Default for AutoMaxWait {
+    fn default() -> Self {
+        Self {
+            factor: 3.0,
+            min: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+        }
+    }
+}
+
+impl AutoMaxWait {
+    /// Return `None` if too few probes.
+    pub fn compute(&self, history: &DelayHistory) -> Option<Duration> {
+        if history.len() < MIN_SAMPLES {
+            return None;
+        }
+        // Timed out on `max` at worst, count as it
+        let p95 = history.p95(self.max)?;
+        let millis = (p95.as_millis() as f64 * self.factor as f64).round() as u64;
+        Some(Duration::from_millis(millis).clamp(self.min, self.max))
+    }
+}
+
+#[test]
+fn test_auto_max_wait() {
+    let auto = AutoMaxWait {
+        factor: 3.0,
+        min: Duration::from_millis(500),
+        max: Duration::from_secs(10),
+    };
+    let ms = Duration::from_millis;
+    let mut history = DelayHistory::default();
+    assert_eq!(None, history.p95(ms(1)));
+    for _ in 0..3 {
+        history.add(Delay::Some(ms(80)));
+    }
+    assert_eq!(None, auto.compute(&history));
+    history.add(Delay::Some(ms(80)));
+    // Fast server, clamped to min
+    assert_eq!(Some(ms(500)), auto.compute(&history));
+
+    // Slow but working
+    for _ in 0..HISTORY_LEN {
+        history.add(Delay::Some(ms(1_200)));
+    }
+    assert_eq!(HISTORY_LEN, history.len());
+    assert_eq!(Some(ms(3_600)), auto.compute(&history));
+
+    // Occasional spike above p95 is ignored
+    let mut history = DelayHistory::default();
+    history.add(Delay::Some(ms(5_000)));
+    for n in 1..HISTORY_LEN as u64 {
+        history.add(Delay::Some(ms(100 + n * 10)));
+    }
+    assert_eq!(Some(ms(410)), history.p95(ms(0)));
+    assert_eq!(Some(ms(1_230)), auto.compute(&history));
+
+    // Timed out, clamped to max
+    for _ in 0..2 {
+        history.add(Delay::TimedOut);
+    }
+    assert_eq!(Some(ms(10_000)), auto.compute(&history));
+}
@@ -1,28 +1,60 @@
+pub mod buffers;
 pub mod copy;
+pub mod error;
 pub mod http;
+pub mod intern;
+pub mod max_wait;
+pub mod prelude;
+pub mod race;
+pub mod resolver;
+#[cfg(feature = "shadowsocks")]
+pub mod shadowsocks;
 use flexstr::{shared_fmt, SharedStr};
 #[cfg(feature = "score_script")]
 use rlua::prelude::*;
+pub mod socks4;
 pub mod socks5;
+pub mod stream;
+pub mod ttfb;
 use parking_lot::{Mutex, RwLock};
 use serde::{Serialize, Serializer};
 use serde_with::{serde_as, DisplayFromStr};
 use std::{
-    cmp, fmt,
+    cmp,
+    collections::HashSet,
+    fmt,
     hash::{Hash, Hasher},
     io,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     ops::{Add, AddAssign},
     str::FromStr,
-    sync::atomic::{AtomicUsize, Ordering},
-    time::Duration,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::TcpStream,
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+use tracing::{debug, error, info, instrument};
+
+use self::{
+    error::{ConnectFailureCounts, HandshakeError, HandshakeErrorCounts, HandshakePhase},
+    max_wait::{AutoMaxWait, DelayHistory},
+    prelude::Prelude,
+    race::RaceStats,
+    stream::ProxyStream,
+    ttfb::TtfbStats,
 };
-use tokio::net::TcpStream;
-use tracing::{debug, instrument};
-
 use crate::policy::capabilities::CapSet;
 
 const GRAPHITE_PATH_PREFIX: &str = "moproxy.proxy_servers";
+/// Default of `ProxyServerConfig::half_close_timeout`.
+pub const DEFAULT_HALF_CLOSE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Implicit capability of servers that passed the TLS verification probe.
+pub const CAP_TLS_VERIFIED: &str = "tls-verified";
 
 #[derive(Hash, Eq, PartialEq, Clone, Debug, Serialize)]
 pub enum ProxyProto {
@@ -34,6 +66,15 @@ pub enum ProxyProto {
         /// servers.
         fake_handshaking: bool,
         user_pass_auth: Option<UserPassAuthCredential>,
+        /// Use 0x05 instead of 0x01 (RFC 1929) as the version of
+        /// username/password authentication, for non-compliant servers.
+        legacy_auth_version: bool,
+    },
+    #[serde(rename = "SOCKSv4")]
+    Socks4 {
+        /// Send domain name to the server as per SOCKSv4a extension,
+        /// instead of resolving it locally.
+        remote_dns: bool,
     },
     #[serde(rename = "HTTP")]
     Http {
@@ -44,9 +85,17 @@ pub enum ProxyProto {
         /// RFC 7231:
         /// >> A payload within a CONNECT request message has no defined
         /// >> semantics; sending a payload body on a CONNECT request might
-        /// cause some existing implementations to reject the request.
+        /// >> cause some existing implementations to reject the request.
         connect_with_payload: bool,
         user_pass_auth: Option<UserPassAuthCredential>,
+        /// Send credential only after challenged by the server with
+        /// `Proxy-Authenticate: Basic`, instead of on the first request.
+        auth_on_challenge: bool,
+    },
+    /// Shadowsocks with AEAD cipher, without plugin.
+    #[cfg(feature = "shadowsocks")]
+    Shadowsocks {
+        cipher: shadowsocks::Cipher,
     },
     Direct,
 }
@@ -65,24 +114,242 @@ impl UserPassAuthCredential {
             password: password.as_ref().into(),
         }
     }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
 }
 
-#[allow(clippy::mutable_key_type)]
 #[derive(Debug, Serialize)]
 pub struct ProxyServer {
     pub addr: SocketAddr,
     pub proto: ProxyProto,
-    pub tag: SharedStr,
+    /// Not part of the identity, may be renamed on reload.
+    tag: RwLock<SharedStr>,
     config: RwLock<ProxyServerConfig>,
     status: Mutex<ProxyServerStatus>,
+    /// Kept apart from `status` to keep its snapshots small.
+    #[serde(skip)]
+    delay_history: Mutex<DelayHistory>,
     traffic: AtomicTraffic,
 }
 
+/// Connections are counted as bulk once exceeding either of limits.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct BulkThreshold {
+    /// Total bytes in both directions.
+    pub bytes: usize,
+    pub duration: Duration,
+}
+
+/// Bounds on scores of servers.
+#[derive(Debug, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScoreLimits {
+    /// Scores below it are raised to it, if set.
+    pub min: Option<i32>,
+    /// Servers with scores above it are excluded as if down, if set.
+    pub max: Option<i32>,
+}
+
+/// Address family preferred by `prefer-ipv4` or `prefer-ipv6` rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    /// IPv4-mapped IPv6 addresses are counted as IPv4.
+    pub fn of(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V6(ip) if ip.to_ipv4_mapped().is_none() => Self::V6,
+            _ => Self::V4,
+        }
+    }
+
+    /// Stable sort `addrs` to dial ones in this family first.
+    pub fn sort_first(self, addrs: &mut [SocketAddr]) {
+        addrs.sort_by_key(|addr| Self::of(addr.ip()) != self);
+    }
+}
+
+/// Score added for each bulk connection on `prefer_non_bulk()`.
+const BULK_SCORE_PENALTY: i32 = 100;
+
+/// Stable sort servers by score with `BULK_SCORE_PENALTY` for each bulk
+/// connection alive. Servers without a score are kept at the end.
+pub fn prefer_non_bulk(servers: &mut [Arc<ProxyServer>]) {
+    sort_by_preference(servers, true, &HashSet::new(), None, 0);
+}
+
+/// Stable sort servers by score, with `BULK_SCORE_PENALTY` added for each
+/// bulk connection alive if `non_bulk`, and `bonus` subtracted for each of
+/// `caps` they meet, and once more if their addresses are in `family`.
+/// Servers without a score are kept at the end.
+pub fn sort_by_preference(
+    servers: &mut [Arc<ProxyServer>],
+    non_bulk: bool,
+    caps: &HashSet<CapSet>,
+    family: Option<IpFamily>,
+    bonus: i32,
+) {
+    servers.sort_by_cached_key(|server| {
+        let status = server.status_snapshot();
+        status.candidate_score().map_or((true, 0), |score| {
+            let mut score = score;
+            if non_bulk {
+                let penalty = BULK_SCORE_PENALTY.saturating_mul(status.bulk_alive as i32);
+                score = score.saturating_add(penalty);
+            }
+            let met = caps.iter().filter(|c| server.capable_anyof(c)).count()
+                + family.map_or(0, |f| (IpFamily::of(server.addr.ip()) == f) as usize);
+            let met = met as i32;
+            (false, score.saturating_sub(bonus.saturating_mul(met)))
+        })
+    });
+}
+
+/// Options applied on TCP sockets to both clients and servers.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct TcpOptions {
+    /// Disable Nagle's algorithm (TCP_NODELAY).
+    pub nodelay: bool,
+    /// Set TCP_NOTSENT_LOWAT. Linux only.
+    pub notsent_lowat: Option<u32>,
+    /// Set TCP_QUICKACK. Linux only.
+    pub quickack: bool,
+    /// Try Multipath TCP on outgoing connections. Linux only.
+    pub mptcp: bool,
+    /// Try TCP Fast Open on outgoing connections. Linux only.
+    pub fastopen: bool,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            notsent_lowat: None,
+            quickack: false,
+            mptcp: false,
+            fastopen: false,
+        }
+    }
+}
+
+impl TcpOptions {
+    /// Connect to `addr` then apply the options.
+    pub async fn connect(&self, addr: &SocketAddr) -> io::Result<TcpStream> {
+        #[cfg(target_os = "linux")]
+        let stream = if self.mptcp || self.fastopen {
+            crate::linux::tcp::new_socket(addr, self.mptcp, self.fastopen)?
+                .connect(*addr)
+                .await?
+        } else {
+            TcpStream::connect(addr).await?
+        };
+        #[cfg(not(target_os = "linux"))]
+        let stream = TcpStream::connect(addr).await?;
+        self.apply(&stream)?;
+        Ok(stream)
+    }
+
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        #[cfg(target_os = "linux")]
+        {
+            use crate::linux::tcp::TcpStreamExt;
+
+            if let Some(bytes) = self.notsent_lowat {
+                stream.set_notsent_lowat(bytes)?;
+            }
+            if self.quickack {
+                stream.set_quickack(true)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Maximum number of concurrent handshakes. Clones share the same permits.
+#[derive(Debug, Clone)]
+pub struct HandshakeLimit {
+    max: usize,
+    semaphore: Arc<Semaphore>,
+}
+
+impl HandshakeLimit {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            semaphore: Arc::new(Semaphore::new(max)),
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        // Never closed
+        self.semaphore.clone().acquire_owned().await.ok()
+    }
+}
+
+impl Serialize for HandshakeLimit {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.max as u64)
+    }
+}
+
+/// Handshake counted as in-progress until dropped.
+pub struct HandshakePermit<'a> {
+    server: &'a ProxyServer,
+    _permits: [Option<OwnedSemaphorePermit>; 2],
+}
+
+impl Drop for HandshakePermit<'_> {
+    fn drop(&mut self) {
+        self.server.status.lock().handshakes -= 1;
+    }
+}
+
 #[derive(Debug, Serialize, Clone)]
 pub struct ProxyServerConfig {
     pub test_dns: SocketAddr,
+    /// Adjusted after each probe if `auto_max_wait` is set.
     pub max_wait: Duration,
+    pub auto_max_wait: Option<AutoMaxWait>,
+    /// Capabilities from the server list.
     pub capabilities: CapSet,
+    /// Capabilities added by `auto capability` rules.
+    /// Kept across reloads, the union with `capabilities` is used.
+    pub dynamic_capabilities: CapSet,
+    /// Host name to verify TLS certificate against when probing, if set.
+    pub probe_verify_tls: Option<SharedStr>,
+    /// Probe with a connect to this port on the test DNS server, instead of
+    /// a DNS query, if set.
+    pub probe_port: Option<u16>,
+    /// Probe every this duration instead of the global `--probe`, if set.
+    pub probe_interval: Option<Duration>,
+    /// Destination ports the server accepts, sorted; any port if not set.
+    pub allowed_ports: Option<Vec<u16>>,
+    pub tcp_options: TcpOptions,
+    /// Close half-closed connections if no traffic for this duration.
+    pub half_close_timeout: Duration,
+    /// Exchanged before the handshake, if set.
+    #[serde(skip)]
+    pub prelude: Option<Prelude>,
+    /// Limit of concurrent handshakes on this server, if set.
+    pub handshake_limit: Option<HandshakeLimit>,
+    /// Limit of concurrent handshakes shared by all servers, if set.
+    #[serde(skip)]
+    pub global_handshake_limit: Option<HandshakeLimit>,
+    /// Classify connections as bulk, if set.
+    pub bulk_threshold: Option<BulkThreshold>,
+    /// Send pending data only after the handshake done, and never race
+    /// with other servers.
+    pub no_early_payload: bool,
+    pub score_limits: ScoreLimits,
     score_base: i32,
 }
 
@@ -93,16 +360,33 @@ impl ToLua<'_> for ProxyServerConfig {
         table.set("test_dns", self.test_dns.to_string())?;
         table.set("max_wait", self.max_wait.as_secs_f32())?;
         table.set("score_base", self.score_base)?;
+        table.set(
+            "probe_verify_tls",
+            self.probe_verify_tls.as_ref().map(|s| s.to_string()),
+        )?;
         table.to_lua(ctx)
     }
 }
 
+/// Result of the last TLS verification probe, see `probe verify tls`.
+#[derive(Debug, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TlsVerification {
+    /// Not probed yet, or the probe itself failed.
+    #[default]
+    Unknown,
+    Verified,
+    /// Got a forged certificate or a non-TLS response.
+    Intercepted,
+}
+
 #[derive(Debug, Serialize, Clone, Copy, Default)]
 pub enum Delay {
     #[default]
     Unknown,
     Some(Duration),
     TimedOut,
+    /// Failed well before timed out, e.g. closed by the server.
+    Failed,
 }
 
 impl Delay {
@@ -129,7 +413,7 @@ impl ToLua<'_> for Delay {
     fn to_lua(self, ctx: LuaContext<'_>) -> LuaResult<LuaValue<'_>> {
         match self {
             Delay::Some(d) => Some(d.as_secs_f32()),
-            Delay::TimedOut => Some(-1f32),
+            Delay::TimedOut | Delay::Failed => Some(-1f32),
             Delay::Unknown => None,
         }
         .to_lua(ctx)
@@ -146,6 +430,63 @@ pub struct ProxyServerStatus {
     pub conn_error: u32,
     #[serde_as(as = "DisplayFromStr")]
     pub close_history: u64,
+    /// Number of retries on connecting.
+    pub conn_retry: u32,
+    /// Like `close_history` but 1 for connection established after retry.
+    #[serde_as(as = "DisplayFromStr")]
+    pub retry_history: u64,
+    pub tls_verification: TlsVerification,
+    /// Set if the server refused our credential.
+    pub auth_failed: bool,
+    /// Number of handshakes in progress, see `ProxyServer::handshake_permit()`.
+    pub handshakes: u32,
+    /// Number of failed handshakes on connecting for clients.
+    pub handshake_errors: HandshakeErrorCounts,
+    /// Failed probes by phase, see `ProxyServer::update_connect_failure()`.
+    pub connect_failures: ConnectFailureCounts,
+    /// Number of alive connections counted as bulk, see `BulkThreshold`.
+    pub bulk_alive: u32,
+    /// Total number of connections counted as bulk.
+    pub bulk_total: u32,
+    /// Time-to-first-byte of recent connections.
+    pub ttfb: TtfbStats,
+    /// Outcomes of racing with other servers on connecting.
+    pub race: RaceStats,
+    /// Set if the score is above `ScoreLimits::max`.
+    pub excluded_by_score: bool,
+    /// Set after the first successful probe following a time out, until
+    /// the next one succeeded too.
+    pub recovering: bool,
+    /// Serialized as the time elapsed since then.
+    #[serde(rename = "last_probe_age", serialize_with = "serialize_age")]
+    pub last_probe_at: Option<Instant>,
+    /// Breakdown of the last probe, `None` if it failed.
+    pub probe_timings: Option<ProbeTimings>,
+}
+
+/// Time taken by each step of a probe.
+#[derive(Debug, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProbeTimings {
+    /// Establishing TCP connection to the proxy server.
+    pub tcp_connect: Duration,
+    /// Prelude and proxy handshake, after the TCP connection.
+    pub handshake: Duration,
+    /// Waiting for the response of the test query, after the handshake.
+    /// Zero for probes without a query.
+    pub probe_query: Duration,
+    /// The last step reached, where it failed if it did.
+    #[serde(skip)]
+    pub phase: HandshakePhase,
+}
+
+impl ProbeTimings {
+    pub fn total(&self) -> Duration {
+        self.tcp_connect + self.handshake + self.probe_query
+    }
+}
+
+fn serialize_age<S: Serializer>(at: &Option<Instant>, serializer: S) -> Result<S::Ok, S::Error> {
+    at.map(|at| at.elapsed()).serialize(serializer)
 }
 
 #[cfg(feature = "score_script")]
@@ -158,21 +499,35 @@ impl ToLua<'_> for ProxyServerStatus {
         status.set("conn_total", self.conn_total)?;
         status.set("conn_error", self.conn_error)?;
         status.set("close_history", self.close_history)?;
+        status.set("conn_retry", self.conn_retry)?;
+        status.set("retry_history", self.retry_history)?;
+        status.set(
+            "intercepted",
+            self.tls_verification == TlsVerification::Intercepted,
+        )?;
+        status.set("auth_failed", self.auth_failed)?;
+        status.set("handshakes", self.handshakes)?;
+        status.set("bulk_alive", self.bulk_alive)?;
+        status.set("bulk_total", self.bulk_total)?;
+        status.set("ttfb_mean", self.ttfb.mean().map(|d| d.as_secs_f32()))?;
+        status.set("race_win_ratio", self.race.win_ratio())?;
+        status.set("excluded_by_score", self.excluded_by_score)?;
+        status.set("recovering", self.recovering)?;
         status.to_lua(ctx)
     }
 }
 
 impl Hash for ProxyServer {
     fn hash<H: Hasher>(&self, state: &mut H) {
+        // Hash must keep unchanged when the tag is renamed
         self.addr.hash(state);
         self.proto.hash(state);
-        self.tag.hash(state);
     }
 }
 
 impl PartialEq for ProxyServer {
     fn eq(&self, other: &ProxyServer) -> bool {
-        self.addr == other.addr && self.proto == other.proto && self.tag == other.tag
+        self.addr == other.addr && self.proto == other.proto && self.tag() == other.tag()
     }
 }
 
@@ -184,7 +539,7 @@ impl ToLua<'_> for &ProxyServer {
         let table = ctx.create_table()?;
         table.set("addr", self.addr.to_string())?;
         table.set("proto", self.proto.to_string())?;
-        table.set("tag", self.tag.to_string())?;
+        table.set("tag", self.tag().to_string())?;
         table.set("config", self.config.read().clone())?;
         table.set("status", *self.status.lock())?;
         table.set("traffic", self.traffic())?;
@@ -233,10 +588,39 @@ impl From<[u8; 16]> for Address {
     }
 }
 
+/// Normalize a domain name: strip the trailing dot, lowercase, and encode
+/// non-ASCII labels with punycode. Applied to domain names from clients,
+/// so that policies match them the same way and upstreams accept them.
+pub fn normalize_domain(name: &str) -> io::Result<SharedStr> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_ascii() {
+        return Ok(name.to_ascii_lowercase().into());
+    }
+    idna::domain_to_ascii(name)
+        .map(Into::into)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid domain name"))
+}
+
 #[derive(Clone)]
 pub struct Destination {
     pub host: Address,
     pub port: u16,
+    /// IPv6 scope ID of link-local destinations, 0 if none. It has no wire
+    /// representation thus never sent to upstreams, but used on direct
+    /// connects.
+    pub scope_id: u32,
+}
+
+impl Destination {
+    /// Convert IPv4-mapped IPv6 address (`::ffff:a.b.c.d`) into IPv4.
+    pub fn canonicalize(&mut self) {
+        if let Address::Ip(ip) = &mut self.host {
+            *ip = ip.to_canonical();
+            if ip.is_ipv4() {
+                self.scope_id = 0;
+            }
+        }
+    }
 }
 
 impl fmt::Debug for Destination {
@@ -247,9 +631,14 @@ impl fmt::Debug for Destination {
 
 impl From<SocketAddr> for Destination {
     fn from(addr: SocketAddr) -> Self {
+        let scope_id = match addr {
+            SocketAddr::V6(addr) => addr.scope_id(),
+            SocketAddr::V4(_) => 0,
+        };
         Destination {
             host: Address::Ip(addr.ip()),
             port: addr.port(),
+            scope_id,
         }
     }
 }
@@ -259,6 +648,7 @@ impl<'a> From<(&'a str, u16)> for Destination {
         Destination {
             host: Address::Domain(addr.0.into()),
             port: addr.1,
+            scope_id: 0,
         }
     }
 }
@@ -268,6 +658,7 @@ impl From<(Address, u16)> for Destination {
         Destination {
             host: addr_port.0,
             port: addr_port.1,
+            scope_id: 0,
         }
     }
 }
@@ -354,6 +745,7 @@ impl ProxyProto {
         ProxyProto::Socks5 {
             fake_handshaking,
             user_pass_auth: None,
+            legacy_auth_version: false,
         }
     }
 
@@ -361,13 +753,19 @@ impl ProxyProto {
         ProxyProto::Socks5 {
             fake_handshaking: false,
             user_pass_auth: Some(credential),
+            legacy_auth_version: false,
         }
     }
 
+    pub fn socks4(remote_dns: bool) -> Self {
+        ProxyProto::Socks4 { remote_dns }
+    }
+
     pub fn http(connect_with_payload: bool, credential: Option<UserPassAuthCredential>) -> Self {
         ProxyProto::Http {
             connect_with_payload,
             user_pass_auth: credential,
+            auth_on_challenge: false,
         }
     }
 }
@@ -382,7 +780,21 @@ impl ProxyServerConfig {
         Self {
             test_dns,
             max_wait,
+            auto_max_wait: None,
             capabilities: capabilities.unwrap_or_default(),
+            dynamic_capabilities: Default::default(),
+            probe_verify_tls: None,
+            probe_port: None,
+            probe_interval: None,
+            allowed_ports: None,
+            tcp_options: Default::default(),
+            half_close_timeout: DEFAULT_HALF_CLOSE_TIMEOUT,
+            prelude: None,
+            handshake_limit: None,
+            global_handshake_limit: None,
+            bulk_threshold: None,
+            no_early_payload: false,
+            score_limits: Default::default(),
             score_base: score_base.unwrap_or(0),
         }
     }
@@ -397,27 +809,57 @@ impl ProxyServer {
         capabilities: Option<CapSet>,
         tag: Option<&str>,
         score_base: Option<i32>,
-    ) -> ProxyServer {
-        ProxyServer {
+    ) -> io::Result<ProxyServer> {
+        let tag = match tag {
+            None => shared_fmt!("{}", addr.port()),
+            Some(s) => {
+                if let Err(msg) = check_tag(s) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid tag \"{}\": {}", s, msg),
+                    ));
+                }
+                SharedStr::from(s)
+            }
+        };
+        Ok(ProxyServer {
             addr,
             proto,
-            tag: match tag {
-                None => shared_fmt!("{}", addr.port()),
-                Some(s) => {
-                    if !s.is_ascii() || s.contains(' ') || s.contains('\n') {
-                        panic!(
-                            "Tag \"{}\" contains white spaces, line \
-                             breaks, or non-ASCII characters.",
-                            s
-                        );
-                    }
-                    SharedStr::from(s)
-                }
-            },
+            tag: tag.into(),
             config: ProxyServerConfig::new(test_dns, score_base, capabilities, max_wait).into(),
             status: Default::default(),
+            delay_history: Default::default(),
             traffic: Default::default(),
-        }
+        })
+    }
+
+    /// SOCKSv5 server without auth and with a max wait of 1 second.
+    #[cfg(test)]
+    pub(crate) fn test_socks5(addr: SocketAddr, tag: Option<&str>) -> Self {
+        let test_dns = ([127, 0, 0, 1], 53).into();
+        let max_wait = Duration::from_secs(1);
+        Self::new(
+            addr,
+            ProxyProto::socks5(false),
+            test_dns,
+            max_wait,
+            None,
+            tag,
+            None,
+        )
+        .unwrap()
+    }
+
+    /// HTTP CONNECT server without auth.
+    #[cfg(test)]
+    pub(crate) fn test_http(
+        addr: SocketAddr,
+        connect_with_payload: bool,
+        max_wait: Duration,
+    ) -> Self {
+        let test_dns = ([127, 0, 0, 1], 53).into();
+        let proto = ProxyProto::http(connect_with_payload, None);
+        Self::new(addr, proto, test_dns, max_wait, None, None, None).unwrap()
     }
 
     pub fn direct(max_wait: Duration) -> Self {
@@ -425,52 +867,157 @@ impl ProxyServer {
         Self {
             addr: stub_addr,
             proto: ProxyProto::Direct,
-            tag: "__DIRECT__".into(),
+            tag: SharedStr::from("__DIRECT__").into(),
             config: ProxyServerConfig::new(stub_addr, None, None, max_wait).into(),
             status: Default::default(),
+            delay_history: Default::default(),
             traffic: Default::default(),
         }
     }
 
+    pub fn tag(&self) -> SharedStr {
+        self.tag.read().clone()
+    }
+
+    /// Copy config and tag from another server object.
     pub fn copy_config_from(&self, from: &Self) {
         if !std::ptr::eq(&from.config, &self.config) {
-            *self.config.write() = from.config.read().clone();
+            *self.tag.write() = from.tag();
+            let mut config = from.config.read().clone();
+            let mut this = self.config.write();
+            config.dynamic_capabilities = std::mem::take(&mut this.dynamic_capabilities);
+            // Keep in-progress handshakes counted if the limit is unchanged
+            if let (Some(new), Some(old)) = (&config.handshake_limit, &this.handshake_limit) {
+                if new.max == old.max {
+                    config.handshake_limit = Some(old.clone());
+                }
+            }
+            *this = config;
         }
     }
 
+    pub fn update_config<F>(&self, func: F)
+    where
+        F: FnOnce(&mut ProxyServerConfig),
+    {
+        func(&mut self.config.write())
+    }
+
+    pub async fn connect<T>(&self, addr: &Destination, data: Option<T>) -> io::Result<ProxyStream>
+    where
+        T: AsRef<[u8]> + 'static,
+    {
+        self.connect_with_auth(addr, data, None).await
+    }
+
+    /// Connect with `auth` instead of the credential configured on the
+    /// server, if given. Applied on SOCKSv5 & HTTP servers only.
+    /// Rejected `auth` won't mark the server as auth failed.
+    pub async fn connect_with_auth<T>(
+        &self,
+        addr: &Destination,
+        data: Option<T>,
+        auth: Option<&UserPassAuthCredential>,
+    ) -> io::Result<ProxyStream>
+    where
+        T: AsRef<[u8]> + 'static,
+    {
+        self.connect_timed(addr, data, auth, None).await
+    }
+
+    /// Like `connect_with_auth()`, fill `tcp_connect` and `handshake` of
+    /// `timings` if given.
     #[instrument(skip_all)]
-    pub async fn connect<T>(&self, addr: &Destination, data: Option<T>) -> io::Result<TcpStream>
+    pub async fn connect_timed<T>(
+        &self,
+        addr: &Destination,
+        data: Option<T>,
+        auth: Option<&UserPassAuthCredential>,
+        timings: Option<&mut ProbeTimings>,
+    ) -> io::Result<ProxyStream>
     where
         T: AsRef<[u8]> + 'static,
     {
-        let mut stream = TcpStream::connect(&self.addr).await?;
+        let mut untimed = ProbeTimings::default();
+        let timings = timings.unwrap_or(&mut untimed);
+        timings.phase = HandshakePhase::Connect;
+        let started = Instant::now();
+        let mut stream = self.tcp_options().connect(&self.addr).await?;
+        let connected = Instant::now();
+        timings.tcp_connect = connected - started;
+        timings.phase = HandshakePhase::Negotiate;
         debug!(remote = %stream.peer_addr()?, "TCP established");
-        stream.set_nodelay(true)?;
+        if let Some(prelude) = self.prelude() {
+            prelude.exchange(&mut stream).await?;
+        }
 
+        let mut pending = Vec::new();
+        if !matches!(self.proto, ProxyProto::Socks5 { .. }) {
+            timings.phase = HandshakePhase::Request;
+        }
         match &self.proto {
             ProxyProto::Direct => unimplemented!(),
+            #[cfg(feature = "shadowsocks")]
+            ProxyProto::Shadowsocks { cipher } => {
+                let stream = shadowsocks::connect(stream, cipher, addr, data).await?;
+                timings.handshake = connected.elapsed();
+                timings.phase = HandshakePhase::Response;
+                return Ok(ProxyStream::Shadowsocks(Box::new(stream)));
+            }
             ProxyProto::Socks5 {
                 fake_handshaking,
                 user_pass_auth,
+                legacy_auth_version,
             } => {
-                socks5::handshake(&mut stream, addr, data, *fake_handshaking, user_pass_auth)
-                    .await?
+                let user_pass_auth = auth.cloned().or_else(|| user_pass_auth.clone());
+                socks5::handshake_with_phase(
+                    &mut stream,
+                    addr,
+                    data,
+                    *fake_handshaking,
+                    &user_pass_auth,
+                    *legacy_auth_version,
+                    &mut timings.phase,
+                )
+                .await?
+            }
+            ProxyProto::Socks4 { remote_dns } => {
+                socks4::handshake(&mut stream, addr, data, *remote_dns).await?
             }
             ProxyProto::Http {
                 connect_with_payload,
                 user_pass_auth,
+                auth_on_challenge,
             } => {
-                http::handshake(
+                let user_pass_auth = auth.cloned().or_else(|| user_pass_auth.clone());
+                pending = http::handshake(
                     &mut stream,
                     addr,
                     data,
                     *connect_with_payload,
-                    user_pass_auth,
+                    &user_pass_auth,
+                    *auth_on_challenge,
                 )
-                .await?
+                .await
+                .map_err(|err| {
+                    if auth.is_none()
+                        && matches!(
+                            err,
+                            HandshakeError::AuthRequired | HandshakeError::AuthRejected
+                        )
+                    {
+                        self.set_auth_failed(true, &err);
+                    }
+                    err
+                })?;
             }
         }
-        Ok(stream)
+        timings.handshake = connected.elapsed();
+        timings.phase = HandshakePhase::Response;
+        if auth.is_none() && self.auth_failed() {
+            self.set_auth_failed(false, "handshake succeeded");
+        }
+        Ok(ProxyStream::with_pending(stream, pending))
     }
 
     pub fn status_snapshot(&self) -> ProxyServerStatus {
@@ -481,6 +1028,11 @@ impl ProxyServer {
         self.status.lock().score
     }
 
+    /// See `ProxyServerStatus::candidate_score()`.
+    pub fn candidate_score(&self) -> Option<i32> {
+        self.status.lock().candidate_score()
+    }
+
     pub fn traffic(&self) -> Traffic {
         self.traffic.read()
     }
@@ -493,15 +1045,144 @@ impl ProxyServer {
         self.config.read().test_dns
     }
 
+    pub fn tcp_options(&self) -> TcpOptions {
+        self.config.read().tcp_options
+    }
+
+    /// Wait for permits from both the per-server and the global handshake
+    /// limits, if any. The handshake is counted as in-progress until the
+    /// returned permit is dropped.
+    pub async fn handshake_permit(&self) -> HandshakePermit<'_> {
+        let (limit, global_limit) = {
+            let config = self.config.read();
+            (
+                config.handshake_limit.clone(),
+                config.global_handshake_limit.clone(),
+            )
+        };
+        // Per-server first, avoid holding global permits while waiting
+        let permit = match limit {
+            Some(limit) => limit.acquire().await,
+            None => None,
+        };
+        let global_permit = match global_limit {
+            Some(limit) => limit.acquire().await,
+            None => None,
+        };
+        self.status.lock().handshakes += 1;
+        HandshakePermit {
+            server: self,
+            _permits: [permit, global_permit],
+        }
+    }
+
+    pub fn prelude(&self) -> Option<Prelude> {
+        self.config.read().prelude.clone()
+    }
+
+    pub fn half_close_timeout(&self) -> Duration {
+        self.config.read().half_close_timeout
+    }
+
+    pub fn bulk_threshold(&self) -> Option<BulkThreshold> {
+        self.config.read().bulk_threshold
+    }
+
+    pub fn no_early_payload(&self) -> bool {
+        self.config.read().no_early_payload
+    }
+
+    pub fn score_limits(&self) -> ScoreLimits {
+        self.config.read().score_limits
+    }
+
+    pub fn probe_verify_tls(&self) -> Option<SharedStr> {
+        self.config.read().probe_verify_tls.clone()
+    }
+    pub fn probe_port(&self) -> Option<u16> {
+        self.config.read().probe_port
+    }
+
+    pub fn probe_interval(&self) -> Option<Duration> {
+        self.config.read().probe_interval
+    }
+
+    pub fn allowed_ports(&self) -> Option<Vec<u16>> {
+        self.config.read().allowed_ports.clone()
+    }
+
+    /// Return false if `port` is not in `allowed ports` of this server.
+    pub fn allows_port(&self, port: u16) -> bool {
+        match &self.config.read().allowed_ports {
+            None => true,
+            Some(ports) => ports.binary_search(&port).is_ok(),
+        }
+    }
+
+    /// Set if the server refused our credential. The server should not be
+    /// used until it's reloaded or passed a probe.
+    pub fn auth_failed(&self) -> bool {
+        self.status.lock().auth_failed
+    }
+
+    pub fn set_auth_failed<E: fmt::Display>(&self, failed: bool, reason: E) {
+        let was_failed = std::mem::replace(&mut self.status.lock().auth_failed, failed);
+        match (was_failed, failed) {
+            (false, true) => {
+                error!(proxy = %self.tag(), "authentication failed, stop using it: {}", reason)
+            }
+            (true, false) => info!(proxy = %self.tag(), "authentication recovered: {}", reason),
+            _ => (),
+        }
+    }
+
+    /// Capabilities from the server list, without dynamic ones.
+    pub fn capabilities(&self) -> CapSet {
+        self.config.read().capabilities.clone()
+    }
+
+    pub fn dynamic_capabilities(&self) -> CapSet {
+        self.config.read().dynamic_capabilities.clone()
+    }
+
+    pub fn set_dynamic_capabilities(&self, caps: CapSet) {
+        self.config.write().dynamic_capabilities = caps;
+    }
+
+    pub fn set_tls_verification(&self, verification: TlsVerification) {
+        self.status.lock().tls_verification = verification;
+    }
+
+    pub fn set_probe_timings(&self, timings: Option<ProbeTimings>) {
+        self.status.lock().probe_timings = timings;
+    }
+
+    pub fn last_probe_at(&self) -> Option<Instant> {
+        self.status.lock().last_probe_at
+    }
+
+    /// Keep the probe delay in history, and adjust `max_wait` if it's
+    /// auto.
+    fn record_delay(&self, delay: Option<Duration>) {
+        let mut history = self.delay_history.lock();
+        history.add(delay.into());
+        let mut config = self.config.write();
+        if let Some(max_wait) = config.auto_max_wait.and_then(|auto| auto.compute(&history)) {
+            config.max_wait = max_wait;
+        }
+    }
+
     pub fn update_delay(&self, delay: Option<Duration>) {
+        self.record_delay(delay);
         let mut status = self.status.lock();
         let config = self.config.read();
+        status.last_probe_at = Some(Instant::now());
 
         if let Some(delay) = delay {
             let last_score = status.score.unwrap_or_else(|| {
                 match status.delay {
                     Delay::Some(d) => d,
-                    Delay::Unknown => delay,
+                    Delay::Unknown | Delay::Failed => delay,
                     Delay::TimedOut => config.max_wait,
                 }
                 .as_millis() as i32
@@ -511,9 +1192,13 @@ impl ProxyServer {
                 .recent_error_rate(16)
                 .min(status.recent_error_rate(64));
 
+            let retry_rate = status.recent_retry_rate(16);
+
             let score = delay.as_millis() as i32 + config.score_base;
             // give penalty for continuous errors
             let score = score + (score as f32 * err_rate * 10f32).round() as i32;
+            // and less penalty for connections need retry
+            let score = score + (score as f32 * retry_rate * 2f32).round() as i32;
             // moving average on score
             // give more weight to delays exceed the mean for network jitter penalty
             let score = if score < last_score {
@@ -522,27 +1207,68 @@ impl ProxyServer {
                 (last_score * 8 + score * 2) / 10
             };
             status.score = Some(score);
-            status.delay = Delay::Some(delay);
+            status.record_probe(config.score_limits, Some(delay));
 
             // Shift error history
             // This give the server with high error penalty a chance to recovery.
             status.close_history <<= 1;
+            status.retry_history <<= 1;
         } else {
             // Timed out
-            status.delay = Delay::TimedOut;
             status.score = None;
+            status.record_probe(config.score_limits, None);
         };
     }
 
+    /// Update score with a probe failed in `phase`.
+    ///
+    /// Timed out ones are the same as `update_delay(None)`. Others failed
+    /// fast, telling the server is sick rather than stuck: it's down as
+    /// well, but the next successful probe makes it a candidate at once,
+    /// with the score from that delay instead of the one of `max_wait`.
+    pub fn update_connect_failure(&self, phase: HandshakePhase, timed_out: bool) {
+        self.add_connect_failure(phase, timed_out);
+        if timed_out {
+            return self.update_delay(None);
+        }
+        let mut status = self.status.lock();
+        status.last_probe_at = Some(Instant::now());
+        status.score = None;
+        status.delay = Delay::Failed;
+        status.recovering = false;
+        status.excluded_by_score = false;
+    }
+
+    /// Forget the score and the delay, e.g. stale after resumed from
+    /// suspend, along with errors of connections broken by that, so that
+    /// the next probe scores it as on start.
+    pub fn invalidate_score(&self) {
+        let mut status = self.status.lock();
+        status.delay = Delay::Unknown;
+        status.score = None;
+        status.close_history = 0;
+        status.retry_history = 0;
+        status.recovering = false;
+        status.excluded_by_score = false;
+    }
+
+    /// Count a failed probe only, for scores computed elsewhere.
+    pub fn add_connect_failure(&self, phase: HandshakePhase, timed_out: bool) {
+        self.status.lock().connect_failures.add(phase, timed_out);
+    }
+
     #[cfg(feature = "score_script")]
     pub fn update_delay_with_lua(&self, delay: Option<Duration>, ctx: LuaContext) -> LuaResult<()> {
         let func: LuaFunction = ctx.globals().get("calc_score")?;
         let delay_secs = delay.map(|t| t.as_secs_f32());
         let score: Option<i32> = func.call((self, delay_secs))?;
+        self.record_delay(delay);
 
+        let limits = self.score_limits();
         let mut status = self.status.lock();
         status.score = score;
-        status.delay = delay.into();
+        status.record_probe(limits, delay);
+        status.last_probe_at = Some(Instant::now());
         Ok(())
     }
 
@@ -550,10 +1276,57 @@ impl ProxyServer {
         self.traffic.add(traffic);
     }
 
-    pub fn update_stats_conn_open(&self) {
+    pub fn update_stats_conn_open(&self, retried: bool) {
         let mut status = self.status.lock();
         status.conn_alive += 1;
         status.conn_total += 1;
+        status.retry_history = status.retry_history << 1 | retried as u64;
+    }
+
+    /// Count an alive connection as bulk (`true`), or it's closed (`false`).
+    pub fn update_stats_bulk(&self, bulk: bool) {
+        let mut status = self.status.lock();
+        if bulk {
+            status.bulk_alive += 1;
+            status.bulk_total += 1;
+        } else {
+            status.bulk_alive -= 1;
+        }
+    }
+
+    /// Record the time from the upstream handshake finished to the first
+    /// data back from remote.
+    pub fn add_ttfb(&self, ttfb: Duration) {
+        self.status.lock().ttfb.add(ttfb);
+    }
+
+    /// Record a race with other servers won.
+    pub fn add_race_won(&self) {
+        self.status.lock().race.add_won();
+    }
+
+    /// Record a race with other servers lost, with the time it took to
+    /// finish the handshake anyway, or `None` if failed.
+    pub fn add_race_lost(&self, handshake: Option<Duration>) {
+        self.status.lock().race.add_lost(handshake);
+    }
+
+    pub fn add_handshake_error(&self, err: &HandshakeError) {
+        self.status.lock().handshake_errors.add(err);
+    }
+
+    pub fn update_stats_conn_retry(&self) {
+        self.status.lock().conn_retry += 1;
+    }
+
+    /// Count a connection failed before established, as if it's closed
+    /// with error immediately.
+    pub fn update_stats_conn_failed(&self) {
+        let mut status = self.status.lock();
+        status.conn_total += 1;
+        status.conn_error += 1;
+        status.close_history = status.close_history << 1 | 1;
+        status.retry_history <<= 1;
     }
 
     pub fn update_stats_conn_close(&self, has_error: bool) {
@@ -570,24 +1343,93 @@ impl ProxyServer {
         format!(
             "{}.{}.{}",
             GRAPHITE_PATH_PREFIX,
-            self.tag.replace('.', "_"),
+            self.tag().replace('.', "_"),
             suffix
         )
     }
 
     pub fn capable_anyof(&self, caps: &CapSet) -> bool {
-        self.config.read().capabilities.has_intersection(caps)
+        let verify_tls = {
+            let config = self.config.read();
+            if config.capabilities.has_intersection(caps)
+                || config.dynamic_capabilities.has_intersection(caps)
+            {
+                return true;
+            }
+            config.probe_verify_tls.is_some()
+        };
+        verify_tls
+            && caps.contains(CAP_TLS_VERIFIED)
+            && self.status.lock().tls_verification == TlsVerification::Verified
+    }
+}
+
+/// Check if `tag` is usable as a server tag, which is used in Graphite
+/// paths, metric labels and URL paths.
+pub fn check_tag(tag: &str) -> Result<(), &'static str> {
+    if tag.is_empty() {
+        Err("empty tag")
+    } else if tag.len() > 255 {
+        Err("tag too long")
+    } else if tag.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        Err("white spaces or control characters in tag")
+    } else if tag.contains(['/', '"', '\\']) {
+        Err("slash, backslash or double quote in tag")
+    } else {
+        Ok(())
+    }
+}
+
+/// Number of bits set in the lowest `n` (at most 64) bits of `history`.
+fn recent_count(history: u64, n: u8) -> u32 {
+    match cmp::min(n, 64) {
+        0 => 0,
+        n => (history << (64 - n)).count_ones(),
+    }
+}
+
+/// Like `recent_count()` but divided by `n`, 0 if `n` is 0.
+fn recent_rate(history: u64, n: u8) -> f32 {
+    match cmp::min(n, 64) {
+        0 => 0.0,
+        n => recent_count(history, n) as f32 / n as f32,
     }
 }
 
 impl ProxyServerStatus {
-    pub fn recent_error_count(&self, n: u8) -> u8 {
-        let n = 64 - cmp::min(n, 64);
-        (self.close_history << n).count_ones() as u8
+    /// Score to choose servers by: `score` unless excluded by score or
+    /// still recovering.
+    pub fn candidate_score(&self) -> Option<i32> {
+        self.score
+            .filter(|_| !self.excluded_by_score && !self.recovering)
+    }
+
+    /// Record the probe result after `score` updated, then apply `limits`
+    /// on it. A server timed out needs two successful probes in a row to
+    /// be a candidate again, rather than one lucky fresh score.
+    fn record_probe(&mut self, limits: ScoreLimits, delay: Option<Duration>) {
+        self.recovering = delay.is_some() && matches!(self.delay, Delay::TimedOut);
+        self.delay = delay.into();
+        if let (Some(score), Some(min)) = (self.score, limits.min) {
+            self.score = Some(score.max(min));
+        }
+        self.excluded_by_score = match (self.score, limits.max) {
+            (Some(score), Some(max)) => score > max,
+            _ => false,
+        };
+    }
+
+    /// Number of errors in the last `n` (at most 64) connections.
+    pub fn recent_error_count(&self, n: u8) -> u32 {
+        recent_count(self.close_history, n)
     }
 
     pub fn recent_error_rate(&self, n: u8) -> f32 {
-        self.recent_error_count(n) as f32 / (cmp::min(n, 64) as f32)
+        recent_rate(self.close_history, n)
+    }
+
+    pub fn recent_retry_rate(&self, n: u8) -> f32 {
+        recent_rate(self.retry_history, n)
     }
 }
 
@@ -596,7 +1438,7 @@ impl fmt::Display for ProxyServer {
         if self.proto == ProxyProto::Direct {
             f.write_str("DIRECT")
         } else {
-            write!(f, "{} ({} {})", self.tag, self.proto, self.addr)
+            write!(f, "{} ({} {})", self.tag(), self.proto, self.addr)
         }
     }
 }
@@ -605,8 +1447,11 @@ impl fmt::Display for ProxyProto {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             ProxyProto::Socks5 { .. } => write!(f, "SOCKSv5"),
+            ProxyProto::Socks4 { .. } => write!(f, "SOCKSv4"),
             ProxyProto::Http { .. } => write!(f, "HTTP"),
-            ProxyProto::Direct { .. } => write!(f, "DIRECT"),
+            #[cfg(feature = "shadowsocks")]
+            ProxyProto::Shadowsocks { .. } => write!(f, "Shadowsocks"),
+            ProxyProto::Direct => write!(f, "DIRECT"),
         }
     }
 }
@@ -632,9 +1477,219 @@ impl FromStr for ProxyProto {
         match s.to_lowercase().as_str() {
             // default to disable fake handshaking
             "socks5" | "socksv5" => Ok(ProxyProto::socks5(false)),
+            "socks4" | "socksv4" => Ok(ProxyProto::socks4(false)),
+            "socks4a" | "socksv4a" => Ok(ProxyProto::socks4(true)),
             // default to disable connect with payload
             "http" => Ok(ProxyProto::http(false, None)),
             _ => Err(()),
         }
     }
 }
+
+#[test]
+fn test_check_tag() {
+    assert!(check_tag("server-1").is_ok());
+    assert!(check_tag("hk.aws_01").is_ok());
+    assert!(check_tag("東京").is_ok());
+    assert!(check_tag("").is_err());
+    assert!(check_tag("a b").is_err());
+    assert!(check_tag("a\tb").is_err());
+    assert!(check_tag("a/b").is_err());
+    assert!(check_tag("a\"b").is_err());
+}
+
+#[test]
+fn test_normalize_domain() {
+    let normalize = |name| normalize_domain(name).unwrap().to_string();
+    assert_eq!("www.example.com", normalize("WWW.Example.COM"));
+    assert_eq!("example.com", normalize("example.com."));
+    assert_eq!("xn--bcher-kva.example", normalize("Bücher.example."));
+    assert_eq!("xn--wgv71a.jp", normalize("日本.jp"));
+    assert!(normalize_domain("a\u{fffd}.com").is_err());
+}
+
+#[test]
+fn test_prefer_non_bulk() {
+    let server = |port: u16, delay: Option<u64>| {
+        let server = ProxyServer::test_socks5(([127, 0, 0, 1], port).into(), None);
+        server.update_delay(delay.map(Duration::from_millis));
+        Arc::new(server)
+    };
+    let (a, b, c) = (server(1, Some(100)), server(2, Some(150)), server(3, None));
+    let mut servers = vec![c.clone(), a.clone(), b.clone()];
+    prefer_non_bulk(&mut servers);
+    assert_eq!(vec![a.clone(), b.clone(), c.clone()], servers);
+
+    a.update_stats_bulk(true);
+    prefer_non_bulk(&mut servers);
+    assert_eq!(vec![b.clone(), a.clone(), c.clone()], servers);
+    a.update_stats_bulk(false);
+    assert_eq!(1, a.status_snapshot().bulk_total);
+}
+
+#[test]
+fn test_sort_by_preference() {
+    use crate::policy::parser;
+
+    let server = |port: u16, delay: Option<u64>, caps| {
+        let (_, caps) = parser::capabilities(caps).unwrap();
+        let server = ProxyServer::test_socks5(([127, 0, 0, 1], port).into(), None);
+        server.update_config(|c| c.capabilities = caps);
+        server.update_delay(delay.map(Duration::from_millis));
+        Arc::new(server)
+    };
+    let (a, b, c) = (
+        server(1, Some(100), "fast"),
+        server(2, Some(300), "cheap"),
+        server(3, Some(200), "cheap"),
+    );
+    let prefer = HashSet::from([CapSet::new(["cheap"].into_iter())]);
+    let mut servers = vec![a.clone(), b.clone(), c.clone()];
+    sort_by_preference(&mut servers, false, &prefer, None, 1000);
+    assert_eq!(vec![c.clone(), b.clone(), a.clone()], servers);
+    // Not filtered, and the bonus is just a bonus
+    sort_by_preference(&mut servers, false, &prefer, None, 150);
+    assert_eq!(vec![c.clone(), a.clone(), b.clone()], servers);
+
+    // Preferred servers are down
+    b.update_delay(None);
+    c.update_delay(None);
+    sort_by_preference(&mut servers, false, &prefer, None, 1000);
+    assert_eq!(a, servers[0]);
+    assert_eq!(3, servers.len());
+}
+
+#[test]
+fn test_prefer_family() {
+    let addrs =
+        |list: &[&str]| -> Vec<SocketAddr> { list.iter().map(|a| a.parse().unwrap()).collect() };
+    let mut dial = addrs(&[
+        "192.0.2.1:80",
+        "[2001:db8::1]:80",
+        "192.0.2.2:80",
+        "[::ffff:192.0.2.3]:80",
+    ]);
+    IpFamily::V6.sort_first(&mut dial);
+    assert_eq!(
+        addrs(&[
+            "[2001:db8::1]:80",
+            "192.0.2.1:80",
+            "192.0.2.2:80",
+            "[::ffff:192.0.2.3]:80"
+        ]),
+        dial
+    );
+    IpFamily::V4.sort_first(&mut dial);
+    assert_eq!(
+        addrs(&[
+            "192.0.2.1:80",
+            "192.0.2.2:80",
+            "[::ffff:192.0.2.3]:80",
+            "[2001:db8::1]:80"
+        ]),
+        dial
+    );
+
+    let server = |addr: &str, delay| {
+        let server = ProxyServer::test_socks5(addr.parse().unwrap(), None);
+        server.update_delay(Some(Duration::from_millis(delay)));
+        Arc::new(server)
+    };
+    let (v4, v6) = (
+        server("192.0.2.1:1080", 100),
+        server("[2001:db8::1]:1080", 200),
+    );
+    let mut servers = vec![v6.clone(), v4.clone()];
+    let caps = HashSet::new();
+    sort_by_preference(&mut servers, false, &caps, None, 1000);
+    assert_eq!(vec![v4.clone(), v6.clone()], servers);
+    sort_by_preference(&mut servers, false, &caps, Some(IpFamily::V6), 1000);
+    assert_eq!(vec![v6.clone(), v4.clone()], servers);
+    sort_by_preference(&mut servers, false, &caps, Some(IpFamily::V4), 1000);
+    assert_eq!(vec![v4.clone(), v6.clone()], servers);
+}
+
+#[test]
+fn test_recent_error_count() {
+    let status = |close_history| ProxyServerStatus {
+        close_history,
+        ..Default::default()
+    };
+    let all = status(u64::MAX);
+    assert_eq!(0, all.recent_error_count(0));
+    assert_eq!(1, all.recent_error_count(1));
+    assert_eq!(64, all.recent_error_count(64));
+    assert_eq!(64, all.recent_error_count(u8::MAX));
+    assert_eq!(0.0, all.recent_error_rate(0));
+    assert_eq!(1.0, all.recent_error_rate(64));
+    assert_eq!(1.0, all.recent_error_rate(100));
+
+    // Only the latest one at the lowest bit failed
+    let one = status(1);
+    assert_eq!(1, one.recent_error_count(1));
+    assert_eq!(1, one.recent_error_count(64));
+    assert_eq!(1.0 / 16.0, one.recent_error_rate(16));
+    let oldest = status(1 << 63);
+    assert_eq!(0, oldest.recent_error_count(16));
+    assert_eq!(1, oldest.recent_error_count(64));
+    assert_eq!(0.0, status(0).recent_retry_rate(0));
+}
+
+#[test]
+fn test_score_limits() {
+    let server = |score_base, min, max| {
+        let server = ProxyServer::new(
+            ([127, 0, 0, 1], 1080).into(),
+            ProxyProto::socks5(false),
+            "127.0.0.1:53".parse().unwrap(),
+            Duration::from_secs(1),
+            None,
+            None,
+            score_base,
+        )
+        .unwrap();
+        server.update_config(|config| config.score_limits = ScoreLimits { min, max });
+        server
+    };
+    let ms = |n| Some(Duration::from_millis(n));
+
+    // Excluded only if above the max
+    let at_max = server(None, None, Some(100));
+    at_max.update_delay(ms(100));
+    assert_eq!(Some(100), at_max.candidate_score());
+    let above_max = server(None, None, Some(100));
+    above_max.update_delay(ms(101));
+    assert_eq!(None, above_max.candidate_score());
+    let status = above_max.status_snapshot();
+    assert!(status.excluded_by_score);
+    assert_eq!(Some(101), status.score);
+    // Still reported as is
+    assert_eq!(Some(101), above_max.score());
+
+    // Kept at the floor
+    let negative = server(Some(-500), None, None);
+    negative.update_delay(ms(100));
+    assert_eq!(Some(-400), negative.candidate_score());
+    let floored = server(Some(-500), Some(0), None);
+    floored.update_delay(ms(100));
+    assert_eq!(Some(0), floored.candidate_score());
+    let above_min = server(None, Some(0), None);
+    above_min.update_delay(ms(1));
+    assert_eq!(Some(1), above_min.candidate_score());
+
+    // Two successful probes in a row to recover from time out
+    let flaky = server(None, None, None);
+    flaky.update_delay(ms(100));
+    assert!(flaky.candidate_score().is_some());
+    flaky.update_delay(None);
+    assert_eq!(None, flaky.candidate_score());
+    flaky.update_delay(ms(100));
+    assert_eq!(None, flaky.candidate_score());
+    assert!(flaky.status_snapshot().recovering);
+    flaky.update_delay(None);
+    flaky.update_delay(ms(100));
+    assert_eq!(None, flaky.candidate_score());
+    flaky.update_delay(ms(100));
+    assert!(flaky.candidate_score().is_some());
+    assert!(!flaky.status_snapshot().recovering);
+}
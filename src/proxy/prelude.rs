@@ -0,0 +1,71 @@
+use std::io;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tracing::debug;
+
+/// Bytes exchanged with the server right after TCP connected, before the
+/// handshake of proxy protocol.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Prelude {
+    /// Written to the server.
+    pub send: Vec<u8>,
+    /// Read from the server then, must be exactly the same.
+    pub expect: Vec<u8>,
+}
+
+impl Prelude {
+    pub async fn exchange(&self, stream: &mut TcpStream) -> io::Result<()> {
+        if !self.send.is_empty() {
+            stream.write_all(&self.send).await?;
+        }
+        if !self.expect.is_empty() {
+            let mut buf = vec![0u8; self.expect.len()];
+            stream.read_exact(&mut buf).await?;
+            if buf != self.expect {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unexpected prelude response",
+                ));
+            }
+        }
+        debug!(
+            sent = self.send.len(),
+            read = self.expect.len(),
+            "prelude done"
+        );
+        Ok(())
+    }
+}
+
+/// Decode `hex:DEADBEEF` to bytes.
+pub fn parse_hex(value: &str) -> io::Result<Vec<u8>> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    let hex = value
+        .strip_prefix("hex:")
+        .ok_or_else(|| invalid("missing `hex:` prefix"))?;
+    if !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid("not a hex digit"));
+    }
+    if hex.len() % 2 != 0 {
+        return Err(invalid("odd number of hex digits"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid("not a hex digit")))
+        .collect()
+}
+
+#[test]
+fn test_parse_hex() {
+    assert_eq!(
+        vec![0xde, 0xad, 0xbe, 0xef],
+        parse_hex("hex:DEADbeef").unwrap()
+    );
+    assert!(parse_hex("hex:").unwrap().is_empty());
+    assert!(parse_hex("DEADBEEF").is_err());
+    assert!(parse_hex("hex:ABC").is_err());
+    assert!(parse_hex("hex:XY").is_err());
+    assert!(parse_hex("hex:+1").is_err());
+}
@@ -0,0 +1,102 @@
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+use std::time::Duration;
+
+/// All counts are halved once reaching this, so recent races dominate the
+/// statistic.
+const WINDOW: u32 = 1024;
+
+/// Rolling statistic of a server racing with others on `n_parallel`
+/// connecting, see `TryConnectAll`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RaceStats {
+    raced: u32,
+    won: u32,
+    /// Losers finished their handshakes in background.
+    lost_handshakes: u32,
+    lost_handshake_sum_ms: u64,
+}
+
+impl RaceStats {
+    fn add_race(&mut self) {
+        if self.raced >= WINDOW {
+            self.raced /= 2;
+            self.won /= 2;
+            self.lost_handshakes /= 2;
+            self.lost_handshake_sum_ms /= 2;
+        }
+        self.raced += 1;
+    }
+
+    pub fn add_won(&mut self) {
+        self.add_race();
+        self.won += 1;
+    }
+
+    /// `handshake` is the time taken to finish its handshake, `None` if
+    /// failed.
+    pub fn add_lost(&mut self, handshake: Option<Duration>) {
+        self.add_race();
+        if let Some(handshake) = handshake {
+            let ms = handshake.as_millis().min(u32::MAX as u128) as u64;
+            self.lost_handshakes += 1;
+            self.lost_handshake_sum_ms += ms;
+        }
+    }
+
+    /// Number of races in the window.
+    pub fn count(&self) -> u32 {
+        self.raced
+    }
+
+    /// Ratio of races won, in `0.0..=1.0`.
+    pub fn win_ratio(&self) -> Option<f32> {
+        match self.raced {
+            0 => None,
+            n => Some(self.won as f32 / n as f32),
+        }
+    }
+
+    /// Mean handshake time of races lost.
+    pub fn lost_handshake_mean(&self) -> Option<Duration> {
+        match self.lost_handshakes {
+            0 => None,
+            n => Some(Duration::from_millis(self.lost_handshake_sum_ms / n as u64)),
+        }
+    }
+}
+
+impl Serialize for RaceStats {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("RaceStats", 4)?;
+        state.serialize_field("count", &self.raced)?;
+        state.serialize_field("won", &self.won)?;
+        state.serialize_field("win_ratio", &self.win_ratio())?;
+        state.serialize_field("lost_handshake_mean", &self.lost_handshake_mean())?;
+        state.end()
+    }
+}
+
+#[test]
+fn test_race_stats() {
+    let mut stats = RaceStats::default();
+    assert_eq!(None, stats.win_ratio());
+    assert_eq!(None, stats.lost_handshake_mean());
+
+    stats.add_won();
+    stats.add_lost(Some(Duration::from_millis(100)));
+    stats.add_lost(Some(Duration::from_millis(300)));
+    stats.add_lost(None);
+    assert_eq!(4, stats.count());
+    assert_eq!(Some(0.25), stats.win_ratio());
+    assert_eq!(
+        Some(Duration::from_millis(200)),
+        stats.lost_handshake_mean()
+    );
+
+    // Rolling window
+    for _ in 0..WINDOW {
+        stats.add_won();
+    }
+    assert!(stats.count() <= WINDOW);
+    assert!(stats.win_ratio().unwrap() > 0.99);
+}
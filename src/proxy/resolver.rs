@@ -0,0 +1,563 @@
+use parking_lot::{Mutex, RwLock};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    io::{self, ErrorKind, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{lookup_host, TcpStream, UdpSocket},
+    time::timeout,
+};
+use tracing::{debug, trace};
+
+use crate::privacy::HOSTNAMES;
+
+/// Timeout of each query to each server.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Max number of names kept in cache, expired ones are dropped first.
+const MAX_CACHE_ENTRIES: usize = 1024;
+/// Cap TTL of cached records.
+const MAX_TTL: u32 = 3600;
+const MAX_UDP_LEN: usize = 1232;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// Stub resolver sending A & AAAA queries to `--resolver` servers, used
+/// for all internal resolutions. Resolved by the system if no server set.
+#[derive(Debug)]
+pub struct Resolver {
+    servers: RwLock<Vec<SocketAddr>>,
+    /// Lowercased name => addresses & when expire.
+    cache: Mutex<BTreeMap<String, (Vec<IpAddr>, Instant)>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+/// Snapshot of the cache of `Resolver`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ResolverCacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub size: usize,
+}
+
+/// Global resolver, see `Resolver::set_servers()`.
+pub static RESOLVER: Resolver = Resolver::new();
+
+/// Answers of a query, `ttl` is the min one among them.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Answers {
+    addrs: Vec<IpAddr>,
+    ttl: u32,
+}
+
+impl Resolver {
+    pub const fn new() -> Self {
+        Self {
+            servers: RwLock::new(Vec::new()),
+            cache: Mutex::new(BTreeMap::new()),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// Query these servers in order, empty for the system resolver.
+    pub fn set_servers(&self, servers: Vec<SocketAddr>) {
+        *self.servers.write() = servers;
+        self.cache.lock().clear();
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.servers.read().is_empty()
+    }
+
+    pub fn cache_stats(&self) -> ResolverCacheStats {
+        ResolverCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            size: self.cache.lock().len(),
+        }
+    }
+
+    /// Resolve `host` to socket addresses, IPv4 ones come first.
+    pub async fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let (addrs, _) = self.lookup_ip(host).await?;
+        with_port(addrs, port)
+    }
+
+    /// Resolve `host` to IP addresses, with how long they're valid for,
+    /// i.e. the (capped) TTL left. No TTL if resolved by the system, or
+    /// `host` is an IP address.
+    pub async fn lookup_ip(&self, host: &str) -> io::Result<(Vec<IpAddr>, Option<Duration>)> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok((vec![ip], None));
+        }
+        let servers = self.servers.read().clone();
+        if servers.is_empty() {
+            let addrs = lookup_host((host, 0)).await?.map(|addr| addr.ip());
+            return Ok((addrs.collect(), None));
+        }
+        let name = host.trim_end_matches('.').to_ascii_lowercase();
+        if let Some((addrs, ttl)) = self.cached(&name) {
+            return Ok((addrs, Some(ttl)));
+        }
+        let (v4, v6) = tokio::join!(
+            query_all(&servers, &name, TYPE_A),
+            query_all(&servers, &name, TYPE_AAAA),
+        );
+        let (addrs, ttl) = self.store(name, v4, v6)?;
+        Ok((addrs, Some(ttl)))
+    }
+
+    /// Like `lookup()` but blocking, for loading config.
+    pub fn lookup_blocking(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![(ip, port).into()]);
+        }
+        let servers = self.servers.read().clone();
+        if servers.is_empty() {
+            return Ok((host, port).to_socket_addrs()?.collect());
+        }
+        let name = host.trim_end_matches('.').to_ascii_lowercase();
+        if let Some((addrs, _)) = self.cached(&name) {
+            return with_port(addrs, port);
+        }
+        let v4 = query_all_blocking(&servers, &name, TYPE_A);
+        let v6 = query_all_blocking(&servers, &name, TYPE_AAAA);
+        self.store(name, v4, v6)
+            .and_then(|(addrs, _)| with_port(addrs, port))
+    }
+
+    /// Cached addresses with the TTL left.
+    fn cached(&self, name: &str) -> Option<(Vec<IpAddr>, Duration)> {
+        let cache = self.cache.lock();
+        let now = Instant::now();
+        match cache.get(name) {
+            Some((addrs, expire)) if *expire > now => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some((addrs.clone(), *expire - now))
+            }
+            _ => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Merge answers of A & AAAA, cache them unless both failed. Return
+    /// the addresses with the capped TTL.
+    fn store(
+        &self,
+        name: String,
+        v4: io::Result<Answers>,
+        v6: io::Result<Answers>,
+    ) -> io::Result<(Vec<IpAddr>, Duration)> {
+        let answers = match (v4, v6) {
+            (Err(err), Err(_)) => return Err(err),
+            (Ok(v4), Err(err)) | (Err(err), Ok(v4)) if v4.addrs.is_empty() => return Err(err),
+            (Ok(answers), Err(_)) | (Err(_), Ok(answers)) => answers,
+            (Ok(mut v4), Ok(v6)) => {
+                v4.ttl = match (v4.addrs.is_empty(), v6.addrs.is_empty()) {
+                    (true, _) => v6.ttl,
+                    (_, true) => v4.ttl,
+                    _ => v4.ttl.min(v6.ttl),
+                };
+                v4.addrs.extend(v6.addrs);
+                v4
+            }
+        };
+        if answers.addrs.is_empty() {
+            return Err(io::Error::new(ErrorKind::NotFound, "no address resolved"));
+        }
+        let ttl = answers.ttl.min(MAX_TTL);
+        trace!(name = %HOSTNAMES.name(&name), ?answers.addrs, ttl, "resolved");
+        let ttl = Duration::from_secs(ttl as u64);
+        if !ttl.is_zero() {
+            let now = Instant::now();
+            let expire = now + ttl;
+            let mut cache = self.cache.lock();
+            if cache.len() >= MAX_CACHE_ENTRIES {
+                cache.retain(|_, (_, expire)| *expire > now);
+            }
+            if cache.len() >= MAX_CACHE_ENTRIES {
+                cache.pop_first();
+            }
+            cache.insert(name, (answers.addrs.clone(), expire));
+        }
+        Ok((answers.addrs, ttl))
+    }
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn with_port(addrs: Vec<IpAddr>, port: u16) -> io::Result<Vec<SocketAddr>> {
+    Ok(addrs.into_iter().map(|ip| (ip, port).into()).collect())
+}
+
+/// Try `servers` in order until any of them answered.
+async fn query_all(servers: &[SocketAddr], name: &str, qtype: u16) -> io::Result<Answers> {
+    let mut last_err = io::Error::new(ErrorKind::InvalidInput, "no resolver");
+    for server in servers {
+        match query(*server, name, qtype).await {
+            Ok(answers) => return Ok(answers),
+            Err(err) => {
+                debug!(%server, name = %HOSTNAMES.name(name), qtype, "DNS query failed: {}", err);
+                last_err = err;
+            }
+        }
+    }
+    Err(last_err)
+}
+
+/// Query on UDP, retry on TCP if truncated.
+async fn query(server: SocketAddr, name: &str, qtype: u16) -> io::Result<Answers> {
+    let id = rand::random();
+    let request = build_query(id, name, qtype)?;
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(server).await?;
+    socket.send(&request).await?;
+    let mut buf = vec![0u8; MAX_UDP_LEN];
+    let response = timeout(QUERY_TIMEOUT, async {
+        loop {
+            let len = socket.recv(&mut buf).await?;
+            match parse_response(&buf[..len], id, qtype) {
+                // Stray response of previous queries
+                Err(err) if err.kind() == ErrorKind::InvalidData && is_other_id(&buf, id) => {
+                    continue
+                }
+                result => return result,
+            }
+        }
+    })
+    .await??;
+    if let Some(answers) = response {
+        return Ok(answers);
+    }
+    trace!(%server, name = %HOSTNAMES.name(name), "DNS response truncated, retry on TCP");
+    timeout(QUERY_TIMEOUT, async {
+        let mut stream = TcpStream::connect(server).await?;
+        stream.write_all(&with_length(&request)).await?;
+        let len = stream.read_u16().await? as usize;
+        buf.resize(len, 0);
+        stream.read_exact(&mut buf).await?;
+        parse_response(&buf, id, qtype)?
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "truncated DNS response"))
+    })
+    .await?
+}
+
+fn query_all_blocking(servers: &[SocketAddr], name: &str, qtype: u16) -> io::Result<Answers> {
+    let mut last_err = io::Error::new(ErrorKind::InvalidInput, "no resolver");
+    for server in servers {
+        match query_blocking(*server, name, qtype) {
+            Ok(answers) => return Ok(answers),
+            Err(err) => {
+                debug!(%server, name = %HOSTNAMES.name(name), qtype, "DNS query failed: {}", err);
+                last_err = err;
+            }
+        }
+    }
+    Err(last_err)
+}
+
+fn query_blocking(server: SocketAddr, name: &str, qtype: u16) -> io::Result<Answers> {
+    let id = rand::random();
+    let request = build_query(id, name, qtype)?;
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = std::net::UdpSocket::bind(local)?;
+    socket.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    socket.connect(server)?;
+    socket.send(&request)?;
+    let mut buf = vec![0u8; MAX_UDP_LEN];
+    loop {
+        let len = socket.recv(&mut buf)?;
+        match parse_response(&buf[..len], id, qtype) {
+            Err(err) if err.kind() == ErrorKind::InvalidData && is_other_id(&buf, id) => continue,
+            Ok(Some(answers)) => return Ok(answers),
+            Ok(None) => break,
+            Err(err) => return Err(err),
+        }
+    }
+    let mut stream = std::net::TcpStream::connect_timeout(&server, QUERY_TIMEOUT)?;
+    stream.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    stream.write_all(&with_length(&request))?;
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len)?;
+    buf.resize(u16::from_be_bytes(len) as usize, 0);
+    stream.read_exact(&mut buf)?;
+    parse_response(&buf, id, qtype)?
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "truncated DNS response"))
+}
+
+fn is_other_id(response: &[u8], id: u16) -> bool {
+    response.len() >= 2 && u16::from_be_bytes([response[0], response[1]]) != id
+}
+
+/// Prefix the message with its length, for DNS over TCP.
+fn with_length(message: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(message.len() + 2);
+    buf.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    buf.extend_from_slice(message);
+    buf
+}
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn build_query(id: u16, name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    if name.len() > 253 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "domain name too long",
+        ));
+    }
+    let mut buf = Vec::with_capacity(18 + name.len());
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(&[
+        1, 0, // standard query, recursion desired
+        0, 1, // one query
+        0, 0, // answer
+        0, 0, // authority
+        0, 0, // addition
+    ]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "invalid domain name",
+            ));
+        }
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    buf.extend_from_slice(&qtype.to_be_bytes());
+    buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(buf)
+}
+
+/// Return `None` if truncated. NXDOMAIN gives no answers.
+fn parse_response(buf: &[u8], id: u16, qtype: u16) -> io::Result<Option<Answers>> {
+    if buf.len() < 12 {
+        return Err(invalid_data("DNS response too short"));
+    }
+    let u16_at = |i: usize| -> io::Result<u16> {
+        buf.get(i..i + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(|| invalid_data("DNS response too short"))
+    };
+    if u16_at(0)? != id || buf[2] & 0x80 == 0 {
+        return Err(invalid_data("unexpected DNS response"));
+    }
+    if buf[2] & 0x02 != 0 {
+        return Ok(None);
+    }
+    match buf[3] & 0x0f {
+        0 => (),
+        RCODE_NXDOMAIN => return Ok(Some(Answers::default())),
+        _ => return Err(io::Error::other("DNS server failure")),
+    }
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(buf, pos)? + 4;
+    }
+    let mut result = Answers {
+        addrs: vec![],
+        ttl: u32::MAX,
+    };
+    for _ in 0..answers {
+        pos = skip_name(buf, pos)?;
+        let rtype = u16_at(pos)?;
+        let class = u16_at(pos + 2)?;
+        let ttl = (u16_at(pos + 4)? as u32) << 16 | u16_at(pos + 6)? as u32;
+        let len = u16_at(pos + 8)? as usize;
+        pos += 10;
+        let data = buf
+            .get(pos..pos + len)
+            .ok_or_else(|| invalid_data("DNS response too short"))?;
+        pos += len;
+        // Skip others, e.g. CNAME, the aliased records follow
+        let ip: IpAddr = match (rtype, len) {
+            _ if rtype != qtype || class != CLASS_IN => continue,
+            (TYPE_A, 4) => <[u8; 4]>::try_from(data).unwrap().into(),
+            (TYPE_AAAA, 16) => <[u8; 16]>::try_from(data).unwrap().into(),
+            _ => return Err(invalid_data("malformed DNS record")),
+        };
+        result.addrs.push(ip);
+        result.ttl = result.ttl.min(ttl);
+    }
+    if result.addrs.is_empty() {
+        result.ttl = 0;
+    }
+    Ok(Some(result))
+}
+
+/// Return the position after the name.
+fn skip_name(buf: &[u8], mut pos: usize) -> io::Result<usize> {
+    loop {
+        let len = *buf
+            .get(pos)
+            .ok_or_else(|| invalid_data("DNS response too short"))?;
+        match len {
+            0 => return Ok(pos + 1),
+            // Compression pointer, the name ends here
+            _ if len & 0xc0 == 0xc0 => return Ok(pos + 2),
+            _ => pos += 1 + len as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+fn build_response(query: &[u8], truncated: bool, answers: &[(IpAddr, u32)]) -> Vec<u8> {
+    let mut buf = query.to_vec();
+    buf[2] |= 0x80;
+    if truncated {
+        buf[2] |= 0x02;
+    }
+    let qtype = u16::from_be_bytes([query[query.len() - 4], query[query.len() - 3]]);
+    for (ip, ttl) in answers {
+        let data = match ip {
+            IpAddr::V4(ip) if qtype == TYPE_A => ip.octets().to_vec(),
+            IpAddr::V6(ip) if qtype == TYPE_AAAA => ip.octets().to_vec(),
+            _ => continue,
+        };
+        buf.extend_from_slice(&[0xc0, 12]);
+        buf.extend_from_slice(&qtype.to_be_bytes());
+        buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+        buf.extend_from_slice(&ttl.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&data);
+        buf[7] += 1;
+    }
+    buf
+}
+
+#[test]
+fn test_parse_response() {
+    let query = build_query(0x1234, "Example.com", TYPE_A).unwrap();
+    assert_eq!(b"\x07Example\x03com\x00\x00\x01\x00\x01", &query[12..]);
+    let ip: IpAddr = "192.0.2.1".parse().unwrap();
+    let response = build_response(&query, false, &[(ip, 300), ("::1".parse().unwrap(), 5)]);
+    assert_eq!(
+        Some(Answers {
+            addrs: vec![ip],
+            ttl: 300
+        }),
+        parse_response(&response, 0x1234, TYPE_A).unwrap()
+    );
+    assert!(parse_response(&response, 0x4321, TYPE_A).is_err());
+    assert!(parse_response(&response[..response.len() - 1], 0x1234, TYPE_A).is_err());
+    let truncated = build_response(&query, true, &[]);
+    assert_eq!(None, parse_response(&truncated, 0x1234, TYPE_A).unwrap());
+    let mut nxdomain = build_response(&query, false, &[]);
+    nxdomain[3] |= RCODE_NXDOMAIN;
+    assert_eq!(
+        Some(Answers::default()),
+        parse_response(&nxdomain, 0x1234, TYPE_A).unwrap()
+    );
+    assert!(build_query(1, "a..b", TYPE_A).is_err());
+}
+
+#[tokio::test]
+async fn test_resolver() {
+    use tokio::net::TcpListener;
+
+    // A on UDP, AAAA is truncated on UDP then answered on TCP
+    let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server = udp.local_addr().unwrap();
+    let tcp = TcpListener::bind(server).await.unwrap();
+    let v4: IpAddr = "192.0.2.1".parse().unwrap();
+    let v6: IpAddr = "2001:db8::1".parse().unwrap();
+    let answer = move |query: &[u8]| match (query.get(12), query.len()) {
+        // "nx.test"
+        (Some(2), _) if query[13] == b'n' => {
+            let mut response = build_response(query, false, &[]);
+            response[3] |= RCODE_NXDOMAIN;
+            response
+        }
+        _ => build_response(query, false, &[(v4, 60), (v6, 30)]),
+    };
+    tokio::spawn(async move {
+        let mut buf = [0u8; 512];
+        loop {
+            let (len, peer) = udp.recv_from(&mut buf).await.unwrap();
+            let query = &buf[..len];
+            let qtype = u16::from_be_bytes([query[len - 4], query[len - 3]]);
+            let response = match qtype {
+                TYPE_AAAA => build_response(query, true, &[]),
+                _ => answer(query),
+            };
+            udp.send_to(&response, peer).await.unwrap();
+        }
+    });
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = tcp.accept().await.unwrap();
+            let len = stream.read_u16().await.unwrap() as usize;
+            let mut query = vec![0u8; len];
+            stream.read_exact(&mut query).await.unwrap();
+            stream
+                .write_all(&with_length(&answer(&query)))
+                .await
+                .unwrap();
+        }
+    });
+
+    let resolver = Resolver::new();
+    resolver.set_servers(vec![server]);
+    let addrs = resolver.lookup("www.example.com", 443).await.unwrap();
+    let expected: Vec<SocketAddr> = vec![(v4, 443).into(), (v6, 443).into()];
+    assert_eq!(expected, addrs);
+    assert_eq!(
+        ResolverCacheStats {
+            hits: 0,
+            misses: 1,
+            size: 1
+        },
+        resolver.cache_stats()
+    );
+    // Cached, case-insensitive
+    let addrs = resolver.lookup("WWW.example.com.", 80).await.unwrap();
+    assert_eq!(v4, addrs[0].ip());
+    assert_eq!(1, resolver.cache_stats().hits);
+    // With the min TTL left
+    let (addrs, ttl) = resolver.lookup_ip("www.example.com").await.unwrap();
+    assert_eq!(vec![v4, v6], addrs);
+    assert!(ttl.is_some_and(|ttl| ttl <= Duration::from_secs(30) && ttl > Duration::from_secs(25)));
+    // IP is not resolved
+    let addrs = resolver.lookup("192.0.2.9", 80).await.unwrap();
+    assert_eq!(vec![SocketAddr::from(([192, 0, 2, 9], 80))], addrs);
+
+    let err = resolver.lookup("nx.test", 80).await.unwrap_err();
+    assert_eq!(ErrorKind::NotFound, err.kind());
+    assert_eq!(1, resolver.cache_stats().size);
+
+    let blocking = Resolver::new();
+    blocking.set_servers(vec![server]);
+    let addrs = tokio::task::spawn_blocking(move || blocking.lookup_blocking("a.test", 1))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(expected.len(), addrs.len());
+}
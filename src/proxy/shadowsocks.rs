@@ -0,0 +1,438 @@
+//! Shadowsocks with AEAD ciphers (SIP004), plugins (SIP003) are not supported.
+use bytes::{Buf, BufMut, BytesMut};
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, NONCE_LEN},
+    hkdf,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::Serialize;
+use std::{
+    cmp, fmt, io,
+    pin::Pin,
+    str::FromStr,
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
+use tracing::{instrument, trace};
+
+use super::{socks5::build_address, Destination};
+
+/// Key & salt size of all supported methods.
+const KEY_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+const MAX_PAYLOAD_LEN: usize = 0x3fff;
+const SUBKEY_INFO: &[u8] = b"ss-subkey";
+const READ_BUF_SIZE: usize = 1024 * 8;
+
+#[derive(Hash, Eq, PartialEq, Clone, Copy, Debug, Serialize)]
+pub enum Method {
+    #[serde(rename = "chacha20-ietf-poly1305")]
+    Chacha20IetfPoly1305,
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+}
+
+impl Method {
+    fn algorithm(self) -> &'static aead::Algorithm {
+        match self {
+            Method::Chacha20IetfPoly1305 => &aead::CHACHA20_POLY1305,
+            Method::Aes256Gcm => &aead::AES_256_GCM,
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "chacha20-ietf-poly1305" => Ok(Method::Chacha20IetfPoly1305),
+            "aes-256-gcm" => Ok(Method::Aes256Gcm),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported shadowsocks method \"{}\"", s),
+            )),
+        }
+    }
+}
+
+/// Method with the master key derived from password.
+#[derive(Hash, Eq, PartialEq, Clone, Serialize)]
+pub struct Cipher {
+    method: Method,
+    #[serde(skip_serializing)]
+    key: [u8; KEY_LEN],
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Cipher")
+            .field("method", &self.method)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Cipher {
+    pub fn new(method: Method, password: &str) -> Self {
+        Self {
+            method,
+            key: bytes_to_key(password.as_bytes()),
+        }
+    }
+
+    pub fn method(&self) -> Method {
+        self.method
+    }
+
+    fn subkey(&self, salt: &[u8]) -> LessSafeKey {
+        let algorithm = self.method.algorithm();
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA1_FOR_LEGACY_USE_ONLY, salt).extract(&self.key);
+        let okm = prk
+            .expand(&[SUBKEY_INFO], algorithm)
+            .expect("subkey length same as the key");
+        LessSafeKey::new(UnboundKey::from(okm))
+    }
+}
+
+/// Little-endian counter starts from zero, increased after each use.
+#[derive(Debug, Default)]
+struct NonceSeq([u8; NONCE_LEN]);
+
+impl NonceSeq {
+    fn advance(&mut self) -> Nonce {
+        let nonce = Nonce::assume_unique_for_key(self.0);
+        for byte in self.0.iter_mut() {
+            *byte = byte.wrapping_add(1);
+            if *byte != 0 {
+                break;
+            }
+        }
+        nonce
+    }
+}
+
+/// Encrypt written data into chunks and decrypt read chunks.
+/// Both directions begin with its own random salt.
+///
+/// Written data may be kept in buffer until next write, flush or shutdown.
+#[derive(Debug)]
+pub struct AeadStream<S> {
+    inner: S,
+    cipher: Cipher,
+    enc_key: LessSafeKey,
+    enc_nonce: NonceSeq,
+    /// Encrypted data not yet written into `inner`.
+    write_buf: BytesMut,
+    /// Set once the salt received.
+    dec: Option<(LessSafeKey, NonceSeq)>,
+    /// Encrypted data read from `inner`.
+    read_buf: BytesMut,
+    /// Length of the next payload, if its length chunk has been decrypted.
+    payload_len: Option<usize>,
+    /// Decrypted payload not yet read.
+    plain: BytesMut,
+}
+
+impl<S> AeadStream<S> {
+    pub fn new(inner: S, cipher: Cipher) -> io::Result<Self> {
+        let mut salt = [0u8; KEY_LEN];
+        SystemRandom::new()
+            .fill(&mut salt)
+            .map_err(|_| io::Error::other("fail to generate salt"))?;
+        let mut write_buf = BytesMut::with_capacity(cmp::max(READ_BUF_SIZE, KEY_LEN));
+        write_buf.extend_from_slice(&salt);
+        Ok(Self {
+            inner,
+            enc_key: cipher.subkey(&salt),
+            cipher,
+            enc_nonce: Default::default(),
+            write_buf,
+            dec: None,
+            read_buf: BytesMut::with_capacity(READ_BUF_SIZE),
+            payload_len: None,
+            plain: BytesMut::new(),
+        })
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Append length chunk & payload chunk into `write_buf`.
+    fn encrypt(&mut self, data: &[u8]) {
+        assert!(data.len() <= MAX_PAYLOAD_LEN);
+        let len = (data.len() as u16).to_be_bytes();
+        for chunk in [&len[..], data] {
+            let start = self.write_buf.len();
+            self.write_buf.put_slice(chunk);
+            let tag = self
+                .enc_key
+                .seal_in_place_separate_tag(
+                    self.enc_nonce.advance(),
+                    Aad::empty(),
+                    &mut self.write_buf[start..],
+                )
+                .expect("chunk too large");
+            self.write_buf.put_slice(tag.as_ref());
+        }
+    }
+
+    /// Decrypt one chunk from `read_buf` if it's complete.
+    /// Return false if no more chunk can be decrypted.
+    fn decrypt(&mut self) -> io::Result<bool> {
+        let (key, nonce) = match &mut self.dec {
+            Some((key, nonce)) => (key, nonce),
+            None if self.read_buf.len() >= KEY_LEN => {
+                let salt = self.read_buf.split_to(KEY_LEN);
+                let (key, nonce) = self
+                    .dec
+                    .insert((self.cipher.subkey(&salt), Default::default()));
+                (key, nonce)
+            }
+            None => return Ok(false),
+        };
+        let chunk_len = self.payload_len.unwrap_or(2) + TAG_LEN;
+        if self.read_buf.len() < chunk_len {
+            return Ok(false);
+        }
+        let mut chunk = self.read_buf.split_to(chunk_len);
+        let plain = key
+            .open_in_place(nonce.advance(), Aad::empty(), &mut chunk)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "fail to decrypt chunk"))?;
+        match self.payload_len.take() {
+            None => {
+                let len = u16::from_be_bytes([plain[0], plain[1]]) as usize;
+                self.payload_len = Some(len & MAX_PAYLOAD_LEN);
+            }
+            Some(len) => {
+                chunk.truncate(len);
+                self.plain = chunk;
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AeadStream<S> {
+    fn poll_write_buffered(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "write zero byte into writer",
+                )));
+            }
+            self.write_buf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for AeadStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.plain.is_empty() {
+                let n = cmp::min(buf.remaining(), this.plain.len());
+                buf.put_slice(&this.plain[..n]);
+                this.plain.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+            if this.decrypt()? {
+                continue;
+            }
+            let start = this.read_buf.len();
+            this.read_buf.resize(start + READ_BUF_SIZE, 0);
+            let mut read_buf = ReadBuf::new(&mut this.read_buf[start..]);
+            let result = Pin::new(&mut this.inner).poll_read(cx, &mut read_buf);
+            let n = read_buf.filled().len();
+            this.read_buf.truncate(start + n);
+            ready!(result)?;
+            trace!("{} bytes read", n);
+            if n == 0 {
+                if this.read_buf.is_empty() && this.payload_len.is_none() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed within a chunk",
+                )));
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for AeadStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buffered(cx))?;
+        let n = cmp::min(buf.len(), MAX_PAYLOAD_LEN);
+        if n == 0 {
+            return Poll::Ready(Ok(0));
+        }
+        this.encrypt(&buf[..n]);
+        // Try to write it out now, otherwise leave it on next call
+        if let Poll::Ready(Err(err)) = this.poll_write_buffered(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buffered(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buffered(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// There is no handshake, the address is sent along with the first chunk.
+#[instrument(name = "shadowsocks_connect", skip_all)]
+pub async fn connect<T>(
+    stream: TcpStream,
+    cipher: &Cipher,
+    addr: &Destination,
+    data: Option<T>,
+) -> io::Result<AeadStream<TcpStream>>
+where
+    T: AsRef<[u8]>,
+{
+    let mut stream = AeadStream::new(stream, cipher.clone())?;
+    let mut buf = Vec::with_capacity(32);
+    build_address(&mut buf, addr);
+    if let Some(data) = data {
+        buf.extend_from_slice(data.as_ref());
+    }
+    trace!("shadowsocks: write request {:?}", addr);
+    stream.write_all(&buf).await?;
+    stream.flush().await?;
+    Ok(stream)
+}
+
+/// OpenSSL's EVP_BytesToKey() with MD5 and no salt, as original shadowsocks.
+fn bytes_to_key(password: &[u8]) -> [u8; KEY_LEN] {
+    let mut key = [0u8; KEY_LEN];
+    let mut data = password.to_vec();
+    for i in 0..(KEY_LEN / 16) {
+        let digest = md5(&data);
+        key[i * 16..(i + 1) * 16].copy_from_slice(&digest);
+        data = [&digest[..], password].concat();
+    }
+    key
+}
+
+/// MD5 (RFC 1321), needed only for the key derivation.
+fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [[u32; 4]; 4] = [
+        [7, 12, 17, 22],
+        [5, 9, 14, 20],
+        [4, 11, 16, 23],
+        [6, 10, 15, 21],
+    ];
+    let table: Vec<u32> = (1..=64)
+        .map(|i| ((i as f64).sin().abs() * 4294967296.0) as u32)
+        .collect();
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for block in message.chunks(64) {
+        let words: Vec<u32> = block
+            .chunks(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let f = f
+                .wrapping_add(a)
+                .wrapping_add(table[i])
+                .wrapping_add(words[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(SHIFTS[i / 16][i % 4]));
+        }
+        for (s, v) in state.iter_mut().zip([a, b, c, d]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0u8; 16];
+    for (out, s) in digest.chunks_mut(4).zip(state) {
+        out.copy_from_slice(&s.to_le_bytes());
+    }
+    digest
+}
+
+#[test]
+fn test_md5() {
+    let hex =
+        |digest: [u8; 16]| -> String { digest.iter().map(|b| format!("{:02x}", b)).collect() };
+    assert_eq!("d41d8cd98f00b204e9800998ecf8427e", hex(md5(b"")));
+    assert_eq!("900150983cd24fb0d6963f7d28e17f72", hex(md5(b"abc")));
+    let long = b"12345678901234567890123456789012345678901234567890123456789012345678901234567890";
+    assert_eq!("57edf4a22be3c955ac49da2e2107b67a", hex(md5(long)));
+
+    let key = bytes_to_key(b"abc");
+    assert_eq!(md5(b"abc"), key[..16]);
+    assert_eq!(md5(&[&md5(b"abc")[..], b"abc"].concat()), key[16..]);
+}
+
+#[tokio::test]
+async fn test_aead_stream_round_trip() {
+    use tokio::io::{duplex, AsyncReadExt};
+
+    for method in [Method::Chacha20IetfPoly1305, Method::Aes256Gcm] {
+        let cipher = Cipher::new(method, "password");
+        let (left, right) = duplex(1024);
+        let mut left = AeadStream::new(left, cipher.clone()).unwrap();
+        let mut right = AeadStream::new(right, cipher.clone()).unwrap();
+
+        // Larger than one chunk & the duplex buffer
+        let data: Vec<u8> = (0..MAX_PAYLOAD_LEN * 3).map(|i| i as u8).collect();
+        let expected = data.clone();
+        tokio::spawn(async move {
+            left.write_all(&data).await.unwrap();
+            left.shutdown().await.unwrap();
+        });
+        let mut received = vec![];
+        right.read_to_end(&mut received).await.unwrap();
+        assert_eq!(expected, received);
+
+        let (left, right) = duplex(1024);
+        let mut left = AeadStream::new(left, Cipher::new(method, "wrong")).unwrap();
+        let mut right = AeadStream::new(right, cipher.clone()).unwrap();
+        left.write_all(b"hello").await.unwrap();
+        left.flush().await.unwrap();
+        let err = right.read(&mut [0u8; 5]).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
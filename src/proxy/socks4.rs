@@ -0,0 +1,88 @@
+use crate::proxy::{resolver::RESOLVER, Address, Destination};
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tracing::{instrument, trace};
+
+macro_rules! err {
+    ($msg:expr) => {
+        return Err(io::Error::new(ErrorKind::Other, $msg))
+    };
+}
+
+#[instrument(name = "socks4_handshake", skip_all)]
+pub async fn handshake<T>(
+    stream: &mut TcpStream,
+    addr: &Destination,
+    data: Option<T>,
+    remote_dns: bool,
+) -> io::Result<()>
+where
+    T: AsRef<[u8]>,
+{
+    let mut buf = Vec::with_capacity(16);
+    match &addr.host {
+        Address::Domain(host) if remote_dns => {
+            trace!("socks4: do SOCKSv4a handshake w/ {:?}", addr);
+            build_request(&mut buf, Ipv4Addr::new(0, 0, 0, 1), addr.port);
+            if host.len() > 255 {
+                err!("domain name too long for SOCKSv4a");
+            }
+            buf.extend_from_slice(host.as_bytes());
+            buf.push(0x00);
+        }
+        Address::Domain(host) => {
+            trace!("socks4: resolve {:?} locally", addr);
+            let ip = RESOLVER
+                .lookup(host, addr.port)
+                .await?
+                .into_iter()
+                .find_map(|addr| match addr.ip() {
+                    IpAddr::V4(ip) => Some(ip),
+                    IpAddr::V6(ip) => ip.to_ipv4_mapped(),
+                })
+                .ok_or_else(|| {
+                    io::Error::new(ErrorKind::NotFound, "no IPv4 address for SOCKSv4")
+                })?;
+            build_request(&mut buf, ip, addr.port);
+        }
+        Address::Ip(IpAddr::V4(ip)) => build_request(&mut buf, *ip, addr.port),
+        Address::Ip(IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+            Some(ip) => build_request(&mut buf, ip, addr.port),
+            None => err!("IPv6 destination is not supported by SOCKSv4"),
+        },
+    }
+    trace!("socks4: write request {:?}", buf);
+    stream.write_all(&buf).await?;
+
+    // Check server's reply
+    buf.resize(8, 0);
+    stream.read_exact(&mut buf).await?;
+    trace!("socks4: read reply {:?}", buf);
+    match buf[..2] {
+        // 90: request granted
+        [0x00, 90] => (),
+        [0x00, 91] => err!("request rejected or failed by socks server"),
+        [0x00, 92] | [0x00, 93] => err!("identd required by socks server"),
+        _ => err!("unrecognized reply from socks server"),
+    }
+
+    // Write out payload if exist
+    if let Some(data) = data {
+        trace!("socks4: write payload {:?}", data.as_ref());
+        stream.write_all(data.as_ref()).await?;
+    }
+    Ok(())
+}
+
+/// Build a CONNECT request with empty user ID.
+fn build_request(buffer: &mut Vec<u8>, ip: Ipv4Addr, port: u16) {
+    buffer.extend_from_slice(&[4, 1]);
+    buffer.push((port >> 8) as u8);
+    buffer.push(port as u8);
+    buffer.extend_from_slice(&ip.octets());
+    buffer.push(0x00); // empty user id
+}
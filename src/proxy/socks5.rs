@@ -1,5 +1,4 @@
 use crate::proxy::{Address, Destination};
-use std::io::{self, ErrorKind};
 use std::net::IpAddr;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
@@ -7,25 +6,65 @@ use tokio::{
 };
 use tracing::{instrument, trace};
 
-use super::UserPassAuthCredential;
+use super::{
+    error::{HandshakeError, HandshakePhase},
+    UserPassAuthCredential,
+};
 
-#[instrument(name = "socks5_handshake", skip_all)]
 pub async fn handshake<T>(
     stream: &mut TcpStream,
     addr: &Destination,
     data: Option<T>,
     fake_handshaking: bool,
     user_pass_auth: &Option<UserPassAuthCredential>,
-) -> io::Result<()>
+    legacy_auth_version: bool,
+) -> Result<(), HandshakeError>
+where
+    T: AsRef<[u8]>,
+{
+    let mut phase = HandshakePhase::Negotiate;
+    handshake_with_phase(
+        stream,
+        addr,
+        data,
+        fake_handshaking,
+        user_pass_auth,
+        legacy_auth_version,
+        &mut phase,
+    )
+    .await
+}
+
+/// Like `handshake()`, with `phase` set to the step it's on, thus where
+/// it failed if it did.
+#[instrument(name = "socks5_handshake", skip_all)]
+pub async fn handshake_with_phase<T>(
+    stream: &mut TcpStream,
+    addr: &Destination,
+    data: Option<T>,
+    fake_handshaking: bool,
+    user_pass_auth: &Option<UserPassAuthCredential>,
+    legacy_auth_version: bool,
+    phase: &mut HandshakePhase,
+) -> Result<(), HandshakeError>
 where
     T: AsRef<[u8]>,
 {
     if fake_handshaking && user_pass_auth.is_none() {
         trace!("socks: do FAKE handshake w/ {:?}", addr);
+        *phase = HandshakePhase::Request;
         fake_handshake(stream, addr, data).await
     } else {
         trace!("socks: do FULL handshake w/ {:?}", addr);
-        full_handshake(stream, addr, data, user_pass_auth).await
+        full_handshake(
+            stream,
+            addr,
+            data,
+            user_pass_auth,
+            legacy_auth_version,
+            phase,
+        )
+        .await
     }
 }
 
@@ -33,7 +72,7 @@ pub async fn fake_handshake<T>(
     stream: &mut TcpStream,
     addr: &Destination,
     data: Option<T>,
-) -> io::Result<()>
+) -> Result<(), HandshakeError>
 where
     T: AsRef<[u8]>,
 {
@@ -49,21 +88,20 @@ where
     Ok(())
 }
 
-macro_rules! err {
-    ($msg:expr) => {
-        return Err(io::Error::new(ErrorKind::Other, $msg))
-    };
-}
-
 pub async fn full_handshake<T>(
     stream: &mut TcpStream,
     addr: &Destination,
     data: Option<T>,
     user_pass_auth: &Option<UserPassAuthCredential>,
-) -> io::Result<()>
+    legacy_auth_version: bool,
+    phase: &mut HandshakePhase,
+) -> Result<(), HandshakeError>
 where
     T: AsRef<[u8]>,
 {
+    *phase = HandshakePhase::Negotiate;
+    // RFC 1929 subnegotiation version, some servers expect 0x05 instead
+    let auth_version = if legacy_auth_version { 0x05 } else { 0x01 };
     let mut buf = vec![];
     if user_pass_auth.is_none() {
         // Send request w/ auth method 0x00 (no auth)
@@ -81,7 +119,7 @@ where
     trace!("socks: read {:?}", buf);
     match buf[..2] {
         // 0xff: no acceptable method
-        [0x05, 0xff] => err!("auth required by socks server"),
+        [0x05, 0xff] => return Err(HandshakeError::AuthRequired),
         // 0x00: no auth required
         [0x05, 0x00] => (),
         // 0x02: username/password method
@@ -90,8 +128,9 @@ where
                 if auth.username.len() > 255 || auth.password.len() > 255 {
                     panic!("SOCKSv5 username/password exceeds 255 bytes");
                 }
+                *phase = HandshakePhase::Auth;
                 buf.clear();
-                buf.push(0x01); // version
+                buf.push(auth_version);
                 buf.push(auth.username.len() as u8);
                 buf.extend(auth.username.as_bytes());
                 buf.push(auth.password.len() as u8);
@@ -103,17 +142,18 @@ where
                 buf.resize(2, 0);
                 stream.read_exact(&mut buf).await?;
                 trace!("socks: read {:?}", buf);
-                if buf != [0x01, 0x00] {
-                    err!("auth rejected by SOCKSv5 server")
+                if buf != [auth_version, 0x00] {
+                    return Err(HandshakeError::AuthRejected);
                 }
             } else {
-                err!("missing username/password required by socks server");
+                return Err(HandshakeError::AuthRequired);
             }
         }
-        _ => err!("unrecognized reply from socks server"),
+        _ => return Err(HandshakeError::UnexpectedReply("unknown auth method")),
     }
 
     // Write the actual request
+    *phase = HandshakePhase::Request;
     buf.clear();
     build_request(&mut buf, addr);
     trace!("socks: write request {:?}", buf);
@@ -123,8 +163,11 @@ where
     buf.resize(10, 0);
     stream.read_exact(&mut buf).await?;
     trace!("socks: read reply {:?}", buf);
-    if !buf.starts_with(&[0x05, 0x00]) {
-        err!("socks server reply error");
+    if buf[0] != 0x05 {
+        return Err(HandshakeError::UnexpectedReply("not SOCKSv5"));
+    }
+    if buf[1] != 0x00 {
+        return Err(HandshakeError::UpstreamCode(buf[1].into()));
     }
     if buf[3] == 4 {
         // Consume truncted IPv6 address
@@ -142,6 +185,11 @@ where
 
 fn build_request(buffer: &mut Vec<u8>, addr: &Destination) {
     buffer.extend_from_slice(&[5, 1, 0]);
+    build_address(buffer, addr);
+}
+
+/// Append ATYP, address & port as in SOCKSv5 requests.
+pub(crate) fn build_address(buffer: &mut Vec<u8>, addr: &Destination) {
     match addr.host {
         Address::Ip(ip) => match ip {
             IpAddr::V4(ip) => {
@@ -0,0 +1,124 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+
+#[cfg(feature = "shadowsocks")]
+use super::shadowsocks::AeadStream;
+
+/// Connection to the upstream proxy server (or the destination if direct),
+/// possibly wrapped by the proxy protocol.
+#[derive(Debug)]
+pub enum ProxyStream {
+    Tcp(TcpStream),
+    /// TCP with data read ahead during handshaking, which is returned
+    /// before any further reads.
+    TcpWithPending(TcpStream, Vec<u8>),
+    #[cfg(feature = "shadowsocks")]
+    Shadowsocks(Box<AeadStream<TcpStream>>),
+}
+
+impl ProxyStream {
+    pub fn with_pending(stream: TcpStream, pending: Vec<u8>) -> Self {
+        if pending.is_empty() {
+            ProxyStream::Tcp(stream)
+        } else {
+            ProxyStream::TcpWithPending(stream, pending)
+        }
+    }
+
+    /// The underlying TCP connection.
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            ProxyStream::Tcp(stream) => stream,
+            ProxyStream::TcpWithPending(stream, _) => stream,
+            #[cfg(feature = "shadowsocks")]
+            ProxyStream::Shadowsocks(stream) => stream.get_ref(),
+        }
+    }
+
+    /// Peek the underlying TCP connection, or the pending data if any.
+    pub async fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ProxyStream::TcpWithPending(_, pending) if !pending.is_empty() => {
+                let len = buf.len().min(pending.len());
+                buf[..len].copy_from_slice(&pending[..len]);
+                Ok(len)
+            }
+            _ => self.tcp().peek(buf).await,
+        }
+    }
+
+    /// Unwrap the underlying TCP connection, discarding any buffered data.
+    pub fn into_tcp(self) -> TcpStream {
+        match self {
+            ProxyStream::Tcp(stream) => stream,
+            ProxyStream::TcpWithPending(stream, _) => stream,
+            #[cfg(feature = "shadowsocks")]
+            ProxyStream::Shadowsocks(stream) => stream.into_inner(),
+        }
+    }
+}
+
+impl From<TcpStream> for ProxyStream {
+    fn from(stream: TcpStream) -> Self {
+        ProxyStream::Tcp(stream)
+    }
+}
+
+impl AsyncRead for ProxyStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ProxyStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            ProxyStream::TcpWithPending(stream, pending) => {
+                if pending.is_empty() {
+                    return Pin::new(stream).poll_read(cx, buf);
+                }
+                let len = buf.remaining().min(pending.len());
+                buf.put_slice(&pending[..len]);
+                pending.drain(..len);
+                Poll::Ready(Ok(()))
+            }
+            #[cfg(feature = "shadowsocks")]
+            ProxyStream::Shadowsocks(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ProxyStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ProxyStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            ProxyStream::TcpWithPending(stream, _) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "shadowsocks")]
+            ProxyStream::Shadowsocks(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ProxyStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            ProxyStream::TcpWithPending(stream, _) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "shadowsocks")]
+            ProxyStream::Shadowsocks(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ProxyStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            ProxyStream::TcpWithPending(stream, _) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "shadowsocks")]
+            ProxyStream::Shadowsocks(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+use std::time::Duration;
+
+use crate::histogram::Buckets;
+
+/// Upper bounds of histogram buckets in milliseconds.
+const BUCKET_BOUNDS_MS: [u64; 10] = [10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000];
+/// All counts are halved once reaching this, so recent connections
+/// dominate the statistic.
+const WINDOW: u64 = 1024;
+
+/// Rolling statistic of time-to-first-byte of proxied connections, from
+/// the upstream handshake finished to the first data back from remote.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TtfbStats(Buckets<{ BUCKET_BOUNDS_MS.len() }>);
+
+impl Default for TtfbStats {
+    fn default() -> Self {
+        Self(Buckets::new(&BUCKET_BOUNDS_MS))
+    }
+}
+
+impl TtfbStats {
+    pub fn add(&mut self, ttfb: Duration) {
+        if self.0.count() >= WINDOW {
+            self.0.halve();
+        }
+        self.0.add(ttfb.as_millis().min(u64::MAX as u128) as u64);
+    }
+
+    /// Number of samples in the window.
+    pub fn count(&self) -> u64 {
+        self.0.count()
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        match self.0.count() {
+            0 => None,
+            n => Some(Duration::from_millis(self.0.sum() / n)),
+        }
+    }
+
+    /// 95th percentile rounded up to the bucket bound, capped at the
+    /// largest bound.
+    pub fn p95(&self) -> Option<Duration> {
+        self.0.percentile(95).map(Duration::from_millis)
+    }
+}
+
+impl Serialize for TtfbStats {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("TtfbStats", 3)?;
+        state.serialize_field("count", &self.count())?;
+        state.serialize_field("mean", &self.mean())?;
+        state.serialize_field("p95", &self.p95())?;
+        state.end()
+    }
+}
+
+#[test]
+fn test_ttfb_stats() {
+    let mut stats = TtfbStats::default();
+    assert_eq!(None, stats.mean());
+    assert_eq!(None, stats.p95());
+
+    for _ in 0..95 {
+        stats.add(Duration::from_millis(20));
+    }
+    for _ in 0..5 {
+        stats.add(Duration::from_millis(900));
+    }
+    assert_eq!(100, stats.count());
+    assert_eq!(Some(Duration::from_millis(64)), stats.mean());
+    assert_eq!(Some(Duration::from_millis(25)), stats.p95());
+    stats.add(Duration::from_millis(900));
+    assert_eq!(Some(Duration::from_millis(1_000)), stats.p95());
+
+    let mut stats = TtfbStats::default();
+    stats.add(Duration::from_secs(60));
+    assert_eq!(Some(Duration::from_millis(10_000)), stats.p95());
+
+    // Rolling window
+    for _ in 0..WINDOW {
+        stats.add(Duration::from_millis(5));
+    }
+    assert!(stats.count() <= WINDOW);
+    assert_eq!(Some(Duration::from_millis(10)), stats.p95());
+}
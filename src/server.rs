@@ -1,35 +1,76 @@
 use anyhow::{anyhow, bail, Context};
+use flexstr::SharedStr;
 use futures_util::{stream, StreamExt};
 use ini::Ini;
 use parking_lot::RwLock;
 use std::{
-    collections::HashSet, io, net::SocketAddr, net::ToSocketAddrs, path::PathBuf, sync::Arc,
-    time::Duration,
+    collections::HashSet,
+    io,
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 use tokio::net::{TcpListener, TcpStream};
-use tracing::{error, info, instrument, warn};
+use tracing::{debug, error, info, instrument, warn};
 
-use crate::{cli::CliArgs, FromOptionStr};
+use crate::{
+    cli::{CliArgs, MinHealthyAction, NParallel, ProbeLogMode},
+    log_sampler::LogSampler,
+    FromOptionStr,
+};
+#[cfg(feature = "graphite_tls")]
+use moproxy::monitor::GraphiteTls;
 #[cfg(feature = "web_console")]
-use moproxy::web::WebServer;
+use moproxy::web::{PlainStatsServer, WebServer, WebServerListener, WebService};
 use moproxy::{
-    client::{FailedClient, NewClient},
+    client::{reject_page::RejectPage, ConnectedClient, FailedClient, NewClient},
+    duration::{parse_duration_or_in, DurationExt},
     futures_stream::TcpListenerStream,
-    monitor::Monitor,
-    policy::{parser, ActionType, Policy},
-    proxy::{ProxyProto, ProxyServer, UserPassAuthCredential},
-    web::WebServerListener,
+    monitor::{
+        ConfigHash, DedupOutcome, DedupTicket, HandshakePermit, LivenessSignal, Monitor,
+        ParallelKey, ServerListDiff, SkippedSection, SourcePermit, TaskGuard, TaskKind, ACCOUNTING,
+        DEFAULT_KEEP_DAYS, DESTINATIONS, EVENT_LOOP,
+    },
+    policy::{
+        capabilities::CapSet, dns::PolicyDns, parser, Action, ActionType, Policy, RequestFeatures,
+    },
+    privacy::HOSTNAMES,
+    proxy::{
+        buffers::BUFFERS,
+        max_wait::AutoMaxWait,
+        prelude::{self, Prelude},
+        resolver::RESOLVER,
+        sort_by_preference, BulkThreshold, HandshakeLimit, ProxyProto, ProxyServer, ScoreLimits,
+        TcpOptions, UserPassAuthCredential,
+    },
+    sha256,
 };
 
+/// How often the accept loop beats `LivenessSignal::Accept` if idle.
+const ACCEPT_IDLE_BEAT: Duration = Duration::from_secs(1);
+/// How long to wait for the first byte from `--health-check-source`.
+const HEALTH_CHECK_WINDOW: Duration = Duration::from_millis(200);
+
 #[derive(Clone)]
 pub(crate) struct MoProxy {
     cli_args: Arc<CliArgs>,
     server_list_config: Arc<ServerListConfig>,
     pub(crate) monitor: Monitor,
     direct_server: Arc<ProxyServer>,
+    /// Shared with the web console, which edits maintenance in place.
+    /// Also guards the server list of the same generation: reload holds
+    /// the write lock across swapping both, so a reader holding the read
+    /// lock never sees a new policy with old servers or vice versa.
     pub(crate) policy: Arc<RwLock<Policy>>,
+    /// See `--reject-http-page`.
+    reject_page: Arc<RwLock<Option<Arc<RejectPage>>>>,
+    client_errors: Arc<LogSampler<(IpAddr, io::ErrorKind)>>,
     #[cfg(feature = "web_console")]
     web_server: Option<WebServer>,
+    /// Serve clients asking for `--stats-dest`.
+    #[cfg(feature = "web_console")]
+    web_service: Option<WebService>,
 }
 
 pub(crate) struct MoProxyListener {
@@ -37,6 +78,8 @@ pub(crate) struct MoProxyListener {
     listeners: Vec<TcpListenerStream>,
     #[cfg(feature = "web_console")]
     web_server: Option<WebServerListener>,
+    #[cfg(feature = "web_console")]
+    plain_stats: Option<PlainStatsServer>,
 }
 
 #[derive(Debug)]
@@ -44,14 +87,50 @@ enum PolicyResult {
     Filtered(Vec<Arc<ProxyServer>>),
     Direct,
     Reject,
+    Unavailable,
+}
+
+/// Decisions made once for a connection by `MoProxy::decide()`, kept
+/// along its connecting and fallbacks.
+#[derive(Debug)]
+struct ConnectionContext {
+    /// Matched action of the policy.
+    action: Action,
+    /// Candidate servers (in order) or other outcome of the action.
+    result: PolicyResult,
+    /// Set if the candidates are narrowed to the server pinned by client.
+    pinned: bool,
+    /// Connect directly once all candidates failed.
+    fallback_direct: bool,
+}
+
+impl ConnectionContext {
+    /// Candidate servers, empty unless filtered.
+    fn candidates(&self) -> &[Arc<ProxyServer>] {
+        match &self.result {
+            PolicyResult::Filtered(servers) => servers,
+            _ => &[],
+        }
+    }
 }
 
 impl MoProxy {
     pub(crate) async fn new(args: CliArgs) -> anyhow::Result<Self> {
+        RESOLVER.set_servers(args.resolvers.clone());
         // Load proxy server list
-        let server_list_config = ServerListConfig::new(&args);
-        let servers = server_list_config.load().context("fail to load servers")?;
-        let direct_server = Arc::new(ProxyServer::direct(args.max_wait));
+        let server_list_config = ServerListConfig::new(&args)?;
+        let LoadedServerList {
+            servers,
+            skipped,
+            hash: list_hash,
+        } = server_list_config.load().context("fail to load servers")?;
+        let direct_server = ProxyServer::direct(args.max_wait);
+        direct_server.update_config(|config| {
+            config.tcp_options = args.tcp_options();
+            config.half_close_timeout = args.half_close_timeout;
+            config.bulk_threshold = args.bulk_threshold();
+        });
+        let direct_server = Arc::new(direct_server);
 
         // Load policy
         let policy = {
@@ -63,12 +142,29 @@ impl MoProxy {
             }
         };
 
+        warn_unbound_listen_ports(&policy.read(), &args.port);
+        let reject_page = Arc::new(RwLock::new(load_reject_page(&args)?));
+
         // Setup proxy monitor
         let graphite = args.graphite;
-        #[cfg(feature = "score_script")]
         let mut monitor = Monitor::new(servers, graphite);
-        #[cfg(not(feature = "score_script"))]
-        let monitor = Monitor::new(servers, graphite);
+        monitor.set_config_hashes(config_hashes(&args, list_hash, &policy.read()));
+        monitor.set_skipped_sections(skipped);
+        monitor.set_auto_capabilities(policy.read().auto_capabilities().to_vec());
+        #[cfg(feature = "graphite_tls")]
+        if let (Some(addr), true) = (graphite, args.graphite_tls) {
+            let name = match &args.graphite_tls_name {
+                Some(name) => name.clone(),
+                None => addr.ip().to_string(),
+            };
+            let tls = GraphiteTls::new(
+                &name,
+                args.graphite_tls_ca.as_deref(),
+                args.graphite_tls_insecure,
+            )
+            .context("cannot setup graphite tls")?;
+            monitor.set_graphite_tls(tls);
+        }
         #[cfg(feature = "score_script")]
         {
             if let Some(ref path) = args.score_script {
@@ -78,44 +174,164 @@ impl MoProxy {
             }
         }
 
+        if let Some(stale_after) = args.probe_on_demand {
+            monitor.enable_probe_on_demand(stale_after);
+        }
+        if args.probe_log == ProbeLogMode::StateChange {
+            monitor.set_probe_log_changes_only();
+        }
+        if let Some(dir) = &args.probe_capture {
+            std::fs::create_dir_all(dir).context("cannot create probe capture directory")?;
+            let max_bytes = args.probe_capture_max_mb * 1024 * 1024;
+            monitor.set_probe_capture(dir.clone(), args.probe_capture_rate, max_bytes);
+        }
+        if let Some(budget) = args.resolve_sni_for_policy {
+            monitor.set_policy_dns(PolicyDns::new(budget));
+        }
+        if let Some(min_healthy) = args.min_healthy {
+            monitor.set_min_healthy(min_healthy);
+        }
+        if let Some(max) = args.max_connections {
+            monitor.set_max_clients(max as usize);
+        }
+        if let Some(max) = args.max_pending_handshakes {
+            monitor.set_max_pending_handshakes(max as usize);
+        }
+        if let Some(window) = args.dedup_window_ms {
+            monitor.set_dedup_window(window);
+        }
+        if let NParallel::Auto(max) = args.n_parallel {
+            monitor.set_adaptive_parallel(max);
+        }
+        monitor.set_direct_server(direct_server.clone());
+        BUFFERS.set_pending_limit(args.max_pending_mb.map(|mb| mb * 1024 * 1024));
+        match (args.accounting_days, &args.accounting_file) {
+            (Some(days), _) => ACCOUNTING.enable(days),
+            (None, Some(_)) => ACCOUNTING.enable(DEFAULT_KEEP_DAYS),
+            (None, None) => (),
+        }
+        if let Some(n) = args.track_destinations {
+            DESTINATIONS.enable(n);
+        }
+        HOSTNAMES.set(
+            args.hostname_privacy.into(),
+            args.hostname_privacy_key.as_deref().unwrap_or_default(),
+        );
+        monitor.set_throughput_meter(args.throughput_interval, args.throughput_half_life);
+
         // Setup web console
         #[cfg(feature = "web_console")]
-        let web_server = if let Some(addr) = &args.web_bind {
-            Some(WebServer::new(monitor.clone(), addr.into())?)
+        let web_server = if !args.web_bind.is_empty() || args.stats_dest.is_some() {
+            let addrs = args.web_bind.iter().map(SharedStr::from).collect();
+            let server = WebServer::new(monitor.clone(), policy.clone(), addrs)?;
+            let server = server
+                .with_whoami_token(args.whoami_token.as_deref().map(SharedStr::from))
+                .with_debug_log_token(args.debug_log_token.as_deref().map(SharedStr::from))
+                .with_status_token(args.status_token.as_deref().map(SharedStr::from))
+                .with_admin_token(args.admin_token.as_deref().map(SharedStr::from))
+                .with_healthz_always_ok(args.healthz_always_ok)
+                .with_tenants(
+                    args.tenant_token
+                        .iter()
+                        .filter(|(tenant, _)| args.port_tenant.iter().any(|(_, t)| t == tenant))
+                        .map(|(tenant, token)| (tenant.into(), token.into()))
+                        .collect(),
+                )
+                .with_path_prefix(args.web_path_prefix.as_deref());
+            Some(server)
         } else {
             None
         };
+        #[cfg(feature = "web_console")]
+        let web_service = match (&args.stats_dest, &web_server) {
+            (Some(_), Some(server)) => {
+                tokio::spawn(monitor.clone().monitor_throughput());
+                Some(server.service())
+            }
+            _ => None,
+        };
 
         // Launch monitor
-        if args.probe_secs > 0 {
-            tokio::spawn(monitor.clone().monitor_delay(args.probe_secs));
+        if !args.probe.is_zero() {
+            tokio::spawn(monitor.clone().monitor_delay(args.probe));
+        }
+        if args.probe_on_demand.is_some() {
+            tokio::spawn(monitor.clone().monitor_on_demand());
+        }
+        if args.task_leak_factor > 0.0 {
+            let watchdog = monitor
+                .clone()
+                .monitor_task_leak(args.task_leak_factor, Duration::from_secs(60));
+            tokio::spawn(watchdog);
         }
 
+        let client_errors = Arc::new(LogSampler::new(args.log_sample_secs));
         Ok(Self {
+            client_errors,
             cli_args: Arc::new(args),
             server_list_config: Arc::new(server_list_config),
             direct_server,
             monitor,
             policy,
+            reject_page,
             #[cfg(feature = "web_console")]
             web_server,
+            #[cfg(feature = "web_console")]
+            web_service,
         })
     }
 
     pub(crate) fn reload(&self) -> anyhow::Result<()> {
+        let start = Instant::now();
+        let result = self.try_reload();
+        let duration = start.elapsed();
+        match result {
+            Ok((diff, rules_delta)) => {
+                self.monitor.reload_succeeded(diff, rules_delta, duration);
+                let generation = self.monitor.reload_history().generation;
+                info!(generation, "reloaded in {}", duration.format_millis());
+                Ok(())
+            }
+            Err(err) => {
+                self.monitor.reload_failed(format!("{:#}", err), duration);
+                Err(err)
+            }
+        }
+    }
+
+    /// Return the server list diff and the change of number of rules.
+    fn try_reload(&self) -> anyhow::Result<(ServerListDiff, isize)> {
         // Load proxy server list
-        let servers = self.server_list_config.load()?;
+        let LoadedServerList {
+            servers,
+            skipped,
+            hash: list_hash,
+        } = self.server_list_config.load()?;
         // Load policy
-        let policy = match &self.cli_args.policy {
+        let mut policy = match &self.cli_args.policy {
             Some(path) => Policy::load_from_file(path).context("cannot to load policy")?,
             _ => Default::default(),
         };
+        let reject_page = load_reject_page(&self.cli_args)?;
         // TODO: reload lua script
+        warn_unbound_listen_ports(&policy, &self.cli_args.port);
 
-        // Apply only if no error occur
-        self.monitor.update_servers(servers);
-        *self.policy.write() = policy;
-        Ok(())
+        // Apply only if no error occur. Servers are updated under the
+        // policy write lock, then the policy is replaced before releasing
+        // it, so no request is routed with the new servers and old policy.
+        let mut current_policy = self.policy.write();
+        let diff = self.monitor.update_servers(servers);
+        let rules_delta = policy.rule_count() as isize - current_policy.rule_count() as isize;
+        policy.inherit_stats(&current_policy);
+        policy.inherit_maintenance(&mut current_policy);
+        self.monitor
+            .set_auto_capabilities(policy.auto_capabilities().to_vec());
+        self.monitor
+            .set_config_hashes(config_hashes(&self.cli_args, list_hash, &policy));
+        self.monitor.set_skipped_sections(skipped);
+        *current_policy = policy;
+        *self.reject_page.write() = reject_page;
+        Ok((diff, rules_delta))
     }
 
     pub(crate) async fn listen(&self) -> anyhow::Result<MoProxyListener> {
@@ -123,6 +339,21 @@ impl MoProxy {
         let mut listeners = Vec::with_capacity(ports.len());
         for port in ports {
             let addr = SocketAddr::new(self.cli_args.host, *port);
+            #[cfg(target_os = "linux")]
+            let listener = if self.cli_args.tproxy {
+                moproxy::linux::tcp::bind_transparent(addr).map_err(|err| {
+                    if err.kind() == io::ErrorKind::PermissionDenied {
+                        anyhow!("cannot set IP_TRANSPARENT for --tproxy, CAP_NET_ADMIN required")
+                    } else {
+                        anyhow!(err).context("cannot bind to port")
+                    }
+                })?
+            } else {
+                TcpListener::bind(&addr)
+                    .await
+                    .context("cannot bind to port")?
+            };
+            #[cfg(not(target_os = "linux"))]
             let listener = TcpListener::bind(&addr)
                 .await
                 .context("cannot bind to port")?;
@@ -145,63 +376,379 @@ impl MoProxy {
         } else {
             None
         };
+        #[cfg(feature = "web_console")]
+        let plain_stats = match self.cli_args.stats_plain_bind {
+            Some(addr) => Some(PlainStatsServer::bind(self.monitor.clone(), addr).await?),
+            None => None,
+        };
 
         Ok(MoProxyListener {
             moproxy: self.clone(),
             listeners,
             #[cfg(feature = "web_console")]
             web_server,
+            #[cfg(feature = "web_console")]
+            plain_stats,
         })
     }
 
-    fn apply_policy(&self, client: &NewClient) -> PolicyResult {
-        let action = self.policy.read().matches(&client.features());
-        match action.action {
-            ActionType::Reject => PolicyResult::Reject,
-            ActionType::Direct => PolicyResult::Direct,
+    /// Apply the policy on the request, with the server tag pinned by the
+    /// client if any.
+    fn decide<S: AsRef<str>>(
+        &self,
+        features: &RequestFeatures<S>,
+        pinned_tag: Option<&str>,
+        required_cap: Option<&str>,
+    ) -> ConnectionContext {
+        // Read both under the policy lock, see `MoProxy::policy`
+        let (mut action, servers) = {
+            let policy = self.policy.read();
+            (policy.matches(features), self.monitor.servers())
+        };
+        if let Some(cap) = required_cap {
+            match &mut action.action {
+                ActionType::Require(caps) => {
+                    caps.insert(CapSet::new(std::iter::once(cap)));
+                }
+                _ => debug!(cap, "client hint ignored, not proxied by policy"),
+            }
+        }
+        // Implicit `listen port PORT require TENANT`, see `--port-tenant`
+        let tenant = features
+            .listen_port
+            .and_then(|port| self.cli_args.port_tenant(port));
+        if let Some(tenant) = tenant {
+            let cap = CapSet::new(std::iter::once(tenant));
+            match &mut action.action {
+                ActionType::Require(caps) => {
+                    caps.insert(cap);
+                }
+                // Not connected directly bypassing the tenant's servers
+                ActionType::Direct => action.action = ActionType::Require([cap].into()),
+                ActionType::Reject => (),
+            }
+        }
+        let (result, pinned) = self.filter_servers(&action, &servers, features, pinned_tag);
+        ConnectionContext {
+            action,
+            result,
+            pinned,
+            fallback_direct: self.cli_args.allow_direct && tenant.is_none(),
+        }
+    }
+
+    /// Return the outcome of `action` on `servers`, and whether pinned.
+    fn filter_servers<S: AsRef<str>>(
+        &self,
+        action: &Action,
+        servers: &[Arc<ProxyServer>],
+        #[cfg_attr(not(feature = "score_script"), allow(unused_variables))]
+        features: &RequestFeatures<S>,
+        pinned_tag: Option<&str>,
+    ) -> (PolicyResult, bool) {
+        match &action.action {
+            ActionType::Reject => (PolicyResult::Reject, false),
+            ActionType::Direct => (PolicyResult::Direct, false),
             ActionType::Require(caps) => {
-                let servers = self
-                    .monitor
-                    .servers()
-                    .into_iter()
+                let mut servers: Vec<_> = servers
+                    .iter()
+                    .filter(|s| !s.auth_failed())
                     .filter(|s| caps.iter().all(|c| s.capable_anyof(c)))
+                    .cloned()
                     .collect();
-                PolicyResult::Filtered(servers)
+                if action.prefer_non_bulk
+                    || !action.prefer.is_empty()
+                    || action.prefer_family.is_some()
+                {
+                    sort_by_preference(
+                        &mut servers,
+                        action.prefer_non_bulk,
+                        &action.prefer,
+                        action.prefer_family,
+                        self.cli_args.prefer_bonus,
+                    );
+                }
+                #[cfg(feature = "score_script")]
+                self.monitor.pick_server(features, &mut servers);
+                self.monitor.probe_on_demand(&mut servers);
+                if self.cli_args.min_healthy_action == MinHealthyAction::RejectNew
+                    && self.monitor.is_degraded()
+                {
+                    servers.retain(|s| s.candidate_score().is_some());
+                    if servers.is_empty() {
+                        return (PolicyResult::Unavailable, false);
+                    }
+                }
+                let pinned = match pinned_tag {
+                    Some(tag) => {
+                        let pinned = pin_server(&mut servers, tag);
+                        if !pinned {
+                            info!(tag, "pinned server not found, unhealthy, or not allowed");
+                        }
+                        pinned
+                    }
+                    None => false,
+                };
+                (PolicyResult::Filtered(servers), pinned)
             }
         }
     }
 
-    #[instrument(level = "error", skip_all, fields(on_port=sock.local_addr()?.port(), peer=?sock.peer_addr()?))]
-    async fn handle_client(&self, sock: TcpStream) -> io::Result<()> {
-        let mut client = NewClient::from_socket(sock).await?;
+    fn log_client_error(&self, peer: Option<SocketAddr>, err: &io::Error) {
+        let ip = match peer {
+            Some(addr) => addr.ip(),
+            None => {
+                info!("error on handle client: {}", err);
+                return;
+            }
+        };
+        match self.client_errors.check(&(ip, err.kind())) {
+            Some(0) => info!(peer = %ip, "error on handle client: {}", err),
+            Some(n) => info!(
+                peer = %ip,
+                "error on handle client: {} ({} similar suppressed)", err, n
+            ),
+            None => (),
+        }
+    }
+
+    /// Count the client toward the limit of its source address, taken
+    /// from `src ip` rules or `--max-conn-per-ip`. `Err(limit)` if it's
+    /// reached, `Ok(None)` if there's no limit.
+    fn source_permit(&self, sock: &TcpStream) -> Result<Option<SourcePermit>, usize> {
+        let ip = match sock.peer_addr() {
+            Ok(addr) => addr.ip(),
+            Err(_) => return Ok(None),
+        };
+        let limit = self.policy.read().src_ip_limit(ip);
+        match limit.or(self.cli_args.max_conn_per_ip.map(|n| n as usize)) {
+            Some(limit) => self.monitor.source_permit(ip, limit).map(Some).ok_or(limit),
+            None => Ok(None),
+        }
+    }
+
+    #[instrument(level = "error", skip_all, fields(on_port=listen_port, peer=?sock.peer_addr()?))]
+    async fn handle_client(
+        &self,
+        sock: TcpStream,
+        listen_port: u16,
+        task: &TaskGuard,
+    ) -> io::Result<()> {
         let args = &self.cli_args;
+        let permit = match self.monitor.handshake_permit() {
+            Some(permit) => permit,
+            None => {
+                NewClient::refuse(sock);
+                return Err(io::Error::other("refused: too many pending handshakes"));
+            }
+        };
+        args.tcp_options().apply(&sock)?;
+        if args.is_health_check_source(sock.peer_addr()?.ip())
+            && NewClient::is_health_check(&sock, HEALTH_CHECK_WINDOW).await
+        {
+            self.monitor.add_health_check();
+            debug!("Health check closed");
+            return Ok(());
+        }
+        #[cfg(target_os = "linux")]
+        let mut client = if args.tproxy {
+            NewClient::from_tproxy_socket(sock, listen_port, args.keep_ipv4_mapped)?
+        } else {
+            self.accept(sock).await?
+        };
+        #[cfg(not(target_os = "linux"))]
+        let mut client = self.accept(sock).await?;
+        client.advertised_addr = args.advertised_addr;
+        client.fingerprint_tls = args.fingerprint_tls;
 
-        if (args.remote_dns || args.n_parallel > 1) && client.dest.port == 443 {
+        if (args.remote_dns || args.fingerprint_tls || args.n_parallel.max() > 1)
+            && client.dest.port == 443
+        {
             // Try parse TLS client hello
             client.retrieve_dest_from_sni().await?;
             if args.remote_dns {
                 client.override_dest_with_sni();
             }
         }
-        let result = match self.apply_policy(&client) {
+        task.set_dest(HOSTNAMES.dest(&client.dest).to_string());
+        let mut features = client.features();
+        if let (Some(dns), None, Some(name)) = (
+            self.monitor.policy_dns(),
+            features.dst_ip,
+            &features.dst_domain,
+        ) {
+            features.dst_ip = dns.resolve(name).await;
+        }
+        let context = self.decide(
+            &features,
+            client.pinned_tag(),
+            client.hints.require.as_deref(),
+        );
+        client.connect_timeout = context.action.timeout;
+        client.upstream_auth = context.action.upstream_auth.clone();
+        client.prefer_family = context.action.prefer_family;
+        debug!(
+            action = %context.action,
+            candidates = context.candidates().len(),
+            pinned = context.pinned,
+            "Policy applied"
+        );
+        #[cfg(feature = "web_console")]
+        if let Some(service) = self.web_service_for(&client, &context) {
+            drop(permit);
+            let stream = client.reply_local().await?;
+            debug!("Served by web console");
+            let peer = stream.peer_addr().ok().map(|addr| addr.ip());
+            service.serve_connection(stream, peer).await;
+            return Ok(());
+        }
+        self.connect(client, &context, permit).await
+    }
+
+    /// Return the web service if the client asks for `--stats-dest` and
+    /// is not rejected.
+    #[cfg(feature = "web_console")]
+    fn web_service_for(
+        &self,
+        client: &NewClient,
+        context: &ConnectionContext,
+    ) -> Option<&WebService> {
+        let dest = self.cli_args.stats_dest.as_deref()?;
+        let matched = dest.eq_ignore_ascii_case(&client.dest.to_string())
+            && !matches!(context.result, PolicyResult::Reject);
+        matched.then_some(self.web_service.as_ref()?)
+    }
+
+    /// Accept the client, limited by `--client-handshake-timeout`.
+    async fn accept(&self, sock: TcpStream) -> io::Result<NewClient> {
+        let args = &self.cli_args;
+        let options = args.inbound_options();
+        let accept = NewClient::accept(sock, &options);
+        match tokio::time::timeout(args.client_handshake_timeout, accept).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.monitor.add_handshake_timeout();
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "client handshake timed out",
+                ))
+            }
+        }
+    }
+
+    /// Connect the client according to `context`, then serve it. `permit`
+    /// is released once it's served.
+    async fn connect(
+        &self,
+        client: NewClient,
+        context: &ConnectionContext,
+        permit: HandshakePermit,
+    ) -> io::Result<()> {
+        let args = &self.cli_args;
+        let result = match &context.result {
             PolicyResult::Reject => {
                 info!("rejected by policy");
-                return Ok(());
+                let page = self.reject_page.read().clone();
+                return match page {
+                    Some(page) => {
+                        let rule = context
+                            .action
+                            .rules()
+                            .first()
+                            .and_then(|n| self.policy.read().rule_text(*n).map(str::to_string));
+                        let rule = rule.unwrap_or_default();
+                        client.reply_rejected_page(&page, &rule).await
+                    }
+                    None => client.reply_rejected().await,
+                };
+            }
+            PolicyResult::Unavailable => {
+                info!("rejected: no healthy upstream while degraded");
+                return client.reply_failed().await;
+            }
+            PolicyResult::Direct => {
+                // Nothing to fall back to, the client has been replied
+                let client = client.direct_connect(self.direct_server.clone()).await?;
+                return self.serve(client, permit).await;
             }
-            PolicyResult::Direct => client
-                .direct_connect(self.direct_server.clone())
-                .await
-                .map_err(|err| err.into()),
             PolicyResult::Filtered(proxies) => {
-                client.connect_server(proxies, args.n_parallel).await
+                let mut proxies = proxies.clone();
+                let guard = match self.monitor.connect_dedup() {
+                    Some(dedup) => {
+                        let key = (
+                            client.peer_addr()?.ip(),
+                            client.dest.host.to_string().into(),
+                            client.dest.port,
+                        );
+                        match dedup.enter(key).await {
+                            DedupTicket::Leader(guard) => Some(guard),
+                            DedupTicket::Followed(outcome) => {
+                                debug!(?outcome, "delayed by an identical connection");
+                                outcome.reorder(&mut proxies);
+                                None
+                            }
+                            DedupTicket::Bypass => None,
+                        }
+                    }
+                    None => None,
+                };
+                // Only those sniffed a full TLS hello may race
+                let adaptive = self.monitor.adaptive_parallel();
+                let adaptive = adaptive
+                    .filter(|_| client.has_full_tls_hello())
+                    .map(|parallel| {
+                        let key: ParallelKey =
+                            (client.dest.host.to_string().into(), client.dest.port);
+                        let port = client.dest.port;
+                        let first = proxies.iter().find(|s| s.allows_port(port)).cloned();
+                        (parallel, key, first)
+                    });
+                let n_parallel = match &adaptive {
+                    Some((parallel, key, _)) => parallel.n_parallel(key),
+                    None => args.n_parallel.max(),
+                };
+                let result = client
+                    .connect_server(proxies, n_parallel, args.connect_retries)
+                    .await;
+                // Nothing told about the first choice if it merely lost
+                // the race to another one
+                if let (Some((parallel, key, Some(first))), Ok(client)) = (adaptive, &result) {
+                    if !client.lost_race(&first) {
+                        let first_choice = &first == client.server() && !client.retried();
+                        parallel.record(key, first_choice);
+                    }
+                }
+                if let Some(guard) = guard {
+                    guard.finish(match &result {
+                        Ok(client) => DedupOutcome::Connected(client.server().clone()),
+                        Err(FailedClient::Recoverable(_)) => DedupOutcome::Failed,
+                        Err(FailedClient::Unrecoverable(_)) => DedupOutcome::Abandoned,
+                    });
+                }
+                result
             }
         };
         let client = match result {
             Ok(client) => client,
-            Err(FailedClient::Recoverable(client)) if args.allow_direct => {
+            Err(FailedClient::Recoverable(client)) if context.fallback_direct => {
                 client.direct_connect(self.direct_server.clone()).await?
             }
-            Err(_) => return Ok(()),
+            Err(FailedClient::Recoverable(client)) => return client.reply_failed().await,
+            Err(FailedClient::Unrecoverable(_)) => return Ok(()),
+        };
+        self.serve(client, permit).await
+    }
+
+    async fn serve(&self, client: ConnectedClient, permit: HandshakePermit) -> io::Result<()> {
+        drop(permit);
+        #[cfg(feature = "web_console")]
+        let _entry = match self.cli_args.whoami_token {
+            Some(_) => self.monitor.register_connection(
+                client.peer_addr()?,
+                client.server().tag(),
+                client.dest().to_string(),
+            ),
+            None => None,
         };
         client.serve().await
     }
@@ -213,15 +760,83 @@ impl MoProxyListener {
         if let Some(web) = self.web_server {
             web.run_background()
         }
+        #[cfg(feature = "web_console")]
+        if let Some(plain_stats) = self.plain_stats {
+            plain_stats.run_background()
+        }
 
-        let mut clients = stream::select_all(self.listeners.iter_mut());
-        while let Some(sock) = clients.next().await {
+        if let Some(path) = self.moproxy.cli_args.accounting_file.clone() {
+            tokio::spawn(ACCOUNTING.append_csv_daily(path));
+        }
+
+        let sampler = self.moproxy.client_errors.clone();
+        if !sampler.period().is_zero() {
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(sampler.period());
+                loop {
+                    interval.tick().await;
+                    for ((ip, kind), n) in sampler.flush() {
+                        info!(peer = %ip, "{} similar error(s) ({}) suppressed", n, kind);
+                    }
+                }
+            });
+        }
+
+        let mut clients = stream::select_all(self.listeners.iter_mut().map(|listener| {
+            let port = listener
+                .0
+                .local_addr()
+                .map(|a| a.port())
+                .unwrap_or_default();
+            listener.map(move |sock| sock.map(|sock| (sock, port)))
+        }));
+        // Beat even if idle, as the watchdog only cares about stalls
+        let liveness = self.moproxy.monitor.liveness().clone();
+        let mut idle = tokio::time::interval(ACCEPT_IDLE_BEAT);
+        loop {
+            let sock = tokio::select! {
+                sock = clients.next() => match sock {
+                    Some(sock) => sock,
+                    None => break,
+                },
+                _ = idle.tick() => {
+                    liveness.beat(LivenessSignal::Accept);
+                    continue;
+                }
+            };
+            liveness.beat(LivenessSignal::Accept);
             let moproxy = self.moproxy.clone();
             match sock {
-                Ok(sock) => {
+                Ok((sock, listen_port)) => {
+                    let accepted_at = EVENT_LOOP.sample_spawn();
+                    let permit = match moproxy.monitor.client_permit() {
+                        Some(permit) => permit,
+                        None => {
+                            debug!(peer = ?sock.peer_addr().ok(), "refused: too many clients");
+                            NewClient::refuse(sock);
+                            continue;
+                        }
+                    };
+                    let source_permit = match moproxy.source_permit(&sock) {
+                        Ok(permit) => permit,
+                        Err(limit) => {
+                            debug!(
+                                peer = ?sock.peer_addr().ok(),
+                                "refused: {} clients from the source already", limit
+                            );
+                            tokio::spawn(NewClient::refuse_not_allowed(sock));
+                            continue;
+                        }
+                    };
+                    let task = moproxy.monitor.track_task(TaskKind::Client);
                     tokio::spawn(async move {
-                        if let Err(e) = moproxy.handle_client(sock).await {
-                            info!("error on hanle client: {}", e);
+                        if let Some(at) = accepted_at {
+                            EVENT_LOOP.add_spawn_delay(at);
+                        }
+                        let _permit = (permit, source_permit);
+                        let peer = sock.peer_addr().ok();
+                        if let Err(e) = moproxy.handle_client(sock, listen_port, &task).await {
+                            moproxy.log_client_error(peer, &e);
                         }
                     });
                 }
@@ -231,16 +846,149 @@ impl MoProxyListener {
     }
 }
 
+/// Parse `host:port` of servers. IPv6 zone (scope ID) may be given as an
+/// interface name or index, e.g. `[fe80::1%eth0]:1080`, so that link-local
+/// servers are connected via the interface.
+fn parse_server_addr(addr: &str) -> anyhow::Result<SocketAddr> {
+    let zoned = addr
+        .strip_prefix('[')
+        .and_then(|addr| addr.split_once("]:"))
+        .and_then(|(ip, port)| Some((ip.split_once('%')?, port)));
+    let ((ip, zone), port) = match zoned {
+        Some(zoned) => zoned,
+        None => {
+            if let Ok(addr) = addr.parse() {
+                return Ok(addr);
+            }
+            let (host, port) = addr.rsplit_once(':').context("missing port")?;
+            return RESOLVER
+                .lookup_blocking(host, port.parse()?)?
+                .into_iter()
+                .next()
+                .ok_or(anyhow!("no address resolved"));
+        }
+    };
+    let ip: Ipv6Addr = ip.parse()?;
+    let port: u16 = port.parse()?;
+    let scope_id = match zone.parse() {
+        Ok(index) => index,
+        #[cfg(target_os = "linux")]
+        Err(_) => nix::net::if_::if_nametoindex(zone)
+            .with_context(|| format!("unknown interface {}", zone))?,
+        #[cfg(not(target_os = "linux"))]
+        Err(_) => bail!("zone must be an interface index"),
+    };
+    Ok(SocketAddrV6::new(ip, port, 0, scope_id).into())
+}
+
+/// Parse comma-separated port numbers, e.g. `443,8443`. Return sorted.
+fn parse_port_list(list: &str) -> anyhow::Result<Vec<u16>> {
+    let mut ports = list
+        .split(',')
+        .map(|port| match port.trim().parse() {
+            Ok(0) | Err(_) => Err(anyhow!("not a valid port number: {}", port)),
+            Ok(port) => Ok(port),
+        })
+        .collect::<anyhow::Result<Vec<u16>>>()?;
+    ports.sort_unstable();
+    ports.dedup();
+    Ok(ports)
+}
+
+/// Find common mistakes in a server list before passing it to `rust-ini`,
+/// which otherwise reads on to the end of file and reports there.
+/// Return the 1-based line number and column, and a hint.
+fn check_ini_syntax(text: &str) -> Option<(usize, usize, &'static str)> {
+    let mut continued = false;
+    for (line_no, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        let col = line.len() - line.trim_start().len() + 1;
+        if continued {
+            continued = trimmed.ends_with('\\');
+            continue;
+        }
+        if trimmed.contains(['"', '\'']) {
+            // Quoted values may span lines, leave them to `rust-ini`
+            return None;
+        }
+        continued = trimmed.ends_with('\\');
+        if trimmed.is_empty() || trimmed.starts_with(['#', ';']) {
+            continue;
+        }
+        if trimmed.starts_with('[') {
+            if !trimmed.contains(']') {
+                let col = col + line.trim().chars().count();
+                return Some((line_no + 1, col, "unterminated section name, expected `]`"));
+            }
+        } else if !trimmed.contains(['=', ':']) {
+            return Some((
+                line_no + 1,
+                col,
+                "missing `=` after key, expected `key = value`",
+            ));
+        }
+    }
+    None
+}
+
+/// Load a server list, pointing out the offending line on syntax errors.
+/// Return it with the SHA-256 of the file.
+fn load_ini(path: &Path) -> anyhow::Result<(Ini, String)> {
+    let text = std::fs::read_to_string(path)?;
+    let hash = sha256::hex_digest(text.as_bytes());
+    let text = text.strip_prefix('\u{feff}').unwrap_or(&text);
+    let name = path.display().to_string();
+    let line = |n: usize| text.lines().nth(n - 1).unwrap_or_default();
+    if let Some((line_no, col, hint)) = check_ini_syntax(text) {
+        bail!(parser::annotate_error(
+            &name,
+            line_no,
+            col,
+            line(line_no),
+            hint
+        ));
+    }
+    let ini = Ini::load_from_str(text).map_err(|err| {
+        // `rust-ini` reports the column after the offending character
+        let col = err.col.saturating_sub(1).max(1);
+        anyhow!(parser::annotate_error(
+            &name,
+            err.line,
+            col,
+            line(err.line),
+            &err.msg
+        ))
+    })?;
+    Ok((ini, hash))
+}
+
+#[derive(Debug)]
+struct LoadedServerList {
+    servers: Vec<Arc<ProxyServer>>,
+    /// Sections skipped for errors if lenient.
+    skipped: Vec<SkippedSection>,
+    /// SHA-256 of the file, if any.
+    hash: Option<String>,
+}
+
 struct ServerListConfig {
     default_test_dns: SocketAddr,
     default_max_wait: Duration,
+    auto_max_wait: AutoMaxWait,
     cli_servers: Vec<Arc<ProxyServer>>,
     path: Option<PathBuf>,
     allow_direct: bool,
+    allow_duplicate_tags: bool,
+    lenient: bool,
+    tcp_options: TcpOptions,
+    half_close_timeout: Duration,
+    global_handshake_limit: Option<HandshakeLimit>,
+    bulk_threshold: Option<BulkThreshold>,
+    score_limits: ScoreLimits,
 }
 
 impl ServerListConfig {
-    fn new(args: &CliArgs) -> Self {
+    fn new(args: &CliArgs) -> anyhow::Result<Self> {
         let default_test_dns = args.test_dns;
         let default_max_wait = args.max_wait;
 
@@ -254,7 +1002,7 @@ impl ServerListConfig {
                 None,
                 None,
                 None,
-            )));
+            )?));
         }
 
         for addr in &args.http_servers {
@@ -266,46 +1014,117 @@ impl ServerListConfig {
                 None,
                 None,
                 None,
-            )));
+            )?));
         }
 
         let path = args.server_list.clone();
-        Self {
+        if args.auto_max_wait_factor.is_nan() || args.auto_max_wait_factor <= 0.0 {
+            bail!("--auto-max-wait-factor must be positive");
+        }
+        if args.auto_max_wait_min > args.auto_max_wait_max {
+            bail!("--auto-max-wait-min is larger than --auto-max-wait-max");
+        }
+        let score_limits = args.score_limits();
+        if let (Some(min), Some(max)) = (score_limits.min, score_limits.max) {
+            if min > max {
+                bail!("--score-min is larger than --score-max");
+            }
+        }
+        let auto_max_wait = AutoMaxWait {
+            factor: args.auto_max_wait_factor,
+            min: args.auto_max_wait_min,
+            max: args.auto_max_wait_max,
+        };
+        Ok(Self {
             default_test_dns,
             default_max_wait,
+            auto_max_wait,
             cli_servers,
             path,
             allow_direct: args.allow_direct,
-        }
+            allow_duplicate_tags: args.allow_duplicate_tags,
+            lenient: args.server_list_lenient,
+            tcp_options: args.tcp_options(),
+            half_close_timeout: args.half_close_timeout,
+            global_handshake_limit: args.max_handshakes.map(|n| HandshakeLimit::new(n as usize)),
+            bulk_threshold: args.bulk_threshold(),
+            score_limits,
+        })
     }
 
     #[instrument(skip_all)]
-    fn load(&self) -> anyhow::Result<Vec<Arc<ProxyServer>>> {
+    fn load(&self) -> anyhow::Result<LoadedServerList> {
         let mut servers = self.cli_servers.clone();
+        let mut skipped = vec![];
+        let mut hash = None;
         if let Some(path) = &self.path {
-            let ini = Ini::load_from_file(path).context("cannot read server list file")?;
+            let (ini, file_hash) = load_ini(path).context("cannot read server list file")?;
+            hash = Some(file_hash);
             for (section, props) in ini.iter() {
                 if section.is_none() && props.is_empty() {
                     // `rust-ini` always return empty general section on 0.19 & 0.20
                     continue;
                 }
-                let server = self
-                    .load_proxy_from_ini_section(section, props)
-                    .with_context(|| {
-                        format!(
-                            "load [{}] from {}",
-                            section.unwrap_or("<general>"),
-                            path.display()
-                        )
-                    })?;
-                servers.push(Arc::new(server));
+                let name = section.unwrap_or("<general>");
+                match self.load_proxy_from_ini_section(section, props) {
+                    Ok(server) => servers.push(Arc::new(server)),
+                    Err(err) if self.lenient => {
+                        warn!(section = name, "skip invalid server: {:#}", err);
+                        skipped.push(SkippedSection {
+                            section: name.to_string(),
+                            error: format!("{:#}", err),
+                        });
+                    }
+                    Err(err) => {
+                        return Err(err.context(format!("load [{}] from {}", name, path.display())))
+                    }
+                }
+            }
+        }
+        for server in &servers {
+            server.update_config(|config| {
+                config.tcp_options = self.tcp_options;
+                config.half_close_timeout = self.half_close_timeout;
+                config.global_handshake_limit = self.global_handshake_limit.clone();
+                config.bulk_threshold = self.bulk_threshold;
+                config.score_limits = self.score_limits;
+            });
+        }
+        let mut tags = HashSet::with_capacity(servers.len());
+        for server in &servers {
+            if tags.insert(server.tag()) {
+                continue;
+            }
+            if self.allow_duplicate_tags {
+                warn!("multiple servers share the same tag \"{}\"", server.tag());
+            } else {
+                bail!(
+                    "multiple servers share the same tag \"{}\" \
+                    (use --allow-duplicate-tags to ignore)",
+                    server.tag()
+                );
             }
         }
         if servers.is_empty() && !self.allow_direct {
+            if !skipped.is_empty() {
+                bail!("no valid server, {} section(s) skipped", skipped.len());
+            }
             bail!("missing server list");
         }
-        info!("total {} server(s) loaded", servers.len());
-        Ok(servers)
+        if skipped.is_empty() {
+            info!("total {} server(s) loaded", servers.len());
+        } else {
+            warn!(
+                "total {} server(s) loaded, {} invalid skipped",
+                servers.len(),
+                skipped.len()
+            );
+        }
+        Ok(LoadedServerList {
+            servers,
+            skipped,
+            hash,
+        })
     }
 
     fn load_proxy_from_ini_section(
@@ -314,46 +1133,114 @@ impl ServerListConfig {
         props: &ini::Properties,
     ) -> anyhow::Result<ProxyServer> {
         let tag = props.get("tag").or(section);
-        let addr: SocketAddr = props
-            .get("address")
-            .ok_or(anyhow!("address not specified"))?
-            .to_socket_addrs()
-            .context("not a valid socket address")?
-            .next()
-            .unwrap();
+        let addr = parse_server_addr(
+            props
+                .get("address")
+                .ok_or(anyhow!("address not specified"))?,
+        )
+        .context("`address` not a valid socket address")?;
         let base = props
             .get("score base")
             .parse()
-            .context("score base not a integer")?;
+            .context("`score base` not an integer")?;
         let test_dns = props
             .get("test dns")
             .parse()
-            .context("not a valid socket address")?
+            .context("`test dns` not a valid socket address")?
             .unwrap_or(self.default_test_dns);
-        let max_wait = props
-            .get("max wait")
-            .parse()
-            .context("not a valid number")?
-            .map(Duration::from_secs)
-            .unwrap_or(self.default_max_wait);
+        let (max_wait, auto_max_wait) = match props.get("max wait") {
+            Some(auto) if auto.eq_ignore_ascii_case("auto") => {
+                // Until enough probes done
+                let max_wait = self
+                    .default_max_wait
+                    .clamp(self.auto_max_wait.min, self.auto_max_wait.max);
+                (max_wait, Some(self.auto_max_wait))
+            }
+            Some(max_wait) => {
+                let max_wait = parse_duration_or_in(max_wait, Duration::from_secs(1))
+                    .context("`max wait` not a valid duration")?;
+                (max_wait, None)
+            }
+            None => (self.default_max_wait, None),
+        };
         if props.get("listen ports").is_some() {
             // TODO: add a link to how-to --policy
             error!("`listen ports` is not longer supported, use --policy instead");
         }
+        let probe_verify_tls = props.get("probe verify tls").map(SharedStr::from);
+        let probe_port = props
+            .get("probe port")
+            .parse()
+            .context("`probe port` not a valid port number")?;
+        let probe_interval = props
+            .get("probe interval")
+            .parse()
+            .context("`probe interval` not a valid number")?
+            .map(|secs: u64| match secs {
+                0 => bail!("probe interval must be positive"),
+                secs => Ok(Duration::from_secs(secs)),
+            })
+            .transpose()?;
+        let allowed_ports = props
+            .get("allowed ports")
+            .map(parse_port_list)
+            .transpose()
+            .context("`allowed ports` not a valid list of ports")?;
+        if let (Some(port), Some(ports)) = (probe_port, &allowed_ports) {
+            if !ports.contains(&port) {
+                bail!("probe port {} not in allowed ports", port);
+            }
+        }
+        let prelude_send = match (props.get("prelude"), props.get("prelude file")) {
+            (Some(_), Some(_)) => bail!("both prelude and prelude file are set"),
+            (Some(hex), None) => {
+                Some(prelude::parse_hex(hex).context("`prelude` not a valid prelude")?)
+            }
+            (None, Some(path)) => Some(
+                std::fs::read(path)
+                    .with_context(|| format!("cannot read prelude file {}", path))?,
+            ),
+            (None, None) => None,
+        };
+        let handshake_limit = props
+            .get("handshake concurrency")
+            .parse()
+            .context("`handshake concurrency` not a valid number")?
+            .map(|n: usize| match n {
+                0 => bail!("handshake concurrency must be positive"),
+                n => Ok(HandshakeLimit::new(n)),
+            })
+            .transpose()?;
+        let no_early_payload = props
+            .get("no early payload")
+            .parse()
+            .context("`no early payload` not a boolean value")?
+            .unwrap_or(false);
+        let prelude_expect = props
+            .get("prelude expect")
+            .map(prelude::parse_hex)
+            .transpose()
+            .context("`prelude expect` not a valid prelude")?;
+        let prelude = match (prelude_send, prelude_expect) {
+            (None, None) => None,
+            (send, expect) => Some(Prelude {
+                send: send.unwrap_or_default(),
+                expect: expect.unwrap_or_default(),
+            }),
+        };
         let (_, capabilities) = parser::capabilities(props.get("capabilities").unwrap_or_default())
             .map_err(|e| e.to_owned())
-            .context("not a valid list of capabilities")?;
-        let proto = match props
+            .context("`capabilities` not a valid list of capabilities")?;
+        let protocol = props
             .get("protocol")
             .context("protocol not specified")?
-            .to_lowercase()
-            .as_str()
-        {
+            .to_lowercase();
+        let proto = match protocol.as_str() {
             "socks5" | "socksv5" => {
                 let fake_hs = props
                     .get("socks fake handshaking")
                     .parse()
-                    .context("not a boolean value")?
+                    .context("`socks fake handshaking` not a boolean value")?
                     .unwrap_or(false);
                 let username = props.get("socks username").unwrap_or("");
                 let password = props.get("socks password").unwrap_or("");
@@ -363,16 +1250,33 @@ impl ServerListConfig {
                     (u, p) if u > 255 || p > 255 => {
                         bail!("socks username/password too long")
                     }
-                    _ => ProxyProto::socks5_with_auth(UserPassAuthCredential::new(
-                        username, password,
-                    )),
+                    _ => {
+                        let legacy_auth_version = props
+                            .get("socks legacy auth version")
+                            .parse()
+                            .context("`socks legacy auth version` not a boolean value")?
+                            .unwrap_or(false);
+                        ProxyProto::Socks5 {
+                            fake_handshaking: false,
+                            user_pass_auth: Some(UserPassAuthCredential::new(username, password)),
+                            legacy_auth_version,
+                        }
+                    }
                 }
             }
+            "socks4" | "socksv4" | "socks4a" | "socksv4a" => {
+                let remote_dns = props
+                    .get("socks remote dns")
+                    .parse()
+                    .context("`socks remote dns` not a boolean value")?
+                    .unwrap_or(protocol.ends_with('a'));
+                ProxyProto::socks4(remote_dns)
+            }
             "http" => {
                 let cwp = props
                     .get("http allow connect payload")
                     .parse()
-                    .context("not a boolean value")?
+                    .context("`http allow connect payload` not a boolean value")?
                     .unwrap_or(false);
                 let credential = match (props.get("http username"), props.get("http password")) {
                     (None, None) => None,
@@ -384,11 +1288,36 @@ impl ServerListConfig {
                         pass.unwrap_or(""),
                     )),
                 };
-                ProxyProto::http(cwp, credential)
+                let auth_on_challenge = props
+                    .get("http auth on challenge")
+                    .parse()
+                    .context("`http auth on challenge` not a boolean value")?
+                    .unwrap_or(false);
+                ProxyProto::Http {
+                    connect_with_payload: cwp,
+                    user_pass_auth: credential,
+                    auth_on_challenge,
+                }
+            }
+            #[cfg(feature = "shadowsocks")]
+            "ss" | "shadowsocks" => {
+                use moproxy::proxy::shadowsocks::Cipher;
+                let method = props
+                    .get("method")
+                    .context("shadowsocks method not specified")?
+                    .parse()?;
+                let password = props
+                    .get("password")
+                    .context("shadowsocks password not specified")?;
+                ProxyProto::Shadowsocks {
+                    cipher: Cipher::new(method, password),
+                }
             }
+            #[cfg(not(feature = "shadowsocks"))]
+            "ss" | "shadowsocks" => bail!("shadowsocks support not enabled in this build"),
             _ => bail!("unknown proxy protocol"),
         };
-        Ok(ProxyServer::new(
+        let server = ProxyServer::new(
             addr,
             proto,
             test_dns,
@@ -396,6 +1325,1332 @@ impl ServerListConfig {
             Some(capabilities),
             tag,
             base,
-        ))
+        )?;
+        server.update_config(|config| {
+            config.probe_verify_tls = probe_verify_tls;
+            config.probe_port = probe_port;
+            config.probe_interval = probe_interval;
+            config.allowed_ports = allowed_ports;
+            config.prelude = prelude;
+            config.handshake_limit = handshake_limit;
+            config.no_early_payload = no_early_payload;
+            config.auto_max_wait = auto_max_wait;
+        });
+        Ok(server)
+    }
+}
+
+/// Return ports in `listen port` rules of `policy` that are not in `ports`,
+/// which never match.
+fn unbound_listen_ports(policy: &Policy, ports: &[u16]) -> Vec<u16> {
+    policy
+        .listen_ports()
+        .into_iter()
+        .filter(|port| !ports.contains(port))
+        .collect()
+}
+
+fn load_reject_page(args: &CliArgs) -> anyhow::Result<Option<Arc<RejectPage>>> {
+    let page = match &args.reject_http_page {
+        None => return Ok(None),
+        Some(None) => RejectPage::default(),
+        Some(Some(path)) => RejectPage::load(path)
+            .with_context(|| format!("cannot load reject page {}", path.display()))?,
+    };
+    Ok(Some(Arc::new(page)))
+}
+
+/// Checksums of the server list and policy files as they were loaded,
+/// logged as well.
+fn config_hashes(args: &CliArgs, list_hash: Option<String>, policy: &Policy) -> Vec<ConfigHash> {
+    let mut hashes = Vec::new();
+    if let (Some(path), Some(hash)) = (&args.server_list, list_hash) {
+        hashes.push(ConfigHash {
+            file: path.display().to_string(),
+            kind: "file",
+            hash,
+        });
+    }
+    if let Some(path) = &args.policy {
+        hashes.push(ConfigHash {
+            file: path.display().to_string(),
+            kind: "file",
+            hash: policy.file_hash().to_string(),
+        });
+        hashes.push(ConfigHash {
+            file: path.display().to_string(),
+            kind: "structural",
+            hash: policy.structural_hash().to_string(),
+        });
+    }
+    for hash in &hashes {
+        info!(
+            file = hash.file,
+            kind = hash.kind,
+            "config sha256: {}",
+            hash.hash
+        );
+    }
+    hashes
+}
+
+fn warn_unbound_listen_ports(policy: &Policy, ports: &[u16]) {
+    for port in unbound_listen_ports(policy, ports) {
+        warn!(
+            port,
+            "policy: listen port {} is not in --port, never matched", port
+        );
+    }
+}
+
+/// Keep only the server with `tag` if it's among `servers` and healthy.
+/// Return whether pinned.
+fn pin_server(servers: &mut Vec<Arc<ProxyServer>>, tag: &str) -> bool {
+    match servers
+        .iter()
+        .find(|s| s.tag() == tag && s.candidate_score().is_some())
+    {
+        Some(server) => {
+            *servers = vec![server.clone()];
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+fn write_test_server_list(name: &str, content: &str) -> PathBuf {
+    let path =
+        std::env::temp_dir().join(format!("moproxy-test-{}-{}.ini", name, std::process::id()));
+    std::fs::write(&path, content).unwrap();
+    path
+}
+
+#[tokio::test]
+async fn test_reload_with_bad_tag() {
+    use clap::Parser;
+
+    let path = write_test_server_list(
+        "bad-tag",
+        "[server-1]\naddress=127.0.0.1:2001\nprotocol=socks5\n",
+    );
+    let args = CliArgs::parse_from(["moproxy", "-p0", "-i0", "-l", path.to_str().unwrap()]);
+    let moproxy = MoProxy::new(args).await.unwrap();
+    assert!(moproxy.monitor.server_by_tag("server-1").is_some());
+
+    // Bad tag is rejected, the old list is kept
+    std::fs::write(
+        &path,
+        "[bad tag]\naddress=127.0.0.1:2002\nprotocol=socks5\n",
+    )
+    .unwrap();
+    let err = moproxy.reload().unwrap_err();
+    assert!(format!("{:#}", err).contains("invalid tag"));
+    std::fs::write(
+        &path,
+        "[a]\naddress=127.0.0.1:2002\nprotocol=http\ntag=x/y\n",
+    )
+    .unwrap();
+    assert!(moproxy.reload().is_err());
+    assert_eq!(1, moproxy.monitor.servers().len());
+    assert!(moproxy.monitor.server_by_tag("server-1").is_some());
+
+    // Fixed list is applied
+    std::fs::write(
+        &path,
+        "[server-2]\naddress=127.0.0.1:2002\nprotocol=socks5\n",
+    )
+    .unwrap();
+    moproxy.reload().unwrap();
+    assert!(moproxy.monitor.server_by_tag("server-1").is_none());
+    assert!(moproxy.monitor.server_by_tag("server-2").is_some());
+
+    // Both failures and the success are recorded
+    let history = moproxy.monitor.reload_history();
+    assert_eq!(1, history.generation);
+    assert_eq!(3, history.recent.len());
+    assert!(history.recent[0]
+        .error
+        .as_ref()
+        .unwrap()
+        .contains("invalid tag"));
+    let last = history.recent.back().unwrap();
+    assert!(last.success);
+    assert_eq!((1, 1), (last.servers_added, last.servers_removed));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_config_hashes() {
+    use clap::Parser;
+
+    let list = write_test_server_list("hashes", "[a]\naddress=127.0.0.1:2001\nprotocol=socks5\n");
+    let rules = write_test_server_list("hashes-policy", "default require a\n");
+    let args = CliArgs::parse_from([
+        "moproxy",
+        "-p0",
+        "-i0",
+        "-l",
+        list.to_str().unwrap(),
+        "--policy",
+        rules.to_str().unwrap(),
+    ]);
+    let moproxy = MoProxy::new(args).await.unwrap();
+    let hashes = moproxy.monitor.config_hashes();
+    let kinds: Vec<_> = hashes.iter().map(|h| (h.file.as_str(), h.kind)).collect();
+    assert_eq!(
+        vec![
+            (list.to_str().unwrap(), "file"),
+            (rules.to_str().unwrap(), "file"),
+            (rules.to_str().unwrap(), "structural"),
+        ],
+        kinds
+    );
+    assert_eq!(
+        sha256::hex_digest(b"[a]\naddress=127.0.0.1:2001\nprotocol=socks5\n"),
+        hashes[0].hash
+    );
+    assert_eq!(sha256::hex_digest(b"default require a\n"), hashes[1].hash);
+
+    // Comments change the file hash only
+    std::fs::write(&rules, "# Comment\ndefault  require a\n").unwrap();
+    moproxy.reload().unwrap();
+    let reloaded = moproxy.monitor.config_hashes();
+    assert_eq!(hashes[0], reloaded[0]);
+    assert_ne!(hashes[1], reloaded[1]);
+    assert_eq!(hashes[2], reloaded[2]);
+    std::fs::write(&rules, "default require b\n").unwrap();
+    moproxy.reload().unwrap();
+    assert_ne!(hashes[2], moproxy.monitor.config_hashes()[2]);
+
+    // Failed reloads keep the hashes of the loaded ones
+    std::fs::write(&rules, "default require\n").unwrap();
+    let before = moproxy.monitor.config_hashes();
+    assert!(moproxy.reload().is_err());
+    assert_eq!(before, moproxy.monitor.config_hashes());
+    std::fs::remove_file(list).unwrap();
+    std::fs::remove_file(rules).unwrap();
+}
+
+#[tokio::test]
+async fn test_reload_reject_page() {
+    use clap::Parser;
+
+    let page =
+        std::env::temp_dir().join(format!("moproxy-test-reject-{}.html", std::process::id()));
+    std::fs::write(&page, "blocked {{host}}").unwrap();
+    let page_arg = format!("--reject-http-page={}", page.display());
+    let args = ["moproxy", "-p0", "-i0", "--allow-direct", &page_arg];
+    let moproxy = MoProxy::new(CliArgs::parse_from(args)).await.unwrap();
+    let render = || moproxy.reject_page.read().as_ref().unwrap().render("a", "");
+    assert_eq!("blocked a", render());
+
+    std::fs::write(&page, "denied {{host}}").unwrap();
+    moproxy.reload().unwrap();
+    assert_eq!("denied a", render());
+    // Kept if too large
+    std::fs::write(&page, vec![b'x'; 1 << 20]).unwrap();
+    assert!(moproxy.reload().is_err());
+    assert_eq!("denied a", render());
+    std::fs::remove_file(&page).unwrap();
+
+    // Built-in page
+    let args = [
+        "moproxy",
+        "-p0",
+        "-i0",
+        "--allow-direct",
+        "--reject-http-page",
+    ];
+    let moproxy = MoProxy::new(CliArgs::parse_from(args)).await.unwrap();
+    assert!(moproxy.reject_page.read().is_some());
+}
+
+#[tokio::test]
+async fn test_source_permit() {
+    use clap::Parser;
+    use tokio::net::TcpSocket;
+
+    let rules = write_test_server_list("src-limit", "src ip 127.0.0.2 limit 1\n");
+    let args = CliArgs::parse_from([
+        "moproxy",
+        "-p0",
+        "-i0",
+        "--allow-direct",
+        "--max-conn-per-ip=2",
+        "--policy",
+        rules.to_str().unwrap(),
+    ]);
+    let moproxy = MoProxy::new(args).await.unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut clients = vec![];
+    let mut accept_from = |src: [u8; 4]| {
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.bind((src, 0).into()).unwrap();
+        clients.push(socket.connect(addr));
+    };
+    accept_from([127, 0, 0, 2]);
+    accept_from([127, 0, 0, 2]);
+    accept_from([127, 0, 0, 1]);
+    accept_from([127, 0, 0, 1]);
+    accept_from([127, 0, 0, 1]);
+    let _clients = futures_util::future::try_join_all(clients).await.unwrap();
+    let mut results = std::collections::HashMap::<_, Vec<_>>::new();
+    let mut permits = vec![];
+    for _ in 0..5 {
+        let (sock, peer) = listener.accept().await.unwrap();
+        let permit = moproxy.source_permit(&sock);
+        let result = permit.as_ref().map(Option::is_some).map_err(|limit| *limit);
+        results.entry(peer.ip()).or_default().push(result);
+        permits.push(permit);
+    }
+    assert_eq!(2, moproxy.monitor.source_stats().sources);
+    let results = |ip: [u8; 4]| {
+        let mut results = results[&IpAddr::from(ip)].clone();
+        results.sort();
+        results
+    };
+    // By the policy and --max-conn-per-ip respectively
+    assert_eq!(vec![Ok(true), Err(1)], results([127, 0, 0, 2]));
+    assert_eq!(vec![Ok(true), Ok(true), Err(2)], results([127, 0, 0, 1]));
+    let offenders = moproxy.monitor.offenders(10);
+    assert_eq!(2, offenders.len());
+    // Released once dropped
+    drop(permits);
+    assert_eq!(0, moproxy.monitor.source_stats().sources);
+}
+
+#[tokio::test]
+async fn test_reload_with_duplicate_tags() {
+    use clap::Parser;
+
+    let path = write_test_server_list(
+        "dup-tag",
+        "[a]\naddress=127.0.0.1:2001\nprotocol=socks5\ntag=x\n\
+         [b]\naddress=127.0.0.1:2002\nprotocol=socks5\ntag=x\n",
+    );
+    let list = path.to_str().unwrap();
+    let args = CliArgs::parse_from(["moproxy", "-p0", "-i0", "-l", list]);
+    let err = MoProxy::new(args).await.err().unwrap();
+    assert!(format!("{:#}", err).contains("same tag"));
+
+    let args = CliArgs::parse_from([
+        "moproxy",
+        "-p0",
+        "-i0",
+        "-l",
+        list,
+        "--allow-duplicate-tags",
+    ]);
+    let moproxy = MoProxy::new(args).await.unwrap();
+    assert_eq!(2, moproxy.monitor.servers().len());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_server_list_lenient() {
+    use clap::Parser;
+
+    let path = write_test_server_list(
+        "lenient",
+        "[a]\naddress=127.0.0.1:2001\nprotocol=socks5\n\
+         [broken]\naddress=127.0.0.1:x\nprotocol=socks5\n\
+         [c]\naddress=127.0.0.1:2003\nprotocol=http\n",
+    );
+    let list = path.to_str().unwrap();
+    let args = |lenient: bool| {
+        let mut args = vec!["moproxy", "-p0", "-i0", "-l", list];
+        if lenient {
+            args.push("--server-list-lenient");
+        }
+        CliArgs::parse_from(args)
+    };
+
+    // Strict by default
+    let err = MoProxy::new(args(false)).await.err().unwrap();
+    assert!(format!("{:#}", err).contains("load [broken]"));
+
+    let moproxy = MoProxy::new(args(true)).await.unwrap();
+    let tags: Vec<_> = moproxy
+        .monitor
+        .servers()
+        .iter()
+        .map(|s| s.tag().to_string())
+        .collect();
+    assert_eq!(vec!["a", "c"], tags);
+    let skipped = moproxy.monitor.reload_history().skipped;
+    assert_eq!(1, skipped.len());
+    assert_eq!("broken", skipped[0].section);
+    assert!(skipped[0]
+        .error
+        .contains("`address` not a valid socket address"));
+
+    // Listed after reload, and cleared once fixed
+    moproxy.reload().unwrap();
+    let history = moproxy.monitor.reload_history();
+    assert_eq!(["broken"], history.recent[0].skipped_sections[..]);
+    std::fs::write(
+        &path,
+        "[a]\naddress=127.0.0.1:2001\nprotocol=socks5\n\
+         [broken]\naddress=127.0.0.1:2002\nprotocol=socks5\n",
+    )
+    .unwrap();
+    moproxy.reload().unwrap();
+    assert_eq!(2, moproxy.monitor.servers().len());
+    assert!(moproxy.monitor.reload_history().skipped.is_empty());
+
+    // Still an error if nothing left
+    std::fs::write(&path, "[broken]\naddress=127.0.0.1:x\nprotocol=socks5\n").unwrap();
+    let err = moproxy.reload().unwrap_err();
+    assert!(format!("{:#}", err).contains("no valid server, 1 section(s) skipped"));
+    assert_eq!(2, moproxy.monitor.servers().len());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "web_console")]
+#[tokio::test]
+async fn test_stats_dest() {
+    use clap::Parser;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let path = write_test_server_list(
+        "stats-dest",
+        "[a]\naddress=127.0.0.1:2001\nprotocol=socks5\n",
+    );
+    let list = path.to_str().unwrap();
+    let args = [
+        "moproxy",
+        "-b",
+        "::1",
+        "-p0",
+        "-i0",
+        "-l",
+        list,
+        "--stats-dest",
+    ];
+    let moproxy = MoProxy::new(CliArgs::parse_from(args)).await.unwrap();
+    let listener = moproxy.listen().await.unwrap();
+    let addr = listener.listeners[0].0.local_addr().unwrap();
+    tokio::spawn(listener.handle_forever());
+
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    let host = b"moproxy.internal";
+    let mut request = vec![5, 1, 0, 5, 1, 0, 3, host.len() as u8];
+    request.extend_from_slice(host);
+    request.extend_from_slice(&80u16.to_be_bytes());
+    client.write_all(&request).await.unwrap();
+    // Bound to IPv6 address
+    let mut reply = [0u8; 2 + 22];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!([5, 0, 5, 0], reply[..4]);
+
+    client
+        .write_all(b"GET /status HTTP/1.1\r\nHost: moproxy.internal\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    let status: serde_json::Value = serde_json::from_str(body).unwrap();
+    assert_eq!("a", status["servers"][0]["server"]["tag"]);
+    std::fs::remove_file(path).unwrap();
+}
+
+/// SOCKSv5 upstream sending its name to every client.
+#[cfg(test)]
+async fn named_socks5_upstream(name: &'static str) -> SocketAddr {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 262];
+                stream.read_exact(&mut buf[..3]).await.unwrap();
+                stream.write_all(&[5, 0]).await.unwrap();
+                stream.read_exact(&mut buf[..4]).await.unwrap();
+                let len = match buf[3] {
+                    1 => 4,
+                    4 => 16,
+                    _ => stream.read_u8().await.unwrap() as usize,
+                };
+                stream.read_exact(&mut buf[..len + 2]).await.unwrap();
+                stream
+                    .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                    .await
+                    .unwrap();
+                stream.write_all(name.as_bytes()).await.unwrap();
+            });
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn test_http_connect_client_hints() {
+    use clap::Parser;
+    use moproxy::client::CLIENT_HINTS;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let path = write_test_server_list(
+        "client-hints",
+        &format!(
+            "[a]\naddress={}\nprotocol=socks5\ncapabilities=jp\n\
+            [b]\naddress={}\nprotocol=socks5\ncapabilities=us\n",
+            named_socks5_upstream("jp").await,
+            named_socks5_upstream("us").await,
+        ),
+    );
+    let list = path.to_str().unwrap();
+    let args = [
+        "moproxy",
+        "-b",
+        "::1",
+        "-p0",
+        "-i0",
+        "-l",
+        list,
+        "--accept-http-connect",
+        "--allow-client-hints",
+    ];
+    let moproxy = MoProxy::new(CliArgs::parse_from(args)).await.unwrap();
+    for server in moproxy.monitor.servers().iter() {
+        server.update_delay(Some(Duration::from_millis(10)));
+    }
+    let listener = moproxy.listen().await.unwrap();
+    let addr = listener.listeners[0].0.local_addr().unwrap();
+    tokio::spawn(listener.handle_forever());
+
+    let connect = |headers: &'static str| async move {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let request = format!("CONNECT 127.0.0.1:9 HTTP/1.1\r\n{}\r\n", headers);
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = [0; 64];
+        let mut len = 0;
+        while !response[..len].ends_with(b"jp") && !response[..len].ends_with(b"us") {
+            match client.read(&mut response[len..]).await.unwrap() {
+                0 => break,
+                n => len += n,
+            }
+        }
+        String::from_utf8(response[..len].to_vec()).unwrap()
+    };
+
+    let response = connect("").await;
+    assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+    for _ in 0..5 {
+        assert!(connect("X-Moproxy-Require: us\r\n").await.ends_with("us"));
+        assert!(connect("X-Moproxy-Server: a\r\n").await.ends_with("jp"));
+    }
+    // Required on top of pinning
+    let response = connect("X-Moproxy-Require: us\r\nX-Moproxy-Server: a\r\n").await;
+    assert!(response.ends_with("us"));
+    let response = connect("X-Moproxy-Require: kr\r\n").await;
+    assert!(!response.starts_with("HTTP/1.1 200 "), "{}", response);
+    // Invalid ones are ignored
+    let response = connect("X-Moproxy-Require: jp us\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+
+    let hints = CLIENT_HINTS.snapshot();
+    assert!(hints.require >= 7);
+    assert!(hints.server >= 6);
+    assert!(hints.invalid >= 1);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_dedup_retry_storm() {
+    use clap::Parser;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Slow upstream failing every connection
+    let slow = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let slow_addr = slow.local_addr().unwrap();
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let slow_attempts = attempts.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = slow.accept().await.unwrap();
+            slow_attempts.fetch_add(1, Ordering::Relaxed);
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                drop(stream);
+            });
+        }
+    });
+    let path = write_test_server_list(
+        "dedup",
+        &format!(
+            "[slow]\naddress={}\nprotocol=socks5\n\
+            [ok]\naddress={}\nprotocol=socks5\n",
+            slow_addr,
+            named_socks5_upstream("ok").await,
+        ),
+    );
+    let list = path.to_str().unwrap();
+    let args = [
+        "moproxy",
+        "-b",
+        "::1",
+        "-p0",
+        "-i0",
+        "-l",
+        list,
+        "--accept-http-connect",
+        "--dedup-window-ms",
+        "1000",
+    ];
+    let moproxy = MoProxy::new(CliArgs::parse_from(args)).await.unwrap();
+    // The slow one is the best
+    for server in moproxy.monitor.servers().iter() {
+        let delay = if server.tag() == "slow" { 10 } else { 500 };
+        server.update_delay(Some(Duration::from_millis(delay)));
+    }
+    // Resorted on reload
+    moproxy.reload().unwrap();
+    assert_eq!(moproxy.monitor.servers()[0].tag(), "slow");
+    let listener = moproxy.listen().await.unwrap();
+    let addr = listener.listeners[0].0.local_addr().unwrap();
+    tokio::spawn(listener.handle_forever());
+
+    let storm: Vec<_> = (0..10)
+        .map(|_| {
+            tokio::spawn(async move {
+                let mut client = TcpStream::connect(addr).await.unwrap();
+                client
+                    .write_all(b"CONNECT 127.0.0.1:9 HTTP/1.1\r\n\r\n")
+                    .await
+                    .unwrap();
+                let mut response = String::new();
+                client.read_to_string(&mut response).await.unwrap();
+                response
+            })
+        })
+        .collect();
+    for client in storm {
+        let response = client.await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+        assert!(response.ends_with("ok"));
+    }
+    // Only the first one tried the slow upstream
+    assert_eq!(1, attempts.load(Ordering::Relaxed));
+    assert_eq!(0, moproxy.monitor.connect_dedup().unwrap().inflight());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_max_pending_handshakes_shed() {
+    use clap::Parser;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let args = [
+        "moproxy",
+        "-b",
+        "::1",
+        "-p0",
+        "-i0",
+        "--allow-direct",
+        "--max-pending-handshakes",
+        "3",
+    ];
+    let moproxy = MoProxy::new(CliArgs::parse_from(args)).await.unwrap();
+    let listener = moproxy.listen().await.unwrap();
+    let addr = listener.listeners[0].0.local_addr().unwrap();
+    tokio::spawn(listener.handle_forever());
+    let monitor = &moproxy.monitor;
+
+    let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dest = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut sock, _) = echo.accept().await.unwrap();
+        let (mut rx, mut tx) = sock.split();
+        tokio::io::copy(&mut rx, &mut tx).await.unwrap();
+    });
+
+    // Established before the flood
+    let mut established = TcpStream::connect(&addr).await.unwrap();
+    let mut request = vec![5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1];
+    request.extend_from_slice(&dest.port().to_be_bytes());
+    established.write_all(&request).await.unwrap();
+    let mut buf = [0u8; 12];
+    established.read_exact(&mut buf).await.unwrap();
+    assert_eq!([5, 0, 5, 0], buf[..4]);
+
+    // Idle clients hold all permits
+    let mut idle = vec![];
+    for _ in 0..3 {
+        idle.push(TcpStream::connect(&addr).await.unwrap());
+    }
+    while monitor.handshake_stats().pending < 3 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // Shed at the cap
+    for _ in 0..5 {
+        let mut client = TcpStream::connect(&addr).await.unwrap();
+        let read = client.read(&mut buf).await;
+        assert!(read.map_or(true, |n| n == 0));
+    }
+    let stats = monitor.handshake_stats();
+    assert_eq!((3, 5, Some(3)), (stats.pending, stats.shed, stats.max));
+
+    // Established one is unaffected
+    established.write_all(b"hello").await.unwrap();
+    let mut buf = [0u8; 5];
+    established.read_exact(&mut buf).await.unwrap();
+    assert_eq!(b"hello", &buf);
+
+    // Accepted again once idle clients gone
+    idle.clear();
+    while monitor.handshake_stats().pending > 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(monitor.handshake_permit().is_some());
+}
+
+#[tokio::test]
+async fn test_adaptive_n_parallel() {
+    use clap::Parser;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Upstream refusing one destination only
+    let picky = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let picky_addr = picky.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = picky.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 262];
+                stream.read_exact(&mut buf[..3]).await.unwrap();
+                stream.write_all(&[5, 0]).await.unwrap();
+                stream.read_exact(&mut buf[..5]).await.unwrap();
+                let len = buf[4] as usize;
+                stream.read_exact(&mut buf[..len + 2]).await.unwrap();
+                let reply = if &buf[..len] == b"blocked.test" { 2 } else { 0 };
+                stream
+                    .write_all(&[5, reply, 0, 1, 0, 0, 0, 0, 0, 0])
+                    .await
+                    .unwrap();
+                // Read the hello, if any, before responding
+                let _ = tokio::time::timeout(Duration::from_millis(100), async {
+                    stream.read(&mut buf).await
+                })
+                .await;
+                stream.write_all(b"picky").await.unwrap();
+            });
+        }
+    });
+    let path = write_test_server_list(
+        "adaptive-parallel",
+        &format!(
+            "[picky]\naddress={}\nprotocol=socks5\n\
+            [ok]\naddress={}\nprotocol=socks5\n",
+            picky_addr,
+            named_socks5_upstream("ok").await,
+        ),
+    );
+    let list = path.to_str().unwrap();
+    let args = [
+        "moproxy",
+        "-b",
+        "::1",
+        "-p0",
+        "-i0",
+        "-l",
+        list,
+        "--accept-http-connect",
+        "--n-parallel",
+        "auto",
+    ];
+    let moproxy = MoProxy::new(CliArgs::parse_from(args)).await.unwrap();
+    for server in moproxy.monitor.servers().iter() {
+        let delay = if server.tag() == "picky" { 10 } else { 500 };
+        server.update_delay(Some(Duration::from_millis(delay)));
+    }
+    moproxy.reload().unwrap();
+    assert_eq!(moproxy.monitor.servers()[0].tag(), "picky");
+    let listener = moproxy.listen().await.unwrap();
+    let addr = listener.listeners[0].0.local_addr().unwrap();
+    tokio::spawn(listener.handle_forever());
+
+    // TLS ClientHello without any extension, sent along with the request
+    let mut hello = vec![22, 3, 1, 0, 47, 1, 0, 0, 43, 3, 3];
+    hello.extend_from_slice(&[0; 33]);
+    hello.extend_from_slice(&[0, 2, 0x13, 0x01, 1, 0, 0, 0]);
+    let connect = |host: &'static str, port: u16| {
+        let hello = hello.clone();
+        async move {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            let mut request = format!("CONNECT {}:{} HTTP/1.1\r\n\r\n", host, port).into_bytes();
+            if port == 443 {
+                request.extend_from_slice(&hello);
+            }
+            client.write_all(&request).await.unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            String::from_utf8_lossy(&response).into_owned()
+        }
+    };
+    for host in ["blocked.test", "fine.test", "blocked.test", "fine.test"] {
+        let response = connect(host, 443).await;
+        let via = if host == "blocked.test" {
+            "ok"
+        } else {
+            "picky"
+        };
+        assert!(response.ends_with(via), "{}: {}", host, response);
     }
+    // Never race without a full hello, not counted
+    assert!(connect("fine.test", 80).await.ends_with("picky"));
+    let parallel = moproxy.monitor.adaptive_parallel().unwrap();
+    let stats = parallel.stats();
+    assert_eq!((3, 4, 1), (stats.max, stats.decisions, stats.escalations));
+    assert_eq!((2, 1), (stats.destinations, stats.escalated));
+    assert_eq!(3, parallel.n_parallel(&("blocked.test".into(), 443)));
+    assert_eq!(1, parallel.n_parallel(&("fine.test".into(), 443)));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_load_prelude() {
+    use clap::Parser;
+
+    let path = write_test_server_list(
+        "prelude",
+        "[a]\naddress=127.0.0.1:2001\nprotocol=socks5\n\
+        prelude=hex:DEADBEEF\nprelude expect=hex:00\n",
+    );
+    let args = CliArgs::parse_from(["moproxy", "-p0", "-l", path.to_str().unwrap()]);
+    let config = ServerListConfig::new(&args).unwrap();
+    let servers = config.load().unwrap().servers;
+    let prelude = servers[0].prelude().unwrap();
+    assert_eq!(vec![0xde, 0xad, 0xbe, 0xef], prelude.send);
+    assert_eq!(vec![0], prelude.expect);
+
+    std::fs::write(
+        &path,
+        "[a]\naddress=127.0.0.1:2001\nprotocol=socks5\nprelude=DEADBEEF\n",
+    )
+    .unwrap();
+    assert!(config.load().is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_load_socks_legacy_auth_version() {
+    use clap::Parser;
+
+    let path = write_test_server_list(
+        "socks-legacy-auth",
+        "[a]\naddress=127.0.0.1:2001\nprotocol=socks5\n\
+        socks username=user\nsocks password=pass\n\
+        [b]\naddress=127.0.0.1:2002\nprotocol=socks5\n\
+        socks username=user\nsocks password=pass\nsocks legacy auth version=true\n",
+    );
+    let args = CliArgs::parse_from(["moproxy", "-p0", "-l", path.to_str().unwrap()]);
+    let servers = ServerListConfig::new(&args)
+        .unwrap()
+        .load()
+        .unwrap()
+        .servers;
+    let legacy: Vec<_> = servers
+        .iter()
+        .map(|s| match s.proto {
+            ProxyProto::Socks5 {
+                legacy_auth_version,
+                ..
+            } => legacy_auth_version,
+            _ => unreachable!(),
+        })
+        .collect();
+    assert_eq!(vec![false, true], legacy);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_parse_server_addr() {
+    let addr = |s| parse_server_addr(s).unwrap();
+    assert_eq!("127.0.0.1:1080", addr("127.0.0.1:1080").to_string());
+    assert_eq!("[fe80::1%2]:1080", addr("[fe80::1%2]:1080").to_string());
+    #[cfg(target_os = "linux")]
+    {
+        let lo = nix::net::if_::if_nametoindex("lo").unwrap();
+        let expected = SocketAddrV6::new("fe80::1".parse().unwrap(), 1080, 0, lo);
+        assert_eq!(SocketAddr::V6(expected), addr("[fe80::1%lo]:1080"));
+    }
+    assert!(parse_server_addr("[fe80::1%no-such-if0]:1080").is_err());
+    assert!(parse_server_addr("[fe80::1%lo]:http").is_err());
+}
+
+#[test]
+fn test_load_allowed_ports() {
+    use clap::Parser;
+
+    let path = write_test_server_list(
+        "allowed-ports",
+        "[a]\naddress=127.0.0.1:2001\nprotocol=http\n\
+        probe port=443\nallowed ports=8443, 443\n",
+    );
+    let args = CliArgs::parse_from(["moproxy", "-p0", "-l", path.to_str().unwrap()]);
+    let config = ServerListConfig::new(&args).unwrap();
+    let servers = config.load().unwrap().servers;
+    assert_eq!(Some(443), servers[0].probe_port());
+    assert_eq!(Some(vec![443, 8443]), servers[0].allowed_ports());
+    assert!(servers[0].allows_port(8443));
+    assert!(!servers[0].allows_port(80));
+
+    std::fs::write(
+        &path,
+        "[a]\naddress=127.0.0.1:2001\nprotocol=http\n\
+        probe port=53\nallowed ports=443\n",
+    )
+    .unwrap();
+    assert!(config.load().is_err());
+    assert!(parse_port_list("443,0").is_err());
+    assert!(parse_port_list("443,https").is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_load_auto_max_wait() {
+    use clap::Parser;
+
+    let path = write_test_server_list(
+        "auto-max-wait",
+        "[a]\naddress=127.0.0.1:2001\nprotocol=http\nmax wait=auto\n\
+        [b]\naddress=127.0.0.1:2002\nprotocol=http\nmax wait=2\n",
+    );
+    let list = path.to_str().unwrap();
+    let args = CliArgs::parse_from(["moproxy", "-p0", "-l", list, "--auto-max-wait-max", "3"]);
+    let servers = ServerListConfig::new(&args)
+        .unwrap()
+        .load()
+        .unwrap()
+        .servers;
+    // Default 4s clamped before any probe
+    assert_eq!(Duration::from_secs(3), servers[0].max_wait());
+    for _ in 0..4 {
+        servers[0].update_delay(Some(Duration::from_millis(100)));
+        servers[1].update_delay(Some(Duration::from_millis(100)));
+    }
+    assert_eq!(Duration::from_millis(500), servers[0].max_wait());
+    assert_eq!(Duration::from_secs(2), servers[1].max_wait());
+
+    let args = CliArgs::parse_from(["moproxy", "-p0", "-l", list, "--auto-max-wait-factor", "0"]);
+    assert!(ServerListConfig::new(&args).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_pin_server() {
+    let server = |port, caps, tag| {
+        let (_, caps) = parser::capabilities(caps).unwrap();
+        Arc::new(
+            ProxyServer::new(
+                ([127, 0, 0, 1], port).into(),
+                ProxyProto::socks5(false),
+                ([127, 0, 0, 1], 53).into(),
+                Duration::from_secs(1),
+                Some(caps),
+                Some(tag),
+                None,
+            )
+            .unwrap(),
+        )
+    };
+    let all = [
+        server(1, "us", "a"),
+        server(2, "us", "b"),
+        server(3, "jp", "c"),
+        server(4, "us", "down"),
+    ];
+    for server in &all[..3] {
+        server.update_delay(Some(Duration::from_millis(10)));
+    }
+    // As filtered by `require us`
+    let (_, caps) = parser::capabilities("us").unwrap();
+    let candidates: Vec<_> = all
+        .iter()
+        .filter(|s| s.capable_anyof(&caps))
+        .cloned()
+        .collect();
+
+    let mut servers = candidates.clone();
+    assert!(pin_server(&mut servers, "b"));
+    assert_eq!(1, servers.len());
+    assert_eq!("b", servers[0].tag().as_str());
+
+    // Unknown, unhealthy, or conflicting with the policy
+    for tag in ["x", "down", "c"] {
+        let mut servers = candidates.clone();
+        assert!(!pin_server(&mut servers, tag));
+        assert_eq!(3, servers.len());
+    }
+}
+
+#[tokio::test]
+async fn test_decide() {
+    use clap::Parser;
+
+    let list = write_test_server_list(
+        "decide",
+        "[a]\naddress=127.0.0.1:2001\nprotocol=socks5\ncapabilities=us\n\
+        [b]\naddress=127.0.0.1:2002\nprotocol=socks5\ncapabilities=jp\n",
+    );
+    let policy = list.with_extension("rules");
+    std::fs::write(
+        &policy,
+        "dst domain example.com require jp\n\
+         dst ip 192.0.2.0/24 direct\n\
+         listen port 2081 reject\n",
+    )
+    .unwrap();
+    let args = CliArgs::parse_from([
+        "moproxy",
+        "-p0",
+        "-i0",
+        "-l",
+        list.to_str().unwrap(),
+        "--policy",
+        policy.to_str().unwrap(),
+        "--allow-direct",
+    ]);
+    let moproxy = MoProxy::new(args).await.unwrap();
+    let request = |port, domain: &'static str| RequestFeatures {
+        listen_port: Some(port),
+        dst_ip: domain.parse().ok(),
+        dst_domain: Some(domain).filter(|d| d.parse::<IpAddr>().is_err()),
+    };
+    let tags = |context: &ConnectionContext| -> Vec<_> {
+        context
+            .candidates()
+            .iter()
+            .map(|s| s.tag().to_string())
+            .collect()
+    };
+
+    let context = moproxy.decide(&request(2081, "example.org"), None, None);
+    assert!(matches!(context.result, PolicyResult::Reject));
+    let context = moproxy.decide(&request(2080, "192.0.2.1"), None, None);
+    assert!(matches!(context.result, PolicyResult::Direct));
+    assert!(context.candidates().is_empty());
+
+    let context = moproxy.decide(&request(2080, "example.com"), None, None);
+    assert_eq!(vec!["b"], tags(&context));
+    assert_eq!(&[0], &context.action.rules()[..]);
+    assert!(context.fallback_direct);
+    assert!(!context.pinned);
+    let context = moproxy.decide(&request(2080, "example.org"), None, None);
+    assert_eq!(2, context.candidates().len());
+
+    // Pinned only if allowed by the policy
+    let context = moproxy.decide(&request(2080, "example.com"), Some("a"), None);
+    assert_eq!(vec!["b"], tags(&context));
+    assert!(!context.pinned);
+    for server in moproxy.monitor.servers().iter() {
+        server.update_delay(Some(Duration::from_millis(10)));
+    }
+    let context = moproxy.decide(&request(2080, "example.com"), Some("b"), None);
+    assert!(context.pinned);
+    let context = moproxy.decide(&request(2080, "example.org"), Some("a"), None);
+    assert_eq!(vec!["a"], tags(&context));
+    assert!(context.pinned);
+
+    std::fs::remove_file(&list).unwrap();
+    std::fs::remove_file(&policy).unwrap();
+}
+
+#[tokio::test]
+async fn test_decide_port_tenant() {
+    use clap::Parser;
+
+    let list = write_test_server_list(
+        "port-tenant",
+        "[a1]\naddress=127.0.0.1:2001\nprotocol=socks5\ncapabilities=team-a jp\n\
+        [a2]\naddress=127.0.0.1:2002\nprotocol=socks5\ncapabilities=team-a us\n\
+        [b]\naddress=127.0.0.1:2003\nprotocol=socks5\ncapabilities=team-b jp\n",
+    );
+    let policy = list.with_extension("rules");
+    std::fs::write(
+        &policy,
+        "dst domain example.com require jp\ndst domain example.net direct\n",
+    )
+    .unwrap();
+    let args = CliArgs::parse_from([
+        "moproxy",
+        "-p0",
+        "-i0",
+        "-l",
+        list.to_str().unwrap(),
+        "--policy",
+        policy.to_str().unwrap(),
+        "--port-tenant",
+        "8081=team-a",
+        "--port-tenant",
+        "8082=team-b",
+        "--status-token",
+        "secret",
+        "--allow-direct",
+    ]);
+    let moproxy = MoProxy::new(args).await.unwrap();
+    let tags = |port, domain| -> Vec<_> {
+        let features = RequestFeatures {
+            listen_port: Some(port),
+            dst_ip: None,
+            dst_domain: Some(domain),
+        };
+        let mut tags: Vec<_> = moproxy
+            .decide(&features, None, None)
+            .candidates()
+            .iter()
+            .map(|s| s.tag().to_string())
+            .collect();
+        tags.sort();
+        tags
+    };
+
+    assert_eq!(vec!["a1", "a2"], tags(8081, "example.org"));
+    assert_eq!(vec!["a1"], tags(8081, "example.com"));
+    assert_eq!(vec!["b"], tags(8082, "example.com"));
+    assert_eq!(vec!["a1", "b"], tags(8080, "example.com"));
+    // Not bypassed by direct rules, nor fallen back to direct
+    assert_eq!(vec!["b"], tags(8082, "example.net"));
+    let features = RequestFeatures {
+        listen_port: Some(8082),
+        dst_ip: None,
+        dst_domain: Some("example.net"),
+    };
+    assert!(!moproxy.decide(&features, None, None).fallback_direct);
+    let features = RequestFeatures {
+        listen_port: Some(8080),
+        ..features
+    };
+    let context = moproxy.decide(&features, None, None);
+    assert!(matches!(context.result, PolicyResult::Direct));
+    assert!(context.fallback_direct);
+    // Never pinned to servers of others
+    let features = RequestFeatures {
+        listen_port: Some(8082),
+        dst_ip: None,
+        dst_domain: Some("example.org"),
+    };
+    for server in moproxy.monitor.servers().iter() {
+        server.update_delay(Some(Duration::from_millis(10)));
+    }
+    let context = moproxy.decide(&features, Some("a1"), None);
+    assert!(!context.pinned);
+    assert_eq!(1, context.candidates().len());
+
+    std::fs::remove_file(&list).unwrap();
+    std::fs::remove_file(&policy).unwrap();
+}
+
+#[tokio::test]
+async fn test_decide_tls_verified() {
+    use clap::Parser;
+    use moproxy::proxy::TlsVerification;
+
+    let list = write_test_server_list(
+        "tls-verified",
+        "[verified]\naddress=127.0.0.1:2001\nprotocol=socks5\nprobe verify tls=example.com\n\
+        [intercepted]\naddress=127.0.0.1:2002\nprotocol=socks5\nprobe verify tls=example.com\n\
+        [unknown]\naddress=127.0.0.1:2003\nprotocol=socks5\nprobe verify tls=example.com\n\
+        [unprobed]\naddress=127.0.0.1:2004\nprotocol=socks5\n\
+        [static]\naddress=127.0.0.1:2005\nprotocol=socks5\ncapabilities=tls-verified\n",
+    );
+    let policy = list.with_extension("rules");
+    std::fs::write(&policy, "dst domain example.com require tls-verified\n").unwrap();
+    let args = CliArgs::parse_from([
+        "moproxy",
+        "-p0",
+        "-i0",
+        "-l",
+        list.to_str().unwrap(),
+        "--policy",
+        policy.to_str().unwrap(),
+    ]);
+    let moproxy = MoProxy::new(args).await.unwrap();
+    let server = |tag| moproxy.monitor.server_by_tag(tag).unwrap();
+    server("verified").set_tls_verification(TlsVerification::Verified);
+    server("intercepted").set_tls_verification(TlsVerification::Intercepted);
+
+    let features = RequestFeatures {
+        listen_port: Some(8080),
+        dst_ip: None,
+        dst_domain: Some("example.com"),
+    };
+    let mut tags: Vec<_> = moproxy
+        .decide(&features, None, None)
+        .candidates()
+        .iter()
+        .map(|s| s.tag().to_string())
+        .collect();
+    tags.sort();
+    assert_eq!(vec!["static", "verified"], tags);
+
+    std::fs::remove_file(&list).unwrap();
+    std::fs::remove_file(&policy).unwrap();
+}
+
+#[tokio::test]
+async fn test_decide_during_reload() {
+    use clap::Parser;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    // Each generation requires a capability only its own servers have
+    let configs = [
+        (
+            "[a]\naddress=127.0.0.1:2001\nprotocol=socks5\ncapabilities=jp\n\
+            [b]\naddress=127.0.0.1:2002\nprotocol=socks5\ncapabilities=us\n",
+            "dst domain example.com require jp\n",
+        ),
+        (
+            "[c]\naddress=127.0.0.1:2003\nprotocol=socks5\ncapabilities=kr\n",
+            "dst domain example.com require kr\nlisten port 2081 reject\n",
+        ),
+    ];
+    let list = write_test_server_list("decide-reload", configs[0].0);
+    let policy = list.with_extension("rules");
+    std::fs::write(&policy, configs[0].1).unwrap();
+    let args = CliArgs::parse_from([
+        "moproxy",
+        "-p0",
+        "-i0",
+        "-l",
+        list.to_str().unwrap(),
+        "--policy",
+        policy.to_str().unwrap(),
+    ]);
+    let moproxy = MoProxy::new(args).await.unwrap();
+    let request = RequestFeatures {
+        listen_port: Some(2080),
+        dst_ip: None,
+        dst_domain: Some("example.com"),
+    };
+
+    let done = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        let clients: Vec<_> = (0..4)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut decided = 0;
+                    while !done.load(Ordering::Relaxed) {
+                        let context = moproxy.decide(&request, None, None);
+                        assert!(matches!(context.result, PolicyResult::Filtered(_)));
+                        assert_eq!(1, context.candidates().len());
+                        decided += 1;
+                    }
+                    decided
+                })
+            })
+            .collect();
+        for n in 1..=100 {
+            let (servers, rules) = configs[n % 2];
+            std::fs::write(&list, servers).unwrap();
+            std::fs::write(&policy, rules).unwrap();
+            moproxy.reload().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        for client in clients {
+            assert!(client.join().unwrap() > 0);
+        }
+    });
+
+    let history = moproxy.monitor.reload_history();
+    assert_eq!(100, history.generation);
+    let last = history.recent.back().unwrap();
+    assert_eq!(100, last.generation);
+    assert!(last.duration_secs > 0.0);
+
+    std::fs::remove_file(&list).unwrap();
+    std::fs::remove_file(&policy).unwrap();
+}
+
+#[test]
+fn test_unbound_listen_ports() {
+    let policy = Policy::load(
+        "listen port 2080 require a\nlisten port 1080 direct\ndst domain test reject".as_bytes(),
+    )
+    .unwrap();
+    assert_eq!(vec![1080, 2080], policy.listen_ports());
+    assert!(unbound_listen_ports(&policy, &[1080, 2080, 3080]).is_empty());
+    assert_eq!(vec![2080], unbound_listen_ports(&policy, &[1080]));
+    assert_eq!(vec![1080, 2080], unbound_listen_ports(&policy, &[]));
+    assert!(unbound_listen_ports(&Policy::default(), &[1080]).is_empty());
+}
+
+#[test]
+fn test_load_syntax_error() {
+    use clap::Parser;
+
+    let path = write_test_server_list(
+        "syntax-error",
+        "[a]\naddress=127.0.0.1:2001\nprotocol socks5\n[b]\n",
+    );
+    let args = CliArgs::parse_from(["moproxy", "-p0", "-l", path.to_str().unwrap()]);
+    let config = ServerListConfig::new(&args).unwrap();
+    let err = format!("{:#}", config.load().unwrap_err());
+    assert!(err.contains("moproxy-test-syntax-error-"), "{}", err);
+    assert!(err.contains(".ini:3:1: missing `=` after key"), "{}", err);
+    assert!(err.contains("\n3 | protocol socks5\n  | ^"), "{}", err);
+
+    std::fs::write(&path, "[a]\naddress=127.0.0.1:2001\n  [b\n").unwrap();
+    let err = format!("{:#}", config.load().unwrap_err());
+    assert!(
+        err.contains(".ini:3:5: unterminated section name"),
+        "{}",
+        err
+    );
+    assert!(err.contains("\n3 |   [b\n  |     ^"), "{}", err);
+
+    std::fs::write(
+        &path,
+        "[a]\naddress=127.0.0.1:2001\nprotocol=socks5\nmax wait=4x\n",
+    )
+    .unwrap();
+    let err = format!("{:#}", config.load().unwrap_err());
+    assert!(err.contains("load [a] from "), "{}", err);
+    assert!(err.contains("`max wait` not a valid duration"), "{}", err);
+    assert!(err.contains("unknown unit"), "{}", err);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_load_score_limits() {
+    use clap::Parser;
+
+    let path = write_test_server_list(
+        "score-limits",
+        "[a]\naddress=127.0.0.1:2001\nprotocol=socks5\n",
+    );
+    let list = path.to_str().unwrap();
+    let args = ["moproxy", "-p0", "-l", list, "--score-min", "-100"];
+    let args = CliArgs::parse_from(args.iter().chain(&["--score-max", "5000"]));
+    let servers = ServerListConfig::new(&args)
+        .unwrap()
+        .load()
+        .unwrap()
+        .servers;
+    let limits = ScoreLimits {
+        min: Some(-100),
+        max: Some(5000),
+    };
+    assert_eq!(limits, servers[0].score_limits());
+
+    let args = CliArgs::parse_from([
+        "moproxy",
+        "-p0",
+        "-l",
+        list,
+        "--score-min",
+        "10",
+        "--score-max",
+        "1",
+    ]);
+    assert!(ServerListConfig::new(&args).is_err());
 }
@@ -0,0 +1,73 @@
+//! SHA-256 for config checksums.
+use ring::digest::{Context, SHA256};
+use std::fmt::{self, Write};
+
+/// Incremental SHA-256.
+#[derive(Clone)]
+pub struct Sha256(Context);
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Sha256 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sha256").finish_non_exhaustive()
+    }
+}
+
+impl Sha256 {
+    pub fn new() -> Self {
+        Self(Context::new(&SHA256))
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data)
+    }
+
+    /// Digest in lowercase hex.
+    pub fn finish_hex(self) -> String {
+        self.0
+            .finish()
+            .as_ref()
+            .iter()
+            .fold(String::with_capacity(64), |mut s, b| {
+                write!(s, "{:02x}", b).unwrap();
+                s
+            })
+    }
+}
+
+/// SHA-256 of `data` in lowercase hex.
+pub fn hex_digest(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hasher.finish_hex()
+}
+
+#[test]
+fn test_sha256() {
+    // Test vectors from FIPS 180-2
+    assert_eq!(
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+        hex_digest(b"")
+    );
+    assert_eq!(
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+        hex_digest(b"abc")
+    );
+    let long = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+    assert_eq!(
+        "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+        hex_digest(long)
+    );
+    // Fed in pieces across blocks
+    let data = vec![b'a'; 1_000];
+    let mut hasher = Sha256::new();
+    for chunk in data.chunks(7) {
+        hasher.update(chunk);
+    }
+    assert_eq!(hex_digest(&data), hasher.finish_hex());
+}
@@ -0,0 +1,280 @@
+use anyhow::{anyhow, Context};
+use moproxy::{
+    policy::{ActionType, Policy, RequestFeatures},
+    proxy::ProxyServer,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    net::IpAddr,
+    path::Path,
+    sync::Arc,
+};
+
+use crate::server::MoProxy;
+
+/// Max number of differing requests listed in the report.
+const MAX_DIFFS_SHOWN: usize = 20;
+
+/// A line of input: `<listen-port> <dest-host> <dest-port>`.
+#[derive(Debug, PartialEq, Eq)]
+struct Request<'a> {
+    listen_port: u16,
+    host: &'a str,
+    port: u16,
+}
+
+impl<'a> Request<'a> {
+    /// Parse a line, return `None` on empty lines and comments.
+    fn parse(line: &'a str) -> anyhow::Result<Option<Self>> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+        let mut fields = line.split_whitespace();
+        let mut next = |name| fields.next().ok_or_else(|| anyhow!("{} missing", name));
+        let listen_port = next("listen port")?
+            .parse()
+            .context("invalid listen port")?;
+        let host = next("destination host")?;
+        let port = next("destination port")?
+            .parse()
+            .context("invalid destination port")?;
+        if fields.next().is_some() {
+            return Err(anyhow!("too many fields"));
+        }
+        Ok(Some(Self {
+            listen_port,
+            host,
+            port,
+        }))
+    }
+
+    fn features(&self) -> RequestFeatures<&str> {
+        let host = self.host.trim_start_matches('[').trim_end_matches(']');
+        match host.parse::<IpAddr>() {
+            Ok(ip) => RequestFeatures {
+                listen_port: Some(self.listen_port),
+                dst_ip: Some(ip),
+                dst_domain: None,
+            },
+            Err(_) => RequestFeatures {
+                listen_port: Some(self.listen_port),
+                dst_ip: None,
+                dst_domain: Some(host),
+            },
+        }
+    }
+}
+
+/// Where a request goes, and the rules matched.
+struct Route {
+    outcome: String,
+    rules: Vec<usize>,
+}
+
+fn route(policy: &Policy, servers: &[Arc<ProxyServer>], request: &Request) -> Route {
+    let action = policy.matches(&request.features());
+    let outcome = match &action.action {
+        ActionType::Direct | ActionType::Reject => action.to_string(),
+        ActionType::Require(caps) => {
+            let tags: Vec<_> = servers
+                .iter()
+                .filter(|s| caps.iter().all(|c| s.capable_anyof(c)))
+                .filter(|s| s.allows_port(request.port))
+                .map(|s| s.tag())
+                .collect();
+            match tags.is_empty() {
+                true => format!("{} => (no server)", action),
+                false => format!("{} => {}", action, tags.join(", ")),
+            }
+        }
+    };
+    Route {
+        outcome,
+        rules: action.rules().to_vec(),
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Report {
+    total: usize,
+    outcomes: HashMap<String, usize>,
+    rules: HashMap<String, usize>,
+    /// Set on diff mode.
+    compared: bool,
+    /// (request, outcome of the other policy, outcome of this one)
+    diffs: Vec<(String, String, String)>,
+}
+
+/// Route each request from `input` with `policy`, and `other` policy if
+/// given, against `servers`.
+fn simulate<R: BufRead>(
+    policy: &Policy,
+    other: Option<&Policy>,
+    servers: &[Arc<ProxyServer>],
+    input: R,
+) -> anyhow::Result<Report> {
+    let mut report = Report {
+        compared: other.is_some(),
+        ..Default::default()
+    };
+    for (n, line) in input.lines().enumerate() {
+        let line = line?;
+        let request = match Request::parse(&line).with_context(|| format!("line {}", n + 1))? {
+            Some(request) => request,
+            None => continue,
+        };
+        let Route { outcome, rules } = route(policy, servers, &request);
+        report.total += 1;
+        for rule in rules.iter().filter_map(|n| policy.rule_text(*n)) {
+            *report.rules.entry(rule.to_string()).or_default() += 1;
+        }
+        if let Some(other) = other {
+            let old = route(other, servers, &request).outcome;
+            if old != outcome {
+                report
+                    .diffs
+                    .push((line.trim().to_string(), old, outcome.clone()));
+            }
+        }
+        *report.outcomes.entry(outcome).or_default() += 1;
+    }
+    Ok(report)
+}
+
+/// Run `moproxy simulate`. Use the configured policy if `policy` is not set.
+pub(crate) fn run(
+    moproxy: &MoProxy,
+    policy: Option<&Path>,
+    input: &Path,
+    diff: Option<&Path>,
+) -> anyhow::Result<Report> {
+    let policy = policy
+        .map(Policy::load_from_file)
+        .transpose()
+        .context("cannot load policy")?;
+    let other = diff
+        .map(Policy::load_from_file)
+        .transpose()
+        .context("cannot load policy to compare")?;
+    let current = moproxy.policy.read();
+    let policy = policy.as_ref().unwrap_or(&current);
+    let servers = moproxy.monitor.servers();
+    if input == Path::new("-") {
+        simulate(policy, other.as_ref(), &servers, io::stdin().lock())
+    } else {
+        let file = File::open(input).context("cannot open input")?;
+        simulate(policy, other.as_ref(), &servers, BufReader::new(file))
+    }
+}
+
+/// Write `(key, count)` pairs from the most frequent one.
+fn write_counts(f: &mut fmt::Formatter, counts: &HashMap<String, usize>) -> fmt::Result {
+    let mut counts: Vec<_> = counts.iter().collect();
+    counts.sort_unstable_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+    for (key, count) in counts {
+        writeln!(f, "{:>8}  {}", count, key)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Requests: {}", self.total)?;
+        writeln!(f, "Actions:")?;
+        write_counts(f, &self.outcomes)?;
+        writeln!(f, "Matched rules:")?;
+        write_counts(f, &self.rules)?;
+        if self.compared {
+            writeln!(f, "Changed: {} of {}", self.diffs.len(), self.total)?;
+            for (request, old, new) in self.diffs.iter().take(MAX_DIFFS_SHOWN) {
+                writeln!(f, "  {}: {} -> {}", request, old, new)?;
+            }
+            if self.diffs.len() > MAX_DIFFS_SHOWN {
+                writeln!(f, "  ({} more)", self.diffs.len() - MAX_DIFFS_SHOWN)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_parse_request() {
+    let request = Request::parse(" 2080 example.com 443 ").unwrap().unwrap();
+    assert_eq!(
+        Request {
+            listen_port: 2080,
+            host: "example.com",
+            port: 443
+        },
+        request
+    );
+    assert!(Request::parse("# comment").unwrap().is_none());
+    assert!(Request::parse("2080 example.com").is_err());
+    assert!(Request::parse("2080 example.com 443 1").is_err());
+    let features = Request::parse("2080 [::1] 80").unwrap().unwrap();
+    assert_eq!(Some("::1".parse().unwrap()), features.features().dst_ip);
+}
+
+#[test]
+fn test_simulate() {
+    use moproxy::{policy::parser, proxy::ProxyProto};
+    use std::{io::Cursor, time::Duration};
+
+    let server = |port, caps, tag| {
+        let (_, caps) = parser::capabilities(caps).unwrap();
+        Arc::new(
+            ProxyServer::new(
+                ([127, 0, 0, 1], port).into(),
+                ProxyProto::socks5(false),
+                ([127, 0, 0, 1], 53).into(),
+                Duration::from_secs(1),
+                Some(caps),
+                Some(tag),
+                None,
+            )
+            .unwrap(),
+        )
+    };
+    let servers = vec![server(1, "us", "a"), server(2, "us jp", "b")];
+    servers[0].update_config(|config| config.allowed_ports = Some(vec![443]));
+
+    let old = Policy::load(Cursor::new("dst domain example.com require us\n")).unwrap();
+    let new = Policy::load(Cursor::new(
+        "dst domain example.com require jp\n\
+         dst ip 192.0.2.0/24 direct\n\
+         listen port 2081 reject\n",
+    ))
+    .unwrap();
+    let log = include_str!("../tests/fixtures/simulate.log");
+    let report = simulate(&new, Some(&old), &servers, Cursor::new(log)).unwrap();
+
+    assert_eq!(5, report.total);
+    assert_eq!(Some(&2), report.outcomes.get("REQUIRE jp => b"));
+    assert_eq!(Some(&1), report.outcomes.get("DIRECT"));
+    assert_eq!(Some(&1), report.outcomes.get("REJECT"));
+    assert_eq!(Some(&1), report.outcomes.get("REQUIRE NOTHING => b"));
+    assert_eq!(
+        Some(&2),
+        report.rules.get("dst domain example.com require jp")
+    );
+
+    // `a` also serves example.com:443 under the old policy
+    assert_eq!(4, report.diffs.len());
+    assert_eq!(
+        (
+            "2080 www.example.com 443".to_string(),
+            "REQUIRE us => a, b".to_string(),
+            "REQUIRE jp => b".to_string()
+        ),
+        report.diffs[0]
+    );
+    let text = report.to_string();
+    assert!(text.starts_with("Requests: 5\n"));
+    assert!(text.contains("Changed: 4 of 5\n"));
+
+    assert!(simulate(&new, None, &servers, Cursor::new("80 x")).is_err());
+}
@@ -2,10 +2,26 @@ use hyper::Request;
 use number_prefix::NumberPrefix::{self, Prefixed, Standalone};
 use once_cell::sync::Lazy;
 use regex::Regex;
-use std::{fmt::Write, time::Duration};
+use std::{net::IpAddr, str::from_utf8};
+
+/// Address of the web console client, set on requests by
+/// `WebService::serve_connection()` if known.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerIp(pub IpAddr);
 
 pub trait RequestExt {
     fn accept_html(&self) -> bool;
+    /// Percent-decoded value of the query parameter, the first one if
+    /// repeated.
+    fn query_param(&self, name: &str) -> Option<String>;
+    fn bearer_token(&self) -> Option<&str>;
+    /// Whether the bearer token is `token`, compared in constant time.
+    fn has_bearer_token(&self, token: &str) -> bool {
+        self.bearer_token()
+            .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()))
+    }
+    /// See `PeerIp`.
+    fn peer_ip(&self) -> Option<IpAddr>;
 }
 
 impl<T> RequestExt for Request<T> {
@@ -30,36 +46,55 @@ impl<T> RequestExt for Request<T> {
             true
         }
     }
-}
 
-pub trait DurationExt {
-    fn format(&self) -> String;
-    fn format_millis(&self) -> String;
-}
+    fn query_param(&self, name: &str) -> Option<String> {
+        self.uri()
+            .query()?
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| percent_decode(value))
+    }
 
-impl DurationExt for Duration {
-    fn format(&self) -> String {
-        let secs = self.as_secs();
-        let d = secs / 86400;
-        let h = (secs % 86400) / 3600;
-        let m = (secs % 3600) / 60;
-        let s = secs % 60;
-        let mut buf = String::new();
-        vec![(d, 'd'), (h, 'h'), (m, 'm'), (s, 's')]
-            .into_iter()
-            .filter(|(v, _)| *v > 0)
-            .take(2)
-            .for_each(|(v, u)| {
-                write!(&mut buf, "{}{}", v, u).unwrap();
-            });
-        buf
+    fn bearer_token(&self) -> Option<&str> {
+        self.headers()
+            .get("authorization")?
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")
     }
 
-    fn format_millis(&self) -> String {
-        format!("{} ms", self.as_millis())
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.extensions().get::<PeerIp>().map(|peer| peer.0)
     }
 }
 
+/// Leak nothing but the length on the time taken.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Decode %XX escapes in URL path, invalid escapes are kept as is.
+pub fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut buf = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| from_utf8(h).ok());
+        match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+            Some(b) if bytes[i] == b'%' => {
+                buf.push(b);
+                i += 3;
+            }
+            _ => {
+                buf.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&buf).into_owned()
+}
+
 pub fn to_human_bytes(n: usize) -> String {
     if n == 0 {
         String::new()
@@ -84,3 +119,19 @@ pub fn to_human_bps_prefix_only(n: usize) -> String {
         Prefixed(prefix, n) => format!("{:.0}{}", n, prefix),
     }
 }
+
+#[test]
+fn test_constant_time_eq() {
+    assert!(constant_time_eq(b"secret", b"secret"));
+    assert!(!constant_time_eq(b"secret", b"secreT"));
+    assert!(!constant_time_eq(b"secret", b"secret2"));
+    assert!(constant_time_eq(b"", b""));
+}
+
+#[test]
+fn test_percent_decode() {
+    assert_eq!("abc", percent_decode("abc"));
+    assert_eq!("a b/東", percent_decode("a%20b%2F%E6%9D%B1"));
+    assert_eq!("100%", percent_decode("100%"));
+    assert_eq!("%zz", percent_decode("%zz"));
+}
@@ -0,0 +1,219 @@
+use parking_lot::Mutex;
+use std::{
+    collections::VecDeque,
+    fmt::{self, Write},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+/// Recent tracing events kept in memory, for `/debug/log`.
+#[derive(Debug)]
+pub struct LogTail {
+    records: Mutex<Records>,
+    /// Max number of records kept, 0 for disabled.
+    capacity: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct Records {
+    queue: VecDeque<LogRecord>,
+    next_seq: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    /// Increased by one for each event, starting from 1.
+    pub seq: u64,
+    pub time: SystemTime,
+    pub level: Level,
+    /// Formatted event, without time and level.
+    pub text: String,
+}
+
+/// Global tail of events captured by `LogTailLayer`.
+pub static LOG_TAIL: LogTail = LogTail::new();
+
+impl LogTail {
+    pub const fn new() -> Self {
+        Self {
+            records: Mutex::new(Records {
+                queue: VecDeque::new(),
+                next_seq: 1,
+            }),
+            capacity: AtomicUsize::new(0),
+        }
+    }
+
+    /// Start keeping at most `capacity` recent events.
+    pub fn enable(&self, capacity: usize) {
+        self.capacity.store(capacity, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity.load(Ordering::Relaxed) > 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity.load(Ordering::Relaxed)
+    }
+
+    fn push(&self, level: Level, text: String) {
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        if capacity == 0 {
+            return;
+        }
+        let mut records = self.records.lock();
+        while records.queue.len() >= capacity {
+            records.queue.pop_front();
+        }
+        let seq = records.next_seq;
+        records.next_seq += 1;
+        records.queue.push_back(LogRecord {
+            seq,
+            time: SystemTime::now(),
+            level,
+            text,
+        });
+    }
+
+    /// At most the last `lines` records at `level` or more severe, and
+    /// after `after` if set, the oldest first.
+    pub fn recent(&self, level: Level, lines: usize, after: Option<u64>) -> Vec<LogRecord> {
+        let records = self.records.lock();
+        let mut recent: Vec<_> = records
+            .queue
+            .iter()
+            .rev()
+            .take_while(|record| after.map_or(true, |after| record.seq > after))
+            .filter(|record| record.level <= level)
+            .take(lines)
+            .cloned()
+            .collect();
+        recent.reverse();
+        recent
+    }
+}
+
+impl Default for LogTail {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let time = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        write!(
+            f,
+            "{} {}.{:03} {:>5} {}",
+            self.seq,
+            time.as_secs(),
+            time.subsec_millis(),
+            self.level,
+            self.text
+        )
+    }
+}
+
+/// Layer capturing events into a `LogTail`, formatted like
+/// `span:span: target: message key=value`.
+pub struct LogTailLayer {
+    tail: &'static LogTail,
+}
+
+impl LogTailLayer {
+    pub fn new(tail: &'static LogTail) -> Self {
+        Self { tail }
+    }
+}
+
+impl<S> Layer<S> for LogTailLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if !self.tail.is_enabled() {
+            return;
+        }
+        let mut text = String::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                text.push_str(span.name());
+                text.push(':');
+            }
+            if !text.is_empty() {
+                text.push(' ');
+            }
+        }
+        let meta = event.metadata();
+        let _ = write!(text, "{}:", meta.target());
+        event.record(&mut FieldWriter(&mut text));
+        self.tail.push(*meta.level(), text);
+    }
+}
+
+struct FieldWriter<'a>(&'a mut String);
+
+impl Visit for FieldWriter<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            let _ = write!(self.0, " {}", value);
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, " {:?}", value);
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+#[test]
+fn test_log_tail() {
+    use tracing::{debug, info, info_span, trace, warn};
+    use tracing_subscriber::prelude::*;
+
+    static TAIL: LogTail = LogTail::new();
+    let subscriber = tracing_subscriber::registry().with(LogTailLayer::new(&TAIL));
+    tracing::subscriber::with_default(subscriber, || {
+        info!("not enabled");
+        TAIL.enable(3);
+        debug!(n = 1, "first");
+        let span = info_span!("client", peer = "192.0.2.1");
+        let _enter = span.enter();
+        info!(name = "a.test", "second");
+        warn!("third");
+        trace!("fourth");
+    });
+
+    let records = TAIL.recent(Level::TRACE, 10, None);
+    let seqs: Vec<_> = records.iter().map(|r| r.seq).collect();
+    assert_eq!(vec![2, 3, 4], seqs);
+    assert_eq!(Level::INFO, records[0].level);
+    assert!(records[0]
+        .text
+        .starts_with("client: moproxy::web::log_tail: second"));
+    assert!(records[0].text.ends_with(" name=\"a.test\""));
+    assert!(records[2].to_string().contains("TRACE client: "));
+
+    // Filtered by level, then the last lines
+    let records = TAIL.recent(Level::INFO, 10, None);
+    assert_eq!(
+        vec![2, 3],
+        records.iter().map(|r| r.seq).collect::<Vec<_>>()
+    );
+    let records = TAIL.recent(Level::TRACE, 1, None);
+    assert_eq!(4, records[0].seq);
+    let records = TAIL.recent(Level::TRACE, 10, Some(3));
+    assert_eq!(vec![4], records.iter().map(|r| r.seq).collect::<Vec<_>>());
+    assert!(TAIL.recent(Level::TRACE, 10, Some(4)).is_empty());
+}
@@ -1,29 +1,34 @@
 mod helpers;
+pub mod log_tail;
 mod open_metrics;
+mod plain;
 #[cfg(feature = "rich_web")]
 mod rich;
+mod router;
 use anyhow::Context;
 use bytes::Bytes;
 use flexstr::SharedStr;
-use helpers::{DurationExt, RequestExt};
-use http_body_util::Full;
+use helpers::{percent_decode, PeerIp, RequestExt};
+use http_body_util::{BodyExt, Full, Limited};
 use hyper::{
     body::Incoming, server::conn::http1, service::service_fn, Method, Request, Response, StatusCode,
 };
 use hyper_util::rt::TokioIo;
-#[cfg(feature = "rich_web")]
 use once_cell::sync::Lazy;
+use parking_lot::RwLock;
 use prettytable::{cell, format::consts::FORMAT_NO_LINESEP_WITH_TITLE, row, Table};
 use serde_derive::Serialize;
 use std::{
+    collections::BTreeMap,
     fmt::Write,
-    fs, io,
-    net::SocketAddr,
-    path::Path,
+    io,
+    net::{IpAddr, SocketAddr},
     sync::Arc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 #[cfg(unix)]
+use std::{fs, path::Path};
+#[cfg(unix)]
 use tokio::net::{UnixListener, UnixStream};
 use tokio::{
     self,
@@ -32,25 +37,98 @@ use tokio::{
 };
 use tracing::{info, instrument, warn};
 
+use log_tail::LOG_TAIL;
+use router::Router;
+
 use crate::{
-    monitor::{Monitor, Throughput},
-    proxy::{Delay, ProxyServer},
+    client::{
+        reject_page::{RejectPageCounters, REJECT_PAGES},
+        ClientGoneCounters, ClientHintCounters, InboundRejectCounters, Socks5NegotiationCounters,
+        TlsFingerprintCount, TlsSniffCounters, CLIENT_GONE_EARLY, CLIENT_HINTS, INBOUND_REJECTS,
+        SOCKS5_NEGOTIATION, TLS_FINGERPRINTS, TLS_SNIFF_STATS,
+    },
+    duration::DurationExt,
+    monitor::{
+        AdaptiveParallelStats, ClientStats, ConfigHash, EventLoopCounters, HandshakeStats, Monitor,
+        Offender, ReloadHistory, SourceStats, TaskInfo, TaskKind, TaskStats, Throughput,
+        ACCOUNTING, DESTINATIONS, EVENT_LOOP,
+    },
+    policy::{capabilities::CapSet, dns::PolicyDnsStats, maintenance::Maintenance, Policy},
+    proxy::{
+        buffers::{BufferUsage, BUFFERS},
+        resolver::{ResolverCacheStats, RESOLVER},
+        Delay, ProxyServer,
+    },
 };
 
+pub use plain::PlainStatsServer;
+
 #[cfg(feature = "rich_web")]
 static BUNDLE: Lazy<rich::ResourceBundle> = Lazy::new(rich::ResourceBundle::new);
 
+/// Windows of recent connections to calculate error ratios on.
+const ERROR_WINDOWS: [u8; 2] = [16, 64];
+
 #[derive(Debug, Serialize)]
 struct ServerStatus {
     server: Arc<ProxyServer>,
     throughput: Option<Throughput>,
+    /// Ratio of errors in each of `ERROR_WINDOWS`.
+    recent_error_ratio: BTreeMap<u8, f32>,
+}
+
+impl ServerStatus {
+    fn new(server: Arc<ProxyServer>, throughput: Option<Throughput>) -> Self {
+        let status = server.status_snapshot();
+        let recent_error_ratio = ERROR_WINDOWS
+            .iter()
+            .map(|&n| (n, status.recent_error_rate(n)))
+            .collect();
+        Self {
+            server,
+            throughput,
+            recent_error_ratio,
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
 struct Status {
     servers: Vec<ServerStatus>,
+    /// Connections without proxy.
+    direct: Option<Arc<ProxyServer>>,
     uptime: Duration,
     throughput: Throughput,
+    clients: ClientStats,
+    /// Clients not yet piped, see `--max-pending-handshakes`.
+    handshakes: HandshakeStats,
+    /// Clients counted per source address, see `--max-conn-per-ip`.
+    sources: SourceStats,
+    /// Spawned tasks alive, see `--task-leak-factor`.
+    tasks: TaskStats,
+    /// Spawn-to-poll delay of clients and lag of probe rounds.
+    event_loop: EventLoopCounters,
+    tls_sniff: TlsSniffCounters,
+    tls_fingerprints: Vec<TlsFingerprintCount>,
+    /// Non-NATed connections in unaccepted protocols.
+    inbound_rejects: InboundRejectCounters,
+    socks5_negotiation: Socks5NegotiationCounters,
+    /// Rejected HTTP requests answered with `--reject-http-page`.
+    reject_pages: RejectPageCounters,
+    /// Routing hints of HTTP CONNECT clients, see `--allow-client-hints`.
+    client_hints: ClientHintCounters,
+    /// Clients closed before connected to upstream.
+    client_gone_early: ClientGoneCounters,
+    buffers: BufferUsage,
+    /// Cache of `--resolver`, unset if not enabled.
+    resolver: Option<ResolverCacheStats>,
+    /// Names resolved for `--resolve-sni-for-policy`, unset if not enabled.
+    policy_dns: Option<PolicyDnsStats>,
+    /// Destinations raced for `--n-parallel auto`, unset if not enabled.
+    adaptive_parallel: Option<AdaptiveParallelStats>,
+    reload: ReloadHistory,
+    /// Checksums of the loaded server list and policy.
+    config: Vec<ConfigHash>,
 }
 
 impl Status {
@@ -59,39 +137,151 @@ impl Status {
         let throughput = thps.values().fold(Default::default(), |a, b| a + *b);
         let servers = monitor
             .servers()
-            .into_iter()
-            .map(|server| ServerStatus {
-                throughput: thps.remove(&server),
-                server,
-            })
+            .iter()
+            .map(|server| ServerStatus::new(server.clone(), thps.remove(server)))
             .collect();
         Status {
             servers,
+            direct: monitor.direct_server(),
             throughput,
             uptime: start_time.elapsed(),
+            clients: monitor.client_stats(),
+            handshakes: monitor.handshake_stats(),
+            sources: monitor.source_stats(),
+            tasks: monitor.task_stats(),
+            event_loop: EVENT_LOOP.snapshot(),
+            tls_sniff: TLS_SNIFF_STATS.snapshot(),
+            tls_fingerprints: TLS_FINGERPRINTS.snapshot(),
+            inbound_rejects: INBOUND_REJECTS.snapshot(),
+            socks5_negotiation: SOCKS5_NEGOTIATION.snapshot(),
+            reject_pages: REJECT_PAGES.snapshot(),
+            client_hints: CLIENT_HINTS.snapshot(),
+            client_gone_early: CLIENT_GONE_EARLY.snapshot(),
+            buffers: BUFFERS.snapshot(),
+            resolver: RESOLVER.is_enabled().then(|| RESOLVER.cache_stats()),
+            policy_dns: monitor.policy_dns().map(|dns| dns.stats()),
+            adaptive_parallel: monitor.adaptive_parallel().map(|p| p.stats()),
+            reload: monitor.reload_history(),
+            config: monitor.config_hashes(),
+        }
+    }
+}
+
+/// Servers of a tenant only, see `WebServer::with_tenants()`. Nothing
+/// shared with other tenants is included.
+#[derive(Debug, Serialize)]
+struct TenantStatus {
+    tenant: SharedStr,
+    servers: Vec<ServerStatus>,
+    uptime: Duration,
+    /// Of the servers of the tenant.
+    throughput: Throughput,
+}
+
+impl TenantStatus {
+    fn from(start_time: &Instant, monitor: &Monitor, tenant: SharedStr) -> Self {
+        let cap = CapSet::new(std::iter::once(tenant.as_str()));
+        let mut thps = monitor.throughputs();
+        let servers: Vec<_> = monitor
+            .servers()
+            .iter()
+            .filter(|server| server.capable_anyof(&cap))
+            .map(|server| ServerStatus::new(server.clone(), thps.remove(server)))
+            .collect();
+        let throughput = servers
+            .iter()
+            .filter_map(|s| s.throughput)
+            .fold(Default::default(), |a, b| a + b);
+        TenantStatus {
+            tenant,
+            servers,
+            uptime: start_time.elapsed(),
+            throughput,
         }
     }
 }
 
 type BytesResult = Result<Response<Full<Bytes>>, http::Error>;
 
-fn home_page(req: &Request<Incoming>, start_time: &Instant, monitor: &Monitor) -> BytesResult {
+/// Requests with a larger body are refused.
+const MAX_REQUEST_BODY: usize = 64 * 1024;
+
+#[cfg(feature = "rich_web")]
+fn bundle_response(path: &str) -> Option<BytesResult> {
+    BUNDLE.get(path).map(|(mime, body)| {
+        Response::builder()
+            .header("Content-Type", mime)
+            .body(body.into())
+    })
+}
+
+#[cfg(not(feature = "rich_web"))]
+fn bundle_response(_path: &str) -> Option<BytesResult> {
+    None
+}
+
+#[cfg(feature = "rich_web")]
+fn index_html() -> Vec<u8> {
+    BUNDLE
+        .get("/index.html")
+        .map(|(_, html)| html)
+        .unwrap_or_else(|| include_str!("index.html").into())
+}
+
+#[cfg(not(feature = "rich_web"))]
+fn index_html() -> Vec<u8> {
+    include_str!("index.html").into()
+}
+
+fn home_page<T>(req: &Request<T>, ctx: &WebContext) -> BytesResult {
     if req.accept_html() {
-        #[cfg(feature = "rich_web")]
-        let resp = BUNDLE.get("/index.html").map(|(mime, content)| {
-            Response::builder()
-                .header("Content-Type", mime)
-                .body(content.into())
-        });
-        #[cfg(not(feature = "rich_web"))]
-        let resp = None;
-        resp.unwrap_or_else(|| {
-            Response::builder()
-                .header("Content-Type", "text/html")
-                .body(include_str!("index.html").into())
-        })
+        let html = index_html();
+        let html = match ctx.options.path_prefix.as_deref() {
+            Some(prefix) => with_path_prefix(&String::from_utf8_lossy(&html), prefix).into(),
+            None => html,
+        };
+        Response::builder()
+            .header("Content-Type", "text/html")
+            .body(html.into())
     } else {
-        plaintext_status_response(start_time, monitor)
+        unfiltered(req, ctx, || {
+            plaintext_status_response(&ctx.start_time, &ctx.monitor)
+        })
+    }
+}
+
+/// Make root-relative URLs (`="/..."`) of the page under `prefix`, and set
+/// the base URL for relative ones.
+fn with_path_prefix(html: &str, prefix: &str) -> String {
+    let mut buf = String::with_capacity(html.len() + 64);
+    let mut rest = html;
+    while let Some(i) = rest.find("=\"/") {
+        let (head, tail) = rest.split_at(i + 2);
+        buf.push_str(head);
+        // Leave network-path references (`//host/...`) alone
+        if !tail.starts_with("//") {
+            buf.push_str(prefix);
+        }
+        rest = tail;
+    }
+    buf.push_str(rest);
+    if let Some(i) = buf.find("<head>") {
+        buf.insert_str(i + 6, &format!("<base href=\"{}/\">", prefix));
+    }
+    buf
+}
+
+/// Return the path with `prefix` stripped, empty if it's the prefix
+/// itself. `None` if outside the prefix.
+fn strip_path_prefix<'a>(path: &'a str, prefix: Option<&str>) -> Option<&'a str> {
+    let prefix = match prefix {
+        Some(prefix) => prefix,
+        None => return Some(path),
+    };
+    match path.strip_prefix(prefix)? {
+        "" => Some(""),
+        path if path.starts_with('/') => Some(path),
+        _ => None,
     }
 }
 
@@ -106,12 +296,32 @@ fn plaintext_status(start_time: &Instant, monitor: &Monitor) -> String {
         status.uptime.format()
     )
     .unwrap();
+    if let Some(last) = status.reload.recent.back() {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|t| t.as_secs())
+            .unwrap_or_default();
+        let ago = Duration::from_secs(now.saturating_sub(last.time)).format();
+        writeln!(
+            &mut buf,
+            "Config generation {}, last reload {} {} ago{}",
+            status.reload.generation,
+            if last.success { "succeeded" } else { "failed" },
+            if ago.is_empty() { "0s" } else { &ago },
+            match &last.error {
+                Some(err) => format!(": {}", err),
+                None => "".into(),
+            }
+        )
+        .unwrap();
+    }
 
     let mut table = Table::new();
     table.add_row(row![
         "Server",
         "Score",
         "Delay",
+        "TTFB",
         "CUR",
         "TTL",
         "E16:64",
@@ -121,18 +331,30 @@ fn plaintext_status(start_time: &Instant, monitor: &Monitor) -> String {
     ]);
     table.set_format(*FORMAT_NO_LINESEP_WITH_TITLE);
     let mut total_alive_conns = 0;
-    for ServerStatus { server, throughput } in status.servers {
+    for ServerStatus {
+        server, throughput, ..
+    } in status.servers
+    {
         let status = server.status_snapshot();
         let traffic = server.traffic();
         total_alive_conns += status.conn_alive;
         let row = table.add_empty_row();
         // Server
-        row.add_cell(cell!(l -> server.tag));
+        match server.allowed_ports() {
+            None => row.add_cell(cell!(l -> server.tag())),
+            Some(ports) => {
+                let ports: Vec<_> = ports.iter().map(|p| p.to_string()).collect();
+                row.add_cell(cell!(l -> format!("{} :{}", server.tag(), ports.join(","))))
+            }
+        }
         // Score
-        if let Some(v) = status.score {
-            row.add_cell(cell!(r -> v));
-        } else {
-            row.add_cell(cell!(r -> "-"));
+        match status.score {
+            Some(v) if status.excluded_by_score => {
+                row.add_cell(cell!(r -> format!("{} (excluded)", v)))
+            }
+            Some(v) if status.recovering => row.add_cell(cell!(r -> format!("{} (recovering)", v))),
+            Some(v) => row.add_cell(cell!(r -> v)),
+            None => row.add_cell(cell!(r -> "-")),
         }
         // Delay
         if let Delay::Some(v) = status.delay {
@@ -140,15 +362,20 @@ fn plaintext_status(start_time: &Instant, monitor: &Monitor) -> String {
         } else {
             row.add_cell(cell!(r -> "-"));
         }
+        // TTFB
+        if let Some(v) = status.ttfb.mean() {
+            row.add_cell(cell!(r -> v.format_millis()));
+        } else {
+            row.add_cell(cell!(r -> "-"));
+        }
         // CUR TTL
         row.add_cell(cell!(r -> status.conn_alive));
         row.add_cell(cell!(r -> status.conn_total));
-        // Error rate
-        // TODO: document the two columns
+        // Errors in the last 16 and 64 connections
         row.add_cell(cell!(r ->
             format!("{:02}:{:02}",
-                status.recent_error_count( 16),
-                status.recent_error_count(64),
+                status.recent_error_count(ERROR_WINDOWS[0]),
+                status.recent_error_count(ERROR_WINDOWS[1]),
             )
         ));
         // Up Down
@@ -156,7 +383,7 @@ fn plaintext_status(start_time: &Instant, monitor: &Monitor) -> String {
         row.add_cell(cell!(r -> helpers::to_human_bytes(traffic.rx_bytes)));
         // ↑↓
         if let Some(tp) = throughput {
-            let sum = tp.tx_bps + tp.rx_bps;
+            let sum = tp.tx_bps_avg + tp.rx_bps_avg;
             if sum > 0 {
                 row.add_cell(cell!(r -> helpers::to_human_bps_prefix_only(sum)));
             }
@@ -167,8 +394,8 @@ fn plaintext_status(start_time: &Instant, monitor: &Monitor) -> String {
         &mut buf,
         "[{}] ↑ {} ↓ {}\n{}",
         total_alive_conns,
-        helpers::to_human_bps(status.throughput.tx_bps),
-        helpers::to_human_bps(status.throughput.rx_bps),
+        helpers::to_human_bps(status.throughput.tx_bps_avg),
+        helpers::to_human_bps(status.throughput.rx_bps_avg),
         table
     )
     .unwrap();
@@ -181,45 +408,471 @@ fn plaintext_status_response(start_time: &Instant, monitor: &Monitor) -> BytesRe
         .body(plaintext_status(start_time, monitor).into())
 }
 
-fn response(req: &Request<Incoming>, start_time: Instant, monitor: Monitor) -> BytesResult {
-    if req.method() != Method::GET {
+fn server_status_response(tag: &str, monitor: &Monitor) -> BytesResult {
+    let tag = percent_decode(tag);
+    let server = match monitor.server_by_tag(&tag) {
+        Some(server) => server,
+        None => {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .header("Content-Type", "text/plain")
+                .body("server not found".into())
+        }
+    };
+    let throughput = monitor.throughputs().remove(&server);
+    let status = ServerStatus::new(server, throughput);
+    let json = serde_json::to_string(&status).expect("fail to serialize server to json");
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(json.into())
+}
+
+#[derive(Debug, Serialize)]
+struct ConfigResponse {
+    version: &'static str,
+    generation: u64,
+    hashes: Vec<ConfigHash>,
+}
+
+fn config_response(monitor: &Monitor) -> BytesResult {
+    let config = ConfigResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        generation: monitor.reload_history().generation,
+        hashes: monitor.config_hashes(),
+    };
+    let json = serde_json::to_string(&config).expect("fail to serialize config to json");
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(json.into())
+}
+
+fn policy_stats_response(policy: &RwLock<Policy>) -> BytesResult {
+    let json = serde_json::to_string(&policy.read().rule_stats())
+        .expect("fail to serialize policy stats to json");
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(json.into())
+}
+
+/// Check the bearer token of pages enabled by setting `token`, return
+/// the error response if not authorized.
+fn authorize<T>(req: &Request<T>, token: Option<&str>) -> Option<BytesResult> {
+    let token = match token {
+        Some(token) => token,
+        None => {
+            return Some(
+                Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .header("Content-Type", "text/plain")
+                    .body("page not found".into()),
+            )
+        }
+    };
+    if !req.has_bearer_token(token) {
+        return Some(unauthorized());
+    }
+    None
+}
+
+fn unauthorized() -> BytesResult {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header("WWW-Authenticate", "Bearer")
+        .header("Content-Type", "text/plain")
+        .body("unauthorized".into())
+}
+
+/// Serve `page` showing things of all tenants, if authorized by the
+/// token of `WebServer::with_status_token()` or it's not set.
+fn unfiltered<T, F>(req: &Request<T>, ctx: &WebContext, page: F) -> BytesResult
+where
+    F: FnOnce() -> BytesResult,
+{
+    match ctx.options.status_token.as_deref() {
+        Some(token) if !req.has_bearer_token(token) => unauthorized(),
+        _ => page(),
+    }
+}
+
+/// Serve `page` changing things, only with the token of
+/// `WebServer::with_admin_token()`.
+fn admin<T, F>(req: &Request<T>, ctx: &WebContext, page: F) -> BytesResult
+where
+    F: FnOnce() -> BytesResult,
+{
+    authorize(req, ctx.options.admin_token.as_deref()).unwrap_or_else(page)
+}
+
+fn whoami_response<T>(req: &Request<T>, monitor: &Monitor, token: Option<&str>) -> BytesResult {
+    if let Some(resp) = authorize(req, token) {
+        return resp;
+    }
+    let port = match req.query_param("src_port").and_then(|p| p.parse().ok()) {
+        Some(port) => port,
+        None => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .header("Content-Type", "text/plain")
+                .body("src_port missing or invalid".into())
+        }
+    };
+    // Only tell clients about their own connections, IPv4-mapped IPv6
+    // as IPv4 since the proxy listens on `::` by default
+    let peer = req.peer_ip().map(|ip| ip.to_canonical());
+    let mut connections = monitor.connections_by_source_port(port);
+    connections.retain(|conn| Some(conn.client.ip().to_canonical()) == peer);
+    if connections.is_empty() {
         return Response::builder()
-            .status(StatusCode::METHOD_NOT_ALLOWED)
-            .header("Allow", "GET")
+            .status(StatusCode::NOT_FOUND)
             .header("Content-Type", "text/plain")
-            .body("only GET is allowed".into());
+            .body("connection not found".into());
     }
+    let json = serde_json::to_string(&connections).expect("fail to serialize connections to json");
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(json.into())
+}
 
-    match req.uri().path() {
-        "/" | "/index.html" => home_page(req, &start_time, &monitor),
-        "/plain" => plaintext_status_response(&start_time, &monitor),
-        "/version" => Response::builder()
+/// Recent log events, see `--debug-log-token`. `X-Log-Seq` is the last
+/// sequence number returned, for polling with `after=SEQ`.
+fn debug_log_response<T>(req: &Request<T>, token: Option<&str>) -> BytesResult {
+    if let Some(resp) = authorize(req, token) {
+        return resp;
+    }
+    let bad_request = |msg: &'static str| {
+        Response::builder()
+            .status(StatusCode::BAD_REQUEST)
             .header("Content-Type", "text/plain")
-            .body(env!("CARGO_PKG_VERSION").into()),
-        "/status" => {
-            let json = serde_json::to_string(&Status::from(&start_time, &monitor))
-                .expect("fail to serialize servers to json");
-            Response::builder()
-                .header("Content-Type", "application/json")
-                .body(json.into())
+            .body(msg.into())
+    };
+    let level = match req.query_param("level").map(|l| l.parse()) {
+        None => tracing::Level::TRACE,
+        Some(Ok(level)) => level,
+        Some(Err(_)) => return bad_request("invalid level"),
+    };
+    let lines = match req.query_param("lines").map(|n| n.parse()) {
+        None => 200,
+        Some(Ok(lines)) => lines,
+        Some(Err(_)) => return bad_request("invalid lines"),
+    };
+    let after = match req.query_param("after").map(|n| n.parse()) {
+        None => None,
+        Some(Ok(seq)) => Some(seq),
+        Some(Err(_)) => return bad_request("invalid after"),
+    };
+    let records = LOG_TAIL.recent(level, lines, after);
+    let mut buf = String::new();
+    for record in &records {
+        writeln!(buf, "{}", record).unwrap();
+    }
+    let last_seq = records.last().map(|r| r.seq).or(after).unwrap_or(0);
+    Response::builder()
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .header("X-Log-Seq", last_seq)
+        .body(buf.into())
+}
+
+/// Sample of client tasks to debug leaks, see `--task-leak-factor`.
+#[derive(Debug, Serialize)]
+struct DebugTasks {
+    tasks: TaskStats,
+    /// Connections alive to all servers.
+    connections: usize,
+    /// Oldest client tasks alive.
+    oldest: Vec<TaskInfo>,
+}
+
+fn debug_tasks_response<T>(req: &Request<T>, monitor: &Monitor) -> BytesResult {
+    let limit = match req.query_param("limit").map(|n| n.parse()) {
+        None => 100,
+        Some(Ok(limit)) => limit,
+        Some(Err(_)) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .header("Content-Type", "text/plain")
+                .body("invalid limit".into())
         }
-        "/metrics" => open_metrics::exporter(&start_time, &monitor),
-        path => {
-            #[cfg(feature = "rich_web")]
-            let resp = BUNDLE.get(path).map(|(mime, body)| {
-                Response::builder()
-                    .header("Content-Type", mime)
-                    .body(body.into())
-            });
-            #[cfg(not(feature = "rich_web"))]
-            let resp = None;
-            resp.unwrap_or_else(|| {
+    };
+    let tasks = DebugTasks {
+        tasks: monitor.task_stats(),
+        connections: monitor.alive_connections(),
+        oldest: monitor.oldest_tasks(limit),
+    };
+    let json = serde_json::to_string(&tasks).expect("fail to serialize tasks to json");
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(json.into())
+}
+
+/// Source addresses refused the most by `--max-conn-per-ip` or
+/// `src ip ... limit N`.
+#[derive(Debug, Serialize)]
+struct DebugOffenders {
+    refused: usize,
+    offenders: Vec<Offender>,
+}
+
+fn debug_offenders_response<T>(req: &Request<T>, monitor: &Monitor) -> BytesResult {
+    let limit = match req.query_param("limit").map(|n| n.parse()) {
+        None => 100,
+        Some(Ok(limit)) => limit,
+        Some(Err(_)) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .header("Content-Type", "text/plain")
+                .body("invalid limit".into())
+        }
+    };
+    let offenders = DebugOffenders {
+        refused: monitor.source_stats().refused,
+        offenders: monitor.offenders(limit),
+    };
+    let json = serde_json::to_string(&offenders).expect("fail to serialize offenders to json");
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(json.into())
+}
+
+fn accounting_response<T>(req: &Request<T>) -> BytesResult {
+    if !ACCOUNTING.is_enabled() {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .header("Content-Type", "text/plain")
+            .body("accounting not enabled".into());
+    }
+    // All days kept by default
+    let days = match req.query_param("days").map(|d| d.parse()) {
+        None => usize::MAX,
+        Some(Ok(days)) => days,
+        Some(Err(_)) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .header("Content-Type", "text/plain")
+                .body("invalid days".into())
+        }
+    };
+    let json = serde_json::to_string(&ACCOUNTING.recent(days))
+        .expect("fail to serialize accounting to json");
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(json.into())
+}
+
+fn top_destinations_response<T>(req: &Request<T>) -> BytesResult {
+    if !DESTINATIONS.is_enabled() {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .header("Content-Type", "text/plain")
+            .body("destination tracking not enabled".into());
+    }
+    let n = match req.query_param("n").map(|n| n.parse()) {
+        None => 20,
+        Some(Ok(n)) => n,
+        Some(Err(_)) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .header("Content-Type", "text/plain")
+                .body("invalid n".into())
+        }
+    };
+    let json = serde_json::to_string(&DESTINATIONS.top(n))
+        .expect("fail to serialize destinations to json");
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(json.into())
+}
+
+fn reset_destinations_response() -> BytesResult {
+    let removed = DESTINATIONS.reset();
+    info!("{} destination(s) reset via web console", removed);
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Default::default())
+}
+
+fn maintenance_json(status: StatusCode, value: &[Maintenance]) -> BytesResult {
+    let json = serde_json::to_string(value).expect("fail to serialize maintenance to json");
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(json.into())
+}
+
+/// Install a `Policy::add_maintenance()` overlay.
+fn add_maintenance_response(req: &Request<Bytes>, policy: &RwLock<Policy>) -> BytesResult {
+    let text = |status, text: &'static str| {
+        Response::builder()
+            .status(status)
+            .header("Content-Type", "text/plain")
+            .body(text.into())
+    };
+    // Not sendable by cross-site forms without a CORS preflight
+    let content_type = req.headers().get("Content-Type");
+    if !content_type.is_some_and(|t| t.as_bytes().starts_with(b"application/json")) {
+        return text(StatusCode::UNSUPPORTED_MEDIA_TYPE, "JSON is expected");
+    }
+    let maintenance: Maintenance = match serde_json::from_slice(req.body()) {
+        Ok(maintenance) => maintenance,
+        Err(_) => return text(StatusCode::BAD_REQUEST, "invalid maintenance"),
+    };
+    if maintenance.caps.is_empty() || !maintenance.is_active(SystemTime::now()) {
+        return text(StatusCode::BAD_REQUEST, "no caps or already expired");
+    }
+    info!(
+        caps = ?maintenance.caps,
+        action = ?maintenance.action,
+        "maintenance added via web console"
+    );
+    policy.write().add_maintenance(maintenance.clone());
+    maintenance_json(StatusCode::CREATED, &[maintenance])
+}
+
+fn clear_maintenance_response(policy: &RwLock<Policy>) -> BytesResult {
+    let removed = policy.write().clear_maintenance();
+    info!("{} maintenance removed via web console", removed.len());
+    maintenance_json(StatusCode::OK, &removed)
+}
+
+fn not_found() -> BytesResult {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .header("Content-Type", "text/plain")
+        .body("page not found".into())
+}
+
+/// 200 if any server is healthy (has a score), 503 otherwise, unless
+/// `WebServer::with_healthz_always_ok()`.
+fn healthz_response(ctx: &WebContext) -> BytesResult {
+    let servers = ctx.monitor.servers();
+    let healthy = servers.iter().filter(|s| s.score().is_some()).count();
+    let status = if healthy > 0 || ctx.options.healthz_always_ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let state = if healthy > 0 { "ok" } else { "down" };
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "text/plain")
+        .header("Cache-Control", "no-store")
+        .body(format!("{}: {}/{} servers healthy\n", state, healthy, servers.len()).into())
+}
+
+/// All servers, or those of the tenant with `?tenant=NAME`.
+fn status_response<T>(req: &Request<T>, ctx: &WebContext) -> BytesResult {
+    let json = match req.query_param("tenant") {
+        None => {
+            return unfiltered(req, ctx, || {
+                let json = serde_json::to_string(&Status::from(&ctx.start_time, &ctx.monitor))
+                    .expect("fail to serialize servers to json");
                 Response::builder()
+                    .header("Content-Type", "application/json")
+                    .body(json.into())
+            })
+        }
+        Some(tenant) => match ctx.options.tenants.iter().find(|(t, _)| *t == tenant) {
+            Some((tenant, token)) => {
+                let admin = ctx.options.status_token.as_deref();
+                if !req.has_bearer_token(token) && !admin.is_some_and(|t| req.has_bearer_token(t)) {
+                    return unauthorized();
+                }
+                let status = TenantStatus::from(&ctx.start_time, &ctx.monitor, tenant.clone());
+                serde_json::to_string(&status).expect("fail to serialize servers to json")
+            }
+            None => {
+                return Response::builder()
                     .status(StatusCode::NOT_FOUND)
                     .header("Content-Type", "text/plain")
-                    .body("page not found".into())
+                    .body("tenant not found".into())
+            }
+        },
+    };
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(json.into())
+}
+
+static ROUTES: Lazy<Router> = Lazy::new(|| {
+    use Method as M;
+    Router::default()
+        .route(M::GET, "/", |req, ctx, _| home_page(req, ctx))
+        .route(M::GET, "/index.html", |req, ctx, _| home_page(req, ctx))
+        .route(M::GET, "/plain", |req, ctx, _| {
+            unfiltered(req, ctx, || {
+                plaintext_status_response(&ctx.start_time, &ctx.monitor)
+            })
+        })
+        .route(M::GET, "/version", |_, _, _| {
+            Response::builder()
+                .header("Content-Type", "text/plain")
+                .body(env!("CARGO_PKG_VERSION").into())
+        })
+        .route(M::GET, "/status", |req, ctx, _| status_response(req, ctx))
+        .route(M::GET, "/healthz", |_, ctx, _| healthz_response(ctx))
+        .route(M::GET, "/status/*", |req, ctx, tag| {
+            unfiltered(req, ctx, || server_status_response(tag, &ctx.monitor))
+        })
+        .route(M::GET, "/metrics", |req, ctx, _| {
+            unfiltered(req, ctx, || {
+                open_metrics::exporter(&ctx.start_time, &ctx.monitor)
             })
+        })
+        .route(M::GET, "/config", |req, ctx, _| {
+            unfiltered(req, ctx, || config_response(&ctx.monitor))
+        })
+        .route(M::GET, "/policy/stats", |req, ctx, _| {
+            unfiltered(req, ctx, || policy_stats_response(&ctx.policy))
+        })
+        .route(M::GET, "/accounting", |req, ctx, _| {
+            unfiltered(req, ctx, || accounting_response(req))
+        })
+        .route(M::GET, "/top", |req, ctx, _| {
+            unfiltered(req, ctx, || top_destinations_response(req))
+        })
+        .route(M::DELETE, "/top", |req, ctx, _| {
+            admin(req, ctx, reset_destinations_response)
+        })
+        .route(M::GET, "/whoami", |req, ctx, _| {
+            whoami_response(req, &ctx.monitor, ctx.options.whoami_token.as_deref())
+        })
+        .route(M::GET, "/debug/log", |req, ctx, _| {
+            debug_log_response(req, ctx.options.debug_log_token.as_deref())
+        })
+        .route(M::GET, "/debug/tasks", |req, ctx, _| {
+            let token = ctx.options.status_token.as_deref();
+            authorize(req, token).unwrap_or_else(|| debug_tasks_response(req, &ctx.monitor))
+        })
+        .route(M::GET, "/debug/offenders", |req, ctx, _| {
+            unfiltered(req, ctx, || debug_offenders_response(req, &ctx.monitor))
+        })
+        .route(M::GET, "/maintenance", |req, ctx, _| {
+            unfiltered(req, ctx, || {
+                maintenance_json(StatusCode::OK, &ctx.policy.read().maintenance())
+            })
+        })
+        .route(M::POST, "/maintenance", |req, ctx, _| {
+            admin(req, ctx, || add_maintenance_response(req, &ctx.policy))
+        })
+        .route(M::DELETE, "/maintenance", |req, ctx, _| {
+            admin(req, ctx, || clear_maintenance_response(&ctx.policy))
+        })
+        .route(M::GET, "*", |_, _, path| {
+            bundle_response(path).unwrap_or_else(not_found)
+        })
+});
+
+fn response(req: &Request<Bytes>, ctx: &WebContext) -> BytesResult {
+    let prefix = ctx.options.path_prefix.as_deref();
+    match strip_path_prefix(req.uri().path(), prefix) {
+        Some("") => {
+            // Relative URLs on the page would miss the prefix w/o the slash
+            Response::builder()
+                .status(StatusCode::PERMANENT_REDIRECT)
+                .header("Location", format!("{}/", prefix.unwrap_or_default()))
+                .body(Default::default())
         }
+        Some(path) => ROUTES.dispatch(req, ctx, path),
+        None => not_found(),
     }
 }
 
@@ -240,131 +893,289 @@ enum Listener {
 }
 
 trait Accept<IO> {
-    async fn accept(&self) -> io::Result<IO>;
+    /// Return the stream with the IP address of the peer, if any.
+    async fn accept(&self) -> io::Result<(IO, Option<IpAddr>)>;
 }
 
 impl Accept<TcpStream> for TcpListener {
-    async fn accept(&self) -> io::Result<TcpStream> {
-        let (client, _) = self.accept().await?;
-        Ok(client)
+    async fn accept(&self) -> io::Result<(TcpStream, Option<IpAddr>)> {
+        let (client, addr) = self.accept().await?;
+        Ok((client, Some(addr.ip())))
     }
 }
 
 #[cfg(unix)]
 impl Accept<UnixStream> for UnixListener {
-    async fn accept(&self) -> io::Result<UnixStream> {
+    async fn accept(&self) -> io::Result<(UnixStream, Option<IpAddr>)> {
         let (client, _) = self.accept().await?;
-        Ok(client)
+        Ok((client, None))
     }
 }
 
+#[derive(Debug, Clone, Default)]
+struct WebOptions {
+    whoami_token: Option<SharedStr>,
+    debug_log_token: Option<SharedStr>,
+    /// Without trailing slash, e.g. `/moproxy`.
+    path_prefix: Option<SharedStr>,
+    /// Names allowed on `/status?tenant=NAME`, with their tokens.
+    tenants: Vec<(SharedStr, SharedStr)>,
+    /// Required for views of all tenants if set.
+    status_token: Option<SharedStr>,
+    /// Required for pages changing things, which are disabled without it.
+    admin_token: Option<SharedStr>,
+    /// `/healthz` never fails.
+    healthz_always_ok: bool,
+}
+
+/// Shared by all request handlers.
+struct WebContext {
+    start_time: Instant,
+    monitor: Monitor,
+    policy: Arc<RwLock<Policy>>,
+    options: WebOptions,
+}
+
 #[derive(Clone)]
 pub struct WebServer {
     monitor: Monitor,
-    bind_addr: ListenAddr,
+    policy: Arc<RwLock<Policy>>,
+    bind_addrs: Vec<ListenAddr>,
+    options: WebOptions,
 }
 
 pub struct WebServerListener {
-    monitor: Monitor,
-    listener: Listener,
+    service: WebService,
+    listeners: Vec<Listener>,
 }
 
-impl WebServer {
-    pub fn new(monitor: Monitor, bind_addr: SharedStr) -> anyhow::Result<Self> {
-        let bind_addr = if !bind_addr.starts_with('/') || cfg!(not(unix)) {
+/// The web console without listener, serving connections accepted by
+/// others, e.g. proxy clients asking for `--stats-dest`.
+#[derive(Clone)]
+pub struct WebService {
+    ctx: Arc<WebContext>,
+}
+
+impl ListenAddr {
+    fn parse(bind_addr: SharedStr) -> anyhow::Result<Self> {
+        if !bind_addr.starts_with('/') || cfg!(not(unix)) {
             // TCP socket
             let addr = str::parse(bind_addr.as_str())
                 .context("Not valid TCP socket address for web server")?;
-            ListenAddr::TcpSocket(addr)
+            Ok(ListenAddr::TcpSocket(addr))
         } else {
             #[cfg(unix)]
             {
-                ListenAddr::UnixPath(bind_addr)
+                Ok(ListenAddr::UnixPath(bind_addr))
             }
             #[cfg(not(unix))]
             anyhow::bail!("No UNIX domain socket support on this system")
-        };
-        Ok(Self { monitor, bind_addr })
+        }
     }
 
-    pub async fn listen(&self) -> anyhow::Result<WebServerListener> {
-        let listener = match &self.bind_addr {
+    async fn bind(&self) -> anyhow::Result<Listener> {
+        match self {
             ListenAddr::TcpSocket(addr) => {
                 info!("Web console listen on tcp:{}", addr);
                 let listener = TcpListener::bind(&addr)
                     .await
                     .context("fail to bind web server")?;
-                Listener::Tcp(listener)
+                Ok(Listener::Tcp(listener))
             }
             #[cfg(unix)]
             ListenAddr::UnixPath(addr) => {
                 info!("Web console listen on unix:{}", addr);
                 let file = AutoRemoveFile(addr.clone());
                 let listener = UnixListener::bind(&file).context("fail to bind web server")?;
-                Listener::Unix { listener, file }
+                Ok(Listener::Unix { listener, file })
             }
-        };
+        }
+    }
+}
+
+impl WebServer {
+    /// Listen on all of `bind_addrs`, which are TCP socket addresses or
+    /// paths of UNIX domain sockets. May be empty if only `service()` is
+    /// used.
+    pub fn new(
+        monitor: Monitor,
+        policy: Arc<RwLock<Policy>>,
+        bind_addrs: Vec<SharedStr>,
+    ) -> anyhow::Result<Self> {
+        let bind_addrs = bind_addrs
+            .into_iter()
+            .map(ListenAddr::parse)
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            monitor,
+            policy,
+            bind_addrs,
+            options: Default::default(),
+        })
+    }
+
+    /// Enable `/whoami` for requests with the bearer token, if given.
+    /// Connections must be registered via `Monitor::register_connection()`.
+    pub fn with_whoami_token(mut self, token: Option<SharedStr>) -> Self {
+        self.options.whoami_token = token;
+        self
+    }
+
+    /// Enable `/debug/log` for requests with the bearer token, if given.
+    /// Events are captured by `log_tail::LogTailLayer` into `LOG_TAIL`.
+    pub fn with_debug_log_token(mut self, token: Option<SharedStr>) -> Self {
+        self.options.debug_log_token = token;
+        self
+    }
+
+    /// Enable `/status?tenant=NAME` for each of `tenants` in `(NAME,
+    /// TOKEN)`, showing only servers with the capability `NAME` to requests
+    /// with the bearer token `TOKEN` or of `with_status_token()`.
+    pub fn with_tenants(mut self, tenants: Vec<(SharedStr, SharedStr)>) -> Self {
+        self.options.tenants = tenants;
+        self
+    }
+
+    /// Require the bearer token for pages showing things of all tenants,
+    /// i.e. all but the home page, `/version`, `/healthz`, and those with
+    /// their own tokens.
+    pub fn with_status_token(mut self, token: Option<SharedStr>) -> Self {
+        self.options.status_token = token;
+        self
+    }
+
+    /// Enable pages changing things, i.e. `POST` & `DELETE /maintenance`
+    /// and `DELETE /top`, for requests with the bearer token, if given.
+    pub fn with_admin_token(mut self, token: Option<SharedStr>) -> Self {
+        self.options.admin_token = token;
+        self
+    }
+
+    /// Always respond 200 on `/healthz`, for checkers that only care
+    /// whether the process is up. The body still tells the state.
+    pub fn with_healthz_always_ok(mut self, always_ok: bool) -> Self {
+        self.options.healthz_always_ok = always_ok;
+        self
+    }
+
+    /// Serve all pages under `prefix` (e.g. `/moproxy`) instead of the root,
+    /// for mounting behind a reverse proxy. Others are not found.
+    pub fn with_path_prefix(mut self, prefix: Option<&str>) -> Self {
+        self.options.path_prefix = prefix
+            .map(|prefix| prefix.trim_matches('/'))
+            .filter(|prefix| !prefix.is_empty())
+            .map(|prefix| format!("/{}", prefix).into());
+        self
+    }
+
+    pub async fn listen(&self) -> anyhow::Result<WebServerListener> {
+        let mut listeners = Vec::with_capacity(self.bind_addrs.len());
+        for addr in &self.bind_addrs {
+            listeners.push(addr.bind().await?);
+        }
         Ok(WebServerListener {
-            monitor: self.monitor.clone(),
-            listener,
+            service: self.service(),
+            listeners,
         })
     }
+
+    /// Serve connections to the web console accepted by the caller.
+    pub fn service(&self) -> WebService {
+        WebService {
+            ctx: Arc::new(WebContext {
+                start_time: Instant::now(),
+                monitor: self.monitor.clone(),
+                policy: self.policy.clone(),
+                options: self.options.clone(),
+            }),
+        }
+    }
 }
 
 impl WebServerListener {
     pub fn run_background(self) {
-        match self.listener {
-            Listener::Tcp(tcp) => {
-                tokio::spawn(run_server(tcp, self.monitor));
+        for listener in self.listeners {
+            let service = self.service.clone();
+            match listener {
+                Listener::Tcp(tcp) => {
+                    tokio::spawn(run_server(tcp, service));
+                }
+                #[cfg(unix)]
+                Listener::Unix { listener, file } => {
+                    tokio::spawn(async move {
+                        run_server(listener, service).await;
+                        drop(file);
+                    });
+                }
             }
-            #[cfg(unix)]
-            Listener::Unix { listener, file } => {
-                tokio::spawn(async move {
-                    run_server(listener, self.monitor).await;
-                    drop(file);
-                });
+        }
+    }
+}
+
+impl WebService {
+    /// Serve HTTP/1 requests on `stream` until it's closed. `peer` is the
+    /// address of the client if it's on IP, and `/whoami` tells nothing
+    /// without it.
+    pub async fn serve_connection<IO>(&self, stream: IO, peer: Option<IpAddr>)
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let _task = self.ctx.monitor.track_task(TaskKind::Web);
+        let ctx = self.ctx.clone();
+        let service = service_fn(move |req: Request<Incoming>| {
+            let ctx = ctx.clone();
+            async move {
+                let (parts, body) = req.into_parts();
+                let body = match Limited::new(body, MAX_REQUEST_BODY).collect().await {
+                    Ok(body) => body.to_bytes(),
+                    Err(_) => {
+                        return Response::builder()
+                            .status(StatusCode::PAYLOAD_TOO_LARGE)
+                            .header("Content-Type", "text/plain")
+                            .body("request body too large".into())
+                    }
+                };
+                let mut req = Request::from_parts(parts, body);
+                if let Some(peer) = peer {
+                    req.extensions_mut().insert(PeerIp(peer));
+                }
+                response(&req, &ctx)
             }
+        });
+        let conn = http1::Builder::new().serve_connection(TokioIo::new(stream), service);
+        if let Err(e) = conn.await {
+            warn!("web server error: {}", e);
         }
     }
 }
 
 #[instrument(name = "web_server", skip_all)]
-async fn run_server<L, IO>(listener: L, monitor: Monitor)
+async fn run_server<L, IO>(listener: L, service: WebService)
 where
     L: Accept<IO> + Unpin,
     IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
-    tokio::spawn(monitor.clone().monitor_throughput());
-    let start_time = Instant::now();
-
+    tokio::spawn(service.ctx.monitor.clone().monitor_throughput());
     loop {
-        let stream = match listener.accept().await {
+        let (stream, peer) = match listener.accept().await {
             Ok(stream) => stream,
             Err(err) => {
                 warn!("failed to accept: {}", err);
                 break;
             }
         };
-        let monitor = monitor.clone();
-        let service = service_fn(move |req: Request<Incoming>| {
-            let monitor = monitor.clone();
-            async move { response(&req, start_time, monitor) }
-        });
-
-        tokio::spawn(async move {
-            let conn = http1::Builder::new().serve_connection(TokioIo::new(stream), service);
-            if let Err(e) = conn.await {
-                warn!("web server error: {}", e);
-            }
-        });
+        let service = service.clone();
+        tokio::spawn(async move { service.serve_connection(stream, peer).await });
     }
 
     warn!("web server stopped");
 }
 
 /// File on this path will be removed on `drop()`.
+#[cfg(unix)]
 struct AutoRemoveFile(SharedStr);
 
+#[cfg(unix)]
 impl Drop for AutoRemoveFile {
     fn drop(&mut self) {
         if let Err(err) = fs::remove_file(self.0.as_str()) {
@@ -373,8 +1184,439 @@ impl Drop for AutoRemoveFile {
     }
 }
 
+#[cfg(unix)]
 impl AsRef<Path> for AutoRemoveFile {
     fn as_ref(&self) -> &Path {
         self.0.as_str().as_ref()
     }
 }
+
+#[tokio::test]
+async fn test_whoami_response() {
+    let monitor = Monitor::new(vec![], None);
+    let client: SocketAddr = "192.0.2.1:50000".parse().unwrap();
+    let _entry = monitor
+        .register_connection(client, "s1".into(), "example.com:443".into())
+        .unwrap();
+    let request_from = |uri, token: Option<&str>, peer: &str| {
+        let mut req = Request::builder()
+            .uri(uri)
+            .extension(PeerIp(peer.parse().unwrap()));
+        if let Some(token) = token {
+            req = req.header("Authorization", format!("Bearer {}", token));
+        }
+        req.body(()).unwrap()
+    };
+    let request = |uri, token| request_from(uri, token, "192.0.2.1");
+    let status = |req, token| whoami_response(&req, &monitor, token).unwrap().status();
+
+    let req = request("/whoami?src_port=50000", Some("secret"));
+    assert_eq!(StatusCode::NOT_FOUND, status(req, None));
+    let req = request("/whoami?src_port=50000", Some("wrong"));
+    assert_eq!(StatusCode::UNAUTHORIZED, status(req, Some("secret")));
+    let req = request("/whoami?src_port=x", Some("secret"));
+    assert_eq!(StatusCode::BAD_REQUEST, status(req, Some("secret")));
+    let req = request("/whoami?src_port=50001", Some("secret"));
+    assert_eq!(StatusCode::NOT_FOUND, status(req, Some("secret")));
+    // Connections of others are not told
+    let req = request_from("/whoami?src_port=50000", Some("secret"), "192.0.2.2");
+    assert_eq!(StatusCode::NOT_FOUND, status(req, Some("secret")));
+    let req = request_from("/whoami?src_port=50000", Some("secret"), "::ffff:192.0.2.1");
+    assert_eq!(StatusCode::OK, status(req, Some("secret")));
+
+    let req = request("/whoami?a=b&src_port=50000", Some("secret"));
+    let resp = whoami_response(&req, &monitor, Some("secret")).unwrap();
+    assert_eq!(StatusCode::OK, resp.status());
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!("s1", json[0]["server"]);
+    assert_eq!("example.com:443", json[0]["dest"]);
+    assert_eq!("192.0.2.1:50000", json[0]["client"]);
+}
+
+#[tokio::test]
+async fn test_path_prefix() {
+    let monitor = Monitor::new(vec![], None);
+    let policy = Arc::new(RwLock::new(Policy::default()));
+    let server = WebServer::new(monitor.clone(), policy.clone(), vec!["127.0.0.1:0".into()])
+        .unwrap()
+        .with_path_prefix(Some("moproxy/"));
+    assert_eq!(Some("/moproxy"), server.options.path_prefix.as_deref());
+    let get = |path: &str, options: &WebOptions| {
+        let req = Request::builder().uri(path).body(Bytes::new()).unwrap();
+        let ctx = WebContext {
+            start_time: Instant::now(),
+            monitor: monitor.clone(),
+            policy: policy.clone(),
+            options: options.clone(),
+        };
+        response(&req, &ctx).unwrap()
+    };
+
+    let options = &server.options;
+    let resp = get("/moproxy/version", options);
+    assert_eq!(StatusCode::OK, resp.status());
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(env!("CARGO_PKG_VERSION").as_bytes(), &body[..]);
+    assert_eq!(StatusCode::OK, get("/moproxy/metrics", options).status());
+    assert_eq!(StatusCode::NOT_FOUND, get("/version", options).status());
+    assert_eq!(
+        StatusCode::NOT_FOUND,
+        get("/moproxy2/version", options).status()
+    );
+    let resp = get("/moproxy", options);
+    assert_eq!(StatusCode::PERMANENT_REDIRECT, resp.status());
+    assert_eq!("/moproxy/", resp.headers()["Location"]);
+    let resp = get("/moproxy/", options);
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    assert!(std::str::from_utf8(&body)
+        .unwrap()
+        .contains(r#"<base href="/moproxy/">"#));
+
+    // Served on root w/o prefix
+    let options = &WebOptions::default();
+    assert_eq!(StatusCode::OK, get("/version", options).status());
+    assert_eq!(
+        StatusCode::NOT_FOUND,
+        get("/moproxy/version", options).status()
+    );
+}
+
+#[test]
+fn test_with_path_prefix() {
+    let html = r#"<head><script src="/app.js"></script></head><a href="//cdn/x">"#;
+    assert_eq!(
+        r#"<head><base href="/p/"><script src="/p/app.js"></script></head><a href="//cdn/x">"#,
+        with_path_prefix(html, "/p")
+    );
+}
+
+#[tokio::test]
+async fn test_maintenance_response() {
+    use crate::policy::{ActionType, RequestFeatures};
+
+    let policy = Arc::new(RwLock::new(
+        Policy::load("default require provider-x".as_bytes()).unwrap(),
+    ));
+    let mut ctx = WebContext {
+        start_time: Instant::now(),
+        monitor: Monitor::new(vec![], None),
+        policy: policy.clone(),
+        options: Default::default(),
+    };
+    let request_with = |ctx: &WebContext, method, content_type, body: &'static str, token| {
+        let req = Request::builder()
+            .method(method)
+            .uri("/maintenance")
+            .header("Content-Type", content_type)
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Bytes::from_static(body.as_bytes()))
+            .unwrap();
+        response(&req, ctx).unwrap()
+    };
+    let body = r#"{"caps": ["provider-x"], "action": "reject", "until": "2999-06-01T02:00:00Z"}"#;
+    // Read-only without the admin token
+    let resp = request_with(&ctx, Method::POST, "application/json", body, "secret");
+    assert_eq!(StatusCode::NOT_FOUND, resp.status());
+    ctx.options.admin_token = Some("secret".into());
+    let resp = request_with(&ctx, Method::POST, "application/json", body, "wrong");
+    assert_eq!(StatusCode::UNAUTHORIZED, resp.status());
+    let resp = request_with(&ctx, Method::DELETE, "", "", "wrong");
+    assert_eq!(StatusCode::UNAUTHORIZED, resp.status());
+
+    let request =
+        |method, content_type, body| request_with(&ctx, method, content_type, body, "secret");
+    let list = || async {
+        let resp = request(Method::GET, "", "");
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+    };
+
+    assert_eq!(
+        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        request(Method::POST, "text/plain", body).status()
+    );
+    let expired = r#"{"caps": ["a"], "action": "direct", "until": "2000-01-01T00:00:00Z"}"#;
+    assert_eq!(
+        StatusCode::BAD_REQUEST,
+        request(Method::POST, "application/json", expired).status()
+    );
+    assert_eq!(
+        StatusCode::BAD_REQUEST,
+        request(Method::POST, "application/json", "{}").status()
+    );
+    assert_eq!(serde_json::json!([]), list().await);
+
+    let resp = request(Method::POST, "application/json", body);
+    assert_eq!(StatusCode::CREATED, resp.status());
+    let rejected = policy.read().matches(&RequestFeatures::<&str>::default());
+    assert_eq!(ActionType::Reject, rejected.action);
+    let json = list().await;
+    assert_eq!("reject", json[0]["action"]);
+    assert_eq!("2999-06-01T02:00:00Z", json[0]["until"]);
+
+    assert_eq!(StatusCode::OK, request(Method::DELETE, "", "").status());
+    assert_eq!(serde_json::json!([]), list().await);
+    let resp = request(Method::PUT, "", "");
+    assert_eq!(StatusCode::METHOD_NOT_ALLOWED, resp.status());
+    assert_eq!("GET, HEAD, POST, DELETE", resp.headers()["Allow"]);
+}
+
+#[tokio::test]
+async fn test_routes() {
+    let monitor = Monitor::new(vec![], None);
+    let policy = Arc::new(RwLock::new(Policy::default()));
+    let ctx = WebContext {
+        start_time: Instant::now(),
+        monitor,
+        policy,
+        options: WebOptions {
+            whoami_token: Some("secret".into()),
+            debug_log_token: None,
+            path_prefix: None,
+            tenants: vec![],
+            status_token: None,
+            admin_token: None,
+            healthz_always_ok: false,
+        },
+    };
+    let request = |method, uri| {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("User-Agent", "curl/8.0")
+            .body(Bytes::new())
+            .unwrap();
+        response(&req, &ctx).unwrap()
+    };
+    let get = |uri| request(Method::GET, uri);
+    let content_type = |resp: &Response<_>| resp.headers()["Content-Type"].clone();
+
+    let resp = get("/");
+    assert_eq!(StatusCode::OK, resp.status());
+    assert_eq!("text/plain; charset=utf-8", content_type(&resp));
+    assert_eq!(StatusCode::OK, get("/index.html").status());
+    assert_eq!(StatusCode::OK, get("/plain").status());
+    assert_eq!(StatusCode::OK, get("/version").status());
+    let resp = get("/status");
+    assert_eq!(StatusCode::OK, resp.status());
+    assert_eq!("application/json", content_type(&resp));
+    assert_eq!(StatusCode::NOT_FOUND, get("/status/unknown").status());
+    let resp = get("/metrics");
+    assert_eq!(StatusCode::OK, resp.status());
+    assert_eq!(StatusCode::OK, get("/policy/stats").status());
+    assert_eq!(StatusCode::OK, get("/config").status());
+    assert_eq!(StatusCode::NOT_FOUND, get("/accounting").status());
+    assert_eq!(StatusCode::NOT_FOUND, get("/top").status());
+    let resp = request(Method::DELETE, "/top");
+    assert_eq!(StatusCode::NOT_FOUND, resp.status());
+    assert_eq!(StatusCode::UNAUTHORIZED, get("/whoami").status());
+    assert_eq!(StatusCode::OK, get("/maintenance").status());
+    // Destinations of clients are not shown without --status-token
+    assert_eq!(StatusCode::NOT_FOUND, get("/debug/tasks").status());
+    assert_eq!(StatusCode::NOT_FOUND, get("/debug/log").status());
+    let resp = get("/debug/offenders");
+    assert_eq!(StatusCode::OK, resp.status());
+    assert_eq!("application/json", content_type(&resp));
+    assert_eq!(
+        StatusCode::BAD_REQUEST,
+        get("/debug/offenders?limit=-1").status()
+    );
+    assert_eq!(StatusCode::NOT_FOUND, get("/no-such-page").status());
+
+    // HEAD as GET w/o body
+    let body = get("/version")
+        .into_body()
+        .collect()
+        .await
+        .unwrap()
+        .to_bytes();
+    let resp = request(Method::HEAD, "/version");
+    assert_eq!(StatusCode::OK, resp.status());
+    assert_eq!("text/plain", content_type(&resp));
+    assert_eq!(
+        body.len().to_string(),
+        resp.headers()["Content-Length"].to_str().unwrap()
+    );
+    assert!(resp
+        .into_body()
+        .collect()
+        .await
+        .unwrap()
+        .to_bytes()
+        .is_empty());
+    assert_eq!(StatusCode::OK, request(Method::HEAD, "/").status());
+    assert_eq!(
+        StatusCode::UNAUTHORIZED,
+        request(Method::HEAD, "/whoami").status()
+    );
+
+    let resp = request(Method::POST, "/status");
+    assert_eq!(StatusCode::METHOD_NOT_ALLOWED, resp.status());
+    assert_eq!("GET, HEAD", resp.headers()["Allow"]);
+}
+
+#[test]
+fn test_debug_log_response() {
+    use log_tail::LogTailLayer;
+    use tracing_subscriber::prelude::*;
+
+    let subscriber = tracing_subscriber::registry().with(LogTailLayer::new(&LOG_TAIL));
+    tracing::subscriber::with_default(subscriber, || {
+        LOG_TAIL.enable(100);
+        tracing::debug!("noisy");
+        tracing::warn!(peer = "192.0.2.1", "something wrong");
+    });
+    let get = |uri: &str, token: Option<&str>| {
+        let mut req = Request::builder().uri(uri);
+        if let Some(token) = token {
+            req = req.header("Authorization", format!("Bearer {}", token));
+        }
+        debug_log_response(&req.body(()).unwrap(), Some("secret")).unwrap()
+    };
+    let body = |resp: Response<Full<Bytes>>| {
+        let body = futures_util::FutureExt::now_or_never(resp.into_body().collect());
+        String::from_utf8(body.unwrap().unwrap().to_bytes().to_vec()).unwrap()
+    };
+    assert_eq!(StatusCode::UNAUTHORIZED, get("/debug/log", None).status());
+    assert_eq!(
+        StatusCode::UNAUTHORIZED,
+        get("/debug/log", Some("wrong")).status()
+    );
+
+    let resp = get("/debug/log?level=info", Some("secret"));
+    assert_eq!(StatusCode::OK, resp.status());
+    let seq: u64 = resp.headers()["X-Log-Seq"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    let text = body(resp);
+    assert!(text.contains(" WARN ") && text.contains("something wrong peer=\"192.0.2.1\""));
+    assert!(!text.contains("noisy"));
+    let text = body(get("/debug/log?level=debug&lines=200", Some("secret")));
+    assert!(text.contains("noisy"));
+    let resp = get(&format!("/debug/log?after={}", seq), Some("secret"));
+    assert_eq!(seq.to_string(), resp.headers()["X-Log-Seq"]);
+    assert!(body(resp).is_empty());
+    let resp = get("/debug/log?level=loud", Some("secret"));
+    assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+}
+
+#[tokio::test]
+async fn test_tenant_status() {
+    let server = |port: u16, tag: &str, cap: &str| {
+        let server = ProxyServer::test_socks5(([127, 0, 0, 1], port).into(), Some(tag));
+        server.update_config(|c| c.capabilities = CapSet::new(std::iter::once(cap)));
+        Arc::new(server)
+    };
+    let servers = vec![server(1, "a", "team-a"), server(2, "b", "team-b")];
+    let ctx = WebContext {
+        start_time: Instant::now(),
+        monitor: Monitor::new(servers, None),
+        policy: Default::default(),
+        options: WebOptions {
+            tenants: vec![
+                ("team-a".into(), "token-a".into()),
+                ("team-b".into(), "token-b".into()),
+            ],
+            status_token: Some("secret".into()),
+            ..Default::default()
+        },
+    };
+    let get = |uri: &str, token: Option<&str>| {
+        let mut req = Request::builder().uri(uri);
+        if let Some(token) = token {
+            req = req.header("Authorization", format!("Bearer {}", token));
+        }
+        response(&req.body(Bytes::new()).unwrap(), &ctx).unwrap()
+    };
+    let json = |resp: Response<Full<Bytes>>| {
+        let body = futures_util::FutureExt::now_or_never(resp.into_body().collect());
+        let body = body.unwrap().unwrap().to_bytes();
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+    };
+
+    // Each tenant with its own token
+    for token in [None, Some("token-b"), Some("wrong")] {
+        let resp = get("/status?tenant=team-a", token);
+        assert_eq!(StatusCode::UNAUTHORIZED, resp.status());
+    }
+    let resp = get("/status?tenant=team-a", Some("secret"));
+    assert_eq!(StatusCode::OK, resp.status());
+    let resp = get("/status?tenant=team-a", Some("token-a"));
+    assert_eq!(StatusCode::OK, resp.status());
+    let status = json(resp);
+    assert_eq!("team-a", status["tenant"]);
+    let servers = status["servers"].as_array().unwrap();
+    assert_eq!(1, servers.len());
+    assert_eq!("a", servers[0]["server"]["tag"]);
+    assert!(status.get("clients").is_none());
+    assert_eq!(
+        StatusCode::NOT_FOUND,
+        get("/status?tenant=jp", Some("token-a")).status()
+    );
+
+    // Views of all tenants
+    for uri in [
+        "/status",
+        "/status/a",
+        "/plain",
+        "/metrics",
+        "/top",
+        "/debug/tasks",
+    ] {
+        assert_eq!(StatusCode::UNAUTHORIZED, get(uri, None).status(), "{}", uri);
+        assert_eq!(StatusCode::UNAUTHORIZED, get(uri, Some("wrong")).status());
+        assert_eq!(StatusCode::UNAUTHORIZED, get(uri, Some("token-a")).status());
+    }
+    let resp = get("/status", Some("secret"));
+    assert_eq!(StatusCode::OK, resp.status());
+    assert_eq!(2, json(resp)["servers"].as_array().unwrap().len());
+    assert_eq!(StatusCode::OK, get("/debug/tasks", Some("secret")).status());
+    assert_eq!(
+        StatusCode::BAD_REQUEST,
+        get("/debug/tasks?limit=x", Some("secret")).status()
+    );
+    assert_eq!(StatusCode::OK, get("/version", None).status());
+}
+
+#[test]
+fn test_healthz() {
+    let server = Arc::new(ProxyServer::test_socks5(([127, 0, 0, 1], 1).into(), None));
+    let mut ctx = WebContext {
+        start_time: Instant::now(),
+        monitor: Monitor::new(vec![server.clone()], None),
+        policy: Default::default(),
+        options: Default::default(),
+    };
+    let get = |ctx: &WebContext| {
+        let req = Request::builder().uri("/healthz").body(Bytes::new());
+        let resp = response(&req.unwrap(), ctx).unwrap();
+        let status = resp.status();
+        let body = futures_util::FutureExt::now_or_never(resp.into_body().collect());
+        let body = body.unwrap().unwrap().to_bytes();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    };
+
+    server.update_delay(None);
+    assert_eq!(
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "down: 0/1 servers healthy\n".into()
+        ),
+        get(&ctx)
+    );
+    ctx.options.healthz_always_ok = true;
+    assert_eq!(StatusCode::OK, get(&ctx).0);
+    assert!(get(&ctx).1.starts_with("down: "));
+
+    ctx.options.healthz_always_ok = false;
+    // Healthy once scored, even if still recovering
+    server.update_delay(Some(Duration::from_millis(10)));
+    assert!(server.status_snapshot().recovering);
+    assert_eq!(
+        (StatusCode::OK, "ok: 1/1 servers healthy\n".into()),
+        get(&ctx)
+    );
+}
@@ -5,7 +5,10 @@ use std::{
 };
 
 use super::{BytesResult, ServerStatus, Status};
-use crate::{monitor::Monitor, proxy::Delay};
+use crate::{
+    monitor::{HistogramCounts, Monitor},
+    proxy::{Delay, ProxyProto, ProxyServer},
+};
 
 const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
 
@@ -14,6 +17,65 @@ fn new_metric(buf: &mut String, name: &str, metric_type: &str, help: &str) {
     writeln!(buf, "# TYPE moproxy_{} {}", name, metric_type).unwrap();
 }
 
+/// Histogram of samples in microseconds, exported in seconds.
+fn histogram(buf: &mut String, name: &str, help: &str, counts: &HistogramCounts) {
+    let secs = |us| us as f64 / 1e6;
+    new_metric(buf, name, "histogram", help);
+    for (bound, n) in counts.cumulative() {
+        match bound {
+            Some(bound) => writeln!(
+                buf,
+                "moproxy_{}_bucket{{le=\"{:?}\"}} {}",
+                name,
+                secs(bound),
+                n
+            ),
+            None => writeln!(buf, "moproxy_{}_bucket{{le=\"+Inf\"}} {}", name, n),
+        }
+        .unwrap();
+    }
+    writeln!(buf, "moproxy_{}_sum {}", name, secs(counts.sum())).unwrap();
+    writeln!(buf, "moproxy_{}_count {}", name, counts.count()).unwrap();
+}
+
+/// Escape backslash, double-quote and line feed in label values.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn proto_label(proto: &ProxyProto) -> &'static str {
+    match proto {
+        ProxyProto::Socks5 { .. } => "socks5",
+        ProxyProto::Socks4 { .. } => "socks4",
+        ProxyProto::Http { .. } => "http",
+        #[cfg(feature = "shadowsocks")]
+        ProxyProto::Shadowsocks { .. } => "shadowsocks",
+        ProxyProto::Direct => "direct",
+    }
+}
+
+/// Labels of per-server series. Capabilities are taken from the server
+/// list only (sorted, comma-joined), so that label sets keep stable.
+fn server_labels(server: &ProxyServer) -> String {
+    let caps = server.capabilities();
+    let caps: Vec<_> = caps.iter().map(|c| c.as_str()).collect();
+    format!(
+        "server=\"{}\",proto=\"{}\",caps=\"{}\"",
+        escape(&server.tag()),
+        proto_label(&server.proto),
+        escape(&caps.join(","))
+    )
+}
+
 fn each_server<F, D>(buf: &mut String, name: &str, servers: &[ServerStatus], metric: F)
 where
     F: Fn(&ServerStatus) -> Option<D>,
@@ -23,8 +85,10 @@ where
         if let Some(value) = metric(s) {
             writeln!(
                 buf,
-                "moproxy_{}{{server=\"{}\"}} {}",
-                name, s.server.tag, value
+                "moproxy_{}{{{}}} {}",
+                name,
+                server_labels(&s.server),
+                value
             )
             .unwrap();
         }
@@ -32,6 +96,12 @@ where
 }
 
 pub fn exporter(start_time: &Instant, monitor: &Monitor) -> BytesResult {
+    Response::builder()
+        .header("Content-Type", CONTENT_TYPE)
+        .body(render(start_time, monitor).into())
+}
+
+fn render(start_time: &Instant, monitor: &Monitor) -> String {
     let status = Status::from(start_time, monitor);
     let mut buf = String::new();
 
@@ -41,6 +111,12 @@ pub fn exporter(start_time: &Instant, monitor: &Monitor) -> BytesResult {
             each_server(&mut buf, $name, &status.servers, $func);
         };
     }
+    macro_rules! server_counter {
+        ($name:expr, $help:expr, $func:expr) => {
+            new_metric(&mut buf, $name, "counter", $help);
+            each_server(&mut buf, concat!($name, "_total"), &status.servers, $func);
+        };
+    }
 
     server_gauge!(
         "proxy_server_bytes_tx_total",
@@ -52,6 +128,16 @@ pub fn exporter(start_time: &Instant, monitor: &Monitor) -> BytesResult {
         "Current total of incoming bytes",
         |s| Some(s.server.traffic().rx_bytes)
     );
+    server_gauge!(
+        "proxy_server_tx_bps",
+        "Outgoing bits per second in the last sampling interval",
+        |s| s.throughput.map(|tp| tp.tx_bps)
+    );
+    server_gauge!(
+        "proxy_server_rx_bps",
+        "Incoming bits per second in the last sampling interval",
+        |s| s.throughput.map(|tp| tp.rx_bps)
+    );
     server_gauge!(
         "proxy_server_connections_alive",
         "Current number of alive connections",
@@ -67,6 +153,16 @@ pub fn exporter(start_time: &Instant, monitor: &Monitor) -> BytesResult {
         "Current total number of connections",
         |s| Some(s.server.status_snapshot().conn_total)
     );
+    server_gauge!(
+        "proxy_server_connections_bulk",
+        "Current number of alive connections counted as bulk",
+        |s| Some(s.server.status_snapshot().bulk_alive)
+    );
+    server_counter!(
+        "proxy_server_bulk_connections",
+        "Total number of connections counted as bulk",
+        |s| Some(s.server.status_snapshot().bulk_total)
+    );
     server_gauge!(
         "proxy_server_dns_delay_seconds",
         "Total seconds for the last DNS query test",
@@ -75,14 +171,734 @@ pub fn exporter(start_time: &Instant, monitor: &Monitor) -> BytesResult {
             _ => None,
         }
     );
+    server_gauge!(
+        "proxy_server_probe_tcp_connect_seconds",
+        "Seconds taken to establish TCP connection in the last probe",
+        |s| s
+            .server
+            .status_snapshot()
+            .probe_timings
+            .map(|t| t.tcp_connect.as_secs_f32())
+    );
+    server_gauge!(
+        "proxy_server_probe_handshake_seconds",
+        "Seconds taken by the proxy handshake in the last probe",
+        |s| s
+            .server
+            .status_snapshot()
+            .probe_timings
+            .map(|t| t.handshake.as_secs_f32())
+    );
+    server_gauge!(
+        "proxy_server_probe_query_seconds",
+        "Seconds waiting for the test query response in the last probe",
+        |s| s
+            .server
+            .status_snapshot()
+            .probe_timings
+            .map(|t| t.probe_query.as_secs_f32())
+    );
+    server_gauge!(
+        "proxy_server_ttfb_mean_seconds",
+        "Mean time-to-first-byte of recent connections",
+        |s| s
+            .server
+            .status_snapshot()
+            .ttfb
+            .mean()
+            .map(|d| d.as_secs_f32())
+    );
+    server_gauge!(
+        "proxy_server_ttfb_p95_seconds",
+        "95th percentile time-to-first-byte of recent connections, rounded up to bucket bounds",
+        |s| s
+            .server
+            .status_snapshot()
+            .ttfb
+            .p95()
+            .map(|d| d.as_secs_f32())
+    );
+    server_gauge!(
+        "proxy_server_ttfb_samples",
+        "Number of recent connections in the time-to-first-byte statistic",
+        |s| Some(s.server.status_snapshot().ttfb.count())
+    );
+    server_gauge!(
+        "proxy_server_race_win_ratio",
+        "Ratio of recent races won when connecting along with other servers",
+        |s| s.server.status_snapshot().race.win_ratio()
+    );
+    server_gauge!(
+        "proxy_server_race_lost_handshake_mean_seconds",
+        "Mean handshake time of recent races lost",
+        |s| s
+            .server
+            .status_snapshot()
+            .race
+            .lost_handshake_mean()
+            .map(|d| d.as_secs_f32())
+    );
+    server_gauge!(
+        "proxy_server_probe_age_seconds",
+        "Time elapsed since the last probe",
+        |s| s
+            .server
+            .last_probe_at()
+            .map(|at| at.elapsed().as_secs_f32())
+    );
     server_gauge!(
         "proxy_server_score",
         "Score of server based on the last DNS query test",
         |s| s.server.status_snapshot().score
     );
+    server_gauge!(
+        "proxy_server_excluded_by_score",
+        "1 if excluded from candidates due to score above --score-max",
+        |s| Some(s.server.status_snapshot().excluded_by_score as u8)
+    );
+
+    new_metric(
+        &mut buf,
+        "proxy_server_recent_error_ratio",
+        "gauge",
+        "Ratio of errors in the last WINDOW connections",
+    );
+    for s in &status.servers {
+        for (window, ratio) in &s.recent_error_ratio {
+            writeln!(
+                buf,
+                "moproxy_proxy_server_recent_error_ratio{{{},window=\"{}\"}} {}",
+                server_labels(&s.server),
+                window,
+                ratio
+            )
+            .unwrap();
+        }
+    }
+
+    new_metric(
+        &mut buf,
+        "proxy_server_handshake_errors",
+        "counter",
+        "Number of failed handshakes with server by kind of error",
+    );
+    for s in &status.servers {
+        for (kind, value) in s.server.status_snapshot().handshake_errors.iter() {
+            writeln!(
+                buf,
+                "moproxy_proxy_server_handshake_errors_total{{{},kind=\"{}\"}} {}",
+                server_labels(&s.server),
+                kind,
+                value
+            )
+            .unwrap();
+        }
+    }
+
+    new_metric(
+        &mut buf,
+        "proxy_server_probe_failures",
+        "counter",
+        "Number of failed probes by phase they failed in",
+    );
+    for s in &status.servers {
+        let failures = s.server.status_snapshot().connect_failures;
+        for (phase, value) in failures.iter() {
+            writeln!(
+                buf,
+                "moproxy_proxy_server_probe_failures_total{{{},phase=\"{}\"}} {}",
+                server_labels(&s.server),
+                phase,
+                value
+            )
+            .unwrap();
+        }
+    }
+
+    new_metric(
+        &mut buf,
+        "proxy_server_probe_timeouts",
+        "counter",
+        "Number of probes timed out, in any phase",
+    );
+    for s in &status.servers {
+        writeln!(
+            buf,
+            "moproxy_proxy_server_probe_timeouts_total{{{}}} {}",
+            server_labels(&s.server),
+            s.server.status_snapshot().connect_failures.timed_out
+        )
+        .unwrap();
+    }
+
+    new_metric(
+        &mut buf,
+        "healthy_servers",
+        "gauge",
+        "Current number of servers with a score",
+    );
+    writeln!(buf, "moproxy_healthy_servers {}", monitor.healthy_servers()).unwrap();
+    new_metric(
+        &mut buf,
+        "degraded",
+        "gauge",
+        "Whether healthy servers are less than --min-healthy (1) or not (0)",
+    );
+    writeln!(buf, "moproxy_degraded {}", monitor.is_degraded() as u8).unwrap();
+
+    new_metric(
+        &mut buf,
+        "connections_refused",
+        "counter",
+        "Number of clients refused due to --max-connections",
+    );
+    writeln!(
+        buf,
+        "moproxy_connections_refused_total {}",
+        status.clients.refused
+    )
+    .unwrap();
+
+    new_metric(
+        &mut buf,
+        "source_refused",
+        "counter",
+        "Number of clients refused due to --max-conn-per-ip or src ip limit rules",
+    );
+    writeln!(
+        buf,
+        "moproxy_source_refused_total {}",
+        status.sources.refused
+    )
+    .unwrap();
+
+    new_metric(
+        &mut buf,
+        "source_addresses",
+        "gauge",
+        "Number of source addresses with clients counted toward their limit",
+    );
+    writeln!(buf, "moproxy_source_addresses {}", status.sources.sources).unwrap();
+
+    new_metric(
+        &mut buf,
+        "pending_handshakes",
+        "gauge",
+        "Current number of clients not yet piped",
+    );
+    writeln!(
+        buf,
+        "moproxy_pending_handshakes {}",
+        status.handshakes.pending
+    )
+    .unwrap();
+    new_metric(
+        &mut buf,
+        "handshakes_shed",
+        "counter",
+        "Number of clients closed due to --max-pending-handshakes",
+    );
+    writeln!(
+        buf,
+        "moproxy_handshakes_shed_total {}",
+        status.handshakes.shed
+    )
+    .unwrap();
+    new_metric(
+        &mut buf,
+        "handshake_timeouts",
+        "counter",
+        "Number of clients closed due to --client-handshake-timeout",
+    );
+    writeln!(
+        buf,
+        "moproxy_handshake_timeouts_total {}",
+        status.handshakes.timeouts
+    )
+    .unwrap();
+    new_metric(
+        &mut buf,
+        "health_checks",
+        "counter",
+        "Number of connections from --health-check-source closed without data",
+    );
+    writeln!(
+        buf,
+        "moproxy_health_checks_total {}",
+        status.handshakes.health_checks
+    )
+    .unwrap();
+
+    new_metric(
+        &mut buf,
+        "tasks",
+        "gauge",
+        "Current number of spawned tasks by kind",
+    );
+    let tasks = &status.tasks;
+    for (kind, n) in [
+        ("client", tasks.client),
+        ("probe", tasks.probe),
+        ("web", tasks.web),
+    ] {
+        writeln!(buf, "moproxy_tasks{{kind=\"{}\"}} {}", kind, n).unwrap();
+    }
+
+    histogram(
+        &mut buf,
+        "spawn_delay_seconds",
+        "Time from clients accepted to their tasks started, sampled",
+        &status.event_loop.spawn_delay,
+    );
+    histogram(
+        &mut buf,
+        "probe_lag_seconds",
+        "Time probe rounds started later than scheduled",
+        &status.event_loop.probe_lag,
+    );
+
+    new_metric(
+        &mut buf,
+        "config_generation",
+        "gauge",
+        "Number of successful config reloads",
+    );
+    writeln!(
+        buf,
+        "moproxy_config_generation {}",
+        status.reload.generation
+    )
+    .unwrap();
+    // Failed ones may be cut short at any step
+    if let Some(last) = status.reload.recent.iter().rev().find(|r| r.success) {
+        new_metric(
+            &mut buf,
+            "reload_duration_seconds",
+            "gauge",
+            "Time spent on the latest successful config reload",
+        );
+        writeln!(
+            buf,
+            "moproxy_reload_duration_seconds {}",
+            last.duration_secs
+        )
+        .unwrap();
+    }
+    new_metric(
+        &mut buf,
+        "server_list_skipped_sections",
+        "gauge",
+        "Number of invalid sections skipped in the server list, see --server-list-lenient",
+    );
+    writeln!(
+        buf,
+        "moproxy_server_list_skipped_sections {}",
+        status.reload.skipped.len()
+    )
+    .unwrap();
+
+    let sniff = &status.tls_sniff;
+    new_metric(
+        &mut buf,
+        "tls_sniff",
+        "counter",
+        "Results of sniffing TLS ClientHello from clients",
+    );
+    for (result, value) in [
+        ("hello_with_sni", sniff.hello_with_sni),
+        ("hello_without_sni", sniff.hello_without_sni),
+        ("parse_error", sniff.parse_error),
+        ("not_tls", sniff.not_tls),
+        ("timed_out", sniff.timed_out),
+        ("skipped", status.buffers.sniff_skipped),
+    ] {
+        writeln!(
+            buf,
+            "moproxy_tls_sniff_total{{result=\"{}\"}} {}",
+            result, value
+        )
+        .unwrap();
+    }
+    new_metric(
+        &mut buf,
+        "tls_sniff_early_data",
+        "counter",
+        "Number of TLS ClientHello with early data",
+    );
+    writeln!(
+        buf,
+        "moproxy_tls_sniff_early_data_total {}",
+        sniff.early_data
+    )
+    .unwrap();
+
+    let buffers = &status.buffers;
+    new_metric(
+        &mut buf,
+        "buffer_bytes",
+        "gauge",
+        "Bytes held in per-connection buffers, pending (before connected) or pipe",
+    );
+    for (kind, value) in [
+        ("pending", buffers.pending_bytes),
+        ("pipe", buffers.pipe_bytes),
+    ] {
+        writeln!(buf, "moproxy_buffer_bytes{{kind=\"{}\"}} {}", kind, value).unwrap();
+    }
+    if let Some(limit) = buffers.pending_limit {
+        new_metric(
+            &mut buf,
+            "buffer_pending_limit_bytes",
+            "gauge",
+            "Limit of pending buffers, TLS sniffs are skipped beyond it",
+        );
+        writeln!(buf, "moproxy_buffer_pending_limit_bytes {}", limit).unwrap();
+    }
+
+    if let Some(cache) = &status.resolver {
+        new_metric(
+            &mut buf,
+            "resolver_cache_lookups",
+            "counter",
+            "Lookups of the resolver cache, by hit or miss",
+        );
+        for (result, value) in [("hit", cache.hits), ("miss", cache.misses)] {
+            writeln!(
+                buf,
+                "moproxy_resolver_cache_lookups_total{{result=\"{}\"}} {}",
+                result, value
+            )
+            .unwrap();
+        }
+        new_metric(
+            &mut buf,
+            "resolver_cache_entries",
+            "gauge",
+            "Number of names in the resolver cache",
+        );
+        writeln!(buf, "moproxy_resolver_cache_entries {}", cache.size).unwrap();
+    }
+
+    if let Some(cache) = &status.policy_dns {
+        new_metric(
+            &mut buf,
+            "policy_dns_lookups",
+            "counter",
+            "Lookups of domain names resolved for policy, by hit or miss",
+        );
+        for (result, value) in [("hit", cache.hits), ("miss", cache.misses)] {
+            writeln!(
+                buf,
+                "moproxy_policy_dns_lookups_total{{result=\"{}\"}} {}",
+                result, value
+            )
+            .unwrap();
+        }
+        new_metric(
+            &mut buf,
+            "policy_dns_budget_misses",
+            "counter",
+            "Policy applied without the address as not resolved in time",
+        );
+        writeln!(
+            buf,
+            "moproxy_policy_dns_budget_misses_total {}",
+            cache.budget_misses
+        )
+        .unwrap();
+    }
+
+    if let Some(parallel) = &status.adaptive_parallel {
+        new_metric(
+            &mut buf,
+            "n_parallel_decisions",
+            "counter",
+            "Connections n_parallel chosen for by destination history",
+        );
+        writeln!(
+            buf,
+            "moproxy_n_parallel_decisions_total {}",
+            parallel.decisions
+        )
+        .unwrap();
+        new_metric(
+            &mut buf,
+            "n_parallel_escalations",
+            "counter",
+            "Connections raced as the best proxy failed the destination recently",
+        );
+        writeln!(
+            buf,
+            "moproxy_n_parallel_escalations_total {}",
+            parallel.escalations
+        )
+        .unwrap();
+        new_metric(
+            &mut buf,
+            "n_parallel_escalation_ratio",
+            "gauge",
+            "Ratio of connections raced among those n_parallel chosen for",
+        );
+        writeln!(
+            buf,
+            "moproxy_n_parallel_escalation_ratio {}",
+            parallel.escalation_ratio()
+        )
+        .unwrap();
+        new_metric(
+            &mut buf,
+            "n_parallel_escalated_destinations",
+            "gauge",
+            "Destinations remembered with recent failures of the best proxy",
+        );
+        writeln!(
+            buf,
+            "moproxy_n_parallel_escalated_destinations {}",
+            parallel.escalated
+        )
+        .unwrap();
+    }
+
+    if !status.config.is_empty() {
+        new_metric(
+            &mut buf,
+            "config_hash",
+            "info",
+            "SHA-256 of the loaded config, by file and kind",
+        );
+        for config in &status.config {
+            writeln!(
+                buf,
+                "moproxy_config_hash_info{{file=\"{}\",kind=\"{}\",hash=\"{}\"}} 1",
+                escape(&config.file),
+                config.kind,
+                config.hash
+            )
+            .unwrap();
+        }
+    }
+
+    let gone = &status.client_gone_early;
+    new_metric(
+        &mut buf,
+        "client_gone_early",
+        "counter",
+        "Clients closed before connected to upstream, by stage",
+    );
+    for (stage, value) in [
+        ("before_connect", gone.before_connect),
+        ("during_connect", gone.during_connect),
+    ] {
+        writeln!(
+            buf,
+            "moproxy_client_gone_early_total{{stage=\"{}\"}} {}",
+            stage, value
+        )
+        .unwrap();
+    }
+
+    let rejects = &status.inbound_rejects;
+    new_metric(
+        &mut buf,
+        "inbound_rejected",
+        "counter",
+        "Non-NATed connections refused for unaccepted protocols, by guess",
+    );
+    for (guess, value) in [
+        ("tls", rejects.tls),
+        ("http", rejects.http),
+        ("unknown", rejects.unknown),
+    ] {
+        writeln!(
+            buf,
+            "moproxy_inbound_rejected_total{{guess=\"{}\"}} {}",
+            guess, value
+        )
+        .unwrap();
+    }
+
+    let negotiation = &status.socks5_negotiation;
+    new_metric(
+        &mut buf,
+        "socks5_negotiation_failures",
+        "counter",
+        "SOCKSv5 clients failed on negotiating the method or authenticating",
+    );
+    for (reason, value) in [
+        ("no_acceptable_method", negotiation.no_acceptable_method),
+        ("no_methods", negotiation.no_methods),
+        ("auth_version", negotiation.auth_version),
+        ("auth_rejected", negotiation.auth_rejected),
+    ] {
+        writeln!(
+            buf,
+            "moproxy_socks5_negotiation_failures_total{{reason=\"{}\"}} {}",
+            reason, value
+        )
+        .unwrap();
+    }
+
+    let pages = &status.reject_pages;
+    new_metric(
+        &mut buf,
+        "reject_pages",
+        "counter",
+        "Rejected NATed port 80 connections, by whether the reject page served",
+    );
+    for (result, value) in [("served", pages.served), ("not_http", pages.not_http)] {
+        writeln!(
+            buf,
+            "moproxy_reject_pages_total{{result=\"{}\"}} {}",
+            result, value
+        )
+        .unwrap();
+    }
+
+    let hints = &status.client_hints;
+    new_metric(
+        &mut buf,
+        "client_hints",
+        "counter",
+        "Routing hints of HTTP CONNECT clients, by hint",
+    );
+    for (hint, value) in [
+        ("require", hints.require),
+        ("server", hints.server),
+        ("invalid", hints.invalid),
+    ] {
+        writeln!(
+            buf,
+            "moproxy_client_hints_total{{hint=\"{}\"}} {}",
+            hint, value
+        )
+        .unwrap();
+    }
+
+    new_metric(
+        &mut buf,
+        "tls_fingerprint",
+        "counter",
+        "Number of TLS ClientHello by fingerprint and listen port, the most seen only",
+    );
+    for fp in &status.tls_fingerprints {
+        writeln!(
+            buf,
+            "moproxy_tls_fingerprint_total{{port=\"{}\",hash=\"{}\"}} {}",
+            fp.listen_port, fp.hash, fp.count
+        )
+        .unwrap();
+    }
 
     writeln!(buf, "# EOF").unwrap();
-    Response::builder()
-        .header("Content-Type", CONTENT_TYPE)
-        .body(buf.into())
+    buf
+}
+
+/// Check HELP/TYPE lines precede their samples, and labels are well-formed.
+#[cfg(test)]
+fn validate(text: &str) -> Result<(), String> {
+    use regex::Regex;
+
+    let help = Regex::new(r"^# HELP (moproxy_\w+) \S.*$").unwrap();
+    let type_ = Regex::new(r"^# TYPE (moproxy_\w+) (gauge|counter|info|histogram)$").unwrap();
+    let sample = Regex::new(
+        r#"^(moproxy_\w+)(\{\w+="(?:[^"\\\n]|\\[\\"n])*"(?:,\w+="(?:[^"\\\n]|\\[\\"n])*")*\})? -?[0-9.]+$"#,
+    )
+    .unwrap();
+    let mut lines = text.lines().peekable();
+    let mut family: Option<(String, String)> = None;
+    while let Some(line) = lines.next() {
+        if line == "# EOF" {
+            return match lines.next() {
+                None => Ok(()),
+                Some(line) => Err(format!("after EOF: {}", line)),
+            };
+        }
+        if let Some(caps) = help.captures(line) {
+            let name = caps[1].to_string();
+            let next = lines.next().unwrap_or_default();
+            let caps = type_
+                .captures(next)
+                .ok_or_else(|| format!("TYPE expected: {}", next))?;
+            if caps[1] != name {
+                return Err(format!("TYPE of another metric: {}", next));
+            }
+            family = Some((name, caps[2].to_string()));
+        } else if let Some(caps) = sample.captures(line) {
+            let (name, metric_type) = family
+                .as_ref()
+                .ok_or_else(|| format!("sample w/o TYPE: {}", line))?;
+            let suffixes: &[&str] = match metric_type.as_str() {
+                "counter" => &["_total"],
+                "info" => &["_info"],
+                "histogram" => &["_bucket", "_sum", "_count"],
+                _ => &[""],
+            };
+            if !suffixes
+                .iter()
+                .any(|suffix| caps[1] == format!("{}{}", name, suffix))
+            {
+                return Err(format!("sample not of {}: {}", name, line));
+            }
+        } else {
+            return Err(format!("malformed line: {}", line));
+        }
+    }
+    Err("no EOF".into())
+}
+
+#[test]
+fn test_exporter_labels() {
+    use crate::{policy::capabilities::CapSet, proxy::ProxyServer};
+    use std::{sync::Arc, time::Duration};
+
+    let server = |port, proto, caps: &[&str], tag| {
+        Arc::new(
+            ProxyServer::new(
+                ([127, 0, 0, 1], port).into(),
+                proto,
+                ([127, 0, 0, 1], 53).into(),
+                Duration::from_secs(1),
+                Some(CapSet::new(caps.iter().copied())),
+                Some(tag),
+                None,
+            )
+            .unwrap(),
+        )
+    };
+    let servers = vec![
+        server(1, ProxyProto::socks5(false), &["b", "a"], "s1"),
+        server(2, ProxyProto::http(false, None), &["q\"uo\\te\n"], "s2"),
+    ];
+    servers[0].update_delay(Some(Duration::from_millis(10)));
+    let monitor = Monitor::new(servers, None);
+    monitor.set_config_hashes(vec![crate::monitor::ConfigHash {
+        file: "/etc/\"proxy\".ini".into(),
+        kind: "file",
+        hash: "ab".repeat(32),
+    }]);
+    let text = render(&Instant::now(), &monitor);
+    assert!(!text.contains("moproxy_reload_duration_seconds"));
+    monitor.reload_succeeded(Default::default(), 0, Duration::from_millis(500));
+    monitor.reload_failed("bad".into(), Duration::from_millis(1));
+    let text = render(&Instant::now(), &monitor);
+    validate(&text).unwrap();
+    assert!(text.contains("moproxy_reload_duration_seconds 0.5\n"));
+    assert!(text.contains(&format!(
+        r#"moproxy_config_hash_info{{file="/etc/\"proxy\".ini",kind="file",hash="{}"}} 1"#,
+        "ab".repeat(32)
+    )));
+
+    assert!(
+        text.contains("moproxy_proxy_server_score{server=\"s1\",proto=\"socks5\",caps=\"a,b\"} ")
+    );
+    assert!(text.contains(
+        r#"moproxy_proxy_server_connections_total{server="s2",proto="http",caps="q\"uo\\te\n"} 0"#
+    ));
+
+    assert!(text.contains("moproxy_probe_lag_seconds_bucket{le=\"0.0001\"} "));
+    assert!(text.contains("moproxy_spawn_delay_seconds_bucket{le=\"5.0\"} "));
+    assert!(text.contains("moproxy_spawn_delay_seconds_bucket{le=\"+Inf\"} "));
+
+    assert!(validate("# HELP moproxy_x x\n# TYPE moproxy_x gauge\nmoproxy_y 1\n# EOF\n").is_err());
+    assert!(validate("moproxy_x{a=\"\"\"} 1\n# EOF\n").is_err());
 }
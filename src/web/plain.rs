@@ -0,0 +1,106 @@
+use anyhow::Context;
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::TcpListener,
+    sync::Semaphore,
+    time::{sleep, timeout},
+};
+use tracing::{debug, info, instrument, warn};
+
+use super::plaintext_status;
+use crate::monitor::{Monitor, TaskKind};
+
+/// Max number of connections being written concurrently, exceeded ones
+/// are closed immediately.
+const MAX_CONNS: usize = 16;
+/// Close the connection if the client don't read the status in time.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Wait before accepting again after an error, e.g. out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Write the plaintext status then close, on each TCP connection.
+/// No HTTP involved, for `nc` or alike.
+pub struct PlainStatsServer {
+    monitor: Monitor,
+    listener: TcpListener,
+}
+
+impl PlainStatsServer {
+    pub async fn bind(monitor: Monitor, addr: SocketAddr) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(&addr)
+            .await
+            .context("fail to bind plaintext stats server")?;
+        info!("Plaintext stats listen on tcp:{}", addr);
+        Ok(Self { monitor, listener })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn run_background(self) {
+        tokio::spawn(self.run());
+    }
+
+    #[instrument(name = "plain_stats_server", skip_all)]
+    async fn run(self) {
+        tokio::spawn(self.monitor.clone().monitor_throughput());
+        let start_time = Instant::now();
+        let permits = Arc::new(Semaphore::new(MAX_CONNS));
+
+        loop {
+            let (mut stream, peer) = match self.listener.accept().await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    warn!("failed to accept: {}", err);
+                    sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            let permit = match permits.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    debug!(%peer, "too many connections, close it");
+                    continue;
+                }
+            };
+            let status = plaintext_status(&start_time, &self.monitor);
+            let task = self.monitor.track_task(TaskKind::Web);
+            tokio::spawn(async move {
+                let _task = task;
+                let write = async {
+                    stream.write_all(status.as_bytes()).await?;
+                    stream.shutdown().await
+                };
+                match timeout(WRITE_TIMEOUT, write).await {
+                    Ok(Ok(())) => (),
+                    Ok(Err(err)) => debug!(%peer, "fail to write status: {}", err),
+                    Err(_) => debug!(%peer, "timed out on writing status"),
+                }
+                drop(permit);
+            });
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_plain_stats_server() {
+    use tokio::{io::AsyncReadExt, net::TcpStream};
+
+    let monitor = Monitor::new(vec![], None);
+    let server = PlainStatsServer::bind(monitor, "127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    server.run_background();
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut buf = String::new();
+    stream.read_to_string(&mut buf).await.unwrap();
+    assert!(buf.starts_with("moproxy ("));
+}
@@ -0,0 +1,120 @@
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{body::Body, header::CONTENT_LENGTH, Method, Request, Response, StatusCode};
+
+use super::{not_found, BytesResult, WebContext};
+
+/// Take the request, the context, and the part of path matched by `*`
+/// (or the whole path if without `*`).
+pub(super) type Handler = fn(&Request<Bytes>, &WebContext, &str) -> BytesResult;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pattern {
+    Exact(&'static str),
+    Prefix(&'static str),
+}
+
+impl Pattern {
+    /// `/a/b` matches itself only, `/a/*` matches any path under `/a/`.
+    fn new(pattern: &'static str) -> Self {
+        match pattern.strip_suffix('*') {
+            Some(prefix) => Self::Prefix(prefix),
+            None => Self::Exact(pattern),
+        }
+    }
+
+    fn matches<'a>(&self, path: &'a str) -> Option<&'a str> {
+        match self {
+            Self::Exact(pattern) => (path == *pattern).then_some(path),
+            Self::Prefix(prefix) => path.strip_prefix(prefix),
+        }
+    }
+}
+
+struct Route {
+    method: Method,
+    pattern: Pattern,
+    handler: Handler,
+}
+
+/// Dispatch requests to the first route with matched method and path.
+/// HEAD is served by GET routes with the body dropped.
+#[derive(Default)]
+pub(super) struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub(super) fn route(mut self, method: Method, pattern: &'static str, handler: Handler) -> Self {
+        self.routes.push(Route {
+            method,
+            pattern: Pattern::new(pattern),
+            handler,
+        });
+        self
+    }
+
+    /// Methods of routes matching `path`, in order added.
+    fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        let mut methods = vec![];
+        for route in &self.routes {
+            if route.pattern.matches(path).is_some() && !methods.contains(&route.method) {
+                methods.push(route.method.clone());
+                if route.method == Method::GET {
+                    methods.push(Method::HEAD);
+                }
+            }
+        }
+        methods
+    }
+
+    pub(super) fn dispatch(
+        &self,
+        req: &Request<Bytes>,
+        ctx: &WebContext,
+        path: &str,
+    ) -> BytesResult {
+        let is_head = req.method() == Method::HEAD;
+        let method = if is_head { &Method::GET } else { req.method() };
+        let found = self
+            .routes
+            .iter()
+            .find_map(|route| match route.method == method {
+                true => route.pattern.matches(path).map(|param| (route, param)),
+                false => None,
+            });
+        if let Some((route, param)) = found {
+            let resp = (route.handler)(req, ctx, param)?;
+            return Ok(if is_head { without_body(resp) } else { resp });
+        }
+        let allowed = self.allowed_methods(path);
+        if allowed.is_empty() {
+            return not_found();
+        }
+        let allowed: Vec<_> = allowed.iter().map(Method::as_str).collect();
+        let allowed = allowed.join(", ");
+        Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header("Allow", &allowed)
+            .header("Content-Type", "text/plain")
+            .body(format!("only {} allowed", allowed).into())
+    }
+}
+
+/// Keep headers of `resp`, including the length of its body.
+fn without_body(resp: Response<Full<Bytes>>) -> Response<Full<Bytes>> {
+    let (mut parts, body) = resp.into_parts();
+    let len = body.size_hint().exact().unwrap_or_default();
+    parts.headers.entry(CONTENT_LENGTH).or_insert(len.into());
+    Response::from_parts(parts, Default::default())
+}
+
+#[test]
+fn test_pattern() {
+    assert_eq!(Some("/a"), Pattern::new("/a").matches("/a"));
+    assert_eq!(None, Pattern::new("/a").matches("/a/"));
+    assert_eq!(Some("x%20y"), Pattern::new("/a/*").matches("/a/x%20y"));
+    assert_eq!(Some(""), Pattern::new("/a/*").matches("/a/"));
+    assert_eq!(None, Pattern::new("/a/*").matches("/a"));
+    assert_eq!(Some("/b"), Pattern::new("*").matches("/b"));
+}
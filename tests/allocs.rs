@@ -0,0 +1,179 @@
+//! Allocations saved by interning destination domain names, sharing the
+//! server list, etc. on the accept-to-connect path.
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    io::{Read, Write},
+    net::{self, SocketAddr},
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+
+use moproxy::{
+    client::NewClient,
+    monitor::Monitor,
+    proxy::{intern::Interner, normalize_domain, ProxyProto, ProxyServer},
+};
+use tokio::net::TcpListener;
+
+thread_local! {
+    static ALLOCS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Count allocations made on each thread.
+struct CountingAlloc;
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Number of allocations made by `f`.
+fn allocs<T>(f: impl FnOnce() -> T) -> usize {
+    let before = ALLOCS.with(Cell::get);
+    let value = f();
+    let n = ALLOCS.with(Cell::get) - before;
+    drop(value);
+    n
+}
+
+#[test]
+fn test_intern_allocs() {
+    let interner = Interner::new(64);
+    let name = "a-long-name-not-inlined.example.com";
+    let dotted = format!("{}.", name);
+    let upper = "A-Long-Name-Not-Inlined.Example.COM.";
+    assert!(allocs(|| interner.normalize(name).unwrap()) > 0);
+
+    // Seen names never allocate again, unless not normalized yet
+    assert_eq!(0, allocs(|| interner.normalize(name).unwrap()));
+    assert_eq!(0, allocs(|| interner.normalize(&dotted).unwrap()));
+    assert!(allocs(|| interner.normalize(upper).unwrap()) > 0);
+    // While it always allocates without interning
+    assert!(allocs(|| normalize_domain(name).unwrap()) > 0);
+
+    // Short names are inlined
+    assert_eq!(0, allocs(|| interner.normalize("example.com").unwrap()));
+
+    // Still cheap for names seen often after a scan of many others
+    let others: Vec<_> = (0..1000)
+        .map(|n| format!("scan-{}-not-inlined.example.com", n))
+        .collect();
+    for (n, other) in others.iter().enumerate() {
+        interner.normalize(other).unwrap();
+        if n % 32 == 0 {
+            interner.normalize(name).unwrap();
+        }
+    }
+    assert_eq!(0, allocs(|| interner.normalize(name).unwrap()));
+}
+
+/// SOCKSv5 server replying success to every CONNECT request, on its own
+/// thread so that its allocations are not counted.
+fn socks5_upstream() -> SocketAddr {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut buf = [0u8; 262];
+            stream.read_exact(&mut buf[..3]).unwrap();
+            assert_eq!(&[5, 1, 0], &buf[..3]);
+            stream.write_all(&[5, 0]).unwrap();
+            stream.read_exact(&mut buf[..5]).unwrap();
+            assert_eq!(&[5, 1, 0, 3], &buf[..4]);
+            let len = buf[4] as usize + 2;
+            stream.read_exact(&mut buf[..len]).unwrap();
+            stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+            // Until moproxy drops it
+            let _ = stream.read(&mut buf);
+        }
+    });
+    addr
+}
+
+/// SOCKSv5 client connecting to `name` via `proxy` on every name sent,
+/// on its own thread. Every name is acknowledged once connected.
+fn socks5_client(proxy: SocketAddr) -> (mpsc::Sender<String>, mpsc::Receiver<()>) {
+    let (name_tx, name_rx) = mpsc::channel::<String>();
+    let (done_tx, done_rx) = mpsc::channel();
+    thread::spawn(move || {
+        for name in name_rx {
+            let mut stream = net::TcpStream::connect(proxy).unwrap();
+            let mut buf = [0u8; 10];
+            stream.write_all(&[5, 1, 0]).unwrap();
+            stream.read_exact(&mut buf[..2]).unwrap();
+            assert_eq!([5, 0], buf[..2]);
+            let mut request = vec![5, 1, 0, 3, name.len() as u8];
+            request.extend_from_slice(name.as_bytes());
+            request.extend_from_slice(&443u16.to_be_bytes());
+            stream.write_all(&request).unwrap();
+            stream.read_exact(&mut buf).unwrap();
+            assert_eq!(&[5, 0, 0], &buf[..3]);
+            done_tx.send(()).unwrap();
+        }
+    });
+    (name_tx, done_rx)
+}
+
+#[test]
+fn test_accept_to_connect_allocs() {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let upstream = ProxyServer::new(
+        socks5_upstream(),
+        ProxyProto::socks5(false),
+        "127.0.0.1:53".parse().unwrap(),
+        Duration::from_secs(1),
+        None,
+        None,
+        None,
+    )
+    .unwrap();
+    let monitor = Monitor::new(vec![Arc::new(upstream)], None);
+    let listener = rt.block_on(TcpListener::bind("[::1]:0")).unwrap();
+    let (names, done) = socks5_client(listener.local_addr().unwrap());
+
+    // Number of allocations from accepting a client asking for `name` to
+    // connected to the upstream
+    let connect = |name: &str| {
+        names.send(name.to_string()).unwrap();
+        let n = allocs(|| {
+            rt.block_on(async {
+                let (sock, _) = listener.accept().await.unwrap();
+                let client = NewClient::from_socket(sock, false).await.unwrap();
+                client
+                    .connect_server(monitor.servers().to_vec(), 1, 0)
+                    .await
+                    .map_err(|_| "fail to connect upstream")
+                    .unwrap()
+            })
+        });
+        done.recv().unwrap();
+        n
+    };
+    let name = "a-long-name-not-inlined.example.com";
+    connect(name);
+
+    // Names seen share the interned one
+    let seen = connect(name);
+    let new = connect("another-long-name-not-inlined.example.com");
+    assert!(seen < new, "seen {} vs. new {}", seen, new);
+    assert_eq!(seen, connect(name));
+
+    // The server list is shared, not copied for each client
+    assert_eq!(0, allocs(|| monitor.servers()));
+    assert!(allocs(|| monitor.servers().to_vec()) > 0);
+}
@@ -0,0 +1,128 @@
+use moproxy::{
+    client::{FailedClient, NewClient, CLIENT_GONE_EARLY},
+    proxy::{ProxyProto, ProxyServer},
+};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+
+/// Accept a SOCKSv5 client requesting 192.0.2.1:80, which shuts down its
+/// write half after `linger`.
+async fn accept_client(linger: Duration) -> NewClient {
+    let listener = TcpListener::bind("[::1]:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        let request = [5, 1, 0, 5, 1, 0, 1, 192, 0, 2, 1, 0, 80];
+        stream.write_all(&request).await.unwrap();
+        tokio::time::sleep(linger).await;
+        stream.shutdown().await.unwrap();
+        // Keep the read half open
+        let _ = stream.read(&mut [0; 64]).await;
+    });
+    let (sock, _) = listener.accept().await.unwrap();
+    NewClient::from_socket(sock, false).await.unwrap()
+}
+
+fn upstream(addr: SocketAddr) -> Arc<ProxyServer> {
+    let server = ProxyServer::new(
+        addr,
+        ProxyProto::socks5(false),
+        "192.0.2.53:53".parse().unwrap(),
+        Duration::from_secs(5),
+        None,
+        None,
+        None,
+    )
+    .unwrap();
+    Arc::new(server)
+}
+
+#[tokio::test]
+async fn test_client_gone_before_connect() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = upstream(listener.local_addr().unwrap());
+    let client = accept_client(Duration::ZERO).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let result = client.connect_server(vec![server.clone()], 1, 0).await;
+    assert!(matches!(result, Err(FailedClient::Unrecoverable(_))));
+    assert!(CLIENT_GONE_EARLY.snapshot().before_connect >= 1);
+    // No upstream connection attempted
+    let accept = timeout(Duration::from_millis(200), listener.accept()).await;
+    assert!(accept.is_err());
+    assert_eq!(0, server.status_snapshot().conn_error);
+}
+
+#[tokio::test]
+async fn test_client_gone_during_connect() {
+    // Upstream that never replies
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = upstream(listener.local_addr().unwrap());
+    let accepted = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let _ = stream.read(&mut [0; 64]).await;
+        stream
+    });
+    let client = accept_client(Duration::from_millis(200)).await;
+
+    let connect = client.connect_server(vec![server.clone()], 1, 0);
+    let result = timeout(Duration::from_secs(2), connect).await.unwrap();
+    assert!(matches!(result, Err(FailedClient::Unrecoverable(_))));
+    assert!(CLIENT_GONE_EARLY.snapshot().during_connect >= 1);
+    assert_eq!(0, server.status_snapshot().conn_error);
+
+    // Connection to upstream closed
+    let mut stream = accepted.await.unwrap();
+    let read = timeout(Duration::from_secs(1), stream.read(&mut [0; 64])).await;
+    assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+}
+
+#[tokio::test]
+async fn test_client_half_closed_with_pending_data() {
+    // SOCKSv5 server that replies, then reads the data forwarded
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = upstream(listener.local_addr().unwrap());
+    let accepted = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 10];
+        stream.read_exact(&mut buf[..3]).await.unwrap();
+        stream.write_all(&[5, 0]).await.unwrap();
+        stream.read_exact(&mut buf).await.unwrap();
+        stream
+            .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        buf
+    });
+
+    // Data sent with the request then shut down, like a HTTP/1.0 client
+    let listener = TcpListener::bind("[::1]:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        let mut request = vec![5, 1, 0, 5, 1, 0, 1, 192, 0, 2, 1, 1, 187];
+        request.extend_from_slice(b"early");
+        stream.write_all(&request).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let _ = stream.read(&mut [0; 64]).await;
+    });
+    let (sock, _) = listener.accept().await.unwrap();
+    let mut client = NewClient::from_socket(sock, false).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    // Read into the pending data
+    client.retrieve_dest_from_sni().await.unwrap();
+    assert!(client.is_not_tls());
+    assert!(!client.has_gone());
+
+    let connect = client.connect_server(vec![server], 1, 0);
+    let result = timeout(Duration::from_secs(2), connect).await.unwrap();
+    assert!(result.is_ok());
+    assert_eq!(b"early", &accepted.await.unwrap());
+}
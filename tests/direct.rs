@@ -0,0 +1,52 @@
+use moproxy::{client::NewClient, proxy::ProxyServer};
+use std::{
+    io::ErrorKind,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    self,
+    net::{TcpListener, TcpSocket, TcpStream},
+};
+
+/// A listener never accepting, with its backlog filled up so that
+/// further SYN are dropped.
+async fn blackhole() -> (TcpListener, Vec<TcpStream>) {
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let listener = socket.listen(0).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut fillers = vec![];
+    for _ in 0..4 {
+        match tokio::time::timeout(Duration::from_millis(100), TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => fillers.push(stream),
+            _ => break,
+        }
+    }
+    (listener, fillers)
+}
+
+#[tokio::test]
+async fn test_direct_connect_timeout() {
+    let (blackhole, _fillers) = blackhole().await;
+    let dest = blackhole.local_addr().unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let _stream = TcpStream::connect(&addr).await.unwrap();
+    let (sock, _) = listener.accept().await.unwrap();
+    let mut client = NewClient::from_tproxy_socket(sock, addr.port(), false).unwrap();
+    client.dest = dest.into();
+
+    let direct = Arc::new(ProxyServer::direct(Duration::from_millis(300)));
+    let now = Instant::now();
+    let err = client.direct_connect(direct.clone()).await.unwrap_err();
+    assert_eq!(ErrorKind::TimedOut, err.kind());
+    assert!(now.elapsed() < Duration::from_secs(2));
+
+    let status = direct.status_snapshot();
+    assert_eq!(
+        (0, 1, 1),
+        (status.conn_alive, status.conn_total, status.conn_error)
+    );
+}
@@ -0,0 +1,98 @@
+//! SOCKSv5 client -> moproxy -> SOCKSv5 upstream -> destination, with
+//! only portable APIs so that it runs on every supported target.
+use moproxy::{
+    client::NewClient,
+    proxy::{ProxyProto, ProxyServer},
+};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    self,
+    io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Echo server, return its address.
+async fn echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (mut rx, mut tx) = stream.split();
+        tokio::io::copy(&mut rx, &mut tx).await.unwrap();
+    });
+    addr
+}
+
+/// Minimal SOCKSv5 server accepting one IPv4 CONNECT request.
+async fn socks5_upstream() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 10];
+        stream.read_exact(&mut buf[..3]).await.unwrap();
+        assert_eq!(&[5, 1, 0], &buf[..3]);
+        stream.write_all(&[5, 0]).await.unwrap();
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&[5, 1, 0, 1], &buf[..4]);
+        let ip: [u8; 4] = buf[4..8].try_into().unwrap();
+        let port = u16::from_be_bytes([buf[8], buf[9]]);
+        let dest = SocketAddr::from((ip, port));
+        let mut dest = TcpStream::connect(dest).await.unwrap();
+        stream
+            .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        copy_bidirectional(&mut stream, &mut dest).await.unwrap();
+    });
+    addr
+}
+
+#[tokio::test]
+async fn test_socks5_end_to_end() {
+    let dest = match echo_server().await {
+        SocketAddr::V4(dest) => dest,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    let upstream = ProxyServer::new(
+        socks5_upstream().await,
+        ProxyProto::socks5(false),
+        "127.0.0.1:53".parse().unwrap(),
+        Duration::from_secs(1),
+        None,
+        None,
+        None,
+    )
+    .unwrap();
+
+    // Listen on IPv6 as the default `--host ::` does
+    let listener = TcpListener::bind("[::1]:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (sock, _) = listener.accept().await.unwrap();
+        let client = NewClient::from_socket(sock, false).await.unwrap();
+        let connected = client
+            .connect_server(vec![Arc::new(upstream)], 1, 0)
+            .await
+            .map_err(|_| "fail to connect upstream")
+            .unwrap();
+        connected.serve().await.unwrap();
+    });
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut buf = [0u8; 10];
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    stream.read_exact(&mut buf[..2]).await.unwrap();
+    assert_eq!([5, 0], buf[..2]);
+    let mut request = vec![5, 1, 0, 1];
+    request.extend_from_slice(&dest.ip().octets());
+    request.extend_from_slice(&dest.port().to_be_bytes());
+    stream.write_all(&request).await.unwrap();
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&[5, 0, 0], &buf[..3]);
+
+    stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(b"ping", &buf);
+}
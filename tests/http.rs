@@ -0,0 +1,196 @@
+use moproxy::proxy::{error::HandshakeError, ProxyProto, ProxyServer, UserPassAuthCredential};
+use std::{io::ErrorKind, net::SocketAddr, time::Duration};
+use tokio::{
+    self,
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+const CHALLENGE: &[u8] = b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+    Proxy-Authenticate: Basic realm=\"test\"\r\n\
+    Content-Length: 6\r\n\r\n\
+    denied";
+
+/// Read a request header, return the Proxy-Authorization value if any.
+async fn read_request(stream: &mut BufReader<TcpStream>) -> Option<String> {
+    let mut auth = None;
+    loop {
+        let mut line = String::new();
+        stream.read_line(&mut line).await.unwrap();
+        if line == "\r\n" {
+            return auth;
+        }
+        if let Some(value) = line.strip_prefix("Proxy-Authorization: ") {
+            auth = Some(value.trim_end().to_string());
+        }
+    }
+}
+
+fn http_server(addr: SocketAddr, auth_on_challenge: bool) -> ProxyServer {
+    let proto = ProxyProto::Http {
+        connect_with_payload: false,
+        user_pass_auth: Some(UserPassAuthCredential::new("user", "pass")),
+        auth_on_challenge,
+    };
+    ProxyServer::new(
+        addr,
+        proto,
+        "127.0.0.1:53".parse().unwrap(),
+        Duration::from_secs(1),
+        None,
+        None,
+        None,
+    )
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_http_auth_on_challenge() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = http_server(listener.local_addr().unwrap(), true);
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        assert_eq!(None, read_request(&mut stream).await);
+        stream.write_all(CHALLENGE).await.unwrap();
+        // base64("user:pass")
+        assert_eq!(
+            Some("Basic dXNlcjpwYXNz"),
+            read_request(&mut stream).await.as_deref()
+        );
+        stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0u8; 7];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"payload", &buf);
+        stream.write_all(b"response").await.unwrap();
+    });
+
+    let dest = ("example.com", 443).into();
+    let mut stream = server.connect(&dest, Some(b"payload")).await.unwrap();
+    let mut buf = [0u8; 8];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(b"response", &buf);
+    assert!(!server.auth_failed());
+}
+
+#[tokio::test]
+async fn test_http_auth_failed() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = http_server(listener.local_addr().unwrap(), false);
+
+    tokio::spawn(async move {
+        // Refuse the credential, then accept it
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        assert!(read_request(&mut stream).await.is_some());
+        stream.write_all(CHALLENGE).await.unwrap();
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        assert!(read_request(&mut stream).await.is_some());
+        stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await
+            .unwrap();
+    });
+
+    let dest = ("example.com", 443).into();
+    let err = server.connect::<&[u8]>(&dest, None).await.unwrap_err();
+    assert_eq!(ErrorKind::PermissionDenied, err.kind());
+    assert!(matches!(
+        HandshakeError::from_io(&err),
+        Some(HandshakeError::AuthRejected)
+    ));
+    assert!(server.auth_failed());
+
+    server.connect::<&[u8]>(&dest, None).await.unwrap();
+    assert!(!server.auth_failed());
+}
+
+fn http_server_without_auth(addr: SocketAddr, connect_with_payload: bool) -> ProxyServer {
+    let proto = ProxyProto::Http {
+        connect_with_payload,
+        user_pass_auth: None,
+        auth_on_challenge: false,
+    };
+    ProxyServer::new(
+        addr,
+        proto,
+        "127.0.0.1:53".parse().unwrap(),
+        Duration::from_secs(1),
+        None,
+        None,
+        None,
+    )
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_http_response_with_payload() {
+    for connect_with_payload in [false, true] {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = http_server_without_auth(listener.local_addr().unwrap(), connect_with_payload);
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            assert_eq!(None, read_request(&mut stream).await);
+            if !connect_with_payload {
+                stream
+                    .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                    .await
+                    .unwrap();
+            }
+            let mut buf = [0u8; 7];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(b"payload", &buf);
+            // Response header & the destination's reply in one segment
+            let reply: &[u8] = if connect_with_payload {
+                b"HTTP/1.1 200 Connection established\r\n\r\nresponse"
+            } else {
+                b"response"
+            };
+            stream.write_all(reply).await.unwrap();
+            stream.write_all(b" more").await.unwrap();
+        });
+
+        let dest = ("example.com", 443).into();
+        let mut stream = server.connect(&dest, Some(b"payload")).await.unwrap();
+        let mut buf = [0u8; 4];
+        assert_eq!(4, stream.peek(&mut buf).await.unwrap());
+        assert_eq!(b"resp", &buf);
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(b"response more", &buf[..]);
+    }
+}
+
+#[tokio::test]
+async fn test_http_response_split_and_coalesced() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = http_server_without_auth(listener.local_addr().unwrap(), false);
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        assert_eq!(None, read_request(&mut stream).await);
+        // Header split across segments, the last one carries the payload too
+        stream.write_all(b"HTTP/1.1 200 Connection").await.unwrap();
+        stream.flush().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stream
+            .write_all(b" established\r\nVia: test\r\n\r\n\x16\x03\x01")
+            .await
+            .unwrap();
+    });
+
+    let dest = ("example.com", 443).into();
+    let mut stream = server.connect::<&[u8]>(&dest, None).await.unwrap();
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(b"\x16\x03\x01", &buf[..]);
+}
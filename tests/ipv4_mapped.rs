@@ -0,0 +1,100 @@
+use moproxy::{
+    client::NewClient,
+    proxy::{Destination, ProxyProto, ProxyServer},
+};
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Send a SOCKSv5 request with IPv6 address `::ffff:1.2.3.4` to a new
+/// client, return the destination it retrieved.
+async fn accept_mapped_dest(keep_ipv4_mapped: bool) -> Destination {
+    // Listen on IPv6 as the default `--host ::` does
+    let listener = TcpListener::bind("[::1]:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        let mut buf = [0u8; 2];
+        stream.write_all(&[5, 1, 0]).await.unwrap();
+        stream.read_exact(&mut buf).await.unwrap();
+        let mut request = vec![5, 1, 0, 4];
+        request.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 1, 2, 3, 4]);
+        request.extend_from_slice(&[0, 80]);
+        stream.write_all(&request).await.unwrap();
+    });
+
+    let (sock, _) = listener.accept().await.unwrap();
+    NewClient::from_socket(sock, keep_ipv4_mapped)
+        .await
+        .unwrap()
+        .dest
+}
+
+fn proxy_server(addr: SocketAddr, proto: ProxyProto) -> ProxyServer {
+    let test_dns = "127.0.0.1:53".parse().unwrap();
+    ProxyServer::new(
+        addr,
+        proto,
+        test_dns,
+        Duration::from_secs(1),
+        None,
+        None,
+        None,
+    )
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_socks5_ipv4_mapped_dest() {
+    let dest = accept_mapped_dest(false).await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = proxy_server(listener.local_addr().unwrap(), ProxyProto::socks5(false));
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 10];
+        stream.read_exact(&mut buf[..3]).await.unwrap();
+        stream.write_all(&[5, 0]).await.unwrap();
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, &[5, 1, 0, 1, 1, 2, 3, 4, 0, 80]); // ATYP=1, 1.2.3.4
+        stream
+            .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 80])
+            .await
+            .unwrap();
+    });
+    server.connect::<&[u8]>(&dest, None).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_http_ipv4_mapped_dest() {
+    let dest = accept_mapped_dest(false).await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = proxy_server(
+        listener.local_addr().unwrap(),
+        ProxyProto::http(false, None),
+    );
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 256];
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(buf[..n].starts_with(b"CONNECT 1.2.3.4:80 HTTP/1.1\r\n"));
+        stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await
+            .unwrap();
+    });
+    server.connect::<&[u8]>(&dest, None).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_keep_ipv4_mapped_dest() {
+    let dest = accept_mapped_dest(true).await;
+    assert_eq!("::ffff:1.2.3.4:80", dest.to_string());
+    let dest = accept_mapped_dest(false).await;
+    assert_eq!("1.2.3.4:80", dest.to_string());
+}
@@ -0,0 +1,78 @@
+use moproxy::{client::NewClient, monitor::Monitor};
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+#[tokio::test]
+async fn test_max_connections_refused() {
+    let mut monitor = Monitor::new(vec![], None);
+    monitor.set_max_clients(2);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut clients = vec![];
+    let mut permits = vec![];
+    for _ in 0..2 {
+        clients.push(TcpStream::connect(&addr).await.unwrap());
+        let (_sock, _) = listener.accept().await.unwrap();
+        permits.push(monitor.client_permit().unwrap());
+    }
+    assert_eq!(2, monitor.client_stats().alive);
+
+    // SOCKSv5 greeting arrived before refused
+    let mut client = TcpStream::connect(&addr).await.unwrap();
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let (sock, _) = listener.accept().await.unwrap();
+    sock.readable().await.unwrap();
+    assert!(monitor.client_permit().is_none());
+    NewClient::refuse(sock);
+    let mut buf = [0u8; 12];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!([5, 0, 5, 1], buf[..4]);
+
+    // Closed silently otherwise
+    let mut client = TcpStream::connect(&addr).await.unwrap();
+    let (sock, _) = listener.accept().await.unwrap();
+    assert!(monitor.client_permit().is_none());
+    NewClient::refuse(sock);
+    assert!(client.read(&mut buf).await.map_or(true, |n| n == 0));
+
+    let stats = monitor.client_stats();
+    assert_eq!((2, 2), (stats.alive, stats.refused));
+    permits.pop();
+    assert!(monitor.client_permit().is_some());
+}
+
+#[tokio::test]
+async fn test_refuse_not_allowed() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // SOCKSv5 greeting arrived after accepted
+    let mut client = TcpStream::connect(&addr).await.unwrap();
+    let (sock, _) = listener.accept().await.unwrap();
+    let refusing = tokio::spawn(NewClient::refuse_not_allowed(sock));
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let mut buf = [0u8; 2];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!([5, 0], buf);
+    client
+        .write_all(&[5, 1, 0, 1, 127, 0, 0, 1, 0, 80])
+        .await
+        .unwrap();
+    let mut buf = [0u8; 10];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!([5, 2], buf[..2]);
+    refusing.await.unwrap();
+    assert!(client.read(&mut buf).await.map_or(true, |n| n == 0));
+
+    // Closed silently otherwise
+    let mut client = TcpStream::connect(&addr).await.unwrap();
+    let (sock, _) = listener.accept().await.unwrap();
+    let refusing = tokio::spawn(NewClient::refuse_not_allowed(sock));
+    client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+    refusing.await.unwrap();
+    assert!(client.read(&mut buf).await.map_or(true, |n| n == 0));
+}
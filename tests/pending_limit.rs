@@ -0,0 +1,50 @@
+use moproxy::{
+    client::{InboundOptions, NewClient},
+    proxy::buffers::BUFFERS,
+};
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+// In its own test binary as it sets the global limit
+#[tokio::test]
+async fn test_pending_limit_exceeded() {
+    let listener = TcpListener::bind("[::1]:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    BUFFERS.set_pending_limit(Some(1));
+    let mut lease = BUFFERS.lease_pending();
+    lease.resize(1);
+    assert!(BUFFERS.pending_exceeded());
+    let options = InboundOptions {
+        raw_tls_port: Some(443),
+        ..Default::default()
+    };
+
+    // Raw TLS refused without sniffing
+    let mut client = TcpStream::connect(&addr).await.unwrap();
+    client.write_all(&[0x16, 3, 1, 0, 1, 1]).await.unwrap();
+    let (sock, _) = listener.accept().await.unwrap();
+    assert!(NewClient::accept(sock, &options).await.is_err());
+    let mut buf = [0u8; 1];
+    assert!(client.read(&mut buf).await.map_or(true, |n| n == 0));
+
+    // SOCKSv5 still replied on every path, just not sniffed
+    let mut client = TcpStream::connect(&addr).await.unwrap();
+    let mut request = vec![5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1];
+    request.extend_from_slice(&443u16.to_be_bytes());
+    request.extend_from_slice(&[0x16, 3, 1]);
+    client.write_all(&request).await.unwrap();
+    let (sock, _) = listener.accept().await.unwrap();
+    let mut accepted = NewClient::accept(sock, &options).await.unwrap();
+    accepted.retrieve_dest_from_sni().await.unwrap();
+    assert!(accepted.tls.is_some());
+    accepted.reply_failed().await.unwrap();
+    let mut buf = [0u8; 12];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!([5, 0, 5, 1], buf[..4]);
+
+    drop(lease);
+    assert!(!BUFFERS.pending_exceeded());
+}
@@ -0,0 +1,82 @@
+use moproxy::{
+    client::NewClient,
+    proxy::{resolver::RESOLVER, Address, IpFamily, ProxyServer},
+};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    self,
+    net::{TcpListener, TcpStream, UdpSocket},
+    time::timeout,
+};
+
+/// DNS server answering 127.0.0.1 (A) and ::1 (AAAA) for any name.
+async fn dual_stack_dns() -> std::net::SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 512];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+            let query = &buf[..len];
+            let qtype = u16::from_be_bytes([query[len - 4], query[len - 3]]);
+            let data = match qtype {
+                1 => vec![127, 0, 0, 1],
+                _ => std::net::Ipv6Addr::LOCALHOST.octets().to_vec(),
+            };
+            let mut response = query.to_vec();
+            response[2] |= 0x80;
+            response[7] = 1;
+            response.extend_from_slice(&[0xc0, 12]);
+            response.extend_from_slice(&qtype.to_be_bytes());
+            response.extend_from_slice(&[0, 1, 0, 0, 0, 60]);
+            response.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            response.extend_from_slice(&data);
+            socket.send_to(&response, peer).await.unwrap();
+        }
+    });
+    addr
+}
+
+/// Listeners on both 127.0.0.1 and ::1 with the same port.
+async fn dual_stack_listeners() -> (TcpListener, TcpListener) {
+    loop {
+        let v4 = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = v4.local_addr().unwrap().port();
+        if let Ok(v6) = TcpListener::bind(("::1", port)).await {
+            return (v4, v6);
+        }
+    }
+}
+
+/// Connect directly to `dual.test`, return whether it's dialed on IPv6.
+async fn dial(family: Option<IpFamily>) -> bool {
+    let (v4, v6) = dual_stack_listeners().await;
+    let port = v4.local_addr().unwrap().port();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let _stream = TcpStream::connect(&addr).await.unwrap();
+    let (sock, _) = listener.accept().await.unwrap();
+    let mut client = NewClient::from_tproxy_socket(sock, addr.port(), false).unwrap();
+    client.dest = (Address::Domain("dual.test".into()), port).into();
+    client.prefer_family = family;
+
+    let direct = Arc::new(ProxyServer::direct(Duration::from_secs(1)));
+    let _connected = client.direct_connect(direct).await.unwrap();
+    let accept = async {
+        tokio::select! {
+            _ = v4.accept() => false,
+            _ = v6.accept() => true,
+        }
+    };
+    timeout(Duration::from_secs(1), accept).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_direct_dial_order() {
+    RESOLVER.set_servers(vec![dual_stack_dns().await]);
+    // IPv4 first by default
+    assert!(!dial(None).await);
+    assert!(dial(Some(IpFamily::V6)).await);
+    assert!(!dial(Some(IpFamily::V4)).await);
+}
@@ -0,0 +1,51 @@
+#![cfg(feature = "shadowsocks")]
+use moproxy::proxy::{
+    shadowsocks::{AeadStream, Cipher, Method},
+    ProxyProto, ProxyServer,
+};
+use std::time::Duration;
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+#[tokio::test]
+async fn test_shadowsocks_connect() {
+    for method in [Method::Chacha20IetfPoly1305, Method::Aes256Gcm] {
+        let cipher = Cipher::new(method, "secret");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = ProxyServer::new(
+            listener.local_addr().unwrap(),
+            ProxyProto::Shadowsocks {
+                cipher: cipher.clone(),
+            },
+            "127.0.0.1:53".parse().unwrap(),
+            Duration::from_secs(1),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = AeadStream::new(stream, cipher).unwrap();
+            // ATYP domain, len, "example.com", port 443, then payload
+            let mut buf = [0u8; 1 + 1 + 11 + 2 + 7];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&[3, 11], &buf[..2]);
+            assert_eq!(b"example.com", &buf[2..13]);
+            assert_eq!(&[1, 187], &buf[13..15]);
+            assert_eq!(b"payload", &buf[15..]);
+            stream.write_all(b"response").await.unwrap();
+            stream.flush().await.unwrap();
+        });
+
+        let dest = ("example.com", 443).into();
+        let mut stream = server.connect(&dest, Some(b"payload")).await.unwrap();
+        let mut buf = [0u8; 8];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"response", &buf);
+    }
+}
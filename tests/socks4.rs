@@ -0,0 +1,90 @@
+use moproxy::proxy::socks4::handshake;
+use std::net::SocketAddr;
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+#[tokio::test]
+async fn test_socks4a_domain() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 128];
+        stream.read_exact(&mut buf[..21]).await.unwrap();
+        assert_eq!(&buf[..9], &[4, 1, 0, 80, 0, 0, 0, 1, 0]); // 0.0.0.1, no user id
+        assert_eq!(&buf[9..21], b"example.com\0");
+        stream.write_all(&[0, 90, 0, 0, 0, 0, 0, 0]).await.unwrap();
+
+        stream.read_exact(&mut buf[..13]).await.unwrap();
+        assert!(buf.starts_with(b"early-payload"));
+        stream.write_all(b"response").await.unwrap();
+    });
+
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    let dest = ("example.com", 80).into();
+    let payload = b"early-payload";
+    handshake(&mut stream, &dest, Some(payload), true)
+        .await
+        .unwrap();
+    let mut buf = [0u8; 128];
+    let n = stream.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"response");
+}
+
+#[tokio::test]
+async fn test_socks4_local_resolve() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 128];
+        stream.read_exact(&mut buf[..9]).await.unwrap();
+        assert_eq!(&buf[..9], &[4, 1, 0, 80, 127, 0, 0, 1, 0]); // resolved
+        stream.write_all(&[0, 90, 0, 0, 0, 0, 0, 0]).await.unwrap();
+        stream.write_all(b"response").await.unwrap();
+    });
+
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    let dest = ("localhost", 80).into();
+    handshake::<&[u8]>(&mut stream, &dest, None, false)
+        .await
+        .unwrap();
+    let mut buf = [0u8; 128];
+    let n = stream.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"response");
+}
+
+#[tokio::test]
+async fn test_socks4_ipv4_rejected() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 128];
+        stream.read_exact(&mut buf[..9]).await.unwrap();
+        assert_eq!(&buf[..9], &[4, 1, 0x01, 0xbb, 192, 0, 2, 1, 0]);
+        stream.write_all(&[0, 91, 0, 0, 0, 0, 0, 0]).await.unwrap();
+    });
+
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    let dest = "192.0.2.1:443".parse::<SocketAddr>().unwrap().into();
+    let result = handshake(&mut stream, &dest, Some(b"payload"), true).await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn test_socks4_ipv6_unsupported() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    let dest = "[2001:db8::1]:80".parse::<SocketAddr>().unwrap().into();
+    let result = handshake::<&[u8]>(&mut stream, &dest, None, true).await;
+    assert!(result.is_err());
+}
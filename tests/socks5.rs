@@ -18,7 +18,7 @@ async fn test_socks5_domain() {
         assert_eq!(&[5, 1, 0], &buf[..3]); // ver 5, no auth
         stream.write_all(&[5, 0]).await.unwrap(); // no auth
 
-        stream.read(&mut buf).await.unwrap();
+        stream.read_exact(&mut buf[..18]).await.unwrap();
         assert!(buf.starts_with(&[5, 1, 0, 3, 11])); // domain, len 11
         assert!(buf[5..].starts_with(b"example.com"));
         assert!(buf[16..].starts_with(&[0, 80]));
@@ -27,7 +27,7 @@ async fn test_socks5_domain() {
             .await
             .unwrap();
 
-        stream.read(&mut buf).await.unwrap();
+        stream.read_exact(&mut buf[..13]).await.unwrap();
         assert!(buf.starts_with(b"early-payload"));
         stream.write_all(b"response").await.unwrap();
     });
@@ -35,7 +35,7 @@ async fn test_socks5_domain() {
     let mut stream = TcpStream::connect(&addr).await.unwrap();
     let dest = ("example.com", 80).into();
     let payload = b"early-payload";
-    handshake(&mut stream, &dest, Some(payload), false, &None)
+    handshake(&mut stream, &dest, Some(payload), false, &None, false)
         .await
         .unwrap();
     let mut buf = [0u8; 128];
@@ -55,7 +55,7 @@ async fn test_socks5_ipv6() {
         assert_eq!(&[5, 1, 0], &buf[..3]); // ver 5, no auth
         stream.write_all(&[5, 0]).await.unwrap(); // no auth
 
-        stream.read(&mut buf).await.unwrap();
+        stream.read_exact(&mut buf[..22]).await.unwrap();
         assert_eq!(
             &buf[..22],
             &[
@@ -72,7 +72,7 @@ async fn test_socks5_ipv6() {
             .await
             .unwrap();
 
-        stream.read(&mut buf).await.unwrap();
+        stream.read_exact(&mut buf[..13]).await.unwrap();
         assert!(buf.starts_with(b"early-payload"));
         stream.write_all(b"response").await.unwrap();
     });
@@ -80,10 +80,246 @@ async fn test_socks5_ipv6() {
     let mut stream = TcpStream::connect(&addr).await.unwrap();
     let dest = "[2001:db8::1]:80".parse::<SocketAddr>().unwrap().into();
     let payload = b"early-payload";
-    handshake(&mut stream, &dest, Some(payload), false, &None)
+    handshake(&mut stream, &dest, Some(payload), false, &None, false)
         .await
         .unwrap();
     let mut buf = [0u8; 128];
     let n = stream.read(&mut buf).await.unwrap();
     assert_eq!(&buf[..n], b"response");
 }
+
+#[tokio::test]
+async fn test_socks5_with_prelude() {
+    use moproxy::proxy::{prelude::Prelude, ProxyProto, ProxyServer};
+    use std::time::Duration;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = ProxyServer::new(
+        listener.local_addr().unwrap(),
+        ProxyProto::socks5(false),
+        "127.0.0.1:53".parse().unwrap(),
+        Duration::from_secs(1),
+        None,
+        None,
+        None,
+    )
+    .unwrap();
+    server.update_config(|config| {
+        config.prelude = Some(Prelude {
+            send: b"knock".to_vec(),
+            expect: b"ok".to_vec(),
+        })
+    });
+
+    tokio::spawn(async move {
+        // Accept greeting after the prelude, else refuse
+        for answer in [&b"ok"[..], &b"no"[..]] {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(b"knock", &buf);
+            stream.write_all(answer).await.unwrap();
+            if answer == b"no" {
+                continue;
+            }
+            stream.read_exact(&mut buf[..3]).await.unwrap();
+            assert_eq!(&[5, 1, 0], &buf[..3]);
+            stream.write_all(&[5, 0]).await.unwrap();
+            let mut buf = [0u8; 10];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 80])
+                .await
+                .unwrap();
+        }
+    });
+
+    let dest = "1.2.3.4:80".parse::<SocketAddr>().unwrap().into();
+    server.connect::<&[u8]>(&dest, None).await.unwrap();
+    let err = server.connect::<&[u8]>(&dest, None).await.unwrap_err();
+    assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+}
+
+#[tokio::test]
+async fn test_socks5_upstream_code() {
+    use moproxy::proxy::error::HandshakeError;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 10];
+        stream.read_exact(&mut buf[..3]).await.unwrap();
+        stream.write_all(&[5, 0]).await.unwrap();
+        stream.read_exact(&mut buf).await.unwrap();
+        // connection refused
+        stream
+            .write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+    });
+
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    let dest = "1.2.3.4:80".parse::<SocketAddr>().unwrap().into();
+    let err = handshake(&mut stream, &dest, None::<&[u8]>, false, &None, false)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, HandshakeError::UpstreamCode(5)));
+    assert_eq!("upstream_code", err.kind());
+}
+
+#[tokio::test]
+async fn test_socks5_scoped_ipv6() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 22];
+        stream.read_exact(&mut buf[..3]).await.unwrap();
+        stream.write_all(&[5, 0]).await.unwrap();
+        stream.read_exact(&mut buf).await.unwrap();
+        // Scope ID stripped
+        let mut expected = vec![5, 1, 0, 4, 0xfe, 0x80];
+        expected.extend_from_slice(&[0; 13]);
+        expected.extend_from_slice(&[1, 0, 80]);
+        assert_eq!(&expected[..], &buf[..]);
+        stream
+            .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+    });
+
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    let dest: moproxy::proxy::Destination = "[fe80::1%2]:80".parse::<SocketAddr>().unwrap().into();
+    assert_eq!(2, dest.scope_id);
+    handshake(&mut stream, &dest, None::<&[u8]>, false, &None, false)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_socks5_per_connection_auth() {
+    use moproxy::proxy::{ProxyProto, ProxyServer, UserPassAuthCredential};
+    use std::time::Duration;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = ProxyServer::new(
+        listener.local_addr().unwrap(),
+        ProxyProto::socks5_with_auth(UserPassAuthCredential::new("static", "pw")),
+        "127.0.0.1:53".parse().unwrap(),
+        Duration::from_secs(1),
+        None,
+        None,
+        None,
+    )
+    .unwrap();
+
+    tokio::spawn(async move {
+        // Reject the first one, accept the second
+        for accept in [false, true] {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!([5, 2, 0, 2], buf);
+            stream.write_all(&[5, 2]).await.unwrap();
+            let mut buf = [0u8; 1 + 1 + 5 + 1 + 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(b"user1", &buf[2..7]);
+            assert_eq!(b"pass1", &buf[8..]);
+            if !accept {
+                stream.write_all(&[1, 1]).await.unwrap();
+                continue;
+            }
+            stream.write_all(&[1, 0]).await.unwrap();
+            let mut buf = [0u8; 10];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 80])
+                .await
+                .unwrap();
+        }
+    });
+
+    let dest = "1.2.3.4:80".parse::<SocketAddr>().unwrap().into();
+    let auth = UserPassAuthCredential::new("user1", "pass1");
+    server
+        .connect_with_auth::<&[u8]>(&dest, None, Some(&auth))
+        .await
+        .unwrap_err();
+    // Only the per-connection credential is rejected
+    assert!(!server.auth_failed());
+    server
+        .connect_with_auth::<&[u8]>(&dest, None, Some(&auth))
+        .await
+        .unwrap();
+}
+
+/// Accept username/password authentication of `user`/`pass` with
+/// `version` only, return whether it's accepted.
+async fn auth_server(listener: TcpListener, version: u8) -> bool {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!([5, 2, 0, 2], buf);
+    stream.write_all(&[5, 2]).await.unwrap();
+    let mut buf = [0u8; 1 + 1 + 4 + 1 + 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    if buf[0] != version || buf[1..] != *b"\x04user\x04pass" {
+        stream.write_all(&[version, 1]).await.unwrap();
+        return false;
+    }
+    stream.write_all(&[version, 0]).await.unwrap();
+    let mut buf = [0u8; 10];
+    stream.read_exact(&mut buf).await.unwrap();
+    stream
+        .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 80])
+        .await
+        .unwrap();
+    true
+}
+
+#[tokio::test]
+async fn test_socks5_auth_version() {
+    use moproxy::proxy::{error::HandshakeError, UserPassAuthCredential};
+
+    let auth = Some(UserPassAuthCredential::new("user", "pass"));
+    let dest = "1.2.3.4:80".parse::<SocketAddr>().unwrap().into();
+    for (version, legacy, accepted) in [(1, false, true), (1, true, false), (5, true, true)] {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(auth_server(listener, version));
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        let result = handshake(&mut stream, &dest, None::<&[u8]>, false, &auth, legacy).await;
+        assert_eq!(accepted, server.await.unwrap());
+        match result {
+            Ok(()) => assert!(accepted),
+            Err(HandshakeError::AuthRejected) => assert!(!accepted),
+            Err(err) => panic!("{}", err),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_socks5_auth_no_legacy_version_by_default() {
+    use moproxy::proxy::{ProxyProto, ProxyServer, UserPassAuthCredential};
+    use std::time::Duration;
+
+    // Servers expecting 0x05 refuse us unless configured
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = ProxyServer::new(
+        listener.local_addr().unwrap(),
+        ProxyProto::socks5_with_auth(UserPassAuthCredential::new("user", "pass")),
+        "127.0.0.1:53".parse().unwrap(),
+        Duration::from_secs(1),
+        None,
+        None,
+        None,
+    )
+    .unwrap();
+    let strict = tokio::spawn(auth_server(listener, 5));
+    let dest = "1.2.3.4:80".parse::<SocketAddr>().unwrap().into();
+    server.connect::<&[u8]>(&dest, None).await.unwrap_err();
+    assert!(!strict.await.unwrap());
+}
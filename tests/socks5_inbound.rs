@@ -0,0 +1,423 @@
+use moproxy::{client::NewClient, proxy::ProxyServer};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Connect to `addr` as a SOCKSv5 client requesting `dest`, with `payload`
+/// sent right after the request without waiting for the reply.
+async fn socks5_request(addr: SocketAddr, dest: SocketAddr, payload: &[u8]) -> TcpStream {
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    let mut buf = [0u8; 2];
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!([5, 0], buf);
+    let mut request = vec![5, 1, 0, 1];
+    match dest {
+        SocketAddr::V4(dest) => request.extend_from_slice(&dest.ip().octets()),
+        SocketAddr::V6(dest) => {
+            request[3] = 4;
+            request.extend_from_slice(&dest.ip().octets());
+        }
+    }
+    request.extend_from_slice(&dest.port().to_be_bytes());
+    request.extend_from_slice(payload);
+    stream.write_all(&request).await.unwrap();
+    stream
+}
+
+/// Accept a SOCKSv5 client requesting `dest`, return both sides.
+async fn accept_client(dest: SocketAddr, payload: &'static [u8]) -> (NewClient, TcpStream) {
+    // Listen on IPv6 as the default `--host ::` does
+    let listener = TcpListener::bind("[::1]:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let request = tokio::spawn(async move { socks5_request(addr, dest, payload).await });
+    let (sock, _) = listener.accept().await.unwrap();
+    let client = NewClient::from_socket(sock, false).await.unwrap();
+    (client, request.await.unwrap())
+}
+
+/// Read a SOCKSv5 reply, return its reply field and bound address.
+async fn read_reply(stream: &mut TcpStream) -> (u8, SocketAddr) {
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(5, buf[0]);
+    let ip: IpAddr = match buf[3] {
+        1 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip).await.unwrap();
+            ip.into()
+        }
+        4 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip).await.unwrap();
+            ip.into()
+        }
+        n => panic!("unexpected ATYP {}", n),
+    };
+    let port = stream.read_u16().await.unwrap();
+    (buf[1], (ip, port).into())
+}
+
+fn direct_server() -> Arc<ProxyServer> {
+    Arc::new(ProxyServer::direct(Duration::from_secs(1)))
+}
+
+#[tokio::test]
+async fn test_socks5_reply_rejected() {
+    let dest = "127.0.0.1:80".parse().unwrap();
+    let (client, mut stream) = accept_client(dest, b"").await;
+    client.reply_rejected().await.unwrap();
+    let (reply, bound) = read_reply(&mut stream).await;
+    assert_eq!(2, reply);
+    assert_eq!("0.0.0.0:0".parse::<SocketAddr>().unwrap(), bound);
+}
+
+#[tokio::test]
+async fn test_socks5_reply_deferred_until_connected() {
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dest = upstream.local_addr().unwrap();
+    let (client, mut stream) = accept_client(dest, b"early").await;
+
+    // Not replied before connected
+    let mut buf = [0u8; 1];
+    let read = tokio::time::timeout(Duration::from_millis(50), stream.read(&mut buf));
+    assert!(read.await.is_err());
+
+    let connected = client.direct_connect(direct_server()).await.unwrap();
+    tokio::spawn(connected.serve());
+    let (reply, bound) = read_reply(&mut stream).await;
+    assert_eq!(0, reply);
+
+    // Data sent before the reply is forwarded
+    let (mut right, peer) = upstream.accept().await.unwrap();
+    assert_eq!(peer, bound);
+    let mut buf = [0u8; 5];
+    right.read_exact(&mut buf).await.unwrap();
+    assert_eq!(b"early", &buf);
+}
+
+#[tokio::test]
+async fn test_socks5_replay_on_direct_fallback() {
+    use moproxy::proxy::ProxyProto;
+
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dest = upstream.local_addr().unwrap();
+    let payload: Vec<u8> = (0..8192).map(|n| (n % 251) as u8).collect();
+    let (mut client, mut stream) = accept_client(dest, payload.clone().leak()).await;
+
+    let proxy = {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    };
+    let proxy = ProxyServer::new(
+        proxy,
+        ProxyProto::socks5(false),
+        "127.0.0.1:53".parse().unwrap(),
+        Duration::from_secs(1),
+        None,
+        None,
+        None,
+    )
+    .unwrap();
+    client.retrieve_dest_from_sni().await.unwrap();
+    let client = client
+        .connect_server(vec![Arc::new(proxy)], 1, 0)
+        .await
+        .unwrap_err()
+        .recovery()
+        .unwrap();
+    let connected = client.direct_connect(direct_server()).await.unwrap();
+    tokio::spawn(connected.serve());
+    assert_eq!(0, read_reply(&mut stream).await.0);
+
+    let (mut right, _) = upstream.accept().await.unwrap();
+    let mut buf = vec![0u8; payload.len()];
+    right.read_exact(&mut buf).await.unwrap();
+    assert_eq!(payload, buf);
+}
+
+#[tokio::test]
+async fn test_socks5_reply_connection_refused() {
+    let dest = {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    };
+    let (client, mut stream) = accept_client(dest, b"").await;
+    client.direct_connect(direct_server()).await.unwrap_err();
+    assert_eq!(5, read_reply(&mut stream).await.0);
+}
+
+#[tokio::test]
+async fn test_socks5_reply_bound_addr() {
+    let upstream = TcpListener::bind("[::1]:0").await.unwrap();
+    let dest = upstream.local_addr().unwrap();
+    let (client, mut stream) = accept_client(dest, b"").await;
+    let _connected = client.direct_connect(direct_server()).await.unwrap();
+    let (_, peer) = upstream.accept().await.unwrap();
+    assert_eq!((0, peer), read_reply(&mut stream).await);
+
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dest = upstream.local_addr().unwrap();
+    let (mut client, mut stream) = accept_client(dest, b"").await;
+    let advertised: IpAddr = "2001:db8::1".parse().unwrap();
+    client.advertised_addr = Some(advertised);
+    let _connected = client.direct_connect(direct_server()).await.unwrap();
+    let (_, peer) = upstream.accept().await.unwrap();
+    let (reply, bound) = read_reply(&mut stream).await;
+    assert_eq!(0, reply);
+    assert_eq!(SocketAddr::new(advertised, peer.port()), bound);
+}
+
+#[tokio::test]
+async fn test_socks5_domain_normalized() {
+    let listener = TcpListener::bind("[::1]:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        let mut buf = [0u8; 2];
+        stream.write_all(&[5, 1, 0]).await.unwrap();
+        stream.read_exact(&mut buf).await.unwrap();
+        let name = "WWW.Bücher.Example.".as_bytes();
+        let mut request = vec![5, 1, 0, 3, name.len() as u8];
+        request.extend_from_slice(name);
+        request.extend_from_slice(&443u16.to_be_bytes());
+        stream.write_all(&request).await.unwrap();
+    });
+    let (sock, _) = listener.accept().await.unwrap();
+    let client = NewClient::from_socket(sock, false).await.unwrap();
+    assert_eq!(
+        Some("www.xn--bcher-kva.example"),
+        client.features().dst_domain.as_deref()
+    );
+
+    // Upstream receives the normalized name
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (mut stream, _) = upstream.accept().await.unwrap();
+        let mut buf = [0u8; 64];
+        stream.read_exact(&mut buf[..3]).await.unwrap();
+        stream.write_all(&[5, 0]).await.unwrap();
+        stream.read_exact(&mut buf[..5]).await.unwrap();
+        let len = buf[4] as usize;
+        stream.read_exact(&mut buf[..len + 2]).await.unwrap();
+        stream
+            .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        buf[..len + 2].to_vec()
+    });
+    let mut stream = TcpStream::connect(&upstream_addr).await.unwrap();
+    moproxy::proxy::socks5::handshake(
+        &mut stream,
+        &client.dest,
+        None::<&[u8]>,
+        false,
+        &None,
+        false,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        b"www.xn--bcher-kva.example\x01\xbb",
+        &server.await.unwrap()[..]
+    );
+}
+
+#[tokio::test]
+async fn test_skip_server_not_allowing_port() {
+    use moproxy::proxy::ProxyProto;
+
+    let dest = "127.0.0.1:80".parse().unwrap();
+    let (client, _stream) = accept_client(dest, b"").await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy = ProxyServer::new(
+        listener.local_addr().unwrap(),
+        ProxyProto::socks5(false),
+        "127.0.0.1:53".parse().unwrap(),
+        Duration::from_secs(1),
+        None,
+        None,
+        None,
+    )
+    .unwrap();
+    proxy.update_config(|config| config.allowed_ports = Some(vec![443, 8443]));
+    client
+        .connect_server(vec![Arc::new(proxy)], 1, 0)
+        .await
+        .unwrap_err()
+        .recovery()
+        .unwrap();
+
+    // Never connected
+    let accept = tokio::time::timeout(Duration::from_millis(50), listener.accept());
+    assert!(accept.await.is_err());
+}
+
+/// Accept a client that sends the greeting, the request to `dest` and
+/// 4 KiB payload all at once, before reading any reply. Sniff TLS on it
+/// if `sniff` is set.
+async fn accept_pipelined(dest: SocketAddr, sniff: bool) -> (NewClient, TcpStream, Vec<u8>) {
+    let listener = TcpListener::bind("[::1]:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let payload: Vec<u8> = (0..4096).map(|n| (n % 251) as u8).collect();
+    let mut request = vec![5, 1, 0, 5, 1, 0, 1];
+    match dest {
+        SocketAddr::V4(dest) => request.extend_from_slice(&dest.ip().octets()),
+        SocketAddr::V6(_) => panic!("IPv4 only"),
+    }
+    request.extend_from_slice(&dest.port().to_be_bytes());
+    request.extend_from_slice(&payload);
+    let stream = tokio::spawn(async move {
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        stream.write_all(&request).await.unwrap();
+        stream
+    });
+    let (sock, _) = listener.accept().await.unwrap();
+    let mut client = NewClient::from_socket(sock, false).await.unwrap();
+    if sniff {
+        client.retrieve_dest_from_sni().await.unwrap();
+    }
+    (client, stream.await.unwrap(), payload)
+}
+
+/// Read the replies to the pipelined greeting and request, then send
+/// more data after them.
+async fn finish_pipelined(stream: &mut TcpStream) {
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!([5, 0], buf);
+    assert_eq!(0, read_reply(stream).await.0);
+    stream.write_all(b"tail").await.unwrap();
+}
+
+#[tokio::test]
+async fn test_socks5_pipelined_direct() {
+    for sniff in [false, true] {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dest = upstream.local_addr().unwrap();
+        let (client, mut stream, payload) = accept_pipelined(dest, sniff).await;
+        let connected = client.direct_connect(direct_server()).await.unwrap();
+        tokio::spawn(connected.serve());
+
+        let (mut right, _) = upstream.accept().await.unwrap();
+        let mut buf = vec![0u8; payload.len()];
+        right.read_exact(&mut buf).await.unwrap();
+        assert_eq!(payload, buf);
+
+        finish_pipelined(&mut stream).await;
+        let mut buf = [0u8; 4];
+        right.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"tail", &buf);
+    }
+}
+
+#[tokio::test]
+async fn test_socks5_pipelined_proxied() {
+    use moproxy::proxy::ProxyProto;
+
+    for sniff in [false, true] {
+        let dest = "192.0.2.1:80".parse().unwrap();
+        let (client, mut stream, payload) = accept_pipelined(dest, sniff).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = ProxyServer::new(
+            listener.local_addr().unwrap(),
+            ProxyProto::socks5(false),
+            "127.0.0.1:53".parse().unwrap(),
+            Duration::from_secs(1),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        // SOCKSv5 server that reads all data after its reply
+        let upstream = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 10];
+            stream.read_exact(&mut buf[..3]).await.unwrap();
+            assert_eq!([5, 1, 0], buf[..3]);
+            stream.write_all(&[5, 0]).await.unwrap();
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!([5, 1, 0, 1, 192, 0, 2, 1, 0, 80], buf);
+            stream
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            stream
+        });
+        let connected = client
+            .connect_server(vec![Arc::new(proxy)], 1, 0)
+            .await
+            .unwrap();
+        tokio::spawn(connected.serve());
+
+        let mut right = upstream.await.unwrap();
+        let mut buf = vec![0u8; payload.len()];
+        right.read_exact(&mut buf).await.unwrap();
+        assert_eq!(payload, buf);
+
+        finish_pipelined(&mut stream).await;
+        let mut buf = [0u8; 4];
+        right.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"tail", &buf);
+    }
+}
+
+#[tokio::test]
+async fn test_socks5_not_replied_on_sniffing() {
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dest = upstream.local_addr().unwrap();
+    let (mut client, mut stream) = accept_client(dest, b"").await;
+
+    // Nothing sent, skipped at once rather than replied to get the hello
+    let sniff = tokio::time::timeout(Duration::from_millis(100), client.retrieve_dest_from_sni());
+    sniff.await.unwrap().unwrap();
+    assert!(client.tls.is_some());
+    let mut buf = [0u8; 1];
+    let read = tokio::time::timeout(Duration::from_millis(50), stream.read(&mut buf));
+    assert!(read.await.is_err());
+
+    let _connected = client.direct_connect(direct_server()).await.unwrap();
+    assert_eq!(0, read_reply(&mut stream).await.0);
+}
+
+#[tokio::test]
+async fn test_socks5_reply_failed_after_sniffing() {
+    use moproxy::proxy::ProxyProto;
+
+    let dest = "192.0.2.1:443".parse().unwrap();
+    for payload in [&b""[..], b"not tls"] {
+        let (mut client, mut stream) = accept_client(dest, payload).await;
+        client.retrieve_dest_from_sni().await.unwrap();
+        let proxy = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+        let proxy = ProxyServer::new(
+            proxy,
+            ProxyProto::socks5(false),
+            "127.0.0.1:53".parse().unwrap(),
+            Duration::from_secs(1),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let client = client
+            .connect_server(vec![Arc::new(proxy)], 1, 0)
+            .await
+            .unwrap_err()
+            .recovery()
+            .unwrap();
+        client.reply_failed().await.unwrap();
+        assert_eq!(5, read_reply(&mut stream).await.0);
+    }
+}
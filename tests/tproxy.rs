@@ -0,0 +1,46 @@
+use moproxy::client::NewClient;
+use tokio::{
+    self,
+    net::{TcpListener, TcpStream},
+};
+
+/// Accept a connection on `listener` in TPROXY mode, return the client.
+async fn accept_tproxy(listener: TcpListener, listen_port: u16) -> NewClient {
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let _stream = TcpStream::connect(&addr).await.unwrap();
+    });
+    let (sock, _) = listener.accept().await.unwrap();
+    NewClient::from_tproxy_socket(sock, listen_port, false).unwrap()
+}
+
+#[tokio::test]
+async fn test_tproxy_dest_from_local_addr() {
+    for host in ["127.0.0.1:0", "[::1]:0"] {
+        let listener = TcpListener::bind(host).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Differ from the actual port, as the redirected port would
+        let client = accept_tproxy(listener, 1080).await;
+        assert_eq!(addr.port(), client.dest.port);
+        let features = client.features();
+        assert_eq!(Some(addr.ip()), features.dst_ip);
+        assert_eq!(Some(1080), features.listen_port);
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_tproxy_bind_transparent() {
+    use moproxy::linux::tcp::bind_transparent;
+    use std::io::ErrorKind;
+
+    let listener = match bind_transparent("127.0.0.1:0".parse().unwrap()) {
+        Ok(listener) => listener,
+        // Without CAP_NET_ADMIN
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => return,
+        Err(err) => panic!("bind_transparent: {}", err),
+    };
+    let addr = listener.local_addr().unwrap();
+    let client = accept_tproxy(listener, addr.port()).await;
+    assert_eq!(addr.port(), client.dest.port);
+}